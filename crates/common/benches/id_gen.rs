@@ -0,0 +1,19 @@
+use common::{generate_random_id, generate_random_id_secure, IdCharset};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+
+fn bench_generate_random_id(c: &mut Criterion) {
+    let mut group = c.benchmark_group("generate_random_id");
+    for len in [8, 12, 24, 48] {
+        group.throughput(Throughput::Bytes(len as u64));
+        group.bench_with_input(BenchmarkId::new("os_rng", len), &len, |b, &len| {
+            b.iter(|| generate_random_id(len, IdCharset::VisuallyDistinct))
+        });
+        group.bench_with_input(BenchmarkId::new("getrandom", len), &len, |b, &len| {
+            b.iter(|| generate_random_id_secure(len, IdCharset::VisuallyDistinct).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_generate_random_id);
+criterion_main!(benches);
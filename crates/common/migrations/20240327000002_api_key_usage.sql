@@ -0,0 +1,17 @@
+-- Per-request usage log for API keys
+CREATE TABLE IF NOT EXISTS api_key_usage (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    api_key_id TEXT NOT NULL,
+    endpoint TEXT NOT NULL,
+    method TEXT NOT NULL,
+    status_code INTEGER NOT NULL,
+    response_ms INTEGER NOT NULL,
+    used_at INTEGER NOT NULL,
+    FOREIGN KEY(api_key_id) REFERENCES api_keys(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_api_key_usage_key_time
+ON api_key_usage(api_key_id, used_at);
+
+CREATE INDEX IF NOT EXISTS idx_api_key_usage_used_at
+ON api_key_usage(used_at);
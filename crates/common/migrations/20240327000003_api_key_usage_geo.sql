@@ -0,0 +1,3 @@
+-- Optional GeoIP data for API key usage
+ALTER TABLE api_key_usage ADD COLUMN country_code TEXT;
+ALTER TABLE api_key_usage ADD COLUMN city TEXT;
@@ -0,0 +1,2 @@
+-- Allow API keys to be revoked without deleting them
+ALTER TABLE api_keys ADD COLUMN revoked_at INTEGER;
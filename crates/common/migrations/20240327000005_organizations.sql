@@ -0,0 +1,25 @@
+-- Organizations share mailboxes between their members
+CREATE TABLE IF NOT EXISTS organizations (
+    id TEXT PRIMARY KEY,
+    name TEXT NOT NULL,
+    owner_user_id TEXT NOT NULL REFERENCES users(id),
+    max_mailboxes INTEGER,
+    created_at INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS organization_members (
+    org_id TEXT NOT NULL REFERENCES organizations(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    role TEXT NOT NULL CHECK(role IN ('owner', 'admin', 'member')),
+    created_at INTEGER NOT NULL,
+    PRIMARY KEY (org_id, user_id)
+);
+
+CREATE INDEX IF NOT EXISTS idx_organization_members_user
+ON organization_members(user_id);
+
+ALTER TABLE mailboxes ADD COLUMN organization_id TEXT REFERENCES organizations(id);
+ALTER TABLE api_keys ADD COLUMN organization_id TEXT REFERENCES organizations(id);
+
+CREATE INDEX IF NOT EXISTS idx_mailboxes_organization
+ON mailboxes(organization_id);
@@ -0,0 +1,2 @@
+-- Per-user email retention policy, stored as JSON
+ALTER TABLE user_settings ADD COLUMN email_cleanup_policy TEXT;
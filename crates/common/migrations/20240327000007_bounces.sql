@@ -0,0 +1,12 @@
+-- Delivery status notifications received by the SMTP service
+CREATE TABLE IF NOT EXISTS bounces (
+    id TEXT PRIMARY KEY,
+    original_recipient TEXT NOT NULL,
+    status_code TEXT NOT NULL,
+    diagnostic TEXT,
+    permanent BOOLEAN NOT NULL,
+    bounced_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_bounces_recipient
+ON bounces(original_recipient, bounced_at);
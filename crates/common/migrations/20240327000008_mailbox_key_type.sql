@@ -0,0 +1,2 @@
+-- Recipient type of the mailbox public key; existing keys are all age X25519
+ALTER TABLE mailboxes ADD COLUMN key_type TEXT NOT NULL DEFAULT 'age_x25519';
@@ -0,0 +1,10 @@
+-- Keyword search tokens (HMAC-SHA256 of each keyword keyed by the mailbox public key)
+CREATE TABLE IF NOT EXISTS email_search_tokens (
+    email_id TEXT NOT NULL,
+    mailbox_id TEXT NOT NULL,
+    search_token TEXT NOT NULL,
+    PRIMARY KEY (email_id, search_token),
+    FOREIGN KEY(email_id) REFERENCES emails(id) ON DELETE CASCADE
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_search_tokens_mailbox_token ON email_search_tokens(mailbox_id, search_token);
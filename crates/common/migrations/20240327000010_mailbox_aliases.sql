@@ -0,0 +1,13 @@
+-- Every address of a mailbox; the primary alias mirrors mailboxes.alias
+CREATE TABLE IF NOT EXISTS mailbox_aliases (
+    id TEXT PRIMARY KEY,
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    alias TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    is_primary BOOLEAN NOT NULL DEFAULT 0
+);
+
+CREATE INDEX IF NOT EXISTS idx_mailbox_aliases_mailbox ON mailbox_aliases(mailbox_id);
+
+INSERT OR IGNORE INTO mailbox_aliases (id, mailbox_id, alias, created_at, is_primary)
+SELECT id, id, alias, created_at, 1 FROM mailboxes;
@@ -0,0 +1,23 @@
+-- Millisecond timestamp of the last change to a mailbox, used for list caching headers
+ALTER TABLE mailboxes ADD COLUMN last_modified_at INTEGER NOT NULL DEFAULT 0;
+
+UPDATE mailboxes SET last_modified_at = created_at * 1000;
+
+CREATE TRIGGER IF NOT EXISTS mailboxes_last_modified_insert
+AFTER INSERT ON mailboxes
+FOR EACH ROW
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = NEW.id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS mailboxes_last_modified_update
+AFTER UPDATE ON mailboxes
+FOR EACH ROW
+WHEN NEW.last_modified_at = OLD.last_modified_at
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = NEW.id;
+END;
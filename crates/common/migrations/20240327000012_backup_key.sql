@@ -0,0 +1,4 @@
+-- Backup key for passphrase recovery: an age X25519 key pair whose private
+-- key is encrypted with a passphrase only the user knows
+ALTER TABLE user_credentials ADD COLUMN backup_public_key TEXT;
+ALTER TABLE user_credentials ADD COLUMN backup_key_encrypted TEXT;
@@ -0,0 +1,2 @@
+-- Tokens issued before the last password change are rejected
+ALTER TABLE users ADD COLUMN password_changed_at INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,7 @@
+-- Runtime overrides of the mail service's ENABLE_* settings, managed via the admin API
+CREATE TABLE IF NOT EXISTS feature_flags (
+    flag_name TEXT PRIMARY KEY,
+    enabled BOOLEAN NOT NULL,
+    updated_by TEXT,
+    updated_at INTEGER NOT NULL
+);
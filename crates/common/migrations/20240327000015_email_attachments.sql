@@ -0,0 +1,12 @@
+-- Mailboxes can store the attachments of incoming emails apart from the body
+ALTER TABLE mailboxes ADD COLUMN strip_attachments BOOLEAN NOT NULL DEFAULT 0;
+
+CREATE TABLE IF NOT EXISTS email_attachments (
+    id TEXT PRIMARY KEY,
+    email_id TEXT NOT NULL REFERENCES emails(id) ON DELETE CASCADE,
+    filename TEXT,
+    mime_type TEXT NOT NULL,
+    encrypted_content TEXT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_email_attachments_email ON email_attachments(email_id);
@@ -0,0 +1,4 @@
+-- Plaintext From, To and Subject of each email, for filtering without decryption
+ALTER TABLE emails ADD COLUMN from_addr TEXT NOT NULL DEFAULT '';
+ALTER TABLE emails ADD COLUMN to_addr TEXT NOT NULL DEFAULT '';
+ALTER TABLE emails ADD COLUMN subject TEXT NOT NULL DEFAULT '';
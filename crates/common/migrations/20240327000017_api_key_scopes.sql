@@ -0,0 +1,2 @@
+-- Comma-separated operations each API key may perform; existing keys keep full access
+ALTER TABLE api_keys ADD COLUMN scopes TEXT NOT NULL DEFAULT 'read_emails,delete_emails,manage_mailboxes';
@@ -0,0 +1,3 @@
+-- Optional per-user limits on stored emails, unlimited when NULL
+ALTER TABLE user_settings ADD COLUMN email_count_limit INTEGER;
+ALTER TABLE user_settings ADD COLUMN total_storage_bytes_limit INTEGER;
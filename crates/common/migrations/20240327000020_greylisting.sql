@@ -0,0 +1,10 @@
+-- Greylisted (IP, sender, recipient) triples, kept across restarts
+CREATE TABLE IF NOT EXISTS greylisting (
+    ip TEXT NOT NULL,
+    from_addr TEXT NOT NULL,
+    to_addr TEXT NOT NULL,
+    first_seen INTEGER NOT NULL,
+    PRIMARY KEY (ip, from_addr, to_addr)
+);
+
+CREATE INDEX IF NOT EXISTS idx_greylisting_first_seen ON greylisting(first_seen);
@@ -0,0 +1,2 @@
+-- Plaintext JSON object of selected headers of each email, see Email::with_headers
+ALTER TABLE emails ADD COLUMN headers_json TEXT;
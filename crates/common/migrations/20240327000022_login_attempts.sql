@@ -0,0 +1,9 @@
+-- Failed password logins, counted to temporarily lock accounts
+CREATE TABLE IF NOT EXISTS login_attempts (
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    attempt_at INTEGER NOT NULL,
+    ip TEXT
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_attempts_user ON login_attempts(user_id, attempt_at);
+CREATE INDEX IF NOT EXISTS idx_login_attempts_attempt_at ON login_attempts(attempt_at);
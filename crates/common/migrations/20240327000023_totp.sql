@@ -0,0 +1,14 @@
+-- TOTP two-factor authentication. The secret is only used once confirmed
+-- with a code, and the last accepted time step prevents replaying a code.
+ALTER TABLE user_credentials ADD COLUMN totp_secret TEXT;
+ALTER TABLE user_credentials ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT 0;
+ALTER TABLE user_credentials ADD COLUMN totp_last_step INTEGER;
+
+-- Issued by password logins of accounts with TOTP enabled, exchanged for
+-- tokens with a code; only their SHA-256 is stored
+CREATE TABLE IF NOT EXISTS totp_challenges (
+    token_hash TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    expires_at INTEGER NOT NULL,
+    failed_attempts INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,14 @@
+-- HTTP callbacks of a mailbox; `events` is comma-separated and
+-- `last_status` is the HTTP status of the last delivery, NULL if it got none
+CREATE TABLE IF NOT EXISTS webhooks (
+    id TEXT PRIMARY KEY,
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    url TEXT NOT NULL,
+    secret TEXT NOT NULL,
+    events TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    last_triggered_at INTEGER,
+    last_status INTEGER
+);
+
+CREATE INDEX IF NOT EXISTS idx_webhooks_mailbox ON webhooks(mailbox_id);
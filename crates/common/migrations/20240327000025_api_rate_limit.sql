@@ -0,0 +1,2 @@
+-- Requests per minute a user may make to the web API, the server default when NULL
+ALTER TABLE user_settings ADD COLUMN api_rate_limit_per_minute INTEGER;
@@ -0,0 +1,45 @@
+-- When each user last listed the emails of a mailbox; later emails are unread
+CREATE TABLE IF NOT EXISTS mailbox_views (
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    user_id TEXT NOT NULL,
+    viewed_at INTEGER NOT NULL,
+    PRIMARY KEY (mailbox_id, user_id)
+);
+
+-- Email and unread counts are part of the mailbox list, so changes to them
+-- must advance last_modified_at for its caching headers
+CREATE TRIGGER IF NOT EXISTS emails_mailbox_modified_insert
+AFTER INSERT ON emails
+FOR EACH ROW
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = NEW.mailbox_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS emails_mailbox_modified_delete
+AFTER DELETE ON emails
+FOR EACH ROW
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = OLD.mailbox_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS mailbox_views_mailbox_modified_insert
+AFTER INSERT ON mailbox_views
+FOR EACH ROW
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = NEW.mailbox_id;
+END;
+
+CREATE TRIGGER IF NOT EXISTS mailbox_views_mailbox_modified_update
+AFTER UPDATE ON mailbox_views
+FOR EACH ROW
+BEGIN
+    UPDATE mailboxes
+    SET last_modified_at = CAST((julianday('now') - 2440587.5) * 86400000 AS INTEGER)
+    WHERE id = NEW.mailbox_id;
+END;
@@ -0,0 +1,11 @@
+-- Primary aliases replaced by a rotation, rejected instead of bounced until expires_at
+ALTER TABLE mailboxes ADD COLUMN alias_rotated_at INTEGER;
+
+CREATE TABLE IF NOT EXISTS old_aliases (
+    alias TEXT PRIMARY KEY,
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    retired_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_old_aliases_expires ON old_aliases(expires_at);
@@ -0,0 +1,26 @@
+-- Full-text index of the plaintext email metadata. Rows share the rowid of
+-- their email so deleting one is a rowid lookup; searches join on email_id.
+CREATE VIRTUAL TABLE IF NOT EXISTS email_search USING fts5(
+    email_id UNINDEXED,
+    from_addr,
+    to_addr,
+    subject
+);
+
+INSERT INTO email_search (rowid, email_id, from_addr, to_addr, subject)
+SELECT rowid, id, from_addr, to_addr, subject FROM emails;
+
+CREATE TRIGGER IF NOT EXISTS emails_search_insert
+AFTER INSERT ON emails
+FOR EACH ROW
+BEGIN
+    INSERT INTO email_search (rowid, email_id, from_addr, to_addr, subject)
+    VALUES (NEW.rowid, NEW.id, NEW.from_addr, NEW.to_addr, NEW.subject);
+END;
+
+CREATE TRIGGER IF NOT EXISTS emails_search_delete
+AFTER DELETE ON emails
+FOR EACH ROW
+BEGIN
+    DELETE FROM email_search WHERE rowid = OLD.rowid;
+END;
@@ -0,0 +1,2 @@
+-- Most mailboxes a user may own, unlimited when NULL
+ALTER TABLE user_settings ADD COLUMN max_mailboxes INTEGER DEFAULT 20;
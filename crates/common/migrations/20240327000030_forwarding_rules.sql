@@ -0,0 +1,13 @@
+-- Copies of a mailbox's received emails sent to a webhook URL or saved in
+-- another mailbox; the filters are glob patterns, NULL matching everything
+CREATE TABLE IF NOT EXISTS forwarding_rules (
+    id TEXT PRIMARY KEY,
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    kind TEXT NOT NULL CHECK(kind IN ('webhook', 'mailbox')),
+    destination TEXT NOT NULL,
+    filter_from TEXT,
+    filter_subject TEXT,
+    created_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_forwarding_rules_mailbox ON forwarding_rules(mailbox_id);
@@ -0,0 +1,16 @@
+-- Security-sensitive actions taken by users. Entries are kept when the user
+-- is deleted, so there is no foreign key on user_id.
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL,
+    action TEXT NOT NULL,
+    resource_type TEXT,
+    resource_id TEXT,
+    ip TEXT,
+    user_agent TEXT,
+    occurred_at INTEGER NOT NULL,
+    metadata JSON
+);
+
+CREATE INDEX IF NOT EXISTS idx_audit_log_user_time
+ON audit_log(user_id, occurred_at);
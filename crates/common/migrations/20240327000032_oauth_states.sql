@@ -0,0 +1,12 @@
+-- Pending OAuth authorizations, keyed by the SHA-256 of the CSRF token sent
+-- as the `state` parameter. The callback uses each once, exchanging the code
+-- with the PKCE verifier.
+CREATE TABLE IF NOT EXISTS oauth_states (
+    state_hash TEXT PRIMARY KEY,
+    provider TEXT NOT NULL,
+    code_verifier TEXT NOT NULL,
+    redirect_to TEXT,
+    user_id TEXT,
+    action TEXT,
+    expires_at INTEGER NOT NULL
+);
@@ -0,0 +1,13 @@
+-- Public keys emails of a mailbox are encrypted to; the one labelled
+-- 'primary' mirrors mailboxes.public_key
+CREATE TABLE IF NOT EXISTS mailbox_keys (
+    mailbox_id TEXT NOT NULL REFERENCES mailboxes(id) ON DELETE CASCADE,
+    label TEXT NOT NULL,
+    public_key TEXT NOT NULL,
+    key_type TEXT NOT NULL DEFAULT 'age_x25519',
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (mailbox_id, label)
+);
+
+INSERT OR IGNORE INTO mailbox_keys (mailbox_id, label, public_key, key_type, added_at)
+SELECT id, 'primary', public_key, key_type, created_at FROM mailboxes;
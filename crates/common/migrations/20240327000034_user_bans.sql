@@ -0,0 +1,2 @@
+-- Set by the admin API; banned users can no longer sign in
+ALTER TABLE users ADD COLUMN banned_at INTEGER;
@@ -0,0 +1,2 @@
+-- Size of the message as received, before encryption; 0 for emails stored before it was recorded
+ALTER TABLE emails ADD COLUMN raw_size_bytes INTEGER NOT NULL DEFAULT 0;
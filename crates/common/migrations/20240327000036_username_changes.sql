@@ -0,0 +1,2 @@
+-- Users may change their username once per day
+ALTER TABLE users ADD COLUMN username_changed_at INTEGER;
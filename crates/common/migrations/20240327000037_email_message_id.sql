@@ -0,0 +1,4 @@
+-- Retransmissions of a message already in the mailbox are dropped. Emails
+-- stored before this have no Message-ID, and NULLs never conflict.
+ALTER TABLE emails ADD COLUMN message_id TEXT;
+CREATE UNIQUE INDEX idx_emails_mailbox_message_id ON emails(mailbox_id, message_id);
@@ -0,0 +1,17 @@
+-- Access tokens issued, by their JWT ID, so every session of a user can be revoked
+CREATE TABLE IF NOT EXISTS issued_tokens (
+    jti TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    issued_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_issued_tokens_user ON issued_tokens(user_id, expires_at);
+
+-- Access tokens rejected before they expire; kept until then
+CREATE TABLE IF NOT EXISTS token_revocations (
+    jti TEXT PRIMARY KEY,
+    user_id TEXT NOT NULL REFERENCES users(id) ON DELETE CASCADE,
+    revoked_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
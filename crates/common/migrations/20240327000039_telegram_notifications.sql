@@ -0,0 +1,7 @@
+-- Chat new-email notifications are sent to; for the private chat with the
+-- bot it is the Telegram user ID
+ALTER TABLE user_credentials ADD COLUMN telegram_chat_id TEXT;
+UPDATE user_credentials SET telegram_chat_id = telegram_id WHERE telegram_id IS NOT NULL;
+
+-- When the owner was last notified of an email in the mailbox
+ALTER TABLE mailboxes ADD COLUMN last_notification_sent_at INTEGER;
@@ -0,0 +1,4 @@
+-- Message-ID of the bounced message, so a retransmitted DSN is only recorded
+-- once per recipient
+ALTER TABLE bounces ADD COLUMN message_id TEXT;
+CREATE UNIQUE INDEX idx_bounces_message_recipient ON bounces(message_id, original_recipient);
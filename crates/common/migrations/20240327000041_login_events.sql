@@ -0,0 +1,13 @@
+-- Successful logins with where they came from, for the security log and the
+-- new-country check. Location columns stay NULL without a GeoIP database.
+CREATE TABLE IF NOT EXISTS login_events (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id TEXT NOT NULL,
+    ip TEXT,
+    country_code TEXT,
+    city TEXT,
+    logged_in_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_login_events_user_time
+ON login_events(user_id, logged_in_at);
@@ -0,0 +1,3 @@
+-- HMAC of the `X-Signature` header, chosen by the webhook owner
+ALTER TABLE webhooks ADD COLUMN signature_algorithm TEXT NOT NULL DEFAULT 'sha256'
+    CHECK (signature_algorithm IN ('sha256', 'sha512'));
@@ -0,0 +1,2 @@
+-- Tera template rendered in place of the default payload, NULL for that one
+ALTER TABLE webhooks ADD COLUMN payload_template TEXT;
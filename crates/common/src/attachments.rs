@@ -0,0 +1,117 @@
+use mail_parser::{Message, MimeHeaders};
+
+/// An attachment taken out of an email, before encryption
+#[derive(Debug, Clone)]
+pub struct ExtractedAttachment {
+    pub id: String,
+    pub filename: Option<String>,
+    pub mime_type: String,
+    /// Decoded content of the part
+    pub content: Vec<u8>,
+}
+
+/// Removes the attachments of `message` from its raw form. Each attachment
+/// part is replaced by a `message/external-body` part that keeps the original
+/// part headers and names the attachment by ID, so clients can tell what was
+/// stripped and fetch it separately.
+pub fn strip_attachments(message: &Message<'_>) -> (Vec<u8>, Vec<ExtractedAttachment>) {
+    let raw = message.raw_message.as_ref();
+    let mut body = Vec::with_capacity(raw.len());
+    let mut attachments = Vec::new();
+    let mut copied_up_to = 0;
+
+    let mut part_ids: Vec<usize> = message.attachments.iter()
+        .copied()
+        // The top-level part holds the message headers and inline bodies
+        // are shown to the reader, so neither is stripped
+        .filter(|&id| id != 0 && !message.text_body.contains(&id) && !message.html_body.contains(&id))
+        .collect();
+    part_ids.sort_unstable();
+
+    for id in part_ids {
+        let Some(part) = message.parts.get(id) else {
+            continue;
+        };
+        if part.offset_header < copied_up_to || part.offset_end > raw.len() {
+            continue;
+        }
+
+        let attachment = ExtractedAttachment {
+            id: uuid::Uuid::new_v4().to_string(),
+            filename: part.attachment_name().map(str::to_string),
+            mime_type: part.content_type()
+                .map(|content_type| match content_type.subtype() {
+                    Some(subtype) => format!("{}/{}", content_type.ctype(), subtype),
+                    None => content_type.ctype().to_string(),
+                })
+                .unwrap_or_else(|| "application/octet-stream".to_string())
+                .to_ascii_lowercase(),
+            content: part.contents().to_vec(),
+        };
+
+        body.extend_from_slice(&raw[copied_up_to..part.offset_header]);
+        body.extend_from_slice(format!(
+            "Content-Type: message/external-body; access-type=\"x-attachment-id\"; attachment-id=\"{}\"\r\n\r\n",
+            attachment.id
+        ).as_bytes());
+        body.extend_from_slice(&raw[part.offset_header..part.offset_body]);
+        copied_up_to = part.offset_end;
+
+        attachments.push(attachment);
+    }
+
+    body.extend_from_slice(&raw[copied_up_to..]);
+    (body, attachments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const EMAIL: &str = "From: sender@example.com\r\n\
+        To: inbox@example.com\r\n\
+        Subject: Report\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+        \r\n\
+        --b1\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        See the attached report.\r\n\
+        --b1\r\n\
+        Content-Type: application/pdf; name=\"report.pdf\"\r\n\
+        Content-Disposition: attachment; filename=\"report.pdf\"\r\n\
+        Content-Transfer-Encoding: base64\r\n\
+        \r\n\
+        JVBERi0xLjQK\r\n\
+        --b1--\r\n";
+
+    #[test]
+    fn test_strip_attachments() {
+        let message = Message::parse(EMAIL.as_bytes()).unwrap();
+        let (body, attachments) = strip_attachments(&message);
+
+        assert_eq!(attachments.len(), 1);
+        let attachment = &attachments[0];
+        assert_eq!(attachment.filename.as_deref(), Some("report.pdf"));
+        assert_eq!(attachment.mime_type, "application/pdf");
+        assert_eq!(attachment.content, b"%PDF-1.4\n");
+
+        let stripped = Message::parse(&body).unwrap();
+        assert_eq!(stripped.body_text(0).as_deref(), Some("See the attached report."));
+        assert!(!String::from_utf8_lossy(&body).contains("JVBERi0xLjQK"));
+        let placeholder = stripped.attachment(0).unwrap();
+        assert_eq!(placeholder.content_type().unwrap().subtype(), Some("external-body"));
+        assert_eq!(placeholder.content_type().unwrap().attribute("attachment-id"), Some(attachment.id.as_str()));
+    }
+
+    #[test]
+    fn test_plain_email_is_unchanged() {
+        let email = "From: sender@example.com\r\nSubject: Hi\r\n\r\nHello\r\n";
+        let message = Message::parse(email.as_bytes()).unwrap();
+        let (body, attachments) = strip_attachments(&message);
+
+        assert!(attachments.is_empty());
+        assert_eq!(body, email.as_bytes());
+    }
+}
@@ -0,0 +1,212 @@
+use dashmap::DashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::AppError;
+
+pub const DEFAULT_FAILURE_THRESHOLD: u32 = 5;
+pub const DEFAULT_RESET_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CircuitState {
+    /// Requests pass through
+    Closed,
+    /// Requests fail immediately until the reset timeout elapses
+    Open,
+    /// A single trial request decides whether to close or reopen
+    HalfOpen,
+}
+
+#[derive(Debug)]
+pub struct CircuitBreakerState {
+    state: CircuitState,
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+    trial_in_flight: bool,
+}
+
+impl CircuitBreakerState {
+    fn new() -> Self {
+        Self {
+            state: CircuitState::Closed,
+            consecutive_failures: 0,
+            opened_at: None,
+            trial_in_flight: false,
+        }
+    }
+
+    pub fn state(&self) -> CircuitState {
+        self.state
+    }
+}
+
+/// Guards outbound HTTP calls with one circuit per destination host, so a
+/// provider that keeps failing or hanging is skipped instead of tying up tasks.
+/// Transport errors, 5xx and 429 responses count as failures.
+pub struct CircuitBreaker {
+    failure_threshold: u32,
+    reset_timeout: Duration,
+    circuits: DashMap<String, Arc<Mutex<CircuitBreakerState>>>,
+}
+
+impl Default for CircuitBreaker {
+    fn default() -> Self {
+        Self::new(DEFAULT_FAILURE_THRESHOLD, DEFAULT_RESET_TIMEOUT)
+    }
+}
+
+impl CircuitBreaker {
+    pub fn new(failure_threshold: u32, reset_timeout: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            reset_timeout,
+            circuits: DashMap::new(),
+        }
+    }
+
+    /// Current state of the circuit for `domain`
+    pub fn state(&self, domain: &str) -> CircuitState {
+        self.circuits
+            .get(domain)
+            .map_or(CircuitState::Closed, |circuit| circuit.lock().unwrap().state())
+    }
+
+    /// Sends the request unless the circuit of its host is open
+    pub async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, AppError> {
+        let (client, request) = request.build_split();
+        let request = request.map_err(|e| AppError::Internal(format!("Invalid request: {}", e)))?;
+        let domain = request.url().host_str().unwrap_or_default().to_string();
+
+        let circuit = self.circuit(&domain);
+        if !self.try_acquire(&circuit) {
+            return Err(AppError::Internal(format!(
+                "Requests to {} are temporarily suspended after repeated failures",
+                domain
+            )));
+        }
+
+        let result = client.execute(request).await;
+        let failed = match &result {
+            Ok(response) => response.status().is_server_error()
+                || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS,
+            Err(_) => true,
+        };
+        self.record(&circuit, &domain, !failed);
+
+        result.map_err(|e| AppError::Internal(format!("Request to {} failed: {}", domain, e)))
+    }
+
+    fn circuit(&self, domain: &str) -> Arc<Mutex<CircuitBreakerState>> {
+        self.circuits
+            .entry(domain.to_string())
+            .or_insert_with(|| Arc::new(Mutex::new(CircuitBreakerState::new())))
+            .clone()
+    }
+
+    fn try_acquire(&self, circuit: &Mutex<CircuitBreakerState>) -> bool {
+        let mut circuit = circuit.lock().unwrap();
+        let timed_out = circuit.opened_at.is_some_and(|at| at.elapsed() >= self.reset_timeout);
+        // A trial whose future was dropped never reports back, so it is
+        // replaced once the timeout elapses again
+        let allowed = match circuit.state {
+            CircuitState::Closed => return true,
+            CircuitState::Open => timed_out,
+            CircuitState::HalfOpen => !circuit.trial_in_flight || timed_out,
+        };
+        if allowed {
+            circuit.state = CircuitState::HalfOpen;
+            circuit.trial_in_flight = true;
+            circuit.opened_at = Some(Instant::now());
+        }
+        allowed
+    }
+
+    fn record(&self, circuit: &Mutex<CircuitBreakerState>, domain: &str, success: bool) {
+        let mut circuit = circuit.lock().unwrap();
+        circuit.trial_in_flight = false;
+
+        if success {
+            circuit.state = CircuitState::Closed;
+            circuit.consecutive_failures = 0;
+            circuit.opened_at = None;
+            return;
+        }
+
+        circuit.consecutive_failures += 1;
+        if circuit.state == CircuitState::HalfOpen || circuit.consecutive_failures >= self.failure_threshold {
+            if circuit.state != CircuitState::Open {
+                warn!(
+                    "Opening circuit for {} after {} consecutive failures",
+                    domain, circuit.consecutive_failures
+                );
+            }
+            circuit.state = CircuitState::Open;
+            circuit.opened_at = Some(Instant::now());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fail(breaker: &CircuitBreaker, circuit: &Mutex<CircuitBreakerState>) {
+        assert!(breaker.try_acquire(circuit));
+        breaker.record(circuit, "example.com", false);
+    }
+
+    #[test]
+    fn test_opens_after_threshold() {
+        let breaker = CircuitBreaker::new(3, Duration::from_secs(60));
+        let circuit = breaker.circuit("example.com");
+
+        fail(&breaker, &circuit);
+        fail(&breaker, &circuit);
+        assert_eq!(breaker.state("example.com"), CircuitState::Closed);
+        fail(&breaker, &circuit);
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+        assert!(!breaker.try_acquire(&circuit));
+
+        // Other hosts are unaffected
+        assert_eq!(breaker.state("other.com"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_success_resets_failures() {
+        let breaker = CircuitBreaker::new(2, Duration::from_secs(60));
+        let circuit = breaker.circuit("example.com");
+
+        fail(&breaker, &circuit);
+        assert!(breaker.try_acquire(&circuit));
+        breaker.record(&circuit, "example.com", true);
+        fail(&breaker, &circuit);
+        assert_eq!(breaker.state("example.com"), CircuitState::Closed);
+    }
+
+    #[test]
+    fn test_half_open_allows_single_trial() {
+        let reset_timeout = Duration::from_millis(50);
+        let breaker = CircuitBreaker::new(1, reset_timeout);
+        let circuit = breaker.circuit("example.com");
+
+        fail(&breaker, &circuit);
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        // After the timeout one trial request is let through
+        std::thread::sleep(reset_timeout);
+        assert!(breaker.try_acquire(&circuit));
+        assert_eq!(breaker.state("example.com"), CircuitState::HalfOpen);
+        assert!(!breaker.try_acquire(&circuit));
+
+        // A failed trial reopens the circuit
+        breaker.record(&circuit, "example.com", false);
+        assert_eq!(breaker.state("example.com"), CircuitState::Open);
+
+        // A successful trial closes it
+        std::thread::sleep(reset_timeout);
+        assert!(breaker.try_acquire(&circuit));
+        breaker.record(&circuit, "example.com", true);
+        assert_eq!(breaker.state("example.com"), CircuitState::Closed);
+    }
+}
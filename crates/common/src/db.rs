@@ -1,8 +1,17 @@
-use crate::{ApiKey, AppError, AuthType, Email, Mailbox, User, UserSettings};
+use crate::greylist::GreylistStatus;
+use crate::{
+    ApiKey, ApiKeyEndpointUsage, ApiKeyScope, ApiKeyUsage, ApiKeyUsageBucket, AppError, AuditEntry, AuthType, BackupKey, Bounce,
+    CleanupPolicy, Email, EmailAttachment, EmailFilter, EmailMetadata, FeatureFlag, ForwardingRule, InstanceStats, LoginEvent, Mailbox, MailboxAlias, MailboxKey, MailboxStorageStats, MailboxWithStats, OrgRole, Organization, OrganizationMember, SignatureAlgorithm, User, UserEmailStats, UserSettings, Webhook,
+};
 use async_trait::async_trait;
-use sqlx::{migrate::MigrateDatabase, sqlite::SqlitePool, Row, Sqlite};
-use std::{future::Future, sync::Arc};
-use tracing::info;
+use futures::{stream::BoxStream, StreamExt};
+use sqlx::{
+    migrate::MigrateDatabase,
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqliteSynchronous},
+    ConnectOptions, Connection, Row, Sqlite,
+};
+use std::{collections::HashMap, future::Future, str::FromStr, sync::Arc, time::{Duration, Instant}};
+use tracing::{info, warn};
 use rand::{rngs::OsRng, Rng};
 
 #[async_trait]
@@ -14,10 +23,27 @@ pub trait Database: Send + Sync {
     // User operations
     async fn create_user(&self, username: &str, auth_type: AuthType) -> Result<User, AppError>;
     async fn get_user(&self, user_id: &str) -> Result<Option<User>, AppError>;
+    async fn get_backup_key(&self, user_id: &str) -> Result<Option<BackupKey>, AppError>;
+    /// Stores the backup key unless the user already has one, returning whether it was stored
+    async fn set_backup_key(&self, user_id: &str, backup_key: &BackupKey) -> Result<bool, AppError>;
+    /// Users whose username contains `search`, oldest first, `page` counting
+    /// from 1, with the number of users matching
+    async fn list_users(&self, search: Option<&str>, page: i64, per_page: i64) -> Result<(Vec<User>, i64), AppError>;
+    /// Deletes the user with their mailboxes, emails, API keys and the
+    /// organizations they own, returning whether the user existed
+    async fn delete_user(&self, user_id: &str) -> Result<bool, AppError>;
+    /// Marks the user banned unless they already are, returning whether the user exists
+    async fn ban_user(&self, user_id: &str, banned_at: i64) -> Result<bool, AppError>;
+    async fn get_instance_stats(&self) -> Result<InstanceStats, AppError>;
 
     // User settings operations
     async fn get_user_settings(&self, user_id: &str) -> Result<Option<UserSettings>, AppError>;
     async fn update_user_settings(&self, settings: &UserSettings) -> Result<(), AppError>;
+    async fn get_user_email_stats(&self, user_id: &str) -> Result<UserEmailStats, AppError>;
+    /// Raw size of each mailbox the user owns, empty ones included
+    async fn get_mailbox_storage_stats(&self, owner_id: &str) -> Result<Vec<MailboxStorageStats>, AppError>;
+    async fn get_email_cleanup_policies(&self) -> Result<Vec<(String, CleanupPolicy)>, AppError>;
+    async fn apply_email_cleanup_policy(&self, user_id: &str, policy: &CleanupPolicy) -> Result<u64, AppError>;
 
     // Mailbox operations
     async fn create_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError>;
@@ -25,25 +51,538 @@ pub trait Database: Send + Sync {
     async fn get_mailbox_by_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError>;
     async fn get_mailbox_by_incoming_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError>;
     async fn get_mailboxes_by_owner(&self, owner_id: &str) -> Result<Vec<Mailbox>, AppError>;
+    /// The user's own mailboxes and those shared with their organizations
+    async fn get_accessible_mailboxes(&self, user_id: &str) -> Result<Vec<Mailbox>, AppError>;
+    /// The mailbox with its email count and the emails `user_id` has not seen
+    async fn get_mailbox_with_stats(&self, mailbox_id: &str, user_id: &str) -> Result<Option<MailboxWithStats>, AppError>;
+    /// [`Database::get_mailboxes_by_owner`] with the counts of
+    /// [`Database::get_mailbox_with_stats`], in one query
+    async fn get_mailboxes_by_owner_with_stats(&self, owner_id: &str) -> Result<Vec<MailboxWithStats>, AppError>;
+    /// Number of emails stored in the mailbox
+    async fn get_mailbox_email_count(&self, mailbox_id: &str) -> Result<u64, AppError>;
+    /// [`Database::get_mailbox_email_count`] of each mailbox in one query,
+    /// zero for mailboxes without emails
+    async fn get_mailboxes_email_counts(&self, mailbox_ids: &[String]) -> Result<HashMap<String, u64>, AppError>;
+    /// Records that the user listed the mailbox's emails, resetting its unread count
+    async fn mark_mailbox_viewed(&self, mailbox_id: &str, user_id: &str, viewed_at: i64) -> Result<(), AppError>;
+    /// Number of mailboxes listed for the user and their latest `last_modified_at` in milliseconds
+    async fn get_mailbox_list_version(&self, user_id: &str) -> Result<(i64, Option<i64>), AppError>;
     async fn delete_mailbox(&self, mailbox_id: &str) -> Result<(), AppError>;
+    async fn count_mailboxes(&self) -> Result<i64, AppError>;
+    /// Sum of the raw sizes of the mailbox's emails
+    async fn get_mailbox_total_size(&self, mailbox_id: &str) -> Result<i64, AppError>;
     async fn cleanup_expired_mailboxes(&self) -> Result<(), AppError>;
     async fn update_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError>;
+    /// Changes the primary alias, failing with a UNIQUE constraint error when
+    /// it is taken
+    async fn update_mailbox_alias(&self, mailbox_id: &str, alias: &str) -> Result<(), AppError>;
+    /// Replaces the primary alias like [`Database::update_mailbox_alias`],
+    /// keeping the old one retired until `retired_until`
+    async fn rotate_mailbox_alias(&self, mailbox_id: &str, alias: &str, rotated_at: i64, retired_until: i64) -> Result<(), AppError>;
+    /// Whether the alias was rotated away before `now` expired its retirement
+    /// and no mailbox has taken it since
+    async fn is_alias_retired(&self, alias: &str, now: i64) -> Result<bool, AppError>;
+    /// Forgets retired aliases whose retirement ended before `now`
+    async fn cleanup_old_aliases(&self, now: i64) -> Result<u64, AppError>;
+
+    // Mailbox alias operations
+    async fn get_mailbox_aliases(&self, mailbox_id: &str) -> Result<Vec<MailboxAlias>, AppError>;
+    async fn create_mailbox_alias(&self, alias: &MailboxAlias) -> Result<(), AppError>;
+    /// Removes a secondary alias of the mailbox, returning whether one was removed
+    async fn delete_mailbox_alias(&self, mailbox_id: &str, alias_id: &str) -> Result<bool, AppError>;
+
+    // Mailbox key operations
+    /// Fails with a UNIQUE constraint error when the label is taken
+    async fn add_mailbox_key(&self, key: &MailboxKey) -> Result<(), AppError>;
+    /// Removes an additional key of the mailbox, returning whether one was
+    /// removed. The primary key is only replaced through [`Database::update_mailbox`].
+    async fn remove_mailbox_key(&self, mailbox_id: &str, label: &str) -> Result<bool, AppError>;
+    /// Every key of the mailbox, the primary one first
+    async fn list_mailbox_keys(&self, mailbox_id: &str) -> Result<Vec<MailboxKey>, AppError>;
+
+    // Webhook operations
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<(), AppError>;
+    async fn get_mailbox_webhooks(&self, mailbox_id: &str) -> Result<Vec<Webhook>, AppError>;
+    /// Removes a webhook of the mailbox, returning whether one was removed
+    async fn delete_webhook(&self, mailbox_id: &str, webhook_id: &str) -> Result<bool, AppError>;
+    /// Replaces the secret and signature algorithm of a webhook of the
+    /// mailbox, returning whether there was one
+    async fn rotate_webhook_secret(&self, mailbox_id: &str, webhook_id: &str, secret: &str, signature_algorithm: SignatureAlgorithm) -> Result<bool, AppError>;
+    /// Records the outcome of a delivery, `status` being `None` when no response was received
+    async fn update_webhook_status(&self, webhook_id: &str, triggered_at: i64, status: Option<i64>) -> Result<(), AppError>;
+
+    // Notification operations
+    /// The Telegram chat new-email notifications of the user go to, if they linked Telegram
+    async fn get_telegram_chat_id(&self, user_id: &str) -> Result<Option<String>, AppError>;
+    /// Records a notification about the mailbox sent at `now` unless one was
+    /// within `cooldown_secs`, returning whether it may be sent
+    async fn claim_mailbox_notification(&self, mailbox_id: &str, now: i64, cooldown_secs: i64) -> Result<bool, AppError>;
+
+    // Forwarding rule operations
+    async fn create_forwarding_rule(&self, rule: &ForwardingRule) -> Result<(), AppError>;
+    async fn get_forwarding_rules(&self, mailbox_id: &str) -> Result<Vec<ForwardingRule>, AppError>;
+    /// Removes a forwarding rule of the mailbox, returning whether one was removed
+    async fn delete_forwarding_rule(&self, mailbox_id: &str, rule_id: &str) -> Result<bool, AppError>;
 
     // Email operations
-    async fn save_email(&self, email: &Email) -> Result<(), AppError>;
+    /// Saves the email unless the mailbox already has one with the same
+    /// `message_id`, returning whether it was saved
+    async fn save_email(&self, email: &Email) -> Result<bool, AppError>;
+    async fn email_exists_by_message_id(&self, mailbox_id: &str, message_id: &str) -> Result<bool, AppError>;
     async fn get_email(&self, email_id: &str) -> Result<Option<Email>, AppError>;
     async fn get_mailbox_emails(&self, mailbox_id: &str) -> Result<Vec<Email>, AppError>;
+    /// Emails of the mailbox received within `[since, until]`, newest first
+    async fn get_mailbox_emails_in_range(
+        &self,
+        mailbox_id: &str,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<Vec<Email>, AppError>;
+    /// Up to `limit` emails of the mailbox received within `[since, until]`,
+    /// oldest first, starting after the `(received_at, id)` of `after`
+    async fn get_mailbox_emails_batch(
+        &self,
+        mailbox_id: &str,
+        since: Option<i64>,
+        until: Option<i64>,
+        after: Option<(i64, &str)>,
+        limit: i64,
+    ) -> Result<Vec<Email>, AppError>;
+    /// The headers captured when the email was received, `None` for emails
+    /// stored before they were captured
+    async fn get_email_headers(&self, email_id: &str) -> Result<Option<serde_json::Value>, AppError>;
+    /// Newest first, starting after the email whose ID is `cursor`, of the
+    /// emails received within `[since, until]`
+    async fn get_mailbox_email_metadata(
+        &self,
+        mailbox_id: &str,
+        limit: i64,
+        cursor: Option<&str>,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<Vec<EmailMetadata>, AppError>;
+    /// Stores the keyword search tokens of an already saved email
+    async fn save_email_search_tokens(&self, email: &Email, search_tokens: &[String]) -> Result<(), AppError>;
+    /// Emails of the mailbox indexed under the given search token
+    async fn search_mailbox_emails(&self, mailbox_id: &str, search_token: &str) -> Result<Vec<Email>, AppError>;
+    /// Emails of the mailboxes the user can access whose From, To or Subject
+    /// match an FTS5 query, newest first, `page` counting from 1
+    async fn search_emails(
+        &self,
+        user_id: &str,
+        query: &str,
+        mailbox_id: Option<&str>,
+        page: i64,
+        per_page: i64,
+    ) -> Result<Vec<Email>, AppError>;
+    /// Emails of the mailbox matching the filter, newest first
+    async fn filter_mailbox_emails(&self, mailbox_id: &str, filter: &EmailFilter) -> Result<Vec<Email>, AppError>;
+    /// Replaces the ciphertext of an email and drops its search tokens, which
+    /// were derived from the previous key
+    async fn update_email_content(&self, email_id: &str, encrypted_content: &str) -> Result<(), AppError>;
+    /// Sets when the email is deleted by the cleanup, `None` keeping it until deleted
+    async fn update_email_expiry(&self, email_id: &str, new_expires_at: Option<i64>) -> Result<(), AppError>;
+    /// Stores the attachments stripped from an already saved email
+    async fn save_email_attachments(&self, attachments: &[EmailAttachment]) -> Result<(), AppError>;
+    async fn get_email_attachment(&self, attachment_id: &str) -> Result<Option<EmailAttachment>, AppError>;
+    async fn get_email_attachments(&self, email_id: &str) -> Result<Vec<EmailAttachment>, AppError>;
+    async fn update_email_attachment_content(&self, attachment_id: &str, encrypted_content: &str) -> Result<(), AppError>;
     async fn delete_email(&self, email_id: &str) -> Result<(), AppError>;
+    /// Deletes the emails of the mailbox matching every given filter, or all of
+    /// them without filters, returning how many were deleted. IDs of emails in
+    /// other mailboxes are ignored.
+    async fn delete_emails_bulk(&self, mailbox_id: &str, ids: Option<&[String]>, before: Option<i64>) -> Result<u64, AppError>;
+    /// Deletes the mailbox's emails that are past their expiry, returning how
+    /// many were deleted
+    async fn delete_expired_emails_for_mailbox(&self, mailbox_id: &str) -> Result<u64, AppError>;
     async fn cleanup_expired_emails(&self) -> Result<(), AppError>;
 
     // API Key operations
-    async fn create_api_key(&self, user_id: &str) -> Result<ApiKey, AppError>;
+    /// `key_length` is the number of random characters after the key prefix
+    async fn create_api_key(
+        &self,
+        user_id: &str,
+        key_length: usize,
+        scopes: &[ApiKeyScope],
+        expires_in_seconds: Option<i64>,
+    ) -> Result<ApiKey, AppError>;
     async fn get_api_key(&self, key: &str) -> Result<Option<ApiKey>, AppError>;
     async fn delete_api_key(&self, key_id: &str) -> Result<(), AppError>;
+    /// Revokes every active API key of the user, returning how many were revoked
+    async fn revoke_user_api_keys(&self, user_id: &str) -> Result<u64, AppError>;
+
+    // Organization operations
+    /// Creates the organization and adds its owner as a member with the `owner` role
+    async fn create_organization(&self, organization: &Organization) -> Result<(), AppError>;
+    async fn get_organization(&self, org_id: &str) -> Result<Option<Organization>, AppError>;
+    /// Returns whether the organization exists
+    async fn set_organization_max_mailboxes(&self, org_id: &str, max_mailboxes: Option<i64>) -> Result<bool, AppError>;
+    async fn get_organizations_for_user(&self, user_id: &str) -> Result<Vec<Organization>, AppError>;
+    async fn get_organization_role(&self, org_id: &str, user_id: &str) -> Result<Option<OrgRole>, AppError>;
+    async fn add_organization_member(&self, member: &OrganizationMember) -> Result<(), AppError>;
+    async fn remove_organization_member(&self, org_id: &str, user_id: &str) -> Result<(), AppError>;
+    async fn count_organization_mailboxes(&self, org_id: &str) -> Result<i64, AppError>;
+    /// Mailboxes owned by the user, organization mailboxes included
+    async fn count_user_mailboxes(&self, owner_id: &str) -> Result<u64, AppError>;
+
+    // API key usage operations
+    async fn record_api_key_usage(&self, usage: &ApiKeyUsage) -> Result<(), AppError>;
+    /// Request counts for `[since, until)` split into `bucket_seconds` wide buckets, empty buckets included
+    async fn get_api_key_usage(
+        &self,
+        api_key_id: &str,
+        since: i64,
+        until: i64,
+        bucket_seconds: i64,
+    ) -> Result<Vec<ApiKeyUsageBucket>, AppError>;
+    async fn get_api_key_top_endpoints(
+        &self,
+        api_key_id: &str,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<ApiKeyEndpointUsage>, AppError>;
+    async fn cleanup_api_key_usage(&self, older_than: i64) -> Result<(), AppError>;
+    /// Removes failed logins made before `older_than`
+    async fn cleanup_login_attempts(&self, older_than: i64) -> Result<u64, AppError>;
+    /// Forgets the access tokens issued and revoked that expired before `now`,
+    /// returning how many rows were removed
+    async fn cleanup_expired_tokens(&self, now: i64) -> Result<u64, AppError>;
+
+    // Bounce operations
+    /// Records a bounce unless one for the same message and recipient already
+    /// is, returning whether it was stored
+    async fn save_bounce(&self, bounce: &Bounce) -> Result<bool, AppError>;
+    async fn get_bounces(&self, recipient: Option<&str>, permanent: Option<bool>) -> Result<Vec<Bounce>, AppError>;
+    /// Removes bounces recorded before `older_than`
+    async fn cleanup_bounces(&self, older_than: i64) -> Result<u64, AppError>;
+
+    // Audit log operations
+    async fn append_audit_log(&self, entry: &AuditEntry) -> Result<(), AppError>;
+    /// Entries of the user, newest first; `page` starts at 1
+    async fn get_audit_log(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<AuditEntry>, AppError>;
+
+    // Login event operations
+    async fn record_login_event(&self, event: &LoginEvent) -> Result<(), AppError>;
+    /// Logins of the user, newest first; `page` starts at 1
+    async fn get_login_events(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<LoginEvent>, AppError>;
+    /// Distinct countries the user logged in from since `since`
+    async fn get_login_countries(&self, user_id: &str, since: i64) -> Result<Vec<String>, AppError>;
+    /// Number of logins per country code, logins without a known country left out
+    async fn get_login_geography(&self) -> Result<HashMap<String, u64>, AppError>;
+
+    // Feature flag operations
+    async fn get_feature_flags(&self) -> Result<Vec<FeatureFlag>, AppError>;
+    async fn set_feature_flag(&self, flag: &FeatureFlag) -> Result<(), AppError>;
+
+    // Greylisting operations
+    /// Records the triple on first sight, and removes it once `delay_secs`
+    /// have passed since then
+    async fn greylist_check_and_insert(&self, ip: &str, from: &str, to: &str, delay_secs: i64) -> Result<GreylistStatus, AppError>;
+    async fn greylist_remove(&self, ip: &str, from: &str, to: &str) -> Result<(), AppError>;
+    /// Drops triples first seen before `older_than`, returning how many were dropped
+    async fn greylist_cleanup(&self, older_than: i64) -> Result<u64, AppError>;
 }
 
 pub struct SqliteDatabase {
     pool: SqlitePool,
+    slow_query_threshold: Duration,
+    _pool_metrics: PoolMetricsTask,
+}
+
+/// Connection PRAGMAs, read from `SQLITE_JOURNAL_MODE`, `SQLITE_SYNCHRONOUS`,
+/// `SQLITE_CACHE_SIZE`, `SQLITE_BUSY_TIMEOUT_MS` and `SQLITE_BUSY_MAX_RETRIES`,
+/// and the slow query threshold from `DATABASE_SLOW_QUERY_THRESHOLD_MS`.
+/// Defaults to WAL + NORMAL with SQLite's own cache size, a 30 second busy
+/// timeout and a 100 ms slow query threshold.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SqliteSettings {
+    pub journal_mode: SqliteJournalMode,
+    pub synchronous: SqliteSynchronous,
+    /// Page count; negative values are interpreted by SQLite as KiB
+    pub cache_size: Option<i64>,
+    /// Total time a statement waits for a lock before failing
+    pub busy_timeout: Duration,
+    /// Number of logged waits the busy timeout is split into; 0 leaves waiting to SQLite
+    pub busy_max_retries: u32,
+    /// Database methods taking longer are logged; zero disables the warning
+    pub slow_query_threshold: Duration,
+}
+
+impl Default for SqliteSettings {
+    fn default() -> Self {
+        Self {
+            journal_mode: SqliteJournalMode::Wal,
+            synchronous: SqliteSynchronous::Normal,
+            cache_size: None,
+            busy_timeout: Duration::from_secs(30),
+            busy_max_retries: 10,
+            slow_query_threshold: Duration::from_millis(100),
+        }
+    }
+}
+
+impl SqliteSettings {
+    pub fn from_env() -> Result<Self, AppError> {
+        let mut settings = Self::default();
+
+        if let Some(value) = read_env("SQLITE_JOURNAL_MODE") {
+            settings.journal_mode = value
+                .parse()
+                .map_err(|_| AppError::Database(format!("Invalid SQLITE_JOURNAL_MODE '{}', expected one of: wal, delete, truncate, persist, memory, off", value)))?;
+        }
+        if let Some(value) = read_env("SQLITE_SYNCHRONOUS") {
+            settings.synchronous = value
+                .parse()
+                .map_err(|_| AppError::Database(format!("Invalid SQLITE_SYNCHRONOUS '{}', expected one of: off, normal, full, extra", value)))?;
+        }
+        if let Some(value) = read_env("SQLITE_CACHE_SIZE") {
+            settings.cache_size = Some(
+                value
+                    .parse()
+                    .map_err(|_| AppError::Database(format!("Invalid SQLITE_CACHE_SIZE '{}', expected an integer", value)))?,
+            );
+        }
+        if let Some(value) = read_env("SQLITE_BUSY_TIMEOUT_MS") {
+            settings.busy_timeout = Duration::from_millis(
+                value
+                    .parse()
+                    .map_err(|_| AppError::Database(format!("Invalid SQLITE_BUSY_TIMEOUT_MS '{}', expected milliseconds", value)))?,
+            );
+        }
+        if let Some(value) = read_env("SQLITE_BUSY_MAX_RETRIES") {
+            settings.busy_max_retries = value
+                .parse()
+                .map_err(|_| AppError::Database(format!("Invalid SQLITE_BUSY_MAX_RETRIES '{}', expected a non-negative integer", value)))?;
+        }
+        if let Some(value) = read_env("DATABASE_SLOW_QUERY_THRESHOLD_MS") {
+            settings.slow_query_threshold = Duration::from_millis(
+                value
+                    .parse()
+                    .map_err(|_| AppError::Database(format!("Invalid DATABASE_SLOW_QUERY_THRESHOLD_MS '{}', expected milliseconds", value)))?,
+            );
+        }
+
+        Ok(settings)
+    }
+
+    /// Combinations that are valid but likely to lose data on a crash
+    pub fn warnings(&self) -> Vec<&'static str> {
+        let mut warnings = Vec::new();
+        if self.synchronous == SqliteSynchronous::Off && self.journal_mode != SqliteJournalMode::Wal {
+            warnings.push("synchronous=off without WAL can corrupt the database on power loss");
+        }
+        if matches!(self.journal_mode, SqliteJournalMode::Off | SqliteJournalMode::Memory) {
+            warnings.push("journal_mode=off/memory disables crash recovery, only use it for testing");
+        }
+        warnings
+    }
+}
+
+/// Records `db_query_duration_seconds` for a database method when dropped, so
+/// early returns and errors are timed too, and warns when it was slow
+struct QueryTimer {
+    method: &'static str,
+    started: Instant,
+    slow_threshold: Duration,
+}
+
+impl Drop for QueryTimer {
+    fn drop(&mut self) {
+        let elapsed = self.started.elapsed();
+        metrics::histogram!("db_query_duration_seconds", "method" => self.method)
+            .record(elapsed.as_secs_f64());
+        if !self.slow_threshold.is_zero() && elapsed >= self.slow_threshold {
+            warn!(method = self.method, elapsed_ms = elapsed.as_millis() as u64, "Slow database query");
+        }
+    }
+}
+
+/// How often the pool gauges are refreshed
+const POOL_METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Records the `db_pool_size` and `db_pool_idle` gauges until dropped with its
+/// database. sqlx 0.7 doesn't expose how many tasks wait for a connection.
+struct PoolMetricsTask(tokio::task::JoinHandle<()>);
+
+impl PoolMetricsTask {
+    fn spawn(pool: SqlitePool) -> Self {
+        Self(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(POOL_METRICS_INTERVAL);
+            loop {
+                interval.tick().await;
+                metrics::gauge!("db_pool_size").set(pool.size() as f64);
+                metrics::gauge!("db_pool_idle").set(pool.num_idle() as f64);
+            }
+        }))
+    }
+}
+
+impl Drop for PoolMetricsTask {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+fn record_deleted_emails(count: u64) {
+    metrics::counter!("emails_deleted_total").increment(count);
+}
+
+/// Converts a query error, replacing lock timeouts with a message fit for users
+pub fn database_error(e: sqlx::Error) -> AppError {
+    if is_busy_error(&e) {
+        warn!("SQLite busy, giving up: {}", e);
+        return AppError::Database("Database temporarily unavailable - try again shortly".to_string());
+    }
+    AppError::Database(e.to_string())
+}
+
+fn is_busy_error(e: &sqlx::Error) -> bool {
+    // SQLITE_BUSY (5) and SQLITE_LOCKED (6), including their extended codes
+    matches!(
+        e.as_database_error()
+            .and_then(|e| e.code())
+            .and_then(|code| code.parse::<i32>().ok())
+            .map(|code| code & 0xff),
+        Some(5 | 6)
+    )
+}
+
+/// Retry policy of the SQLite busy handler
+#[derive(Debug)]
+struct BusyPolicy {
+    max_retries: u32,
+    wait: Duration,
+}
+
+/// Called by SQLite on the connection's worker thread while a lock is held
+/// elsewhere; returning 0 makes the statement fail with SQLITE_BUSY
+extern "C" fn busy_handler(policy: *mut std::ffi::c_void, attempt: std::ffi::c_int) -> std::ffi::c_int {
+    // SAFETY: the pointer comes from a leaked `&'static BusyPolicy` in `install_busy_handler`
+    let policy = unsafe { &*(policy as *const BusyPolicy) };
+    let attempt = attempt as u32 + 1;
+    if attempt > policy.max_retries {
+        return 0;
+    }
+    warn!("SQLite busy, attempt {}/{}", attempt, policy.max_retries);
+    std::thread::sleep(policy.wait);
+    1
+}
+
+async fn install_busy_handler(
+    conn: &mut sqlx::SqliteConnection,
+    policy: &'static BusyPolicy,
+) -> Result<(), sqlx::Error> {
+    let mut handle = conn.lock_handle().await?;
+    // SAFETY: the handle is valid while locked, and the policy lives for the rest of the process
+    let rc = unsafe {
+        libsqlite3_sys::sqlite3_busy_handler(
+            handle.as_raw_handle().as_ptr(),
+            Some(busy_handler),
+            policy as *const BusyPolicy as *mut std::ffi::c_void,
+        )
+    };
+    if rc != libsqlite3_sys::SQLITE_OK {
+        return Err(sqlx::Error::Protocol(format!("Failed to install SQLite busy handler: {}", rc)));
+    }
+    Ok(())
+}
+
+/// Applies pending migrations to the database, or with `dry_run` to an
+/// in-memory copy of it that is then discarded. Used by `--migrate-only`.
+pub async fn run_migrations_only(database_url: &str, dry_run: bool) -> Result<(), AppError> {
+    if !dry_run {
+        SqliteDatabase::new(database_url).await?;
+        info!("Migrations applied to {}", database_url);
+        return Ok(());
+    }
+
+    let applied = dry_run_migrations(database_url).await?;
+    info!("Dry run: {} pending migrations apply cleanly to a copy of {}", applied, database_url);
+    Ok(())
+}
+
+/// Copies the database into memory with the SQLite backup API and runs the
+/// migrations there, returning how many were pending
+pub async fn dry_run_migrations(database_url: &str) -> Result<usize, AppError> {
+    let connect_error = |e: sqlx::Error| AppError::Database(format!("Failed to connect to database: {}", e));
+
+    let mut source = SqliteConnectOptions::from_str(database_url)
+        .map_err(connect_error)?
+        .read_only(true)
+        .connect()
+        .await
+        .map_err(connect_error)?;
+    let mut copy = SqliteConnectOptions::from_str("sqlite::memory:")
+        .map_err(connect_error)?
+        .foreign_keys(true)
+        .connect()
+        .await
+        .map_err(connect_error)?;
+
+    copy_database(&mut source, &mut copy)
+        .await
+        .map_err(|e| AppError::Database(format!("Failed to copy database: {}", e)))?;
+    source.close().await.map_err(database_error)?;
+
+    let migrator = sqlx::migrate!("./migrations");
+    let before = count_applied_migrations(&mut copy).await?;
+    migrator
+        .run(&mut copy)
+        .await
+        .map_err(|e| AppError::Database(format!("Failed to run migrations: {}", e)))?;
+    let after = count_applied_migrations(&mut copy).await?;
+
+    Ok(after.saturating_sub(before) as usize)
+}
+
+async fn copy_database(
+    source: &mut sqlx::SqliteConnection,
+    destination: &mut sqlx::SqliteConnection,
+) -> Result<(), sqlx::Error> {
+    let mut source = source.lock_handle().await?;
+    let mut destination = destination.lock_handle().await?;
+    let source = source.as_raw_handle().as_ptr();
+    let destination = destination.as_raw_handle().as_ptr();
+
+    // SAFETY: both handles are valid and locked for the duration of the copy
+    let rc = unsafe {
+        let backup = libsqlite3_sys::sqlite3_backup_init(destination, c"main".as_ptr(), source, c"main".as_ptr());
+        if backup.is_null() {
+            libsqlite3_sys::sqlite3_errcode(destination)
+        } else {
+            let step_rc = libsqlite3_sys::sqlite3_backup_step(backup, -1);
+            let finish_rc = libsqlite3_sys::sqlite3_backup_finish(backup);
+            if step_rc == libsqlite3_sys::SQLITE_DONE { finish_rc } else { step_rc }
+        }
+    };
+    if rc != libsqlite3_sys::SQLITE_OK {
+        return Err(sqlx::Error::Protocol(format!("SQLite backup failed with code {}", rc)));
+    }
+    Ok(())
+}
+
+async fn count_applied_migrations(conn: &mut sqlx::SqliteConnection) -> Result<i64, AppError> {
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')"
+    )
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(database_error)?;
+    if !has_table {
+        return Ok(0);
+    }
+
+    sqlx::query_scalar("SELECT COUNT(*) FROM _sqlx_migrations WHERE success = 1")
+        .fetch_one(&mut *conn)
+        .await
+        .map_err(database_error)
+}
+
+fn read_env(name: &str) -> Option<String> {
+    std::env::var(name)
+        .ok()
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
 }
 
 impl SqliteDatabase {
@@ -52,6 +591,10 @@ impl SqliteDatabase {
     }
 
     pub async fn new(database_url: &str) -> Result<Self, AppError> {
+        Self::new_with_settings(database_url, SqliteSettings::from_env()?).await
+    }
+
+    pub async fn new_with_settings(database_url: &str, settings: SqliteSettings) -> Result<Self, AppError> {
         let trimmed_db_url = database_url.trim();
         let filename = trimmed_db_url.trim_start_matches("sqlite:").to_string();
         let in_memory = filename == ":memory:";
@@ -65,28 +608,74 @@ impl SqliteDatabase {
             info!("Using in-memory database");
         }
 
+        for warning in settings.warnings() {
+            warn!("SQLite configuration: {}", warning);
+        }
+
         // Configure connection options for concurrent access
-        let connect_options = sqlx::sqlite::SqliteConnectOptions::new()
+        let mut connect_options = sqlx::sqlite::SqliteConnectOptions::new()
             .filename(filename)
             .create_if_missing(true)
             .foreign_keys(true)
-            .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal) // Use WAL mode for better concurrency
-            .synchronous(sqlx::sqlite::SqliteSynchronous::Normal) // Balance between safety and performance
-            .busy_timeout(std::time::Duration::from_secs(30)); // Wait up to 30 seconds if database is locked
+            .journal_mode(settings.journal_mode)
+            .synchronous(settings.synchronous)
+            .busy_timeout(settings.busy_timeout);
+        if let Some(cache_size) = settings.cache_size {
+            connect_options = connect_options.pragma("cache_size", cache_size.to_string());
+        }
 
         // In-memory database should have a single connection, otherwise we'll have multiple independent databases for each connection
         let max_connections = if in_memory { 1 } else { 10 };
 
-        let pool = sqlx::sqlite::SqlitePoolOptions::new()
-            .max_connections(max_connections)
+        let mut pool_options = sqlx::sqlite::SqlitePoolOptions::new().max_connections(max_connections);
+        if settings.busy_max_retries > 0 {
+            // Replaces the plain busy timeout with one that logs each wait. Leaked
+            // once per database so every pooled connection can point at it.
+            let policy: &'static BusyPolicy = Box::leak(Box::new(BusyPolicy {
+                max_retries: settings.busy_max_retries,
+                wait: settings.busy_timeout / settings.busy_max_retries,
+            }));
+            pool_options = pool_options.after_connect(move |conn, _| {
+                Box::pin(install_busy_handler(conn, policy))
+            });
+        }
+
+        let pool = pool_options
             .connect_with(connect_options)
             .await
             .map_err(|e| AppError::Database(format!("Failed to connect to database: {}", e)))?;
 
-        let db = Self { pool };
+        let db = Self {
+            _pool_metrics: PoolMetricsTask::spawn(pool.clone()),
+            pool,
+            slow_query_threshold: settings.slow_query_threshold,
+        };
+        db.log_pragmas().await;
         db.init().await?;
         Ok(db)
     }
+
+    fn query_timer(&self, method: &'static str) -> QueryTimer {
+        QueryTimer { method, started: Instant::now(), slow_threshold: self.slow_query_threshold }
+    }
+
+    async fn log_pragmas(&self) {
+        let mut active = Vec::new();
+        for pragma in ["journal_mode", "synchronous", "cache_size", "foreign_keys", "busy_timeout"] {
+            let value = sqlx::query(&format!("PRAGMA {}", pragma))
+                .fetch_one(&self.pool)
+                .await
+                .and_then(|row| match row.try_get::<String, _>(0) {
+                    Ok(value) => Ok(value),
+                    Err(_) => row.try_get::<i64, _>(0).map(|v| v.to_string()),
+                });
+            match value {
+                Ok(value) => active.push(format!("{}={}", pragma, value)),
+                Err(e) => warn!("Failed to read PRAGMA {}: {}", pragma, e),
+            }
+        }
+        info!("SQLite PRAGMAs: {}", active.join(", "));
+    }
 }
 
 #[async_trait]
@@ -114,12 +703,15 @@ impl Database for SqliteDatabase {
     }
 
     async fn create_user(&self, username: &str, auth_type: AuthType) -> Result<User, AppError> {
+        let _timer = self.query_timer("create_user");
         let now = chrono::Utc::now().timestamp();
         let user = User {
             id: uuid::Uuid::new_v4().to_string(),
             username: username.to_string(),
             auth_type,
             created_at: now,
+            banned_at: None,
+            username_changed_at: None,
         };
 
         sqlx::query("INSERT INTO users (id, username, auth_type, created_at, updated_at) VALUES (?, ?, ?, ?, ?)")
@@ -130,17 +722,18 @@ impl Database for SqliteDatabase {
             .bind(now)
             .execute(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         Ok(user)
     }
 
     async fn get_user(&self, user_id: &str) -> Result<Option<User>, AppError> {
+        let _timer = self.query_timer("get_user");
         let user = sqlx::query("SELECT * FROM users WHERE id = ?")
             .bind(user_id)
             .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         match user {
             Some(row) => {
@@ -157,18 +750,161 @@ impl Database for SqliteDatabase {
                     username: row.get("username"),
                     auth_type,
                     created_at: row.get("created_at"),
+                    banned_at: row.get("banned_at"),
+                    username_changed_at: row.get("username_changed_at"),
                 }))
             }
             None => Ok(None),
         }
     }
 
+    async fn get_backup_key(&self, user_id: &str) -> Result<Option<BackupKey>, AppError> {
+        let _timer = self.query_timer("get_backup_key");
+        let row = sqlx::query(
+            "SELECT backup_public_key, backup_key_encrypted FROM user_credentials
+             WHERE user_id = ? AND backup_public_key IS NOT NULL AND backup_key_encrypted IS NOT NULL"
+        )
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(row.map(|row| BackupKey {
+            public_key: row.get("backup_public_key"),
+            encrypted_key: row.get("backup_key_encrypted"),
+        }))
+    }
+
+    async fn set_backup_key(&self, user_id: &str, backup_key: &BackupKey) -> Result<bool, AppError> {
+        let _timer = self.query_timer("set_backup_key");
+        // Users signed up without a password may have no credentials row yet
+        let result = sqlx::query(
+            "INSERT INTO user_credentials (user_id, backup_public_key, backup_key_encrypted, created_at, updated_at)
+             VALUES (?1, ?2, ?3, ?4, ?4)
+             ON CONFLICT(user_id) DO UPDATE SET
+                 backup_public_key = excluded.backup_public_key,
+                 backup_key_encrypted = excluded.backup_key_encrypted,
+                 updated_at = excluded.updated_at
+             WHERE user_credentials.backup_key_encrypted IS NULL"
+        )
+            .bind(user_id)
+            .bind(&backup_key.public_key)
+            .bind(&backup_key.encrypted_key)
+            .bind(chrono::Utc::now().timestamp())
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn list_users(&self, search: Option<&str>, page: i64, per_page: i64) -> Result<(Vec<User>, i64), AppError> {
+        let _timer = self.query_timer("list_users");
+        // LIKE wildcards in the search match literally
+        let pattern = search.map(|search| {
+            let escaped = search.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let users = sqlx::query_as::<_, User>(
+            r#"
+            SELECT * FROM users
+            WHERE ?1 IS NULL OR username LIKE ?1 ESCAPE '\'
+            ORDER BY created_at, id
+            LIMIT ?2 OFFSET ?3
+            "#,
+        )
+        .bind(&pattern)
+        .bind(per_page)
+        .bind((page - 1).max(0) * per_page)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let total: i64 = sqlx::query_scalar(r#"SELECT COUNT(*) FROM users WHERE ?1 IS NULL OR username LIKE ?1 ESCAPE '\'"#)
+            .bind(&pattern)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok((users, total))
+    }
+
+    async fn delete_user(&self, user_id: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("delete_user");
+        let mut tx = self.pool.begin().await.map_err(database_error)?;
+
+        // Only some tables cascade from users, the rest is deleted first. Emails
+        // and everything else attached to a mailbox cascade from it.
+        let owned_orgs = "SELECT id FROM organizations WHERE owner_user_id = ?1";
+        for statement in [
+            format!("DELETE FROM mailboxes WHERE owner_id = ?1 OR organization_id IN ({})", owned_orgs),
+            format!("DELETE FROM api_keys WHERE user_id = ?1 OR organization_id IN ({})", owned_orgs),
+            "DELETE FROM organizations WHERE owner_user_id = ?1".to_string(),
+            "DELETE FROM user_settings WHERE user_id = ?1".to_string(),
+            "DELETE FROM mailbox_views WHERE user_id = ?1".to_string(),
+        ] {
+            sqlx::query(&statement)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await
+                .map_err(database_error)?;
+        }
+
+        let result = sqlx::query("DELETE FROM users WHERE id = ?")
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        tx.commit().await.map_err(database_error)?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn ban_user(&self, user_id: &str, banned_at: i64) -> Result<bool, AppError> {
+        let _timer = self.query_timer("ban_user");
+        let result = sqlx::query("UPDATE users SET banned_at = COALESCE(banned_at, ?), updated_at = ? WHERE id = ?")
+            .bind(banned_at)
+            .bind(banned_at)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_instance_stats(&self) -> Result<InstanceStats, AppError> {
+        let _timer = self.query_timer("get_instance_stats");
+        let (user_count, mailbox_count, email_count): (i64, i64, i64) = sqlx::query_as(
+            "SELECT (SELECT COUNT(*) FROM users), (SELECT COUNT(*) FROM mailboxes), (SELECT COUNT(*) FROM emails)",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let database_size_bytes: i64 = sqlx::query_scalar(
+            "SELECT page_count * page_size FROM pragma_page_count(), pragma_page_size()",
+        )
+        .fetch_one(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(InstanceStats {
+            user_count,
+            mailbox_count,
+            email_count,
+            database_size_bytes,
+        })
+    }
+
     async fn get_user_settings(&self, user_id: &str) -> Result<Option<UserSettings>, AppError> {
+        let _timer = self.query_timer("get_user_settings");
         let settings = sqlx::query("SELECT * FROM user_settings WHERE user_id = ?")
             .bind(user_id)
             .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         match settings {
             Some(row) => Ok(Some(UserSettings {
@@ -176,58 +912,250 @@ impl Database for SqliteDatabase {
                 email_notifications: row.get("email_notifications"),
                 auto_delete_expired: row.get("auto_delete_expired"),
                 default_mailbox_expiry: row.get("default_mailbox_expiry"),
+                email_cleanup_policy: parse_cleanup_policy(row.get("email_cleanup_policy"))?,
+                email_count_limit: row.get("email_count_limit"),
+                total_storage_bytes_limit: row.get("total_storage_bytes_limit"),
+                api_rate_limit_per_minute: row.get("api_rate_limit_per_minute"),
+                max_mailboxes: row.get::<Option<i64>, _>("max_mailboxes").map(|limit| limit.max(0) as u64),
             })),
             None => Ok(None),
         }
     }
 
     async fn update_user_settings(&self, settings: &UserSettings) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_user_settings");
+        let policy_json = settings
+            .email_cleanup_policy
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| AppError::Internal(e.to_string()))?;
+
         sqlx::query(
             r#"
-            INSERT INTO user_settings (user_id, email_notifications, auto_delete_expired, default_mailbox_expiry)
-            VALUES (?, ?, ?, ?)
+            INSERT INTO user_settings (user_id, email_notifications, auto_delete_expired, default_mailbox_expiry, email_cleanup_policy,
+                email_count_limit, total_storage_bytes_limit, api_rate_limit_per_minute, max_mailboxes)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)
             ON CONFLICT(user_id) DO UPDATE SET
                 email_notifications = excluded.email_notifications,
                 auto_delete_expired = excluded.auto_delete_expired,
-                default_mailbox_expiry = excluded.default_mailbox_expiry
+                default_mailbox_expiry = excluded.default_mailbox_expiry,
+                email_cleanup_policy = excluded.email_cleanup_policy,
+                email_count_limit = excluded.email_count_limit,
+                total_storage_bytes_limit = excluded.total_storage_bytes_limit,
+                api_rate_limit_per_minute = excluded.api_rate_limit_per_minute,
+                max_mailboxes = excluded.max_mailboxes
             "#,
         )
         .bind(&settings.user_id)
         .bind(settings.email_notifications)
         .bind(settings.auto_delete_expired)
         .bind(settings.default_mailbox_expiry)
+        .bind(policy_json)
+        .bind(settings.email_count_limit)
+        .bind(settings.total_storage_bytes_limit)
+        .bind(settings.api_rate_limit_per_minute)
+        .bind(settings.max_mailboxes.map(|limit| i64::try_from(limit).unwrap_or(i64::MAX)))
         .execute(&self.pool)
         .await
-        .map_err(|e| AppError::Database(e.to_string()))?;
+        .map_err(database_error)?;
 
         Ok(())
     }
 
+    async fn get_user_email_stats(&self, user_id: &str) -> Result<UserEmailStats, AppError> {
+        let _timer = self.query_timer("get_user_email_stats");
+        let (email_count, email_bytes): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COALESCE(SUM(LENGTH(e.encrypted_content)), 0)
+             FROM emails e JOIN mailboxes m ON e.mailbox_id = m.id
+             WHERE m.owner_id = ?",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        // Stripped attachments are stored apart from the emails
+        let attachment_bytes: i64 = sqlx::query_scalar(
+            "SELECT COALESCE(SUM(LENGTH(a.encrypted_content)), 0)
+             FROM email_attachments a
+             JOIN emails e ON a.email_id = e.id
+             JOIN mailboxes m ON e.mailbox_id = m.id
+             WHERE m.owner_id = ?",
+        )
+        .bind(user_id)
+        .fetch_one(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(UserEmailStats {
+            email_count,
+            total_bytes: email_bytes + attachment_bytes,
+        })
+    }
+
+    async fn get_mailbox_storage_stats(&self, owner_id: &str) -> Result<Vec<MailboxStorageStats>, AppError> {
+        let _timer = self.query_timer("get_mailbox_storage_stats");
+        let rows = sqlx::query(
+            "SELECT m.id, COUNT(e.id) AS email_count, COALESCE(SUM(e.raw_size_bytes), 0) AS total_bytes
+             FROM mailboxes m LEFT JOIN emails e ON e.mailbox_id = m.id
+             WHERE m.owner_id = ?
+             GROUP BY m.id
+             ORDER BY m.created_at, m.id",
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| MailboxStorageStats {
+                mailbox_id: row.get("id"),
+                email_count: row.get::<i64, _>("email_count") as u64,
+                total_bytes: row.get("total_bytes"),
+            })
+            .collect())
+    }
+
+    async fn get_email_cleanup_policies(&self) -> Result<Vec<(String, CleanupPolicy)>, AppError> {
+        let _timer = self.query_timer("get_email_cleanup_policies");
+        let rows = sqlx::query(
+            "SELECT user_id, email_cleanup_policy FROM user_settings WHERE email_cleanup_policy IS NOT NULL",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let mut policies = Vec::with_capacity(rows.len());
+        for row in rows {
+            let user_id: String = row.get("user_id");
+            // One bad row must not stop the cleanup of every other user
+            match parse_cleanup_policy(row.get("email_cleanup_policy")) {
+                Ok(Some(policy)) => match policy.validate() {
+                    Ok(()) => policies.push((user_id, policy)),
+                    Err(e) => warn!("Skipping email cleanup policy of user {}: {}", user_id, e),
+                },
+                Ok(None) => {}
+                Err(e) => warn!("Skipping email cleanup policy of user {}: {}", user_id, e),
+            }
+        }
+        Ok(policies)
+    }
+
+    async fn apply_email_cleanup_policy(&self, user_id: &str, policy: &CleanupPolicy) -> Result<u64, AppError> {
+        let _timer = self.query_timer("apply_email_cleanup_policy");
+        let mailbox_filter = if policy.apply_to_all_mailboxes {
+            "SELECT id FROM mailboxes WHERE owner_id = ?"
+        } else {
+            "SELECT id FROM mailboxes WHERE owner_id = ? AND mail_expires_in IS NULL"
+        };
+        let mut deleted = 0;
+
+        if let Some(max_age) = policy.max_age_seconds {
+            let cutoff = chrono::Utc::now().timestamp() - max_age;
+            deleted += sqlx::query(&format!(
+                "DELETE FROM emails WHERE mailbox_id IN ({}) AND received_at < ?",
+                mailbox_filter
+            ))
+            .bind(user_id)
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+        }
+
+        if let Some(max_count) = policy.max_count {
+            let max_count = i64::try_from(max_count).unwrap_or(i64::MAX);
+            deleted += sqlx::query(&format!(
+                r#"
+                DELETE FROM emails WHERE id IN (
+                    SELECT id FROM (
+                        SELECT id, ROW_NUMBER() OVER (
+                            PARTITION BY mailbox_id ORDER BY received_at DESC, id DESC
+                        ) AS position
+                        FROM emails
+                        WHERE mailbox_id IN ({})
+                    )
+                    WHERE position > ?
+                )
+                "#,
+                mailbox_filter
+            ))
+            .bind(user_id)
+            .bind(max_count)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+        }
+        record_deleted_emails(deleted);
+
+        Ok(deleted)
+    }
+
     async fn create_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
+        let _timer = self.query_timer("create_mailbox");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
         sqlx::query(
-            "INSERT INTO mailboxes (id, alias, name, public_key, owner_id, created_at, mail_expires_in) 
-             VALUES (?, ?, ?, ?, ?, ?, ?)",
+            "INSERT OR FAIL INTO mailboxes (id, alias, name, public_key, key_type, owner_id, created_at, mail_expires_in, organization_id, strip_attachments) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
         )
         .bind(&mailbox.id)
         .bind(&mailbox.alias)
         .bind(&mailbox.name)
         .bind(&mailbox.public_key)
+        .bind(mailbox.key_type)
         .bind(&mailbox.owner_id)
         .bind(mailbox.created_at)
         .bind(mailbox.mail_expires_in)
-        .execute(&self.pool)
+        .bind(&mailbox.organization_id)
+        .bind(mailbox.strip_attachments)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| AppError::Database(e.to_string()))?;
+        .map_err(database_error)?;
+
+        // The primary alias shares the mailbox's ID. A taken alias fails
+        // either insert with a UNIQUE constraint error.
+        sqlx::query(
+            "INSERT OR FAIL INTO mailbox_aliases (id, mailbox_id, alias, created_at, is_primary) VALUES (?, ?, ?, ?, 1)",
+        )
+        .bind(&mailbox.id)
+        .bind(&mailbox.id)
+        .bind(&mailbox.alias)
+        .bind(mailbox.created_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+        sqlx::query(
+            "INSERT INTO mailbox_keys (mailbox_id, label, public_key, key_type, added_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&mailbox.id)
+        .bind(MailboxKey::PRIMARY_LABEL)
+        .bind(&mailbox.public_key)
+        .bind(mailbox.key_type)
+        .bind(mailbox.created_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+        tx.commit().await
+            .map_err(database_error)?;
 
         Ok(())
     }
 
     async fn get_mailbox(&self, mailbox_id: &str) -> Result<Option<Mailbox>, AppError> {
+        let _timer = self.query_timer("get_mailbox");
         let mailbox = sqlx::query("SELECT * FROM mailboxes WHERE id = ?")
             .bind(mailbox_id)
             .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         match mailbox {
             Some(row) => Ok(Some(Mailbox {
@@ -235,20 +1163,29 @@ impl Database for SqliteDatabase {
                 alias: row.get("alias"),
                 name: row.get("name"),
                 public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
                 owner_id: row.get("owner_id"),
                 created_at: row.get("created_at"),
                 mail_expires_in: row.get("mail_expires_in"),
+                organization_id: row.get("organization_id"),
+                strip_attachments: row.get("strip_attachments"),
+                alias_rotated_at: row.get("alias_rotated_at"),
             })),
             None => Ok(None),
         }
     }
 
     async fn get_mailbox_by_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
-        let mailbox = sqlx::query("SELECT * FROM mailboxes WHERE alias = ?")
+        let _timer = self.query_timer("get_mailbox_by_address");
+        let mailbox = sqlx::query(
+            "SELECT m.* FROM mailboxes m
+             JOIN mailbox_aliases a ON a.mailbox_id = m.id
+             WHERE a.alias = lower(?)"
+        )
             .bind(local_part)
             .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         match mailbox {
             Some(row) => Ok(Some(Mailbox {
@@ -256,28 +1193,34 @@ impl Database for SqliteDatabase {
                 alias: row.get("alias"),
                 name: row.get("name"),
                 public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
                 owner_id: row.get("owner_id"),
                 created_at: row.get("created_at"),
                 mail_expires_in: row.get("mail_expires_in"),
+                organization_id: row.get("organization_id"),
+                strip_attachments: row.get("strip_attachments"),
+                alias_rotated_at: row.get("alias_rotated_at"),
             })),
             None => Ok(None),
         }
     }
 
     async fn get_mailbox_by_incoming_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
+        let _timer = self.query_timer("get_mailbox_by_incoming_address");
         // First try exact match
         if let Some(mailbox) = self.get_mailbox_by_address(local_part).await? {
             return Ok(Some(mailbox));
         }
 
-        // Then try prefix match
+        // Then try prefix match, on primary aliases only since secondary
+        // aliases are user-chosen and may be short
         let mailbox = sqlx::query(
-            "SELECT * FROM mailboxes WHERE ? LIKE alias || '%' ORDER BY length(alias) DESC LIMIT 1"
+            "SELECT * FROM mailboxes WHERE substr(?, 1, length(alias)) = alias ORDER BY length(alias) DESC LIMIT 1"
         )
             .bind(local_part)
             .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         match mailbox {
             Some(row) => Ok(Some(Mailbox {
@@ -285,20 +1228,25 @@ impl Database for SqliteDatabase {
                 alias: row.get("alias"),
                 name: row.get("name"),
                 public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
                 owner_id: row.get("owner_id"),
                 created_at: row.get("created_at"),
                 mail_expires_in: row.get("mail_expires_in"),
+                organization_id: row.get("organization_id"),
+                strip_attachments: row.get("strip_attachments"),
+                alias_rotated_at: row.get("alias_rotated_at"),
             })),
             None => Ok(None),
         }
     }
 
     async fn get_mailboxes_by_owner(&self, owner_id: &str) -> Result<Vec<Mailbox>, AppError> {
+        let _timer = self.query_timer("get_mailboxes_by_owner");
         let mailboxes = sqlx::query("SELECT * FROM mailboxes WHERE owner_id = ?")
             .bind(owner_id)
             .fetch_all(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         Ok(mailboxes
             .into_iter()
@@ -307,275 +1255,2484 @@ impl Database for SqliteDatabase {
                 alias: row.get("alias"),
                 name: row.get("name"),
                 public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
                 owner_id: row.get("owner_id"),
                 created_at: row.get("created_at"),
                 mail_expires_in: row.get("mail_expires_in"),
+                organization_id: row.get("organization_id"),
+                strip_attachments: row.get("strip_attachments"),
+                alias_rotated_at: row.get("alias_rotated_at"),
             })
             .collect())
     }
 
-    async fn delete_mailbox(&self, mailbox_id: &str) -> Result<(), AppError> {
-        sqlx::query("DELETE FROM mailboxes WHERE id = ?")
+    async fn get_accessible_mailboxes(&self, user_id: &str) -> Result<Vec<Mailbox>, AppError> {
+        let _timer = self.query_timer("get_accessible_mailboxes");
+        let mailboxes = sqlx::query(
+            "SELECT * FROM mailboxes WHERE owner_id = ?1
+             OR organization_id IN (SELECT org_id FROM organization_members WHERE user_id = ?1)"
+        )
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(mailboxes
+            .into_iter()
+            .map(|row| Mailbox {
+                id: row.get("id"),
+                alias: row.get("alias"),
+                name: row.get("name"),
+                public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
+                owner_id: row.get("owner_id"),
+                created_at: row.get("created_at"),
+                mail_expires_in: row.get("mail_expires_in"),
+                organization_id: row.get("organization_id"),
+                strip_attachments: row.get("strip_attachments"),
+                alias_rotated_at: row.get("alias_rotated_at"),
+            })
+            .collect())
+    }
+
+    async fn get_mailbox_with_stats(&self, mailbox_id: &str, user_id: &str) -> Result<Option<MailboxWithStats>, AppError> {
+        let _timer = self.query_timer("get_mailbox_with_stats");
+        let row = sqlx::query(&format!("{} WHERE m.id = ?2 GROUP BY m.id", MAILBOX_WITH_STATS_QUERY))
+            .bind(user_id)
             .bind(mailbox_id)
-            .execute(&self.pool)
+            .fetch_optional(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
-        Ok(())
+        Ok(row.map(|row| mailbox_with_stats_from_row(&row)))
     }
 
-    async fn cleanup_expired_mailboxes(&self) -> Result<(), AppError> {
-        // Mailboxes don't expire, only their emails do
-        Ok(())
+    async fn get_mailboxes_by_owner_with_stats(&self, owner_id: &str) -> Result<Vec<MailboxWithStats>, AppError> {
+        let _timer = self.query_timer("get_mailboxes_by_owner_with_stats");
+        let rows = sqlx::query(&format!(
+            "{} WHERE m.owner_id = ?1
+             OR m.organization_id IN (SELECT org_id FROM organization_members WHERE user_id = ?1)
+             GROUP BY m.id",
+            MAILBOX_WITH_STATS_QUERY
+        ))
+            .bind(owner_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(rows.iter().map(mailbox_with_stats_from_row).collect())
     }
 
-    async fn update_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
-        sqlx::query(
-            "UPDATE mailboxes SET name = ?, public_key = ?, mail_expires_in = ? WHERE id = ?",
-        )
-        .bind(&mailbox.name)
-        .bind(&mailbox.public_key)
-        .bind(mailbox.mail_expires_in)
-        .bind(&mailbox.id)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| AppError::Database(e.to_string()))?;
+    async fn get_mailbox_email_count(&self, mailbox_id: &str) -> Result<u64, AppError> {
+        let _timer = self.query_timer("get_mailbox_email_count");
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM emails WHERE mailbox_id = ?")
+            .bind(mailbox_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)?;
 
-        Ok(())
+        Ok(count as u64)
     }
 
-    async fn save_email(&self, email: &Email) -> Result<(), AppError> {
-        sqlx::query(
-            "INSERT INTO emails (id, mailbox_id, encrypted_content, received_at, expires_at) 
-             VALUES (?, ?, ?, ?, ?)",
-        )
-        .bind(&email.id)
-        .bind(&email.mailbox_id)
-        .bind(&email.encrypted_content)
-        .bind(email.received_at)
-        .bind(email.expires_at)
-        .execute(&self.pool)
-        .await
-        .map_err(|e| AppError::Database(e.to_string()))?;
+    async fn get_mailboxes_email_counts(&self, mailbox_ids: &[String]) -> Result<HashMap<String, u64>, AppError> {
+        let _timer = self.query_timer("get_mailboxes_email_counts");
+        let mut counts: HashMap<String, u64> = mailbox_ids.iter().map(|id| (id.clone(), 0)).collect();
+        if mailbox_ids.is_empty() {
+            return Ok(counts);
+        }
 
-        Ok(())
+        let query = format!(
+            "SELECT mailbox_id, COUNT(*) AS email_count FROM emails WHERE mailbox_id IN ({}) GROUP BY mailbox_id",
+            vec!["?"; mailbox_ids.len()].join(", ")
+        );
+        let rows = mailbox_ids
+            .iter()
+            .fold(sqlx::query(&query), |query, id| query.bind(id))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        for row in rows {
+            counts.insert(row.get("mailbox_id"), row.get::<i64, _>("email_count") as u64);
+        }
+        Ok(counts)
     }
 
-    async fn get_email(&self, email_id: &str) -> Result<Option<Email>, AppError> {
-        let row = sqlx::query(
-            "SELECT id, mailbox_id, encrypted_content, received_at, expires_at FROM emails WHERE id = ?"
+    async fn mark_mailbox_viewed(&self, mailbox_id: &str, user_id: &str, viewed_at: i64) -> Result<(), AppError> {
+        let _timer = self.query_timer("mark_mailbox_viewed");
+        sqlx::query(
+            "INSERT INTO mailbox_views (mailbox_id, user_id, viewed_at) VALUES (?, ?, ?)
+             ON CONFLICT(mailbox_id, user_id) DO UPDATE SET viewed_at = excluded.viewed_at"
         )
-        .bind(email_id)
-        .fetch_optional(&self.pool)
-        .await
-        .map_err(|e| AppError::Database(format!("Failed to get email: {}", e)))?;
+            .bind(mailbox_id)
+            .bind(user_id)
+            .bind(viewed_at)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
 
-        match row {
-            Some(row) => Ok(Some(Email {
-                id: row.get("id"),
-                mailbox_id: row.get("mailbox_id"),
-                encrypted_content: row.get("encrypted_content"),
-                received_at: row.get("received_at"),
-                expires_at: row.get("expires_at"),
-            })),
-            None => Ok(None),
-        }
+        Ok(())
     }
 
-    async fn get_mailbox_emails(&self, mailbox_id: &str) -> Result<Vec<Email>, AppError> {
-        let emails = sqlx::query("SELECT * FROM emails WHERE mailbox_id = ? ORDER BY received_at DESC")
-            .bind(mailbox_id)
-            .fetch_all(&self.pool)
+    async fn get_mailbox_list_version(&self, user_id: &str) -> Result<(i64, Option<i64>), AppError> {
+        let _timer = self.query_timer("get_mailbox_list_version");
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS count, MAX(last_modified_at) AS last_modified_at FROM mailboxes
+             WHERE owner_id = ?
+             OR organization_id IN (SELECT org_id FROM organization_members WHERE user_id = ?)"
+        )
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_one(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
-        Ok(emails
-            .into_iter()
-            .map(|row| Email {
-                id: row.get("id"),
-                mailbox_id: row.get("mailbox_id"),
-                encrypted_content: row.get("encrypted_content"),
-                received_at: row.get("received_at"),
-                expires_at: row.get("expires_at"),
-            })
-            .collect())
+        Ok((row.get("count"), row.get("last_modified_at")))
     }
 
-    async fn delete_email(&self, email_id: &str) -> Result<(), AppError> {
-        sqlx::query("DELETE FROM emails WHERE id = ?")
-            .bind(email_id)
+    async fn delete_mailbox(&self, mailbox_id: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("delete_mailbox");
+        sqlx::query("DELETE FROM mailboxes WHERE id = ?")
+            .bind(mailbox_id)
             .execute(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
         Ok(())
     }
 
-    async fn cleanup_expired_emails(&self) -> Result<(), AppError> {
-        let now = chrono::Utc::now().timestamp();
-        sqlx::query("DELETE FROM emails WHERE expires_at IS NOT NULL AND expires_at < ?")
-            .bind(now)
-            .execute(&self.pool)
+    async fn count_mailboxes(&self) -> Result<i64, AppError> {
+        let _timer = self.query_timer("count_mailboxes");
+        sqlx::query_scalar("SELECT COUNT(*) FROM mailboxes")
+            .fetch_one(&self.pool)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)
+    }
 
-        Ok(())
+    async fn get_mailbox_total_size(&self, mailbox_id: &str) -> Result<i64, AppError> {
+        let _timer = self.query_timer("get_mailbox_total_size");
+        sqlx::query_scalar("SELECT COALESCE(SUM(raw_size_bytes), 0) FROM emails WHERE mailbox_id = ?")
+            .bind(mailbox_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)
     }
 
-    async fn create_api_key(&self, user_id: &str) -> Result<ApiKey, AppError> {
-        // Generate a secure random string of 32 characters using OsRng
-        let mut rng = OsRng;
-        let random_chars: String = (0..32)
-            .map(|_| {
-                let idx = rng.gen_range(0..62);
-                match idx {
-                    0..=9 => (b'0' + idx as u8) as char,
-                    10..=35 => (b'a' + (idx - 10) as u8) as char,
-                    _ => (b'A' + (idx - 36) as u8) as char,
-                }
-            })
-            .collect();
+    async fn cleanup_expired_mailboxes(&self) -> Result<(), AppError> {
+        let _timer = self.query_timer("cleanup_expired_mailboxes");
+        // Mailboxes don't expire, only their emails do
+        Ok(())
+    }
 
-        let api_key = ApiKey {
-            id: uuid::Uuid::new_v4().to_string(),
-            user_id: user_id.to_string(),
-            key: format!("vhmhpk-{}", random_chars),
-            created_at: chrono::Utc::now().timestamp(),
-            expires_at: None,
-        };
+    async fn update_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_mailbox");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
 
         sqlx::query(
-            "INSERT INTO api_keys (id, user_id, key, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+            "UPDATE mailboxes SET name = ?, public_key = ?, key_type = ?, mail_expires_in = ?, strip_attachments = ? WHERE id = ?",
         )
-        .bind(&api_key.id)
-        .bind(&api_key.user_id)
-        .bind(&api_key.key)
-        .bind(api_key.created_at)
-        .bind(api_key.expires_at)
-        .execute(&self.pool)
+        .bind(&mailbox.name)
+        .bind(&mailbox.public_key)
+        .bind(mailbox.key_type)
+        .bind(mailbox.mail_expires_in)
+        .bind(mailbox.strip_attachments)
+        .bind(&mailbox.id)
+        .execute(&mut *tx)
         .await
-        .map_err(|e| AppError::Database(e.to_string()))?;
+        .map_err(database_error)?;
+
+        sqlx::query("UPDATE mailbox_keys SET public_key = ?, key_type = ? WHERE mailbox_id = ? AND label = ?")
+            .bind(&mailbox.public_key)
+            .bind(mailbox.key_type)
+            .bind(&mailbox.id)
+            .bind(MailboxKey::PRIMARY_LABEL)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
 
-        Ok(api_key)
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn get_api_key(&self, key: &str) -> Result<Option<ApiKey>, AppError> {
-        let api_key = sqlx::query("SELECT * FROM api_keys WHERE key = ?")
-            .bind(key)
-            .fetch_optional(&self.pool)
+    async fn update_mailbox_alias(&self, mailbox_id: &str, alias: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_mailbox_alias");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        sqlx::query("UPDATE OR FAIL mailboxes SET alias = ? WHERE id = ?")
+            .bind(alias)
+            .bind(mailbox_id)
+            .execute(&mut *tx)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
-        match api_key {
-            Some(row) => Ok(Some(ApiKey {
-                id: row.get("id"),
-                user_id: row.get("user_id"),
-                key: row.get("key"),
-                created_at: row.get("created_at"),
-                expires_at: row.get("expires_at"),
-            })),
-            None => Ok(None),
-        }
+        sqlx::query("UPDATE OR FAIL mailbox_aliases SET alias = ? WHERE mailbox_id = ? AND is_primary = 1")
+            .bind(alias)
+            .bind(mailbox_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn delete_api_key(&self, key_id: &str) -> Result<(), AppError> {
-        sqlx::query("DELETE FROM api_keys WHERE id = ?")
-            .bind(key_id)
-            .execute(&self.pool)
+    async fn rotate_mailbox_alias(&self, mailbox_id: &str, alias: &str, rotated_at: i64, retired_until: i64) -> Result<(), AppError> {
+        let _timer = self.query_timer("rotate_mailbox_alias");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        let old_alias: String = sqlx::query_scalar("SELECT alias FROM mailboxes WHERE id = ?")
+            .bind(mailbox_id)
+            .fetch_optional(&mut *tx)
+            .await
+            .map_err(database_error)?
+            .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+        sqlx::query("UPDATE OR FAIL mailboxes SET alias = ?, alias_rotated_at = ? WHERE id = ?")
+            .bind(alias)
+            .bind(rotated_at)
+            .bind(mailbox_id)
+            .execute(&mut *tx)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
+
+        sqlx::query("UPDATE OR FAIL mailbox_aliases SET alias = ? WHERE mailbox_id = ? AND is_primary = 1")
+            .bind(alias)
+            .bind(mailbox_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        sqlx::query(
+            "INSERT OR REPLACE INTO old_aliases (alias, mailbox_id, retired_at, expires_at) VALUES (?, ?, ?, ?)"
+        )
+            .bind(&old_alias)
+            .bind(mailbox_id)
+            .bind(rotated_at)
+            .bind(retired_until)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        tx.commit().await
+            .map_err(database_error)?;
 
         Ok(())
     }
-}
 
-#[async_trait]
-impl<D: Database + ?Sized> Database for Arc<D> {
-    fn pool(&self) -> &SqlitePool {
-        (**self).pool()
-    }
+    async fn is_alias_retired(&self, alias: &str, now: i64) -> Result<bool, AppError> {
+        let _timer = self.query_timer("is_alias_retired");
+        let retired: Option<i64> = sqlx::query_scalar(
+            "SELECT 1 FROM old_aliases o WHERE o.alias = lower(?) AND o.expires_at > ?
+             AND NOT EXISTS (SELECT 1 FROM mailbox_aliases a WHERE a.alias = o.alias)"
+        )
+            .bind(alias)
+            .bind(now)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
 
-    async fn init(&self) -> Result<(), AppError> {
-        (**self).init().await
+        Ok(retired.is_some())
     }
 
-    async fn create_user(&self, username: &str, auth_type: AuthType) -> Result<User, AppError> {
-        (**self).create_user(username, auth_type).await
-    }
+    async fn cleanup_old_aliases(&self, now: i64) -> Result<u64, AppError> {
+        let _timer = self.query_timer("cleanup_old_aliases");
+        let result = sqlx::query("DELETE FROM old_aliases WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
 
-    async fn get_user(&self, user_id: &str) -> Result<Option<User>, AppError> {
-        (**self).get_user(user_id).await
+        Ok(result.rows_affected())
     }
 
-    async fn get_user_settings(&self, user_id: &str) -> Result<Option<UserSettings>, AppError> {
-        (**self).get_user_settings(user_id).await
-    }
+    async fn get_mailbox_aliases(&self, mailbox_id: &str) -> Result<Vec<MailboxAlias>, AppError> {
+        let _timer = self.query_timer("get_mailbox_aliases");
+        let rows = sqlx::query(
+            "SELECT id, mailbox_id, alias, created_at, is_primary FROM mailbox_aliases
+             WHERE mailbox_id = ? ORDER BY is_primary DESC, created_at"
+        )
+            .bind(mailbox_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
 
-    async fn update_user_settings(&self, settings: &UserSettings) -> Result<(), AppError> {
-        (**self).update_user_settings(settings).await
+        Ok(rows
+            .into_iter()
+            .map(|row| MailboxAlias {
+                id: row.get("id"),
+                mailbox_id: row.get("mailbox_id"),
+                alias: row.get("alias"),
+                created_at: row.get("created_at"),
+                is_primary: row.get("is_primary"),
+            })
+            .collect())
     }
 
-    async fn create_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
-        (**self).create_mailbox(mailbox).await
+    async fn create_mailbox_alias(&self, alias: &MailboxAlias) -> Result<(), AppError> {
+        let _timer = self.query_timer("create_mailbox_alias");
+        sqlx::query(
+            "INSERT INTO mailbox_aliases (id, mailbox_id, alias, created_at, is_primary) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&alias.id)
+        .bind(&alias.mailbox_id)
+        .bind(&alias.alias)
+        .bind(alias.created_at)
+        .bind(alias.is_primary)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn get_mailbox(&self, mailbox_id: &str) -> Result<Option<Mailbox>, AppError> {
-        (**self).get_mailbox(mailbox_id).await
+    async fn delete_mailbox_alias(&self, mailbox_id: &str, alias_id: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("delete_mailbox_alias");
+        let deleted = sqlx::query(
+            "DELETE FROM mailbox_aliases WHERE id = ? AND mailbox_id = ? AND is_primary = 0",
+        )
+        .bind(alias_id)
+        .bind(mailbox_id)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?
+        .rows_affected();
+
+        Ok(deleted > 0)
     }
 
-    async fn get_mailbox_by_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
-        (**self).get_mailbox_by_address(local_part).await
+    async fn add_mailbox_key(&self, key: &MailboxKey) -> Result<(), AppError> {
+        let _timer = self.query_timer("add_mailbox_key");
+        sqlx::query(
+            "INSERT INTO mailbox_keys (mailbox_id, label, public_key, key_type, added_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&key.mailbox_id)
+        .bind(&key.label)
+        .bind(&key.public_key)
+        .bind(key.key_type)
+        .bind(key.added_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn get_mailbox_by_incoming_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
-        (**self).get_mailbox_by_incoming_address(local_part).await
+    async fn remove_mailbox_key(&self, mailbox_id: &str, label: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("remove_mailbox_key");
+        let deleted = sqlx::query("DELETE FROM mailbox_keys WHERE mailbox_id = ? AND label = ? AND label != ?")
+            .bind(mailbox_id)
+            .bind(label)
+            .bind(MailboxKey::PRIMARY_LABEL)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+
+        Ok(deleted > 0)
     }
 
-    async fn get_mailboxes_by_owner(&self, owner_id: &str) -> Result<Vec<Mailbox>, AppError> {
-        (**self).get_mailboxes_by_owner(owner_id).await
+    async fn list_mailbox_keys(&self, mailbox_id: &str) -> Result<Vec<MailboxKey>, AppError> {
+        let _timer = self.query_timer("list_mailbox_keys");
+        // The primary key is read from the mailbox itself, so it is listed
+        // even for mailboxes created before keys had their own table
+        let rows = sqlx::query(
+            "SELECT id AS mailbox_id, ?2 AS label, public_key, key_type, created_at AS added_at, 0 AS position
+             FROM mailboxes WHERE id = ?1
+             UNION ALL
+             SELECT mailbox_id, label, public_key, key_type, added_at, 1 AS position
+             FROM mailbox_keys WHERE mailbox_id = ?1 AND label != ?2
+             ORDER BY position, added_at, label"
+        )
+            .bind(mailbox_id)
+            .bind(MailboxKey::PRIMARY_LABEL)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| MailboxKey {
+                mailbox_id: row.get("mailbox_id"),
+                label: row.get("label"),
+                public_key: row.get("public_key"),
+                key_type: row.get("key_type"),
+                added_at: row.get("added_at"),
+            })
+            .collect())
     }
 
-    async fn delete_mailbox(&self, mailbox_id: &str) -> Result<(), AppError> {
-        (**self).delete_mailbox(mailbox_id).await
+    async fn create_webhook(&self, webhook: &Webhook) -> Result<(), AppError> {
+        let _timer = self.query_timer("create_webhook");
+        sqlx::query(
+            "INSERT INTO webhooks (id, mailbox_id, url, secret, events, signature_algorithm, payload_template, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&webhook.id)
+        .bind(&webhook.mailbox_id)
+        .bind(&webhook.url)
+        .bind(&webhook.secret)
+        .bind(webhook.events.join(","))
+        .bind(webhook.signature_algorithm)
+        .bind(&webhook.payload_template)
+        .bind(webhook.created_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn cleanup_expired_mailboxes(&self) -> Result<(), AppError> {
-        (**self).cleanup_expired_mailboxes().await
+    async fn get_mailbox_webhooks(&self, mailbox_id: &str) -> Result<Vec<Webhook>, AppError> {
+        let _timer = self.query_timer("get_mailbox_webhooks");
+        let rows = sqlx::query(
+            "SELECT id, mailbox_id, url, secret, events, signature_algorithm, payload_template, created_at,
+                    last_triggered_at, last_status
+             FROM webhooks WHERE mailbox_id = ? ORDER BY created_at"
+        )
+            .bind(mailbox_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Webhook {
+                id: row.get("id"),
+                mailbox_id: row.get("mailbox_id"),
+                url: row.get("url"),
+                secret: row.get("secret"),
+                events: row
+                    .get::<String, _>("events")
+                    .split(',')
+                    .filter(|event| !event.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+                signature_algorithm: row.get("signature_algorithm"),
+                payload_template: row.get("payload_template"),
+                created_at: row.get("created_at"),
+                last_triggered_at: row.get("last_triggered_at"),
+                last_status: row.get("last_status"),
+            })
+            .collect())
     }
 
-    async fn update_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
-        (**self).update_mailbox(mailbox).await
+    async fn delete_webhook(&self, mailbox_id: &str, webhook_id: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("delete_webhook");
+        let deleted = sqlx::query("DELETE FROM webhooks WHERE id = ? AND mailbox_id = ?")
+            .bind(webhook_id)
+            .bind(mailbox_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+
+        Ok(deleted > 0)
     }
 
-    async fn save_email(&self, email: &Email) -> Result<(), AppError> {
-        (**self).save_email(email).await
+    async fn rotate_webhook_secret(&self, mailbox_id: &str, webhook_id: &str, secret: &str, signature_algorithm: SignatureAlgorithm) -> Result<bool, AppError> {
+        let _timer = self.query_timer("rotate_webhook_secret");
+        let updated = sqlx::query("UPDATE webhooks SET secret = ?, signature_algorithm = ? WHERE id = ? AND mailbox_id = ?")
+            .bind(secret)
+            .bind(signature_algorithm)
+            .bind(webhook_id)
+            .bind(mailbox_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+
+        Ok(updated > 0)
     }
 
-    async fn get_email(&self, email_id: &str) -> Result<Option<Email>, AppError> {
-        (**self).get_email(email_id).await
+    async fn update_webhook_status(&self, webhook_id: &str, triggered_at: i64, status: Option<i64>) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_webhook_status");
+        sqlx::query("UPDATE webhooks SET last_triggered_at = ?, last_status = ? WHERE id = ?")
+            .bind(triggered_at)
+            .bind(status)
+            .bind(webhook_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn get_mailbox_emails(&self, mailbox_id: &str) -> Result<Vec<Email>, AppError> {
-        (**self).get_mailbox_emails(mailbox_id).await
+    async fn get_telegram_chat_id(&self, user_id: &str) -> Result<Option<String>, AppError> {
+        let _timer = self.query_timer("get_telegram_chat_id");
+        let chat_id: Option<Option<String>> = sqlx::query_scalar("SELECT telegram_chat_id FROM user_credentials WHERE user_id = ?")
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(chat_id.flatten())
     }
 
-    async fn delete_email(&self, email_id: &str) -> Result<(), AppError> {
-        (**self).delete_email(email_id).await
+    async fn claim_mailbox_notification(&self, mailbox_id: &str, now: i64, cooldown_secs: i64) -> Result<bool, AppError> {
+        let _timer = self.query_timer("claim_mailbox_notification");
+        // A single statement, so concurrent deliveries can't both claim it
+        let result = sqlx::query(
+            "UPDATE mailboxes SET last_notification_sent_at = ?
+             WHERE id = ? AND (last_notification_sent_at IS NULL OR last_notification_sent_at <= ?)"
+        )
+            .bind(now)
+            .bind(mailbox_id)
+            .bind(now - cooldown_secs)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
     }
 
-    async fn cleanup_expired_emails(&self) -> Result<(), AppError> {
-        (**self).cleanup_expired_emails().await
+    async fn create_forwarding_rule(&self, rule: &ForwardingRule) -> Result<(), AppError> {
+        let _timer = self.query_timer("create_forwarding_rule");
+        sqlx::query(
+            "INSERT INTO forwarding_rules (id, mailbox_id, kind, destination, filter_from, filter_subject, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&rule.id)
+        .bind(&rule.mailbox_id)
+        .bind(rule.kind)
+        .bind(&rule.destination)
+        .bind(&rule.filter_from)
+        .bind(&rule.filter_subject)
+        .bind(rule.created_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
     }
 
-    async fn create_api_key(&self, user_id: &str) -> Result<ApiKey, AppError> {
-        (**self).create_api_key(user_id).await
+    async fn get_forwarding_rules(&self, mailbox_id: &str) -> Result<Vec<ForwardingRule>, AppError> {
+        let _timer = self.query_timer("get_forwarding_rules");
+        let rows = sqlx::query(
+            "SELECT id, mailbox_id, kind, destination, filter_from, filter_subject, created_at FROM forwarding_rules
+             WHERE mailbox_id = ? ORDER BY created_at, id"
+        )
+            .bind(mailbox_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ForwardingRule {
+                id: row.get("id"),
+                mailbox_id: row.get("mailbox_id"),
+                kind: row.get("kind"),
+                destination: row.get("destination"),
+                filter_from: row.get("filter_from"),
+                filter_subject: row.get("filter_subject"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
     }
 
-    async fn get_api_key(&self, key: &str) -> Result<Option<ApiKey>, AppError> {
-        (**self).get_api_key(key).await
+    async fn delete_forwarding_rule(&self, mailbox_id: &str, rule_id: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("delete_forwarding_rule");
+        let deleted = sqlx::query("DELETE FROM forwarding_rules WHERE id = ? AND mailbox_id = ?")
+            .bind(rule_id)
+            .bind(mailbox_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?
+            .rows_affected();
+
+        Ok(deleted > 0)
     }
 
-    async fn delete_api_key(&self, key_id: &str) -> Result<(), AppError> {
-        (**self).delete_api_key(key_id).await
+    async fn save_email(&self, email: &Email) -> Result<bool, AppError> {
+        let _timer = self.query_timer("save_email");
+        let result = sqlx::query(
+            "INSERT INTO emails (id, mailbox_id, encrypted_content, received_at, expires_at, from_addr, to_addr, subject, headers_json, raw_size_bytes, message_id) 
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (mailbox_id, message_id) DO NOTHING",
+        )
+        .bind(&email.id)
+        .bind(&email.mailbox_id)
+        .bind(&email.encrypted_content)
+        .bind(email.received_at)
+        .bind(email.expires_at)
+        .bind(&email.from_addr)
+        .bind(&email.to_addr)
+        .bind(&email.subject)
+        .bind(&email.headers_json)
+        .bind(email.raw_size_bytes)
+        .bind(&email.message_id)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn email_exists_by_message_id(&self, mailbox_id: &str, message_id: &str) -> Result<bool, AppError> {
+        let _timer = self.query_timer("email_exists_by_message_id");
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM emails WHERE mailbox_id = ? AND message_id = ?)")
+            .bind(mailbox_id)
+            .bind(message_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)
+    }
+
+    async fn get_email(&self, email_id: &str) -> Result<Option<Email>, AppError> {
+        let _timer = self.query_timer("get_email");
+        let row = sqlx::query(
+            "SELECT id, mailbox_id, encrypted_content, received_at, expires_at, from_addr, to_addr, subject, headers_json, raw_size_bytes, message_id FROM emails WHERE id = ?"
+        )
+        .bind(email_id)
+        .fetch_optional(&self.pool)
+        .await
+        .map_err(|e| AppError::Database(format!("Failed to get email: {}", e)))?;
+
+        match row {
+            Some(row) => Ok(Some(email_from_row(&row))),
+            None => Ok(None),
+        }
+    }
+
+    async fn get_mailbox_emails(&self, mailbox_id: &str) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("get_mailbox_emails");
+        let emails = sqlx::query("SELECT * FROM emails WHERE mailbox_id = ? ORDER BY received_at DESC")
+            .bind(mailbox_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn get_mailbox_emails_in_range(
+        &self,
+        mailbox_id: &str,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("get_mailbox_emails_in_range");
+        let emails = sqlx::query(
+            r#"
+            SELECT * FROM emails
+            WHERE mailbox_id = ?1
+              AND (?2 IS NULL OR received_at >= ?2)
+              AND (?3 IS NULL OR received_at <= ?3)
+            ORDER BY received_at DESC
+            "#,
+        )
+        .bind(mailbox_id)
+        .bind(since)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn get_mailbox_emails_batch(
+        &self,
+        mailbox_id: &str,
+        since: Option<i64>,
+        until: Option<i64>,
+        after: Option<(i64, &str)>,
+        limit: i64,
+    ) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("get_mailbox_emails_batch");
+        let (after_received_at, after_id) = after.unzip();
+        let emails = sqlx::query(
+            r#"
+            SELECT * FROM emails
+            WHERE mailbox_id = ?1
+              AND (?2 IS NULL OR received_at >= ?2)
+              AND (?3 IS NULL OR received_at <= ?3)
+              AND (?4 IS NULL OR received_at > ?4 OR (received_at = ?4 AND id > ?5))
+            ORDER BY received_at, id
+            LIMIT ?6
+            "#,
+        )
+        .bind(mailbox_id)
+        .bind(since)
+        .bind(until)
+        .bind(after_received_at)
+        .bind(after_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn get_email_headers(&self, email_id: &str) -> Result<Option<serde_json::Value>, AppError> {
+        let _timer = self.query_timer("get_email_headers");
+        let headers_json: Option<String> = sqlx::query_scalar("SELECT headers_json FROM emails WHERE id = ?")
+            .bind(email_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?
+            .flatten();
+
+        headers_json
+            .map(|headers_json| serde_json::from_str(&headers_json))
+            .transpose()
+            .map_err(|e| AppError::Database(format!("Invalid stored email headers: {}", e)))
+    }
+
+    async fn get_mailbox_email_metadata(
+        &self,
+        mailbox_id: &str,
+        limit: i64,
+        cursor: Option<&str>,
+        since: Option<i64>,
+        until: Option<i64>,
+    ) -> Result<Vec<EmailMetadata>, AppError> {
+        let _timer = self.query_timer("get_mailbox_email_metadata");
+        let rows = sqlx::query(
+            r#"
+            SELECT id, mailbox_id, received_at, expires_at, from_addr, to_addr, subject
+            FROM emails
+            WHERE mailbox_id = ?1
+              AND (?2 IS NULL OR (received_at, id) < (SELECT received_at, id FROM emails WHERE id = ?2))
+              AND (?4 IS NULL OR received_at >= ?4)
+              AND (?5 IS NULL OR received_at <= ?5)
+            ORDER BY received_at DESC, id DESC
+            LIMIT ?3
+            "#,
+        )
+        .bind(mailbox_id)
+        .bind(cursor)
+        .bind(limit)
+        .bind(since)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| EmailMetadata {
+                id: row.get("id"),
+                mailbox_id: row.get("mailbox_id"),
+                received_at: row.get("received_at"),
+                expires_at: row.get("expires_at"),
+                from_addr: row.get("from_addr"),
+                to_addr: row.get("to_addr"),
+                subject: row.get("subject"),
+            })
+            .collect())
+    }
+
+    async fn save_email_search_tokens(&self, email: &Email, search_tokens: &[String]) -> Result<(), AppError> {
+        let _timer = self.query_timer("save_email_search_tokens");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        for search_token in search_tokens {
+            sqlx::query(
+                "INSERT OR IGNORE INTO email_search_tokens (email_id, mailbox_id, search_token) VALUES (?, ?, ?)",
+            )
+            .bind(&email.id)
+            .bind(&email.mailbox_id)
+            .bind(search_token)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+        }
+
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn search_mailbox_emails(&self, mailbox_id: &str, search_token: &str) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("search_mailbox_emails");
+        let emails = sqlx::query(
+            "SELECT e.id, e.mailbox_id, e.encrypted_content, e.received_at, e.expires_at, e.from_addr, e.to_addr, e.subject, e.headers_json, e.raw_size_bytes, e.message_id
+             FROM emails e
+             JOIN email_search_tokens t ON t.email_id = e.id
+             WHERE t.mailbox_id = ? AND t.search_token = ?
+             ORDER BY e.received_at DESC"
+        )
+            .bind(mailbox_id)
+            .bind(search_token)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn search_emails(
+        &self,
+        user_id: &str,
+        query: &str,
+        mailbox_id: Option<&str>,
+        page: i64,
+        per_page: i64,
+    ) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("search_emails");
+        let emails = sqlx::query(
+            "SELECT e.id, e.mailbox_id, e.encrypted_content, e.received_at, e.expires_at, e.from_addr, e.to_addr, e.subject, e.headers_json, e.raw_size_bytes, e.message_id
+             FROM email_search s
+             JOIN emails e ON e.id = s.email_id
+             JOIN mailboxes m ON m.id = e.mailbox_id
+             WHERE email_search MATCH ?1
+             AND (m.owner_id = ?2 OR m.organization_id IN (SELECT org_id FROM organization_members WHERE user_id = ?2))
+             AND (?3 IS NULL OR e.mailbox_id = ?3)
+             ORDER BY e.received_at DESC, e.id
+             LIMIT ?4 OFFSET ?5"
+        )
+            .bind(query)
+            .bind(user_id)
+            .bind(mailbox_id)
+            .bind(per_page)
+            .bind((page - 1).max(0) * per_page)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|e| match &e {
+                // The statement itself is valid, so a plain SQLITE_ERROR comes from a
+                // malformed query, the caller's mistake rather than a database failure
+                sqlx::Error::Database(db_error) if db_error.code().as_deref() == Some("1") => {
+                    AppError::Mail(format!("Invalid search query: {}", db_error.message()))
+                }
+                _ => database_error(e),
+            })?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn filter_mailbox_emails(&self, mailbox_id: &str, filter: &EmailFilter) -> Result<Vec<Email>, AppError> {
+        let _timer = self.query_timer("filter_mailbox_emails");
+        let emails = filtered_emails_query(mailbox_id, filter, None, None)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(emails
+            .into_iter()
+            .map(|row| email_from_row(&row))
+            .collect())
+    }
+
+    async fn update_email_expiry(&self, email_id: &str, new_expires_at: Option<i64>) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_email_expiry");
+        sqlx::query("UPDATE emails SET expires_at = ? WHERE id = ?")
+            .bind(new_expires_at)
+            .bind(email_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn update_email_content(&self, email_id: &str, encrypted_content: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_email_content");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        sqlx::query("UPDATE emails SET encrypted_content = ? WHERE id = ?")
+            .bind(encrypted_content)
+            .bind(email_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        sqlx::query("DELETE FROM email_search_tokens WHERE email_id = ?")
+            .bind(email_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn save_email_attachments(&self, attachments: &[EmailAttachment]) -> Result<(), AppError> {
+        let _timer = self.query_timer("save_email_attachments");
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        for attachment in attachments {
+            sqlx::query(
+                "INSERT INTO email_attachments (id, email_id, filename, mime_type, encrypted_content) VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(&attachment.id)
+            .bind(&attachment.email_id)
+            .bind(&attachment.filename)
+            .bind(&attachment.mime_type)
+            .bind(&attachment.encrypted_content)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+        }
+
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn get_email_attachment(&self, attachment_id: &str) -> Result<Option<EmailAttachment>, AppError> {
+        let _timer = self.query_timer("get_email_attachment");
+        let attachment = sqlx::query("SELECT * FROM email_attachments WHERE id = ?")
+            .bind(attachment_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(attachment.map(|row| EmailAttachment {
+            id: row.get("id"),
+            email_id: row.get("email_id"),
+            filename: row.get("filename"),
+            mime_type: row.get("mime_type"),
+            encrypted_content: row.get("encrypted_content"),
+        }))
+    }
+
+    async fn get_email_attachments(&self, email_id: &str) -> Result<Vec<EmailAttachment>, AppError> {
+        let _timer = self.query_timer("get_email_attachments");
+        let attachments = sqlx::query("SELECT * FROM email_attachments WHERE email_id = ?")
+            .bind(email_id)
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(attachments
+            .into_iter()
+            .map(|row| EmailAttachment {
+                id: row.get("id"),
+                email_id: row.get("email_id"),
+                filename: row.get("filename"),
+                mime_type: row.get("mime_type"),
+                encrypted_content: row.get("encrypted_content"),
+            })
+            .collect())
+    }
+
+    async fn update_email_attachment_content(&self, attachment_id: &str, encrypted_content: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("update_email_attachment_content");
+        sqlx::query("UPDATE email_attachments SET encrypted_content = ? WHERE id = ?")
+            .bind(encrypted_content)
+            .bind(attachment_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn delete_email(&self, email_id: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("delete_email");
+        let result = sqlx::query("DELETE FROM emails WHERE id = ?")
+            .bind(email_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+        record_deleted_emails(result.rows_affected());
+
+        Ok(())
+    }
+
+    async fn delete_emails_bulk(&self, mailbox_id: &str, ids: Option<&[String]>, before: Option<i64>) -> Result<u64, AppError> {
+        let _timer = self.query_timer("delete_emails_bulk");
+        // The IDs are bound as a JSON array so any number of them fits in one statement
+        let ids = ids
+            .map(serde_json::to_string)
+            .transpose()
+            .map_err(|e| AppError::Database(format!("Failed to encode email IDs: {}", e)))?;
+
+        let result = sqlx::query(
+            r#"
+            DELETE FROM emails
+            WHERE mailbox_id = ?1
+              AND (?2 IS NULL OR id IN (SELECT value FROM json_each(?2)))
+              AND (?3 IS NULL OR received_at < ?3)
+            "#,
+        )
+        .bind(mailbox_id)
+        .bind(ids)
+        .bind(before)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+        record_deleted_emails(result.rows_affected());
+
+        Ok(result.rows_affected())
+    }
+
+    async fn delete_expired_emails_for_mailbox(&self, mailbox_id: &str) -> Result<u64, AppError> {
+        let _timer = self.query_timer("delete_expired_emails_for_mailbox");
+        let now = chrono::Utc::now().timestamp();
+        let result = sqlx::query(
+            "DELETE FROM emails WHERE mailbox_id = ? AND expires_at IS NOT NULL AND expires_at < ?"
+        )
+            .bind(mailbox_id)
+            .bind(now)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+        record_deleted_emails(result.rows_affected());
+
+        Ok(result.rows_affected())
+    }
+
+    async fn cleanup_expired_emails(&self) -> Result<(), AppError> {
+        let _timer = self.query_timer("cleanup_expired_emails");
+        let now = chrono::Utc::now().timestamp();
+        let result = sqlx::query("DELETE FROM emails WHERE expires_at IS NOT NULL AND expires_at < ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+        record_deleted_emails(result.rows_affected());
+
+        Ok(())
+    }
+
+    async fn create_api_key(
+        &self,
+        user_id: &str,
+        key_length: usize,
+        scopes: &[ApiKeyScope],
+        expires_in_seconds: Option<i64>,
+    ) -> Result<ApiKey, AppError> {
+        let _timer = self.query_timer("create_api_key");
+        // Generate a secure random string of key_length characters using OsRng
+        let mut rng = OsRng;
+        let random_chars: String = (0..key_length)
+            .map(|_| {
+                let idx = rng.gen_range(0..62);
+                match idx {
+                    0..=9 => (b'0' + idx as u8) as char,
+                    10..=35 => (b'a' + (idx - 10) as u8) as char,
+                    _ => (b'A' + (idx - 36) as u8) as char,
+                }
+            })
+            .collect();
+
+        let created_at = chrono::Utc::now().timestamp();
+        let api_key = ApiKey {
+            id: uuid::Uuid::new_v4().to_string(),
+            user_id: user_id.to_string(),
+            key: format!("vhmhpk-{}", random_chars),
+            created_at,
+            expires_at: expires_in_seconds.map(|seconds| created_at + seconds),
+            revoked_at: None,
+            organization_id: None,
+            scopes: scopes.to_vec(),
+        };
+
+        sqlx::query(
+            "INSERT INTO api_keys (id, user_id, key, created_at, expires_at, scopes) VALUES (?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&api_key.id)
+        .bind(&api_key.user_id)
+        .bind(&api_key.key)
+        .bind(api_key.created_at)
+        .bind(api_key.expires_at)
+        .bind(ApiKeyScope::join(&api_key.scopes))
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(api_key)
+    }
+
+    async fn get_api_key(&self, key: &str) -> Result<Option<ApiKey>, AppError> {
+        let _timer = self.query_timer("get_api_key");
+        let api_key = sqlx::query("SELECT * FROM api_keys WHERE key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        match api_key {
+            Some(row) => Ok(Some(ApiKey {
+                id: row.get("id"),
+                user_id: row.get("user_id"),
+                key: row.get("key"),
+                created_at: row.get("created_at"),
+                expires_at: row.get("expires_at"),
+                revoked_at: row.get("revoked_at"),
+                organization_id: row.get("organization_id"),
+                scopes: ApiKeyScope::parse_list(row.get("scopes")),
+            })),
+            None => Ok(None),
+        }
+    }
+
+    async fn delete_api_key(&self, key_id: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("delete_api_key");
+        sqlx::query("DELETE FROM api_keys WHERE id = ?")
+            .bind(key_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn revoke_user_api_keys(&self, user_id: &str) -> Result<u64, AppError> {
+        let _timer = self.query_timer("revoke_user_api_keys");
+        let result = sqlx::query(
+            "UPDATE api_keys SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL",
+        )
+        .bind(chrono::Utc::now().timestamp())
+        .bind(user_id)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(result.rows_affected())
+    }
+    async fn create_organization(&self, organization: &Organization) -> Result<(), AppError> {
+        let _timer = self.query_timer("create_organization");
+        let mut tx = self.pool.begin().await.map_err(database_error)?;
+
+        sqlx::query(
+            "INSERT INTO organizations (id, name, owner_user_id, max_mailboxes, created_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&organization.id)
+        .bind(&organization.name)
+        .bind(&organization.owner_user_id)
+        .bind(organization.max_mailboxes)
+        .bind(organization.created_at)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+        sqlx::query("INSERT INTO organization_members (org_id, user_id, role, created_at) VALUES (?, ?, ?, ?)")
+            .bind(&organization.id)
+            .bind(&organization.owner_user_id)
+            .bind(OrgRole::Owner)
+            .bind(organization.created_at)
+            .execute(&mut *tx)
+            .await
+            .map_err(database_error)?;
+
+        tx.commit().await.map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn get_organization(&self, org_id: &str) -> Result<Option<Organization>, AppError> {
+        let _timer = self.query_timer("get_organization");
+        let row = sqlx::query("SELECT * FROM organizations WHERE id = ?")
+            .bind(org_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(row.map(|row| Organization {
+            id: row.get("id"),
+            name: row.get("name"),
+            owner_user_id: row.get("owner_user_id"),
+            max_mailboxes: row.get("max_mailboxes"),
+            created_at: row.get("created_at"),
+        }))
+    }
+
+    async fn set_organization_max_mailboxes(&self, org_id: &str, max_mailboxes: Option<i64>) -> Result<bool, AppError> {
+        let _timer = self.query_timer("set_organization_max_mailboxes");
+        let result = sqlx::query("UPDATE organizations SET max_mailboxes = ? WHERE id = ?")
+            .bind(max_mailboxes)
+            .bind(org_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_organizations_for_user(&self, user_id: &str) -> Result<Vec<Organization>, AppError> {
+        let _timer = self.query_timer("get_organizations_for_user");
+        let rows = sqlx::query(
+            "SELECT o.* FROM organizations o
+             JOIN organization_members m ON m.org_id = o.id
+             WHERE m.user_id = ?
+             ORDER BY o.created_at",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Organization {
+                id: row.get("id"),
+                name: row.get("name"),
+                owner_user_id: row.get("owner_user_id"),
+                max_mailboxes: row.get("max_mailboxes"),
+                created_at: row.get("created_at"),
+            })
+            .collect())
+    }
+
+    async fn get_organization_role(&self, org_id: &str, user_id: &str) -> Result<Option<OrgRole>, AppError> {
+        let _timer = self.query_timer("get_organization_role");
+        sqlx::query_scalar("SELECT role FROM organization_members WHERE org_id = ? AND user_id = ?")
+            .bind(org_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(database_error)
+    }
+
+    async fn add_organization_member(&self, member: &OrganizationMember) -> Result<(), AppError> {
+        let _timer = self.query_timer("add_organization_member");
+        sqlx::query(
+            "INSERT INTO organization_members (org_id, user_id, role, created_at) VALUES (?, ?, ?, ?)
+             ON CONFLICT(org_id, user_id) DO UPDATE SET role = excluded.role",
+        )
+        .bind(&member.org_id)
+        .bind(&member.user_id)
+        .bind(member.role)
+        .bind(member.created_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn remove_organization_member(&self, org_id: &str, user_id: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("remove_organization_member");
+        sqlx::query("DELETE FROM organization_members WHERE org_id = ? AND user_id = ?")
+            .bind(org_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn count_organization_mailboxes(&self, org_id: &str) -> Result<i64, AppError> {
+        let _timer = self.query_timer("count_organization_mailboxes");
+        sqlx::query_scalar("SELECT COUNT(*) FROM mailboxes WHERE organization_id = ?")
+            .bind(org_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)
+    }
+
+    async fn count_user_mailboxes(&self, owner_id: &str) -> Result<u64, AppError> {
+        let _timer = self.query_timer("count_user_mailboxes");
+        let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM mailboxes WHERE owner_id = ?")
+            .bind(owner_id)
+            .fetch_one(&self.pool)
+            .await
+            .map_err(database_error)?;
+        Ok(count as u64)
+    }
+
+    async fn record_api_key_usage(&self, usage: &ApiKeyUsage) -> Result<(), AppError> {
+        let _timer = self.query_timer("record_api_key_usage");
+        sqlx::query(
+            "INSERT INTO api_key_usage (api_key_id, endpoint, method, status_code, response_ms, used_at, country_code, city)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&usage.api_key_id)
+        .bind(&usage.endpoint)
+        .bind(&usage.method)
+        .bind(usage.status_code)
+        .bind(usage.response_ms)
+        .bind(usage.used_at)
+        .bind(&usage.country_code)
+        .bind(&usage.city)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn get_api_key_usage(
+        &self,
+        api_key_id: &str,
+        since: i64,
+        until: i64,
+        bucket_seconds: i64,
+    ) -> Result<Vec<ApiKeyUsageBucket>, AppError> {
+        let _timer = self.query_timer("get_api_key_usage");
+        if bucket_seconds <= 0 {
+            return Err(AppError::Internal("Bucket size must be positive".into()));
+        }
+
+        let rows = sqlx::query(
+            "SELECT (used_at - ?) / ? AS bucket,
+                    COUNT(*) AS requests,
+                    SUM(CASE WHEN status_code >= 400 THEN 1 ELSE 0 END) AS errors
+             FROM api_key_usage
+             WHERE api_key_id = ? AND used_at >= ? AND used_at < ?
+             GROUP BY bucket",
+        )
+        .bind(since)
+        .bind(bucket_seconds)
+        .bind(api_key_id)
+        .bind(since)
+        .bind(until)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        let counts: HashMap<i64, (i64, i64)> = rows
+            .iter()
+            .map(|row| (row.get("bucket"), (row.get("requests"), row.get("errors"))))
+            .collect();
+
+        let bucket_count = (until - since + bucket_seconds - 1) / bucket_seconds;
+        Ok((0..bucket_count)
+            .map(|bucket| {
+                let (requests, errors) = counts.get(&bucket).copied().unwrap_or((0, 0));
+                ApiKeyUsageBucket {
+                    bucket_start: since + bucket * bucket_seconds,
+                    requests,
+                    errors,
+                    error_rate: if requests > 0 { errors as f64 / requests as f64 } else { 0.0 },
+                }
+            })
+            .collect())
+    }
+
+    async fn get_api_key_top_endpoints(
+        &self,
+        api_key_id: &str,
+        since: i64,
+        limit: i64,
+    ) -> Result<Vec<ApiKeyEndpointUsage>, AppError> {
+        let _timer = self.query_timer("get_api_key_top_endpoints");
+        let rows = sqlx::query(
+            "SELECT endpoint, method, COUNT(*) AS requests
+             FROM api_key_usage
+             WHERE api_key_id = ? AND used_at >= ?
+             GROUP BY endpoint, method
+             ORDER BY requests DESC, endpoint
+             LIMIT ?",
+        )
+        .bind(api_key_id)
+        .bind(since)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| ApiKeyEndpointUsage {
+                endpoint: row.get("endpoint"),
+                method: row.get("method"),
+                requests: row.get("requests"),
+            })
+            .collect())
+    }
+
+    async fn cleanup_api_key_usage(&self, older_than: i64) -> Result<(), AppError> {
+        let _timer = self.query_timer("cleanup_api_key_usage");
+        sqlx::query("DELETE FROM api_key_usage WHERE used_at < ?")
+            .bind(older_than)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn cleanup_login_attempts(&self, older_than: i64) -> Result<u64, AppError> {
+        let _timer = self.query_timer("cleanup_login_attempts");
+        let result = sqlx::query("DELETE FROM login_attempts WHERE attempt_at < ?")
+            .bind(older_than)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn cleanup_expired_tokens(&self, now: i64) -> Result<u64, AppError> {
+        let _timer = self.query_timer("cleanup_expired_tokens");
+        let mut removed = 0;
+        for table in ["issued_tokens", "token_revocations"] {
+            removed += sqlx::query(&format!("DELETE FROM {} WHERE expires_at < ?", table))
+                .bind(now)
+                .execute(&self.pool)
+                .await
+                .map_err(database_error)?
+                .rows_affected();
+        }
+
+        Ok(removed)
+    }
+
+    async fn save_bounce(&self, bounce: &Bounce) -> Result<bool, AppError> {
+        let _timer = self.query_timer("save_bounce");
+        let result = sqlx::query(
+            "INSERT INTO bounces (id, message_id, original_recipient, status_code, diagnostic, permanent, bounced_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT (message_id, original_recipient) DO NOTHING",
+        )
+        .bind(&bounce.id)
+        .bind(&bounce.message_id)
+        .bind(&bounce.original_recipient)
+        .bind(&bounce.status_code)
+        .bind(&bounce.diagnostic)
+        .bind(bounce.permanent)
+        .bind(bounce.bounced_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn get_bounces(&self, recipient: Option<&str>, permanent: Option<bool>) -> Result<Vec<Bounce>, AppError> {
+        let _timer = self.query_timer("get_bounces");
+        let rows = sqlx::query(
+            r#"
+            SELECT id, message_id, original_recipient, status_code, diagnostic, permanent, bounced_at
+            FROM bounces
+            WHERE (?1 IS NULL OR original_recipient = ?1)
+              AND (?2 IS NULL OR permanent = ?2)
+            ORDER BY bounced_at DESC
+            "#,
+        )
+        .bind(recipient)
+        .bind(permanent)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| Bounce {
+                id: row.get("id"),
+                message_id: row.get("message_id"),
+                original_recipient: row.get("original_recipient"),
+                status_code: row.get("status_code"),
+                diagnostic: row.get("diagnostic"),
+                permanent: row.get("permanent"),
+                bounced_at: row.get("bounced_at"),
+            })
+            .collect())
+    }
+
+    async fn cleanup_bounces(&self, older_than: i64) -> Result<u64, AppError> {
+        let _timer = self.query_timer("cleanup_bounces");
+        let result = sqlx::query("DELETE FROM bounces WHERE bounced_at < ?")
+            .bind(older_than)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn append_audit_log(&self, entry: &AuditEntry) -> Result<(), AppError> {
+        let _timer = self.query_timer("append_audit_log");
+        sqlx::query(
+            "INSERT INTO audit_log (user_id, action, resource_type, resource_id, ip, user_agent, occurred_at, metadata)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&entry.user_id)
+        .bind(&entry.action)
+        .bind(&entry.resource_type)
+        .bind(&entry.resource_id)
+        .bind(&entry.ip)
+        .bind(&entry.user_agent)
+        .bind(entry.occurred_at)
+        .bind(entry.metadata.as_ref().map(|metadata| metadata.to_string()))
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn get_audit_log(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<AuditEntry>, AppError> {
+        let _timer = self.query_timer("get_audit_log");
+        let rows = sqlx::query(
+            "SELECT user_id, action, resource_type, resource_id, ip, user_agent, occurred_at, metadata
+             FROM audit_log
+             WHERE user_id = ?
+             ORDER BY occurred_at DESC, id DESC
+             LIMIT ? OFFSET ?",
+        )
+        .bind(user_id)
+        .bind(per_page)
+        .bind((page - 1).max(0) * per_page)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| AuditEntry {
+                user_id: row.get("user_id"),
+                action: row.get("action"),
+                resource_type: row.get("resource_type"),
+                resource_id: row.get("resource_id"),
+                ip: row.get("ip"),
+                user_agent: row.get("user_agent"),
+                occurred_at: row.get("occurred_at"),
+                metadata: row
+                    .get::<Option<String>, _>("metadata")
+                    .and_then(|metadata| serde_json::from_str(&metadata).ok()),
+            })
+            .collect())
+    }
+
+    async fn record_login_event(&self, event: &LoginEvent) -> Result<(), AppError> {
+        let _timer = self.query_timer("record_login_event");
+        sqlx::query(
+            "INSERT INTO login_events (user_id, ip, country_code, city, logged_in_at) VALUES (?, ?, ?, ?, ?)",
+        )
+        .bind(&event.user_id)
+        .bind(&event.ip)
+        .bind(&event.country_code)
+        .bind(&event.city)
+        .bind(event.logged_in_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn get_login_events(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<LoginEvent>, AppError> {
+        let _timer = self.query_timer("get_login_events");
+        let rows = sqlx::query(
+            "SELECT user_id, ip, country_code, city, logged_in_at
+             FROM login_events
+             WHERE user_id = ?
+             ORDER BY logged_in_at DESC, id DESC
+             LIMIT ? OFFSET ?",
+        )
+        .bind(user_id)
+        .bind(per_page)
+        .bind((page - 1).max(0) * per_page)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| LoginEvent {
+                user_id: row.get("user_id"),
+                ip: row.get("ip"),
+                country_code: row.get("country_code"),
+                city: row.get("city"),
+                logged_in_at: row.get("logged_in_at"),
+            })
+            .collect())
+    }
+
+    async fn get_login_countries(&self, user_id: &str, since: i64) -> Result<Vec<String>, AppError> {
+        let _timer = self.query_timer("get_login_countries");
+        sqlx::query_scalar(
+            "SELECT DISTINCT country_code FROM login_events
+             WHERE user_id = ? AND logged_in_at >= ? AND country_code IS NOT NULL",
+        )
+        .bind(user_id)
+        .bind(since)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)
+    }
+
+    async fn get_login_geography(&self) -> Result<HashMap<String, u64>, AppError> {
+        let _timer = self.query_timer("get_login_geography");
+        let rows = sqlx::query(
+            "SELECT country_code, COUNT(*) AS logins FROM login_events
+             WHERE country_code IS NOT NULL
+             GROUP BY country_code",
+        )
+        .fetch_all(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("country_code"), row.get::<i64, _>("logins") as u64))
+            .collect())
+    }
+
+    async fn get_feature_flags(&self) -> Result<Vec<FeatureFlag>, AppError> {
+        let _timer = self.query_timer("get_feature_flags");
+        let rows = sqlx::query("SELECT flag_name, enabled, updated_by, updated_at FROM feature_flags ORDER BY flag_name")
+            .fetch_all(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| FeatureFlag {
+                flag_name: row.get("flag_name"),
+                enabled: row.get("enabled"),
+                updated_by: row.get("updated_by"),
+                updated_at: row.get("updated_at"),
+            })
+            .collect())
+    }
+
+    async fn set_feature_flag(&self, flag: &FeatureFlag) -> Result<(), AppError> {
+        let _timer = self.query_timer("set_feature_flag");
+        sqlx::query(
+            r#"
+            INSERT INTO feature_flags (flag_name, enabled, updated_by, updated_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(flag_name) DO UPDATE SET
+                enabled = excluded.enabled,
+                updated_by = excluded.updated_by,
+                updated_at = excluded.updated_at
+            "#,
+        )
+        .bind(&flag.flag_name)
+        .bind(flag.enabled)
+        .bind(&flag.updated_by)
+        .bind(flag.updated_at)
+        .execute(&self.pool)
+        .await
+        .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn greylist_check_and_insert(&self, ip: &str, from: &str, to: &str, delay_secs: i64) -> Result<GreylistStatus, AppError> {
+        let _timer = self.query_timer("greylist_check_and_insert");
+        let now = chrono::Utc::now().timestamp();
+        let mut tx = self.pool.begin().await
+            .map_err(database_error)?;
+
+        let first_seen: Option<i64> = sqlx::query_scalar(
+            "SELECT first_seen FROM greylisting WHERE ip = ? AND from_addr = ? AND to_addr = ?",
+        )
+        .bind(ip)
+        .bind(from)
+        .bind(to)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+        let status = match first_seen {
+            Some(first_seen) if now >= first_seen + delay_secs => {
+                sqlx::query("DELETE FROM greylisting WHERE ip = ? AND from_addr = ? AND to_addr = ?")
+                    .bind(ip)
+                    .bind(from)
+                    .bind(to)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(database_error)?;
+                GreylistStatus::Passed
+            }
+            Some(first_seen) => GreylistStatus::Deferred { first_seen, eligible_at: first_seen + delay_secs },
+            None => {
+                sqlx::query("INSERT OR IGNORE INTO greylisting (ip, from_addr, to_addr, first_seen) VALUES (?, ?, ?, ?)")
+                    .bind(ip)
+                    .bind(from)
+                    .bind(to)
+                    .bind(now)
+                    .execute(&mut *tx)
+                    .await
+                    .map_err(database_error)?;
+                GreylistStatus::Deferred { first_seen: now, eligible_at: now + delay_secs }
+            }
+        };
+
+        tx.commit().await
+            .map_err(database_error)?;
+
+        Ok(status)
+    }
+
+    async fn greylist_remove(&self, ip: &str, from: &str, to: &str) -> Result<(), AppError> {
+        let _timer = self.query_timer("greylist_remove");
+        sqlx::query("DELETE FROM greylisting WHERE ip = ? AND from_addr = ? AND to_addr = ?")
+            .bind(ip)
+            .bind(from)
+            .bind(to)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(())
+    }
+
+    async fn greylist_cleanup(&self, older_than: i64) -> Result<u64, AppError> {
+        let _timer = self.query_timer("greylist_cleanup");
+        let result = sqlx::query("DELETE FROM greylisting WHERE first_seen < ?")
+            .bind(older_than)
+            .execute(&self.pool)
+            .await
+            .map_err(database_error)?;
+
+        Ok(result.rows_affected())
+    }
+}
+
+/// Emails of the mailbox matching the filter and received within `[since, until]`,
+/// newest first
+fn filtered_emails_query(
+    mailbox_id: &str,
+    filter: &EmailFilter,
+    since: Option<i64>,
+    until: Option<i64>,
+) -> sqlx::query::Query<'static, Sqlite, sqlx::sqlite::SqliteArguments<'static>> {
+    // LIKE wildcards in the filters match literally
+    let pattern = |value: &Option<String>| {
+        value.as_ref().map(|value| {
+            let escaped = value.replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            format!("%{}%", escaped)
+        })
+    };
+
+    sqlx::query(
+        r#"
+        SELECT id, mailbox_id, encrypted_content, received_at, expires_at, from_addr, to_addr, subject, headers_json, raw_size_bytes, message_id
+        FROM emails
+        WHERE mailbox_id = ?1
+          AND (?2 IS NULL OR from_addr LIKE ?2 ESCAPE '\')
+          AND (?3 IS NULL OR subject LIKE ?3 ESCAPE '\')
+          AND (?4 IS NULL OR raw_size_bytes >= ?4)
+          AND (?5 IS NULL OR raw_size_bytes <= ?5)
+          AND (?6 IS NULL OR received_at >= ?6)
+          AND (?7 IS NULL OR received_at <= ?7)
+        ORDER BY received_at DESC
+        "#,
+    )
+    .bind(mailbox_id.to_string())
+    .bind(pattern(&filter.from))
+    .bind(pattern(&filter.subject))
+    .bind(filter.min_size)
+    .bind(filter.max_size)
+    .bind(since)
+    .bind(until)
+}
+
+/// Like [`Database::filter_mailbox_emails`] within `[since, until]`, reading one
+/// row at a time so large mailboxes are never loaded into memory at once
+pub fn stream_mailbox_emails<'e>(
+    pool: &'e SqlitePool,
+    mailbox_id: &str,
+    filter: &EmailFilter,
+    since: Option<i64>,
+    until: Option<i64>,
+) -> BoxStream<'e, Result<Email, AppError>> {
+    filtered_emails_query(mailbox_id, filter, since, until)
+        .fetch(pool)
+        .map(|row| row.map(|row| email_from_row(&row)).map_err(database_error))
+        .boxed()
+}
+
+/// Reads an email from a row holding all the columns of `emails`
+pub fn email_from_row(row: &sqlx::sqlite::SqliteRow) -> Email {
+    Email {
+        id: row.get("id"),
+        mailbox_id: row.get("mailbox_id"),
+        encrypted_content: row.get("encrypted_content"),
+        received_at: row.get("received_at"),
+        expires_at: row.get("expires_at"),
+        from_addr: row.get("from_addr"),
+        to_addr: row.get("to_addr"),
+        subject: row.get("subject"),
+        headers_json: row.get("headers_json"),
+        raw_size_bytes: row.get("raw_size_bytes"),
+        message_id: row.get("message_id"),
+    }
+}
+
+/// Mailboxes joined with their emails and the view of the user bound as `?1`;
+/// callers add the WHERE clause and `GROUP BY m.id`
+const MAILBOX_WITH_STATS_QUERY: &str =
+    "SELECT m.*, COUNT(e.id) AS email_count,
+            COUNT(CASE WHEN v.viewed_at IS NULL OR e.received_at > v.viewed_at THEN e.id END) AS unread_count
+     FROM mailboxes m
+     LEFT JOIN emails e ON e.mailbox_id = m.id
+     LEFT JOIN mailbox_views v ON v.mailbox_id = m.id AND v.user_id = ?1";
+
+fn mailbox_with_stats_from_row(row: &sqlx::sqlite::SqliteRow) -> MailboxWithStats {
+    MailboxWithStats {
+        mailbox: Mailbox {
+            id: row.get("id"),
+            alias: row.get("alias"),
+            name: row.get("name"),
+            public_key: row.get("public_key"),
+            key_type: row.get("key_type"),
+            owner_id: row.get("owner_id"),
+            created_at: row.get("created_at"),
+            mail_expires_in: row.get("mail_expires_in"),
+            organization_id: row.get("organization_id"),
+            strip_attachments: row.get("strip_attachments"),
+            alias_rotated_at: row.get("alias_rotated_at"),
+        },
+        email_count: row.get::<i64, _>("email_count") as u64,
+        unread_count: row.get::<i64, _>("unread_count") as u64,
+    }
+}
+
+fn parse_cleanup_policy(value: Option<String>) -> Result<Option<CleanupPolicy>, AppError> {
+    value
+        .map(|json| serde_json::from_str(&json))
+        .transpose()
+        .map_err(|e| AppError::Database(format!("Invalid email cleanup policy: {}", e)))
+}
+
+/// Implements `Database` for a pointer type by delegating every method to the
+/// database it points to
+macro_rules! delegate_database {
+    ($($ty:ty),+ $(,)?) => {$(
+        #[async_trait]
+        impl<D: Database + ?Sized> Database for $ty {
+            fn pool(&self) -> &SqlitePool {
+                (**self).pool()
+            }
+
+            async fn init(&self) -> Result<(), AppError> {
+                (**self).init().await
+            }
+
+            async fn create_user(&self, username: &str, auth_type: AuthType) -> Result<User, AppError> {
+                (**self).create_user(username, auth_type).await
+            }
+
+            async fn get_user(&self, user_id: &str) -> Result<Option<User>, AppError> {
+                (**self).get_user(user_id).await
+            }
+
+            async fn get_backup_key(&self, user_id: &str) -> Result<Option<BackupKey>, AppError> {
+                (**self).get_backup_key(user_id).await
+            }
+
+            async fn set_backup_key(&self, user_id: &str, backup_key: &BackupKey) -> Result<bool, AppError> {
+                (**self).set_backup_key(user_id, backup_key).await
+            }
+
+            async fn list_users(&self, search: Option<&str>, page: i64, per_page: i64) -> Result<(Vec<User>, i64), AppError> {
+                (**self).list_users(search, page, per_page).await
+            }
+
+            async fn delete_user(&self, user_id: &str) -> Result<bool, AppError> {
+                (**self).delete_user(user_id).await
+            }
+
+            async fn ban_user(&self, user_id: &str, banned_at: i64) -> Result<bool, AppError> {
+                (**self).ban_user(user_id, banned_at).await
+            }
+
+            async fn get_instance_stats(&self) -> Result<InstanceStats, AppError> {
+                (**self).get_instance_stats().await
+            }
+
+            async fn get_user_settings(&self, user_id: &str) -> Result<Option<UserSettings>, AppError> {
+                (**self).get_user_settings(user_id).await
+            }
+
+            async fn update_user_settings(&self, settings: &UserSettings) -> Result<(), AppError> {
+                (**self).update_user_settings(settings).await
+            }
+
+            async fn get_user_email_stats(&self, user_id: &str) -> Result<UserEmailStats, AppError> {
+                (**self).get_user_email_stats(user_id).await
+            }
+
+            async fn get_mailbox_storage_stats(&self, owner_id: &str) -> Result<Vec<MailboxStorageStats>, AppError> {
+                (**self).get_mailbox_storage_stats(owner_id).await
+            }
+
+            async fn get_email_cleanup_policies(&self) -> Result<Vec<(String, CleanupPolicy)>, AppError> {
+                (**self).get_email_cleanup_policies().await
+            }
+
+            async fn apply_email_cleanup_policy(&self, user_id: &str, policy: &CleanupPolicy) -> Result<u64, AppError> {
+                (**self).apply_email_cleanup_policy(user_id, policy).await
+            }
+
+            async fn create_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
+                (**self).create_mailbox(mailbox).await
+            }
+
+            async fn get_mailbox(&self, mailbox_id: &str) -> Result<Option<Mailbox>, AppError> {
+                (**self).get_mailbox(mailbox_id).await
+            }
+
+            async fn get_mailbox_by_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
+                (**self).get_mailbox_by_address(local_part).await
+            }
+
+            async fn get_mailbox_by_incoming_address(&self, local_part: &str) -> Result<Option<Mailbox>, AppError> {
+                (**self).get_mailbox_by_incoming_address(local_part).await
+            }
+
+            async fn get_mailboxes_by_owner(&self, owner_id: &str) -> Result<Vec<Mailbox>, AppError> {
+                (**self).get_mailboxes_by_owner(owner_id).await
+            }
+
+            async fn get_accessible_mailboxes(&self, user_id: &str) -> Result<Vec<Mailbox>, AppError> {
+                (**self).get_accessible_mailboxes(user_id).await
+            }
+
+            async fn get_mailbox_with_stats(&self, mailbox_id: &str, user_id: &str) -> Result<Option<MailboxWithStats>, AppError> {
+                (**self).get_mailbox_with_stats(mailbox_id, user_id).await
+            }
+
+            async fn get_mailboxes_by_owner_with_stats(&self, owner_id: &str) -> Result<Vec<MailboxWithStats>, AppError> {
+                (**self).get_mailboxes_by_owner_with_stats(owner_id).await
+            }
+
+            async fn get_mailbox_email_count(&self, mailbox_id: &str) -> Result<u64, AppError> {
+                (**self).get_mailbox_email_count(mailbox_id).await
+            }
+
+            async fn get_mailboxes_email_counts(&self, mailbox_ids: &[String]) -> Result<HashMap<String, u64>, AppError> {
+                (**self).get_mailboxes_email_counts(mailbox_ids).await
+            }
+
+            async fn mark_mailbox_viewed(&self, mailbox_id: &str, user_id: &str, viewed_at: i64) -> Result<(), AppError> {
+                (**self).mark_mailbox_viewed(mailbox_id, user_id, viewed_at).await
+            }
+
+            async fn get_mailbox_list_version(&self, user_id: &str) -> Result<(i64, Option<i64>), AppError> {
+                (**self).get_mailbox_list_version(user_id).await
+            }
+
+            async fn delete_mailbox(&self, mailbox_id: &str) -> Result<(), AppError> {
+                (**self).delete_mailbox(mailbox_id).await
+            }
+
+            async fn count_mailboxes(&self) -> Result<i64, AppError> {
+                (**self).count_mailboxes().await
+            }
+
+            async fn get_mailbox_total_size(&self, mailbox_id: &str) -> Result<i64, AppError> {
+                (**self).get_mailbox_total_size(mailbox_id).await
+            }
+
+            async fn cleanup_expired_mailboxes(&self) -> Result<(), AppError> {
+                (**self).cleanup_expired_mailboxes().await
+            }
+
+            async fn update_mailbox(&self, mailbox: &Mailbox) -> Result<(), AppError> {
+                (**self).update_mailbox(mailbox).await
+            }
+
+            async fn update_mailbox_alias(&self, mailbox_id: &str, alias: &str) -> Result<(), AppError> {
+                (**self).update_mailbox_alias(mailbox_id, alias).await
+            }
+
+            async fn rotate_mailbox_alias(&self, mailbox_id: &str, alias: &str, rotated_at: i64, retired_until: i64) -> Result<(), AppError> {
+                (**self).rotate_mailbox_alias(mailbox_id, alias, rotated_at, retired_until).await
+            }
+
+            async fn is_alias_retired(&self, alias: &str, now: i64) -> Result<bool, AppError> {
+                (**self).is_alias_retired(alias, now).await
+            }
+
+            async fn cleanup_old_aliases(&self, now: i64) -> Result<u64, AppError> {
+                (**self).cleanup_old_aliases(now).await
+            }
+
+            async fn get_mailbox_aliases(&self, mailbox_id: &str) -> Result<Vec<MailboxAlias>, AppError> {
+                (**self).get_mailbox_aliases(mailbox_id).await
+            }
+
+            async fn create_mailbox_alias(&self, alias: &MailboxAlias) -> Result<(), AppError> {
+                (**self).create_mailbox_alias(alias).await
+            }
+
+            async fn delete_mailbox_alias(&self, mailbox_id: &str, alias_id: &str) -> Result<bool, AppError> {
+                (**self).delete_mailbox_alias(mailbox_id, alias_id).await
+            }
+
+            async fn add_mailbox_key(&self, key: &MailboxKey) -> Result<(), AppError> {
+                (**self).add_mailbox_key(key).await
+            }
+
+            async fn remove_mailbox_key(&self, mailbox_id: &str, label: &str) -> Result<bool, AppError> {
+                (**self).remove_mailbox_key(mailbox_id, label).await
+            }
+
+            async fn list_mailbox_keys(&self, mailbox_id: &str) -> Result<Vec<MailboxKey>, AppError> {
+                (**self).list_mailbox_keys(mailbox_id).await
+            }
+
+            async fn create_webhook(&self, webhook: &Webhook) -> Result<(), AppError> {
+                (**self).create_webhook(webhook).await
+            }
+
+            async fn get_mailbox_webhooks(&self, mailbox_id: &str) -> Result<Vec<Webhook>, AppError> {
+                (**self).get_mailbox_webhooks(mailbox_id).await
+            }
+
+            async fn delete_webhook(&self, mailbox_id: &str, webhook_id: &str) -> Result<bool, AppError> {
+                (**self).delete_webhook(mailbox_id, webhook_id).await
+            }
+
+            async fn rotate_webhook_secret(&self, mailbox_id: &str, webhook_id: &str, secret: &str, signature_algorithm: SignatureAlgorithm) -> Result<bool, AppError> {
+                (**self).rotate_webhook_secret(mailbox_id, webhook_id, secret, signature_algorithm).await
+            }
+
+            async fn update_webhook_status(&self, webhook_id: &str, triggered_at: i64, status: Option<i64>) -> Result<(), AppError> {
+                (**self).update_webhook_status(webhook_id, triggered_at, status).await
+            }
+
+            async fn get_telegram_chat_id(&self, user_id: &str) -> Result<Option<String>, AppError> {
+                (**self).get_telegram_chat_id(user_id).await
+            }
+
+            async fn claim_mailbox_notification(&self, mailbox_id: &str, now: i64, cooldown_secs: i64) -> Result<bool, AppError> {
+                (**self).claim_mailbox_notification(mailbox_id, now, cooldown_secs).await
+            }
+
+            async fn create_forwarding_rule(&self, rule: &ForwardingRule) -> Result<(), AppError> {
+                (**self).create_forwarding_rule(rule).await
+            }
+
+            async fn get_forwarding_rules(&self, mailbox_id: &str) -> Result<Vec<ForwardingRule>, AppError> {
+                (**self).get_forwarding_rules(mailbox_id).await
+            }
+
+            async fn delete_forwarding_rule(&self, mailbox_id: &str, rule_id: &str) -> Result<bool, AppError> {
+                (**self).delete_forwarding_rule(mailbox_id, rule_id).await
+            }
+
+            async fn save_email(&self, email: &Email) -> Result<bool, AppError> {
+                (**self).save_email(email).await
+            }
+
+            async fn email_exists_by_message_id(&self, mailbox_id: &str, message_id: &str) -> Result<bool, AppError> {
+                (**self).email_exists_by_message_id(mailbox_id, message_id).await
+            }
+
+            async fn get_email(&self, email_id: &str) -> Result<Option<Email>, AppError> {
+                (**self).get_email(email_id).await
+            }
+
+            async fn get_mailbox_emails(&self, mailbox_id: &str) -> Result<Vec<Email>, AppError> {
+                (**self).get_mailbox_emails(mailbox_id).await
+            }
+
+            async fn get_mailbox_emails_in_range(
+                &self,
+                mailbox_id: &str,
+                since: Option<i64>,
+                until: Option<i64>,
+            ) -> Result<Vec<Email>, AppError> {
+                (**self).get_mailbox_emails_in_range(mailbox_id, since, until).await
+            }
+
+            async fn get_mailbox_emails_batch(
+                &self,
+                mailbox_id: &str,
+                since: Option<i64>,
+                until: Option<i64>,
+                after: Option<(i64, &str)>,
+                limit: i64,
+            ) -> Result<Vec<Email>, AppError> {
+                (**self).get_mailbox_emails_batch(mailbox_id, since, until, after, limit).await
+            }
+
+            async fn get_email_headers(&self, email_id: &str) -> Result<Option<serde_json::Value>, AppError> {
+                (**self).get_email_headers(email_id).await
+            }
+
+            async fn get_mailbox_email_metadata(
+                &self,
+                mailbox_id: &str,
+                limit: i64,
+                cursor: Option<&str>,
+                since: Option<i64>,
+                until: Option<i64>,
+            ) -> Result<Vec<EmailMetadata>, AppError> {
+                (**self).get_mailbox_email_metadata(mailbox_id, limit, cursor, since, until).await
+            }
+
+            async fn save_email_search_tokens(&self, email: &Email, search_tokens: &[String]) -> Result<(), AppError> {
+                (**self).save_email_search_tokens(email, search_tokens).await
+            }
+
+            async fn search_mailbox_emails(&self, mailbox_id: &str, search_token: &str) -> Result<Vec<Email>, AppError> {
+                (**self).search_mailbox_emails(mailbox_id, search_token).await
+            }
+
+            async fn search_emails(
+                &self,
+                user_id: &str,
+                query: &str,
+                mailbox_id: Option<&str>,
+                page: i64,
+                per_page: i64,
+            ) -> Result<Vec<Email>, AppError> {
+                (**self).search_emails(user_id, query, mailbox_id, page, per_page).await
+            }
+
+            async fn filter_mailbox_emails(&self, mailbox_id: &str, filter: &EmailFilter) -> Result<Vec<Email>, AppError> {
+                (**self).filter_mailbox_emails(mailbox_id, filter).await
+            }
+
+            async fn update_email_expiry(&self, email_id: &str, new_expires_at: Option<i64>) -> Result<(), AppError> {
+                (**self).update_email_expiry(email_id, new_expires_at).await
+            }
+
+            async fn update_email_content(&self, email_id: &str, encrypted_content: &str) -> Result<(), AppError> {
+                (**self).update_email_content(email_id, encrypted_content).await
+            }
+
+            async fn save_email_attachments(&self, attachments: &[EmailAttachment]) -> Result<(), AppError> {
+                (**self).save_email_attachments(attachments).await
+            }
+
+            async fn get_email_attachment(&self, attachment_id: &str) -> Result<Option<EmailAttachment>, AppError> {
+                (**self).get_email_attachment(attachment_id).await
+            }
+
+            async fn get_email_attachments(&self, email_id: &str) -> Result<Vec<EmailAttachment>, AppError> {
+                (**self).get_email_attachments(email_id).await
+            }
+
+            async fn update_email_attachment_content(&self, attachment_id: &str, encrypted_content: &str) -> Result<(), AppError> {
+                (**self).update_email_attachment_content(attachment_id, encrypted_content).await
+            }
+
+            async fn delete_email(&self, email_id: &str) -> Result<(), AppError> {
+                (**self).delete_email(email_id).await
+            }
+
+            async fn delete_emails_bulk(&self, mailbox_id: &str, ids: Option<&[String]>, before: Option<i64>) -> Result<u64, AppError> {
+                (**self).delete_emails_bulk(mailbox_id, ids, before).await
+            }
+
+            async fn delete_expired_emails_for_mailbox(&self, mailbox_id: &str) -> Result<u64, AppError> {
+                (**self).delete_expired_emails_for_mailbox(mailbox_id).await
+            }
+
+            async fn cleanup_expired_emails(&self) -> Result<(), AppError> {
+                (**self).cleanup_expired_emails().await
+            }
+
+            async fn create_api_key(
+                &self,
+                user_id: &str,
+                key_length: usize,
+                scopes: &[ApiKeyScope],
+                expires_in_seconds: Option<i64>,
+            ) -> Result<ApiKey, AppError> {
+                (**self).create_api_key(user_id, key_length, scopes, expires_in_seconds).await
+            }
+
+            async fn get_api_key(&self, key: &str) -> Result<Option<ApiKey>, AppError> {
+                (**self).get_api_key(key).await
+            }
+
+            async fn delete_api_key(&self, key_id: &str) -> Result<(), AppError> {
+                (**self).delete_api_key(key_id).await
+            }
+
+            async fn revoke_user_api_keys(&self, user_id: &str) -> Result<u64, AppError> {
+                (**self).revoke_user_api_keys(user_id).await
+            }
+
+            async fn create_organization(&self, organization: &Organization) -> Result<(), AppError> {
+                (**self).create_organization(organization).await
+            }
+
+            async fn get_organization(&self, org_id: &str) -> Result<Option<Organization>, AppError> {
+                (**self).get_organization(org_id).await
+            }
+
+            async fn set_organization_max_mailboxes(&self, org_id: &str, max_mailboxes: Option<i64>) -> Result<bool, AppError> {
+                (**self).set_organization_max_mailboxes(org_id, max_mailboxes).await
+            }
+
+            async fn get_organizations_for_user(&self, user_id: &str) -> Result<Vec<Organization>, AppError> {
+                (**self).get_organizations_for_user(user_id).await
+            }
+
+            async fn get_organization_role(&self, org_id: &str, user_id: &str) -> Result<Option<OrgRole>, AppError> {
+                (**self).get_organization_role(org_id, user_id).await
+            }
+
+            async fn add_organization_member(&self, member: &OrganizationMember) -> Result<(), AppError> {
+                (**self).add_organization_member(member).await
+            }
+
+            async fn remove_organization_member(&self, org_id: &str, user_id: &str) -> Result<(), AppError> {
+                (**self).remove_organization_member(org_id, user_id).await
+            }
+
+            async fn count_organization_mailboxes(&self, org_id: &str) -> Result<i64, AppError> {
+                (**self).count_organization_mailboxes(org_id).await
+            }
+
+            async fn count_user_mailboxes(&self, owner_id: &str) -> Result<u64, AppError> {
+                (**self).count_user_mailboxes(owner_id).await
+            }
+
+            async fn record_api_key_usage(&self, usage: &ApiKeyUsage) -> Result<(), AppError> {
+                (**self).record_api_key_usage(usage).await
+            }
+
+            async fn get_api_key_usage(
+                &self,
+                api_key_id: &str,
+                since: i64,
+                until: i64,
+                bucket_seconds: i64,
+            ) -> Result<Vec<ApiKeyUsageBucket>, AppError> {
+                (**self).get_api_key_usage(api_key_id, since, until, bucket_seconds).await
+            }
+
+            async fn get_api_key_top_endpoints(
+                &self,
+                api_key_id: &str,
+                since: i64,
+                limit: i64,
+            ) -> Result<Vec<ApiKeyEndpointUsage>, AppError> {
+                (**self).get_api_key_top_endpoints(api_key_id, since, limit).await
+            }
+
+            async fn cleanup_api_key_usage(&self, older_than: i64) -> Result<(), AppError> {
+                (**self).cleanup_api_key_usage(older_than).await
+            }
+
+            async fn cleanup_login_attempts(&self, older_than: i64) -> Result<u64, AppError> {
+                (**self).cleanup_login_attempts(older_than).await
+            }
+
+            async fn cleanup_expired_tokens(&self, now: i64) -> Result<u64, AppError> {
+                (**self).cleanup_expired_tokens(now).await
+            }
+
+            async fn save_bounce(&self, bounce: &Bounce) -> Result<bool, AppError> {
+                (**self).save_bounce(bounce).await
+            }
+
+            async fn get_bounces(&self, recipient: Option<&str>, permanent: Option<bool>) -> Result<Vec<Bounce>, AppError> {
+                (**self).get_bounces(recipient, permanent).await
+            }
+
+            async fn cleanup_bounces(&self, older_than: i64) -> Result<u64, AppError> {
+                (**self).cleanup_bounces(older_than).await
+            }
+
+            async fn append_audit_log(&self, entry: &AuditEntry) -> Result<(), AppError> {
+                (**self).append_audit_log(entry).await
+            }
+
+            async fn get_audit_log(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<AuditEntry>, AppError> {
+                (**self).get_audit_log(user_id, page, per_page).await
+            }
+
+            async fn record_login_event(&self, event: &LoginEvent) -> Result<(), AppError> {
+                (**self).record_login_event(event).await
+            }
+
+            async fn get_login_events(&self, user_id: &str, page: i64, per_page: i64) -> Result<Vec<LoginEvent>, AppError> {
+                (**self).get_login_events(user_id, page, per_page).await
+            }
+
+            async fn get_login_countries(&self, user_id: &str, since: i64) -> Result<Vec<String>, AppError> {
+                (**self).get_login_countries(user_id, since).await
+            }
+
+            async fn get_login_geography(&self) -> Result<HashMap<String, u64>, AppError> {
+                (**self).get_login_geography().await
+            }
+
+            async fn get_feature_flags(&self) -> Result<Vec<FeatureFlag>, AppError> {
+                (**self).get_feature_flags().await
+            }
+
+            async fn set_feature_flag(&self, flag: &FeatureFlag) -> Result<(), AppError> {
+                (**self).set_feature_flag(flag).await
+            }
+
+            async fn greylist_check_and_insert(&self, ip: &str, from: &str, to: &str, delay_secs: i64) -> Result<GreylistStatus, AppError> {
+                (**self).greylist_check_and_insert(ip, from, to, delay_secs).await
+            }
+
+            async fn greylist_remove(&self, ip: &str, from: &str, to: &str) -> Result<(), AppError> {
+                (**self).greylist_remove(ip, from, to).await
+            }
+
+            async fn greylist_cleanup(&self, older_than: i64) -> Result<u64, AppError> {
+                (**self).greylist_cleanup(older_than).await
+            }
+        }
+    )+};
+}
+
+delegate_database!(Arc<D>, Box<D>, &D);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_busy_database_error() {
+        let path = std::env::temp_dir().join(format!("busy-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}", path.display());
+        let settings = SqliteSettings {
+            busy_timeout: Duration::from_millis(100),
+            busy_max_retries: 2,
+            ..SqliteSettings::default()
+        };
+        let holder = SqliteDatabase::new_with_settings(&url, settings).await.unwrap();
+        let writer = SqliteDatabase::new_with_settings(&url, settings).await.unwrap();
+
+        let mut conn = holder.pool().acquire().await.unwrap();
+        sqlx::query("BEGIN IMMEDIATE").execute(&mut *conn).await.unwrap();
+
+        let err = writer.create_user("busy", AuthType::Password).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Database error: Database temporarily unavailable - try again shortly"
+        );
+
+        sqlx::query("ROLLBACK").execute(&mut *conn).await.unwrap();
+        writer.create_user("busy", AuthType::Password).await.unwrap();
+
+        drop(conn);
+        holder.pool().close().await;
+        writer.pool().close().await;
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_backup_key_without_credentials() {
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        db.init().await.unwrap();
+        // As for users signed up through an OAuth provider
+        let user = db.create_user("no-credentials", AuthType::GitHub).await.unwrap();
+        let backup_key = |public_key: &str| BackupKey {
+            public_key: public_key.to_string(),
+            encrypted_key: "encrypted".to_string(),
+        };
+
+        assert!(db.set_backup_key(&user.id, &backup_key("first")).await.unwrap());
+        assert!(!db.set_backup_key(&user.id, &backup_key("second")).await.unwrap());
+        assert_eq!(db.get_backup_key(&user.id).await.unwrap().unwrap().public_key, "first");
+    }
+
+    #[tokio::test]
+    async fn test_bounce_dedupe_and_cleanup() {
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        db.init().await.unwrap();
+        let bounce = |id: &str, recipient: &str, bounced_at: i64| Bounce {
+            id: id.to_string(),
+            message_id: Some("<original@example.org>".to_string()),
+            original_recipient: recipient.to_string(),
+            status_code: "5.1.1".to_string(),
+            diagnostic: None,
+            permanent: true,
+            bounced_at,
+        };
+
+        assert!(db.save_bounce(&bounce("1", "missing@example.com", 100)).await.unwrap());
+        // The same DSN delivered again
+        assert!(!db.save_bounce(&bounce("2", "missing@example.com", 200)).await.unwrap());
+        assert!(db.save_bounce(&bounce("3", "other@example.com", 300)).await.unwrap());
+        assert_eq!(db.get_bounces(None, None).await.unwrap().len(), 2);
+
+        assert_eq!(db.cleanup_bounces(200).await.unwrap(), 1);
+        let remaining = db.get_bounces(None, None).await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].original_recipient, "other@example.com");
+    }
+
+    #[tokio::test]
+    async fn test_login_events() {
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        db.init().await.unwrap();
+        let login = |user_id: &str, country_code: Option<&str>, logged_in_at: i64| LoginEvent {
+            user_id: user_id.to_string(),
+            ip: Some("192.0.2.1".to_string()),
+            country_code: country_code.map(str::to_string),
+            city: None,
+            logged_in_at,
+        };
+
+        for event in [
+            login("alice", Some("DE"), 100),
+            login("alice", Some("FR"), 200),
+            login("alice", None, 300),
+            login("bob", Some("DE"), 300),
+        ] {
+            db.record_login_event(&event).await.unwrap();
+        }
+
+        let events = db.get_login_events("alice", 1, 2).await.unwrap();
+        let times: Vec<_> = events.iter().map(|event| event.logged_in_at).collect();
+        assert_eq!(times, [300, 200]);
+
+        let mut countries = db.get_login_countries("alice", 0).await.unwrap();
+        countries.sort();
+        assert_eq!(countries, ["DE", "FR"]);
+        assert_eq!(db.get_login_countries("alice", 150).await.unwrap(), ["FR"]);
+
+        let geography = db.get_login_geography().await.unwrap();
+        assert_eq!(geography, HashMap::from([("DE".to_string(), 2), ("FR".to_string(), 1)]));
+    }
+
+    #[test]
+    fn test_slow_query_threshold_from_env() {
+        assert_eq!(SqliteSettings::default().slow_query_threshold, Duration::from_millis(100));
+        std::env::set_var("DATABASE_SLOW_QUERY_THRESHOLD_MS", "250");
+        let settings = SqliteSettings::from_env();
+        std::env::remove_var("DATABASE_SLOW_QUERY_THRESHOLD_MS");
+        assert_eq!(settings.unwrap().slow_query_threshold, Duration::from_millis(250));
+    }
+
+    #[tokio::test]
+    async fn test_dry_run_migrations_leaves_database_untouched() {
+        let path = std::env::temp_dir().join(format!("dry-run-{}.db", uuid::Uuid::new_v4()));
+        let url = format!("sqlite:{}", path.display());
+        Sqlite::create_database(&url).await.unwrap();
+
+        let mut conn = SqliteConnectOptions::from_str(&url).unwrap().connect().await.unwrap();
+        sqlx::query("CREATE TABLE legacy (id INTEGER PRIMARY KEY)").execute(&mut conn).await.unwrap();
+
+        let pending = sqlx::migrate!("./migrations").iter().count();
+        assert_eq!(dry_run_migrations(&url).await.unwrap(), pending);
+        assert_eq!(count_applied_migrations(&mut conn).await.unwrap(), 0);
+
+        // Once applied for real there is nothing left to do
+        conn.close().await.unwrap();
+        let db = SqliteDatabase::new(&url).await.unwrap();
+        db.pool().close().await;
+        assert_eq!(dry_run_migrations(&url).await.unwrap(), 0);
+
+        for suffix in ["", "-wal", "-shm"] {
+            let _ = std::fs::remove_file(format!("{}{}", path.display(), suffix));
+        }
     }
 }
@@ -0,0 +1,115 @@
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+use tokio::sync::broadcast;
+
+/// Events a slow subscriber may fall behind by before it misses some
+const EMAIL_EVENT_CAPACITY: usize = 1024;
+/// Events kept for clients resuming a stream with `Last-Event-ID`
+const RECENT_EVENTS: usize = 50;
+
+/// Published by the mail service whenever it saves a new email
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct EmailEvent {
+    pub mailbox_id: String,
+    pub email_id: String,
+    pub received_at: i64,
+}
+
+/// Broadcasts email events and keeps the most recent ones for replay
+#[derive(Clone)]
+pub struct EmailEvents {
+    sender: broadcast::Sender<EmailEvent>,
+    recent: Arc<Mutex<VecDeque<EmailEvent>>>,
+}
+
+impl Default for EmailEvents {
+    fn default() -> Self {
+        Self {
+            sender: broadcast::channel(EMAIL_EVENT_CAPACITY).0,
+            recent: Arc::new(Mutex::new(VecDeque::with_capacity(RECENT_EVENTS))),
+        }
+    }
+}
+
+static SHARED_EMAIL_EVENTS: Lazy<EmailEvents> = Lazy::new(EmailEvents::default);
+
+impl EmailEvents {
+    /// The process-wide email events, shared by the SMTP service and the live
+    /// notifications of the web app
+    pub fn shared() -> Self {
+        SHARED_EMAIL_EVENTS.clone()
+    }
+
+    pub fn publish(&self, event: EmailEvent) {
+        let mut recent = self.recent.lock().unwrap();
+        if recent.len() == RECENT_EVENTS {
+            recent.pop_front();
+        }
+        recent.push_back(event.clone());
+        // Nobody may be listening, which is not an error
+        let _ = self.sender.send(event);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<EmailEvent> {
+        self.sender.subscribe()
+    }
+
+    /// Subscribes and returns the recent events published after the one for
+    /// `last_email_id`. All recent events are returned when that one is no
+    /// longer kept, since the client may have missed any of them.
+    pub fn subscribe_after(&self, last_email_id: &str) -> (Vec<EmailEvent>, broadcast::Receiver<EmailEvent>) {
+        // Subscribing under the lock means no event is both replayed and received
+        let recent = self.recent.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let start = recent
+            .iter()
+            .position(|event| event.email_id == last_email_id)
+            .map_or(0, |position| position + 1);
+        (recent.iter().skip(start).cloned().collect(), receiver)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(email_id: &str) -> EmailEvent {
+        EmailEvent {
+            mailbox_id: "mailbox".to_string(),
+            email_id: email_id.to_string(),
+            received_at: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_after() {
+        let events = EmailEvents::default();
+        for id in ["a", "b", "c"] {
+            events.publish(event(id));
+        }
+
+        let (replayed, mut receiver) = events.subscribe_after("a");
+        assert_eq!(replayed, vec![event("b"), event("c")]);
+        assert!(events.subscribe_after("c").0.is_empty());
+        assert_eq!(events.subscribe_after("unknown").0.len(), 3);
+
+        events.publish(event("d"));
+        assert_eq!(receiver.recv().await.unwrap(), event("d"));
+    }
+
+    #[test]
+    fn test_recent_events_are_bounded() {
+        let events = EmailEvents::default();
+        for i in 0..RECENT_EVENTS + 10 {
+            events.publish(event(&i.to_string()));
+        }
+
+        let (replayed, _) = events.subscribe_after("unknown");
+        assert_eq!(replayed.len(), RECENT_EVENTS);
+        assert_eq!(replayed[0], event("10"));
+    }
+}
@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use tracing::info;
+
+use crate::{db::Database, AppError};
+
+pub const GREYLISTING: &str = "greylisting";
+pub const SPF: &str = "spf";
+pub const DKIM: &str = "dkim";
+
+/// Flags that can be overridden at runtime
+pub const KNOWN_FLAGS: &[&str] = &[GREYLISTING, SPF, DKIM];
+
+/// Mail service switches that start from the configuration and can be
+/// overridden through the `feature_flags` table without a restart.
+/// Clones share the same values.
+#[derive(Debug, Clone, Default)]
+pub struct FeatureFlags {
+    defaults: Arc<HashMap<String, bool>>,
+    flags: Arc<RwLock<HashMap<String, bool>>>,
+}
+
+impl FeatureFlags {
+    pub fn new<'a>(defaults: impl IntoIterator<Item = (&'a str, bool)>) -> Self {
+        let defaults: HashMap<String, bool> = defaults
+            .into_iter()
+            .map(|(name, enabled)| (name.to_string(), enabled))
+            .collect();
+        Self {
+            flags: Arc::new(RwLock::new(defaults.clone())),
+            defaults: Arc::new(defaults),
+        }
+    }
+
+    /// Unknown flags are disabled
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.flags.read().unwrap().get(name).copied().unwrap_or(false)
+    }
+
+    /// Re-reads the overrides from the database. Flags without an override
+    /// return to their configured default.
+    pub async fn reload(&self, db: &dyn Database) -> Result<(), AppError> {
+        let overrides = db.get_feature_flags().await?;
+
+        let mut flags = (*self.defaults).clone();
+        for flag in overrides {
+            flags.insert(flag.flag_name, flag.enabled);
+        }
+
+        let mut current = self.flags.write().unwrap();
+        for (name, enabled) in &flags {
+            if current.get(name) != Some(enabled) {
+                info!("Feature flag {} is now {}", name, if *enabled { "enabled" } else { "disabled" });
+            }
+        }
+        *current = flags;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::SqliteDatabase, FeatureFlag};
+
+    #[tokio::test]
+    async fn test_reload_applies_overrides() {
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        let flags = FeatureFlags::new([(GREYLISTING, true), (SPF, false)]);
+        assert!(flags.is_enabled(GREYLISTING));
+        assert!(!flags.is_enabled("unknown"));
+
+        let flag = FeatureFlag {
+            flag_name: SPF.to_string(),
+            enabled: true,
+            updated_by: None,
+            updated_at: 0,
+        };
+        db.set_feature_flag(&flag).await.unwrap();
+        flags.reload(&db).await.unwrap();
+
+        // Clones see the reloaded values
+        let clone = flags.clone();
+        assert!(clone.is_enabled(SPF));
+        assert!(clone.is_enabled(GREYLISTING));
+    }
+}
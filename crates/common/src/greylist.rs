@@ -0,0 +1,211 @@
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+#[derive(Debug, Clone, Hash, PartialEq, Eq)]
+pub struct GreylistKey {
+    pub ip: IpAddr,
+    pub sender: String,
+    pub recipient: String,
+}
+
+impl GreylistKey {
+    pub fn new(ip: IpAddr, sender: &str, recipient: &str) -> Self {
+        Self {
+            ip,
+            sender: sender.to_string(),
+            recipient: recipient.to_string(),
+        }
+    }
+
+    /// Stable identifier used by the admin API
+    pub fn id(&self) -> String {
+        let digest = Sha256::new()
+            .chain_update(self.ip.to_string())
+            .chain_update([0])
+            .chain_update(&self.sender)
+            .chain_update([0])
+            .chain_update(&self.recipient)
+            .finalize();
+        hex::encode(&digest[..8])
+    }
+}
+
+/// Outcome of checking a triple against the persisted greylist
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GreylistStatus {
+    /// Delivery has to be retried from `eligible_at`
+    Deferred { first_seen: i64, eligible_at: i64 },
+    /// The delay has passed, and the entry was removed
+    Passed,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct GreylistRecord {
+    first_seen: i64,
+    eligible_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct GreylistEntry {
+    pub id: String,
+    pub ip: String,
+    pub sender: String,
+    pub recipient: String,
+    pub first_seen: i64,
+    pub eligible_at: i64,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GreylistFilter {
+    pub ip: Option<IpAddr>,
+    /// Matched case-insensitively against the part of the sender after `@`
+    pub sender_domain: Option<String>,
+}
+
+impl GreylistFilter {
+    fn matches(&self, key: &GreylistKey) -> bool {
+        self.ip.is_none_or(|ip| key.ip == ip)
+            && self.sender_domain.as_deref().is_none_or(|domain| {
+                key.sender
+                    .rsplit_once('@')
+                    .is_some_and(|(_, sender_domain)| sender_domain.eq_ignore_ascii_case(domain))
+            })
+    }
+}
+
+/// Pending (IP, sender, recipient) triples that were deferred by greylisting.
+/// Clones share the same entries; every change updates the `greylist_size` gauge.
+#[derive(Debug, Clone, Default)]
+pub struct Greylist {
+    entries: Arc<DashMap<GreylistKey, GreylistRecord>>,
+}
+
+static SHARED_GREYLIST: Lazy<Greylist> = Lazy::new(Greylist::default);
+
+impl Greylist {
+    /// The process-wide greylist, shared by the SMTP service and the admin API
+    pub fn shared() -> Self {
+        SHARED_GREYLIST.clone()
+    }
+
+    /// Time from which the triple may be delivered, if it is greylisted
+    pub fn eligible_at(&self, key: &GreylistKey) -> Option<i64> {
+        self.entries.get(key).map(|record| record.eligible_at)
+    }
+
+    pub fn insert(&self, key: GreylistKey, first_seen: i64, eligible_at: i64) {
+        self.entries.insert(key, GreylistRecord { first_seen, eligible_at });
+        self.update_gauge();
+    }
+
+    /// The key of the entry with the given ID
+    pub fn key_by_id(&self, id: &str) -> Option<GreylistKey> {
+        self.entries.iter().find(|entry| entry.key().id() == id).map(|entry| entry.key().clone())
+    }
+
+    pub fn remove(&self, key: &GreylistKey) {
+        self.entries.remove(key);
+        self.update_gauge();
+    }
+
+    /// Removes the entry with the given ID, returning whether it existed
+    pub fn remove_by_id(&self, id: &str) -> bool {
+        let before = self.entries.len();
+        self.entries.retain(|key, _| key.id() != id);
+        self.update_gauge();
+        self.entries.len() < before
+    }
+
+    /// Drops entries first seen before `cutoff`
+    pub fn remove_older_than(&self, cutoff: i64) {
+        self.entries.retain(|_, record| record.first_seen >= cutoff);
+        self.update_gauge();
+    }
+
+    /// Removes every entry, returning how many there were
+    pub fn clear(&self) -> usize {
+        let count = self.entries.len();
+        self.entries.clear();
+        self.update_gauge();
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Matching entries, newest first, with the total number of matches
+    pub fn list(&self, filter: &GreylistFilter, offset: usize, limit: usize) -> (Vec<GreylistEntry>, usize) {
+        let mut entries: Vec<GreylistEntry> = self.entries
+            .iter()
+            .filter(|entry| filter.matches(entry.key()))
+            .map(|entry| GreylistEntry {
+                id: entry.key().id(),
+                ip: entry.key().ip.to_string(),
+                sender: entry.key().sender.clone(),
+                recipient: entry.key().recipient.clone(),
+                first_seen: entry.value().first_seen,
+                eligible_at: entry.value().eligible_at,
+            })
+            .collect();
+        entries.sort_by(|a, b| b.first_seen.cmp(&a.first_seen).then_with(|| a.id.cmp(&b.id)));
+
+        let total = entries.len();
+        (entries.into_iter().skip(offset).take(limit).collect(), total)
+    }
+
+    fn update_gauge(&self) {
+        metrics::gauge!("greylist_size").set(self.entries.len() as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(ip: &str, sender: &str) -> GreylistKey {
+        GreylistKey::new(ip.parse().unwrap(), sender, "inbox@example.com")
+    }
+
+    #[test]
+    fn test_list_filters_and_paginates() {
+        let greylist = Greylist::default();
+        greylist.insert(key("192.0.2.1", "a@Sender.com"), 100, 400);
+        greylist.insert(key("192.0.2.1", "b@other.com"), 200, 500);
+        greylist.insert(key("192.0.2.2", "c@sender.com"), 300, 600);
+
+        let (entries, total) = greylist.list(&GreylistFilter::default(), 0, 2);
+        assert_eq!(total, 3);
+        assert_eq!(entries.iter().map(|e| e.first_seen).collect::<Vec<_>>(), vec![300, 200]);
+
+        let by_ip = GreylistFilter { ip: Some("192.0.2.1".parse().unwrap()), ..Default::default() };
+        assert_eq!(greylist.list(&by_ip, 0, 10).1, 2);
+
+        let by_domain = GreylistFilter { sender_domain: Some("sender.com".into()), ..Default::default() };
+        let (entries, total) = greylist.list(&by_domain, 1, 10);
+        assert_eq!(total, 2);
+        assert_eq!(entries[0].sender, "a@Sender.com");
+    }
+
+    #[test]
+    fn test_remove_by_id_and_clear() {
+        let greylist = Greylist::default();
+        let first = key("192.0.2.1", "a@sender.com");
+        greylist.insert(first.clone(), 100, 400);
+        greylist.insert(key("192.0.2.2", "b@sender.com"), 100, 400);
+
+        assert!(greylist.remove_by_id(&first.id()));
+        assert!(!greylist.remove_by_id(&first.id()));
+        assert_eq!(greylist.eligible_at(&first), None);
+        assert_eq!(greylist.clear(), 1);
+        assert!(greylist.is_empty());
+    }
+}
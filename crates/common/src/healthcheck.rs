@@ -0,0 +1,147 @@
+use axum::{
+    extract::State,
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// How long the readiness probe waits for a pooled connection
+const READY_ACQUIRE_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long the readiness query may take before the database counts as degraded
+const READY_QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Health {
+    pub status: String,
+    pub uptime_seconds: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum Readiness {
+    Ready,
+    Degraded { reason: String },
+}
+
+/// Liveness and readiness probes for container orchestration
+#[derive(Clone)]
+pub struct HealthCheck {
+    started: Instant,
+    pool: SqlitePool,
+    smtp_bound: Option<watch::Receiver<bool>>,
+}
+
+impl HealthCheck {
+    pub fn new(pool: SqlitePool) -> Self {
+        Self {
+            started: Instant::now(),
+            pool,
+            smtp_bound: None,
+        }
+    }
+
+    /// Only reports ready while the SMTP server is listening, for when it
+    /// runs in the same process
+    pub fn with_smtp_bound(mut self, smtp_bound: watch::Receiver<bool>) -> Self {
+        self.smtp_bound = Some(smtp_bound);
+        self
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            status: "ok".to_string(),
+            uptime_seconds: self.started.elapsed().as_secs(),
+        }
+    }
+
+    pub async fn readiness(&self) -> Readiness {
+        let degraded = |reason: &str| Readiness::Degraded { reason: reason.to_string() };
+
+        if self.smtp_bound.as_ref().is_some_and(|bound| !*bound.borrow()) {
+            return degraded("SMTP server is not listening");
+        }
+        // A pool with no connection to spare counts as exhausted rather than
+        // making the probe wait for one
+        let mut conn = match tokio::time::timeout(READY_ACQUIRE_TIMEOUT, self.pool.acquire()).await {
+            Ok(Ok(conn)) => conn,
+            Ok(Err(e)) => return degraded(&format!("Database connection failed: {}", e)),
+            Err(_) => return degraded("Database connection pool exhausted"),
+        };
+        match tokio::time::timeout(READY_QUERY_TIMEOUT, sqlx::query("SELECT 1").execute(&mut *conn)).await {
+            Ok(Ok(_)) => Readiness::Ready,
+            Ok(Err(e)) => degraded(&format!("Database query failed: {}", e)),
+            Err(_) => degraded("Database query timed out"),
+        }
+    }
+
+    /// `GET /api/health` and `GET /api/ready`, both without authentication
+    pub fn routes<S: Clone + Send + Sync + 'static>(self) -> Router<S> {
+        Router::new()
+            .route("/api/health", get(health))
+            .route("/api/ready", get(ready))
+            .with_state(self)
+    }
+}
+
+async fn health(State(check): State<HealthCheck>) -> Json<Health> {
+    Json(check.health())
+}
+
+async fn ready(State(check): State<HealthCheck>) -> Response {
+    let readiness = check.readiness().await;
+    let status = match readiness {
+        Readiness::Ready => StatusCode::OK,
+        Readiness::Degraded { .. } => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    (status, Json(readiness)).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{Database, SqliteDatabase};
+
+    #[tokio::test]
+    async fn test_readiness() {
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        let (smtp_bound, smtp_bound_rx) = watch::channel(false);
+        let check = HealthCheck::new(db.pool().clone());
+        assert_eq!(check.readiness().await, Readiness::Ready);
+        assert_eq!(check.health().status, "ok");
+
+        let check = check.with_smtp_bound(smtp_bound_rx);
+        assert_eq!(
+            check.readiness().await,
+            Readiness::Degraded { reason: "SMTP server is not listening".to_string() }
+        );
+        smtp_bound.send_replace(true);
+        assert_eq!(check.readiness().await, Readiness::Ready);
+
+        let conn = db.pool().acquire().await.unwrap();
+        assert_eq!(
+            check.readiness().await,
+            Readiness::Degraded { reason: "Database connection pool exhausted".to_string() }
+        );
+        drop(conn);
+
+        db.pool().close().await;
+        assert!(matches!(
+            check.readiness().await,
+            Readiness::Degraded { reason } if reason.starts_with("Database connection failed")
+        ));
+    }
+
+    #[test]
+    fn test_readiness_json() {
+        assert_eq!(serde_json::to_value(Readiness::Ready).unwrap(), serde_json::json!({ "status": "ready" }));
+        assert_eq!(
+            serde_json::to_value(Readiness::Degraded { reason: "down".to_string() }).unwrap(),
+            serde_json::json!({ "status": "degraded", "reason": "down" })
+        );
+    }
+}
@@ -0,0 +1,157 @@
+use once_cell::sync::Lazy;
+use rand::{rngs::OsRng, Rng, RngCore};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+// Crockford base32, lowercased so the output sorts the same way as the raw value
+const TIME_ORDERED_CHARSET: &[u8] = b"0123456789abcdefghjkmnpqrstvwxyz";
+const NANOID_CHARSET: &[u8] = b"0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ";
+const NANOID_LENGTH: usize = 21;
+
+pub trait IdGenerator: Send + Sync {
+    fn generate(&self) -> String;
+}
+
+/// Random v4 UUIDs (the historical format)
+pub struct UuidGenerator;
+
+impl IdGenerator for UuidGenerator {
+    fn generate(&self) -> String {
+        uuid::Uuid::new_v4().to_string()
+    }
+}
+
+/// 48-bit millisecond timestamp followed by 80 random bits, encoded as 26
+/// base32 characters. IDs generated by the same process are strictly
+/// increasing, which makes them usable as pagination cursors.
+pub struct TimeOrderedGenerator;
+
+// (last timestamp in ms, last random part)
+static TIME_ORDERED_STATE: Lazy<Mutex<(u64, u128)>> = Lazy::new(|| Mutex::new((0, 0)));
+
+impl IdGenerator for TimeOrderedGenerator {
+    fn generate(&self) -> String {
+        const RANDOM_BITS: u32 = 80;
+        const RANDOM_MASK: u128 = (1 << RANDOM_BITS) - 1;
+
+        let now = chrono::Utc::now().timestamp_millis().max(0) as u64;
+        let mut state = TIME_ORDERED_STATE.lock().unwrap();
+
+        let (timestamp, random) = if now <= state.0 {
+            // Same millisecond (or clock went backwards): bump the random part
+            // so ordering is preserved within this process
+            let next = (state.1 + 1) & RANDOM_MASK;
+            if next == 0 {
+                (state.0 + 1, next)
+            } else {
+                (state.0, next)
+            }
+        } else {
+            let mut bytes = [0u8; 16];
+            OsRng.fill_bytes(&mut bytes[6..]);
+            (now, u128::from_be_bytes(bytes) & RANDOM_MASK)
+        };
+        *state = (timestamp, random);
+        drop(state);
+
+        let mut value = ((timestamp as u128) << RANDOM_BITS) | random;
+        let mut encoded = [0u8; 26];
+        for digit in encoded.iter_mut().rev() {
+            *digit = TIME_ORDERED_CHARSET[(value & 0x1f) as usize];
+            value >>= 5;
+        }
+
+        String::from_utf8(encoded.to_vec()).unwrap()
+    }
+}
+
+/// URL-friendly 21 character base62 IDs
+pub struct NanoIdGenerator;
+
+impl IdGenerator for NanoIdGenerator {
+    fn generate(&self) -> String {
+        let mut rng = OsRng;
+        (0..NANOID_LENGTH)
+            .map(|_| NANOID_CHARSET[rng.gen_range(0..NANOID_CHARSET.len())] as char)
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdFormat {
+    #[default]
+    Uuid,
+    TimeOrdered,
+    NanoId,
+}
+
+impl IdFormat {
+    pub fn generator(self) -> Arc<dyn IdGenerator> {
+        match self {
+            IdFormat::Uuid => Arc::new(UuidGenerator),
+            IdFormat::TimeOrdered => Arc::new(TimeOrderedGenerator),
+            IdFormat::NanoId => Arc::new(NanoIdGenerator),
+        }
+    }
+}
+
+impl FromStr for IdFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "uuid" => Ok(IdFormat::Uuid),
+            "time_ordered" => Ok(IdFormat::TimeOrdered),
+            "nanoid" => Ok(IdFormat::NanoId),
+            other => Err(format!(
+                "Unknown ID format '{}', expected one of: uuid, time_ordered, nanoid",
+                other
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn assert_unique(generator: &dyn IdGenerator) {
+        let ids: HashSet<String> = (0..10_000).map(|_| generator.generate()).collect();
+        assert_eq!(ids.len(), 10_000);
+    }
+
+    #[test]
+    fn test_ids_are_unique() {
+        assert_unique(&UuidGenerator);
+        assert_unique(&TimeOrderedGenerator);
+        assert_unique(&NanoIdGenerator);
+    }
+
+    #[test]
+    fn test_time_ordered_ids_are_sorted() {
+        let ids: Vec<String> = (0..10_000).map(|_| TimeOrderedGenerator.generate()).collect();
+        assert!(ids.windows(2).all(|w| w[0] < w[1]));
+        assert!(ids.iter().all(|id| id.len() == 26));
+
+        let before = TimeOrderedGenerator.generate();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let after = TimeOrderedGenerator.generate();
+        assert!(before < after);
+    }
+
+    #[test]
+    fn test_nanoid_format() {
+        let id = NanoIdGenerator.generate();
+        assert_eq!(id.len(), NANOID_LENGTH);
+        assert!(id.bytes().all(|c| NANOID_CHARSET.contains(&c)));
+    }
+
+    #[test]
+    fn test_parse_id_format() {
+        assert_eq!("uuid".parse::<IdFormat>().unwrap(), IdFormat::Uuid);
+        assert_eq!("TIME_ORDERED".parse::<IdFormat>().unwrap(), IdFormat::TimeOrdered);
+        assert_eq!("nanoid".parse::<IdFormat>().unwrap(), IdFormat::NanoId);
+        assert!("snowflake".parse::<IdFormat>().is_err());
+    }
+}
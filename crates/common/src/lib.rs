@@ -8,12 +8,45 @@ use axum::middleware::Next;
 use axum::http::Request;
 use axum::body::Body;
 
+pub mod attachments;
+pub mod circuit_breaker;
 pub mod db;
+pub mod events;
+pub mod feature_flags;
+pub mod greylist;
+pub mod healthcheck;
+pub mod id;
+pub mod logging;
+pub mod outbound;
+pub mod proxy;
 pub mod security;
+pub mod shutdown;
 pub mod rate_limit;
+pub mod request_id;
+pub mod search;
+pub mod telemetry;
 
-// 24 characters chosen to be visually distinct
-const ID_CHARSET: &[u8] = b"3479acdefhjkmnpqrstuvwxy";
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IdCharset {
+    /// 24 characters chosen to be visually distinct
+    #[default]
+    VisuallyDistinct,
+    AlphanumericLower,
+    /// RFC 4648 URL-safe base64 alphabet
+    UrlSafe,
+    Hex,
+}
+
+impl IdCharset {
+    pub fn chars(self) -> &'static [u8] {
+        match self {
+            IdCharset::VisuallyDistinct => b"3479acdefhjkmnpqrstuvwxy",
+            IdCharset::AlphanumericLower => b"0123456789abcdefghijklmnopqrstuvwxyz",
+            IdCharset::UrlSafe => b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789-_",
+            IdCharset::Hex => b"0123456789abcdef",
+        }
+    }
+}
 
 #[derive(Debug, Error)]
 pub enum AppError {
@@ -27,6 +60,8 @@ pub enum AppError {
     Internal(String),
     #[error("Not found: {0}")]
     NotFound(String),
+    #[error("Forbidden: {0}")]
+    Forbidden(String),
 }
 
 pub async fn handle_json_response(
@@ -45,12 +80,15 @@ pub async fn handle_json_response(
     let res = next.run(req).await;
 
     // If not an error or doesn't want JSON, return as is
-    if res.status().is_success() || !wants_json {
+    if res.status().is_success() || res.status() == StatusCode::NOT_MODIFIED || !wants_json {
         return res;
     }
 
     // Convert error response to JSON
     let status = res.status();
+    // Headers such as Retry-After are kept, only the body is replaced
+    let mut headers = res.headers().clone();
+    headers.remove(axum::http::header::CONTENT_LENGTH);
     
     // Create JSON error response
     let error_response = serde_json::json!({
@@ -61,6 +99,7 @@ pub async fn handle_json_response(
     
     (
         status,
+        headers,
         [(axum::http::header::CONTENT_TYPE, "application/json")],
         axum::Json(error_response)
     ).into_response()
@@ -74,6 +113,7 @@ impl IntoResponse for AppError {
             AppError::Mail(msg) => (StatusCode::BAD_REQUEST, msg),
             AppError::Internal(msg) => (StatusCode::INTERNAL_SERVER_ERROR, msg),
             AppError::NotFound(msg) => (StatusCode::NOT_FOUND, msg),
+            AppError::Forbidden(msg) => (StatusCode::FORBIDDEN, msg),
         };
 
         // Create JSON error response
@@ -91,64 +131,124 @@ impl IntoResponse for AppError {
     }
 }
 
-pub fn generate_random_id(len: usize) -> String {
-    const BASE: u128 = 24;
-    const CHUNK_SIZE: usize = 13;
-    const MAX_CHUNK_VALUE: u128 = BASE.pow(CHUNK_SIZE as u32);
+pub fn generate_random_id(len: usize, charset: IdCharset) -> String {
+    random_id_from(len, charset, || OsRng.next_u64())
+}
+
+/// Like [`generate_random_id`], reading the operating system's generator
+/// directly and returning its failures instead of panicking
+pub fn generate_random_id_secure(len: usize, charset: IdCharset) -> Result<String, AppError> {
+    let mut error = None;
+    let id = random_id_from(len, charset, || {
+        let mut bytes = [0u8; 8];
+        if let Err(e) = getrandom::getrandom(&mut bytes) {
+            error.get_or_insert(e);
+        }
+        u64::from_le_bytes(bytes)
+    });
+    match error {
+        Some(e) => Err(AppError::Internal(format!("Failed to read random bytes: {}", e))),
+        None => Ok(id),
+    }
+}
+
+/// Digits of uniform 64-bit values in the charset's base. Values past the
+/// largest power of the base are rejected, which leaves every digit uniform.
+fn random_id_from(len: usize, charset: IdCharset, mut next_u64: impl FnMut() -> u64) -> String {
+    let chars = charset.chars();
+    let base = chars.len() as u128;
+    // Largest number of digits whose range still fits in 64 random bits
+    let mut chunk_size = 0;
+    let mut max_chunk_value: u128 = 1;
+    while max_chunk_value * base <= 1 << 64 {
+        max_chunk_value *= base;
+        chunk_size += 1;
+    }
     let mut result = String::with_capacity(len);
 
     while result.len() < len {
-        // Pull 64 bits; if >= 24^13, discard and retry
-        let val = loop {
-            let r = OsRng.next_u64() as u128;
-            if r < MAX_CHUNK_VALUE {
+        // Pull 64 bits; if >= base^chunk_size, discard and retry
+        let mut val = loop {
+            let r = next_u64() as u128;
+            if r < max_chunk_value {
                 break r;
             }
         };
 
-        // Convert this chunk into 13 base-24 digits
-        let mut tmp = val;
-        let mut chunk = [0u8; CHUNK_SIZE];
-        chunk.iter_mut().take(CHUNK_SIZE).for_each(|digit| {
-            *digit = ID_CHARSET[(tmp % BASE) as usize];
-            tmp /= BASE;
-        });
-
-        // Append them in most-significant digit first (reverse order)
-        for &c in chunk.iter().rev() {
-            if result.len() == len {
-                break;
-            }
-            result.push(c as char);
+        // Only the digits still needed are extracted from the chunk
+        for _ in 0..chunk_size.min(len - result.len()) {
+            result.push(chars[(val % base) as usize] as char);
+            val /= base;
         }
     }
 
     result
 }
 
+/// Kind of recipient a mailbox's public key encrypts to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "snake_case")]
+#[serde(rename_all = "snake_case")]
+pub enum KeyType {
+    #[default]
+    AgeX25519,
+    SshEd25519,
+    SshRsa,
+}
+
+impl KeyType {
+    /// Detects the key type from its prefix: `age1...`, `ssh-ed25519 ...` or `ssh-rsa ...`
+    pub fn detect(public_key: &str) -> Option<Self> {
+        let key = public_key.trim_start();
+        if key.get(..4).is_some_and(|prefix| prefix.eq_ignore_ascii_case("age1")) {
+            Some(KeyType::AgeX25519)
+        } else if key.starts_with("ssh-ed25519 ") {
+            Some(KeyType::SshEd25519)
+        } else if key.starts_with("ssh-rsa ") {
+            Some(KeyType::SshRsa)
+        } else {
+            None
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Mailbox {
     pub id: String,
     pub alias: String,
     pub name: String,
     pub public_key: String,
+    #[serde(default)]
+    pub key_type: KeyType,
     pub owner_id: String,
     pub mail_expires_in: Option<i64>,
     pub created_at: i64,
+    #[serde(default)]
+    pub organization_id: Option<String>,
+    /// Store attachments of incoming emails apart from the email body
+    #[serde(default)]
+    pub strip_attachments: bool,
+    /// When the primary alias was last replaced by a random one
+    #[serde(default)]
+    pub alias_rotated_at: Option<i64>,
 }
 
 impl Mailbox {
     pub fn new(owner_id: &str, _domain: &str, mail_expires_in: Option<i64>) -> Self {
-        let id = generate_random_id(12); // Use 12 characters for the ID
-        let alias = generate_random_id(12); // Use 12 characters for the alias
+        let id = generate_random_id(12, IdCharset::VisuallyDistinct); // Use 12 characters for the ID
+        let alias = generate_random_id(12, IdCharset::VisuallyDistinct); // Use 12 characters for the alias
         Self {
             id,
             alias,
             name: String::new(),
             public_key: String::new(),
+            key_type: KeyType::default(),
             owner_id: owner_id.to_string(),
             mail_expires_in,
             created_at: chrono::Utc::now().timestamp(),
+            organization_id: None,
+            strip_attachments: false,
+            alias_rotated_at: None,
         }
     }
 
@@ -157,6 +257,152 @@ impl Mailbox {
     }
 }
 
+/// A mailbox as listed to a user, with its email counts
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxWithStats {
+    #[serde(flatten)]
+    pub mailbox: Mailbox,
+    pub email_count: u64,
+    /// Emails received since the user last listed the mailbox's emails
+    pub unread_count: u64,
+}
+
+/// A mailbox with its email count, for listings without a viewing user
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MailboxSummary {
+    #[serde(flatten)]
+    pub mailbox: Mailbox,
+    pub email_count: u64,
+}
+
+/// An address routing to a mailbox. The primary alias is `Mailbox::alias`
+/// and cannot be removed; secondary aliases are added by the owner.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MailboxAlias {
+    pub id: String,
+    pub mailbox_id: String,
+    pub alias: String,
+    pub created_at: i64,
+    pub is_primary: bool,
+}
+
+/// A public key the emails of a mailbox are encrypted to, besides or as its
+/// primary `public_key`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct MailboxKey {
+    pub mailbox_id: String,
+    pub label: String,
+    pub public_key: String,
+    pub key_type: KeyType,
+    pub added_at: i64,
+}
+
+impl MailboxKey {
+    /// Label of the key mirroring the mailbox's `public_key`
+    pub const PRIMARY_LABEL: &'static str = "primary";
+}
+
+/// An HTTP callback notified of a mailbox's events, signed with `secret`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Webhook {
+    pub id: String,
+    pub mailbox_id: String,
+    pub url: String,
+    /// Only shown once, when the webhook is created
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    pub events: Vec<String>,
+    #[serde(default)]
+    pub signature_algorithm: SignatureAlgorithm,
+    /// Tera template the payload is rendered from instead of the default JSON
+    #[serde(default)]
+    pub payload_template: Option<String>,
+    pub created_at: i64,
+    pub last_triggered_at: Option<i64>,
+    /// HTTP status of the last delivery, `None` if it got no response
+    pub last_status: Option<i64>,
+}
+
+impl Webhook {
+    pub const EMAIL_RECEIVED: &'static str = "email.received";
+    /// Sent on request to check the endpoint, whatever the subscribed events
+    pub const TEST: &'static str = "webhook.test";
+    pub const EVENTS: [&'static str; 1] = [Self::EMAIL_RECEIVED];
+
+    pub fn subscribes_to(&self, event: &str) -> bool {
+        self.events.iter().any(|subscribed| subscribed == event)
+    }
+}
+
+/// HMAC a webhook's payloads are signed with, named in the signature prefix
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum SignatureAlgorithm {
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+/// Where a forwarding rule sends copies of a mailbox's emails
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum ForwardingKind {
+    /// The email is POSTed as JSON, still encrypted, to the `destination` URL
+    Webhook,
+    /// A copy encrypted to its own key is saved in the `destination` mailbox
+    Mailbox,
+}
+
+/// Forwards the received emails matching both filters, each a
+/// case-insensitive glob pattern matching everything when `None`
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardingRule {
+    pub id: String,
+    pub mailbox_id: String,
+    pub kind: ForwardingKind,
+    /// URL of a webhook or ID of a mailbox
+    pub destination: String,
+    pub filter_from: Option<String>,
+    pub filter_subject: Option<String>,
+    pub created_at: i64,
+}
+
+impl ForwardingRule {
+    /// Invalid patterns match nothing
+    pub fn matches(&self, email: &Email) -> bool {
+        fn glob_matches(pattern: Option<&str>, value: &str) -> bool {
+            let Some(pattern) = pattern else {
+                return true;
+            };
+            let options = glob::MatchOptions {
+                case_sensitive: false,
+                require_literal_separator: false,
+                require_literal_leading_dot: false,
+            };
+            glob::Pattern::new(pattern).is_ok_and(|pattern| pattern.matches_with(value, options))
+        }
+
+        glob_matches(self.filter_from.as_deref(), &email.from_addr)
+            && glob_matches(self.filter_subject.as_deref(), &email.subject)
+    }
+
+    pub fn is_valid_filter(pattern: &str) -> bool {
+        glob::Pattern::new(pattern).is_ok()
+    }
+}
+
+/// Secondary recipient of a user's emails, used to recover them when the
+/// mailbox private key is lost
+#[derive(Debug, Clone)]
+pub struct BackupKey {
+    /// age X25519 recipient added to every email of the user's mailboxes
+    pub public_key: String,
+    /// Base64 age file of the identity, encrypted with the user's passphrase
+    pub encrypted_key: String,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct Email {
     pub id: String,
@@ -164,6 +410,179 @@ pub struct Email {
     pub encrypted_content: String,
     pub received_at: i64,
     pub expires_at: Option<i64>,
+    /// Plaintext copies of the headers, for listing and filtering without
+    /// decrypting; `encrypted_content` remains the full message
+    #[serde(default)]
+    pub from_addr: String,
+    #[serde(default)]
+    pub to_addr: String,
+    #[serde(default)]
+    pub subject: String,
+    /// JSON object of the headers listed in [`CAPTURED_HEADERS`], served by
+    /// its own endpoint rather than with the email
+    #[serde(skip)]
+    pub headers_json: Option<String>,
+    /// Size of the message as received, before attachments were stripped and
+    /// it was encrypted; 0 for emails stored before it was recorded
+    #[serde(default, rename = "size_bytes")]
+    pub raw_size_bytes: i64,
+    /// The Message-ID header with its angle brackets, or one generated on
+    /// delivery for messages without it. Unique within the mailbox.
+    #[serde(skip)]
+    pub message_id: Option<String>,
+}
+
+/// Headers stored in plaintext with every email, in addition to all `X-*`
+/// headers. Trace and authentication headers such as `Received` and
+/// `Authentication-Results` are left out, as are the MIME headers, which
+/// describe the body.
+pub const CAPTURED_HEADERS: &[&str] = &["from", "to", "subject", "date", "message-id"];
+/// Bounds the plaintext kept for messages with many `X-*` headers
+const MAX_CAPTURED_HEADERS: usize = 64;
+
+impl Email {
+    /// Copies the From, To, Subject and Message-ID headers of the parsed
+    /// message, and the captured headers into `headers_json`
+    pub fn with_headers(mut self, message: &mail_parser::Message) -> Self {
+        self.from_addr = header_addresses(message.from()).join(", ");
+        self.to_addr = header_addresses(message.to()).join(", ");
+        self.subject = message.subject().unwrap_or_default().to_string();
+        self.headers_json = Some(captured_headers(message).to_string());
+        self.message_id = message.message_id().map(|id| format!("<{}>", id));
+        self
+    }
+}
+
+/// Raw values of the captured headers, unfolded and keyed by lowercase
+/// name. Only the first of repeated headers is kept.
+fn captured_headers(message: &mail_parser::Message) -> serde_json::Value {
+    let mut headers = serde_json::Map::new();
+    for (name, value) in message.headers_raw() {
+        let name = name.to_ascii_lowercase();
+        if headers.len() == MAX_CAPTURED_HEADERS {
+            break;
+        }
+        if headers.contains_key(&name) || !(CAPTURED_HEADERS.contains(&name.as_str()) || name.starts_with("x-")) {
+            continue;
+        }
+        let value = value.split_whitespace().collect::<Vec<_>>().join(" ");
+        headers.insert(name, serde_json::Value::String(value));
+    }
+    serde_json::Value::Object(headers)
+}
+
+fn header_addresses(value: &mail_parser::HeaderValue) -> Vec<String> {
+    use mail_parser::HeaderValue;
+
+    let addresses = |list: &[mail_parser::Addr]| -> Vec<String> {
+        list.iter().filter_map(|addr| addr.address.as_deref().map(str::to_string)).collect()
+    };
+    match value {
+        HeaderValue::Address(addr) => addresses(std::slice::from_ref(addr)),
+        HeaderValue::AddressList(list) => addresses(list),
+        HeaderValue::Group(group) => addresses(&group.addresses),
+        HeaderValue::GroupList(groups) => groups.iter().flat_map(|group| addresses(&group.addresses)).collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Filters of the email list on the plaintext headers, each matching
+/// case-insensitively anywhere in the header, and on the raw size
+#[derive(Debug, Clone, Default)]
+pub struct EmailFilter {
+    pub from: Option<String>,
+    pub subject: Option<String>,
+    /// Inclusive bounds on [`Email::raw_size_bytes`]
+    pub min_size: Option<i64>,
+    pub max_size: Option<i64>,
+}
+
+impl EmailFilter {
+    pub fn is_empty(&self) -> bool {
+        self.from.is_none() && self.subject.is_none() && self.min_size.is_none() && self.max_size.is_none()
+    }
+
+    pub fn matches(&self, email: &Email) -> bool {
+        let contains = |value: &str, pattern: &Option<String>| {
+            pattern.as_ref().is_none_or(|pattern| value.to_lowercase().contains(&pattern.to_lowercase()))
+        };
+        contains(&email.from_addr, &self.from)
+            && contains(&email.subject, &self.subject)
+            && self.min_size.is_none_or(|min_size| email.raw_size_bytes >= min_size)
+            && self.max_size.is_none_or(|max_size| email.raw_size_bytes <= max_size)
+    }
+}
+
+/// An attachment stripped from an email of a mailbox with
+/// `strip_attachments` set, encrypted to the same recipients as the email
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmailAttachment {
+    pub id: String,
+    pub email_id: String,
+    pub filename: Option<String>,
+    pub mime_type: String,
+    pub encrypted_content: String,
+}
+
+/// An email without its encrypted content, for listing
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct EmailMetadata {
+    pub id: String,
+    pub mailbox_id: String,
+    pub received_at: i64,
+    pub expires_at: Option<i64>,
+    #[serde(default)]
+    pub from_addr: String,
+    #[serde(default)]
+    pub to_addr: String,
+    #[serde(default)]
+    pub subject: String,
+}
+
+/// Runtime override of a mail service setting, see [`feature_flags`]
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct FeatureFlag {
+    pub flag_name: String,
+    pub enabled: bool,
+    pub updated_by: Option<String>,
+    pub updated_at: i64,
+}
+
+/// A delivery failure reported by a DSN (RFC 3464) that reached one of our mailboxes
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Bounce {
+    pub id: String,
+    /// Message-ID of the message that bounced, or of the DSN itself when it
+    /// does not say
+    pub message_id: Option<String>,
+    pub original_recipient: String,
+    pub status_code: String,
+    pub diagnostic: Option<String>,
+    pub permanent: bool,
+    pub bounced_at: i64,
+}
+
+/// A successful login. The location is only known with a GeoIP database.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LoginEvent {
+    pub user_id: String,
+    pub ip: Option<String>,
+    pub country_code: Option<String>,
+    pub city: Option<String>,
+    pub logged_in_at: i64,
+}
+
+/// A security-sensitive action taken by a user, like a login or an API key creation
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AuditEntry {
+    pub user_id: String,
+    pub action: String,
+    pub resource_type: Option<String>,
+    pub resource_id: Option<String>,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    pub occurred_at: i64,
+    pub metadata: Option<serde_json::Value>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, FromRow)]
@@ -172,6 +591,12 @@ pub struct User {
     pub username: String,
     pub auth_type: AuthType,
     pub created_at: i64,
+    /// When the admin API banned the user, who can then no longer sign in
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub banned_at: Option<i64>,
+    /// When the user last changed their username
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username_changed_at: Option<i64>,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone, sqlx::Type)]
@@ -190,6 +615,117 @@ pub struct ApiKey {
     pub key: String,
     pub created_at: i64,
     pub expires_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+    pub organization_id: Option<String>,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+impl ApiKey {
+    /// Neither expired nor revoked at `now`
+    pub fn is_active(&self, now: i64) -> bool {
+        self.expires_at.is_none_or(|expires_at| expires_at > now)
+            && self.revoked_at.is_none_or(|revoked_at| revoked_at > now)
+    }
+}
+
+/// Operations of the public API that an API key may be limited to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyScope {
+    ReadEmails,
+    DeleteEmails,
+    ManageMailboxes,
+}
+
+impl ApiKeyScope {
+    /// Scopes of keys created without any, matching the access keys had before scopes
+    pub const ALL: [ApiKeyScope; 3] = [
+        ApiKeyScope::ReadEmails,
+        ApiKeyScope::DeleteEmails,
+        ApiKeyScope::ManageMailboxes,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ApiKeyScope::ReadEmails => "read_emails",
+            ApiKeyScope::DeleteEmails => "delete_emails",
+            ApiKeyScope::ManageMailboxes => "manage_mailboxes",
+        }
+    }
+
+    /// Formats scopes for the comma-separated `api_keys.scopes` column
+    pub fn join(scopes: &[ApiKeyScope]) -> String {
+        scopes.iter().map(|scope| scope.as_str()).collect::<Vec<_>>().join(",")
+    }
+
+    /// Parses the `api_keys.scopes` column, skipping scopes this version doesn't know
+    pub fn parse_list(scopes: &str) -> Vec<ApiKeyScope> {
+        scopes
+            .split(',')
+            .filter_map(|scope| Self::ALL.into_iter().find(|known| known.as_str() == scope.trim()))
+            .collect()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyUsage {
+    pub api_key_id: String,
+    pub endpoint: String,
+    pub method: String,
+    pub status_code: i64,
+    pub response_ms: i64,
+    pub used_at: i64,
+    pub country_code: Option<String>,
+    pub city: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyUsageBucket {
+    pub bucket_start: i64,
+    pub requests: i64,
+    pub errors: i64,
+    pub error_rate: f64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ApiKeyEndpointUsage {
+    pub endpoint: String,
+    pub method: String,
+    pub requests: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Organization {
+    pub id: String,
+    pub name: String,
+    pub owner_user_id: String,
+    /// Maximum number of mailboxes owned by the organization, unlimited when `None`
+    pub max_mailboxes: Option<i64>,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, sqlx::Type)]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Owner,
+    Admin,
+    Member,
+}
+
+impl OrgRole {
+    /// Owners and admins manage members and the organization's mailboxes
+    pub fn can_manage(self) -> bool {
+        matches!(self, OrgRole::Owner | OrgRole::Admin)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrganizationMember {
+    pub org_id: String,
+    pub user_id: String,
+    pub role: OrgRole,
+    pub created_at: i64,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -198,4 +734,220 @@ pub struct UserSettings {
     pub email_notifications: bool,
     pub auto_delete_expired: bool,
     pub default_mailbox_expiry: Option<i64>,
+    #[serde(default)]
+    pub email_cleanup_policy: Option<CleanupPolicy>,
+    /// Most emails the user's mailboxes may hold, unlimited when `None`
+    #[serde(default)]
+    pub email_count_limit: Option<i64>,
+    /// Most bytes of encrypted emails and attachments the user's mailboxes may hold
+    #[serde(default)]
+    pub total_storage_bytes_limit: Option<i64>,
+    /// Requests per minute the user may make to the web API, the server default when `None`
+    #[serde(default)]
+    pub api_rate_limit_per_minute: Option<i64>,
+    /// Most mailboxes the user may own, unlimited when `None`
+    #[serde(default = "UserSettings::default_max_mailboxes")]
+    pub max_mailboxes: Option<u64>,
+}
+
+impl UserSettings {
+    pub const DEFAULT_MAX_MAILBOXES: u64 = 20;
+
+    fn default_max_mailboxes() -> Option<u64> {
+        Some(Self::DEFAULT_MAX_MAILBOXES)
+    }
+
+    /// The settings of a user who never changed them
+    pub fn new(user_id: &str) -> Self {
+        Self {
+            user_id: user_id.to_string(),
+            email_notifications: true,
+            auto_delete_expired: true,
+            default_mailbox_expiry: None,
+            email_cleanup_policy: None,
+            email_count_limit: None,
+            total_storage_bytes_limit: None,
+            api_rate_limit_per_minute: None,
+            max_mailboxes: Self::default_max_mailboxes(),
+        }
+    }
+}
+
+/// What a user's mailboxes currently store, as counted against the quota
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UserEmailStats {
+    pub email_count: i64,
+    /// Length of the encrypted content of the emails and their attachments
+    pub total_bytes: i64,
+}
+
+/// A user's storage limits with what their mailboxes already store
+pub struct StorageQuota {
+    pub email_count_limit: Option<i64>,
+    pub total_bytes_limit: Option<i64>,
+    pub stats: UserEmailStats,
+}
+
+impl StorageQuota {
+    /// The owner's storage limits and usage, `None` when no limit is set
+    pub async fn load<D: db::Database + ?Sized>(db: &D, owner_id: &str) -> Result<Option<Self>, AppError> {
+        let Some(settings) = db.get_user_settings(owner_id).await? else {
+            return Ok(None);
+        };
+        if settings.email_count_limit.is_none() && settings.total_storage_bytes_limit.is_none() {
+            return Ok(None);
+        }
+
+        Ok(Some(StorageQuota {
+            email_count_limit: settings.email_count_limit,
+            total_bytes_limit: settings.total_storage_bytes_limit,
+            stats: db.get_user_email_stats(owner_id).await?,
+        }))
+    }
+
+    /// Fails if storing `emails` more emails of `bytes` in total would exceed a limit
+    pub fn check(&self, emails: i64, bytes: i64) -> Result<(), AppError> {
+        let exceeds = |limit: Option<i64>, used: i64, added: i64| limit.is_some_and(|limit| used + added > limit);
+        if exceeds(self.email_count_limit, self.stats.email_count, emails)
+            || exceeds(self.total_bytes_limit, self.stats.total_bytes, bytes)
+        {
+            return Err(AppError::Mail("Storage quota exceeded".to_string()));
+        }
+        Ok(())
+    }
+}
+
+/// Instance-wide totals reported by the admin API
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+pub struct InstanceStats {
+    pub user_count: i64,
+    pub mailbox_count: i64,
+    pub email_count: i64,
+    /// Size of the SQLite database, excluding its write-ahead log
+    pub database_size_bytes: i64,
+}
+
+/// Raw size of the emails of one mailbox, see [`Email::raw_size_bytes`]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct MailboxStorageStats {
+    pub mailbox_id: String,
+    pub email_count: u64,
+    pub total_bytes: i64,
+}
+
+/// Retention rules applied to a user's mailboxes by the background cleanup task
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct CleanupPolicy {
+    /// Delete emails received more than this many seconds ago
+    pub max_age_seconds: Option<i64>,
+    /// Keep only the newest emails of each mailbox
+    pub max_count: Option<u64>,
+    /// When false, mailboxes with their own `mail_expires_in` are left alone
+    #[serde(default)]
+    pub apply_to_all_mailboxes: bool,
+}
+
+impl CleanupPolicy {
+    /// Rejects limits that would delete every email
+    pub fn validate(&self) -> Result<(), AppError> {
+        if self.max_age_seconds.is_some_and(|age| age <= 0) {
+            return Err(AppError::Mail("max_age_seconds must be positive".into()));
+        }
+        if self.max_count == Some(0) {
+            return Err(AppError::Mail("max_count must be at least 1".into()));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate_random_id_charsets() {
+        for charset in [
+            IdCharset::VisuallyDistinct,
+            IdCharset::AlphanumericLower,
+            IdCharset::UrlSafe,
+            IdCharset::Hex,
+        ] {
+            for len in [1, 12, 40] {
+                let id = generate_random_id(len, charset);
+                assert_eq!(id.len(), len);
+                assert!(id.bytes().all(|c| charset.chars().contains(&c)), "{:?}: {}", charset, id);
+            }
+        }
+    }
+
+    #[test]
+    fn test_generate_random_id_distribution() {
+        let chars = IdCharset::VisuallyDistinct.chars();
+        let mut counts = [0u32; 256];
+        for _ in 0..10_000 {
+            for c in generate_random_id(12, IdCharset::VisuallyDistinct).bytes() {
+                counts[c as usize] += 1;
+            }
+        }
+        // 120,000 characters over 24 give 5,000 each, with a standard
+        // deviation of about 70
+        for &c in chars {
+            let count = counts[c as usize];
+            assert!((4_000..=6_500).contains(&count), "{} appeared {} times", c as char, count);
+        }
+
+        let id = generate_random_id_secure(48, IdCharset::VisuallyDistinct).unwrap();
+        assert_eq!(id.len(), 48);
+        assert!(id.bytes().all(|c| chars.contains(&c)));
+    }
+
+    #[test]
+    fn test_api_key_scope_list() {
+        let scopes = [ApiKeyScope::ReadEmails, ApiKeyScope::ManageMailboxes];
+        assert_eq!(ApiKeyScope::join(&scopes), "read_emails,manage_mailboxes");
+        assert_eq!(ApiKeyScope::parse_list("read_emails,manage_mailboxes"), scopes);
+        assert_eq!(ApiKeyScope::parse_list("delete_emails, unknown"), [ApiKeyScope::DeleteEmails]);
+        assert!(ApiKeyScope::parse_list("").is_empty());
+    }
+
+    #[test]
+    fn test_api_key_is_active() {
+        let key = |expires_at, revoked_at| ApiKey {
+            id: "id".to_string(),
+            user_id: "user".to_string(),
+            key: "key".to_string(),
+            created_at: 0,
+            expires_at,
+            revoked_at,
+            organization_id: None,
+            scopes: Vec::new(),
+        };
+        assert!(key(None, None).is_active(100));
+        assert!(key(Some(101), Some(101)).is_active(100));
+        assert!(!key(Some(100), None).is_active(100));
+        assert!(!key(None, Some(100)).is_active(100));
+    }
+
+    #[test]
+    fn test_captured_headers() {
+        let raw = "Received: from mx.example.com\r\n\
+            Authentication-Results: mx.example.com; spf=pass\r\n\
+            From: Sender <sender@example.com>\r\n\
+            Subject: Folded\r\n subject\r\n\
+            X-Mailer: Test\r\n\
+            x-mailer: Repeated\r\n\
+            Content-Type: text/plain\r\n\
+            \r\n\
+            Body\r\n";
+        let message = mail_parser::Message::parse(raw.as_bytes()).unwrap();
+
+        assert_eq!(
+            captured_headers(&message),
+            serde_json::json!({
+                "from": "Sender <sender@example.com>",
+                "subject": "Folded subject",
+                "x-mailer": "Test",
+            })
+        );
+    }
 }
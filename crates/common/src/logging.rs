@@ -0,0 +1,53 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+const VISIBLE_CHARS: usize = 3;
+
+static REDACTION_ENABLED: AtomicBool = AtomicBool::new(true);
+
+/// Turns redaction of `Redacted` values off, e.g. with `DISABLE_LOG_REDACTION`
+/// while debugging delivery issues
+pub fn set_redaction_enabled(enabled: bool) {
+    REDACTION_ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Wraps personal data in log statements, such as email addresses and
+/// usernames, so only its first characters are written to the logs
+pub struct Redacted<T>(pub T);
+
+impl<T: fmt::Display> fmt::Display for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !REDACTION_ENABLED.load(Ordering::Relaxed) {
+            return self.0.fmt(f);
+        }
+
+        let value = self.0.to_string();
+        // Short values are hidden entirely rather than shown in full
+        if value.chars().count() > VISIBLE_CHARS {
+            f.write_str(&value.chars().take(VISIBLE_CHARS).collect::<String>())?;
+        }
+        f.write_str("***")
+    }
+}
+
+impl<T: fmt::Display> fmt::Debug for Redacted<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redacted() {
+        assert_eq!(Redacted("alice@example.com").to_string(), "ali***");
+        assert_eq!(Redacted("bob").to_string(), "***");
+        assert_eq!(format!("{:?}", Redacted("ünïcode")), "ünï***");
+
+        set_redaction_enabled(false);
+        assert_eq!(Redacted("alice@example.com").to_string(), "alice@example.com");
+        set_redaction_enabled(true);
+    }
+}
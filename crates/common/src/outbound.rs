@@ -0,0 +1,193 @@
+//! Requests to URLs chosen by users, such as webhooks, which must not reach
+//! the server itself or its private network. Hosts are checked when a URL is
+//! saved and again on every connection, through [`client_builder`]'s resolver,
+//! so a name later pointed at a private address is still refused.
+
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Url,
+};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use crate::AppError;
+
+/// Whether the address is reachable on the internet rather than the loopback,
+/// private, shared, link-local (cloud metadata included), reserved or
+/// unspecified ones. IPv6 addresses embedding an IPv4 one, which a NAT64 or
+/// 6to4 gateway would forward to it, are judged by that IPv4 address.
+pub fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, c, _] = ip.octets();
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_multicast()
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (a == 100 && (64..128).contains(&b))
+                // IETF protocol assignments, 192.0.0.0/24
+                || (a == 192 && b == 0 && c == 0)
+                // Benchmarking, 198.18.0.0/15
+                || (a == 198 && (b & 0xfe) == 18)
+                // Reserved, 240.0.0.0/4
+                || a >= 240
+                || a == 0)
+        }
+        IpAddr::V6(ip) => match embedded_ipv4(ip) {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    || ip.is_unique_local()
+                    || ip.is_unicast_link_local())
+            }
+        },
+    }
+}
+
+/// The IPv4 address in an IPv4-mapped or IPv4-compatible address, or behind
+/// the NAT64 prefix `64:ff9b::/96` or the 6to4 prefix `2002::/16`
+fn embedded_ipv4(ip: Ipv6Addr) -> Option<Ipv4Addr> {
+    let segments = ip.segments();
+    match segments {
+        [0x64, 0xff9b, 0, 0, 0, 0, high, low] | [0x2002, high, low, ..] => {
+            Some(Ipv4Addr::from((u32::from(high) << 16) | u32::from(low)))
+        }
+        // `::` and `::1` are the IPv6 unspecified and loopback addresses
+        _ if ip.is_unspecified() || ip.is_loopback() => None,
+        _ => ip.to_ipv4(),
+    }
+}
+
+/// Resolves `host`, failing unless every address it has is public
+async fn resolve_public(host: &str, port: u16) -> Result<Vec<SocketAddr>, AppError> {
+    let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host, port))
+        .await
+        .map_err(|_| AppError::Mail(format!("Could not resolve {}", host)))?
+        .collect();
+    if addrs.is_empty() {
+        return Err(AppError::Mail(format!("Could not resolve {}", host)));
+    }
+    if addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(AppError::Mail("URL must not point to a private or local address".into()));
+    }
+    Ok(addrs)
+}
+
+/// Checks that an http(s) URL's host is a public address, or a name resolving
+/// only to public addresses
+pub async fn check_url(url: &Url) -> Result<(), AppError> {
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(AppError::Mail("URL must use http or https".into()));
+    }
+    let host = url.host_str().ok_or_else(|| AppError::Mail("URL must have a host".into()))?;
+    let ip = match host.trim_start_matches('[').trim_end_matches(']').parse::<IpAddr>() {
+        Ok(ip) => ip,
+        Err(_) => {
+            return resolve_public(host, url.port_or_known_default().unwrap_or(0)).await.map(|_| ());
+        }
+    };
+    if !is_public(ip) {
+        return Err(AppError::Mail("URL must not point to a private or local address".into()));
+    }
+    Ok(())
+}
+
+/// Resolves names to their addresses only when all of them are public
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        Box::pin(async move {
+            // The port is replaced by the URL's
+            let addrs = resolve_public(name.as_str(), 0).await?;
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// A client for user-chosen URLs: connections go to public addresses only and
+/// redirects, which could lead anywhere, are not followed. Hosts given as IP
+/// addresses skip the resolver, so URLs still need [`check_url`].
+pub fn client_builder() -> reqwest::ClientBuilder {
+    reqwest::Client::builder()
+        .redirect(redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_is_public() {
+        for private in [
+            "127.0.0.1", "10.1.2.3", "172.16.0.1", "192.168.1.1", "169.254.169.254", "100.64.0.1", "0.0.0.0",
+            "255.255.255.255", "::1", "::", "fe80::1", "fd00::1", "::ffff:127.0.0.1", "::ffff:169.254.169.254",
+        ] {
+            assert!(!is_public(ip(private)), "{} is not public", private);
+        }
+        for public in ["203.0.113.10", "8.8.8.8", "100.128.0.1", "2001:4860:4860::8888", "::ffff:8.8.8.8"] {
+            assert!(is_public(ip(public)), "{} is public", public);
+        }
+    }
+
+    #[test]
+    fn test_is_public_reserved_ipv4() {
+        for private in ["192.0.0.1", "192.0.0.255", "198.18.0.1", "198.19.255.255", "240.0.0.1", "250.1.2.3"] {
+            assert!(!is_public(ip(private)), "{} is not public", private);
+        }
+        for public in ["192.0.1.1", "198.17.255.255", "198.20.0.1", "223.255.255.1"] {
+            assert!(is_public(ip(public)), "{} is public", public);
+        }
+    }
+
+    #[test]
+    fn test_is_public_embedded_ipv4() {
+        for private in [
+            // NAT64
+            "64:ff9b::127.0.0.1", "64:ff9b::a9fe:a9fe", "64:ff9b::10.0.0.1",
+            // 6to4
+            "2002:7f00:1::", "2002:a9fe:a9fe::1", "2002:c0a8:101::",
+            // IPv4-compatible
+            "::127.0.0.1", "::169.254.169.254", "::10.0.0.1",
+            // Reserved IPv4 ranges, embedded
+            "64:ff9b::192.0.0.1", "2002:c612:1::", "::240.0.0.1",
+        ] {
+            assert!(!is_public(ip(private)), "{} is not public", private);
+        }
+        for public in ["64:ff9b::8.8.8.8", "2002:808:808::", "::8.8.8.8"] {
+            assert!(is_public(ip(public)), "{} is public", public);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_check_url() {
+        let check = |url: &str| {
+            let url = Url::parse(url).unwrap();
+            async move { check_url(&url).await }
+        };
+        assert!(check("https://203.0.113.10/hook").await.is_ok());
+        for url in [
+            "http://127.0.0.1:8080/",
+            "http://169.254.169.254/latest/meta-data/",
+            "http://[::1]/",
+            "http://localhost/",
+            "ftp://203.0.113.10/",
+        ] {
+            assert!(check(url).await.is_err(), "{} is refused", url);
+        }
+    }
+}
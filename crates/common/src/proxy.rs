@@ -0,0 +1,100 @@
+//! Client addresses of connections made through reverse proxies
+
+use ipnetwork::IpNetwork;
+use std::{net::IpAddr, sync::Arc};
+
+/// Networks of the reverse proxies whose forwarding headers are believed
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies(Arc<[IpNetwork]>);
+
+impl TrustedProxies {
+    pub fn new(networks: Vec<IpNetwork>) -> Self {
+        Self(networks.into())
+    }
+
+    /// Parses networks in CIDR format, or single addresses
+    pub fn parse(networks: &[String]) -> anyhow::Result<Self> {
+        networks
+            .iter()
+            .map(|network| network.trim())
+            .filter(|network| !network.is_empty())
+            .map(|network| {
+                network
+                    .parse()
+                    .map_err(|_| anyhow::anyhow!("Invalid network in TRUSTED_PROXIES: {}", network))
+            })
+            .collect::<anyhow::Result<Vec<_>>>()
+            .map(Self::new)
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        self.0.iter().any(|network| network.contains(ip))
+    }
+
+    /// The client of a connection from `peer` with the `X-Forwarded-For`
+    /// hops `forwarded_for`. Each proxy appends the address it was reached
+    /// from, so the client is the last hop not added by a trusted proxy; the
+    /// ones before it may be forged.
+    pub fn forwarded_client<'a>(
+        &self,
+        peer: IpAddr,
+        forwarded_for: impl DoubleEndedIterator<Item = &'a str>,
+    ) -> IpAddr {
+        let mut client = peer;
+        if !self.contains(peer) {
+            return client;
+        }
+        for hop in forwarded_for.rev() {
+            let Ok(ip) = hop.trim().parse() else {
+                break;
+            };
+            client = ip;
+            if !self.contains(ip) {
+                break;
+            }
+        }
+        client
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn proxies(networks: &[&str]) -> TrustedProxies {
+        TrustedProxies::parse(&networks.iter().map(|network| network.to_string()).collect::<Vec<_>>()).unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_trusted_proxies() {
+        let trusted = proxies(&["127.0.0.1", " 10.0.0.0/8", "::1", ""]);
+        assert!(trusted.contains(ip("127.0.0.1")));
+        assert!(trusted.contains(ip("10.1.2.3")));
+        assert!(trusted.contains(ip("::1")));
+        assert!(!trusted.contains(ip("127.0.0.2")));
+        assert!(TrustedProxies::parse(&["10.0.0.0/33".to_string()]).is_err());
+        assert!(TrustedProxies::parse(&["proxy".to_string()]).is_err());
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let trusted = proxies(&["127.0.0.1", "10.0.0.0/8"]);
+        let client = |peer: &str, forwarded_for: &str| {
+            trusted.forwarded_client(ip(peer), forwarded_for.split(',').filter(|hop| !hop.is_empty()))
+        };
+
+        // Headers from untrusted peers are ignored
+        assert_eq!(client("198.51.100.1", "203.0.113.7"), ip("198.51.100.1"));
+        assert_eq!(client("127.0.0.1", ""), ip("127.0.0.1"));
+        assert_eq!(client("127.0.0.1", "203.0.113.7"), ip("203.0.113.7"));
+        // Proxies are skipped, and hops the client sent are not believed
+        assert_eq!(client("127.0.0.1", "203.0.113.7, 10.0.0.2"), ip("203.0.113.7"));
+        assert_eq!(client("127.0.0.1", "192.0.2.1, 203.0.113.7"), ip("203.0.113.7"));
+        assert_eq!(client("127.0.0.1", "unknown, 10.0.0.2"), ip("10.0.0.2"));
+        assert_eq!(client("127.0.0.1", "10.0.0.3, 10.0.0.2"), ip("10.0.0.3"));
+    }
+}
@@ -4,7 +4,25 @@ use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use std::fmt::Display;
 
-type RateLimiterMap = Arc<Mutex<HashMap<ResourceKey, Arc<Mutex<RateLimiter>>>>>;
+/// How often idle limiters are dropped from [`RATE_LIMITERS`]
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+struct RateLimiterMap {
+    limiters: HashMap<ResourceKey, Arc<Mutex<RateLimiter>>>,
+    pruned_at: Instant,
+}
+
+impl RateLimiterMap {
+    /// Drops the limiters nobody holds whose windows have all run out, as
+    /// a new one would count the same. Clients come and go, so the map
+    /// would otherwise keep growing.
+    fn prune(&mut self) {
+        self.limiters.retain(|_, limiter| {
+            Arc::strong_count(limiter) > 1 || limiter.try_lock().map_or(true, |limiter| !limiter.is_idle())
+        });
+        self.pruned_at = Instant::now();
+    }
+}
 
 #[derive(Debug, Clone, Hash, Eq, PartialEq)]
 pub struct ResourceKey(String);
@@ -78,7 +96,7 @@ impl WindowCounter {
         }
     }
 
-    fn increment(&mut self, period: Duration) -> bool {
+    fn increment(&mut self, period: Duration) {
         let now = Instant::now();
         let elapsed = now.duration_since(self.window_start);
         
@@ -86,25 +104,19 @@ impl WindowCounter {
             // Start a new window
             self.count = 1;
             self.window_start = now;
-            true
         } else {
             // Still in current window
             self.count = self.count.saturating_add(1);
-            true
         }
     }
 
-    fn is_within_limit(&self, max_requests: u32) -> bool {
-        // Check if window has expired
-        let now = Instant::now();
-        let elapsed = now.duration_since(self.window_start);
-        
-        if elapsed >= Duration::from_secs(0) {
-            self.count < max_requests
-        } else {
-            // If time went backwards, be conservative and deny
-            false
-        }
+    fn is_within_limit(&self, rule: &RateLimitRule) -> bool {
+        // A window that has run its period starts over on the next request
+        self.window_start.elapsed() >= rule.period || self.count < rule.max_requests
+    }
+
+    fn resets_in(&self, period: Duration) -> Duration {
+        period.saturating_sub(self.window_start.elapsed())
     }
 }
 
@@ -124,20 +136,42 @@ impl RateLimiter {
         }
     }
 
+    /// Counts a request, or returns false without counting it when a rule's
+    /// limit is reached
     pub fn trigger(&mut self) -> bool {
+        // Checked first, so a request refused by one rule isn't counted by the others
+        if !self.rules.iter().zip(&self.windows).all(|(rule, window)| window.is_within_limit(rule)) {
+            return false;
+        }
         for (rule, window) in self.rules.iter().zip(self.windows.iter_mut()) {
-            if !window.is_within_limit(rule.max_requests) {
-                return false;
-            }
-            if !window.increment(rule.period) {
-                return false;
-            }
+            window.increment(rule.period);
         }
         true
     }
+
+    /// Whether every window has run its period, leaving nothing to count
+    fn is_idle(&self) -> bool {
+        self.rules.iter().zip(&self.windows).all(|(rule, window)| window.window_start.elapsed() >= rule.period)
+    }
+
+    /// How long until `trigger` can succeed again
+    pub fn retry_after(&self) -> Duration {
+        self.rules
+            .iter()
+            .zip(&self.windows)
+            .filter(|(rule, window)| !window.is_within_limit(rule))
+            .map(|(rule, window)| window.resets_in(rule.period))
+            .max()
+            .unwrap_or_default()
+    }
 }
 
-static RATE_LIMITERS: Lazy<RateLimiterMap> = Lazy::new(|| Arc::new(Mutex::new(HashMap::new())));
+static RATE_LIMITERS: Lazy<Mutex<RateLimiterMap>> = Lazy::new(|| {
+    Mutex::new(RateLimiterMap {
+        limiters: HashMap::new(),
+        pruned_at: Instant::now(),
+    })
+});
 
 pub fn get_or_create_rate_limiter<K, C>(key: K, config: C) -> Arc<Mutex<RateLimiter>>
 where
@@ -147,11 +181,74 @@ where
     let key = key.into();
     let mut limiters = RATE_LIMITERS.lock().unwrap();
 
-    if let Some(limiter) = limiters.get(&key) {
+    if let Some(limiter) = limiters.limiters.get(&key) {
         limiter.clone()
     } else {
+        if limiters.pruned_at.elapsed() >= PRUNE_INTERVAL {
+            limiters.prune();
+        }
         let limiter = Arc::new(Mutex::new(RateLimiter::new(config.into())));
-        limiters.insert(key, limiter.clone());
+        limiters.limiters.insert(key, limiter.clone());
         limiter
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_resets() {
+        let rule = RateLimitRule { max_requests: 2, period: Duration::from_millis(100) };
+        let mut limiter = RateLimiter::new(vec![rule]);
+        assert!(limiter.trigger());
+        assert!(limiter.trigger());
+        assert!(!limiter.trigger());
+        assert!(limiter.retry_after() > Duration::ZERO);
+        assert!(limiter.retry_after() <= Duration::from_millis(100));
+
+        std::thread::sleep(Duration::from_millis(120));
+        assert_eq!(limiter.retry_after(), Duration::ZERO);
+        assert!(limiter.trigger());
+    }
+
+    #[test]
+    fn test_refused_requests_are_not_counted() {
+        let mut limiter = RateLimiter::new(vec![
+            RateLimitRule::new(3, 3600),
+            RateLimitRule { max_requests: 1, period: Duration::from_millis(100) },
+        ]);
+        assert!(limiter.trigger());
+        assert!(!limiter.trigger());
+        assert!(!limiter.trigger());
+
+        // Only the accepted request counts towards the hourly limit
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.trigger());
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(limiter.trigger());
+        std::thread::sleep(Duration::from_millis(120));
+        assert!(!limiter.trigger());
+    }
+
+    #[test]
+    fn test_idle_limiters_are_pruned() {
+        let rule = || vec![RateLimitRule { max_requests: 1, period: Duration::from_millis(50) }];
+        let idle = get_or_create_rate_limiter("prune-test:idle", rule);
+        assert!(idle.lock().unwrap().trigger());
+        drop(idle);
+        let held = get_or_create_rate_limiter("prune-test:held", rule);
+        std::thread::sleep(Duration::from_millis(60));
+        let active = get_or_create_rate_limiter("prune-test:active", rule);
+        assert!(active.lock().unwrap().trigger());
+        drop(active);
+
+        let mut limiters = RATE_LIMITERS.lock().unwrap();
+        limiters.prune();
+        assert!(!limiters.limiters.contains_key(&"prune-test:idle".into()));
+        assert!(limiters.limiters.contains_key(&"prune-test:held".into()));
+        assert!(limiters.limiters.contains_key(&"prune-test:active".into()));
+        drop(limiters);
+        drop(held);
+    }
+}
@@ -0,0 +1,132 @@
+//! Correlation IDs tying the logs of a request together. Each HTTP request
+//! runs in a `request` span carrying its `request_id` and, once
+//! authenticated, its `user_id`, and the ID is returned in `X-Request-ID`.
+
+use axum::http::{HeaderName, HeaderValue, Request, Response};
+use futures::future::BoxFuture;
+use std::{
+    task::{Context, Poll},
+    time::Instant,
+};
+use tower_layer::Layer;
+use tower_service::Service;
+use tracing::{field, Instrument, Span};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied ID that is kept rather than replaced
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+/// The request's correlation ID, in the request extensions
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId(pub String);
+
+pub fn generate_request_id() -> String {
+    uuid::Uuid::new_v4().to_string()
+}
+
+/// IDs from clients and proxies are kept when they can't break log lines
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_' | b'.'))
+}
+
+/// The `request` span, in the request extensions so it can be found from
+/// within spans opened by inner layers
+#[derive(Debug, Clone)]
+struct RequestSpan(Span);
+
+/// Records the authenticated user on the request's `request` span
+pub fn record_user_id<B>(req: &Request<B>, user_id: &str) {
+    let span = req.extensions().get::<RequestSpan>().map_or_else(Span::current, |span| span.0.clone());
+    span.record("user_id", field::display(user_id));
+}
+
+/// Runs each request in a span with its correlation ID, taken from the
+/// `X-Request-ID` header when valid and generated otherwise
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for RequestIdService<S>
+where
+    S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+    S::Future: Send + 'static,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<ReqBody>) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(&REQUEST_ID_HEADER)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id))
+            .map(str::to_string)
+            .unwrap_or_else(generate_request_id);
+        let header = HeaderValue::from_str(&request_id).expect("request IDs are valid header values");
+
+        let span = tracing::info_span!(
+            "request",
+            method = %req.method(),
+            path = %req.uri().path(),
+            request_id = field::Empty,
+            user_id = field::Empty,
+        );
+        let future = span.in_scope(|| {
+            Span::current().record("request_id", field::display(&request_id));
+            req.headers_mut().insert(REQUEST_ID_HEADER, header.clone());
+            req.extensions_mut().insert(RequestId(request_id));
+            req.extensions_mut().insert(RequestSpan(Span::current()));
+            self.inner.call(req)
+        });
+
+        let started_at = Instant::now();
+        Box::pin(
+            async move {
+                let mut response = future.await?;
+                response.headers_mut().insert(REQUEST_ID_HEADER, header);
+                tracing::debug!(
+                    status = response.status().as_u16(),
+                    elapsed_ms = started_at.elapsed().as_millis() as u64,
+                    "Request completed"
+                );
+                Ok(response)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_valid_request_id() {
+        assert!(is_valid_request_id("3f2a9c1e-77b1-4c5e-9d0a-2b8e4f6a1c3d"));
+        assert!(is_valid_request_id("edge.42_abc"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("id with spaces"));
+        assert!(!is_valid_request_id("id\nforged=1"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LENGTH + 1)));
+    }
+}
@@ -0,0 +1,75 @@
+//! Keyword search over encrypted emails.
+//!
+//! Each keyword of an email is stored as `HMAC-SHA256(public_key, keyword)`
+//! so the plaintext word never reaches the database. Clients derive the same
+//! token to search. The server also knows the public key, so this keeps
+//! keywords out of the database at rest but does not hide them from someone
+//! able to guess words and hash them; the index is therefore opt-in.
+
+use std::collections::BTreeSet;
+
+use hmac::{Hmac, Mac};
+use mail_parser::Message;
+use sha2::Sha256;
+
+/// Shorter words are too common to be worth indexing
+pub const MIN_KEYWORD_LENGTH: usize = 4;
+
+/// Lowercased, deduplicated words of the subject and text bodies
+pub fn extract_keywords(message: &Message) -> BTreeSet<String> {
+    let mut keywords = BTreeSet::new();
+    let mut add_words = |text: &str| {
+        for word in text.split(|c: char| !c.is_alphanumeric()) {
+            if word.chars().count() >= MIN_KEYWORD_LENGTH {
+                keywords.insert(word.to_lowercase());
+            }
+        }
+    };
+
+    if let Some(subject) = message.subject() {
+        add_words(subject);
+    }
+    for pos in 0..message.text_body_count() {
+        if let Some(body) = message.body_text(pos) {
+            add_words(&body);
+        }
+    }
+
+    keywords
+}
+
+/// Hex encoded search token of a keyword for the given mailbox public key
+pub fn search_token(public_key: &str, keyword: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(public_key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(keyword.to_lowercase().as_bytes());
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Search tokens for every keyword of the message
+pub fn search_tokens(message: &Message, public_key: &str) -> Vec<String> {
+    extract_keywords(message)
+        .iter()
+        .map(|keyword| search_token(public_key, keyword))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn extracts_subject_and_body_keywords() {
+        let raw = b"Subject: Invoice for March\r\n\r\nPlease PAY the invoice, thanks.\r\n";
+        let message = Message::parse(raw).unwrap();
+        let keywords: Vec<_> = extract_keywords(&message).into_iter().collect();
+        assert_eq!(keywords, ["invoice", "march", "please", "thanks"]);
+    }
+
+    #[test]
+    fn tokens_depend_on_key_and_ignore_case() {
+        assert_eq!(search_token("age1abc", "Invoice"), search_token("age1abc", "invoice"));
+        assert_ne!(search_token("age1abc", "invoice"), search_token("age1xyz", "invoice"));
+        assert_eq!(search_token("age1abc", "invoice").len(), 64);
+    }
+}
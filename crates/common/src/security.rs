@@ -1,53 +1,217 @@
 use anyhow::Result;
-use crate::AppError;
+use crate::{AppError, KeyType, MailboxKey};
+use std::io::{Read, Write};
 use std::str::FromStr;
+use age::secrecy::{ExposeSecret, SecretString};
 use base64::Engine as _;
 
-pub fn encrypt_email(raw_email: &[u8], public_key: &str) -> Result<String, AppError> {
-    // Parse the recipient's public key
-    let recipient = age::x25519::Recipient::from_str(public_key)
-        .map_err(|e| AppError::Mail(format!("Invalid public key: {}", e)))?;
+/// Detects the key type of a public key and returns it with the key's
+/// canonical form, or `None` if it doesn't parse. Bech32 age keys may be
+/// written in either case; SSH keys lose their trailing comment.
+pub fn normalize_public_key(public_key: &str) -> Option<(KeyType, String)> {
+    let public_key = public_key.trim();
+    let key_type = KeyType::detect(public_key)?;
+    let normalized = match key_type {
+        KeyType::AgeX25519 => age::x25519::Recipient::from_str(&public_key.to_ascii_lowercase())
+            .ok()?
+            .to_string(),
+        KeyType::SshEd25519 | KeyType::SshRsa => age::ssh::Recipient::from_str(public_key)
+            .ok()?
+            .to_string(),
+    };
+    Some((key_type, normalized))
+}
+
+pub fn encrypt_email(raw_email: &[u8], public_key: &str, key_type: KeyType) -> Result<String, AppError> {
+    encrypt_email_with_backup(raw_email, public_key, key_type, None)
+}
+
+/// Encrypts the email to the mailbox key and, if the owner set one up, to
+/// their backup key as a second recipient
+pub fn encrypt_email_with_backup(
+    raw_email: &[u8],
+    public_key: &str,
+    key_type: KeyType,
+    backup_public_key: Option<&str>,
+) -> Result<String, AppError> {
+    encrypt_to_recipients(raw_email, recipients([(public_key, key_type)], backup_public_key)?)
+}
+
+/// Encrypts the email to every key of the mailbox, as listed by
+/// `Database::list_mailbox_keys`, and to the owner's backup key if set up
+pub fn encrypt_email_to_keys(
+    raw_email: &[u8],
+    keys: &[MailboxKey],
+    backup_public_key: Option<&str>,
+) -> Result<String, AppError> {
+    let keys = keys.iter().map(|key| (key.public_key.as_str(), key.key_type));
+    encrypt_to_recipients(raw_email, recipients(keys, backup_public_key)?)
+}
+
+/// Like `encrypt_email`, but reads the email from `reader` instead of
+/// requiring it in memory. Only the base64 output is buffered.
+pub fn encrypt_email_streaming(reader: impl Read, public_key: &str, key_type: KeyType) -> Result<String, AppError> {
+    encrypt_to_recipients(reader, recipients([(public_key, key_type)], None)?)
+}
+
+fn recipients<'a>(
+    keys: impl IntoIterator<Item = (&'a str, KeyType)>,
+    backup_public_key: Option<&str>,
+) -> Result<Vec<Box<dyn age::Recipient + Send>>, AppError> {
+    // Parse each recipient's public key according to its type
+    let mut recipients = keys
+        .into_iter()
+        .map(|(public_key, key_type)| -> Result<Box<dyn age::Recipient + Send>, AppError> {
+            Ok(match key_type {
+                KeyType::AgeX25519 => Box::new(
+                    age::x25519::Recipient::from_str(public_key)
+                        .map_err(|e| AppError::Mail(format!("Invalid public key: {}", e)))?,
+                ),
+                KeyType::SshEd25519 | KeyType::SshRsa => Box::new(
+                    age::ssh::Recipient::from_str(public_key)
+                        .map_err(|e| AppError::Mail(format!("Invalid SSH public key: {:?}", e)))?,
+                ),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if let Some(backup_public_key) = backup_public_key {
+        recipients.push(Box::new(
+            age::x25519::Recipient::from_str(backup_public_key)
+                .map_err(|e| AppError::Mail(format!("Invalid backup public key: {}", e)))?,
+        ));
+    }
+    Ok(recipients)
+}
 
-    // Encrypt the email
-    let encryptor = age::Encryptor::with_recipients(vec![Box::new(recipient)])
+fn encrypt_to_recipients(
+    mut reader: impl Read,
+    recipients: Vec<Box<dyn age::Recipient + Send>>,
+) -> Result<String, AppError> {
+    // Encrypt the email straight into the base64 encoder
+    let encryptor = age::Encryptor::with_recipients(recipients)
         .ok_or_else(|| AppError::Mail("Failed to create encryptor".to_string()))?;
 
-    let mut encrypted = Vec::new();
-    let mut writer = encryptor.wrap_output(&mut encrypted)
-        .map_err(|e| AppError::Mail(format!("Encryption error: {}", e)))?;
-    
-    std::io::Write::write_all(&mut writer, raw_email)
+    // age writes its header with single `write` calls, which the base64
+    // encoder may only partially accept, so it goes through a buffer
+    let output = std::io::BufWriter::new(
+        base64::write::EncoderStringWriter::new(&base64::engine::general_purpose::STANDARD),
+    );
+    let mut writer = encryptor.wrap_output(output)
         .map_err(|e| AppError::Mail(format!("Encryption error: {}", e)))?;
-    
-    writer.finish()
+
+    std::io::copy(&mut reader, &mut writer)
         .map_err(|e| AppError::Mail(format!("Encryption error: {}", e)))?;
 
-    Ok(base64::engine::general_purpose::STANDARD.encode(&encrypted))
+    let output = writer.finish()
+        .map_err(|e| AppError::Mail(format!("Encryption error: {}", e)))?
+        .into_inner()
+        .map_err(|e| AppError::Mail(format!("Encryption error: {}", e.error())))?;
+
+    Ok(output.into_inner())
 }
 
 pub fn decrypt_email(encrypted_content: &str, secret_key: &str) -> Result<Vec<u8>, AppError> {
-    // Decode base64 content
-    let encrypted = base64::engine::general_purpose::STANDARD.decode(encrypted_content)
-        .map_err(|e| AppError::Mail(format!("Base64 decode error: {}", e)))?;
+    let mut decrypted = Vec::new();
+    decrypt_email_streaming(encrypted_content, secret_key, &mut decrypted)?;
+    Ok(decrypted)
+}
 
+/// Like `decrypt_email`, but decodes and decrypts incrementally into `writer`
+/// instead of buffering the decoded ciphertext and the plaintext. Returns the
+/// number of bytes written.
+pub fn decrypt_email_streaming(
+    encrypted_content: &str,
+    secret_key: &str,
+    mut writer: impl Write,
+) -> Result<u64, AppError> {
     // Parse the secret key
     let identity = age::x25519::Identity::from_str(secret_key)
         .map_err(|e| AppError::Mail(format!("Invalid secret key: {}", e)))?;
 
+    let encrypted = base64::read::DecoderReader::new(
+        encrypted_content.as_bytes(),
+        &base64::engine::general_purpose::STANDARD,
+    );
+
     // Create decryptor
-    let decryptor = match age::Decryptor::new(&encrypted[..])
+    let decryptor = match age::Decryptor::new(encrypted)
         .map_err(|e| AppError::Mail(format!("Decryption error: {}", e)))? {
         age::Decryptor::Recipients(d) => d,
         _ => return Err(AppError::Mail("Invalid decryptor type".to_string())),
     };
 
     // Decrypt the content
-    let mut decrypted = Vec::new();
     let mut reader = decryptor.decrypt(std::iter::once(&identity as &dyn age::Identity))
         .map_err(|e| AppError::Mail(format!("Decryption error: {}", e)))?;
 
-    std::io::Read::read_to_end(&mut reader, &mut decrypted)
-        .map_err(|e| AppError::Mail(format!("Decryption error: {}", e)))?;
+    std::io::copy(&mut reader, &mut writer)
+        .map_err(|e| AppError::Mail(format!("Decryption error: {}", e)))
+}
 
-    Ok(decrypted)
-} 
\ No newline at end of file
+/// Generates a backup key pair and encrypts its identity with the passphrase.
+/// Returns the public key and the encrypted identity.
+pub fn generate_backup_key(passphrase: &str) -> Result<(String, String), AppError> {
+    let identity = age::x25519::Identity::generate();
+    let public_key = identity.to_public().to_string();
+
+    let encryptor = age::Encryptor::with_user_passphrase(SecretString::new(passphrase.to_string()));
+    let mut encrypted = Vec::new();
+    let mut writer = encryptor.wrap_output(&mut encrypted)
+        .map_err(|e| AppError::Internal(format!("Backup key encryption error: {}", e)))?;
+
+    writer.write_all(identity.to_string().expose_secret().as_bytes())
+        .map_err(|e| AppError::Internal(format!("Backup key encryption error: {}", e)))?;
+
+    writer.finish()
+        .map_err(|e| AppError::Internal(format!("Backup key encryption error: {}", e)))?;
+
+    Ok((public_key, base64::engine::general_purpose::STANDARD.encode(&encrypted)))
+}
+
+/// Decrypts a backup identity produced by `generate_backup_key`
+pub fn decrypt_backup_key(encrypted_key: &str, passphrase: &str) -> Result<SecretString, AppError> {
+    let encrypted = base64::engine::general_purpose::STANDARD.decode(encrypted_key)
+        .map_err(|e| AppError::Internal(format!("Base64 decode error: {}", e)))?;
+
+    let decryptor = match age::Decryptor::new(&encrypted[..])
+        .map_err(|e| AppError::Internal(format!("Backup key decryption error: {}", e)))? {
+        age::Decryptor::Passphrase(d) => d,
+        _ => return Err(AppError::Internal("Invalid decryptor type".to_string())),
+    };
+
+    let mut reader = decryptor.decrypt(&SecretString::new(passphrase.to_string()), None)
+        .map_err(|e| match e {
+            age::DecryptError::DecryptionFailed => AppError::Auth("Incorrect backup passphrase".to_string()),
+            e => AppError::Internal(format!("Backup key decryption error: {}", e)),
+        })?;
+
+    let mut identity = String::new();
+    reader.read_to_string(&mut identity)
+        .map_err(|e| AppError::Internal(format!("Backup key decryption error: {}", e)))?;
+
+    Ok(SecretString::new(identity))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_streaming_round_trip() {
+        let identity = age::x25519::Identity::generate();
+        let public_key = identity.to_public().to_string();
+        let secret_key = identity.to_string().expose_secret().clone();
+
+        // Larger than age's 64 KiB chunks
+        let raw_email: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let encrypted = encrypt_email_streaming(&raw_email[..], &public_key, KeyType::AgeX25519).unwrap();
+
+        let mut decrypted = Vec::new();
+        let written = decrypt_email_streaming(&encrypted, &secret_key, &mut decrypted).unwrap();
+        assert_eq!(written, raw_email.len() as u64);
+        assert_eq!(decrypted, raw_email);
+
+        assert!(decrypt_email(&encrypted[1..], &secret_key).is_err());
+    }
+}
@@ -0,0 +1,79 @@
+//! Tracing setup shared by the binaries. Events are written to stdout; built
+//! with the `otlp` feature and `OTEL_EXPORTER_OTLP_ENDPOINT` set, spans are
+//! also exported over OTLP/HTTP. `OTEL_SERVICE_NAME` and
+//! `OTEL_RESOURCE_ATTRIBUTES` describe the service to the collector.
+
+/// Flushes and stops the span exporter when dropped, so it is kept until the
+/// process exits
+#[must_use]
+pub struct TracingGuard {
+    #[cfg(feature = "otlp")]
+    provider: Option<opentelemetry_sdk::trace::SdkTracerProvider>,
+}
+
+impl Drop for TracingGuard {
+    fn drop(&mut self) {
+        #[cfg(feature = "otlp")]
+        if let Some(provider) = self.provider.take() {
+            if let Err(e) = provider.shutdown() {
+                eprintln!("Failed to shut down the OpenTelemetry exporter: {}", e);
+            }
+        }
+    }
+}
+
+/// Installs the global subscriber. `service_name` names the service unless
+/// the environment does.
+pub fn init_tracing(service_name: &'static str) -> TracingGuard {
+    #[cfg(feature = "otlp")]
+    if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some() {
+        return otlp::init(service_name);
+    }
+
+    let _ = service_name;
+    tracing_subscriber::fmt::init();
+    TracingGuard {
+        #[cfg(feature = "otlp")]
+        provider: None,
+    }
+}
+
+#[cfg(feature = "otlp")]
+mod otlp {
+    use opentelemetry::trace::TracerProvider as _;
+    use opentelemetry_otlp::SpanExporter;
+    use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+    use tracing_subscriber::{filter::LevelFilter, layer::SubscriberExt as _, util::SubscriberInitExt as _};
+
+    use super::TracingGuard;
+
+    pub(super) fn init(service_name: &'static str) -> TracingGuard {
+        // The endpoint and its headers are read from the environment
+        let exporter = match SpanExporter::builder().with_http().build() {
+            Ok(exporter) => exporter,
+            Err(e) => {
+                tracing_subscriber::fmt::init();
+                tracing::warn!("OpenTelemetry export disabled, failed to create the exporter: {}", e);
+                return TracingGuard { provider: None };
+            }
+        };
+
+        let mut resource = Resource::builder();
+        let named_by_env = std::env::var_os("OTEL_SERVICE_NAME").is_some()
+            || std::env::var("OTEL_RESOURCE_ATTRIBUTES").is_ok_and(|attributes| attributes.contains("service.name="));
+        if !named_by_env {
+            resource = resource.with_service_name(service_name);
+        }
+        let provider = SdkTracerProvider::builder()
+            .with_batch_exporter(exporter)
+            .with_resource(resource.build())
+            .build();
+
+        tracing_subscriber::registry()
+            .with(LevelFilter::INFO)
+            .with(tracing_subscriber::fmt::layer())
+            .with(tracing_opentelemetry::layer().with_tracer(provider.tracer(service_name)))
+            .init();
+        TracingGuard { provider: Some(provider) }
+    }
+}
@@ -0,0 +1,210 @@
+use mail_parser::{HeaderValue, Message, MimeHeaders};
+
+/// A single per-recipient block of an RFC 3464 delivery status notification
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeliveryStatus {
+    pub original_recipient: String,
+    pub status_code: String,
+    pub diagnostic: Option<String>,
+    pub permanent: bool,
+}
+
+/// Whether the message looks like a bounce: a `multipart/report` with
+/// `report-type=delivery-status`, or anything sent by `mailer-daemon@`
+pub fn is_bounce(message: &Message) -> bool {
+    let is_report = message.content_type().is_some_and(|ct| {
+        ct.ctype().eq_ignore_ascii_case("multipart")
+            && ct.subtype().is_some_and(|s| s.eq_ignore_ascii_case("report"))
+            && ct
+                .attribute("report-type")
+                .is_some_and(|t| t.eq_ignore_ascii_case("delivery-status"))
+    });
+
+    is_report || from_mailer_daemon(message.from())
+}
+
+fn from_mailer_daemon(from: &HeaderValue) -> bool {
+    let address = match from {
+        HeaderValue::Address(addr) => addr.address.as_deref(),
+        HeaderValue::AddressList(list) => list.first().and_then(|addr| addr.address.as_deref()),
+        _ => None,
+    };
+    address.is_some_and(|address| {
+        address
+            .to_ascii_lowercase()
+            .starts_with("mailer-daemon@")
+    })
+}
+
+/// Extracts the per-recipient statuses from the `message/delivery-status`
+/// part. Bounces without a machine readable part yield nothing.
+pub fn parse_delivery_status(message: &Message) -> Vec<DeliveryStatus> {
+    message
+        .parts
+        .iter()
+        .filter(|part| {
+            part.content_type().is_some_and(|ct| {
+                ct.ctype().eq_ignore_ascii_case("message")
+                    && ct
+                        .subtype()
+                        .is_some_and(|s| s.eq_ignore_ascii_case("delivery-status"))
+            })
+        })
+        .filter_map(|part| part.text_contents())
+        .flat_map(parse_status_fields)
+        .collect()
+}
+
+/// Message-ID of the bounced message, from the copy of it (or of its headers)
+/// the report returns
+pub fn original_message_id(message: &Message) -> Option<String> {
+    message
+        .parts
+        .iter()
+        .filter(|part| {
+            part.content_type().is_some_and(|ct| {
+                let subtype = ct.subtype().unwrap_or_default();
+                (ct.ctype().eq_ignore_ascii_case("message") && subtype.eq_ignore_ascii_case("rfc822"))
+                    || (ct.ctype().eq_ignore_ascii_case("text") && subtype.eq_ignore_ascii_case("rfc822-headers"))
+            })
+        })
+        .find_map(|part| {
+            let message_id = match part.message() {
+                Some(original) => original.message_id()?.to_string(),
+                None => {
+                    // Returned headers usually lack the blank line ending them
+                    let headers = [part.contents(), b"\r\n\r\n"].concat();
+                    Message::parse(&headers)?.message_id()?.to_string()
+                }
+            };
+            Some(format!("<{}>", message_id))
+        })
+}
+
+fn parse_status_fields(body: &str) -> Vec<DeliveryStatus> {
+    // The first block holds per-message fields, every following block describes one recipient
+    body.replace("\r\n", "\n")
+        .split("\n\n")
+        .skip(1)
+        .filter_map(parse_recipient_block)
+        .collect()
+}
+
+fn parse_recipient_block(block: &str) -> Option<DeliveryStatus> {
+    let mut original_recipient = None;
+    let mut final_recipient = None;
+    let mut status_code = None;
+    let mut diagnostic = None;
+
+    for (name, value) in unfold_fields(block) {
+        match name.to_ascii_lowercase().as_str() {
+            "original-recipient" => original_recipient = Some(strip_address_type(&value)),
+            "final-recipient" => final_recipient = Some(strip_address_type(&value)),
+            "status" => status_code = Some(value),
+            "diagnostic-code" => diagnostic = Some(strip_address_type(&value)),
+            _ => {}
+        }
+    }
+
+    let status_code = status_code?;
+    Some(DeliveryStatus {
+        original_recipient: original_recipient.or(final_recipient)?,
+        // Class 5 is a permanent failure, class 4 a transient one
+        permanent: status_code.starts_with('5'),
+        status_code,
+        diagnostic,
+    })
+}
+
+/// Splits a block into `(name, value)` pairs, joining continuation lines
+fn unfold_fields(block: &str) -> Vec<(String, String)> {
+    let mut fields: Vec<(String, String)> = Vec::new();
+    for line in block.lines() {
+        if line.starts_with([' ', '\t']) {
+            if let Some((_, value)) = fields.last_mut() {
+                value.push(' ');
+                value.push_str(line.trim());
+            }
+        } else if let Some((name, value)) = line.split_once(':') {
+            fields.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+    fields
+}
+
+/// `rfc822; user@example.com` -> `user@example.com`
+fn strip_address_type(value: &str) -> String {
+    value
+        .split_once(';')
+        .map_or(value, |(_, rest)| rest)
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DSN: &str = "From: Mail Delivery System <MAILER-DAEMON@mx.example.com>\r\n\
+        To: sender@example.org\r\n\
+        Subject: Undelivered Mail Returned to Sender\r\n\
+        MIME-Version: 1.0\r\n\
+        Content-Type: multipart/report; report-type=delivery-status; boundary=\"b1\"\r\n\
+        \r\n\
+        --b1\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        Your message could not be delivered.\r\n\
+        --b1\r\n\
+        Content-Type: message/delivery-status\r\n\
+        \r\n\
+        Reporting-MTA: dns; mx.example.com\r\n\
+        \r\n\
+        Original-Recipient: rfc822; missing@example.com\r\n\
+        Final-Recipient: rfc822; missing@example.com\r\n\
+        Action: failed\r\n\
+        Status: 5.1.1\r\n\
+        Diagnostic-Code: smtp; 550 5.1.1 User unknown\r\n\
+        \x20in virtual mailbox table\r\n\
+        \r\n\
+        Final-Recipient: rfc822; busy@example.com\r\n\
+        Action: delayed\r\n\
+        Status: 4.2.2\r\n\
+        --b1\r\n\
+        Content-Type: text/rfc822-headers\r\n\
+        \r\n\
+        From: sender@example.org\r\n\
+        Message-ID: <original@example.org>\r\n\
+        --b1--\r\n";
+
+    #[test]
+    fn test_parse_delivery_status() {
+        let message = Message::parse(DSN.as_bytes()).unwrap();
+        assert!(is_bounce(&message));
+
+        let statuses = parse_delivery_status(&message);
+        assert_eq!(statuses, vec![
+            DeliveryStatus {
+                original_recipient: "missing@example.com".to_string(),
+                status_code: "5.1.1".to_string(),
+                diagnostic: Some("550 5.1.1 User unknown in virtual mailbox table".to_string()),
+                permanent: true,
+            },
+            DeliveryStatus {
+                original_recipient: "busy@example.com".to_string(),
+                status_code: "4.2.2".to_string(),
+                diagnostic: None,
+                permanent: false,
+            },
+        ]);
+        assert_eq!(original_message_id(&message).as_deref(), Some("<original@example.org>"));
+    }
+
+    #[test]
+    fn test_regular_email_is_not_bounce() {
+        let message = Message::parse(b"From: alice@example.com\r\nSubject: Hi\r\n\r\nHello").unwrap();
+        assert!(!is_bounce(&message));
+        assert!(parse_delivery_status(&message).is_empty());
+        assert_eq!(original_message_id(&message), None);
+    }
+}
@@ -1,4 +1,5 @@
 use clap::Parser;
+use common::id::IdFormat;
 use std::path::PathBuf;
 
 #[derive(Parser)]
@@ -15,6 +16,10 @@ pub struct Config {
     #[arg(long, env = "SMTP_TLS_BIND_ADDR", default_value = "127.0.0.1:465")]
     pub smtp_tls_bind_addr: String,
 
+    /// SMTP STARTTLS server bind address (only used when TLS is configured)
+    #[arg(long, env = "SMTP_STARTTLS_BIND_ADDR", default_value = "127.0.0.1:587")]
+    pub smtp_starttls_bind_addr: String,
+
     /// TLS certificate file path (optional, enables TLS if provided)
     #[arg(long, env = "TLS_CERT_PATH")]
     pub tls_cert_path: Option<PathBuf>,
@@ -31,6 +36,22 @@ pub struct Config {
     #[arg(long, env = "BLOCKED_NETWORKS", value_delimiter = ',')]
     pub blocked_networks: Option<Vec<String>>,
 
+    /// Relays of SMTP connections in CIDR format, whose prepended Received header names the client (comma-separated, none by default)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',')]
+    pub trusted_proxies: Vec<String>,
+
+    /// DNSBL zones to reject listed client IPs with (e.g. "zen.spamhaus.org,bl.spamcop.net")
+    #[arg(long, env = "BLOCKED_DNSBLS", value_delimiter = ',')]
+    pub blocked_dnsbls: Option<Vec<String>>,
+
+    /// Domains mail is accepted for (comma-separated), the web app's supported domains
+    #[arg(long, env = "SUPPORTED_DOMAINS", value_delimiter = ',', default_value = "mail-hook.example.com")]
+    pub supported_domains: Vec<String>,
+
+    /// Token of the Telegram login bot, which also notifies users of new emails; no notifications are sent when unset
+    #[arg(long, env = "TELEGRAM_BOT_TOKEN")]
+    pub telegram_bot_token: Option<String>,
+
     /// Maximum email size in bytes
     #[arg(long, env = "MAX_EMAIL_SIZE", default_value = "10485760")] // 10MB
     pub max_email_size: usize,
@@ -62,4 +83,40 @@ pub struct Config {
     /// TLS file polling interval in seconds (for watching TLS certificate changes)
     #[arg(long, env = "TLS_POLL_INTERVAL", default_value = "300")]
     pub tls_poll_interval: u64,
+
+    /// Maximum number of recipients of a single message processed concurrently
+    #[arg(long, env = "MAX_PARALLEL_RECIPIENTS", default_value = "8")]
+    pub max_parallel_recipients: usize,
+
+    /// Maximum number of SMTP connections served at once
+    #[arg(long, env = "MAX_SMTP_CONNECTIONS", default_value = "100")]
+    pub max_smtp_connections: usize,
+
+    /// Format of generated email IDs: uuid, time_ordered or nanoid
+    #[arg(long, env = "EMAIL_ID_FORMAT", default_value = "uuid")]
+    pub email_id_format: IdFormat,
+
+    /// Days to keep API key usage records
+    #[arg(long, env = "API_KEY_USAGE_RETENTION_DAYS", default_value = "30")]
+    pub api_key_usage_retention_days: u32,
+
+    /// Seconds to wait for in-progress SMTP transactions after a shutdown signal
+    #[arg(long, env = "SHUTDOWN_GRACE_PERIOD_SECS", default_value = "30")]
+    pub shutdown_grace_period_secs: u64,
+
+    /// Index email keywords as HMAC search tokens keyed by the mailbox public key
+    #[arg(long, env = "ENABLE_SEARCH_INDEX")]
+    pub enable_search_index: bool,
+
+    /// Apply pending database migrations and exit
+    #[arg(long, env = "MIGRATE_ONLY")]
+    pub migrate_only: bool,
+
+    /// Apply pending migrations to an in-memory copy of the database and exit without changing it; implies --migrate-only
+    #[arg(long = "dry-run", env = "DATABASE_MIGRATION_DRY_RUN")]
+    pub migration_dry_run: bool,
+
+    /// Log email addresses and usernames in full instead of only their first characters
+    #[arg(long, env = "DISABLE_LOG_REDACTION")]
+    pub disable_log_redaction: bool,
 } 
\ No newline at end of file
@@ -1,10 +1,20 @@
 use anyhow::Result;
 use common::AppError;
-use trust_dns_resolver::TokioAsyncResolver;
+#[cfg(any(test, feature = "test"))]
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use trust_dns_resolver::{error::ResolveErrorKind, TokioAsyncResolver};
 
 #[async_trait::async_trait]
 pub trait DnsResolver: Send + Sync {
     async fn mx_lookup(&self, domain: &str) -> Result<Vec<String>, AppError>;
+    /// TXT records of the domain, each with its strings joined. A domain
+    /// without records yields an empty list rather than an error.
+    async fn txt_lookup(&self, domain: &str) -> Result<Vec<String>, AppError>;
+    /// A and AAAA records of the domain, empty if there are none
+    async fn ip_lookup(&self, domain: &str) -> Result<Vec<IpAddr>, AppError>;
+    /// Whether the host has an A record, as DNSBLs answer for listed IPs
+    async fn a_lookup(&self, host: &str) -> Result<bool, AppError>;
 }
 
 pub struct TrustDnsResolver {
@@ -26,17 +36,82 @@ impl DnsResolver for TrustDnsResolver {
         
         Ok(mx_lookup.iter().map(|mx| mx.exchange().to_string()).collect())
     }
+
+    async fn txt_lookup(&self, domain: &str) -> Result<Vec<String>, AppError> {
+        match self.resolver.txt_lookup(domain).await {
+            Ok(txt_lookup) => Ok(txt_lookup
+                .iter()
+                .map(|txt| {
+                    txt.txt_data()
+                        .iter()
+                        .map(|data| String::from_utf8_lossy(data))
+                        .collect::<String>()
+                })
+                .collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+            Err(e) => Err(AppError::Mail(format!("Failed to lookup TXT records: {}", e))),
+        }
+    }
+
+    async fn ip_lookup(&self, domain: &str) -> Result<Vec<IpAddr>, AppError> {
+        match self.resolver.lookup_ip(domain).await {
+            Ok(ip_lookup) => Ok(ip_lookup.iter().collect()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(Vec::new()),
+            Err(e) => Err(AppError::Mail(format!("Failed to lookup addresses: {}", e))),
+        }
+    }
+
+    async fn a_lookup(&self, host: &str) -> Result<bool, AppError> {
+        match self.resolver.ipv4_lookup(host).await {
+            Ok(ipv4_lookup) => Ok(ipv4_lookup.iter().next().is_some()),
+            Err(e) if matches!(e.kind(), ResolveErrorKind::NoRecordsFound { .. }) => Ok(false),
+            Err(e) => Err(AppError::Mail(format!("Failed to lookup A records: {}", e))),
+        }
+    }
 }
 
+/// Answers every MX lookup with the same records, and TXT, address and A
+/// lookups from per-domain records added with the `with_*` methods
 #[cfg(any(test, feature = "test"))]
 pub struct MockDnsResolver {
     mx_records: Vec<String>,
+    txt_records: HashMap<String, Vec<String>>,
+    ip_records: HashMap<String, Vec<IpAddr>>,
+    a_records: HashSet<String>,
+    failing_a_lookups: HashSet<String>,
 }
 
 #[cfg(any(test, feature = "test"))]
 impl MockDnsResolver {
     pub fn new(mx_records: Vec<String>) -> Self {
-        Self { mx_records }
+        Self {
+            mx_records,
+            txt_records: HashMap::new(),
+            ip_records: HashMap::new(),
+            a_records: HashSet::new(),
+            failing_a_lookups: HashSet::new(),
+        }
+    }
+
+    pub fn with_txt(mut self, domain: &str, record: &str) -> Self {
+        self.txt_records.entry(domain.to_string()).or_default().push(record.to_string());
+        self
+    }
+
+    pub fn with_ip(mut self, domain: &str, ip: IpAddr) -> Self {
+        self.ip_records.entry(domain.to_string()).or_default().push(ip);
+        self
+    }
+
+    pub fn with_a(mut self, host: &str) -> Self {
+        self.a_records.insert(host.to_string());
+        self
+    }
+
+    /// Makes A lookups of the host fail, as with an unreachable server
+    pub fn with_a_error(mut self, host: &str) -> Self {
+        self.failing_a_lookups.insert(host.to_string());
+        self
     }
 }
 
@@ -46,6 +121,21 @@ impl DnsResolver for MockDnsResolver {
     async fn mx_lookup(&self, _domain: &str) -> Result<Vec<String>, AppError> {
         Ok(self.mx_records.clone())
     }
+
+    async fn txt_lookup(&self, domain: &str) -> Result<Vec<String>, AppError> {
+        Ok(self.txt_records.get(domain).cloned().unwrap_or_default())
+    }
+
+    async fn ip_lookup(&self, domain: &str) -> Result<Vec<IpAddr>, AppError> {
+        Ok(self.ip_records.get(domain).cloned().unwrap_or_default())
+    }
+
+    async fn a_lookup(&self, host: &str) -> Result<bool, AppError> {
+        if self.failing_a_lookups.contains(host) {
+            return Err(AppError::Mail(format!("Failed to lookup A records of {}", host)));
+        }
+        Ok(self.a_records.contains(host))
+    }
 }
 
 #[cfg(test)]
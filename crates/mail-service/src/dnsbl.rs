@@ -0,0 +1,84 @@
+use crate::dns::DnsResolver;
+use futures_util::future::join_all;
+use std::net::IpAddr;
+use tracing::warn;
+
+/// The name queried to check `ip` against `list`: the IPv4 octets, or the
+/// IPv6 nibbles, in reverse order followed by the list's zone
+pub fn query_name(ip: IpAddr, list: &str) -> String {
+    let reversed: Vec<String> = match ip {
+        IpAddr::V4(ip) => ip.octets().iter().rev().map(u8::to_string).collect(),
+        IpAddr::V6(ip) => ip
+            .octets()
+            .iter()
+            .rev()
+            .flat_map(|octet| [octet & 0x0f, octet >> 4])
+            .map(|nibble| format!("{:x}", nibble))
+            .collect(),
+    };
+    format!("{}.{}", reversed.join("."), list.trim_end_matches('.'))
+}
+
+/// Queries every list concurrently and returns the first one listing `ip`.
+/// A failed lookup counts as not listed, so an unreachable list does not
+/// block delivery.
+pub async fn find_listing<'a>(resolver: &dyn DnsResolver, ip: IpAddr, lists: &'a [String]) -> Option<&'a str> {
+    let lookups = lists.iter().map(|list| async move {
+        match resolver.a_lookup(&query_name(ip, list)).await {
+            Ok(listed) => listed,
+            Err(e) => {
+                warn!("DNSBL lookup of {} on {} failed: {}", ip, list, e);
+                false
+            }
+        }
+    });
+
+    join_all(lookups)
+        .await
+        .into_iter()
+        .zip(lists)
+        .find(|(listed, _)| *listed)
+        .map(|(_, list)| list.as_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::MockDnsResolver;
+
+    fn lists() -> Vec<String> {
+        vec!["zen.spamhaus.org".to_string(), "bl.spamcop.net".to_string()]
+    }
+
+    #[test]
+    fn test_query_name() {
+        assert_eq!(query_name("192.0.2.1".parse().unwrap(), "zen.spamhaus.org"), "1.2.0.192.zen.spamhaus.org");
+        assert_eq!(
+            query_name("2001:db8::1".parse().unwrap(), "bl.spamcop.net."),
+            "1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.bl.spamcop.net"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_listed() {
+        let resolver = MockDnsResolver::new(vec![]).with_a("1.2.0.192.bl.spamcop.net");
+        let lists = lists();
+        assert_eq!(find_listing(&resolver, "192.0.2.1".parse().unwrap(), &lists).await, Some("bl.spamcop.net"));
+    }
+
+    #[tokio::test]
+    async fn test_not_listed() {
+        let resolver = MockDnsResolver::new(vec![]).with_a("2.2.0.192.zen.spamhaus.org");
+        let lists = lists();
+        assert_eq!(find_listing(&resolver, "192.0.2.1".parse().unwrap(), &lists).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_error_does_not_block() {
+        let resolver = MockDnsResolver::new(vec![])
+            .with_a_error("1.2.0.192.zen.spamhaus.org")
+            .with_a_error("1.2.0.192.bl.spamcop.net");
+        let lists = lists();
+        assert_eq!(find_listing(&resolver, "192.0.2.1".parse().unwrap(), &lists).await, None);
+    }
+}
@@ -3,19 +3,39 @@ pub mod service;
 pub mod smtp;
 pub mod security;
 pub mod dns;
+pub mod bounce;
+pub mod spf;
+pub mod dnsbl;
+pub mod notifications;
+pub mod webhooks;
 
 use anyhow::Result;
+use common::shutdown::CancellationToken;
 pub use config::Config;  // Re-export Config
 pub use service::{MailService, ServiceConfig};  // Re-export MailService and ServiceConfig
 pub use dns::DnsResolver;  // Re-export DNS trait
 #[cfg(test)]
 pub use dns::MockDnsResolver;  // Re-export MockDnsResolver for testing
 
-use smtp::server::run_smtp_server;
+use smtp::{handler::SmtpShutdown, server::run_smtp_server};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// How often feature flag overrides are re-read from the database
+const FEATURE_FLAG_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Runs the SMTP server, reporting through `smtp_bound` whether it is
+/// listening, until `shutdown` is cancelled
+pub async fn run(mut config: Config, smtp_bound: watch::Sender<bool>, shutdown: CancellationToken) -> Result<()> {
+    common::logging::set_redaction_enabled(!config.disable_log_redaction);
+    let database_url = format!("sqlite:{}", config.database_path);
+    if config.migrate_only || config.migration_dry_run {
+        common::db::run_migrations_only(&database_url, config.migration_dry_run).await?;
+        return Ok(());
+    }
 
-pub async fn run(mut config: Config) -> Result<()> {
     // Parse blocked networks
     let blocked_networks = config.blocked_networks.take()
         .unwrap_or_default()
@@ -23,6 +43,13 @@ pub async fn run(mut config: Config) -> Result<()> {
         .filter_map(|cidr| cidr.parse().ok())
         .collect();
 
+    let trusted_proxies = config.trusted_proxies
+        .iter()
+        .map(|network| network.trim())
+        .filter(|network| !network.is_empty())
+        .map(|network| network.parse().map_err(|_| anyhow::anyhow!("Invalid network in TRUSTED_PROXIES: {}", network)))
+        .collect::<Result<_>>()?;
+
     let service_config = ServiceConfig {
         blocked_networks,
         max_email_size: config.max_email_size,
@@ -31,13 +58,25 @@ pub async fn run(mut config: Config) -> Result<()> {
         greylist_delay: Duration::from_secs(config.greylist_delay * 60),
         enable_spf: config.enable_spf,
         enable_dkim: config.enable_dkim,
+        email_id_format: config.email_id_format,
+        api_key_usage_retention_days: config.api_key_usage_retention_days,
+        enable_search_index: config.enable_search_index,
+        blocked_dnsbls: config.blocked_dnsbls.take().unwrap_or_default(),
+        supported_domains: std::mem::take(&mut config.supported_domains),
+        max_smtp_connections: config.max_smtp_connections,
+        trusted_proxies,
     };
 
-    let db = common::db::SqliteDatabase::new(&format!("sqlite:{}", config.database_path)).await?;
-    let service = Arc::new(MailService::new(
+    let db = common::db::SqliteDatabase::new(&database_url).await?;
+    // Shared with the web app's admin API when both run in one process
+    let mut service = MailService::new(
         Arc::new(db),
         service_config,
-    ).await?);
+    ).await?.with_greylist(common::greylist::Greylist::shared());
+    if let Some(bot_token) = config.telegram_bot_token.take() {
+        service = service.with_telegram_notifier(notifications::TelegramNotifier::new(bot_token));
+    }
+    let service = Arc::new(service);
 
     // Start cleanup task
     let cleanup_service = service.clone();
@@ -45,8 +84,34 @@ pub async fn run(mut config: Config) -> Result<()> {
         cleanup_service.start_cleanup_task(Duration::from_secs(config.cleanup_interval * 60)).await;
     });
 
-    // Run SMTP server
-    run_smtp_server(&config, service).await?;
+    service.clone().start_feature_flag_watcher(FEATURE_FLAG_POLL_INTERVAL).await;
+
+    // Run SMTP server until shutdown is requested
+    let smtp_shutdown = SmtpShutdown::default();
+    tokio::select! {
+        result = run_smtp_server(&config, service, smtp_shutdown.clone(), smtp_bound) => result?,
+        _ = shutdown.cancelled() => {
+            info!("Shutdown requested, refusing new SMTP transactions");
+            smtp_shutdown.begin();
+            drain_smtp_transactions(&smtp_shutdown, Duration::from_secs(config.shutdown_grace_period_secs)).await;
+        }
+    }
 
     Ok(())
 }
+
+async fn drain_smtp_transactions(shutdown: &SmtpShutdown, grace_period: Duration) {
+    let started = Instant::now();
+    loop {
+        let active = shutdown.active_transactions();
+        if active == 0 {
+            break;
+        }
+        if started.elapsed() >= grace_period {
+            warn!("Shutdown grace period elapsed with {} active SMTP sessions", active);
+            break;
+        }
+        info!("Waiting for {} active SMTP sessions", active);
+        tokio::time::sleep(Duration::from_millis(500)).await;
+    }
+}
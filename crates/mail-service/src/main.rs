@@ -1,18 +1,23 @@
 use tracing::info;
 use mail_service::{Config, run};
 use clap::Parser;
+use common::shutdown::CancellationToken;
 
 #[tokio::main]
 async fn main() {
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing, exporting spans until the end of main
+    let _tracing = common::telemetry::init_tracing("mail-service");
 
     // Parse command line arguments
     let config = Config::parse();
 
     info!("Mail service starting...");
     
-    if let Err(e) = run(config).await {
+    // Nothing serves the readiness probe in the standalone service
+    let (smtp_bound, _) = tokio::sync::watch::channel(false);
+    let shutdown = CancellationToken::new();
+    common::shutdown::cancel_on_signal(&shutdown);
+    if let Err(e) = run(config, smtp_bound, shutdown).await {
         tracing::error!("Mail service error: {}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,95 @@
+//! Telegram messages telling mailbox owners about new emails, sent by the
+//! bot of the Telegram login to users who linked their account and keep
+//! `email_notifications` on. At most one is sent per mailbox and minute.
+
+use common::{db::Database, AppError, Mailbox, UserSettings};
+use serde::Serialize;
+use std::{sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+const TELEGRAM_API_URL: &str = "https://api.telegram.org";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+/// Shortest time between two notifications about one mailbox
+pub const NOTIFICATION_COOLDOWN: Duration = Duration::from_secs(60);
+
+#[derive(Debug, Serialize)]
+struct SendMessage<'a> {
+    chat_id: &'a str,
+    text: &'a str,
+}
+
+#[derive(Clone)]
+pub struct TelegramNotifier {
+    client: reqwest::Client,
+    api_url: String,
+    bot_token: String,
+}
+
+impl TelegramNotifier {
+    pub fn new(bot_token: impl Into<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build Telegram HTTP client"),
+            api_url: TELEGRAM_API_URL.to_string(),
+            bot_token: bot_token.into(),
+        }
+    }
+
+    /// Sends through another Bot API server, e.g. a local one
+    pub fn with_api_url(mut self, api_url: impl Into<String>) -> Self {
+        self.api_url = api_url.into();
+        self
+    }
+
+    /// Notifies the owner of the mailbox of a saved email in the background
+    pub fn email_received(&self, db: Arc<dyn Database>, mailbox: &Mailbox) {
+        let notifier = self.clone();
+        let mailbox = mailbox.clone();
+        tokio::spawn(async move {
+            if let Err(e) = notifier.notify(db.as_ref(), &mailbox).await {
+                error!("Failed to notify the owner of mailbox {}: {}", mailbox.id, e);
+            }
+        });
+    }
+
+    async fn notify(&self, db: &dyn Database, mailbox: &Mailbox) -> Result<(), AppError> {
+        let settings = db
+            .get_user_settings(&mailbox.owner_id)
+            .await?
+            .unwrap_or_else(|| UserSettings::new(&mailbox.owner_id));
+        if !settings.email_notifications {
+            return Ok(());
+        }
+        let Some(chat_id) = db.get_telegram_chat_id(&mailbox.owner_id).await? else {
+            return Ok(());
+        };
+        let now = chrono::Utc::now().timestamp();
+        if !db.claim_mailbox_notification(&mailbox.id, now, NOTIFICATION_COOLDOWN.as_secs() as i64).await? {
+            debug!("Skipping notification for mailbox {}, one was sent recently", mailbox.id);
+            return Ok(());
+        }
+
+        let name = if mailbox.name.is_empty() { &mailbox.alias } else { &mailbox.name };
+        let text = format!("New email received in mailbox {}", name);
+        self.send_message(&chat_id, &text).await;
+        Ok(())
+    }
+
+    async fn send_message(&self, chat_id: &str, text: &str) {
+        let url = format!("{}/bot{}/sendMessage", self.api_url, self.bot_token);
+        let body = serde_json::to_vec(&SendMessage { chat_id, text }).expect("Telegram message is serializable");
+        let request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body);
+        // The URL holds the bot token, so errors are logged without it
+        match request.send().await {
+            Ok(response) if response.status().is_success() => debug!("Telegram notification sent"),
+            Ok(response) => warn!("Telegram sendMessage failed with status {}", response.status()),
+            Err(e) => warn!("Telegram sendMessage failed: {}", e.without_url()),
+        }
+    }
+}
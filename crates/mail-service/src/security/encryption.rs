@@ -1,2 +1,2 @@
 // This functionality has been moved to common::security
-pub use common::security::encrypt_email; 
\ No newline at end of file
+pub use common::security::{encrypt_email_to_keys, encrypt_email_with_backup};
@@ -1,10 +1,14 @@
-use crate::security::encryption::encrypt_email;
+use crate::security::encryption::encrypt_email_to_keys;
 use crate::dns::{DnsResolver, TrustDnsResolver};
 #[cfg(any(test, feature = "test"))]
 use crate::dns::MockDnsResolver;
 use anyhow::Result;
-use common::{db::Database, AppError, Email};
-use dashmap::DashMap;
+use crate::bounce;
+use crate::dnsbl;
+use crate::spf::{self, SpfResult};
+use crate::notifications::TelegramNotifier;
+use crate::webhooks::WebhookSender;
+use common::{attachments::strip_attachments, db::Database, events::{EmailEvent, EmailEvents}, logging::Redacted, proxy::TrustedProxies, feature_flags::{self, FeatureFlags}, greylist::{Greylist, GreylistKey, GreylistStatus}, id::{IdFormat, IdGenerator}, AppError, Bounce, Email, EmailAttachment, ForwardingKind, Mailbox, StorageQuota};
 use governor::{
     state::keyed::DashMapStateStore,
     Quota, RateLimiter,
@@ -12,9 +16,16 @@ use governor::{
 };
 use ipnetwork::IpNetwork;
 use mail_parser::Message;
-use std::{net::IpAddr, sync::Arc, time::Duration};
+use std::{borrow::Cow, net::IpAddr, sync::Arc, time::Duration};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use tracing::{error, info, warn, debug, trace};
 
+/// How long failed logins are kept; the web app locks accounts over the last 15 minutes
+const LOGIN_ATTEMPT_RETENTION_SECS: i64 = 24 * 60 * 60;
+
+/// How long recorded bounces are kept
+const BOUNCE_RETENTION_SECS: i64 = 90 * 24 * 60 * 60;
+
 #[derive(Clone)]
 pub struct ServiceConfig {
     pub blocked_networks: Vec<IpNetwork>,
@@ -24,6 +35,81 @@ pub struct ServiceConfig {
     pub greylist_delay: Duration,
     pub enable_spf: bool,
     pub enable_dkim: bool,
+    pub email_id_format: IdFormat,
+    pub api_key_usage_retention_days: u32,
+    pub enable_search_index: bool,
+    /// DNSBL zones queried for the client IP of every email
+    pub blocked_dnsbls: Vec<String>,
+    /// Domains mail is accepted for, compared case-insensitively
+    pub supported_domains: Vec<String>,
+    /// SMTP connections served at once; further clients get a 421 on HELO
+    pub max_smtp_connections: usize,
+    /// Relays whose prepended `Received` headers give the client address
+    pub trusted_proxies: Vec<IpNetwork>,
+}
+
+fn normalize_domains(domains: Vec<String>) -> Vec<String> {
+    domains
+        .into_iter()
+        .map(|domain| domain.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|domain| !domain.is_empty())
+        .collect()
+}
+
+/// The values of the `Received` headers of `raw_email`, topmost first
+fn received_headers(raw_email: &[u8]) -> Vec<String> {
+    let mut headers: Vec<String> = Vec::new();
+    let mut in_received = false;
+    let header_lines = raw_email
+        .split(|byte| *byte == b'\n')
+        .map(|line| line.strip_suffix(b"\r").unwrap_or(line))
+        .take_while(|line| !line.is_empty());
+    for line in header_lines {
+        let line = String::from_utf8_lossy(line);
+        // Folded lines continue the previous header
+        if line.starts_with([' ', '\t']) {
+            if let Some(received) = headers.last_mut().filter(|_| in_received) {
+                received.push_str(&line);
+            }
+            continue;
+        }
+        in_received = false;
+        if let Some((name, value)) = line.split_once(':') {
+            if name.eq_ignore_ascii_case("Received") {
+                headers.push(value.to_string());
+                in_received = true;
+            }
+        }
+    }
+    headers
+}
+
+/// The address of the `from` clause of a `Received` header, as in
+/// `from mail.example.com (mail.example.com [192.0.2.1]) by mx.example.com`
+fn received_from_ip(received: &str) -> Option<IpAddr> {
+    let received = received.split_whitespace().collect::<Vec<_>>().join(" ");
+    let (keyword, from) = received.split_once(' ')?;
+    if !keyword.eq_ignore_ascii_case("from") {
+        return None;
+    }
+    let from = from.split(" by ").next()?;
+    let start = from.find('[')? + 1;
+    let address = &from[start..start + from[start..].find(']')?];
+    address.strip_prefix("IPv6:").unwrap_or(address).parse().ok()
+}
+
+/// Feature flags start from the configuration; overrides stored by the
+/// admin API are applied on top when the database is reachable
+async fn load_feature_flags(db: &dyn Database, config: &ServiceConfig) -> FeatureFlags {
+    let flags = FeatureFlags::new([
+        (feature_flags::GREYLISTING, config.enable_greylisting),
+        (feature_flags::SPF, config.enable_spf),
+        (feature_flags::DKIM, config.enable_dkim),
+    ]);
+    if let Err(e) = flags.reload(db).await {
+        warn!("Using configured feature flags, failed to load overrides: {}", e);
+    }
+    flags
 }
 
 pub struct MailService {
@@ -31,12 +117,20 @@ pub struct MailService {
     blocked_networks: Vec<IpNetwork>,
     max_email_size: usize,
     rate_limiter: Arc<RateLimiter<IpAddr, DashMapStateStore<IpAddr>, DefaultClock>>,
-    greylist: Arc<DashMap<(IpAddr, String, String), i64>>, // (IP, from, to) -> first_seen
-    enable_greylisting: bool,
+    greylist: Greylist,
     greylist_delay: Duration,
-    enable_spf: bool,
-    enable_dkim: bool,
-    #[allow(dead_code)]
+    feature_flags: FeatureFlags,
+    id_generator: Arc<dyn IdGenerator>,
+    api_key_usage_retention_days: u32,
+    enable_search_index: bool,
+    blocked_dnsbls: Vec<String>,
+    /// Lowercase, without a trailing dot
+    domains: Vec<String>,
+    webhooks: WebhookSender,
+    /// Sends Telegram notifications of new emails, when a bot token is configured
+    notifier: Option<TelegramNotifier>,
+    smtp_connections: Arc<Semaphore>,
+    trusted_proxies: TrustedProxies,
     dns_resolver: Arc<dyn DnsResolver>,
 }
 
@@ -48,16 +142,25 @@ impl MailService {
 
         let dns_resolver = Arc::new(TrustDnsResolver::new().await?);
 
+        let feature_flags = load_feature_flags(db.as_ref(), &config).await;
+
         Ok(Self {
             db,
             blocked_networks: config.blocked_networks,
             max_email_size: config.max_email_size,
             rate_limiter,
-            greylist: Arc::new(DashMap::new()),
-            enable_greylisting: config.enable_greylisting,
+            greylist: Greylist::default(),
             greylist_delay: config.greylist_delay,
-            enable_spf: config.enable_spf,
-            enable_dkim: config.enable_dkim,
+            feature_flags,
+            id_generator: config.email_id_format.generator(),
+            api_key_usage_retention_days: config.api_key_usage_retention_days,
+            enable_search_index: config.enable_search_index,
+            blocked_dnsbls: config.blocked_dnsbls,
+            domains: normalize_domains(config.supported_domains),
+            webhooks: WebhookSender::default(),
+            notifier: None,
+            smtp_connections: Arc::new(Semaphore::new(config.max_smtp_connections.max(1))),
+            trusted_proxies: TrustedProxies::new(config.trusted_proxies),
             dns_resolver,
         })
     }
@@ -71,20 +174,45 @@ impl MailService {
             std::num::NonZeroU32::new(config.rate_limit_per_hour).unwrap(),
         )));
 
+        let feature_flags = load_feature_flags(db.as_ref(), &config).await;
+
         Ok(Self {
             db,
             blocked_networks: config.blocked_networks,
             max_email_size: config.max_email_size,
             rate_limiter,
-            greylist: Arc::new(DashMap::new()),
-            enable_greylisting: config.enable_greylisting,
+            greylist: Greylist::default(),
             greylist_delay: config.greylist_delay,
-            enable_spf: config.enable_spf,
-            enable_dkim: config.enable_dkim,
+            feature_flags,
+            id_generator: config.email_id_format.generator(),
+            api_key_usage_retention_days: config.api_key_usage_retention_days,
+            enable_search_index: config.enable_search_index,
+            blocked_dnsbls: config.blocked_dnsbls,
+            domains: normalize_domains(config.supported_domains),
+            webhooks: WebhookSender::default(),
+            notifier: None,
+            smtp_connections: Arc::new(Semaphore::new(config.max_smtp_connections.max(1))),
+            trusted_proxies: TrustedProxies::new(config.trusted_proxies),
             dns_resolver,
         })
     }
 
+    /// Replaces the service's private greylist, e.g. with [`Greylist::shared`]
+    pub fn with_greylist(mut self, greylist: Greylist) -> Self {
+        self.greylist = greylist;
+        self
+    }
+
+    pub fn with_webhook_sender(mut self, webhooks: WebhookSender) -> Self {
+        self.webhooks = webhooks;
+        self
+    }
+
+    pub fn with_telegram_notifier(mut self, notifier: TelegramNotifier) -> Self {
+        self.notifier = Some(notifier);
+        self
+    }
+
     #[cfg(any(test, feature = "test"))]
     pub async fn with_mock_resolver(db: Arc<dyn Database>, config: ServiceConfig, mx_records: Vec<String>) -> Result<Self> {
         let rate_limiter = Arc::new(RateLimiter::dashmap(Quota::per_hour(
@@ -93,16 +221,25 @@ impl MailService {
 
         let dns_resolver = Arc::new(MockDnsResolver::new(mx_records));
 
+        let feature_flags = load_feature_flags(db.as_ref(), &config).await;
+
         Ok(Self {
             db,
             blocked_networks: config.blocked_networks,
             max_email_size: config.max_email_size,
             rate_limiter,
-            greylist: Arc::new(DashMap::new()),
-            enable_greylisting: config.enable_greylisting,
+            greylist: Greylist::default(),
             greylist_delay: config.greylist_delay,
-            enable_spf: config.enable_spf,
-            enable_dkim: config.enable_dkim,
+            feature_flags,
+            id_generator: config.email_id_format.generator(),
+            api_key_usage_retention_days: config.api_key_usage_retention_days,
+            enable_search_index: config.enable_search_index,
+            blocked_dnsbls: config.blocked_dnsbls,
+            domains: normalize_domains(config.supported_domains),
+            webhooks: WebhookSender::default(),
+            notifier: None,
+            smtp_connections: Arc::new(Semaphore::new(config.max_smtp_connections.max(1))),
+            trusted_proxies: TrustedProxies::new(config.trusted_proxies),
             dns_resolver,
         })
     }
@@ -111,6 +248,12 @@ impl MailService {
         self.max_email_size
     }
 
+    /// Claims one of the `max_smtp_connections` slots, held until the permit
+    /// is dropped, or `None` when all of them are taken
+    pub fn try_acquire_smtp_connection(&self) -> Option<OwnedSemaphorePermit> {
+        Arc::clone(&self.smtp_connections).try_acquire_owned().ok()
+    }
+
     fn normalize_email_local_part(local_part: &str) -> String {
         // Remove everything after + (including +)
         let base = local_part.split('+').next().unwrap_or(local_part);
@@ -121,59 +264,106 @@ impl MailService {
             .collect()
     }
 
+    #[tracing::instrument(
+        name = "smtp.process_email",
+        skip_all,
+        fields(email.recipient = %Redacted(recipient), email.sender = %Redacted(sender), email.size = raw_email.len())
+    )]
     pub async fn process_incoming_email(
         &self,
         raw_email: &[u8],
         recipient: &str,
         sender: &str,
         client_ip: IpAddr,
+    ) -> Result<(), AppError> {
+        let mut mailbox_id = None;
+        let result = self.receive_email(raw_email, recipient, sender, client_ip, &mut mailbox_id).await;
+
+        // Emails rejected before their mailbox is known have an empty mailbox_id
+        let status = if result.is_ok() { "ok" } else { "rejected" };
+        metrics::counter!(
+            "emails_received_total",
+            "mailbox_id" => mailbox_id.unwrap_or_default(),
+            "status" => status
+        )
+        .increment(1);
+
+        result
+    }
+
+    async fn receive_email(
+        &self,
+        raw_email: &[u8],
+        recipient: &str,
+        sender: &str,
+        client_ip: IpAddr,
+        mailbox_id: &mut Option<String>,
     ) -> Result<(), AppError> {
         info!(
             "Processing incoming email for recipient: {} from {}",
-            recipient, sender
+            Redacted(recipient), Redacted(sender)
         );
 
+        self.check_dnsbls(client_ip).await?;
+
         // Extract local_part and domain from recipient
-        let (local_part, _domain) = recipient.split_once('@')
+        let (local_part, domain) = recipient.split_once('@')
             .ok_or_else(|| AppError::Mail("Invalid recipient address format".to_string()))?;
+        if !self.handles_domain(domain) {
+            return Err(AppError::Mail(format!("Recipient domain not handled: {}", domain)));
+        }
 
-        debug!("Local part: {}", local_part);
+        debug!("Local part: {}", Redacted(local_part));
 
         // Normalize the local part
         let normalized_local_part = Self::normalize_email_local_part(local_part);
-        debug!("Normalized local part: {}", normalized_local_part);
+        debug!("Normalized local part: {}", Redacted(&normalized_local_part));
 
         // Check greylisting if enabled
-        if self.enable_greylisting {
-            trace!("Checking greylisting for {}", recipient);
-            let key = (client_ip, sender.to_string(), recipient.to_string());
+        if self.feature_flags.is_enabled(feature_flags::GREYLISTING) {
+            trace!("Checking greylisting for {}", Redacted(recipient));
+            let key = GreylistKey::new(client_ip, sender, recipient);
             let now = chrono::Utc::now().timestamp();
 
-            if let Some(first_seen) = self.greylist.get(&key) {
-                if now - *first_seen < self.greylist_delay.as_secs() as i64 {
+            // Triples still waiting are answered from memory, the database
+            // decides everything else so the state survives restarts
+            if self.greylist.eligible_at(&key).is_some_and(|eligible_at| now < eligible_at) {
+                debug!("Greylisted, try again later");
+                return Err(AppError::Mail("Greylisted, try again later".to_string()));
+            }
+
+            let status = self.db
+                .greylist_check_and_insert(&client_ip.to_string(), sender, recipient, self.greylist_delay.as_secs() as i64)
+                .await?;
+            match status {
+                GreylistStatus::Deferred { first_seen, eligible_at } => {
+                    self.greylist.insert(key, first_seen, eligible_at);
                     debug!("Greylisted, try again later");
                     return Err(AppError::Mail("Greylisted, try again later".to_string()));
                 }
-                debug!("Greylist removed");
-            } else {
-                self.greylist.insert(key, now);
-                debug!("Greylisted, try again later");
-                return Err(AppError::Mail("Greylisted, try again later".to_string()));
+                GreylistStatus::Passed => {
+                    debug!("Greylist removed");
+                    self.greylist.remove(&key);
+                }
             }
-            // the removal is done here to avoid deadlock with if let
-            // Remove from greylist after successful delay period
-            self.greylist.remove(&key);
         }
 
         trace!("Parsing email content");
         // Parse email for validation and extraction
-        let _parsed_email = Message::parse(raw_email)
+        let parsed_email = Message::parse(raw_email)
             .ok_or_else(|| AppError::Mail("Failed to parse email".to_string()))?;
         trace!("Email parsed successfully");
 
+        // Messages without a Message-ID get a unique one, so they are never
+        // taken for retransmissions
+        let message_id = parsed_email
+            .message_id()
+            .map(|id| format!("<{}>", id))
+            .unwrap_or_else(|| format!("<{}@{}>", uuid::Uuid::new_v4(), domain));
+
         // Validate SPF if enabled
-        if self.enable_spf {
-            trace!("Checking SPF for sender: {}", sender);
+        if self.feature_flags.is_enabled(feature_flags::SPF) {
+            trace!("Checking SPF for sender: {}", Redacted(sender));
             let spf_result = self.check_spf(sender, client_ip).await?;
             if !spf_result {
                 return Err(AppError::Mail("SPF validation failed".to_string()));
@@ -184,7 +374,7 @@ impl MailService {
         }
 
         // Validate DKIM if enabled
-        if self.enable_dkim {
+        if self.feature_flags.is_enabled(feature_flags::DKIM) {
             trace!("Verifying DKIM signature");
             let dkim_result = self.verify_dkim(raw_email).await?;
             if !dkim_result {
@@ -198,11 +388,14 @@ impl MailService {
         debug!("Mailbox pre-validation passed");
 
         trace!("Looking up mailbox in database");
-        let mailbox = self
-            .db
-            .get_mailbox_by_incoming_address(normalized_local_part.as_str())
-            .await?
-            .ok_or_else(|| AppError::Mail(format!("Mailbox not found: {}", recipient)))?;
+        // Chosen aliases may contain characters normalization removes
+        let mailbox = match self.db.get_mailbox_by_address(local_part).await? {
+            Some(mailbox) => Some(mailbox),
+            None => self.db.get_mailbox_by_incoming_address(normalized_local_part.as_str()).await?,
+        };
+        let mailbox = mailbox
+            .ok_or_else(|| AppError::Mail(format!("Mailbox not found: {}", Redacted(recipient))))?;
+        *mailbox_id = Some(mailbox.id.clone());
 
         if !self.check_rate_limit(client_ip) {
             return Err(AppError::Mail("Rate limit exceeded".to_string()));
@@ -210,36 +403,215 @@ impl MailService {
 
         debug!("Mailbox found: {}", mailbox.id);
 
+        let Some(email) = self.store_email(&mailbox, raw_email, &parsed_email, &message_id, client_ip).await? else {
+            // Common after greylisting, the sender retrying a delivery that succeeded
+            info!("Dropped a retransmission of {} already in mailbox {}", message_id, mailbox.id);
+            return Ok(());
+        };
+        self.forward_email(&email, raw_email, &parsed_email, &message_id, client_ip).await;
+
+        if bounce::is_bounce(&parsed_email) {
+            self.record_bounces(&parsed_email).await;
+        }
+        info!("Email processing completed successfully for recipient: {}", Redacted(recipient));
+
+        Ok(())
+    }
+
+    /// Encrypts the email to the mailbox's keys and saves it with its
+    /// attachments, then notifies the mailbox's listeners. Returns `None`
+    /// when the mailbox already has an email with the same Message-ID.
+    async fn store_email(
+        &self,
+        mailbox: &Mailbox,
+        raw_email: &[u8],
+        parsed_email: &Message<'_>,
+        message_id: &str,
+        client_ip: IpAddr,
+    ) -> Result<Option<Email>, AppError> {
+        let quota = StorageQuota::load(self.db.as_ref(), &mailbox.owner_id).await?;
+        if let Some(quota) = &quota {
+            quota.check(1, 0)?;
+        }
+
+        let (body, attachments) = if mailbox.strip_attachments {
+            trace!("Stripping attachments");
+            let (body, attachments) = strip_attachments(parsed_email);
+            debug!("Stripped {} attachments", attachments.len());
+            (Cow::Owned(body), attachments)
+        } else {
+            (Cow::Borrowed(raw_email), Vec::new())
+        };
+
         trace!("Encrypting email content");
-        // Encrypt email content using age encryption
-        let encrypted_content = encrypt_email(raw_email, &mailbox.public_key)?;
+        // Encrypt email content using age encryption to every key of the
+        // mailbox, also to the owner's backup key if set up
+        let keys = self.db.list_mailbox_keys(&mailbox.id).await?;
+        let backup_key = self.db.get_backup_key(&mailbox.owner_id).await?;
+        let backup_public_key = backup_key.as_ref().map(|key| key.public_key.as_str());
+        let encrypted_content = encrypt_email_to_keys(&body, &keys, backup_public_key)?;
 
         debug!("Encrypted content");
 
         let received_at = chrono::Utc::now().timestamp();
         let email = Email {
-            id: uuid::Uuid::new_v4().to_string(),
+            id: self.id_generator.generate(),
             mailbox_id: mailbox.id.clone(),
             encrypted_content,
             received_at,
             expires_at: mailbox.mail_expires_in.map(|duration| received_at + duration),
-        };
+            from_addr: String::new(),
+            to_addr: String::new(),
+            subject: String::new(),
+            headers_json: None,
+            raw_size_bytes: raw_email.len() as i64,
+            message_id: None,
+        }
+        .with_headers(parsed_email);
+        let email = Email { message_id: Some(message_id.to_string()), ..email };
+
+        let attachments = attachments
+            .into_iter()
+            .map(|attachment| {
+                Ok(EmailAttachment {
+                    encrypted_content: encrypt_email_to_keys(&attachment.content, &keys, backup_public_key)?,
+                    id: attachment.id,
+                    email_id: email.id.clone(),
+                    filename: attachment.filename,
+                    mime_type: attachment.mime_type,
+                })
+            })
+            .collect::<Result<Vec<_>, AppError>>()?;
 
         debug!("Email created");
 
+        if let Some(quota) = &quota {
+            let bytes = email.encrypted_content.len()
+                + attachments.iter().map(|attachment| attachment.encrypted_content.len()).sum::<usize>();
+            quota.check(1, bytes as i64)?;
+        }
+
         trace!("Saving email to database");
-        self.db.save_email(&email).await?;
+        if !self.db.save_email(&email).await? {
+            return Ok(None);
+        }
+        if !attachments.is_empty() {
+            if let Err(e) = self.db.save_email_attachments(&attachments).await {
+                // Don't keep a body whose attachments are lost
+                let _ = self.db.delete_email(&email.id).await;
+                return Err(e);
+            }
+        }
 
         debug!("Email saved");
-        info!("Email processing completed successfully for recipient: {}", recipient);
 
-        Ok(())
+        if self.enable_search_index {
+            let search_tokens = common::search::search_tokens(parsed_email, &mailbox.public_key);
+            if let Err(e) = self.db.save_email_search_tokens(&email, &search_tokens).await {
+                error!("Failed to index email {}: {}", email.id, e);
+            }
+        }
+
+        EmailEvents::shared().publish(EmailEvent {
+            mailbox_id: email.mailbox_id.clone(),
+            email_id: email.id.clone(),
+            received_at: email.received_at,
+        });
+        self.webhooks.email_received(self.db.clone(), &email, client_ip);
+        if let Some(notifier) = &self.notifier {
+            notifier.email_received(self.db.clone(), mailbox);
+        }
+
+        Ok(Some(email))
+    }
+
+    /// Applies the mailbox's forwarding rules to a saved email. Copies saved
+    /// in other mailboxes are not forwarded again, so rules cannot loop.
+    /// Failures are only logged since the email has already been delivered.
+    async fn forward_email(&self, email: &Email, raw_email: &[u8], parsed_email: &Message<'_>, message_id: &str, client_ip: IpAddr) {
+        let rules = match self.db.get_forwarding_rules(&email.mailbox_id).await {
+            Ok(rules) => rules,
+            Err(e) => {
+                error!("Failed to load forwarding rules of mailbox {}: {}", email.mailbox_id, e);
+                return;
+            }
+        };
+
+        for rule in rules.iter().filter(|rule| rule.matches(email)) {
+            match rule.kind {
+                ForwardingKind::Webhook => self.webhooks.forward_email(rule, email),
+                ForwardingKind::Mailbox => {
+                    let destination = match self.db.get_mailbox(&rule.destination).await {
+                        Ok(Some(destination)) if destination.id != email.mailbox_id => destination,
+                        Ok(_) => {
+                            warn!("Forwarding rule {} has no valid destination mailbox", rule.id);
+                            continue;
+                        }
+                        Err(e) => {
+                            error!("Failed to load destination of forwarding rule {}: {}", rule.id, e);
+                            continue;
+                        }
+                    };
+                    match self.store_email(&destination, raw_email, parsed_email, message_id, client_ip).await {
+                        Ok(Some(copy)) => debug!("Email {} forwarded to mailbox {} as {}", email.id, destination.id, copy.id),
+                        Ok(None) => debug!("Email {} is already in mailbox {}", email.id, destination.id),
+                        Err(e) => warn!("Forwarding rule {} failed to save a copy: {}", rule.id, e),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores the DSN statuses of a bounce, once per bounced message and
+    /// recipient. Failures are only logged since the bounce itself has already
+    /// been delivered to the mailbox.
+    async fn record_bounces(&self, message: &Message<'_>) {
+        let bounced_at = chrono::Utc::now().timestamp();
+        let message_id = bounce::original_message_id(message)
+            .or_else(|| message.message_id().map(|id| format!("<{}>", id)));
+        for status in bounce::parse_delivery_status(message) {
+            info!(
+                "Bounce for {}: status {} ({})",
+                Redacted(&status.original_recipient),
+                status.status_code,
+                if status.permanent { "permanent" } else { "transient" }
+            );
+            let bounce = Bounce {
+                id: self.id_generator.generate(),
+                message_id: message_id.clone(),
+                original_recipient: status.original_recipient,
+                status_code: status.status_code,
+                diagnostic: status.diagnostic,
+                permanent: status.permanent,
+                bounced_at,
+            };
+            match self.db.save_bounce(&bounce).await {
+                Ok(true) => {}
+                Ok(false) => debug!("Bounce for {} already recorded", Redacted(&bounce.original_recipient)),
+                Err(e) => error!("Failed to record bounce: {}", e),
+            }
+        }
     }
 
-    async fn check_spf(&self, _sender: &str, _client_ip: IpAddr) -> Result<bool, AppError> {
-        // TODO: Implement SPF checking
-        warn!("SPF checking is temporarily disabled");
-        Ok(true) // Temporarily allow all SPF checks to pass
+    /// Only a `fail` result rejects the message; errors and softer results
+    /// are accepted so a broken record does not block delivery
+    async fn check_spf(&self, sender: &str, client_ip: IpAddr) -> Result<bool, AppError> {
+        let result = spf::check_sender(self.dns_resolver.as_ref(), client_ip, sender).await;
+        debug!("SPF result for {} from {}: {:?}", Redacted(sender), client_ip, result);
+        Ok(result != SpfResult::Fail)
+    }
+
+    async fn check_dnsbls(&self, client_ip: IpAddr) -> Result<(), AppError> {
+        if self.blocked_dnsbls.is_empty() {
+            return Ok(());
+        }
+        match dnsbl::find_listing(self.dns_resolver.as_ref(), client_ip, &self.blocked_dnsbls).await {
+            Some(list) => {
+                warn!("Rejecting email from {} listed in DNSBL {}", client_ip, list);
+                Err(AppError::Mail(format!("IP listed in DNSBL: {}", list)))
+            }
+            None => Ok(()),
+        }
     }
 
     async fn verify_dkim(&self, _raw_email: &[u8]) -> Result<bool, AppError> {
@@ -248,10 +620,52 @@ impl MailService {
         Ok(true) // Temporarily allow all DKIM checks to pass
     }
 
+    /// Whether mail for the domain is accepted, one of the supported domains
+    pub fn handles_domain(&self, domain: &str) -> bool {
+        let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+        self.domains.contains(&domain)
+    }
+
+    /// Whether the recipient's alias was recently rotated away, in which case
+    /// mail to it is refused outright rather than accepted and dropped
+    pub async fn is_retired_address(&self, recipient: &str) -> Result<bool, AppError> {
+        let Some((local_part, _domain)) = recipient.split_once('@') else {
+            return Ok(false);
+        };
+        let now = chrono::Utc::now().timestamp();
+        if self.db.is_alias_retired(local_part, now).await? {
+            return Ok(true);
+        }
+        let normalized_local_part = Self::normalize_email_local_part(local_part);
+        Ok(normalized_local_part != local_part && self.db.is_alias_retired(&normalized_local_part, now).await?)
+    }
+
     pub fn is_ip_blocked(&self, ip: IpAddr) -> bool {
         self.blocked_networks.iter().any(|net| net.contains(ip))
     }
 
+    pub fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.contains(ip)
+    }
+
+    /// The client that sent `raw_email` over a connection from `peer`. A
+    /// trusted relay records the address it accepted the message from in the
+    /// `Received` header it prepends, so the headers are followed from the top
+    /// while they were added by trusted relays; the ones below may be forged.
+    pub fn resolve_client_ip(&self, peer: IpAddr, raw_email: &[u8]) -> IpAddr {
+        let mut client = peer;
+        for received in received_headers(raw_email) {
+            if !self.trusted_proxies.contains(client) {
+                break;
+            }
+            match received_from_ip(&received) {
+                Some(ip) => client = ip,
+                None => break,
+            }
+        }
+        client
+    }
+
     pub fn check_rate_limit(&self, ip: IpAddr) -> bool {
         self.rate_limiter.check_key(&ip).is_ok()
     }
@@ -262,6 +676,26 @@ impl MailService {
         self.db.cleanup_expired_emails().await?;
         self.db.cleanup_expired_mailboxes().await?;
 
+        let usage_cutoff = chrono::Utc::now().timestamp()
+            - i64::from(self.api_key_usage_retention_days) * 24 * 60 * 60;
+        self.db.cleanup_api_key_usage(usage_cutoff).await?;
+
+        let login_attempts_cutoff = chrono::Utc::now().timestamp() - LOGIN_ATTEMPT_RETENTION_SECS;
+        self.db.cleanup_login_attempts(login_attempts_cutoff).await?;
+        self.db.cleanup_bounces(chrono::Utc::now().timestamp() - BOUNCE_RETENTION_SECS).await?;
+
+        self.db.cleanup_old_aliases(chrono::Utc::now().timestamp()).await?;
+        self.db.cleanup_expired_tokens(chrono::Utc::now().timestamp()).await?;
+
+        // One user's failing policy shouldn't stop the others from being applied
+        for (user_id, policy) in self.db.get_email_cleanup_policies().await? {
+            match self.db.apply_email_cleanup_policy(&user_id, &policy).await {
+                Ok(0) => {}
+                Ok(deleted) => info!("Cleanup policy removed {} emails for user {}", deleted, user_id),
+                Err(e) => error!("Failed to apply cleanup policy for user {}: {}", user_id, e),
+            }
+        }
+
         Ok(())
     }
 
@@ -281,9 +715,25 @@ impl MailService {
 
                 // Cleanup old greylist entries
                 let now = chrono::Utc::now().timestamp();
-                service.greylist.retain(|_, first_seen| {
-                    now - *first_seen < (service.greylist_delay.as_secs() * 2) as i64
-                });
+                let cutoff = now - (service.greylist_delay.as_secs() * 2) as i64;
+                service.greylist.remove_older_than(cutoff);
+                if let Err(e) = service.db.greylist_cleanup(cutoff).await {
+                    error!("Greylist cleanup error: {}", e);
+                }
+            }
+        });
+    }
+
+    /// Picks up feature flag changes made through the admin API
+    pub async fn start_feature_flag_watcher(self: Arc<Self>, interval: Duration) {
+        let service = self.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                if let Err(e) = service.feature_flags.reload(service.db.as_ref()).await {
+                    error!("Failed to reload feature flags: {}", e);
+                }
             }
         });
     }
@@ -300,4 +750,16 @@ mod tests {
         let result = resolver.mx_lookup("example.com").await.unwrap();
         assert_eq!(result, mock_records);
     }
+
+    #[test]
+    fn test_received_from_ip() {
+        let raw_email = b"Received: from mail.example.com (mail.example.com\r\n\t[192.0.2.1]) by mx.example.com;\r\n Mon, 1 Jan 2024 00:00:00 +0000\r\nX-Received: from [198.51.100.1]\r\nreceived: from [IPv6:2001:db8::1] by relay\r\nSubject: Test\r\n\r\nReceived: from [203.0.113.1]\r\n";
+        let received = received_headers(raw_email);
+        assert_eq!(received.len(), 2);
+        assert_eq!(received_from_ip(&received[0]), Some("192.0.2.1".parse().unwrap()));
+        assert_eq!(received_from_ip(&received[1]), Some("2001:db8::1".parse().unwrap()));
+
+        assert_eq!(received_from_ip("by mx.example.com with ESMTP"), None);
+        assert_eq!(received_from_ip("from mail.example.com by mx.example.com [192.0.2.1]"), None);
+    }
 }
@@ -1,9 +1,101 @@
 use crate::service::MailService;
+use common::{logging::Redacted, request_id};
+use futures_util::{stream, StreamExt};
 use mailin_embedded::{Handler, Response};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::{io, net::IpAddr, sync::Arc};
-use tokio::runtime::Runtime;
-use tracing::{debug, error, warn};
+use tokio::{runtime::Runtime, sync::OwnedSemaphorePermit};
+use tracing::{debug, error, info, warn};
+
+/// Shared between the SMTP server and every handler so a shutdown can stop new
+/// transactions and wait for the in-progress ones
+#[derive(Clone, Default)]
+pub struct SmtpShutdown {
+    pub shutting_down: Arc<AtomicBool>,
+    pub active_transactions: Arc<AtomicUsize>,
+}
+
+impl SmtpShutdown {
+    pub fn begin(&self) {
+        self.shutting_down.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutting_down.load(Ordering::SeqCst)
+    }
+
+    pub fn active_transactions(&self) -> usize {
+        self.active_transactions.load(Ordering::SeqCst)
+    }
+}
+
+/// A connection's hold on one of the service's SMTP connection slots, taken
+/// on HELO and given back when the connection's handler is dropped. Clones
+/// serve new connections, so they start without one.
+#[derive(Default)]
+struct ConnectionSlot(Option<OwnedSemaphorePermit>);
+
+impl ConnectionSlot {
+    /// Returns whether the connection holds a slot, taking one if needed
+    fn acquire(&mut self, service: &MailService) -> bool {
+        if self.0.is_none() {
+            let Some(permit) = service.try_acquire_smtp_connection() else {
+                return false;
+            };
+            self.0 = Some(permit);
+            metrics::gauge!("smtp_connections_active").increment(1.0);
+        }
+        true
+    }
+}
+
+impl Clone for ConnectionSlot {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        if self.0.take().is_some() {
+            metrics::gauge!("smtp_connections_active").decrement(1.0);
+        }
+    }
+}
+
+/// Counts the connection in [`SmtpShutdown::active_transactions`] from DATA
+/// until the message is handled, or until the handler is dropped when the
+/// client goes away mid-message. Clones start outside a transaction.
+#[derive(Default)]
+struct TransactionSlot(Option<Arc<AtomicUsize>>);
+
+impl TransactionSlot {
+    fn begin(&mut self, shutdown: &SmtpShutdown) {
+        if self.0.is_none() {
+            shutdown.active_transactions.fetch_add(1, Ordering::SeqCst);
+            self.0 = Some(shutdown.active_transactions.clone());
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(active_transactions) = self.0.take() {
+            active_transactions.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+}
+
+impl Clone for TransactionSlot {
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for TransactionSlot {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
 
 #[derive(Clone)]
 pub struct SmtpHandler {
@@ -12,11 +104,15 @@ pub struct SmtpHandler {
     recipients: Vec<String>,
     current_sender: Option<String>,
     client_ip: IpAddr,
+    max_parallel_recipients: usize,
+    shutdown: SmtpShutdown,
+    transaction: TransactionSlot,
+    connection: ConnectionSlot,
     runtime: Arc<Mutex<Runtime>>,
 }
 
 impl SmtpHandler {
-    pub fn new(service: Arc<MailService>) -> Self {
+    pub fn new(service: Arc<MailService>, max_parallel_recipients: usize, shutdown: SmtpShutdown) -> Self {
         let runtime = Runtime::new().expect("Failed to create tokio runtime for SMTP handler");
 
         Self {
@@ -25,6 +121,10 @@ impl SmtpHandler {
             recipients: Vec::new(),
             current_sender: None,
             client_ip: "0.0.0.0".parse().unwrap(),
+            max_parallel_recipients: max_parallel_recipients.max(1),
+            shutdown,
+            transaction: TransactionSlot::default(),
+            connection: ConnectionSlot::default(),
             runtime: Arc::new(Mutex::new(runtime)),
         }
     }
@@ -34,6 +134,17 @@ impl SmtpHandler {
 impl Handler for SmtpHandler {
     fn helo(&mut self, client_ip: IpAddr, _domain: &str) -> Response {
         self.client_ip = client_ip;
+        // Slow clients would otherwise tie up the server's threads
+        if !self.connection.acquire(&self.service) {
+            warn!("Too many concurrent connections, refusing {}", self.client_ip);
+            return Response::custom(421, "Too many concurrent connections".to_string());
+        }
+
+        // Mail relayed by a proxy is checked once its headers name the client
+        if self.service.is_trusted_proxy(self.client_ip) {
+            return Response::custom(250, "OK".to_string());
+        }
+
         // Check if IP is blocked
         if self.service.is_ip_blocked(self.client_ip) {
             warn!("Blocked connection from IP: {}", self.client_ip);
@@ -50,6 +161,10 @@ impl Handler for SmtpHandler {
     }
 
     fn mail(&mut self, _client_ip: IpAddr, from: &str, _parameters: &str) -> Response {
+        if self.shutdown.is_shutting_down() {
+            return Response::custom(421, "Service temporarily unavailable".to_string());
+        }
+
         self.current_mail.clear();
         self.recipients.clear();
         self.current_sender = Some(from.to_string());
@@ -59,6 +174,32 @@ impl Handler for SmtpHandler {
     fn rcpt(&mut self, to: &str) -> Response {
         // Extract email from RCPT TO:<email@domain>
         let email = to.trim_start_matches("TO:<").trim_end_matches('>');
+
+        let Some((_, domain)) = email.split_once('@').filter(|(local, domain)| !local.is_empty() && !domain.is_empty()) else {
+            debug!("Rejecting malformed recipient: {}", Redacted(email));
+            return Response::custom(553, "5.1.3 Bad recipient address syntax".to_string());
+        };
+        if !self.service.handles_domain(domain) {
+            debug!("Rejecting recipient for unhandled domain: {}", domain);
+            return Response::custom(550, "5.7.1 Relaying denied".to_string());
+        }
+
+        let retired = match self.runtime.lock() {
+            Ok(rt) => rt.block_on(self.service.is_retired_address(email)),
+            Err(e) => {
+                error!("Failed to acquire runtime lock for recipient check: {}", e);
+                Ok(false)
+            }
+        };
+        match retired {
+            Ok(true) => {
+                debug!("Rejecting recipient with retired alias: {}", Redacted(email));
+                return Response::custom(550, "5.1.1 Mailbox unavailable".to_string());
+            }
+            Ok(false) => {}
+            Err(e) => error!("Failed to check recipient {}: {}", Redacted(email), e),
+        }
+
         self.recipients.push(email.to_string());
         Response::custom(250, "Recipient OK".to_string())
     }
@@ -70,6 +211,8 @@ impl Handler for SmtpHandler {
         _is_last: bool,
         _accepted: &[String],
     ) -> Response {
+        self.transaction.begin(&self.shutdown);
+
         if self.recipients.is_empty() {
             warn!("Attempted to send email with no valid recipients");
             return Response::custom(354, "Start mail input; end with <CRLF>.<CRLF>".to_string());
@@ -90,38 +233,74 @@ impl Handler for SmtpHandler {
     }
 
     fn data_end(&mut self) -> Response {
+        let response = self.deliver();
+        self.transaction.end();
+        response
+    }
+}
+
+impl SmtpHandler {
+    fn deliver(&mut self) -> Response {
         let mail_data = std::mem::take(&mut self.current_mail);
         let recipients = std::mem::take(&mut self.recipients);
         let service = self.service.clone();
         let sender = self.current_sender.clone().unwrap_or_default();
-        let client_ip = self.client_ip;
+        let client_ip = self.service.resolve_client_ip(self.client_ip, &mail_data);
+        if client_ip != self.client_ip {
+            debug!("Email relayed by {} for {}", self.client_ip, client_ip);
+            if self.service.is_ip_blocked(client_ip) {
+                warn!("Blocked connection from IP: {}", client_ip);
+            }
+        }
+        let max_parallel = self.max_parallel_recipients;
+
+        // A synthetic ID per email ties its logs together like those of an HTTP request
+        let span = tracing::info_span!("email", request_id = %request_id::generate_request_id());
+        let _entered = span.enter();
 
         // Use the shared runtime to process the email
         match self.runtime.lock() {
             Ok(rt) => {
-                // Process emails synchronously
+                // Recipients map to independent mailboxes, so process them concurrently
+                // and only answer once all of them are done
                 let results = rt.block_on(async {
-                    let mut results = Vec::new();
-                    for recipient in recipients {
-                        let result = service
-                            .process_incoming_email(&mail_data, &recipient, &sender, client_ip)
-                            .await;
-                        results.push((recipient, result));
-                    }
-                    results
+                    stream::iter(recipients)
+                        .map(|recipient| {
+                            let service = &service;
+                            let mail_data = &mail_data;
+                            let sender = &sender;
+                            async move {
+                                let result = service
+                                    .process_incoming_email(mail_data, &recipient, sender, client_ip)
+                                    .await;
+                                (recipient, result)
+                            }
+                        })
+                        .buffer_unordered(max_parallel)
+                        .collect::<Vec<_>>()
+                        .await
                 });
 
                 // Log errors but don't expose them to sender
+                let total = results.len();
+                let mut succeeded = 0;
                 for (recipient, result) in results {
                     match result {
                         Ok(_) => {
-                            debug!("Email processed successfully for {}", recipient);
+                            succeeded += 1;
+                            debug!("Email processed successfully for {}", Redacted(&recipient));
                         }
                         Err(e) => {
-                            error!("Failed to process email for {}: {}", recipient, e);
+                            error!("Failed to process email for {}: {}", Redacted(&recipient), e);
                         }
                     }
                 }
+                if succeeded > 0 && succeeded < total {
+                    info!(
+                        "Email from {} delivered to {} of {} recipients",
+                        Redacted(sender), succeeded, total
+                    );
+                }
 
                 // Always return success to sender
                 Response::custom(250, "OK".to_string())
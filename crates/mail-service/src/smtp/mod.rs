@@ -1,2 +1,3 @@
 pub mod handler;
-pub mod server; 
\ No newline at end of file
+pub mod server;
+pub mod starttls;
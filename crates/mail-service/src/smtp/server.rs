@@ -1,14 +1,23 @@
-use crate::{config::Config, service::MailService, smtp::handler::SmtpHandler};
+use crate::{
+    config::Config,
+    service::MailService,
+    smtp::{
+        handler::{SmtpHandler, SmtpShutdown},
+        starttls::{load_tls_config, run_starttls_server},
+    },
+};
 use anyhow::Result;
 use mailin_embedded::{Server, SslConfig};
 use notify::{Config as NotifyConfig, Event, PollWatcher, RecursiveMode, Watcher};
-use std::{net::SocketAddr, sync::Arc, time::Duration};
+use std::{net::{SocketAddr, TcpListener}, sync::{Arc, RwLock}, time::Duration};
 use tokio::{sync::watch, task};
 use tracing::{info, warn};
 
 pub async fn run_smtp_server(
     config: &Config,
     service: Arc<MailService>,
+    shutdown: SmtpShutdown,
+    smtp_bound: watch::Sender<bool>,
 ) -> Result<(), anyhow::Error> {
     // Clone the necessary values from config before moving into the task
     let smtp_bind_addr = config.smtp_bind_addr.clone();
@@ -19,8 +28,14 @@ pub async fn run_smtp_server(
         .zip(config.tls_chain_path.as_ref())
         .map(|((cert, key), chain)| (cert.clone(), key.clone(), chain.clone()));
     let tls_bind_addr = config.smtp_tls_bind_addr.clone();
+    let starttls_bind_addr = config.smtp_starttls_bind_addr.clone();
+    let max_parallel_recipients = config.max_parallel_recipients;
+    let plain_shutdown = shutdown.clone();
+    let tls_shutdown = shutdown.clone();
+    let starttls_shutdown = shutdown;
     let plain_service = Arc::clone(&service);
     let tls_service = Arc::clone(&service);
+    let starttls_service = Arc::clone(&service);
 
     // Set up file watching if TLS is configured
     let (tx, mut rx) = watch::channel(());
@@ -67,18 +82,23 @@ pub async fn run_smtp_server(
             let result = tokio::task::spawn_blocking({
                 let plain_addr = smtp_bind_addr.clone();
                 let service = Arc::clone(&plain_service);
+                let shutdown = plain_shutdown.clone();
+                let smtp_bound = smtp_bound.clone();
                 move || -> Result<(), anyhow::Error> {
-                    let handler = SmtpHandler::new(service);
+                    let handler = SmtpHandler::new(service, max_parallel_recipients, shutdown);
                     let addr: SocketAddr = plain_addr.parse()?;
+                    // Bound here rather than by serve() so readiness is known
+                    let listener = TcpListener::bind(addr)
+                        .map_err(|e| anyhow::anyhow!("Failed to bind plain SMTP server: {}", e))?;
                     let mut server = Server::new(handler);
-                    server
-                        .with_name("plain")
-                        .with_addr(addr)
-                        .map_err(|e| anyhow::anyhow!("Failed to configure plain SMTP server: {}", e))?;
+                    server.with_name("plain").with_tcp_listener(listener);
                     info!("Plain SMTP server listening on {}", addr);
-                    server
+                    smtp_bound.send_replace(true);
+                    let result = server
                         .serve()
-                        .map_err(|e| anyhow::anyhow!("Plain SMTP server error: {}", e))
+                        .map_err(|e| anyhow::anyhow!("Plain SMTP server error: {}", e));
+                    smtp_bound.send_replace(false);
+                    result
                 }
             }).await;
             match result {
@@ -90,6 +110,9 @@ pub async fn run_smtp_server(
                     warn!("Plain SMTP server panicked: {}", e);
                 }
             }
+            if plain_shutdown.is_shutting_down() {
+                break;
+            }
             tokio::time::sleep(std::time::Duration::from_secs(5)).await;
         }
     });
@@ -100,9 +123,10 @@ pub async fn run_smtp_server(
             let result = tokio::task::spawn_blocking({
                 let tls_addr = tls_bind_addr.clone();
                 let service = Arc::clone(&tls_service);
+                let shutdown = tls_shutdown.clone();
                 let tls_config = tls_config.clone();
                 move || -> Result<(), anyhow::Error> {
-                    let handler = SmtpHandler::new(service);
+                    let handler = SmtpHandler::new(service, max_parallel_recipients, shutdown);
                     let addr: SocketAddr = tls_addr.parse()?;
                     let mut server = Server::new(handler);
                     server
@@ -132,6 +156,9 @@ pub async fn run_smtp_server(
                     warn!("TLS SMTP server panicked: {}", e);
                 }
             }
+            if tls_shutdown.is_shutting_down() {
+                break;
+            }
             let changed = tokio::time::timeout(std::time::Duration::from_secs(5), rx.changed()).await;
             if changed.is_ok() {
                 info!("TLS configuration changed, restarting TLS SMTP server");
@@ -140,9 +167,45 @@ pub async fn run_smtp_server(
         }
     }));
 
+    // Spawn the STARTTLS SMTP server task, which swaps its certificate in place on changes
+    let starttls_server_task = match tls_config {
+        Some((cert_path, key_path, chain_path)) => {
+            let shared_config = Arc::new(RwLock::new(load_tls_config(&cert_path, &key_path, &chain_path)?));
+            let mut changes = tx.subscribe();
+            let reload_config = Arc::clone(&shared_config);
+            task::spawn(async move {
+                while changes.changed().await.is_ok() {
+                    match load_tls_config(&cert_path, &key_path, &chain_path) {
+                        Ok(config) => {
+                            info!("TLS configuration changed, reloading STARTTLS certificate");
+                            if let Ok(mut current) = reload_config.write() {
+                                *current = config;
+                            }
+                        }
+                        Err(e) => warn!("Failed to reload STARTTLS certificate: {}", e),
+                    }
+                }
+            });
+
+            let addr: SocketAddr = starttls_bind_addr.parse()?;
+            let listener = tokio::net::TcpListener::bind(addr)
+                .await
+                .map_err(|e| anyhow::anyhow!("Failed to bind STARTTLS SMTP server: {}", e))?;
+            Some(tokio::spawn(run_starttls_server(
+                listener,
+                starttls_service,
+                max_parallel_recipients,
+                starttls_shutdown,
+                shared_config,
+            )))
+        }
+        None => None,
+    };
+
     // Wait for the plain server and, if applicable, the TLS server tasks concurrently
-    if let Some(tls_task) = tls_server_task {
-        let _ = tokio::try_join!(plain_server_task, tls_task)?;
+    if let Some((tls_task, starttls_task)) = tls_server_task.zip(starttls_server_task) {
+        let (_, _, starttls_result) = tokio::try_join!(plain_server_task, tls_task, starttls_task)?;
+        starttls_result?;
     } else {
         plain_server_task.await?;
     }
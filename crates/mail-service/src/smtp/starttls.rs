@@ -0,0 +1,263 @@
+//! SMTP listener upgrading connections with STARTTLS (RFC 3207), served with
+//! tokio-rustls. The SMTP conversation is driven by the same `SmtpHandler` as
+//! the other listeners, on a blocking thread per connection since the handler
+//! blocks on its own runtime.
+
+use crate::{
+    service::MailService,
+    smtp::handler::{SmtpHandler, SmtpShutdown},
+};
+use anyhow::{anyhow, Context, Result};
+use mailin::{Action, Response, Session, SessionBuilder};
+use std::{
+    fs::File,
+    io::BufReader,
+    net::IpAddr,
+    path::Path,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufStream},
+    net::TcpListener,
+    sync::mpsc,
+};
+use tokio_rustls::{
+    rustls::{Certificate, PrivateKey, ServerConfig},
+    TlsAcceptor,
+};
+use tracing::{debug, info, warn};
+
+/// Idle time after which a connection is dropped, as in mailin-embedded
+const READ_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+/// Longest line accepted, CRLF included (RFC 5321 section 4.5.3.1.6)
+const MAX_LINE_LENGTH: usize = 1000;
+
+/// The TLS configuration new connections are upgraded with, swapped when the
+/// certificate files change
+pub type SharedTlsConfig = Arc<RwLock<Arc<ServerConfig>>>;
+
+/// Reads the certificate, followed by the chain, and the private key from PEM files
+pub fn load_tls_config(cert_path: &Path, key_path: &Path, chain_path: &Path) -> Result<Arc<ServerConfig>> {
+    let mut certs = Vec::new();
+    for path in [cert_path, chain_path] {
+        let file = File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        certs.extend(
+            rustls_pemfile::certs(&mut BufReader::new(file))
+                .with_context(|| format!("Failed to read certificates from {}", path.display()))?
+                .into_iter()
+                .map(Certificate),
+        );
+    }
+
+    let file = File::open(key_path).with_context(|| format!("Failed to open {}", key_path.display()))?;
+    let mut reader = BufReader::new(file);
+    let key = loop {
+        match rustls_pemfile::read_one(&mut reader)? {
+            Some(
+                rustls_pemfile::Item::PKCS8Key(key)
+                | rustls_pemfile::Item::RSAKey(key)
+                | rustls_pemfile::Item::ECKey(key),
+            ) => break PrivateKey(key),
+            Some(_) => continue,
+            None => return Err(anyhow!("No private key found in {}", key_path.display())),
+        }
+    };
+
+    let config = ServerConfig::builder()
+        .with_safe_defaults()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .context("Invalid TLS certificate or key")?;
+    Ok(Arc::new(config))
+}
+
+enum SessionInput {
+    Line(Vec<u8>),
+    TlsActive,
+}
+
+/// Runs a session on a blocking thread, one line at a time
+struct SessionWorker {
+    input: mpsc::Sender<SessionInput>,
+    output: mpsc::Receiver<Response>,
+}
+
+impl SessionWorker {
+    fn start(mut session: Session<SmtpHandler>) -> Self {
+        let (input, mut input_rx) = mpsc::channel(1);
+        let (output_tx, output) = mpsc::channel(1);
+        tokio::task::spawn_blocking(move || {
+            while let Some(input) = input_rx.blocking_recv() {
+                let response = match input {
+                    SessionInput::Line(line) => session.process(&line),
+                    SessionInput::TlsActive => {
+                        session.tls_active();
+                        continue;
+                    }
+                };
+                if output_tx.blocking_send(response).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { input, output }
+    }
+
+    async fn process(&mut self, line: Vec<u8>) -> Result<Response> {
+        self.input.send(SessionInput::Line(line)).await.map_err(|_| anyhow!("SMTP session ended"))?;
+        self.output.recv().await.ok_or_else(|| anyhow!("SMTP session ended"))
+    }
+
+    async fn tls_active(&self) -> Result<()> {
+        self.input.send(SessionInput::TlsActive).await.map_err(|_| anyhow!("SMTP session ended"))
+    }
+}
+
+/// Holds the handler sessions are cloned from. Dropping the handler's runtime
+/// on an async worker panics, so it is released on its own thread instead.
+struct HandlerTemplate(Option<SmtpHandler>);
+
+impl HandlerTemplate {
+    fn session_handler(&self) -> SmtpHandler {
+        self.0.clone().expect("the handler is only taken on drop")
+    }
+}
+
+impl Drop for HandlerTemplate {
+    fn drop(&mut self) {
+        if let Some(handler) = self.0.take() {
+            std::thread::spawn(move || drop(handler));
+        }
+    }
+}
+
+async fn write_response<S: AsyncWrite + Unpin>(stream: &mut S, response: &Response) -> Result<()> {
+    stream.write_all(&response.buffer()?).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+/// Discards input up to the next line feed without buffering it
+async fn skip_line<S: AsyncRead + AsyncWrite + Unpin>(stream: &mut BufStream<S>) -> Result<()> {
+    loop {
+        let buf = tokio::time::timeout(READ_TIMEOUT, stream.fill_buf())
+            .await
+            .map_err(|_| anyhow!("Timed out waiting for the client"))??;
+        if buf.is_empty() {
+            return Err(anyhow!("Unexpected EOF"));
+        }
+        match buf.iter().position(|&byte| byte == b'\n') {
+            Some(end) => {
+                stream.consume(end + 1);
+                return Ok(());
+            }
+            None => {
+                let len = buf.len();
+                stream.consume(len);
+            }
+        }
+    }
+}
+
+/// Answers lines until the session ends, returning whether the client asked
+/// for a TLS upgrade. A line too long to be SMTP ends the session, as dropping
+/// it from a message in progress would corrupt the message.
+async fn serve_session<S>(stream: &mut BufStream<S>, worker: &mut SessionWorker) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut line = Vec::with_capacity(80);
+    loop {
+        line.clear();
+        let read = tokio::time::timeout(
+            READ_TIMEOUT,
+            (&mut *stream).take(MAX_LINE_LENGTH as u64).read_until(b'\n', &mut line),
+        )
+        .await
+        .map_err(|_| anyhow!("Timed out waiting for the client"))??;
+        if read == 0 {
+            return Err(anyhow!("Unexpected EOF"));
+        }
+        if read == MAX_LINE_LENGTH && !line.ends_with(b"\n") {
+            // Read to the end of the line so the reply isn't lost to a reset
+            skip_line(stream).await?;
+            stream.write_all(b"500 Line too long\r\n").await?;
+            stream.flush().await?;
+            return Err(anyhow!("Line too long"));
+        }
+
+        let response = worker.process(line.clone()).await?;
+        match response.action {
+            Action::Reply => write_response(stream, &response).await?,
+            Action::Close => {
+                write_response(stream, &response).await?;
+                return Ok(false);
+            }
+            Action::UpgradeTls => {
+                write_response(stream, &response).await?;
+                return Ok(true);
+            }
+            Action::NoReply => {}
+        }
+    }
+}
+
+async fn handle_connection(
+    stream: tokio::net::TcpStream,
+    remote: IpAddr,
+    session_builder: Arc<SessionBuilder>,
+    handler: SmtpHandler,
+    tls_config: Arc<ServerConfig>,
+) -> Result<()> {
+    let session = session_builder.build(remote, handler);
+    let greeting = session.greeting();
+    let mut worker = SessionWorker::start(session);
+
+    let mut stream = BufStream::new(stream);
+    write_response(&mut stream, &greeting).await?;
+    if !serve_session(&mut stream, &mut worker).await? {
+        return Ok(());
+    }
+
+    // Anything the client sent after STARTTLS before the handshake is
+    // dropped with the buffer, so it can't be injected into the TLS session
+    let tls_stream = TlsAcceptor::from(tls_config).accept(stream.into_inner()).await?;
+    worker.tls_active().await?;
+    serve_session(&mut BufStream::new(tls_stream), &mut worker).await?;
+    Ok(())
+}
+
+/// Accepts connections until the listener fails
+pub async fn run_starttls_server(
+    listener: TcpListener,
+    service: Arc<MailService>,
+    max_parallel_recipients: usize,
+    shutdown: SmtpShutdown,
+    tls_config: SharedTlsConfig,
+) -> Result<()> {
+    let mut session_builder = SessionBuilder::new("starttls");
+    session_builder.enable_start_tls();
+    let session_builder = Arc::new(session_builder);
+    let handler = HandlerTemplate(Some(SmtpHandler::new(service, max_parallel_recipients, shutdown)));
+    info!("STARTTLS SMTP server listening on {}", listener.local_addr()?);
+
+    loop {
+        let (stream, remote) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Failed to accept STARTTLS SMTP connection: {}", e);
+                continue;
+            }
+        };
+        let tls_config = tls_config.read().map_err(|_| anyhow!("TLS configuration lock poisoned"))?.clone();
+        let session_builder = Arc::clone(&session_builder);
+        let handler = handler.session_handler();
+        tokio::spawn(async move {
+            debug!("New STARTTLS connection from {}", remote.ip());
+            if let Err(e) = handle_connection(stream, remote.ip(), session_builder, handler, tls_config).await {
+                debug!("STARTTLS session with {} ended: {}", remote.ip(), e);
+            }
+        });
+    }
+}
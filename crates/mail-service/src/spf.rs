@@ -0,0 +1,368 @@
+use crate::dns::DnsResolver;
+use futures_util::future::{BoxFuture, FutureExt};
+use ipnetwork::IpNetwork;
+use std::net::IpAddr;
+
+/// RFC 7208 limit on mechanisms and modifiers that cause DNS lookups
+const MAX_DNS_LOOKUPS: usize = 10;
+/// RFC 7208 limit on the MX hosts looked up by a single `mx` mechanism
+const MAX_MX_HOSTS: usize = 10;
+
+/// Outcome of an SPF check, as defined by RFC 7208 section 2.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpfResult {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+    /// The domain publishes no SPF record
+    None,
+    TempError,
+    PermError,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Qualifier {
+    Pass,
+    Fail,
+    SoftFail,
+    Neutral,
+}
+
+impl Qualifier {
+    fn result(self) -> SpfResult {
+        match self {
+            Qualifier::Pass => SpfResult::Pass,
+            Qualifier::Fail => SpfResult::Fail,
+            Qualifier::SoftFail => SpfResult::SoftFail,
+            Qualifier::Neutral => SpfResult::Neutral,
+        }
+    }
+}
+
+/// Checks whether `ip` may send mail for the domain of the MAIL FROM
+/// address. The null sender has no domain to check and yields `None`.
+pub async fn check_sender(resolver: &dyn DnsResolver, ip: IpAddr, sender: &str) -> SpfResult {
+    let Some((_, domain)) = sender.rsplit_once('@') else {
+        return SpfResult::None;
+    };
+    if domain.is_empty() {
+        return SpfResult::None;
+    }
+
+    let mut evaluator = Evaluator { resolver, ip, lookups: 0 };
+    evaluator.check_host(domain.trim_end_matches('.').to_ascii_lowercase()).await
+}
+
+struct Evaluator<'a> {
+    resolver: &'a dyn DnsResolver,
+    ip: IpAddr,
+    lookups: usize,
+}
+
+impl<'a> Evaluator<'a> {
+    fn check_host(&mut self, domain: String) -> BoxFuture<'_, SpfResult> {
+        async move {
+            let records = match self.resolver.txt_lookup(&domain).await {
+                Ok(records) => records,
+                Err(_) => return SpfResult::TempError,
+            };
+            let mut spf_records = records.iter().filter(|record| is_spf_record(record));
+            let Some(record) = spf_records.next() else {
+                return SpfResult::None;
+            };
+            if spf_records.next().is_some() {
+                return SpfResult::PermError;
+            }
+
+            self.evaluate(&domain, record).await
+        }
+        .boxed()
+    }
+
+    async fn evaluate(&mut self, domain: &str, record: &str) -> SpfResult {
+        let mut redirect = None;
+
+        for term in record.split_ascii_whitespace().skip(1) {
+            if let Some((name, value)) = term.split_once('=') {
+                // Unknown modifiers, and `exp` since no explanation is sent, are ignored
+                if name.eq_ignore_ascii_case("redirect") {
+                    if redirect.is_some() {
+                        return SpfResult::PermError;
+                    }
+                    redirect = Some(value.to_string());
+                }
+                continue;
+            }
+
+            let (qualifier, mechanism) = match term.as_bytes()[0] {
+                b'+' => (Qualifier::Pass, &term[1..]),
+                b'-' => (Qualifier::Fail, &term[1..]),
+                b'~' => (Qualifier::SoftFail, &term[1..]),
+                b'?' => (Qualifier::Neutral, &term[1..]),
+                _ => (Qualifier::Pass, term),
+            };
+
+            match self.matches(domain, mechanism).await {
+                Ok(true) => return qualifier.result(),
+                Ok(false) => {}
+                Err(result) => return result,
+            }
+        }
+
+        match redirect {
+            Some(target) => {
+                if !self.count_lookup() || !is_valid_domain(&target) {
+                    return SpfResult::PermError;
+                }
+                match self.check_host(target.to_ascii_lowercase()).await {
+                    SpfResult::None => SpfResult::PermError,
+                    result => result,
+                }
+            }
+            None => SpfResult::Neutral,
+        }
+    }
+
+    /// Whether the mechanism matches the client IP, or the result to end
+    /// the evaluation with
+    async fn matches(&mut self, domain: &str, mechanism: &str) -> Result<bool, SpfResult> {
+        let (name, argument) = match mechanism.find([':', '/']) {
+            Some(index) => (&mechanism[..index], &mechanism[index..]),
+            None => (mechanism, ""),
+        };
+
+        match name.to_ascii_lowercase().as_str() {
+            "all" if argument.is_empty() => Ok(true),
+            "ip4" | "ip6" => {
+                let network: IpNetwork = argument
+                    .strip_prefix(':')
+                    .and_then(|network| network.parse().ok())
+                    .ok_or(SpfResult::PermError)?;
+                if (name.eq_ignore_ascii_case("ip4")) != network.is_ipv4() {
+                    return Err(SpfResult::PermError);
+                }
+                Ok(network.contains(self.ip))
+            }
+            "a" | "mx" => {
+                let (target, prefix_v4, prefix_v6) = parse_domain_and_cidr(argument, domain)?;
+                if !self.count_lookup() {
+                    return Err(SpfResult::PermError);
+                }
+
+                let hosts = if name.eq_ignore_ascii_case("mx") {
+                    let mut hosts = self.resolver.mx_lookup(&target).await.map_err(|_| SpfResult::TempError)?;
+                    if hosts.len() > MAX_MX_HOSTS {
+                        return Err(SpfResult::PermError);
+                    }
+                    for host in &mut hosts {
+                        *host = host.trim_end_matches('.').to_string();
+                    }
+                    hosts
+                } else {
+                    vec![target]
+                };
+
+                for host in hosts {
+                    let addresses = self.resolver.ip_lookup(&host).await.map_err(|_| SpfResult::TempError)?;
+                    if addresses.into_iter().any(|address| in_network(self.ip, address, prefix_v4, prefix_v6)) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }
+            "include" => {
+                let target = argument.strip_prefix(':')
+                    .filter(|target| is_valid_domain(target))
+                    .ok_or(SpfResult::PermError)?;
+                if !self.count_lookup() {
+                    return Err(SpfResult::PermError);
+                }
+                match self.check_host(target.to_ascii_lowercase()).await {
+                    SpfResult::Pass => Ok(true),
+                    SpfResult::Fail | SpfResult::SoftFail | SpfResult::Neutral => Ok(false),
+                    SpfResult::TempError => Err(SpfResult::TempError),
+                    SpfResult::None | SpfResult::PermError => Err(SpfResult::PermError),
+                }
+            }
+            // `ptr` and `exists` are valid but not supported, so the result
+            // is left undecided rather than risking a wrong fail
+            _ => Err(SpfResult::PermError),
+        }
+    }
+
+    fn count_lookup(&mut self) -> bool {
+        self.lookups += 1;
+        self.lookups <= MAX_DNS_LOOKUPS
+    }
+}
+
+fn is_spf_record(record: &str) -> bool {
+    let record = record.trim_start();
+    record.len() >= 6
+        && record[..6].eq_ignore_ascii_case("v=spf1")
+        && record[6..].chars().next().is_none_or(|c| c == ' ')
+}
+
+/// Macros are not supported, so domains containing them are rejected
+fn is_valid_domain(domain: &str) -> bool {
+    !domain.is_empty() && !domain.contains('%')
+}
+
+/// Splits the `[:domain][/prefix-v4][//prefix-v6]` argument of `a` and `mx`
+fn parse_domain_and_cidr(argument: &str, current_domain: &str) -> Result<(String, u8, u8), SpfResult> {
+    let (target, cidr) = match argument.strip_prefix(':') {
+        Some(rest) => match rest.find('/') {
+            Some(index) => (&rest[..index], &rest[index..]),
+            None => (rest, ""),
+        },
+        None => (current_domain, argument),
+    };
+    if !is_valid_domain(target) {
+        return Err(SpfResult::PermError);
+    }
+
+    let (prefix_v4, prefix_v6) = match cidr.split_once("//") {
+        Some((v4, v6)) => (v4, Some(v6)),
+        None => (cidr, None),
+    };
+    let parse_prefix = |prefix: &str, max: u8| -> Result<u8, SpfResult> {
+        prefix.parse().ok().filter(|&prefix| prefix <= max).ok_or(SpfResult::PermError)
+    };
+    let prefix_v4 = match prefix_v4.strip_prefix('/') {
+        Some(prefix) => parse_prefix(prefix, 32)?,
+        None if prefix_v4.is_empty() => 32,
+        None => return Err(SpfResult::PermError),
+    };
+    let prefix_v6 = match prefix_v6 {
+        Some(prefix) => parse_prefix(prefix, 128)?,
+        None => 128,
+    };
+
+    Ok((target.to_ascii_lowercase(), prefix_v4, prefix_v6))
+}
+
+fn in_network(ip: IpAddr, address: IpAddr, prefix_v4: u8, prefix_v6: u8) -> bool {
+    let prefix = if address.is_ipv4() { prefix_v4 } else { prefix_v6 };
+    IpNetwork::new(address, prefix).is_ok_and(|network| network.contains(ip))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dns::MockDnsResolver;
+
+    fn resolver() -> MockDnsResolver {
+        MockDnsResolver::new(vec!["mail.example.com.".to_string()])
+    }
+
+    async fn check(resolver: &MockDnsResolver, ip: &str) -> SpfResult {
+        check_sender(resolver, ip.parse().unwrap(), "alice@example.com").await
+    }
+
+    #[tokio::test]
+    async fn test_ip4_pass() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 ip4:192.0.2.0/24 -all");
+        assert_eq!(check(&resolver, "192.0.2.10").await, SpfResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_ip4_fail() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 ip4:192.0.2.0/24 -all");
+        assert_eq!(check(&resolver, "198.51.100.1").await, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_ip6() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 ip6:2001:db8::/32 -all");
+        assert_eq!(check(&resolver, "2001:db8::1").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "2001:db9::1").await, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_softfail() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 ip4:192.0.2.1 ~all");
+        assert_eq!(check(&resolver, "198.51.100.1").await, SpfResult::SoftFail);
+    }
+
+    #[tokio::test]
+    async fn test_neutral() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 ip4:192.0.2.1 ?all");
+        assert_eq!(check(&resolver, "198.51.100.1").await, SpfResult::Neutral);
+
+        // No matching mechanism and no redirect is neutral too
+        let resolver = MockDnsResolver::new(vec![]).with_txt("example.com", "v=spf1 ip4:192.0.2.1");
+        assert_eq!(check(&resolver, "198.51.100.1").await, SpfResult::Neutral);
+    }
+
+    #[tokio::test]
+    async fn test_plus_all() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 +all");
+        assert_eq!(check(&resolver, "203.0.113.7").await, SpfResult::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_missing_record() {
+        let resolver = resolver().with_txt("example.com", "google-site-verification=abc");
+        assert_eq!(check(&resolver, "192.0.2.1").await, SpfResult::None);
+        assert_eq!(check(&MockDnsResolver::new(vec![]), "192.0.2.1").await, SpfResult::None);
+    }
+
+    #[tokio::test]
+    async fn test_null_sender() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 -all");
+        assert_eq!(check_sender(&resolver, "192.0.2.1".parse().unwrap(), "").await, SpfResult::None);
+    }
+
+    #[tokio::test]
+    async fn test_include() {
+        let resolver = resolver()
+            .with_txt("example.com", "v=spf1 include:_spf.provider.net -all")
+            .with_txt("_spf.provider.net", "v=spf1 ip4:198.51.100.0/24 -all");
+        assert_eq!(check(&resolver, "198.51.100.20").await, SpfResult::Pass);
+        // A fail of the included record only means it did not match
+        assert_eq!(check(&resolver, "203.0.113.1").await, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_include_without_record_is_permerror() {
+        let resolver = resolver().with_txt("example.com", "v=spf1 include:missing.example -all");
+        assert_eq!(check(&resolver, "192.0.2.1").await, SpfResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn test_redirect() {
+        let resolver = resolver()
+            .with_txt("example.com", "v=spf1 redirect=_spf.example.net")
+            .with_txt("_spf.example.net", "v=spf1 ip4:192.0.2.5 -all");
+        assert_eq!(check(&resolver, "192.0.2.5").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "192.0.2.6").await, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_a_and_mx() {
+        let resolver = resolver()
+            .with_txt("example.com", "v=spf1 a mx/24 -all")
+            .with_ip("example.com", "192.0.2.1".parse().unwrap())
+            .with_ip("mail.example.com", "198.51.100.1".parse().unwrap());
+        assert_eq!(check(&resolver, "192.0.2.1").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "198.51.100.99").await, SpfResult::Pass);
+        assert_eq!(check(&resolver, "192.0.2.2").await, SpfResult::Fail);
+    }
+
+    #[tokio::test]
+    async fn test_multiple_records_is_permerror() {
+        let resolver = resolver()
+            .with_txt("example.com", "v=spf1 -all")
+            .with_txt("example.com", "v=spf1 +all");
+        assert_eq!(check(&resolver, "192.0.2.1").await, SpfResult::PermError);
+    }
+
+    #[tokio::test]
+    async fn test_lookup_limit() {
+        // Every include points back at the same record
+        let resolver = resolver().with_txt("example.com", "v=spf1 include:loop.example -all")
+            .with_txt("loop.example", "v=spf1 include:loop.example -all");
+        assert_eq!(check(&resolver, "192.0.2.1").await, SpfResult::PermError);
+    }
+}
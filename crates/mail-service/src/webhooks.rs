@@ -0,0 +1,477 @@
+//! Delivery of mailbox webhooks. Payloads are POSTed as JSON with an
+//! `X-Signature: sha256=<hex>` header, the HMAC-SHA256 of the body keyed
+//! with the webhook secret (`sha512=<hex>` for webhooks signing with
+//! HMAC-SHA512), and retried with exponential backoff. Webhooks with a
+//! payload template are sent that template rendered instead, signed the same
+//! way. Emails
+//! forwarded by a rule are POSTed the same way, unsigned. Only public
+//! addresses are contacted, redirects are not followed, and hosts that keep
+//! failing are skipped for a while by a circuit breaker.
+
+use common::{circuit_breaker::CircuitBreaker, db::Database, outbound, AppError, Email, ForwardingRule, SignatureAlgorithm, Webhook};
+use hmac::{digest::KeyInit, Hmac, Mac};
+use serde::Serialize;
+use sha2::{Sha256, Sha512};
+use std::{error::Error, net::IpAddr, sync::Arc, time::Duration};
+use tracing::{debug, error, warn};
+
+pub const SIGNATURE_HEADER: &str = "X-Signature";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const MAX_RETRIES: u32 = 3;
+const INITIAL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Serialize)]
+struct WebhookPayload<'a> {
+    event: &'a str,
+    email_id: &'a str,
+    mailbox_id: &'a str,
+    received_at: i64,
+}
+
+/// The variables of payload templates: the default payload's fields, the
+/// address of the sending client and the plaintext headers of the email
+#[derive(Debug, Serialize)]
+pub struct PayloadContext {
+    event: &'static str,
+    email_id: String,
+    mailbox_id: String,
+    received_at: i64,
+    sender_ip: String,
+    subject: String,
+    from: String,
+}
+
+impl PayloadContext {
+    fn email_received(email: &Email, sender_ip: IpAddr) -> Self {
+        Self {
+            event: Webhook::EMAIL_RECEIVED,
+            email_id: email.id.clone(),
+            mailbox_id: email.mailbox_id.clone(),
+            received_at: email.received_at,
+            sender_ip: sender_ip.to_string(),
+            subject: email.subject.clone(),
+            from: email.from_addr.clone(),
+        }
+    }
+
+    /// Made-up values for checking and previewing templates
+    pub fn sample(mailbox_id: &str) -> Self {
+        Self {
+            event: Webhook::EMAIL_RECEIVED,
+            email_id: "00000000-0000-0000-0000-000000000000".to_string(),
+            mailbox_id: mailbox_id.to_string(),
+            received_at: chrono::Utc::now().timestamp(),
+            sender_ip: "192.0.2.1".to_string(),
+            subject: "Sample subject".to_string(),
+            from: "sender@example.com".to_string(),
+        }
+    }
+
+    /// The default JSON payload, or the template rendered with this context
+    pub fn render(&self, template: Option<&str>) -> Result<Vec<u8>, AppError> {
+        let Some(template) = template else {
+            return Ok(serde_json::to_vec(&WebhookPayload {
+                event: self.event,
+                email_id: &self.email_id,
+                mailbox_id: &self.mailbox_id,
+                received_at: self.received_at,
+            })
+            .expect("Webhook payload is serializable"));
+        };
+        let context = tera::Context::from_serialize(self).expect("Payload context is serializable");
+        tera::Tera::one_off(template, &context, false)
+            .map(String::into_bytes)
+            .map_err(|e| {
+                // The causes say what is wrong, the error itself only that rendering failed
+                let mut message = String::new();
+                let mut cause = e.source();
+                while let Some(error) = cause {
+                    if !message.is_empty() {
+                        message.push_str(": ");
+                    }
+                    message.push_str(&error.to_string());
+                    cause = error.source();
+                }
+                AppError::Mail(format!("Invalid payload template: {}", if message.is_empty() { e.to_string() } else { message }))
+            })
+    }
+}
+
+/// Sent by [`WebhookSender::send_test`] so owners can check their endpoint
+#[derive(Debug, Serialize)]
+struct WebhookTestPayload<'a> {
+    event: &'a str,
+    webhook_id: &'a str,
+    mailbox_id: &'a str,
+    sent_at: i64,
+}
+
+/// The `X-Signature` value of a payload, prefixed with the algorithm's name
+pub fn sign(algorithm: SignatureAlgorithm, secret: &str, body: &[u8]) -> String {
+    match algorithm {
+        SignatureAlgorithm::Sha256 => format!("sha256={}", hmac_hex::<Hmac<Sha256>>(secret, body)),
+        SignatureAlgorithm::Sha512 => format!("sha512={}", hmac_hex::<Hmac<Sha512>>(secret, body)),
+    }
+}
+
+fn hmac_hex<M: Mac + KeyInit>(secret: &str, body: &[u8]) -> String {
+    let mut mac = <M as KeyInit>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+#[derive(Clone)]
+pub struct WebhookSender {
+    client: reqwest::Client,
+    retry_delay: Duration,
+    allow_private_addresses: bool,
+    circuit_breaker: Arc<CircuitBreaker>,
+}
+
+impl Default for WebhookSender {
+    fn default() -> Self {
+        Self {
+            client: outbound::client_builder()
+                .timeout(REQUEST_TIMEOUT)
+                .build()
+                .expect("Failed to build webhook HTTP client"),
+            retry_delay: INITIAL_RETRY_DELAY,
+            allow_private_addresses: false,
+            circuit_breaker: Arc::new(CircuitBreaker::default()),
+        }
+    }
+}
+
+impl WebhookSender {
+    /// Circuit breaker shared by all deliveries, one circuit per host
+    pub fn with_circuit_breaker(mut self, circuit_breaker: CircuitBreaker) -> Self {
+        self.circuit_breaker = Arc::new(circuit_breaker);
+        self
+    }
+
+    /// Also delivers to loopback and private addresses, for local endpoints
+    /// in tests
+    pub fn allowing_private_addresses(mut self) -> Self {
+        self.client = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()
+            .expect("Failed to build webhook HTTP client");
+        self.allow_private_addresses = true;
+        self
+    }
+
+    /// Delay before the first retry, doubled for each further one
+    pub fn with_retry_delay(mut self, retry_delay: Duration) -> Self {
+        self.retry_delay = retry_delay;
+        self
+    }
+
+    /// Notifies the mailbox's webhooks of a saved email in the background
+    pub fn email_received(&self, db: Arc<dyn Database>, email: &Email, sender_ip: IpAddr) {
+        let sender = self.clone();
+        let context = PayloadContext::email_received(email, sender_ip);
+        tokio::spawn(async move {
+            let webhooks = match db.get_mailbox_webhooks(&context.mailbox_id).await {
+                Ok(webhooks) => webhooks,
+                Err(e) => {
+                    error!("Failed to load webhooks of mailbox {}: {}", context.mailbox_id, e);
+                    return;
+                }
+            };
+
+            for webhook in webhooks.into_iter().filter(|webhook| webhook.subscribes_to(Webhook::EMAIL_RECEIVED)) {
+                // Templates are checked when saved, so this only fails on unusual emails
+                let body = match context.render(webhook.payload_template.as_deref()) {
+                    Ok(body) => body,
+                    Err(e) => {
+                        error!("Failed to render payload of webhook {}: {}", webhook.id, e);
+                        continue;
+                    }
+                };
+                let sender = sender.clone();
+                let db = db.clone();
+                tokio::spawn(async move { sender.deliver(db.as_ref(), &webhook, body).await });
+            }
+        });
+    }
+
+    /// POSTs a saved email, still encrypted, to the URL of a forwarding rule
+    /// in the background, retried like webhook deliveries
+    pub fn forward_email(&self, rule: &ForwardingRule, email: &Email) {
+        let sender = self.clone();
+        let rule_id = rule.id.clone();
+        let url = rule.destination.clone();
+        let body = serde_json::to_vec(email).expect("Email is serializable");
+        tokio::spawn(async move {
+            let mut delay = sender.retry_delay;
+            for attempt in 0..=MAX_RETRIES {
+                if attempt > 0 {
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                if sender.post(&url, None, &body).await.is_some_and(is_success) {
+                    debug!("Email forwarded by rule {}", rule_id);
+                    return;
+                }
+            }
+            warn!("Giving up on forwarding rule {} after {} retries", rule_id, MAX_RETRIES);
+        });
+    }
+
+    /// POSTs a signed `webhook.test` payload once, without retrying or
+    /// recording the status, and returns the response status
+    pub async fn send_test(&self, webhook: &Webhook) -> Option<i64> {
+        let body = serde_json::to_vec(&WebhookTestPayload {
+            event: Webhook::TEST,
+            webhook_id: &webhook.id,
+            mailbox_id: &webhook.mailbox_id,
+            sent_at: chrono::Utc::now().timestamp(),
+        })
+        .expect("Webhook payload is serializable");
+        let signature = sign(webhook.signature_algorithm, &webhook.secret, &body);
+        self.post(&webhook.url, Some(&signature), &body).await
+    }
+
+    /// The response status, `None` when no response was received
+    async fn post(&self, url: &str, signature: Option<&str>, body: &[u8]) -> Option<i64> {
+        // The URL was checked when saved, but names may since resolve elsewhere
+        if !self.allow_private_addresses {
+            let checked = match reqwest::Url::parse(url) {
+                Ok(parsed) => outbound::check_url(&parsed).await,
+                Err(e) => Err(common::AppError::Mail(e.to_string())),
+            };
+            if let Err(e) = checked {
+                warn!("Refusing to POST to {}: {}", url, e);
+                return None;
+            }
+        }
+        let mut request = self
+            .client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body.to_vec());
+        if let Some(signature) = signature {
+            request = request.header(SIGNATURE_HEADER, signature);
+        }
+        match self.circuit_breaker.send(request).await {
+            Ok(response) => Some(i64::from(response.status().as_u16())),
+            Err(e) => {
+                warn!("POST to {} failed: {}", url, e);
+                None
+            }
+        }
+    }
+
+    /// Sends until a 2xx response or the retries run out, recording the
+    /// status of every attempt
+    async fn deliver(&self, db: &dyn Database, webhook: &Webhook, body: Vec<u8>) {
+        let signature = sign(webhook.signature_algorithm, &webhook.secret, &body);
+        let mut delay = self.retry_delay;
+
+        for attempt in 0..=MAX_RETRIES {
+            if attempt > 0 {
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+
+            let status = self.post(&webhook.url, Some(&signature), &body).await;
+            if let Err(e) = db.update_webhook_status(&webhook.id, chrono::Utc::now().timestamp(), status).await {
+                error!("Failed to record status of webhook {}: {}", webhook.id, e);
+            }
+
+            if status.is_some_and(is_success) {
+                debug!("Webhook {} delivered", webhook.id);
+                return;
+            }
+        }
+        warn!("Giving up on webhook {} after {} retries", webhook.id, MAX_RETRIES);
+    }
+}
+
+fn is_success(status: i64) -> bool {
+    (200..300).contains(&status)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use common::{db::SqliteDatabase, AuthType, Mailbox};
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct Received {
+        attempts: usize,
+        signatures: Vec<String>,
+        bodies: Vec<Vec<u8>>,
+    }
+
+    /// Fails the first two attempts
+    async fn flaky_endpoint(State(received): State<Arc<Mutex<Received>>>, headers: HeaderMap, body: axum::body::Bytes) -> StatusCode {
+        let mut received = received.lock().unwrap();
+        received.attempts += 1;
+        received.signatures.push(headers[SIGNATURE_HEADER].to_str().unwrap().to_string());
+        received.bodies.push(body.to_vec());
+        if received.attempts <= 2 {
+            StatusCode::INTERNAL_SERVER_ERROR
+        } else {
+            StatusCode::NO_CONTENT
+        }
+    }
+
+    #[tokio::test]
+    async fn test_delivery_is_signed_and_retried() {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(flaky_endpoint)).with_state(received.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let db = SqliteDatabase::new_in_memory().await.unwrap();
+        db.init().await.unwrap();
+        let db: Arc<dyn Database> = Arc::new(db);
+        let user = db.create_user("webhook-user", AuthType::Password).await.unwrap();
+        let mailbox = Mailbox::new(&user.id, "example.com", None);
+        db.create_mailbox(&mailbox).await.unwrap();
+        let webhook = Webhook {
+            id: "webhook-1".to_string(),
+            mailbox_id: mailbox.id.clone(),
+            url,
+            secret: "webhook-secret".to_string(),
+            events: vec![Webhook::EMAIL_RECEIVED.to_string()],
+            signature_algorithm: SignatureAlgorithm::Sha256,
+            payload_template: None,
+            created_at: 0,
+            last_triggered_at: None,
+            last_status: None,
+        };
+        db.create_webhook(&webhook).await.unwrap();
+
+        let sender = WebhookSender::default()
+            .allowing_private_addresses()
+            .with_retry_delay(Duration::from_millis(10));
+        sender.deliver(db.as_ref(), &webhook, br#"{"event":"email.received"}"#.to_vec()).await;
+
+        let stored = db.get_mailbox_webhooks(&mailbox.id).await.unwrap();
+        assert_eq!(stored[0].last_status, Some(204));
+        assert!(stored[0].last_triggered_at.is_some());
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.attempts, 3);
+        for (signature, body) in received.signatures.iter().zip(&received.bodies) {
+            assert_eq!(signature, &sign(SignatureAlgorithm::Sha256, "webhook-secret", body));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_private_addresses_are_refused() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(|| async { StatusCode::NO_CONTENT }));
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        assert_eq!(WebhookSender::default().post(&url, None, b"{}").await, None);
+        assert_eq!(WebhookSender::default().allowing_private_addresses().post(&url, None, b"{}").await, Some(204));
+    }
+
+    #[tokio::test]
+    async fn test_failing_host_is_skipped() {
+        let attempts = Arc::new(Mutex::new(Received::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let endpoint = |State(received): State<Arc<Mutex<Received>>>| async move {
+            received.lock().unwrap().attempts += 1;
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let app = Router::new().route("/hook", post(endpoint)).with_state(attempts.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let sender = WebhookSender::default()
+            .allowing_private_addresses()
+            .with_circuit_breaker(CircuitBreaker::new(2, Duration::from_secs(60)));
+        for _ in 0..4 {
+            sender.post(&url, None, b"{}").await;
+        }
+        assert_eq!(attempts.lock().unwrap().attempts, 2);
+    }
+
+    #[tokio::test]
+    async fn test_redirects_are_not_followed() {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route(
+            "/hook",
+            post(|| async { axum::response::Redirect::temporary("http://169.254.169.254/latest/meta-data/") }),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let sender = WebhookSender::default().allowing_private_addresses();
+        assert_eq!(sender.post(&url, None, b"{}").await, Some(307));
+    }
+
+    #[test]
+    fn test_signature() {
+        // RFC 4231 test case 2
+        let body = b"what do ya want for nothing?";
+        assert_eq!(
+            sign(SignatureAlgorithm::Sha256, "Jefe", body),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            sign(SignatureAlgorithm::Sha512, "Jefe", body),
+            "sha512=164b7a7bfcf819e2e395fbe73b56e0a387bd64222e831fd610270cd7ea2505549758bf75c05a994a6d034f65f8f0e6fdcaeab1a34d4a6b4b636e070a38bce737"
+        );
+    }
+
+    #[test]
+    fn test_render_payload() {
+        let context = PayloadContext::sample("mailbox-1");
+        let default: serde_json::Value = serde_json::from_slice(&context.render(None).unwrap()).unwrap();
+        assert_eq!(default["event"], Webhook::EMAIL_RECEIVED);
+        assert_eq!(default["mailbox_id"], "mailbox-1");
+        assert!(default.get("subject").is_none());
+
+        let rendered = context.render(Some("{{ mailbox_id }} {{ sender_ip }} {{ from }}: {{ subject }}")).unwrap();
+        assert_eq!(rendered, b"mailbox-1 192.0.2.1 sender@example.com: Sample subject");
+        // Not HTML, so nothing is escaped
+        assert_eq!(context.render(Some("{{ from }} <&>")).unwrap(), b"sender@example.com <&>");
+
+        for template in ["{{ mailbox_id", "{{ unknown }}", "{% if %}"] {
+            let error = context.render(Some(template)).unwrap_err().to_string();
+            assert!(error.starts_with("Mail processing error: Invalid payload template: "), "{}", error);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_send_test_uses_the_configured_algorithm() {
+        let received = Arc::new(Mutex::new(Received::default()));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let app = Router::new().route("/hook", post(flaky_endpoint)).with_state(received.clone());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        let webhook = Webhook {
+            id: "webhook-1".to_string(),
+            mailbox_id: "mailbox-1".to_string(),
+            url,
+            secret: "webhook-secret".to_string(),
+            events: vec![Webhook::EMAIL_RECEIVED.to_string()],
+            signature_algorithm: SignatureAlgorithm::Sha512,
+            payload_template: None,
+            created_at: 0,
+            last_triggered_at: None,
+            last_status: None,
+        };
+        // Sent once, even though the endpoint fails
+        let sender = WebhookSender::default().allowing_private_addresses();
+        assert_eq!(sender.send_test(&webhook).await, Some(500));
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.attempts, 1);
+        let payload: serde_json::Value = serde_json::from_slice(&received.bodies[0]).unwrap();
+        assert_eq!(payload["event"], Webhook::TEST);
+        assert!(received.signatures[0].starts_with("sha512="));
+        assert_eq!(received.signatures[0], sign(SignatureAlgorithm::Sha512, "webhook-secret", &received.bodies[0]));
+    }
+}
@@ -1,8 +1,9 @@
 use std::{sync::Arc, net::IpAddr, time::Duration};
 use anyhow::Result;
-use common::{db::{Database, SqliteDatabase}, Mailbox, User, AuthType, security::decrypt_email};
+use common::{db::{Database, SqliteDatabase}, id::IdFormat, Mailbox, MailboxAlias, KeyType, User, AuthType, Email, CleanupPolicy, UserSettings, security::decrypt_email};
 use mail_service::{MailService, ServiceConfig};
 use mail_service::dns::MockDnsResolver;
+use mail_service::webhooks::WebhookSender;
 use uuid::Uuid;
 
 // Test constants
@@ -36,6 +37,13 @@ async fn setup_test_service(enable_greylisting: bool) -> Result<(Arc<MailService
         greylist_delay: Duration::from_secs(5), // increased to 5 seconds for more reliable testing
         enable_spf: false, // disable SPF for testing
         enable_dkim: false, // disable DKIM for testing
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
     };
 
     // Create a mock resolver with test MX records
@@ -46,7 +54,9 @@ async fn setup_test_service(enable_greylisting: bool) -> Result<(Arc<MailService
         db.clone(),
         config,
         dns_resolver,
-    ).await?;
+    ).await?
+    // Webhook tests listen on loopback
+    .with_webhook_sender(WebhookSender::default().allowing_private_addresses());
     
     Ok((Arc::new(service), db))
 }
@@ -65,6 +75,13 @@ async fn create_fresh_service(db: Arc<dyn Database>, enable_greylisting: bool) -
         greylist_delay: Duration::from_secs(5),
         enable_spf: false,
         enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
     };
 
     let dns_resolver = Arc::new(MockDnsResolver::new(vec!["test-mx.test.com".to_string()]));
@@ -85,18 +102,19 @@ async fn test_smtp_basic_flow() -> Result<()> {
         alias: "test".to_string(),
         name: "Test Mailbox".to_string(),
         public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
         owner_id: test_user.id,
         created_at: chrono::Utc::now().timestamp(),
         mail_expires_in: Some(3600), // 1 hour expiration
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
     };
     
     // Create mailbox using database
     let mailbox_id = test_mailbox.id.clone();
     db.create_mailbox(&test_mailbox).await?;
     
-    // Create a fresh service instance for this test
-    let service = create_fresh_service(db.clone(), false).await?;
-    
     // Test email sending
     let email_content = "From: sender@example.com\r\n\
                         To: test@test.com\r\n\
@@ -118,10 +136,425 @@ async fn test_smtp_basic_flow() -> Result<()> {
     // Verify decrypted content matches original
     let decrypted = decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY)?;
     assert_eq!(decrypted, email_content.as_bytes());
+
+    // The size is of the message as received, not of its ciphertext
+    assert_eq!(emails[0].raw_size_bytes, email_content.len() as i64);
+    assert_eq!(db.get_email(&emails[0].id).await?.unwrap().raw_size_bytes, email_content.len() as i64);
+    assert_eq!(db.get_mailbox_total_size(&mailbox_id).await?, email_content.len() as i64);
     
     Ok(())
 }
 
+#[tokio::test]
+async fn test_boxed_database() -> Result<()> {
+    let db: Box<dyn Database> = Box::new(SqliteDatabase::new("sqlite::memory:").await?);
+    db.init().await?;
+    let user = db.create_user("boxed_user", AuthType::Password).await?;
+
+    let mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "boxed".to_string(),
+        name: "Boxed Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    // Through a shared reference, as borrowing callers see it
+    let by_ref: &dyn Database = &db;
+    by_ref.create_mailbox(&mailbox).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 1000,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(5),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let db: Arc<dyn Database> = Arc::new(db);
+    let service = MailService::new(db.clone(), config).await?;
+
+    let email_content = "From: sender@example.com\r\nTo: boxed@test.com\r\nSubject: Boxed\r\n\r\nStored through a box.";
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &mailbox.get_address("test.com"),
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    let emails = db.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    assert_eq!(decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY)?, email_content.as_bytes());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_message_id_deduplication() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "dedup".to_string(),
+        name: "Dedup Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&mailbox).await?;
+    let address = mailbox.get_address("test.com");
+
+    // A retransmission is accepted but only stored once
+    let email = "From: sender@example.com\r\nTo: dedup@test.com\r\nSubject: Retried\r\nMessage-ID: <retried-1@example.com>\r\n\r\nBody.";
+    for _ in 0..2 {
+        service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse()?).await?;
+    }
+    let emails = db.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].message_id.as_deref(), Some("<retried-1@example.com>"));
+    assert!(db.email_exists_by_message_id(&mailbox.id, "<retried-1@example.com>").await?);
+    assert!(!db.email_exists_by_message_id(&mailbox.id, "<other@example.com>").await?);
+
+    // Messages without a Message-ID each get their own
+    let email = "From: sender@example.com\r\nTo: dedup@test.com\r\nSubject: No ID\r\n\r\nBody.";
+    for _ in 0..2 {
+        service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse()?).await?;
+    }
+    let emails = db.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 3);
+    let generated: Vec<_> = emails.iter().filter(|email| email.subject == "No ID").collect();
+    assert_eq!(generated.len(), 2);
+    assert_ne!(generated[0].message_id, generated[1].message_id);
+    assert!(generated.iter().all(|email| email.message_id.as_deref().is_some_and(|id| id.ends_with("@test.com>"))));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_strip_attachments() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "stripped".to_string(),
+        name: "Stripped Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: true,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: stripped@test.com\r\n\
+                        Subject: Invoice\r\n\
+                        MIME-Version: 1.0\r\n\
+                        Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+                        \r\n\
+                        --b1\r\n\
+                        Content-Type: text/plain\r\n\
+                        \r\n\
+                        Invoice attached.\r\n\
+                        --b1\r\n\
+                        Content-Type: application/pdf; name=\"invoice.pdf\"\r\n\
+                        Content-Disposition: attachment; filename=\"invoice.pdf\"\r\n\
+                        Content-Transfer-Encoding: base64\r\n\
+                        \r\n\
+                        JVBERi0xLjQK\r\n\
+                        --b1--\r\n";
+
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &test_mailbox.get_address("test.com"),
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    let body = String::from_utf8(decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY)?)?;
+    assert!(body.contains("Invoice attached."));
+    assert!(!body.contains("JVBERi0xLjQK"));
+
+    let attachments = db.get_email_attachments(&emails[0].id).await?;
+    assert_eq!(attachments.len(), 1);
+    assert_eq!(attachments[0].filename.as_deref(), Some("invoice.pdf"));
+    assert_eq!(attachments[0].mime_type, "application/pdf");
+    assert!(body.contains(&format!("attachment-id=\"{}\"", attachments[0].id)));
+    assert_eq!(decrypt_email(&attachments[0].encrypted_content, TEST_SECRET_KEY)?, b"%PDF-1.4\n");
+
+    // Attachments are removed with their email
+    db.delete_email(&emails[0].id).await?;
+    assert!(db.get_email_attachment(&attachments[0].id).await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_multiple_mailbox_keys() -> Result<()> {
+    use age::secrecy::ExposeSecret;
+    use common::MailboxKey;
+
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mut mailbox = Mailbox::new(&test_user.id, "test.com", None);
+    mailbox.public_key = TEST_PUBLIC_KEY.to_string();
+    mailbox.strip_attachments = true;
+    db.create_mailbox(&mailbox).await?;
+
+    let teammate_identity = age::x25519::Identity::generate();
+    let teammate_secret_key = teammate_identity.to_string().expose_secret().clone();
+    db.add_mailbox_key(&MailboxKey {
+        mailbox_id: mailbox.id.clone(),
+        label: "teammate".to_string(),
+        public_key: teammate_identity.to_public().to_string(),
+        key_type: KeyType::AgeX25519,
+        added_at: chrono::Utc::now().timestamp(),
+    }).await?;
+
+    let keys = db.list_mailbox_keys(&mailbox.id).await?;
+    let labels: Vec<&str> = keys.iter().map(|key| key.label.as_str()).collect();
+    assert_eq!(labels, vec![MailboxKey::PRIMARY_LABEL, "teammate"]);
+    assert_eq!(keys[0].public_key, TEST_PUBLIC_KEY);
+
+    let email_content = format!(
+        "From: sender@example.com\r\n\
+         To: {}\r\n\
+         Subject: Shared\r\n\
+         MIME-Version: 1.0\r\n\
+         Content-Type: multipart/mixed; boundary=\"b1\"\r\n\
+         \r\n\
+         --b1\r\n\
+         Content-Type: text/plain\r\n\
+         \r\n\
+         For the whole team.\r\n\
+         --b1\r\n\
+         Content-Type: application/pdf; name=\"notes.pdf\"\r\n\
+         Content-Disposition: attachment; filename=\"notes.pdf\"\r\n\
+         Content-Transfer-Encoding: base64\r\n\
+         \r\n\
+         JVBERi0xLjQK\r\n\
+         --b1--\r\n",
+        mailbox.get_address("test.com"),
+    );
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &mailbox.get_address("test.com"),
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    // Both the primary and the additional key decrypt the body and attachments
+    let emails = service.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    let attachments = db.get_email_attachments(&emails[0].id).await?;
+    assert_eq!(attachments.len(), 1);
+    for secret_key in [TEST_SECRET_KEY, teammate_secret_key.as_str()] {
+        let body = String::from_utf8(decrypt_email(&emails[0].encrypted_content, secret_key)?)?;
+        assert!(body.contains("For the whole team."));
+        assert_eq!(decrypt_email(&attachments[0].encrypted_content, secret_key)?, b"%PDF-1.4\n");
+    }
+
+    // The primary key can't be removed, and removed keys get no new emails
+    assert!(!db.remove_mailbox_key(&mailbox.id, MailboxKey::PRIMARY_LABEL).await?);
+    assert!(db.remove_mailbox_key(&mailbox.id, "teammate").await?);
+    assert_eq!(db.list_mailbox_keys(&mailbox.id).await?.len(), 1);
+
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &mailbox.get_address("test.com"),
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+    let emails = service.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 2);
+    assert!(emails.iter().all(|email| decrypt_email(&email.encrypted_content, TEST_SECRET_KEY).is_ok()));
+    let teammate_readable = emails
+        .iter()
+        .filter(|email| decrypt_email(&email.encrypted_content, &teammate_secret_key).is_ok())
+        .count();
+    assert_eq!(teammate_readable, 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delivery_to_secondary_alias() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "primary".to_string(),
+        name: "Aliased Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: Some(3600),
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+    db.create_mailbox_alias(&MailboxAlias {
+        id: Uuid::new_v4().to_string(),
+        mailbox_id: test_mailbox.id.clone(),
+        alias: "secondary".to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        is_primary: false,
+    }).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: secondary@test.com\r\n\
+                        Subject: Alias Test\r\n\
+                        \r\n\
+                        Sent to a secondary alias.";
+
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        "Secondary+news@test.com",
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+
+    // Secondary aliases only match exactly, never as a prefix
+    assert!(db.get_mailbox_by_incoming_address("secondaryx").await?.is_none());
+
+    // Removing the alias stops delivery
+    let aliases = db.get_mailbox_aliases(&test_mailbox.id).await?;
+    assert_eq!(aliases.len(), 2);
+    assert!(aliases[0].is_primary);
+    assert!(!db.delete_mailbox_alias(&test_mailbox.id, &aliases[0].id).await?);
+    assert!(db.delete_mailbox_alias(&test_mailbox.id, &aliases[1].id).await?);
+    assert!(db.get_mailbox_by_incoming_address("secondary").await?.is_none());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_delivery_to_custom_alias() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "john.doe+test".to_string(),
+        name: "Custom Alias Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: Some(3600),
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: john.doe+test@test.com\r\n\
+                        Subject: Custom Alias Test\r\n\
+                        \r\n\
+                        Sent to a chosen alias.";
+
+    // The full local part is matched before '+' tags and punctuation are dropped
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        "John.Doe+Test@test.com",
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_rotated_alias() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "spammedalias".to_string(),
+        name: "Rotated Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: Some(3600),
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let now = chrono::Utc::now().timestamp();
+    db.rotate_mailbox_alias(&test_mailbox.id, "freshalias", now, now + 24 * 60 * 60).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: freshalias@test.com\r\n\
+                        Subject: Rotation Test\r\n\
+                        \r\n\
+                        Sent after the alias was rotated.";
+
+    // The old alias is refused at RCPT time, '+' tags included
+    assert!(service.is_retired_address("spammedalias@test.com").await?);
+    assert!(service.is_retired_address("SpammedAlias+news@test.com").await?);
+    assert!(!service.is_retired_address("freshalias@test.com").await?);
+    let result = service.process_incoming_email(
+        email_content.as_bytes(),
+        "spammedalias@test.com",
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await;
+    assert!(result.is_err());
+
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        "freshalias@test.com",
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+
+    // Once the retirement ends the address is unknown like any other
+    assert_eq!(db.cleanup_old_aliases(now + 24 * 60 * 60).await?, 1);
+    assert!(!service.is_retired_address("spammedalias@test.com").await?);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_ip_blocking() -> Result<()> {
     let (service, _) = setup_test_service(false).await?;
@@ -169,9 +602,13 @@ async fn test_greylisting() -> Result<()> {
         alias: "test".to_string(),
         name: "Test Mailbox".to_string(),
         public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
         owner_id: test_user.id,
         created_at: chrono::Utc::now().timestamp(),
         mail_expires_in: Some(3600), // 1 hour expiration
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
     };
     db.create_mailbox(&test_mailbox).await?;
     
@@ -208,6 +645,53 @@ async fn test_greylisting() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_greylisting_survives_restart() -> Result<()> {
+    let db = setup_test_db().await?;
+    let test_user = create_test_user(&db).await?;
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "test".to_string(),
+        name: "Test Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+    let recipient = test_mailbox.get_address("test.com");
+    let email_content = b"test email content";
+
+    // Greylisted by a previous process long enough ago
+    sqlx::query("INSERT INTO greylisting (ip, from_addr, to_addr, first_seen) VALUES (?, ?, ?, ?)")
+        .bind("192.168.1.1")
+        .bind("sender@example.com")
+        .bind(&recipient)
+        .bind(chrono::Utc::now().timestamp() - 60)
+        .execute(db.pool())
+        .await?;
+
+    let service = create_fresh_service(db.clone(), true).await?;
+    service.process_incoming_email(email_content, &recipient, "sender@example.com", "192.168.1.1".parse()?).await?;
+    assert_eq!(service.get_mailbox_emails(&test_mailbox.id).await?.len(), 1);
+
+    // A triple deferred before a restart is still deferred after it
+    let other_ip: IpAddr = "192.168.1.2".parse()?;
+    let result = service.process_incoming_email(email_content, &recipient, "sender@example.com", other_ip).await;
+    assert!(matches!(result, Err(e) if e.to_string().contains("Greylisted")));
+    let restarted = create_fresh_service(db.clone(), true).await?;
+    let result = restarted.process_incoming_email(email_content, &recipient, "sender@example.com", other_ip).await;
+    assert!(matches!(result, Err(e) if e.to_string().contains("Greylisted")));
+
+    assert_eq!(db.greylist_cleanup(i64::MAX).await?, 1);
+
+    Ok(())
+}
+
 #[tokio::test]
 async fn test_cleanup() -> Result<()> {
     let (_, db) = setup_test_service(false).await?;
@@ -221,9 +705,13 @@ async fn test_cleanup() -> Result<()> {
         alias: "test".to_string(),
         name: "Test Mailbox".to_string(),
         public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
         owner_id: test_user.id,
         created_at: chrono::Utc::now().timestamp(),
         mail_expires_in: Some(1), // 1 second expiration
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
     };
     
     // Create mailbox using database
@@ -284,4 +772,905 @@ async fn test_nonexistent_mailbox() -> Result<()> {
     assert!(err.to_string().contains("Mailbox not found"));
     
     Ok(())
-} 
\ No newline at end of file
+}
+
+#[tokio::test]
+async fn test_recipient_domain() -> Result<()> {
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "domains".to_string(),
+        name: "Domains".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&mailbox).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: domains@test.com\r\n\
+                        Subject: Test Email\r\n\
+                        \r\n\
+                        This is a test email.";
+    let deliver = |recipient: &'static str| {
+        service.process_incoming_email(email_content.as_bytes(), recipient, "sender@example.com", "192.168.1.1".parse().unwrap())
+    };
+
+    // Domains are compared case-insensitively
+    deliver("domains@TEST.com").await?;
+    assert!(service.handles_domain("test.com."));
+    assert_eq!(db.get_mailbox_emails(&mailbox.id).await?.len(), 1);
+
+    let err = deliver("domains@example.com").await.unwrap_err();
+    assert_eq!(err.to_string(), "Mail processing error: Recipient domain not handled: example.com");
+    let err = deliver("domains").await.unwrap_err();
+    assert_eq!(err.to_string(), "Mail processing error: Invalid recipient address format");
+    assert_eq!(db.get_mailbox_emails(&mailbox.id).await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_cleanup_policy() -> Result<()> {
+    let (_, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "test".to_string(),
+        name: "Test Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id.clone(),
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    // Emails received 0, 1, 2, ... days ago
+    let now = chrono::Utc::now().timestamp();
+    for days_ago in 0..5 {
+        db.save_email(&Email {
+            id: Uuid::new_v4().to_string(),
+            mailbox_id: test_mailbox.id.clone(),
+            encrypted_content: "content".to_string(),
+            received_at: now - days_ago * 24 * 60 * 60,
+            expires_at: None,
+            from_addr: String::new(),
+            to_addr: String::new(),
+            subject: String::new(),
+            headers_json: None,
+            raw_size_bytes: 0,
+            message_id: None,
+        }).await?;
+    }
+
+    db.update_user_settings(&UserSettings {
+        user_id: test_user.id.clone(),
+        email_notifications: true,
+        auto_delete_expired: true,
+        default_mailbox_expiry: None,
+        email_cleanup_policy: Some(CleanupPolicy {
+            max_age_seconds: Some(3 * 24 * 60 * 60 + 60),
+            max_count: Some(2),
+            apply_to_all_mailboxes: false,
+        }),
+        email_count_limit: None,
+        total_storage_bytes_limit: None,
+        api_rate_limit_per_minute: None,
+        max_mailboxes: None,
+    }).await?;
+
+    // Policies saved before they were validated are skipped, not applied
+    let other_user = db.create_user("other-cleanup-user", AuthType::Password).await?;
+    let other_mailbox = Mailbox::new(&other_user.id, "test.com", None);
+    db.create_mailbox(&other_mailbox).await?;
+    db.save_email(&Email {
+        id: Uuid::new_v4().to_string(),
+        mailbox_id: other_mailbox.id.clone(),
+        encrypted_content: "content".to_string(),
+        received_at: now,
+        expires_at: None,
+        from_addr: String::new(),
+        to_addr: String::new(),
+        subject: String::new(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    }).await?;
+    let mut other_settings = UserSettings::new(&other_user.id);
+    other_settings.email_cleanup_policy = Some(CleanupPolicy { max_count: Some(0), ..Default::default() });
+    db.update_user_settings(&other_settings).await?;
+
+    let service = create_fresh_service(db.clone(), false).await?;
+    service.cleanup_expired().await?;
+
+    // The 4 day old email is past max_age, then only the newest 2 are kept
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    let mut ages: Vec<i64> = emails.iter().map(|e| (now - e.received_at) / (24 * 60 * 60)).collect();
+    ages.sort();
+    assert_eq!(ages, vec![0, 1]);
+    assert_eq!(service.get_mailbox_emails(&other_mailbox.id).await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_spf_fail_rejects_email() -> Result<()> {
+    let db = setup_test_db().await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "test".to_string(),
+        name: "Test Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 1000,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(5),
+        enable_spf: true,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let dns_resolver = Arc::new(
+        MockDnsResolver::new(vec![]).with_txt("example.com", "v=spf1 ip4:192.0.2.0/24 -all"),
+    );
+    let service = MailService::new_with_resolver(db.clone(), config, dns_resolver).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: test@test.com\r\n\
+                        Subject: SPF\r\n\
+                        \r\n\
+                        Hello.";
+    let recipient = test_mailbox.get_address("test.com");
+
+    let result = service.process_incoming_email(
+        email_content.as_bytes(),
+        &recipient,
+        "sender@example.com",
+        "198.51.100.1".parse()?,
+    ).await;
+    assert!(matches!(result, Err(e) if e.to_string().contains("SPF validation failed")));
+
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &recipient,
+        "sender@example.com",
+        "192.0.2.10".parse()?,
+    ).await?;
+    assert_eq!(service.get_mailbox_emails(&test_mailbox.id).await?.len(), 1);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_dnsbl_listed_ip_rejects_email() -> Result<()> {
+    let db = setup_test_db().await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "test".to_string(),
+        name: "Test Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 1000,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(5),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec!["zen.spamhaus.org".to_string(), "bl.spamcop.net".to_string()],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    // 192.0.2.10 is listed, and the lookups of 192.0.2.20 fail
+    let dns_resolver = Arc::new(
+        MockDnsResolver::new(vec![])
+            .with_a("10.2.0.192.bl.spamcop.net")
+            .with_a_error("20.2.0.192.zen.spamhaus.org")
+            .with_a_error("20.2.0.192.bl.spamcop.net"),
+    );
+    let service = MailService::new_with_resolver(db.clone(), config, dns_resolver).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        To: test@test.com\r\n\
+                        Subject: DNSBL\r\n\
+                        \r\n\
+                        Hello.";
+    let recipient = test_mailbox.get_address("test.com");
+
+    let result = service.process_incoming_email(
+        email_content.as_bytes(),
+        &recipient,
+        "sender@example.com",
+        "192.0.2.10".parse()?,
+    ).await;
+    assert!(matches!(result, Err(e) if e.to_string().contains("IP listed in DNSBL: bl.spamcop.net")));
+    assert!(service.get_mailbox_emails(&test_mailbox.id).await?.is_empty());
+
+    for ip in ["192.0.2.1", "192.0.2.20"] {
+        service.process_incoming_email(
+            email_content.as_bytes(),
+            &recipient,
+            "sender@example.com",
+            ip.parse()?,
+        ).await?;
+    }
+    assert_eq!(service.get_mailbox_emails(&test_mailbox.id).await?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_webhook_notified_of_received_email() -> Result<()> {
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use common::{SignatureAlgorithm, Webhook};
+    use mail_service::webhooks::{sign, SIGNATURE_HEADER};
+    use tokio::sync::mpsc;
+
+    let (deliveries_tx, mut deliveries) = mpsc::unbounded_channel::<(String, axum::body::Bytes)>();
+    let endpoint = |State(tx): State<mpsc::UnboundedSender<(String, axum::body::Bytes)>>, headers: HeaderMap, body: axum::body::Bytes| async move {
+        tx.send((headers[SIGNATURE_HEADER].to_str().unwrap().to_string(), body)).unwrap();
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/hook", listener.local_addr()?);
+    tokio::spawn(async move {
+        axum::serve(listener, Router::new().route("/hook", post(endpoint)).with_state(deliveries_tx)).await.unwrap()
+    });
+
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mut test_mailbox = Mailbox::new(&test_user.id, "test.com", None);
+    test_mailbox.public_key = TEST_PUBLIC_KEY.to_string();
+    db.create_mailbox(&test_mailbox).await?;
+    db.create_webhook(&Webhook {
+        id: Uuid::new_v4().to_string(),
+        mailbox_id: test_mailbox.id.clone(),
+        url,
+        secret: "webhook-secret".to_string(),
+        events: vec![Webhook::EMAIL_RECEIVED.to_string()],
+        signature_algorithm: SignatureAlgorithm::Sha512,
+        payload_template: None,
+        created_at: chrono::Utc::now().timestamp(),
+        last_triggered_at: None,
+        last_status: None,
+    }).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        Subject: Webhook Test\r\n\
+                        \r\n\
+                        Hello.";
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &test_mailbox.get_address("test.com"),
+        "sender@example.com",
+        "192.168.1.1".parse()?,
+    ).await?;
+
+    let (signature, body) = tokio::time::timeout(Duration::from_secs(5), deliveries.recv()).await?.unwrap();
+    assert_eq!(signature, sign(SignatureAlgorithm::Sha512, "webhook-secret", &body));
+    let payload: serde_json::Value = serde_json::from_slice(&body)?;
+    let email = &service.get_mailbox_emails(&test_mailbox.id).await?[0];
+    assert_eq!(payload["event"], "email.received");
+    assert_eq!(payload["email_id"], email.id.as_str());
+    assert_eq!(payload["mailbox_id"], test_mailbox.id.as_str());
+    assert_eq!(payload["received_at"], email.received_at);
+
+    // The status is recorded once the response arrives
+    for _ in 0..50 {
+        if db.get_mailbox_webhooks(&test_mailbox.id).await?[0].last_status.is_some() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(db.get_mailbox_webhooks(&test_mailbox.id).await?[0].last_status, Some(200));
+
+    Ok(())
+}
+
+/// Sends an SMTP command, if any, and returns the lines of its reply
+async fn command<S>(stream: &mut S, line: &str) -> Result<Vec<String>>
+where
+    S: tokio::io::AsyncBufRead + tokio::io::AsyncWrite + Unpin,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    if !line.is_empty() {
+        stream.write_all(format!("{}\r\n", line).as_bytes()).await?;
+        stream.flush().await?;
+    }
+    let mut reply = Vec::new();
+    loop {
+        let mut reply_line = String::new();
+        stream.read_line(&mut reply_line).await?;
+        let reply_line = reply_line.trim_end().to_string();
+        let last = reply_line.as_bytes().get(3) != Some(&b'-');
+        reply.push(reply_line);
+        if last {
+            return Ok(reply);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_webhook_payload_template() -> Result<()> {
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use common::{SignatureAlgorithm, Webhook};
+    use mail_service::webhooks::{sign, SIGNATURE_HEADER};
+    use tokio::sync::mpsc;
+
+    let (deliveries_tx, mut deliveries) = mpsc::unbounded_channel::<(String, axum::body::Bytes)>();
+    let endpoint = |State(tx): State<mpsc::UnboundedSender<(String, axum::body::Bytes)>>, headers: HeaderMap, body: axum::body::Bytes| async move {
+        tx.send((headers[SIGNATURE_HEADER].to_str().unwrap().to_string(), body)).unwrap();
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/hook", listener.local_addr()?);
+    tokio::spawn(async move {
+        axum::serve(listener, Router::new().route("/hook", post(endpoint)).with_state(deliveries_tx)).await.unwrap()
+    });
+
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mut test_mailbox = Mailbox::new(&test_user.id, "test.com", None);
+    test_mailbox.public_key = TEST_PUBLIC_KEY.to_string();
+    db.create_mailbox(&test_mailbox).await?;
+    db.create_webhook(&Webhook {
+        id: Uuid::new_v4().to_string(),
+        mailbox_id: test_mailbox.id.clone(),
+        url,
+        secret: "webhook-secret".to_string(),
+        events: vec![Webhook::EMAIL_RECEIVED.to_string()],
+        signature_algorithm: SignatureAlgorithm::Sha256,
+        payload_template: Some(r#"{"text": {{ subject | json_encode() }}, "from": "{{ from }}", "ip": "{{ sender_ip }}", "id": "{{ email_id }}"}"#.to_string()),
+        created_at: chrono::Utc::now().timestamp(),
+        last_triggered_at: None,
+        last_status: None,
+    }).await?;
+
+    let email_content = "From: sender@example.com\r\n\
+                        Subject: Template \"Test\"\r\n\
+                        \r\n\
+                        Hello.";
+    service.process_incoming_email(
+        email_content.as_bytes(),
+        &test_mailbox.get_address("test.com"),
+        "sender@example.com",
+        "203.0.113.7".parse()?,
+    ).await?;
+
+    // The rendered template replaces the default payload and is what gets signed
+    let (signature, body) = tokio::time::timeout(Duration::from_secs(5), deliveries.recv()).await?.unwrap();
+    assert_eq!(signature, sign(SignatureAlgorithm::Sha256, "webhook-secret", &body));
+    let payload: serde_json::Value = serde_json::from_slice(&body)?;
+    let email = &service.get_mailbox_emails(&test_mailbox.id).await?[0];
+    assert_eq!(payload["text"], "Template \"Test\"");
+    assert_eq!(payload["from"], email.from_addr.as_str());
+    assert_eq!(payload["ip"], "203.0.113.7");
+    assert_eq!(payload["id"], email.id.as_str());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_starttls_upgrade() -> Result<()> {
+    use mail_service::smtp::{handler::SmtpShutdown, starttls::{load_tls_config, run_starttls_server}};
+    use std::{path::Path, sync::RwLock};
+    use tokio::io::BufStream;
+    use tokio_rustls::{rustls::{self, Certificate, RootCertStore, ServerName}, TlsConnector};
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let chain = tempfile::NamedTempFile::new()?;
+    let tls_config = load_tls_config(&fixtures.join("localhost.crt"), &fixtures.join("localhost.key"), chain.path())?;
+
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mut test_mailbox = Mailbox::new(&test_user.id, "test.com", None);
+    test_mailbox.public_key = TEST_PUBLIC_KEY.to_string();
+    db.create_mailbox(&test_mailbox).await?;
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(run_starttls_server(listener, service.clone(), 1, SmtpShutdown::default(), Arc::new(RwLock::new(tls_config))));
+
+    let mut plain = BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+    assert!(command(&mut plain, "").await?[0].starts_with("220"));
+    let ehlo = command(&mut plain, "EHLO client.example.com").await?;
+    assert!(ehlo.iter().any(|line| line.ends_with("STARTTLS")), "{:?}", ehlo);
+    assert!(command(&mut plain, "STARTTLS").await?[0].starts_with("220"));
+
+    let mut roots = RootCertStore::empty();
+    let cert = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(fixtures.join("localhost.crt"))?))?;
+    roots.add(&Certificate(cert[0].clone()))?;
+    let client_config = rustls::ClientConfig::builder()
+        .with_safe_defaults()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let tls = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost")?, plain.into_inner())
+        .await?;
+    let mut tls = BufStream::new(tls);
+
+    let ehlo = command(&mut tls, "EHLO client.example.com").await?;
+    assert!(ehlo[0].starts_with("250"));
+    assert!(!ehlo.iter().any(|line| line.ends_with("STARTTLS")), "{:?}", ehlo);
+    assert!(command(&mut tls, "MAIL FROM:<sender@example.com>").await?[0].starts_with("250"));
+    let rcpt = format!("RCPT TO:<{}>", test_mailbox.get_address("test.com"));
+    assert!(command(&mut tls, &rcpt).await?[0].starts_with("250"));
+    assert!(command(&mut tls, "DATA").await?[0].starts_with("354"));
+    let message = "From: sender@example.com\r\nSubject: STARTTLS Test\r\n\r\nSent over TLS.\r\n.";
+    let reply = command(&mut tls, message).await?;
+    assert!(reply[0].starts_with("250"), "{:?}", reply);
+    assert!(command(&mut tls, "QUIT").await?[0].starts_with("221"));
+
+    let emails = service.get_mailbox_emails(&test_mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].subject, "STARTTLS Test");
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_starttls_line_too_long() -> Result<()> {
+    use mail_service::smtp::{handler::SmtpShutdown, starttls::{load_tls_config, run_starttls_server}};
+    use std::{path::Path, sync::RwLock};
+    use tokio::io::{AsyncReadExt, BufStream};
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let chain = tempfile::NamedTempFile::new()?;
+    let tls_config = load_tls_config(&fixtures.join("localhost.crt"), &fixtures.join("localhost.key"), chain.path())?;
+
+    let (service, _db) = setup_test_service(false).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(run_starttls_server(listener, service, 1, SmtpShutdown::default(), Arc::new(RwLock::new(tls_config))));
+
+    let mut stream = BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+    assert!(command(&mut stream, "").await?[0].starts_with("220"));
+    assert!(command(&mut stream, "EHLO client.example.com").await?[0].starts_with("250"));
+    let reply = command(&mut stream, &format!("NOOP {}", "x".repeat(100_000))).await?;
+    assert_eq!(reply, vec!["500 Line too long"]);
+    // The session is closed rather than reading the rest as commands
+    let mut rest = Vec::new();
+    tokio::time::timeout(Duration::from_secs(5), stream.read_to_end(&mut rest)).await??;
+    assert!(rest.is_empty(), "{}", String::from_utf8_lossy(&rest));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_transaction_ends_with_dropped_connection() -> Result<()> {
+    use mail_service::smtp::{handler::SmtpShutdown, starttls::{load_tls_config, run_starttls_server}};
+    use std::{path::Path, sync::RwLock};
+    use tokio::io::{AsyncWriteExt, BufStream};
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let chain = tempfile::NamedTempFile::new()?;
+    let tls_config = load_tls_config(&fixtures.join("localhost.crt"), &fixtures.join("localhost.key"), chain.path())?;
+
+    let (service, _db) = setup_test_service(false).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let shutdown = SmtpShutdown::default();
+    tokio::spawn(run_starttls_server(listener, service, 1, shutdown.clone(), Arc::new(RwLock::new(tls_config))));
+
+    let mut stream = BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+    assert!(command(&mut stream, "").await?[0].starts_with("220"));
+    assert!(command(&mut stream, "EHLO client.example.com").await?[0].starts_with("250"));
+    assert!(command(&mut stream, "MAIL FROM:<sender@example.com>").await?[0].starts_with("250"));
+    assert!(command(&mut stream, "RCPT TO:<someone@test.com>").await?[0].starts_with("250"));
+    assert!(command(&mut stream, "DATA").await?[0].starts_with("354"));
+    stream.write_all(b"Subject: Never finished\r\n").await?;
+    stream.flush().await?;
+    assert_eq!(shutdown.active_transactions(), 1);
+
+    // A client leaving mid-message must not hold up a shutdown
+    drop(stream);
+    for _ in 0..50 {
+        if shutdown.active_transactions() == 0 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(shutdown.active_transactions(), 0);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_recipient_without_domain() -> Result<()> {
+    use mail_service::smtp::{handler::SmtpShutdown, starttls::{load_tls_config, run_starttls_server}};
+    use std::{path::Path, sync::RwLock};
+    use tokio::io::BufStream;
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let chain = tempfile::NamedTempFile::new()?;
+    let tls_config = load_tls_config(&fixtures.join("localhost.crt"), &fixtures.join("localhost.key"), chain.path())?;
+
+    let (service, _db) = setup_test_service(false).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(run_starttls_server(listener, service, 1, SmtpShutdown::default(), Arc::new(RwLock::new(tls_config))));
+
+    let mut stream = BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+    assert!(command(&mut stream, "").await?[0].starts_with("220"));
+    assert!(command(&mut stream, "EHLO client.example.com").await?[0].starts_with("250"));
+    assert!(command(&mut stream, "MAIL FROM:<sender@example.com>").await?[0].starts_with("250"));
+    // Without a domain there is nothing to check it against, so the address is refused
+    for recipient in ["RCPT TO:<someone>", "RCPT TO:<someone@>", "RCPT TO:<@test.com>"] {
+        let reply = command(&mut stream, recipient).await?;
+        assert!(reply[0].starts_with("553"), "{} is refused: {:?}", recipient, reply);
+    }
+    assert!(command(&mut stream, "RCPT TO:<someone@example.com>").await?[0].starts_with("550"));
+    assert!(command(&mut stream, "RCPT TO:<someone@test.com>").await?[0].starts_with("250"));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_smtp_connection_limit() -> Result<()> {
+    use mail_service::smtp::{handler::SmtpShutdown, starttls::{load_tls_config, run_starttls_server}};
+    use std::{path::Path, sync::RwLock};
+    use tokio::io::BufStream;
+
+    let fixtures = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let chain = tempfile::NamedTempFile::new()?;
+    let tls_config = load_tls_config(&fixtures.join("localhost.crt"), &fixtures.join("localhost.key"), chain.path())?;
+
+    // The test service allows 100 connections
+    let (service, _db) = setup_test_service(false).await?;
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(run_starttls_server(listener, service, 1, SmtpShutdown::default(), Arc::new(RwLock::new(tls_config))));
+
+    let connect = || async move {
+        let mut stream = BufStream::new(tokio::net::TcpStream::connect(addr).await?);
+        assert!(command(&mut stream, "").await?[0].starts_with("220"));
+        let reply = command(&mut stream, "EHLO client.example.com").await?;
+        Ok::<_, anyhow::Error>((stream, reply))
+    };
+
+    let mut connections = Vec::new();
+    for _ in 0..100 {
+        let (stream, reply) = connect().await?;
+        assert!(reply[0].starts_with("250"), "{:?}", reply);
+        connections.push(stream);
+    }
+    let (_, reply) = connect().await?;
+    assert_eq!(reply, vec!["421 Too many concurrent connections"]);
+
+    // Closed connections give their slot back
+    let mut first = connections.remove(0);
+    assert!(command(&mut first, "QUIT").await?[0].starts_with("221"));
+    drop(first);
+    let mut accepted = false;
+    for _ in 0..50 {
+        if connect().await?.1[0].starts_with("250") {
+            accepted = true;
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert!(accepted);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_forwarding_rules() -> Result<()> {
+    use age::secrecy::ExposeSecret;
+    use axum::{extract::State, routing::post, Router};
+    use common::{ForwardingKind, ForwardingRule};
+    use tokio::sync::mpsc;
+
+    let (forwarded_tx, mut forwarded) = mpsc::unbounded_channel::<Email>();
+    let endpoint = |State(tx): State<mpsc::UnboundedSender<Email>>, axum::Json(email): axum::Json<Email>| async move {
+        tx.send(email).unwrap();
+    };
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/forward", listener.local_addr()?);
+    tokio::spawn(async move {
+        axum::serve(listener, Router::new().route("/forward", post(endpoint)).with_state(forwarded_tx)).await.unwrap()
+    });
+
+    let (service, db) = setup_test_service(false).await?;
+    let test_user = create_test_user(&db).await?;
+    let mut source = Mailbox::new(&test_user.id, "test.com", None);
+    source.public_key = TEST_PUBLIC_KEY.to_string();
+    db.create_mailbox(&source).await?;
+    // The copy is encrypted to the destination's own key
+    let destination_identity = age::x25519::Identity::generate();
+    let mut destination = Mailbox::new(&test_user.id, "test.com", None);
+    destination.public_key = destination_identity.to_public().to_string();
+    db.create_mailbox(&destination).await?;
+
+    let rule = |kind, destination: &str, filter_from: Option<&str>, filter_subject: Option<&str>| ForwardingRule {
+        id: Uuid::new_v4().to_string(),
+        mailbox_id: source.id.clone(),
+        kind,
+        destination: destination.to_string(),
+        filter_from: filter_from.map(str::to_string),
+        filter_subject: filter_subject.map(str::to_string),
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    db.create_forwarding_rule(&rule(ForwardingKind::Webhook, &url, None, Some("*INVOICE*"))).await?;
+    db.create_forwarding_rule(&rule(ForwardingKind::Mailbox, &destination.id, Some("*@partner.example"), None)).await?;
+
+    let send = |from: &str, subject: &str| {
+        format!("From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\nHello.", from, source.get_address("test.com"), subject)
+    };
+    let deliver = |content: String| {
+        let service = service.clone();
+        let recipient = source.get_address("test.com");
+        async move {
+            service.process_incoming_email(content.as_bytes(), &recipient, "sender@example.com", "192.168.1.1".parse().unwrap()).await
+        }
+    };
+
+    // Matches neither rule
+    deliver(send("someone@example.com", "Hello")).await?;
+    // Matches the webhook rule only, case-insensitively
+    deliver(send("billing@example.com", "Your invoice #42")).await?;
+    // Matches the mailbox rule only
+    deliver(send("Partner <news@partner.example>", "Newsletter")).await?;
+
+    let forwarded_email = tokio::time::timeout(Duration::from_secs(5), forwarded.recv()).await?.unwrap();
+    assert_eq!(forwarded_email.mailbox_id, source.id);
+    assert_eq!(forwarded_email.subject, "Your invoice #42");
+    let body = String::from_utf8(decrypt_email(&forwarded_email.encrypted_content, TEST_SECRET_KEY)?)?;
+    assert!(body.contains("Subject: Your invoice #42"));
+
+    assert_eq!(service.get_mailbox_emails(&source.id).await?.len(), 3);
+    let copies = service.get_mailbox_emails(&destination.id).await?;
+    assert_eq!(copies.len(), 1);
+    assert_eq!(copies[0].subject, "Newsletter");
+    let body = decrypt_email(&copies[0].encrypted_content, destination_identity.to_string().expose_secret())?;
+    assert!(String::from_utf8(body)?.contains("Subject: Newsletter"));
+    assert!(decrypt_email(&copies[0].encrypted_content, TEST_SECRET_KEY).is_err());
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(forwarded.try_recv().is_err());
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_client_ip_behind_proxy() -> Result<()> {
+    let db = setup_test_db().await?;
+    let test_user = create_test_user(&db).await?;
+
+    let test_mailbox = Mailbox {
+        id: Uuid::new_v4().to_string(),
+        alias: "test".to_string(),
+        name: "Test Mailbox".to_string(),
+        public_key: TEST_PUBLIC_KEY.to_string(),
+        key_type: KeyType::AgeX25519,
+        owner_id: test_user.id,
+        created_at: chrono::Utc::now().timestamp(),
+        mail_expires_in: None,
+        organization_id: None,
+        strip_attachments: false,
+        alias_rotated_at: None,
+    };
+    db.create_mailbox(&test_mailbox).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 1,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(5),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec!["127.0.0.1/32".parse()?, "10.0.0.0/8".parse()?],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+    let proxy: IpAddr = "127.0.0.1".parse()?;
+    let recipient = test_mailbox.get_address("test.com");
+    let email = |received: &str| {
+        format!("{}X-Real-IP: 192.0.2.1\r\nFrom: sender@example.com\r\nTo: {}\r\nSubject: Proxied\r\n\r\n", received, recipient)
+    };
+    let received = |client: &str| format!("Received: from client.example ([{}]) by relay.example;\r\n\tMon, 1 Jan 2024 00:00:00 +0000\r\n", client);
+
+    assert!(service.is_trusted_proxy(proxy));
+    // Headers of the message other than Received never name the client
+    assert_eq!(service.resolve_client_ip(proxy, email("").as_bytes()), proxy);
+    assert_eq!(
+        service.resolve_client_ip(proxy, email("X-Forwarded-For: 198.51.100.1\r\n").as_bytes()),
+        proxy
+    );
+    assert_eq!(
+        service.resolve_client_ip(proxy, email(&received("198.51.100.1")).as_bytes()),
+        "198.51.100.1".parse::<IpAddr>()?
+    );
+    // Received headers are followed through trusted relays only, those below may be forged
+    assert_eq!(
+        service.resolve_client_ip(proxy, email(&format!("{}{}{}", received("10.0.0.2"), received("198.51.100.1"), received("192.0.2.1"))).as_bytes()),
+        "198.51.100.1".parse::<IpAddr>()?
+    );
+    // Other peers can't name a client
+    let peer: IpAddr = "203.0.113.5".parse()?;
+    assert_eq!(service.resolve_client_ip(peer, email(&received("198.51.100.2")).as_bytes()), peer);
+
+    // Each client relayed by the proxy is rate limited on its own
+    let deliver = |client: &'static str| {
+        let raw_email = email(&received(client));
+        let service = &service;
+        let recipient = &recipient;
+        async move {
+            let client_ip = service.resolve_client_ip(proxy, raw_email.as_bytes());
+            service.process_incoming_email(raw_email.as_bytes(), recipient, "sender@example.com", client_ip).await
+        }
+    };
+    deliver("198.51.100.1").await?;
+    let result = deliver("198.51.100.1").await;
+    assert!(matches!(result, Err(e) if e.to_string().contains("Rate limit exceeded")));
+    deliver("198.51.100.2").await?;
+    assert!(service.check_rate_limit(proxy));
+    assert_eq!(service.get_mailbox_emails(&test_mailbox.id).await?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_telegram_notification() -> Result<()> {
+    use mail_service::notifications::TelegramNotifier;
+    use wiremock::{matchers::{method, path}, Mock, MockServer, ResponseTemplate};
+
+    let telegram = MockServer::start().await;
+    Mock::given(method("POST"))
+        .and(path("/bottest-token/sendMessage"))
+        .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "ok": true })))
+        .mount(&telegram)
+        .await;
+
+    let db = setup_test_db().await?;
+    let mut mailboxes = Vec::new();
+    // Notified, opted out, and without Telegram
+    for (username, chat_id, notifications) in [("notified", Some("4242"), true), ("opted_out", Some("4343"), false), ("no_telegram", None, true)] {
+        let user = db.create_user(username, AuthType::Password).await?;
+        sqlx::query("INSERT INTO user_credentials (user_id, created_at, updated_at, telegram_id, telegram_chat_id) VALUES (?, 0, 0, ?, ?)")
+            .bind(&user.id)
+            .bind(chat_id)
+            .bind(chat_id)
+            .execute(db.pool())
+            .await?;
+        db.update_user_settings(&UserSettings { email_notifications: notifications, ..UserSettings::new(&user.id) }).await?;
+        let mailbox = Mailbox {
+            id: Uuid::new_v4().to_string(),
+            alias: username.replace('_', ""),
+            name: format!("{} inbox", username),
+            public_key: TEST_PUBLIC_KEY.to_string(),
+            key_type: KeyType::AgeX25519,
+            owner_id: user.id,
+            created_at: chrono::Utc::now().timestamp(),
+            mail_expires_in: None,
+            organization_id: None,
+            strip_attachments: false,
+            alias_rotated_at: None,
+        };
+        db.create_mailbox(&mailbox).await?;
+        mailboxes.push(mailbox);
+    }
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 1000,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(5),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![])
+        .await?
+        .with_telegram_notifier(TelegramNotifier::new("test-token").with_api_url(telegram.uri()));
+
+    // Two emails to each mailbox, the second within the cooldown
+    for mailbox in &mailboxes {
+        for _ in 0..2 {
+            let email_content = format!("From: sender@example.com\r\nTo: {}@test.com\r\nSubject: Hello\r\n\r\nHello.", mailbox.alias);
+            service.process_incoming_email(
+                email_content.as_bytes(),
+                &mailbox.get_address("test.com"),
+                "sender@example.com",
+                "192.0.2.1".parse()?,
+            ).await?;
+        }
+        assert_eq!(service.get_mailbox_emails(&mailbox.id).await?.len(), 2);
+    }
+
+    // Notifications are sent in the background
+    let mut requests = Vec::new();
+    for _ in 0..50 {
+        requests = telegram.received_requests().await.unwrap_or_default();
+        if !requests.is_empty() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let requests = telegram.received_requests().await.unwrap_or(requests);
+    assert_eq!(requests.len(), 1);
+    assert_eq!(
+        requests[0].body_json::<serde_json::Value>()?,
+        serde_json::json!({ "chat_id": "4242", "text": "New email received in mailbox notified inbox" })
+    );
+
+    // Another email is notified once the cooldown is over
+    sqlx::query("UPDATE mailboxes SET last_notification_sent_at = last_notification_sent_at - 60").execute(db.pool()).await?;
+    service.process_incoming_email(
+        format!("From: sender@example.com\r\nTo: {}@test.com\r\nSubject: Again\r\n\r\nHello.", mailboxes[0].alias).as_bytes(),
+        &mailboxes[0].get_address("test.com"),
+        "sender@example.com",
+        "192.0.2.1".parse()?,
+    ).await?;
+    let mut requests = Vec::new();
+    for _ in 0..50 {
+        requests = telegram.received_requests().await.unwrap_or_default();
+        if requests.len() > 1 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(requests.len(), 2);
+    assert_eq!(requests[1].body_json::<serde_json::Value>()?["chat_id"], "4242");
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
 export interface FetchOptions extends RequestInit {
   requireAuth?: boolean;
+  // Set on the retry after refreshing the session, so it happens only once
+  skipRefresh?: boolean;
 }
 
 export interface ApiResponse<T = any> {
@@ -48,10 +50,55 @@ export function setAuthToken(token: string): void {
 // Function to remove the JWT token from localStorage
 export function removeAuthToken(): void {
   localStorage.removeItem('auth_token');
+  localStorage.removeItem('refresh_token');
+}
+
+export function getRefreshToken(): string | null {
+  return localStorage.getItem('refresh_token');
+}
+
+export function setRefreshToken(token: string): void {
+  localStorage.setItem('refresh_token', token);
+}
+
+let refreshing: Promise<boolean> | null = null;
+
+// Exchanges the refresh token for a new access token. Concurrent callers share
+// one request, since each refresh token can only be used once.
+export function refreshSession(): Promise<boolean> {
+  if (!refreshing) {
+    refreshing = (async () => {
+      const refreshToken = getRefreshToken();
+      if (!refreshToken) {
+        return false;
+      }
+      try {
+        const response = await fetch('/api/auth/refresh', {
+          method: 'POST',
+          headers: { 'Content-Type': 'application/json', 'Accept': 'application/json' },
+          body: JSON.stringify({ refresh_token: refreshToken }),
+          credentials: 'same-origin',
+        });
+        const data = await response.json();
+        if (!response.ok || !data.success) {
+          removeAuthToken();
+          return false;
+        }
+        setAuthToken(data.data.token);
+        setRefreshToken(data.data.refresh_token);
+        return true;
+      } catch {
+        return false;
+      }
+    })().finally(() => {
+      refreshing = null;
+    });
+  }
+  return refreshing;
 }
 
 export async function fetchApi<T = any>(endpoint: string, options: FetchOptions = {}): Promise<ApiResponse<T>> {
-  const { requireAuth = true, headers = {}, ...rest } = options;
+  const { requireAuth = true, skipRefresh = false, headers = {}, ...rest } = options;
 
   const defaultHeaders: Record<string, string> = {
     'Content-Type': 'application/json',
@@ -86,6 +133,11 @@ export async function fetchApi<T = any>(endpoint: string, options: FetchOptions
       credentials: 'same-origin',
     });
 
+    // Access tokens are short-lived, retry once with a refreshed one
+    if (response.status === 401 && requireAuth && !skipRefresh && (await refreshSession())) {
+      return fetchApi<T>(endpoint, { ...options, skipRefresh: true });
+    }
+
     const contentType = response.headers.get('content-type');
     const isJson = contentType && contentType.includes('application/json');
 
@@ -158,19 +210,29 @@ export async function patch<T = any>(endpoint: string, data: any, options: Fetch
   });
 }
 
+export type ApiKeyScope = 'read_emails' | 'delete_emails' | 'manage_mailboxes';
+
 export interface ApiKey {
   id: string;
   key: string;
   created_at: number;
   expires_at: number | null;
+  scopes: ApiKeyScope[];
 }
 
 export async function listApiKeys(): Promise<ApiResponse<ApiKey[]>> {
   return get<ApiKey[]>('/api/api-keys');
 }
 
-export async function createApiKey(): Promise<ApiResponse<ApiKey>> {
-  return post<ApiKey>('/api/api-keys', {});
+export async function createApiKey(
+  scopes?: ApiKeyScope[],
+  expiresInSeconds?: number
+): Promise<ApiResponse<ApiKey>> {
+  return post<ApiKey>('/api/api-keys', { scopes, expires_in_seconds: expiresInSeconds });
+}
+
+export async function getApiKey(keyId: string): Promise<ApiResponse<ApiKey>> {
+  return get<ApiKey>(`/api/api-keys/${keyId}`);
 }
 
 export async function deleteApiKey(keyId: string): Promise<ApiResponse<void>> {
@@ -1,5 +1,5 @@
 import { writable, get as getStore } from 'svelte/store';
-import { get, post } from '$lib/api';
+import { get, post, getRefreshToken, removeAuthToken, setAuthToken, setRefreshToken } from '$lib/api';
 import { goto } from '$app/navigation';
 
 export interface User {
@@ -18,17 +18,23 @@ function createAuthStore() {
       set(user);
     },
     logout: async () => {
-      localStorage.removeItem('auth_token');
+      const refreshToken = getRefreshToken();
+      if (refreshToken) {
+        await post('/api/auth/logout', { refresh_token: refreshToken }, { requireAuth: false }).catch(() => {});
+      }
+      removeAuthToken();
       set(null);
       await goto('/');
     },
-    async login(token: string, user: User) {
-      localStorage.setItem('auth_token', token);
+    async login(token: string, refreshToken: string, user: User) {
+      setAuthToken(token);
+      setRefreshToken(refreshToken);
       set(user);
       await goto('/mailboxes');
     },
-    async register(token: string, user: User) {
-      localStorage.setItem('auth_token', token);
+    async register(token: string, refreshToken: string, user: User) {
+      setAuthToken(token);
+      setRefreshToken(refreshToken);
       set(user);
       await goto('/mailboxes');
     },
@@ -42,7 +48,7 @@ function createAuthStore() {
         set(response.data);
         return response.data;
       } catch (e: any) {
-        localStorage.removeItem('auth_token');
+        removeAuthToken();
         set(null);
         return null;
       }
@@ -0,0 +1,301 @@
+// Without the `admin-api` feature only the shutdown endpoint is routed
+
+use axum::{
+    extract::{Json, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+#[cfg(feature = "admin-api")]
+use axum::{
+    body::Body,
+    extract::{Path, Query},
+    http::Request,
+    middleware::Next,
+};
+use common::db::Database;
+#[cfg(feature = "admin-api")]
+use common::{
+    feature_flags::KNOWN_FLAGS,
+    greylist::{GreylistEntry, GreylistFilter},
+    Bounce, FeatureFlag, Organization,
+};
+#[cfg(feature = "admin-api")]
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+#[cfg(feature = "admin-api")]
+use std::net::IpAddr;
+use tracing::warn;
+#[cfg(feature = "admin-api")]
+use tracing::{error, info};
+
+use crate::{ApiResponse, AppState};
+
+#[cfg(feature = "admin-api")]
+pub mod users;
+
+#[cfg(feature = "admin-api")]
+const DEFAULT_GREYLIST_PAGE_SIZE: usize = 50;
+#[cfg(feature = "admin-api")]
+const MAX_GREYLIST_PAGE_SIZE: usize = 500;
+
+/// Admin routes are authenticated with `ADMIN_SECRET` in the `X-Admin-Secret`
+/// header rather than a user token, and are disabled while it is unset
+#[cfg(feature = "admin-api")]
+pub async fn require_admin<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if state.admin_secret.is_none() {
+        return (StatusCode::FORBIDDEN, "Admin API is disabled").into_response();
+    }
+
+    if !has_admin_secret(&state, req.headers()) {
+        warn!("Rejected admin request to {}", req.uri().path());
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    next.run(req).await
+}
+
+/// Whether the request carries `ADMIN_SECRET`, never the case while it is unset
+#[cfg(feature = "admin-api")]
+fn has_admin_secret<D: Database>(state: &AppState<D>, headers: &HeaderMap) -> bool {
+    let Some(admin_secret) = state.admin_secret.as_deref() else {
+        return false;
+    };
+
+    let provided = headers
+        .get("X-Admin-Secret")
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    // Compare digests so the check does not leak the secret's prefix through timing
+    Sha256::digest(provided) == Sha256::digest(admin_secret)
+}
+
+/// Starts a graceful shutdown, like SIGTERM. Authenticated with `ADMIN_TOKEN`
+/// as a bearer token rather than `ADMIN_SECRET`, so it can be given to the
+/// orchestrator without the rest of the admin API.
+pub async fn shutdown<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    headers: HeaderMap,
+) -> Response {
+    let Some(admin_token) = state.admin_token.as_deref() else {
+        return (StatusCode::FORBIDDEN, "Shutdown endpoint is disabled").into_response();
+    };
+
+    let provided = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .unwrap_or_default();
+    if Sha256::digest(provided) != Sha256::digest(admin_token) {
+        warn!("Rejected shutdown request");
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    }
+
+    warn!("Shutdown requested through the admin API");
+    state.shutdown.cancel();
+    Json(ApiResponse::success(())).into_response()
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Deserialize)]
+pub struct GreylistQuery {
+    ip: Option<IpAddr>,
+    sender_domain: Option<String>,
+    limit: Option<usize>,
+    #[serde(default)]
+    offset: usize,
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Serialize)]
+pub struct GreylistPage {
+    entries: Vec<GreylistEntry>,
+    total: usize,
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Serialize)]
+pub struct GreylistFlushResponse {
+    removed: usize,
+}
+
+#[cfg(feature = "admin-api")]
+pub async fn list_greylist<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Query(query): Query<GreylistQuery>,
+) -> Result<Json<ApiResponse<GreylistPage>>, StatusCode> {
+    let filter = GreylistFilter {
+        ip: query.ip,
+        sender_domain: query.sender_domain,
+    };
+    let limit = query.limit
+        .unwrap_or(DEFAULT_GREYLIST_PAGE_SIZE)
+        .clamp(1, MAX_GREYLIST_PAGE_SIZE);
+
+    let (entries, total) = state.greylist.list(&filter, query.offset, limit);
+    Ok(Json(ApiResponse::success(GreylistPage { entries, total })))
+}
+
+#[cfg(feature = "admin-api")]
+pub async fn delete_greylist_entry<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(entry_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let Some(key) = state.greylist.key_by_id(&entry_id) else {
+        return Ok(Json(ApiResponse::error("Greylist entry not found")));
+    };
+    // Otherwise the stored entry would defer the triple again
+    state.db.greylist_remove(&key.ip.to_string(), &key.sender, &key.recipient).await.map_err(|e| {
+        error!("Database error while removing greylist entry: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    state.greylist.remove(&key);
+    info!("Admin removed greylist entry {}", entry_id);
+    Ok(Json(ApiResponse::success(())))
+}
+
+#[cfg(feature = "admin-api")]
+pub async fn flush_greylist<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+) -> Result<Json<ApiResponse<GreylistFlushResponse>>, StatusCode> {
+    state.db.greylist_cleanup(i64::MAX).await.map_err(|e| {
+        error!("Database error while flushing greylist: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let removed = state.greylist.clear();
+    info!("Admin flushed {} greylist entries", removed);
+    Ok(Json(ApiResponse::success(GreylistFlushResponse { removed })))
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Deserialize)]
+pub struct BouncesQuery {
+    recipient: Option<String>,
+    permanent: Option<bool>,
+}
+
+/// Bounces recorded from incoming DSNs, newest first
+#[cfg(feature = "admin-api")]
+pub async fn list_bounces<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Query(query): Query<BouncesQuery>,
+) -> Result<Json<ApiResponse<Vec<Bounce>>>, StatusCode> {
+    match state.db.get_bounces(query.recipient.as_deref(), query.permanent).await {
+        Ok(bounces) => Ok(Json(ApiResponse::success(bounces))),
+        Err(e) => {
+            error!("Failed to list bounces: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Deserialize)]
+pub struct UpdateOrganizationRequest {
+    /// `null` lifts the limit
+    max_mailboxes: Option<i64>,
+}
+
+/// Sets the organization's mailbox quota, which its members can't change
+#[cfg(feature = "admin-api")]
+pub async fn update_organization<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(org_id): Path<String>,
+    Json(req): Json<UpdateOrganizationRequest>,
+) -> Result<Json<ApiResponse<Organization>>, StatusCode> {
+    if req.max_mailboxes.is_some_and(|max| max < 0) {
+        return Ok(Json(ApiResponse::error("Mailbox limit cannot be negative")));
+    }
+
+    let database_error = |e| {
+        error!("Database error while updating organization {}: {}", org_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    if !state.db.set_organization_max_mailboxes(&org_id, req.max_mailboxes).await.map_err(database_error)? {
+        return Ok(Json(ApiResponse::error("Organization not found")));
+    }
+
+    let organization = state.db.get_organization(&org_id).await.map_err(database_error)?;
+    info!("Admin set the mailbox limit of organization {} to {:?}", org_id, req.max_mailboxes);
+    Ok(Json(organization.map_or_else(|| ApiResponse::error("Organization not found"), ApiResponse::success)))
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Serialize)]
+pub struct FeatureFlagStatus {
+    name: &'static str,
+    /// `None` while the mail service uses its configured value
+    enabled: Option<bool>,
+    updated_by: Option<String>,
+    updated_at: Option<i64>,
+}
+
+#[cfg(feature = "admin-api")]
+#[derive(Debug, Deserialize)]
+pub struct UpdateFeatureFlagRequest {
+    enabled: bool,
+    /// Recorded with the change, since the admin secret doesn't identify anyone
+    updated_by: Option<String>,
+}
+
+#[cfg(feature = "admin-api")]
+pub async fn list_feature_flags<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+) -> Result<Json<ApiResponse<Vec<FeatureFlagStatus>>>, StatusCode> {
+    let overrides = match state.db.get_feature_flags().await {
+        Ok(overrides) => overrides,
+        Err(e) => {
+            error!("Failed to list feature flags: {}", e);
+            return Ok(Json(ApiResponse::error(e.to_string())));
+        }
+    };
+
+    let flags = KNOWN_FLAGS
+        .iter()
+        .map(|&name| {
+            let flag = overrides.iter().find(|flag| flag.flag_name == name);
+            FeatureFlagStatus {
+                name,
+                enabled: flag.map(|flag| flag.enabled),
+                updated_by: flag.and_then(|flag| flag.updated_by.clone()),
+                updated_at: flag.map(|flag| flag.updated_at),
+            }
+        })
+        .collect();
+    Ok(Json(ApiResponse::success(flags)))
+}
+
+/// The mail service picks the change up on its next poll
+#[cfg(feature = "admin-api")]
+pub async fn update_feature_flag<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(name): Path<String>,
+    Json(req): Json<UpdateFeatureFlagRequest>,
+) -> Result<Json<ApiResponse<FeatureFlag>>, StatusCode> {
+    if !KNOWN_FLAGS.contains(&name.as_str()) {
+        return Ok(Json(ApiResponse::error(format!("Unknown feature flag: {}", name))));
+    }
+
+    let flag = FeatureFlag {
+        flag_name: name,
+        enabled: req.enabled,
+        updated_by: req.updated_by,
+        updated_at: chrono::Utc::now().timestamp(),
+    };
+    if let Err(e) = state.db.set_feature_flag(&flag).await {
+        error!("Failed to update feature flag {}: {}", flag.flag_name, e);
+        return Ok(Json(ApiResponse::error(e.to_string())));
+    }
+
+    info!(
+        "Admin {} feature flag {}",
+        if flag.enabled { "enabled" } else { "disabled" },
+        flag.flag_name
+    );
+    Ok(Json(ApiResponse::success(flag)))
+}
@@ -0,0 +1,208 @@
+use axum::{
+    extract::{Json, Path, Query, State},
+    http::StatusCode,
+};
+use common::{db::Database, InstanceStats, MailboxSummary, User, UserSettings};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc};
+use tracing::{error, info, warn};
+
+use crate::{audit, nullable, ApiResponse, AppState, RevokeApiKeysResponse};
+
+const DEFAULT_USER_PAGE_SIZE: i64 = 50;
+const MAX_USER_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub struct UserListQuery {
+    /// Matches anywhere in the username
+    search: Option<String>,
+    /// Counts from 1
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UserPage {
+    users: Vec<User>,
+    total: i64,
+    page: i64,
+    per_page: i64,
+}
+
+pub async fn list_users<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Query(query): Query<UserListQuery>,
+) -> Result<Json<ApiResponse<UserPage>>, StatusCode> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page
+        .unwrap_or(DEFAULT_USER_PAGE_SIZE)
+        .clamp(1, MAX_USER_PAGE_SIZE);
+    let search = query.search.as_deref().filter(|search| !search.is_empty());
+
+    let (users, total) = state.db.list_users(search, page, per_page).await.map_err(|e| {
+        error!("Database error while listing users: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(ApiResponse::success(UserPage { users, total, page, per_page })))
+}
+
+/// Deletes the user and everything they own, signing them out on this instance
+pub async fn delete_user<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(user_id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let deleted = state.db.delete_user(&user_id).await.map_err(|e| {
+        error!("Database error while deleting user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if !deleted {
+        return Ok(Json(ApiResponse::error("User not found")));
+    }
+
+    state.token_owners.insert(&user_id, None);
+    warn!("Admin deleted user {}", user_id);
+    Ok(Json(ApiResponse::success(())))
+}
+
+pub async fn list_user_mailboxes<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(user_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<MailboxSummary>>>, StatusCode> {
+    let user = state.db.get_user(&user_id).await.map_err(|e| {
+        error!("Database error while fetching user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    if user.is_none() {
+        return Ok(Json(ApiResponse::error("User not found")));
+    }
+
+    let database_error = |e| {
+        error!("Database error while listing mailboxes of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let mailboxes = state.db.get_mailboxes_by_owner(&user_id).await.map_err(database_error)?;
+    let ids: Vec<String> = mailboxes.iter().map(|mailbox| mailbox.id.clone()).collect();
+    let counts = state.db.get_mailboxes_email_counts(&ids).await.map_err(database_error)?;
+    let mailboxes = mailboxes
+        .into_iter()
+        .map(|mailbox| MailboxSummary { email_count: counts.get(&mailbox.id).copied().unwrap_or_default(), mailbox })
+        .collect();
+    Ok(Json(ApiResponse::success(mailboxes)))
+}
+
+/// Banned users can't sign in again, and their tokens and API keys are refused
+pub async fn ban_user<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(user_id): Path<String>,
+) -> Result<Json<ApiResponse<User>>, StatusCode> {
+    let database_error = |e| {
+        error!("Database error while banning user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    if !state.db.ban_user(&user_id, chrono::Utc::now().timestamp()).await.map_err(database_error)? {
+        return Ok(Json(ApiResponse::error("User not found")));
+    }
+
+    state.token_owners.invalidate(&user_id);
+
+    let user = state.db.get_user(&user_id).await.map_err(database_error)?;
+    info!("Admin banned user {}", user_id);
+    Ok(Json(user.map_or_else(|| ApiResponse::error("User not found"), ApiResponse::success)))
+}
+
+/// Revokes every API key of the user, for when the account is compromised
+pub async fn revoke_user_api_keys<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    request_info: audit::RequestInfo,
+    Path(user_id): Path<String>,
+) -> Result<Json<ApiResponse<RevokeApiKeysResponse>>, StatusCode> {
+    let database_error = |e| {
+        error!("Database error while revoking API keys of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    if state.db.get_user(&user_id).await.map_err(database_error)?.is_none() {
+        return Ok(Json(ApiResponse::error("User not found")));
+    }
+
+    let revoked = state.db.revoke_user_api_keys(&user_id).await.map_err(database_error)?;
+    warn!(target: "security", "Admin revoked all API keys of user {} ({} revoked)", user_id, revoked);
+    let metadata = serde_json::json!({ "revoked": revoked, "by_admin": true });
+    request_info.audit(&state.db, &user_id, audit::REVOKE_API_KEYS, None, Some(metadata)).await;
+    Ok(Json(ApiResponse::success(RevokeApiKeysResponse { revoked })))
+}
+
+/// Fields left out are unchanged, and `null` lifts the limit
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateLimitsRequest {
+    #[serde(default, deserialize_with = "nullable")]
+    email_count_limit: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    total_storage_bytes_limit: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    api_rate_limit_per_minute: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    max_mailboxes: Option<Option<u64>>,
+}
+
+/// Sets the quotas and limits kept in the user's settings, which users can't
+/// change themselves
+pub async fn update_user_limits<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(user_id): Path<String>,
+    Json(req): Json<UpdateLimitsRequest>,
+) -> Result<Json<ApiResponse<UserSettings>>, StatusCode> {
+    let database_error = |e| {
+        error!("Database error while updating limits of user {}: {}", user_id, e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    };
+    let negative = [req.email_count_limit, req.total_storage_bytes_limit, req.api_rate_limit_per_minute]
+        .into_iter()
+        .any(|limit| matches!(limit, Some(Some(limit)) if limit < 0));
+    if negative {
+        return Ok(Json(ApiResponse::error("Limits can't be negative")));
+    }
+    if state.db.get_user(&user_id).await.map_err(database_error)?.is_none() {
+        return Ok(Json(ApiResponse::error("User not found")));
+    }
+
+    let mut settings = state.db.get_user_settings(&user_id).await.map_err(database_error)?
+        .unwrap_or_else(|| UserSettings::new(&user_id));
+    if let Some(email_count_limit) = req.email_count_limit {
+        settings.email_count_limit = email_count_limit;
+    }
+    if let Some(total_storage_bytes_limit) = req.total_storage_bytes_limit {
+        settings.total_storage_bytes_limit = total_storage_bytes_limit;
+    }
+    if let Some(api_rate_limit_per_minute) = req.api_rate_limit_per_minute {
+        settings.api_rate_limit_per_minute = api_rate_limit_per_minute;
+    }
+    if let Some(max_mailboxes) = req.max_mailboxes {
+        settings.max_mailboxes = max_mailboxes;
+    }
+
+    state.db.update_user_settings(&settings).await.map_err(database_error)?;
+    info!("Admin changed the limits of user {}", user_id);
+    Ok(Json(ApiResponse::success(settings)))
+}
+
+pub async fn get_stats<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+) -> Result<Json<ApiResponse<InstanceStats>>, StatusCode> {
+    let stats = state.db.get_instance_stats().await.map_err(|e| {
+        error!("Database error while computing instance stats: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(ApiResponse::success(stats)))
+}
+
+/// Logins per country code, from logins located through GeoIP
+pub async fn get_login_geography<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+) -> Result<Json<ApiResponse<HashMap<String, u64>>>, StatusCode> {
+    let geography = state.db.get_login_geography().await.map_err(|e| {
+        error!("Database error while computing login geography: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    Ok(Json(ApiResponse::success(geography)))
+}
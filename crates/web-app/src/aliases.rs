@@ -0,0 +1,220 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+};
+use common::{db::Database, generate_random_id, AppError, IdCharset, Mailbox, MailboxAlias};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, can_access_mailbox, get_managed_mailbox, ApiResponse, AppState};
+
+const MAX_ALIAS_LENGTH: usize = 64;
+const MIN_MAILBOX_ALIAS_LENGTH: usize = 4;
+/// How long mail to a rotated-away alias is refused instead of bounced
+const RETIRED_ALIAS_SECS: i64 = 24 * 60 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateAliasRequest {
+    alias: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMailboxAliasRequest {
+    alias: String,
+}
+
+/// Incoming local parts are reduced to ASCII alphanumerics before lookup,
+/// so any other character would make the alias unreachable
+fn normalize_alias(alias: &str) -> Result<String, AppError> {
+    let alias = alias.trim();
+    if alias.is_empty() || alias.len() > MAX_ALIAS_LENGTH {
+        return Err(AppError::Mail(format!("Alias must be 1 to {} characters long", MAX_ALIAS_LENGTH)));
+    }
+    if !alias.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(AppError::Mail("Alias may only contain letters and digits".into()));
+    }
+    Ok(alias.to_ascii_lowercase())
+}
+
+/// Validates a chosen primary alias. Unlike secondary aliases these may
+/// contain `-`, `_`, `+` and `.`, as the mail service matches the full local
+/// part before normalizing it.
+pub(crate) fn normalize_mailbox_alias(alias: &str) -> Result<String, AppError> {
+    let alias = alias.trim();
+    if !(MIN_MAILBOX_ALIAS_LENGTH..=MAX_ALIAS_LENGTH).contains(&alias.len()) {
+        return Err(AppError::Mail(format!(
+            "Alias must be {} to {} characters long",
+            MIN_MAILBOX_ALIAS_LENGTH, MAX_ALIAS_LENGTH
+        )));
+    }
+    if !alias.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '.')) {
+        return Err(AppError::Mail("Alias may only contain letters, digits, '-', '_', '+' and '.'".into()));
+    }
+    Ok(alias.to_ascii_lowercase())
+}
+
+pub async fn list_aliases<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<MailboxAlias>>>, StatusCode> {
+    let result: Result<Vec<MailboxAlias>, AppError> = async {
+        let mailbox = state.db.get_mailbox(&mailbox_id).await?
+            .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+        if !can_access_mailbox(&state, &mailbox, &claims.sub).await? {
+            return Err(AppError::Auth("You do not have permission to access this mailbox".into()));
+        }
+        state.db.get_mailbox_aliases(&mailbox_id).await
+    }.await;
+
+    match result {
+        Ok(aliases) => Ok(Json(ApiResponse::success(aliases))),
+        Err(e) => {
+            error!("Failed to list mailbox aliases: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn create_alias<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    Json(req): Json<CreateAliasRequest>,
+) -> Result<Json<ApiResponse<MailboxAlias>>, StatusCode> {
+    let result: Result<MailboxAlias, AppError> = async {
+        let alias = normalize_alias(&req.alias)?;
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "aliases").await?;
+
+        let existing = state.db.get_mailbox_aliases(&mailbox_id).await?;
+        if existing.len() >= state.max_aliases_per_mailbox {
+            return Err(AppError::Mail(format!(
+                "Mailbox has reached its limit of {} aliases",
+                state.max_aliases_per_mailbox
+            )));
+        }
+
+        let alias = MailboxAlias {
+            id: uuid::Uuid::new_v4().to_string(),
+            mailbox_id: mailbox_id.clone(),
+            alias,
+            created_at: chrono::Utc::now().timestamp(),
+            is_primary: false,
+        };
+        state.db.create_mailbox_alias(&alias).await.map_err(|e| {
+            if e.to_string().contains("UNIQUE constraint failed") {
+                AppError::Mail("This alias is already taken".into())
+            } else {
+                e
+            }
+        })?;
+        Ok(alias)
+    }.await;
+
+    match result {
+        Ok(alias) => Ok(Json(ApiResponse::success(alias))),
+        Err(e) => {
+            error!("Failed to create mailbox alias: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Changes the primary alias, i.e. the address shown for the mailbox
+pub async fn update_mailbox_alias<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    Json(req): Json<UpdateMailboxAliasRequest>,
+) -> Result<Json<ApiResponse<Mailbox>>, StatusCode> {
+    let result: Result<Mailbox, AppError> = async {
+        let alias = normalize_mailbox_alias(&req.alias)?;
+        let mut mailbox = get_managed_mailbox(&state, &mailbox_id, &claims.sub, "aliases").await?;
+
+        state.db.update_mailbox_alias(&mailbox_id, &alias).await.map_err(|e| {
+            if e.to_string().contains("UNIQUE constraint failed") {
+                AppError::Mail("This alias is already taken".into())
+            } else {
+                e
+            }
+        })?;
+        mailbox.alias = alias;
+        Ok(mailbox)
+    }.await;
+
+    match result {
+        Ok(mailbox) => Ok(Json(ApiResponse::success(mailbox))),
+        Err(e) => {
+            error!("Failed to update mailbox alias: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Replaces the primary alias with a random one, for when it attracts spam.
+/// Emails stay with the mailbox, and mail to the old alias is refused for a day.
+pub(crate) async fn rotate_mailbox_alias_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    mailbox_id: &str,
+) -> Result<Mailbox, AppError> {
+    let mut mailbox = get_managed_mailbox(state, mailbox_id, user_id, "aliases").await?;
+
+    let alias = generate_random_id(state.alias_length, IdCharset::VisuallyDistinct);
+    let now = chrono::Utc::now().timestamp();
+    state.db.rotate_mailbox_alias(mailbox_id, &alias, now, now + RETIRED_ALIAS_SECS).await.map_err(|e| {
+        if e.to_string().contains("UNIQUE constraint failed") {
+            AppError::Mail("Unable to generate a unique alias. Please try again".into())
+        } else {
+            e
+        }
+    })?;
+    mailbox.alias = alias;
+    mailbox.alias_rotated_at = Some(now);
+    Ok(mailbox)
+}
+
+pub async fn rotate_mailbox_alias<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<Mailbox>>, StatusCode> {
+    match rotate_mailbox_alias_for_user(&state, &claims.sub, &mailbox_id).await {
+        Ok(mailbox) => Ok(Json(ApiResponse::success(mailbox))),
+        Err(e) => {
+            error!("Failed to rotate mailbox alias: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn delete_alias<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, alias_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result: Result<(), AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "aliases").await?;
+
+        let alias = state.db.get_mailbox_aliases(&mailbox_id).await?
+            .into_iter()
+            .find(|alias| alias.id == alias_id)
+            .ok_or_else(|| AppError::NotFound("Alias not found".into()))?;
+        if alias.is_primary {
+            return Err(AppError::Mail("The primary alias cannot be deleted".into()));
+        }
+
+        state.db.delete_mailbox_alias(&mailbox_id, &alias_id).await?;
+        Ok(())
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("Failed to delete mailbox alias: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
@@ -1,15 +1,15 @@
 use schemars::JsonSchema;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::BTreeMap;
 
 #[derive(Serialize, JsonSchema)]
 struct SwaggerSpec {
     swagger: String,
     info: Info,
-    paths: HashMap<String, PathItem>,
-    definitions: HashMap<String, Schema>,
+    paths: BTreeMap<String, PathItem>,
+    definitions: BTreeMap<String, Schema>,
     #[serde(rename = "securityDefinitions")]
-    security_definitions: HashMap<String, SecurityScheme>,
+    security_definitions: BTreeMap<String, SecurityScheme>,
 }
 
 #[derive(Serialize, JsonSchema)]
@@ -24,6 +24,8 @@ struct PathItem {
     #[serde(skip_serializing_if = "Option::is_none")]
     get: Option<Operation>,
     #[serde(skip_serializing_if = "Option::is_none")]
+    post: Option<Operation>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     delete: Option<Operation>,
     parameters: Vec<Parameter>,
 }
@@ -32,8 +34,10 @@ struct PathItem {
 struct Operation {
     summary: String,
     description: String,
-    responses: HashMap<String, Response>,
-    security: Vec<HashMap<String, Vec<String>>>,
+    responses: BTreeMap<String, Response>,
+    security: Vec<BTreeMap<String, Vec<String>>>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    parameters: Vec<Parameter>,
 }
 
 #[derive(Serialize, JsonSchema)]
@@ -45,12 +49,14 @@ struct Parameter {
     required: bool,
     #[serde(rename = "type")]
     type_: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     format: Option<String>,
 }
 
 #[derive(Serialize, JsonSchema)]
 struct Response {
     description: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     schema: Option<Schema>,
 }
 
@@ -58,6 +64,7 @@ struct Response {
 struct Schema {
     #[serde(rename = "type")]
     type_: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
     format: Option<String>,
 }
 
@@ -71,7 +78,7 @@ struct SecurityScheme {
     location: String,
 }
 
-fn parse_doc_comment(doc: &str) -> (String, String, HashMap<String, String>) {
+fn parse_doc_comment(doc: &str) -> (String, String, BTreeMap<String, String>) {
     let lines = doc.lines()
         .map(|line| line.trim_start_matches("///").trim())
         .collect::<Vec<_>>();
@@ -94,13 +101,13 @@ fn parse_doc_comment(doc: &str) -> (String, String, HashMap<String, String>) {
     }
 
     // Parse sections (Returns:, Parameters:, etc.)
-    let mut sections = HashMap::new();
+    let mut sections = BTreeMap::new();
     let mut current_section = String::new();
     let mut current_content = String::new();
 
     while i < lines.len() {
         let line = lines[i].trim();
-        if line.contains(':') && (line.starts_with("Returns") || line.starts_with("Parameters") || line.starts_with("Authorization")) {
+        if line.contains(':') && (line.starts_with("Returns") || line.starts_with("Parameters") || line.starts_with("Query parameters") || line.starts_with("Authorization")) {
             if !current_section.is_empty() {
                 sections.insert(current_section.clone(), current_content.trim().to_string());
                 current_content.clear();
@@ -126,6 +133,15 @@ fn parse_doc_comment(doc: &str) -> (String, String, HashMap<String, String>) {
 }
 
 fn parse_parameters(params: &str) -> Vec<Parameter> {
+    parse_parameter_list(params, "path", true)
+}
+
+/// Optional query parameters, written as ``- `name` (type): description``
+fn parse_query_parameters(params: &str) -> Vec<Parameter> {
+    parse_parameter_list(params, "query", false)
+}
+
+fn parse_parameter_list(params: &str, location: &str, required: bool) -> Vec<Parameter> {
     params.lines()
         .filter(|line| line.starts_with('-'))
         .map(|line| {
@@ -133,6 +149,10 @@ fn parse_parameters(params: &str) -> Vec<Parameter> {
             let name = parts[0].trim();
             let description = parts.get(1).map(|s| s.trim()).unwrap_or_default();
 
+            let (name, type_) = match name.split_once(" (") {
+                Some((name, type_)) => (name.trim(), type_.trim_end_matches(')')),
+                None => (name, "string"),
+            };
             let name = if name.starts_with('`') && name.ends_with('`') {
                 &name[1..name.len() - 1]
             } else {
@@ -141,18 +161,18 @@ fn parse_parameters(params: &str) -> Vec<Parameter> {
             
             Parameter {
                 name: name.to_string(),
-                location: "path".to_string(),
+                location: location.to_string(),
                 description: description.to_string(),
-                required: true,
-                type_: "string".to_string(),
+                required,
+                type_: type_.to_string(),
                 format: None,
             }
         })
         .collect()
 }
 
-fn parse_responses(responses: &str) -> HashMap<String, Response> {
-    let mut map = HashMap::new();
+fn parse_responses(responses: &str) -> BTreeMap<String, Response> {
+    let mut map = BTreeMap::new();
     
     for line in responses.lines() {
         if let Some(line) = line.trim().strip_prefix('-') {
@@ -185,11 +205,11 @@ fn parse_responses(responses: &str) -> HashMap<String, Response> {
 }
 
 pub fn generate_spec() -> String {
-    let mut paths = HashMap::new();
-    let definitions = HashMap::new();
+    let mut paths = BTreeMap::new();
+    let definitions = BTreeMap::new();
 
     // Add security definitions
-    let mut security_definitions = HashMap::new();
+    let mut security_definitions = BTreeMap::new();
     security_definitions.insert(
         "apiKey".to_string(),
         SecurityScheme {
@@ -228,12 +248,108 @@ pub fn generate_spec() -> String {
         .last()
         .unwrap();
 
+    let api_get_email_headers_doc = lib_contents
+        .split("async fn api_get_email_headers")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
+    let api_get_email_raw_doc = lib_contents
+        .split("async fn api_get_email_raw")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
+    let api_rotate_mailbox_alias_doc = lib_contents
+        .split("async fn api_rotate_mailbox_alias")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
+    let api_create_mailbox_doc = lib_contents
+        .split("async fn api_create_mailbox")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
+    let api_remove_mailbox_doc = lib_contents
+        .split("async fn api_remove_mailbox")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
+    let api_delete_mailbox_emails_doc = lib_contents
+        .split("async fn api_delete_mailbox_emails")
+        .next()
+        .unwrap()
+        .split("// @APIDOC-START")
+        .last()
+        .unwrap();
+
     // Parse doc comments and generate paths
     let (list_summary, list_desc, list_sections) = parse_doc_comment(api_get_mailbox_emails_doc);
     let (get_summary, get_desc, get_sections) = parse_doc_comment(api_get_email_doc);
     let (delete_summary, delete_desc, delete_sections) = parse_doc_comment(api_delete_email_doc);
+    let (headers_summary, headers_desc, headers_sections) = parse_doc_comment(api_get_email_headers_doc);
+    let (raw_summary, raw_desc, raw_sections) = parse_doc_comment(api_get_email_raw_doc);
+    let (bulk_delete_summary, bulk_delete_desc, bulk_delete_sections) = parse_doc_comment(api_delete_mailbox_emails_doc);
+    let (rotate_summary, rotate_desc, rotate_sections) = parse_doc_comment(api_rotate_mailbox_alias_doc);
+    let (create_summary, create_desc, create_sections) = parse_doc_comment(api_create_mailbox_doc);
+    let (remove_summary, remove_desc, remove_sections) = parse_doc_comment(api_remove_mailbox_doc);
+
+    // Add mailbox creation path
+    paths.insert(
+        "/api/v1/mailboxes".to_string(),
+        PathItem {
+            get: None,
+            post: Some(Operation {
+                summary: create_summary,
+                description: create_desc,
+                responses: parse_responses(&create_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
+            }),
+            delete: None,
+            parameters: vec![],
+        },
+    );
+
+    // Add mailbox deletion path
+    paths.insert(
+        "/api/v1/mailboxes/{id}".to_string(),
+        PathItem {
+            get: None,
+            post: None,
+            delete: Some(Operation {
+                summary: remove_summary,
+                description: remove_desc,
+                responses: parse_responses(&remove_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
+            }),
+            parameters: parse_parameters(&remove_sections["Parameters"]),
+        },
+    );
 
-    // Add list emails path
+    // Add list and bulk delete emails path
     paths.insert(
         "/api/v1/mailboxes/{id}/emails".to_string(),
         PathItem {
@@ -242,12 +358,24 @@ pub fn generate_spec() -> String {
                 description: list_desc,
                 responses: parse_responses(&list_sections["Returns"]),
                 security: vec![{
-                    let mut security = HashMap::new();
+                    let mut security = BTreeMap::new();
                     security.insert("apiKey".to_string(), vec![]);
                     security
                 }],
+                parameters: list_sections.get("Query parameters").map_or_else(Vec::new, |params| parse_query_parameters(params)),
+            }),
+            post: None,
+            delete: Some(Operation {
+                summary: bulk_delete_summary,
+                description: bulk_delete_desc,
+                responses: parse_responses(&bulk_delete_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
             }),
-            delete: None,
             parameters: parse_parameters(&list_sections["Parameters"]),
         },
     );
@@ -261,25 +389,91 @@ pub fn generate_spec() -> String {
                 description: get_desc,
                 responses: parse_responses(&get_sections["Returns"]),
                 security: vec![{
-                    let mut security = HashMap::new();
+                    let mut security = BTreeMap::new();
                     security.insert("apiKey".to_string(), vec![]);
                     security
                 }],
+                parameters: vec![],
             }),
+            post: None,
             delete: Some(Operation {
                 summary: delete_summary,
                 description: delete_desc,
                 responses: parse_responses(&delete_sections["Returns"]),
                 security: vec![{
-                    let mut security = HashMap::new();
+                    let mut security = BTreeMap::new();
                     security.insert("apiKey".to_string(), vec![]);
                     security
                 }],
+                parameters: vec![],
             }),
             parameters: parse_parameters(&get_sections["Parameters"]),
         },
     );
 
+    // Add email headers path
+    paths.insert(
+        "/api/v1/mailboxes/{mailbox_id}/emails/{email_id}/headers".to_string(),
+        PathItem {
+            get: Some(Operation {
+                summary: headers_summary,
+                description: headers_desc,
+                responses: parse_responses(&headers_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
+            }),
+            post: None,
+            delete: None,
+            parameters: parse_parameters(&headers_sections["Parameters"]),
+        },
+    );
+
+    // Add raw email download path
+    paths.insert(
+        "/api/v1/mailboxes/{mailbox_id}/emails/{email_id}/raw".to_string(),
+        PathItem {
+            get: Some(Operation {
+                summary: raw_summary,
+                description: raw_desc,
+                responses: parse_responses(&raw_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
+            }),
+            post: None,
+            delete: None,
+            parameters: parse_parameters(&raw_sections["Parameters"]),
+        },
+    );
+
+    // Add alias rotation path
+    paths.insert(
+        "/api/v1/mailboxes/{id}/rotate-alias".to_string(),
+        PathItem {
+            get: None,
+            post: Some(Operation {
+                summary: rotate_summary,
+                description: rotate_desc,
+                responses: parse_responses(&rotate_sections["Returns"]),
+                security: vec![{
+                    let mut security = BTreeMap::new();
+                    security.insert("apiKey".to_string(), vec![]);
+                    security
+                }],
+                parameters: vec![],
+            }),
+            delete: None,
+            parameters: parse_parameters(&rotate_sections["Parameters"]),
+        },
+    );
+
     let spec = SwaggerSpec {
         swagger: "2.0".to_string(),
         info: Info {
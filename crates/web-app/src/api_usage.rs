@@ -0,0 +1,192 @@
+use axum::{
+    body::Body,
+    extract::{Json, MatchedPath, Path, Query, State},
+    http::{header, Request, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use common::{db::Database, ApiKeyEndpointUsage, ApiKeyUsage, ApiKeyUsageBucket};
+use serde::Deserialize;
+use std::{sync::Arc, time::Instant};
+use tracing::{error, warn};
+
+use crate::{auth::Claims, client_ip::client_ip, geoip, ApiResponse, AppState};
+
+const TOP_ENDPOINTS_LIMIT: i64 = 10;
+const MAX_BUCKETS: i64 = 1000;
+
+#[derive(Debug, Deserialize)]
+pub struct UsageQuery {
+    granularity: Option<String>,
+    period: Option<String>,
+}
+
+/// Parses durations like `12h` or `7d` into seconds
+fn parse_duration(value: &str) -> Option<i64> {
+    let value = value.trim();
+    let split = value.len().checked_sub(1).filter(|i| value.is_char_boundary(*i))?;
+    let (amount, unit) = value.split_at(split);
+    let amount: i64 = amount.parse().ok().filter(|n| *n > 0)?;
+    let unit_seconds = match unit {
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_seconds)
+}
+
+/// Records every request made with an API key. The key is resolved once and
+/// handed to [`ApiClaims`](crate::api_auth::ApiClaims) through the request
+/// extensions; the insert runs in the background so it never delays the response.
+pub async fn track_api_key_usage<D: Database + 'static>(
+    State(state): State<Arc<AppState<D>>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let key = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(str::to_string);
+
+    let api_key = match key {
+        Some(key) => state.db.get_api_key(&key).await.unwrap_or_else(|e| {
+            warn!("Failed to resolve API key for usage tracking: {}", e);
+            None
+        }),
+        None => None,
+    };
+    let Some(api_key) = api_key else {
+        return next.run(req).await;
+    };
+    let api_key_id = api_key.id.clone();
+    req.extensions_mut().insert(api_key);
+
+    let endpoint = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map(|path| path.as_str().to_string())
+        .unwrap_or_else(|| req.uri().path().to_string());
+    let method = req.method().to_string();
+    let ip = client_ip(req.extensions());
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    let status_code = response.status().as_u16() as i64;
+    let response_ms = started.elapsed().as_millis() as i64;
+    let db = state.db.clone();
+    tokio::spawn(async move {
+        let location = ip.map(geoip::lookup).unwrap_or_default();
+        let usage = ApiKeyUsage {
+            api_key_id,
+            endpoint,
+            method,
+            status_code,
+            response_ms,
+            used_at: chrono::Utc::now().timestamp(),
+            country_code: location.country_code,
+            city: location.city,
+        };
+        if let Err(e) = db.record_api_key_usage(&usage).await {
+            warn!("Failed to record API key usage: {}", e);
+        }
+    });
+
+    response
+}
+
+async fn verify_api_key_owner<D: Database>(
+    state: &Arc<AppState<D>>,
+    key_id: &str,
+    user_id: &str,
+) -> Result<Option<&'static str>, StatusCode> {
+    let owner: Option<String> = sqlx::query_scalar("SELECT user_id FROM api_keys WHERE id = ?")
+        .bind(key_id)
+        .fetch_optional(state.db.pool())
+        .await
+        .map_err(|e| {
+            error!("Database error while verifying API key ownership: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        })?;
+
+    Ok(match owner {
+        Some(owner) if owner == user_id => None,
+        Some(_) => Some("You don't have permission to view this API key"),
+        None => Some("API key not found"),
+    })
+}
+
+pub async fn get_api_key_usage<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(key_id): Path<String>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<ApiResponse<Vec<ApiKeyUsageBucket>>>, StatusCode> {
+    if let Some(message) = verify_api_key_owner(&state, &key_id, &claims.sub).await? {
+        return Ok(Json(ApiResponse::error(message)));
+    }
+
+    let Some(granularity) = parse_duration(query.granularity.as_deref().unwrap_or("1d")) else {
+        return Ok(Json(ApiResponse::error("Invalid granularity, expected a value like 1h or 1d")));
+    };
+    let Some(period) = parse_duration(query.period.as_deref().unwrap_or("7d")) else {
+        return Ok(Json(ApiResponse::error("Invalid period, expected a value like 24h or 7d")));
+    };
+    if period / granularity > MAX_BUCKETS {
+        return Ok(Json(ApiResponse::error("Too many data points, use a coarser granularity")));
+    }
+
+    // Align buckets to the granularity so consecutive calls return stable buckets
+    let now = chrono::Utc::now().timestamp();
+    let until = now - now.rem_euclid(granularity) + granularity;
+    let since = until - period.max(granularity);
+
+    match state.db.get_api_key_usage(&key_id, since, until, granularity).await {
+        Ok(buckets) => Ok(Json(ApiResponse::success(buckets))),
+        Err(e) => {
+            error!("Database error while reading API key usage: {}", e);
+            Ok(Json(ApiResponse::error("Unable to retrieve API key usage. Please try again later")))
+        }
+    }
+}
+
+pub async fn get_api_key_top_endpoints<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(key_id): Path<String>,
+    Query(query): Query<UsageQuery>,
+) -> Result<Json<ApiResponse<Vec<ApiKeyEndpointUsage>>>, StatusCode> {
+    if let Some(message) = verify_api_key_owner(&state, &key_id, &claims.sub).await? {
+        return Ok(Json(ApiResponse::error(message)));
+    }
+
+    let Some(period) = parse_duration(query.period.as_deref().unwrap_or("30d")) else {
+        return Ok(Json(ApiResponse::error("Invalid period, expected a value like 24h or 7d")));
+    };
+    let since = chrono::Utc::now().timestamp() - period;
+
+    match state.db.get_api_key_top_endpoints(&key_id, since, TOP_ENDPOINTS_LIMIT).await {
+        Ok(endpoints) => Ok(Json(ApiResponse::success(endpoints))),
+        Err(e) => {
+            error!("Database error while reading API key endpoints: {}", e);
+            Ok(Json(ApiResponse::error("Unable to retrieve API key usage. Please try again later")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("1d"), Some(86400));
+        assert_eq!(parse_duration("12h"), Some(43200));
+        assert_eq!(parse_duration("0d"), None);
+        assert_eq!(parse_duration("7w"), None);
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("d"), None);
+    }
+}
@@ -0,0 +1,106 @@
+//! Audit log of security-sensitive operations: logins, password and account
+//! changes, API keys, mailboxes and their keys. Users can read their own entries.
+
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Json, Query, State},
+    http::{header, request::Parts},
+};
+use common::{db::Database, AppError, AuditEntry};
+use serde::Deserialize;
+use std::{convert::Infallible, net::IpAddr, sync::Arc};
+use tracing::error;
+
+use crate::{auth::Claims, client_ip::client_ip, ApiResponse, AppState};
+
+pub(crate) const LOGIN: &str = "login";
+pub(crate) const LOGIN_FAILED: &str = "login_failed";
+pub(crate) const REGISTER: &str = "register";
+pub(crate) const CONNECT_PROVIDER: &str = "connect_provider";
+pub(crate) const SET_PASSWORD: &str = "set_password";
+pub(crate) const CHANGE_PASSWORD: &str = "change_password";
+pub(crate) const CHANGE_USERNAME: &str = "change_username";
+pub(crate) const DELETE_ACCOUNT: &str = "delete_account";
+pub(crate) const REVOKE_SESSIONS: &str = "revoke_sessions";
+pub(crate) const CREATE_API_KEY: &str = "create_api_key";
+pub(crate) const DELETE_API_KEY: &str = "delete_api_key";
+pub(crate) const REVOKE_API_KEYS: &str = "revoke_api_keys";
+pub(crate) const CREATE_MAILBOX: &str = "create_mailbox";
+pub(crate) const DELETE_MAILBOX: &str = "delete_mailbox";
+pub(crate) const ADD_MAILBOX_KEY: &str = "add_mailbox_key";
+pub(crate) const REMOVE_MAILBOX_KEY: &str = "remove_mailbox_key";
+pub(crate) const NEW_LOGIN_COUNTRY: &str = "new_login_country";
+
+const DEFAULT_AUDIT_PAGE_SIZE: i64 = 50;
+const MAX_AUDIT_PAGE_SIZE: i64 = 100;
+
+/// Where a request came from, recorded with each audit entry
+pub(crate) struct RequestInfo {
+    ip: Option<IpAddr>,
+    user_agent: Option<String>,
+}
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for RequestInfo {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self {
+            ip: client_ip(&parts.extensions),
+            user_agent: parts
+                .headers
+                .get(header::USER_AGENT)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+        })
+    }
+}
+
+impl RequestInfo {
+    pub(crate) fn ip(&self) -> Option<IpAddr> {
+        self.ip
+    }
+
+    /// Appends an entry for the user. The operation already happened, so a
+    /// failed insert is logged rather than failing the request.
+    pub(crate) async fn audit<D: Database>(
+        &self,
+        db: &D,
+        user_id: &str,
+        action: &str,
+        resource: Option<(&str, &str)>,
+        metadata: Option<serde_json::Value>,
+    ) {
+        let entry = AuditEntry {
+            user_id: user_id.to_string(),
+            action: action.to_string(),
+            resource_type: resource.map(|(resource_type, _)| resource_type.to_string()),
+            resource_id: resource.map(|(_, resource_id)| resource_id.to_string()),
+            ip: self.ip.map(|ip| ip.to_string()),
+            user_agent: self.user_agent.clone(),
+            occurred_at: chrono::Utc::now().timestamp(),
+            metadata,
+        };
+        if let Err(e) = db.append_audit_log(&entry).await {
+            error!("Failed to append {} to the audit log of user {}: {}", action, user_id, e);
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AuditLogQuery {
+    /// Starts at 1
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+pub async fn list_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Query(query): Query<AuditLogQuery>,
+) -> Result<Json<ApiResponse<Vec<AuditEntry>>>, AppError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_AUDIT_PAGE_SIZE).clamp(1, MAX_AUDIT_PAGE_SIZE);
+    let entries = state.db.get_audit_log(&claims.sub, page, per_page).await?;
+    Ok(Json(ApiResponse::success(entries)))
+}
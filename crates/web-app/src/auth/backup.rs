@@ -0,0 +1,163 @@
+//! Passphrase based recovery for users who lose a mailbox private key.
+//!
+//! Setting up generates an age key pair whose identity is stored encrypted
+//! with the user's passphrase. Emails delivered afterwards to the user's
+//! mailboxes are also encrypted to its public key, so recovering with the
+//! passphrase can re-encrypt them to each mailbox's current public key.
+//! The server handles the identity in memory during both requests.
+
+use crate::{auth::Claims, ApiResponse, AppState};
+use age::secrecy::ExposeSecret;
+use axum::extract::{Json, State};
+use common::{db::Database, security, AppError, BackupKey, EmailAttachment, MailboxKey};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::{info, warn};
+
+const MIN_PASSPHRASE_LENGTH: usize = 12;
+
+#[derive(Debug, Deserialize)]
+pub struct BackupPassphraseRequest {
+    pub passphrase: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupSetupResponse {
+    pub public_key: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BackupRecoveryResponse {
+    /// Emails now encrypted to their mailbox's current public key
+    pub reencrypted: usize,
+    /// Emails that are not encrypted to the backup key, usually because they
+    /// were received before it was set up
+    pub skipped: usize,
+}
+
+/// The passphrase is stretched with scrypt, which takes about a second, and
+/// re-encrypting emails is as CPU bound
+async fn run_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> Result<T, AppError> + Send + 'static,
+) -> Result<T, AppError> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| AppError::Internal(format!("Backup key task failed: {}", e)))?
+}
+
+/// An email re-encrypted to its mailbox's keys
+struct ReencryptedEmail {
+    raw_email: Vec<u8>,
+    encrypted_content: String,
+    /// IDs and contents of the attachments that were encrypted to the backup key
+    attachments: Vec<(String, String)>,
+}
+
+/// `None` when the email is not encrypted to the backup key
+fn reencrypt_email(
+    identity: &str,
+    encrypted_content: &str,
+    attachments: Vec<EmailAttachment>,
+    keys: &[MailboxKey],
+    backup_public_key: &str,
+) -> Result<Option<ReencryptedEmail>, AppError> {
+    let Ok(raw_email) = security::decrypt_email(encrypted_content, identity) else {
+        return Ok(None);
+    };
+    let encrypted_content = security::encrypt_email_to_keys(&raw_email, keys, Some(backup_public_key))?;
+
+    let mut reencrypted_attachments = Vec::new();
+    for attachment in attachments {
+        let Ok(content) = security::decrypt_email(&attachment.encrypted_content, identity) else {
+            continue;
+        };
+        let encrypted_content = security::encrypt_email_to_keys(&content, keys, Some(backup_public_key))?;
+        reencrypted_attachments.push((attachment.id, encrypted_content));
+    }
+
+    Ok(Some(ReencryptedEmail { raw_email, encrypted_content, attachments: reencrypted_attachments }))
+}
+
+// Backup passphrase setup handler
+pub(super) async fn setup_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<BackupPassphraseRequest>,
+) -> Result<Json<ApiResponse<BackupSetupResponse>>, AppError> {
+    if req.passphrase.chars().count() < MIN_PASSPHRASE_LENGTH {
+        return Err(AppError::Auth(format!(
+            "Backup passphrase must be at least {} characters long",
+            MIN_PASSPHRASE_LENGTH
+        )));
+    }
+
+    let already_set_up = || AppError::Auth("A backup passphrase is already set up for this account.".to_string());
+    if state.db.get_backup_key(&claims.sub).await?.is_some() {
+        return Err(already_set_up());
+    }
+
+    let (public_key, encrypted_key) = run_blocking(move || security::generate_backup_key(&req.passphrase)).await?;
+    let backup_key = BackupKey { public_key, encrypted_key };
+    if !state.db.set_backup_key(&claims.sub, &backup_key).await? {
+        return Err(already_set_up());
+    }
+
+    info!("Backup key set up for user {}", claims.sub);
+    Ok(Json(ApiResponse::success(BackupSetupResponse { public_key: backup_key.public_key })))
+}
+
+// Backup passphrase recovery handler
+pub(super) async fn recover_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<BackupPassphraseRequest>,
+) -> Result<Json<ApiResponse<BackupRecoveryResponse>>, AppError> {
+    let backup_key = state.db.get_backup_key(&claims.sub).await?
+        .ok_or_else(|| AppError::NotFound("No backup passphrase has been set up for this account.".to_string()))?;
+
+    let encrypted_key = backup_key.encrypted_key.clone();
+    let identity = run_blocking(move || security::decrypt_backup_key(&encrypted_key, &req.passphrase))
+        .await
+        .inspect_err(|_| warn!("Failed backup recovery attempt for user {}", claims.sub))?;
+
+    let identity = Arc::new(identity);
+    let mut reencrypted = 0;
+    let mut skipped = 0;
+
+    // Only owned mailboxes are encrypted to the user's backup key
+    for mailbox in state.db.get_mailboxes_by_owner(&claims.sub).await? {
+        let keys = Arc::new(state.db.list_mailbox_keys(&mailbox.id).await?);
+        for email in state.db.get_mailbox_emails(&mailbox.id).await? {
+            let attachments = state.db.get_email_attachments(&email.id).await?;
+            let (identity, keys, backup_public_key) = (identity.clone(), keys.clone(), backup_key.public_key.clone());
+            let encrypted_content = email.encrypted_content.clone();
+            let Some(email_content) = run_blocking(move || {
+                reencrypt_email(identity.expose_secret(), &encrypted_content, attachments, &keys, &backup_public_key)
+            })
+            .await?
+            else {
+                skipped += 1;
+                continue;
+            };
+
+            state.db.update_email_content(&email.id, &email_content.encrypted_content).await?;
+            for (attachment_id, encrypted_content) in &email_content.attachments {
+                state.db.update_email_attachment_content(attachment_id, encrypted_content).await?;
+            }
+
+            if state.enable_search_index {
+                if let Some(message) = mail_parser::Message::parse(&email_content.raw_email) {
+                    let search_tokens = common::search::search_tokens(&message, &mailbox.public_key);
+                    state.db.save_email_search_tokens(&email, &search_tokens).await?;
+                }
+            }
+            reencrypted += 1;
+        }
+    }
+
+    info!(
+        "Backup recovery for user {} re-encrypted {} emails, skipped {}",
+        claims.sub, reencrypted, skipped
+    );
+    Ok(Json(ApiResponse::success(BackupRecoveryResponse { reencrypted, skipped })))
+}
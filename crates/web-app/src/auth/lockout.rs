@@ -0,0 +1,105 @@
+//! Accounts are locked for a while after repeated failed password logins.
+//! Failures are stored per user, so the lock holds across instances and
+//! client addresses, and a successful login clears them.
+
+use crate::{ApiResponse, AppState};
+use axum::{
+    extract::{Json, Query, State},
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use common::{db::{database_error, Database}, AppError};
+use serde::{Deserialize, Serialize};
+use std::{net::IpAddr, sync::Arc};
+
+use super::Claims;
+
+/// Failed logins within the window that lock the account
+const MAX_FAILED_LOGINS: i64 = 5;
+const LOCKOUT_WINDOW_SECS: i64 = 15 * 60;
+
+#[derive(Debug, Deserialize)]
+pub struct LockoutStatusQuery {
+    username: Option<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LockoutStatus {
+    pub locked: bool,
+    pub unlock_in_seconds: Option<u64>,
+}
+
+/// Seconds until the account unlocks, `None` when it isn't locked
+pub(super) async fn unlock_in<D: Database>(db: &D, user_id: &str) -> Result<Option<u64>, AppError> {
+    let now = chrono::Utc::now().timestamp();
+    // The oldest of the last MAX_FAILED_LOGINS failures keeps the account
+    // locked until it leaves the window
+    let locked_by: Option<i64> = sqlx::query_scalar(
+        "SELECT attempt_at FROM login_attempts WHERE user_id = ? AND attempt_at > ?
+         ORDER BY attempt_at DESC LIMIT 1 OFFSET ?",
+    )
+    .bind(user_id)
+    .bind(now - LOCKOUT_WINDOW_SECS)
+    .bind(MAX_FAILED_LOGINS - 1)
+    .fetch_optional(db.pool())
+    .await
+    .map_err(database_error)?;
+
+    Ok(locked_by.map(|attempt_at| (attempt_at + LOCKOUT_WINDOW_SECS - now).max(1) as u64))
+}
+
+pub(super) async fn record_failed_login<D: Database>(db: &D, user_id: &str, ip: Option<IpAddr>) -> Result<(), AppError> {
+    sqlx::query("INSERT INTO login_attempts (user_id, attempt_at, ip) VALUES (?, ?, ?)")
+        .bind(user_id)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(ip.map(|ip| ip.to_string()))
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+pub(super) async fn clear_failed_logins<D: Database>(db: &D, user_id: &str) -> Result<(), AppError> {
+    sqlx::query("DELETE FROM login_attempts WHERE user_id = ?")
+        .bind(user_id)
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+/// 429 telling the client when to try again
+pub(super) fn locked_response(unlock_in_seconds: u64) -> Response {
+    let mut response = AppError::Auth("Account temporarily locked".to_string()).into_response();
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.headers_mut().insert(header::RETRY_AFTER, unlock_in_seconds.into());
+    response
+}
+
+/// Lockout of the signed-in user, or of `username` for clients that can't
+/// sign in. Unknown usernames are reported as unlocked.
+pub(super) async fn lockout_status_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: Option<Extension<Claims>>,
+    Query(query): Query<LockoutStatusQuery>,
+) -> Result<Json<ApiResponse<LockoutStatus>>, AppError> {
+    let user_id = match (claims, query.username) {
+        (Some(claims), _) => Some(claims.sub.clone()),
+        (None, Some(username)) => match super::get_user_by_username(&state.db, &username).await {
+            Ok(user) => Some(user.id),
+            Err(AppError::NotFound(_)) => None,
+            Err(e) => return Err(e),
+        },
+        (None, None) => return Err(AppError::Auth("Sign in or pass a username".to_string())),
+    };
+
+    let unlock_in_seconds = match user_id {
+        Some(user_id) => unlock_in(&state.db, &user_id).await?,
+        None => None,
+    };
+    Ok(Json(ApiResponse::success(LockoutStatus {
+        locked: unlock_in_seconds.is_some(),
+        unlock_in_seconds,
+    })))
+}
@@ -1,22 +1,31 @@
-use crate::{ApiResponse, AppState};
+use crate::{audit::{self, RequestInfo}, client_ip::ClientIp, security_log, ApiResponse, AppState};
 use axum::{
     body::Body,
     extract::{Json, State},
     http::{header, Request, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{get, post},
+    routing::{delete, get, patch, post},
     Router,
 };
-use common::{db::Database, AppError, AuthType, User};
+use common::{db::{database_error, Database}, request_id, AppError, AuthType, User};
 use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header, Validation};
 use serde::{Deserialize, Serialize};
-use std::sync::Arc;
+use dashmap::DashMap;
+use sqlx::Row;
+use std::{sync::Arc, time::{Duration, Instant}};
 use tracing::error;
 
+mod backup;
+mod common_passwords;
+mod lockout;
 mod oauth;
 mod password;
+mod refresh;
+mod sessions;
+pub(crate) mod settings;
 mod telegram;
+mod totp;
 
 pub use oauth::*;
 pub use telegram::*;
@@ -27,6 +36,49 @@ pub struct Claims {
     pub sub: String, // user_id
     pub exp: usize,  // expiration time
     pub iat: usize,  // issued at
+    #[serde(default)]
+    pub password_changed_at: i64, // users.password_changed_at when issued
+    #[serde(default)]
+    pub jti: String, // token ID, empty in tokens issued before they were revocable
+}
+
+/// How long a user's `password_changed_at` and ban are trusted before they are
+/// read again. Changes on this instance take effect immediately; this only
+/// bounds how long other instances keep accepting old tokens.
+const TOKEN_OWNER_CACHE_TTL: Duration = Duration::from_secs(30);
+
+/// What a user's tokens are checked against
+#[derive(Debug, Clone, Copy, sqlx::FromRow)]
+pub(crate) struct TokenOwner {
+    password_changed_at: i64,
+    banned_at: Option<i64>,
+}
+
+/// Caches the [`TokenOwner`] of users so authenticated requests don't each
+/// query the database
+#[derive(Default)]
+pub struct TokenOwnerCache {
+    entries: DashMap<String, (Option<TokenOwner>, Instant)>,
+}
+
+impl TokenOwnerCache {
+    fn get(&self, user_id: &str) -> Option<Option<TokenOwner>> {
+        self.entries
+            .get(user_id)
+            .filter(|entry| entry.1.elapsed() < TOKEN_OWNER_CACHE_TTL)
+            .map(|entry| entry.0)
+    }
+
+    /// `None` records that the user no longer exists
+    pub(crate) fn insert(&self, user_id: &str, owner: Option<TokenOwner>) {
+        self.entries.insert(user_id.to_string(), (owner, Instant::now()));
+    }
+
+    /// Has the user's next request read their state again
+    #[cfg(feature = "admin-api")]
+    pub(crate) fn invalidate(&self, user_id: &str) {
+        self.entries.remove(user_id);
+    }
 }
 
 // Registration request
@@ -36,6 +88,18 @@ pub struct RegisterRequest {
     pub password: String,
 }
 
+#[derive(Debug, Deserialize)]
+pub struct PasswordStrengthRequest {
+    pub password: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PasswordStrengthResponse {
+    /// From 0 to 4; only passwords scoring 4 are accepted
+    pub score: u8,
+    pub failed_checks: Vec<password::PasswordCheck>,
+}
+
 // Login request
 #[derive(Debug, Deserialize)]
 pub struct LoginRequest {
@@ -43,13 +107,27 @@ pub struct LoginRequest {
     pub password: String,
 }
 
+/// Access tokens are short-lived, clients renew them with the refresh token
+const ACCESS_TOKEN_LIFETIME_SECS: usize = 3600;
+
 // Auth response
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
+    /// The access token
     pub token: String,
+    pub refresh_token: String,
     pub user: User,
 }
 
+/// What a password login returns, depending on whether the account has
+/// two-factor authentication enabled
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+pub enum LoginResponse {
+    Tokens(AuthResponse),
+    TotpRequired(totp::TotpChallenge),
+}
+
 #[derive(Debug, Serialize)]
 pub struct ConnectedAccount {
     provider: String,
@@ -69,46 +147,84 @@ pub struct SetPasswordRequest {
     pub new_password: String,
 }
 
+// Change password request
+#[derive(Debug, Deserialize)]
+pub struct ChangePasswordRequest {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+// Change username request
+#[derive(Debug, Deserialize)]
+pub struct ChangeUsernameRequest {
+    pub new_username: String,
+}
+
+const USERNAME_MIN_LENGTH: usize = 3;
+const USERNAME_MAX_LENGTH: usize = 32;
+/// How long a user must wait between username changes
+const USERNAME_CHANGE_INTERVAL_SECS: i64 = 24 * 3600;
+
 // Create auth routes
-pub fn create_routes<D: Database + 'static>() -> Router<Arc<AppState<D>>> {
+pub fn create_routes<D: Database + 'static>(state: Arc<AppState<D>>) -> Router<Arc<AppState<D>>> {
+    let auth_limit = middleware::from_fn_with_state(state.route_rate_limits.auth.clone(), crate::rate_limit::limit_route);
+    let callback_limit =
+        middleware::from_fn_with_state(state.route_rate_limits.oauth_callback.clone(), crate::rate_limit::limit_route);
+    let destructive = middleware::from_fn_with_state(state.delete_operations, crate::method_filter::refuse_destructive);
     Router::new()
-        .route("/api/auth/register", post(register_handler::<D>))
-        .route("/api/auth/login", post(login_handler::<D>))
-        .route("/api/auth/github/login", get(github_login_handler))
+        .route("/api/auth/register", post(register_handler::<D>).layer(auth_limit.clone()))
+        .route("/api/auth/login", post(login_handler::<D>).layer(auth_limit.clone()))
+        .route("/api/auth/check-password-strength", post(check_password_strength_handler))
+        .route("/api/auth/refresh", post(refresh::refresh_handler::<D>))
+        .route("/api/auth/totp/complete", post(totp::complete_handler::<D>).layer(auth_limit))
+        .route("/api/auth/:provider/login", get(oauth_login_handler::<D>))
         .route(
-            "/api/auth/github/callback",
-            get(github_callback_handler::<D>),
-        )
-        .route("/api/auth/google/login", get(google_login_handler))
-        .route(
-            "/api/auth/google/callback",
-            get(google_callback_handler::<D>),
+            "/api/auth/:provider/callback",
+            get(oauth_callback_handler::<D>).layer(callback_limit.clone()),
         )
         .nest(
             "/api/auth",
             Router::new()
-                .route("/telegram/verify", post(telegram_verify_handler::<D>))
-                .layer(middleware::from_fn(auth_optional)),
+                .route("/telegram/verify", post(telegram_verify_handler::<D>).layer(callback_limit))
+                .route("/lockout-status", get(lockout::lockout_status_handler::<D>))
+                .route("/logout", post(refresh::logout_handler::<D>))
+                .layer(middleware::from_fn_with_state(state.clone(), auth_optional::<D>)),
         )
         .nest(
             "/api/auth",
             Router::new()
                 .route("/me", get(me_handler::<D>))
+                .route("/sessions", delete(sessions::revoke_sessions_handler::<D>).layer(destructive.clone()))
+                .route("/me/username", patch(change_username_handler::<D>))
                 .route("/connected-accounts", get(connected_accounts_handler::<D>))
-                .route("/delete-account", post(delete_account_handler::<D>))
+                .route("/delete-account", post(delete_account_handler::<D>).layer(destructive))
                 .route("/set-password", post(set_password_handler::<D>))
+                .route("/change-password", post(change_password_handler::<D>))
+                // Alias kept for clients of the earlier route name
+                .route("/password-change", post(change_password_handler::<D>))
+                .route("/settings", get(settings::get_handler::<D>).patch(settings::update_handler::<D>))
+                .route("/audit-log", get(audit::list_handler::<D>))
+                .route("/security-log", get(security_log::list_handler::<D>))
+                .route("/backup-passphrase/setup", post(backup::setup_handler::<D>))
+                .route("/backup-passphrase/recover", post(backup::recover_handler::<D>))
+                .route("/totp/setup", post(totp::setup_handler::<D>))
+                .route("/totp/verify", post(totp::verify_handler::<D>))
+                .route("/totp/disable", post(totp::disable_handler::<D>))
                 .route("/telegram/disconnect", post(telegram_disconnect_handler::<D>))
-                .route("/google/disconnect", post(google_disconnect_handler::<D>))
-                .route("/github/disconnect", post(github_disconnect_handler::<D>))
-                .layer(middleware::from_fn(auth)),
+                .route("/:provider/connect", post(oauth_connect_handler::<D>))
+                .route("/:provider/disconnect", post(oauth_disconnect_handler::<D>))
+                .layer(middleware::from_fn_with_state(state, auth::<D>)),
         )
 }
 
 // Register handler
 async fn register_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
+    request_info: RequestInfo,
     Json(req): Json<RegisterRequest>,
 ) -> Result<Json<ApiResponse<AuthResponse>>, AppError> {
+    password::validate_password_strength(&req.password)?;
+
     // Create user with password auth type
     let user = state
         .db
@@ -130,28 +246,47 @@ async fn register_handler<D: Database>(
             tracing::error!("Database error during credential storage: {}", e);
             AppError::Auth("Account created but unable to set up credentials. Please try logging in, or contact support if you cannot access your account.".to_string())
         })?;
+    let metadata = serde_json::json!({ "method": "password" });
+    request_info.audit(&state.db, &user.id, audit::REGISTER, Some(("user", &user.id)), Some(metadata)).await;
 
-    // Generate JWT token
-    let token = create_token(&user.id)?;
+    Ok(Json(ApiResponse::success(issue_tokens(&state.db, user).await?)))
+}
 
-    Ok(Json(ApiResponse::success(AuthResponse { token, user })))
+// Password strength handler, for feedback while the user types. Doesn't need
+// an account, so it is usable from the registration form.
+async fn check_password_strength_handler(
+    Json(req): Json<PasswordStrengthRequest>,
+) -> Json<ApiResponse<PasswordStrengthResponse>> {
+    let failed_checks = password::failed_checks(&req.password);
+    Json(ApiResponse::success(PasswordStrengthResponse {
+        score: password::strength_score(&failed_checks),
+        failed_checks,
+    }))
 }
 
 // Login handler
 async fn login_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
+    client_ip: Option<axum::extract::Extension<ClientIp>>,
+    request_info: RequestInfo,
     Json(req): Json<LoginRequest>,
-) -> Result<Json<ApiResponse<AuthResponse>>, AppError> {
+) -> Result<Response, AppError> {
     // Get user by username
-    let user = get_user_by_username(&state.db, &req.username).await
-        .map_err(|e| {
-            if matches!(e, AppError::NotFound(_)) {
-                AppError::Auth("The username or password you entered is incorrect. Please check your credentials and try again.".to_string())
-            } else {
-                tracing::error!("Database error during login: {}", e);
-                AppError::Auth("Unable to process login request. Please try again later or contact support if the problem persists.".to_string())
-            }
-        })?;
+    let user = match get_user_by_username(&state.db, &req.username).await {
+        Ok(user) => user,
+        Err(AppError::NotFound(_)) => {
+            password::verify_dummy_password(&req.password);
+            return Err(AppError::Auth("The username or password you entered is incorrect. Please check your credentials and try again.".to_string()));
+        }
+        Err(e) => {
+            tracing::error!("Database error during login: {}", e);
+            return Err(AppError::Auth("Unable to process login request. Please try again later or contact support if the problem persists.".to_string()));
+        }
+    };
+
+    if let Some(unlock_in_seconds) = lockout::unlock_in(&state.db, &user.id).await? {
+        return Ok(lockout::locked_response(unlock_in_seconds));
+    }
 
     // Verify password
     let credentials = get_credentials(&state.db, &user.id).await
@@ -162,17 +297,27 @@ async fn login_handler<D: Database>(
     
     let password_hash = credentials.password_hash.as_deref().unwrap_or_default();
     if password_hash.is_empty() {
+        password::verify_dummy_password(&req.password);
         return Err(AppError::Auth("No password has been set for this account. Please use another login method or reset your password.".to_string()));
     }
 
     if !password::verify_password(&req.password, password_hash)? {
+        let ip = client_ip.map(|axum::extract::Extension(ClientIp(ip))| ip);
+        lockout::record_failed_login(&state.db, &user.id, ip).await?;
+        request_info.audit(&state.db, &user.id, audit::LOGIN_FAILED, None, None).await;
         return Err(AppError::Auth("The username or password you entered is incorrect. Please check your credentials and try again.".to_string()));
     }
 
-    // Generate JWT token
-    let token = create_token(&user.id)?;
-
-    Ok(Json(ApiResponse::success(AuthResponse { token, user })))
+    lockout::clear_failed_logins(&state.db, &user.id).await?;
+    let metadata = serde_json::json!({ "method": "password", "totp_required": credentials.totp_enabled });
+    request_info.audit(&state.db, &user.id, audit::LOGIN, None, Some(metadata)).await;
+    security_log::record_login(&state.db, &request_info, &user.id).await;
+    let response = if credentials.totp_enabled {
+        LoginResponse::TotpRequired(totp::create_challenge(&state.db, &user.id).await?)
+    } else {
+        LoginResponse::Tokens(issue_tokens(&state.db, user).await?)
+    };
+    Ok(Json(ApiResponse::success(response)).into_response())
 }
 
 // Me handler to check authentication status
@@ -202,43 +347,128 @@ fn extract_claims(req: &Request<Body>) -> Result<Option<Claims>, AppError> {
     match auth_header {
         Some(header) => {
             let token = header.strip_prefix("Bearer ").unwrap_or(header);
-            let claims = decode::<Claims>(
-                token,
-                &DecodingKey::from_secret(get_jwt_secret().as_bytes()),
-                &Validation::default(),
-            ).map_err(|_| AppError::Auth("Invalid token".to_string()))?;
-            Ok(Some(claims.claims))
+            decode_token(token).map(Some)
         }
         None => Ok(None),
     }
 }
 
-pub async fn auth_optional(req: Request<Body>, next: Next) -> Response {
-    match extract_claims(&req) {
+fn decode_token(token: &str) -> Result<Claims, AppError> {
+    let claims = decode::<Claims>(
+        token,
+        &DecodingKey::from_secret(get_jwt_secret().as_bytes()),
+        &Validation::default(),
+    ).map_err(|_| AppError::Auth("Invalid token".to_string()))?;
+    Ok(claims.claims)
+}
+
+/// Rejects tokens of deleted users, tokens issued before the user's last
+/// password change and revoked tokens
+async fn validate_claims<D: Database>(state: &AppState<D>, claims: &Claims) -> Result<(), AppError> {
+    let owner = match state.token_owners.get(&claims.sub) {
+        Some(owner) => owner,
+        None => {
+            let owner = get_token_owner(&state.db, &claims.sub).await?;
+            state.token_owners.insert(&claims.sub, owner);
+            owner
+        }
+    };
+
+    match owner {
+        Some(owner) if owner.password_changed_at > claims.password_changed_at => {
+            return Err(AppError::Auth("Token was issued before the last password change".to_string()));
+        }
+        Some(owner) if owner.banned_at.is_some() => {
+            return Err(AppError::Forbidden("This account has been banned.".to_string()));
+        }
+        Some(_) => {}
+        None => return Err(AppError::Auth("User no longer exists".to_string())),
+    }
+
+    if !claims.jti.is_empty() && sessions::is_token_revoked(&state.db, &claims.jti).await? {
+        return Err(AppError::Auth("Token has been revoked".to_string()));
+    }
+    Ok(())
+}
+
+/// Takes the claims rather than the request, which isn't `Sync` and so can't
+/// be borrowed across the database query
+async fn authenticate<D: Database>(
+    state: &AppState<D>,
+    claims: Result<Option<Claims>, AppError>,
+) -> Result<Option<Claims>, Response> {
+    let unauthorized = || (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+
+    let claims = match claims {
+        Ok(Some(claims)) => claims,
+        Ok(None) => return Ok(None),
+        Err(e) => {
+            error!("Failed to extract claims: {}", e);
+            return Err(unauthorized());
+        }
+    };
+
+    match validate_claims(state, &claims).await {
+        Ok(()) => Ok(Some(claims)),
+        Err(AppError::Auth(e)) => {
+            error!("Rejected token of user {}: {}", claims.sub, e);
+            Err(unauthorized())
+        }
+        Err(AppError::Forbidden(e)) => {
+            error!("Rejected token of user {}: {}", claims.sub, e);
+            Err((StatusCode::FORBIDDEN, e).into_response())
+        }
+        Err(e) => {
+            error!("Failed to validate token: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response())
+        }
+    }
+}
+
+pub async fn auth_optional<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let claims = extract_claims(&req);
+    match authenticate(&state, claims).await {
         Ok(Some(claims)) => {
-            let mut req = req;
+            request_id::record_user_id(&req, &claims.sub);
             req.extensions_mut().insert(claims);
             next.run(req).await
         }
         Ok(None) => next.run(req).await,
-        Err(e) => {
-            error!("Failed to extract claims: {}", e);
-            (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
-        }
+        Err(response) => response,
+    }
+}
+
+/// Authenticates a raw token, for connections that can't send an
+/// `Authorization` header such as browser WebSockets
+pub(crate) async fn authenticate_token<D: Database>(
+    state: &AppState<D>,
+    token: &str,
+) -> Result<Claims, Response> {
+    match authenticate(state, decode_token(token).map(Some)).await? {
+        Some(claims) => Ok(claims),
+        None => Err((StatusCode::UNAUTHORIZED, "Unauthorized").into_response()),
     }
 }
 
 // Auth middleware
-pub async fn auth(req: Request<Body>, next: Next) -> Response {
-    match extract_claims(&req) {
+pub async fn auth<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    let claims = extract_claims(&req);
+    match authenticate(&state, claims).await {
         Ok(Some(claims)) => {
-            let mut req = req;
+            request_id::record_user_id(&req, &claims.sub);
             req.extensions_mut().insert(claims);
             next.run(req).await
         }
-        _ => {
-            (StatusCode::UNAUTHORIZED, "Unauthorized").into_response()
-        }
+        Ok(None) => (StatusCode::UNAUTHORIZED, "Unauthorized").into_response(),
+        Err(response) => response,
     }
 }
 
@@ -248,7 +478,7 @@ pub(crate) async fn store_credentials<D: Database>(
     db: &D,
     user_id: &str,
     password_hash: Option<&str>,
-    provider: Option<&str>,
+    provider_column: Option<&'static str>,
     provider_id: Option<&str>,
     telegram_id: Option<&str>,
 ) -> Result<(), AppError> {
@@ -265,20 +495,11 @@ pub(crate) async fn store_credentials<D: Database>(
         now.to_string(),
     ];
 
-    if let (Some(provider), Some(id)) = (provider, provider_id) {
-        match provider {
-            "google" => {
-                query.push_str(", google_id");
-                values.push_str(", ?");
-                params.push(id.to_string());
-            }
-            "github" => {
-                query.push_str(", github_id");
-                values.push_str(", ?");
-                params.push(id.to_string());
-            }
-            _ => {}
-        }
+    if let (Some(column), Some(id)) = (provider_column, provider_id) {
+        query.push_str(", ");
+        query.push_str(column);
+        values.push_str(", ?");
+        params.push(id.to_string());
     }
 
     if let Some(id) = telegram_id {
@@ -322,6 +543,18 @@ pub(crate) async fn get_credentials<D: Database>(
         .ok_or_else(|| AppError::Auth("Account credentials not found. Please try logging in with a different method or contact support.".to_string()))
 }
 
+/// `None` if the user doesn't exist
+async fn get_token_owner<D: Database>(
+    db: &D,
+    user_id: &str,
+) -> Result<Option<TokenOwner>, AppError> {
+    sqlx::query_as("SELECT password_changed_at, banned_at FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(db.pool())
+        .await
+        .map_err(database_error)
+}
+
 pub(crate) async fn get_user_by_username<D: Database>(
     db: &D,
     username: &str,
@@ -345,17 +578,27 @@ pub(crate) struct UserCredentials {
     pub google_id: Option<String>,
     pub github_id: Option<String>,
     pub telegram_id: Option<String>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
     pub created_at: i64,
     pub updated_at: i64,
 }
 
-fn create_token(user_id: &str) -> Result<String, AppError> {
+async fn create_token<D: Database>(db: &D, user_id: &str) -> Result<String, AppError> {
+    let password_changed_at = get_token_owner(db, user_id)
+        .await?
+        .ok_or_else(|| AppError::NotFound("Account not found.".to_string()))?
+        .password_changed_at;
+
     let now = chrono::Utc::now().timestamp() as usize;
     let claims = Claims {
         sub: user_id.to_string(),
-        exp: now + 24 * 3600, // 24 hours from now
+        exp: now + ACCESS_TOKEN_LIFETIME_SECS,
         iat: now,
+        password_changed_at,
+        jti: uuid::Uuid::new_v4().to_string(),
     };
+    sessions::record_issued_token(db, &claims.jti, user_id, claims.iat as i64, claims.exp as i64).await?;
 
     encode(
         &Header::default(),
@@ -365,6 +608,17 @@ fn create_token(user_id: &str) -> Result<String, AppError> {
     .map_err(|e| AppError::Internal(format!("Failed to create token: {}", e)))
 }
 
+/// Signs in `user` with a new access token and refresh token
+/// Every sign-in ends here, so banned users are refused whatever the method
+pub(crate) async fn issue_tokens<D: Database>(db: &D, user: User) -> Result<AuthResponse, AppError> {
+    if user.banned_at.is_some() {
+        return Err(AppError::Forbidden("This account has been banned.".to_string()));
+    }
+    let token = create_token(db, &user.id).await?;
+    let refresh_token = refresh::create_refresh_token(db, &user.id).await?;
+    Ok(AuthResponse { token, refresh_token, user })
+}
+
 fn get_jwt_secret() -> String {
     std::env::var("JWT_SECRET").unwrap_or_else(|_| "your-256-bit-secret".to_string())
 }
@@ -398,22 +652,15 @@ async fn connected_accounts_handler<D: Database>(
         }
     }
 
-    // Add Google if present
-    if let Some(google_id) = credentials.google_id {
-        accounts.push(ConnectedAccount {
-            provider: "google".to_string(),
-            connected_at: credentials.created_at,
-            provider_id: Some(google_id),
-        });
-    }
-
-    // Add GitHub if present
-    if let Some(github_id) = credentials.github_id {
-        accounts.push(ConnectedAccount {
-            provider: "github".to_string(),
-            connected_at: credentials.created_at,
-            provider_id: Some(github_id),
-        });
+    // Add every connected OAuth provider
+    for (provider, provider_id) in oauth_provider_ids(&state, &claims.sub).await? {
+        if let Some(provider_id) = provider_id {
+            accounts.push(ConnectedAccount {
+                provider: provider.to_string(),
+                connected_at: credentials.created_at,
+                provider_id: Some(provider_id),
+            });
+        }
     }
 
     // Add Telegram if present
@@ -432,6 +679,7 @@ async fn connected_accounts_handler<D: Database>(
 async fn set_password_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
+    request_info: RequestInfo,
     Json(req): Json<SetPasswordRequest>,
 ) -> Result<Json<ApiResponse<()>>, AppError> {
     let credentials = get_credentials(&state.db, &claims.sub).await?;
@@ -439,6 +687,7 @@ async fn set_password_handler<D: Database>(
     if credentials.password_hash.is_some() {
         return Err(AppError::Auth("Password is already set. Use change password instead.".to_string()));
     }
+    password::validate_password_strength(&req.new_password)?;
 
     let password_hash = password::hash_password(&req.new_password)?;
     
@@ -454,14 +703,164 @@ async fn set_password_handler<D: Database>(
         tracing::error!("Database error while setting password: {}", e);
         AppError::Internal("Failed to set password. Please try again later.".to_string())
     })?;
+    request_info.audit(&state.db, &claims.sub, audit::SET_PASSWORD, None, None).await;
 
     Ok(Json(ApiResponse::success(())))
 }
 
+// Change password handler. Every token issued before the change stops
+// working, refresh tokens included, so the caller gets fresh ones.
+async fn change_password_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: RequestInfo,
+    Json(req): Json<ChangePasswordRequest>,
+) -> Result<Json<ApiResponse<AuthResponse>>, AppError> {
+    update_password(&state, &claims.sub, &req.current_password, &req.new_password).await?;
+    request_info.audit(&state.db, &claims.sub, audit::CHANGE_PASSWORD, None, None).await;
+
+    let user = state.db.get_user(&claims.sub).await?
+        .ok_or_else(|| AppError::Auth("Your session has expired. Please log in again to continue.".to_string()))?;
+    Ok(Json(ApiResponse::success(issue_tokens(&state.db, user).await?)))
+}
+
+/// Replaces the user's password after checking the current one, revoking
+/// every access and refresh token issued before. The new password must meet
+/// the registration requirements and differ from the current one.
+async fn update_password<D: Database>(
+    state: &AppState<D>,
+    user_id: &str,
+    current_password: &str,
+    new_password: &str,
+) -> Result<(), AppError> {
+    if new_password == current_password {
+        return Err(AppError::Auth("The new password must be different from the current one.".to_string()));
+    }
+    password::validate_password_strength(new_password)?;
+    let credentials = get_credentials(&state.db, user_id).await?;
+
+    let password_hash = credentials.password_hash.as_deref().unwrap_or_default();
+    if password_hash.is_empty() {
+        return Err(AppError::Auth("No password has been set for this account. Use set password instead.".to_string()));
+    }
+    if !password::verify_password(current_password, password_hash)? {
+        return Err(AppError::Auth("Incorrect password. Please try again.".to_string()));
+    }
+
+    let new_password_hash = password::hash_password(new_password)?;
+    let now = chrono::Utc::now().timestamp();
+
+    let mut tx = state.db.pool().begin().await.map_err(database_error)?;
+    sqlx::query("UPDATE user_credentials SET password_hash = ?, updated_at = ? WHERE user_id = ?")
+        .bind(&new_password_hash)
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+
+    // Always advance, so a second change within the same second still revokes
+    // tokens issued after the first
+    let owner: TokenOwner = sqlx::query_as(
+        "UPDATE users SET password_changed_at = MAX(?, password_changed_at + 1), updated_at = ?
+         WHERE id = ? RETURNING password_changed_at, banned_at",
+    )
+    .bind(now)
+    .bind(now)
+    .bind(user_id)
+    .fetch_one(&mut *tx)
+    .await
+    .map_err(database_error)?;
+
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+    tx.commit().await.map_err(database_error)?;
+
+    state.token_owners.insert(user_id, Some(owner));
+    Ok(())
+}
+
+fn validate_username(username: &str) -> Result<(), AppError> {
+    if !(USERNAME_MIN_LENGTH..=USERNAME_MAX_LENGTH).contains(&username.len())
+        || !username.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'_'))
+    {
+        return Err(AppError::Auth(format!(
+            "Usernames must be {} to {} characters long and contain only letters, digits, '-' and '_'.",
+            USERNAME_MIN_LENGTH, USERNAME_MAX_LENGTH
+        )));
+    }
+    Ok(())
+}
+
+// Change username handler. OAuth and Telegram accounts are linked by their
+// provider IDs rather than the username, so every sign-in method keeps working.
+async fn change_username_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: RequestInfo,
+    Json(req): Json<ChangeUsernameRequest>,
+) -> Result<Json<ApiResponse<User>>, AppError> {
+    validate_username(&req.new_username)?;
+    let now = chrono::Utc::now().timestamp();
+
+    let mut tx = state.db.pool().begin().await.map_err(database_error)?;
+    let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
+        .bind(&claims.sub)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(database_error)?
+        .ok_or_else(|| AppError::Auth("Your session has expired. Please log in again to continue.".to_string()))?;
+
+    if user.username == req.new_username {
+        return Err(AppError::Auth("The new username is the same as the current one.".to_string()));
+    }
+    if user.username_changed_at.is_some_and(|changed_at| now - changed_at < USERNAME_CHANGE_INTERVAL_SECS) {
+        return Err(AppError::Forbidden("Your username can only be changed once every 24 hours.".to_string()));
+    }
+
+    let taken_error = || AppError::Auth("Username is already taken. Please choose a different username.".to_string());
+    let taken: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM users WHERE username = ?)")
+        .bind(&req.new_username)
+        .fetch_one(&mut *tx)
+        .await
+        .map_err(database_error)?;
+    if taken {
+        return Err(taken_error());
+    }
+
+    // The unique index still catches a username taken since the check
+    sqlx::query("UPDATE users SET username = ?, username_changed_at = ?, updated_at = ? WHERE id = ?")
+        .bind(&req.new_username)
+        .bind(now)
+        .bind(now)
+        .bind(&claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(|e| match e {
+            sqlx::Error::Database(ref db_error) if db_error.is_unique_violation() => taken_error(),
+            e => database_error(e),
+        })?;
+    tx.commit().await.map_err(database_error)?;
+
+    let metadata = serde_json::json!({ "old_username": user.username, "new_username": req.new_username });
+    request_info.audit(&state.db, &claims.sub, audit::CHANGE_USERNAME, Some(("user", &claims.sub)), Some(metadata)).await;
+
+    Ok(Json(ApiResponse::success(User {
+        username: req.new_username,
+        username_changed_at: Some(now),
+        ..user
+    })))
+}
+
 // Delete account handler
 async fn delete_account_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
+    request_info: RequestInfo,
     Json(req): Json<DeleteAccountRequest>,
 ) -> Result<Json<ApiResponse<()>>, AppError> {
     // Get user credentials to verify password if provided
@@ -481,15 +880,14 @@ async fn delete_account_handler<D: Database>(
         return Err(AppError::Auth("Password is required to delete account.".to_string()));
     }
 
-    // Delete the user - this will cascade to all related tables
-    sqlx::query("DELETE FROM users WHERE id = ?")
-        .bind(&claims.sub)
-        .execute(state.db.pool())
+    state.db.delete_user(&claims.sub)
         .await
         .map_err(|e| {
             tracing::error!("Database error while deleting user: {}", e);
             AppError::Internal("Failed to delete account. Please try again later.".to_string())
         })?;
+    state.token_owners.insert(&claims.sub, None);
+    request_info.audit(&state.db, &claims.sub, audit::DELETE_ACCOUNT, Some(("user", &claims.sub)), None).await;
 
     Ok(Json(ApiResponse::success(())))
 }
@@ -537,90 +935,40 @@ pub(crate) async fn generate_unique_username<D: Database>(
     }
 }
 
-pub async fn github_disconnect_handler<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    claims: axum::extract::Extension<Claims>,
-) -> Result<Json<ApiResponse<()>>, AppError> {
-    // Check if user has other authentication methods before disconnecting
-    let credentials = sqlx::query_as::<_, UserCredentials>(
-        "SELECT * FROM user_credentials WHERE user_id = ?"
-    )
-    .bind(&claims.sub)
-    .fetch_one(state.db.pool())
-    .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
-
-    // Check if user is authenticated with GitHub
-    if credentials.github_id.is_none() {
-        return Err(AppError::Auth("No GitHub account connected".to_string()));
-    }
-
-    // Ensure user has at least one other authentication method
-    let has_password = credentials.password_hash.is_some();
-    let has_google = credentials.google_id.is_some();
-    let has_telegram = credentials.telegram_id.is_some();
-    if !has_password && !has_google && !has_telegram {
-        return Err(AppError::Auth(
-            "Cannot disconnect GitHub account: it is your only authentication method".to_string(),
-        ));
-    }
-
-    // Remove GitHub credentials
-    sqlx::query(
-        r#"UPDATE user_credentials 
-           SET github_id = NULL, 
-               updated_at = ? 
-           WHERE user_id = ?"#
-    )
-    .bind(chrono::Utc::now().timestamp())
-    .bind(&claims.sub)
-    .execute(state.db.pool())
-    .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
-
-    Ok(Json(ApiResponse::success(())))
+/// The user's ID at each registered OAuth provider, `None` when not connected
+pub(crate) async fn oauth_provider_ids<D: Database>(
+    state: &AppState<D>,
+    user_id: &str,
+) -> Result<Vec<(&'static str, Option<String>)>, AppError> {
+    let row = sqlx::query("SELECT * FROM user_credentials WHERE user_id = ?")
+        .bind(user_id)
+        .fetch_one(state.db.pool())
+        .await
+        .map_err(database_error)?;
+
+    state
+        .oauth_providers
+        .iter()
+        .map(|provider| {
+            row.try_get::<Option<String>, _>(provider.credential_column())
+                .map(|id| (provider.name(), id))
+                .map_err(database_error)
+        })
+        .collect()
 }
 
-pub async fn google_disconnect_handler<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    claims: axum::extract::Extension<Claims>,
-) -> Result<Json<ApiResponse<()>>, AppError> {
-    // Check if user has other authentication methods before disconnecting
-    let credentials = sqlx::query_as::<_, UserCredentials>(
-        "SELECT * FROM user_credentials WHERE user_id = ?"
-    )
-    .bind(&claims.sub)
-    .fetch_one(state.db.pool())
-    .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
-
-    // Check if user is authenticated with Google
-    if credentials.google_id.is_none() {
-        return Err(AppError::Auth("No Google account connected".to_string()));
-    }
-
-    // Ensure user has at least one other authentication method
-    let has_password = credentials.password_hash.is_some();
-    let has_github = credentials.github_id.is_some();
-    let has_telegram = credentials.telegram_id.is_some();
-    if !has_password && !has_github && !has_telegram {
-        return Err(AppError::Auth(
-            "Cannot disconnect Google account: it is your only authentication method".to_string(),
-        ));
-    }
-
-    // Remove Google credentials
-    sqlx::query(
-        r#"UPDATE user_credentials 
-           SET google_id = NULL, 
-               updated_at = ? 
-           WHERE user_id = ?"#
-    )
-    .bind(chrono::Utc::now().timestamp())
-    .bind(&claims.sub)
-    .execute(state.db.pool())
-    .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
-
-    Ok(Json(ApiResponse::success(())))
+/// Number of ways the user can log in: password, Telegram and OAuth providers
+pub(crate) async fn count_auth_methods<D: Database>(
+    state: &AppState<D>,
+    user_id: &str,
+) -> Result<usize, AppError> {
+    let credentials = get_credentials(&state.db, user_id).await?;
+    let has_password = credentials.password_hash.as_deref().is_some_and(|hash| !hash.is_empty());
+    let oauth_methods = oauth_provider_ids(state, user_id)
+        .await?
+        .into_iter()
+        .filter(|(_, id)| id.is_some())
+        .count();
+
+    Ok(usize::from(has_password) + usize::from(credentials.telegram_id.is_some()) + oauth_methods)
 }
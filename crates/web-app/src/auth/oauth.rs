@@ -1,473 +1,348 @@
-use crate::auth::{create_token, store_credentials};
-use crate::{get_web_app_url, AppState};
+use crate::auth::{count_auth_methods, issue_tokens, refresh::hash_token, store_credentials, Claims};
+use crate::{audit::{self, RequestInfo}, get_web_app_url, security_log, ApiResponse, AppState};
 use axum::{
-    extract::{Query, State},
+    async_trait,
+    extract::{Path, Query, State},
     response::Redirect,
     Json,
 };
-use common::{db::Database, AppError, AuthType, User};
+use common::{circuit_breaker::CircuitBreaker, db::{database_error, Database}, AppError, AuthType, User};
 use oauth2::{
-    basic::BasicClient, AuthUrl, AuthorizationCode, ClientId, ClientSecret, CsrfToken, RedirectUrl,
-    Scope, TokenResponse, TokenUrl,
+    basic::BasicClient, AuthUrl, ClientId, ClientSecret, CsrfToken, PkceCodeChallenge, PkceCodeVerifier,
+    RedirectUrl, Scope, TokenUrl,
 };
+use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::borrow::Cow;
+use sqlx::Row;
 use std::collections::HashMap;
 use std::sync::Arc;
 
+/// How long users have to approve the login at the provider
+const OAUTH_STATE_LIFETIME_SECS: i64 = 10 * 60;
+
 // OAuth callback parameters
 #[derive(Debug, Deserialize)]
 pub struct OAuthCallback {
     code: String,
-    #[allow(dead_code)]
     state: String,
-    action: Option<String>,
 }
 
-// GitHub user info
-#[derive(Debug, Deserialize)]
-struct GitHubUser {
-    id: i64,
-    login: String,
+#[derive(Debug, Default, Deserialize)]
+pub struct OAuthConnectRequest {
+    redirect_to: Option<String>,
 }
 
-// Google user info
-#[derive(Debug, Deserialize)]
-struct GoogleUser {
-    id: String,
-    email: String,
-    verified_email: bool,
+#[derive(Debug, Serialize)]
+pub struct OAuthConnectResponse {
+    /// The provider's authorization page, for the browser to open
+    pub url: String,
+}
+
+/// What the login request stored for its callback under the CSRF token
+struct PendingAuthorization {
+    code_verifier: PkceCodeVerifier,
+    redirect_to: Option<String>,
+    user_id: Option<String>,
+    action: Option<String>,
+}
+
+/// The provider account a user signed in with
+#[derive(Debug, Clone)]
+pub struct OAuthUserInfo {
+    pub id: String,
+    pub username: String,
 }
 
 // Auth response
 #[derive(Debug, Serialize)]
 pub struct AuthResponse {
-    pub token: String,
-    pub user: User,
+    /// `None` after connecting an account, the user keeping their session
+    #[serde(flatten)]
+    pub auth: Option<crate::auth::AuthResponse>,
     pub redirect_to: String,
 }
 
-// GitHub OAuth handlers
-pub async fn github_login_handler(
-    Query(params): Query<HashMap<String, String>>,
-) -> Result<Redirect, AppError> {
-    let client = github_oauth_client()?;
-    let app_url = get_app_url();
-    let redirect_url = format!("{}/auth/github/callback", app_url);
-
-    let (auth_url, csrf_token) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new("read:user".to_string()))
-        .set_redirect_uri(Cow::Owned(RedirectUrl::new(redirect_url).unwrap()))
-        .url();
-
-    // Store redirect_to, user_id, and action in state if provided
-    let state = if let Some(redirect_to) = params.get("redirect_to") {
-        if let Some(user_id) = params.get("state") {
-            if let Some(action) = params.get("action") {
-                format!(
-                    "{}:{}:{}:{}",
-                    csrf_token.secret(),
-                    redirect_to,
-                    user_id,
-                    action
-                )
-            } else {
-                format!("{}:{}:{}", csrf_token.secret(), redirect_to, user_id)
-            }
-        } else if let Some(action) = params.get("action") {
-            format!("{}:{}::{}", csrf_token.secret(), redirect_to, action)
-        } else {
-            format!("{}:{}", csrf_token.secret(), redirect_to)
-        }
-    } else if let Some(user_id) = params.get("state") {
-        if let Some(action) = params.get("action") {
-            format!("{}::{}:{}", csrf_token.secret(), user_id, action)
-        } else {
-            format!("{}::{}", csrf_token.secret(), user_id)
-        }
-    } else if let Some(action) = params.get("action") {
-        format!("{}:::{}", csrf_token.secret(), action)
-    } else {
-        csrf_token.secret().to_string()
-    };
+/// An OAuth 2 login provider. Registering an implementation in
+/// [`default_oauth_providers`] exposes `/api/auth/<name>/login`, `/callback`
+/// and `/disconnect`; client credentials are read from `<NAME>_CLIENT_ID`
+/// and `<NAME>_CLIENT_SECRET`.
+#[async_trait]
+pub trait OAuthProvider: Send + Sync {
+    /// Lowercase identifier used in routes and environment variables
+    fn name(&self) -> &'static str;
+    /// Name shown to users in messages
+    fn display_name(&self) -> &'static str;
+    fn auth_url(&self) -> &'static str;
+    fn token_url(&self) -> &'static str;
+    fn user_info_url(&self) -> &'static str;
+    fn scopes(&self) -> &'static [&'static str];
+    fn auth_type(&self) -> AuthType;
+    /// Column of `user_credentials` holding the provider's user ID
+    fn credential_column(&self) -> &'static str;
+    /// Requests go through `http` so a failing provider is not hammered
+    async fn get_user_info(&self, http: &CircuitBreaker, access_token: &str) -> Result<OAuthUserInfo, AppError>;
+}
 
-    Ok(Redirect::to(
-        &auth_url.to_string().replace(csrf_token.secret(), &state),
-    ))
+pub fn default_oauth_providers() -> Vec<Box<dyn OAuthProvider>> {
+    vec![Box::new(GitHubProvider), Box::new(GoogleProvider)]
 }
 
-pub async fn github_callback_handler<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    Query(params): Query<OAuthCallback>,
-) -> Result<Json<AuthResponse>, AppError> {
-    // Extract redirect_to, user_id, and action from state if present
-    let state_str = params.state.clone();
-    let parts: Vec<&str> = state_str.split(':').collect();
-    let (_csrf_state, redirect_to, user_id, action) = match parts.len() {
-        4 => (
-            parts[0].to_string(),
-            Some(parts[1].to_string()),
-            Some(parts[2].to_string()),
-            Some(parts[3].to_string()),
-        ),
-        3 => (
-            parts[0].to_string(),
-            Some(parts[1].to_string()),
-            Some(parts[2].to_string()),
-            None,
-        ),
-        2 => (parts[0].to_string(), Some(parts[1].to_string()), None, None),
-        _ => (state_str, None, None, None),
-    };
+pub struct GitHubProvider;
 
-    // Exchange the code for an access token with custom headers
-    let token_response = reqwest::Client::new()
-        .post("https://github.com/login/oauth/access_token")
-        .header("Accept", "application/json")
-        .header("User-Agent", "vh-mail-hook")
-        .form(&[
-            (
-                "client_id",
-                std::env::var("GITHUB_CLIENT_ID")
-                    .map_err(|_| AppError::Internal("GITHUB_CLIENT_ID not set".to_string()))?,
-            ),
-            (
-                "client_secret",
-                std::env::var("GITHUB_CLIENT_SECRET")
-                    .map_err(|_| AppError::Internal("GITHUB_CLIENT_SECRET not set".to_string()))?,
-            ),
-            ("code", params.code.clone()),
-            (
-                "redirect_uri",
-                format!("{}/auth/github/callback", get_app_url()),
-            ),
-        ])
-        .send()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to exchange GitHub code: {}", e)))?;
-
-    let body = token_response
-        .text()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to get token response text: {}", e)))?;
+#[async_trait]
+impl OAuthProvider for GitHubProvider {
+    fn name(&self) -> &'static str {
+        "github"
+    }
 
-    // Parse the token response
-    #[derive(Deserialize)]
-    struct GitHubTokenResponse {
-        access_token: String,
-        #[allow(dead_code)]
-        token_type: String,
+    fn display_name(&self) -> &'static str {
+        "GitHub"
     }
 
-    let token_data: GitHubTokenResponse = serde_json::from_str(&body)
-        .map_err(|e| AppError::Auth(format!("Failed to parse GitHub token response: {}", e)))?;
+    fn auth_url(&self) -> &'static str {
+        "https://github.com/login/oauth/authorize"
+    }
 
-    // Get GitHub user info
-    let response = reqwest::Client::new()
-        .get("https://api.github.com/user")
-        .header(
-            "Authorization",
-            format!("Bearer {}", token_data.access_token),
-        )
-        .header("User-Agent", "vh-mail-hook")
-        .header("Accept", "application/json")
-        .send()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to get GitHub user info: {}", e)))?;
+    fn token_url(&self) -> &'static str {
+        "https://github.com/login/oauth/access_token"
+    }
 
-    let text = response
-        .text()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to get response text: {}", e)))?;
+    fn user_info_url(&self) -> &'static str {
+        "https://api.github.com/user"
+    }
 
-    // Parse the response text back into JSON
-    let github_user: GitHubUser = serde_json::from_str(&text)
-        .map_err(|e| AppError::Auth(format!("Failed to parse GitHub user info: {}", e)))?;
+    fn scopes(&self) -> &'static [&'static str] {
+        &["read:user"]
+    }
 
-    // Check if user exists with this GitHub ID
-    let existing_user = sqlx::query_as::<_, User>(
-        "SELECT u.* FROM users u
-         JOIN user_credentials c ON u.id = c.user_id
-         WHERE c.github_id = ?",
-    )
-    .bind(github_user.id.to_string())
-    .fetch_optional(state.db.pool())
-    .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
+    fn auth_type(&self) -> AuthType {
+        AuthType::GitHub
+    }
 
-    // Handle different actions
-    match action.as_deref().or(params.action.as_deref()) {
-        // Connect action - link GitHub account to existing user
-        Some("connect") => {
-            let user_id = user_id
-                .ok_or_else(|| AppError::Auth("Invalid state for connect action".to_string()))?;
+    fn credential_column(&self) -> &'static str {
+        "github_id"
+    }
 
-            // Check if this GitHub account is already connected to another user
-            if let Some(existing) = &existing_user {
-                if existing.id != user_id {
-                    return Err(AppError::Auth(
-                        "This GitHub account is already connected to another user".to_string(),
-                    ));
-                }
-                return Err(AppError::Auth(
-                    "This GitHub account is already connected to your account".to_string(),
-                ));
-            }
+    async fn get_user_info(&self, http: &CircuitBreaker, access_token: &str) -> Result<OAuthUserInfo, AppError> {
+        #[derive(Debug, Deserialize)]
+        struct GitHubUser {
+            id: i64,
+            login: String,
+        }
 
-            // Update the user's credentials while preserving other OAuth connections
-            sqlx::query(
-                "UPDATE user_credentials 
-                 SET github_id = ?,
-                     updated_at = ?
-                 WHERE user_id = ?",
-            )
-            .bind(github_user.id.to_string())
-            .bind(chrono::Utc::now().timestamp())
-            .bind(&user_id)
-            .execute(state.db.pool())
+        let request = reqwest::Client::new()
+            .get(self.user_info_url())
+            .header("Authorization", format!("Bearer {}", access_token))
+            .header("User-Agent", "vh-mail-hook")
+            .header("Accept", "application/json");
+        let text = http.send(request)
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(|e| AppError::Auth(format!("Failed to get GitHub user info: {}", e)))?
+            .text()
+            .await
+            .map_err(|e| AppError::Auth(format!("Failed to get response text: {}", e)))?;
 
-            // Return success response
-            let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
-                .bind(&user_id)
-                .fetch_one(state.db.pool())
-                .await
-                .map_err(|e| AppError::Database(e.to_string()))?;
+        let github_user: GitHubUser = serde_json::from_str(&text)
+            .map_err(|e| AppError::Auth(format!("Failed to parse GitHub user info: {}", e)))?;
 
-            let token = create_token(&user.id)?;
-            let redirect_to = redirect_to.unwrap_or_else(|| "/settings?success=true".to_string());
-            Ok(Json(AuthResponse {
-                token,
-                user,
-                redirect_to,
-            }))
-        }
+        Ok(OAuthUserInfo {
+            id: github_user.id.to_string(),
+            username: github_user.login,
+        })
+    }
+}
 
-        // Login action - check if account exists
-        Some("login") => match existing_user {
-            Some(user) => {
-                let token = create_token(&user.id)?;
-                let redirect_to = redirect_to.unwrap_or_else(|| "/mailboxes".to_string());
-                Ok(Json(AuthResponse {
-                    token,
-                    user,
-                    redirect_to,
-                }))
-            }
-            None => Err(AppError::Auth(
-                "No account found with this GitHub account. Please register first.".to_string(),
-            )),
-        },
+pub struct GoogleProvider;
 
-        // Register action - create new account
-        Some("register") => {
-            if existing_user.is_some() {
-                Err(AppError::Auth(
-                    "This GitHub account is already registered. Please login instead.".to_string(),
-                ))
-            } else {
-                // Generate unique username from GitHub login
-                let username = crate::auth::generate_unique_username(
-                    &state.db,
-                    &github_user.login,
-                    AuthType::GitHub,
-                )
-                .await?;
+#[async_trait]
+impl OAuthProvider for GoogleProvider {
+    fn name(&self) -> &'static str {
+        "google"
+    }
 
-                // Create new user
-                let user = state.db.create_user(&username, AuthType::GitHub).await?;
+    fn display_name(&self) -> &'static str {
+        "Google"
+    }
 
-                // Store GitHub credentials
-                store_credentials(
-                    &state.db,
-                    &user.id,
-                    None,
-                    Some("github"),
-                    Some(&github_user.id.to_string()),
-                    None,
-                )
-                .await?;
+    fn auth_url(&self) -> &'static str {
+        "https://accounts.google.com/o/oauth2/v2/auth"
+    }
 
-                let token = create_token(&user.id)?;
-                let redirect_to = redirect_to.unwrap_or_else(|| "/mailboxes".to_string());
-                Ok(Json(AuthResponse {
-                    token,
-                    user,
-                    redirect_to,
-                }))
-            }
+    fn token_url(&self) -> &'static str {
+        "https://oauth2.googleapis.com/token"
+    }
+
+    fn user_info_url(&self) -> &'static str {
+        "https://www.googleapis.com/oauth2/v2/userinfo"
+    }
+
+    fn scopes(&self) -> &'static [&'static str] {
+        &[
+            "https://www.googleapis.com/auth/userinfo.profile",
+            "https://www.googleapis.com/auth/userinfo.email",
+        ]
+    }
+
+    fn auth_type(&self) -> AuthType {
+        AuthType::Google
+    }
+
+    fn credential_column(&self) -> &'static str {
+        "google_id"
+    }
+
+    async fn get_user_info(&self, http: &CircuitBreaker, access_token: &str) -> Result<OAuthUserInfo, AppError> {
+        #[derive(Debug, Deserialize)]
+        struct GoogleUser {
+            id: String,
+            email: String,
+            verified_email: bool,
         }
 
-        // Invalid action
-        _ => Err(AppError::Auth("Invalid authentication action".to_string())),
+        let request = reqwest::Client::new()
+            .get(self.user_info_url())
+            .header("Authorization", format!("Bearer {}", access_token));
+        let google_user: GoogleUser = http.send(request)
+            .await
+            .map_err(|e| AppError::Auth(format!("Failed to get Google user info: {}", e)))?
+            .json()
+            .await
+            .map_err(|e| AppError::Auth(format!("Failed to parse Google user info: {}", e)))?;
+
+        if !google_user.verified_email {
+            return Err(AppError::Auth("Google email not verified".to_string()));
+        }
+
+        // Usernames are derived from the email's local part
+        let username = google_user
+            .email
+            .split('@')
+            .next()
+            .unwrap_or(&google_user.email)
+            .to_string();
+
+        Ok(OAuthUserInfo {
+            id: google_user.id,
+            username,
+        })
     }
 }
 
-// Google OAuth handlers
-pub async fn google_login_handler(
+pub async fn oauth_login_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(provider): Path<String>,
     Query(params): Query<HashMap<String, String>>,
 ) -> Result<Redirect, AppError> {
-    let client = google_oauth_client()?;
-    let app_url = get_app_url();
-    let redirect_url = format!("{}/auth/google/callback", app_url);
+    let provider = find_provider(&state, &provider)?;
+    let action = params.get("action").map(String::as_str).unwrap_or("login");
+    if !matches!(action, "login" | "register") {
+        // Connecting needs the signed-in user, see oauth_connect_handler
+        return Err(AppError::Auth("Invalid authentication action".to_string()));
+    }
 
-    let (auth_url, csrf_token) = client
-        .authorize_url(CsrfToken::new_random)
-        .add_scope(Scope::new(
-            "https://www.googleapis.com/auth/userinfo.profile".to_string(),
-        ))
-        .add_scope(Scope::new(
-            "https://www.googleapis.com/auth/userinfo.email".to_string(),
-        ))
-        .set_redirect_uri(Cow::Owned(RedirectUrl::new(redirect_url).unwrap()))
-        .url();
-
-    // Store redirect_to, user_id, and action in state if provided
-    let state = if let Some(redirect_to) = params.get("redirect_to") {
-        if let Some(user_id) = params.get("state") {
-            if let Some(action) = params.get("action") {
-                format!(
-                    "{}:{}:{}:{}",
-                    csrf_token.secret(),
-                    redirect_to,
-                    user_id,
-                    action
-                )
-            } else {
-                format!("{}:{}:{}", csrf_token.secret(), redirect_to, user_id)
-            }
-        } else if let Some(action) = params.get("action") {
-            format!("{}:{}::{}", csrf_token.secret(), redirect_to, action)
-        } else {
-            format!("{}:{}", csrf_token.secret(), redirect_to)
-        }
-    } else if let Some(user_id) = params.get("state") {
-        if let Some(action) = params.get("action") {
-            format!("{}::{}:{}", csrf_token.secret(), user_id, action)
-        } else {
-            format!("{}::{}", csrf_token.secret(), user_id)
-        }
-    } else if let Some(action) = params.get("action") {
-        format!("{}:::{}", csrf_token.secret(), action)
-    } else {
-        csrf_token.secret().to_string()
-    };
+    let auth_url = start_authorization(&state.db, provider, params.get("redirect_to").map(String::as_str), None, action).await?;
+    Ok(Redirect::to(auth_url.as_str()))
+}
 
-    Ok(Redirect::to(
-        &auth_url.to_string().replace(csrf_token.secret(), &state),
-    ))
+/// Starts linking a provider account to the signed-in user, returning the
+/// authorization URL rather than redirecting so the request can carry the
+/// user's token
+pub async fn oauth_connect_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(provider): Path<String>,
+    claims: axum::extract::Extension<Claims>,
+    body: Option<Json<OAuthConnectRequest>>,
+) -> Result<Json<ApiResponse<OAuthConnectResponse>>, AppError> {
+    let provider = find_provider(&state, &provider)?;
+    let Json(req) = body.unwrap_or_default();
+
+    let auth_url = start_authorization(&state.db, provider, req.redirect_to.as_deref(), Some(&claims.sub), "connect").await?;
+    Ok(Json(ApiResponse::success(OAuthConnectResponse { url: auth_url.into() })))
 }
 
-pub async fn google_callback_handler<D: Database>(
+pub async fn oauth_callback_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
+    Path(provider): Path<String>,
     Query(params): Query<OAuthCallback>,
+    request_info: RequestInfo,
 ) -> Result<Json<AuthResponse>, AppError> {
-    let client = google_oauth_client()?;
-
-    // Extract redirect_to, user_id, and action from state if present
-    let state_str = params.state.clone();
-    let parts: Vec<&str> = state_str.split(':').collect();
-    let (_csrf_state, redirect_to, user_id, action) = match parts.len() {
-        4 => (
-            parts[0].to_string(),
-            Some(parts[1].to_string()),
-            Some(parts[2].to_string()),
-            Some(parts[3].to_string()),
-        ),
-        3 => (
-            parts[0].to_string(),
-            Some(parts[1].to_string()),
-            Some(parts[2].to_string()),
-            None,
-        ),
-        2 => (parts[0].to_string(), Some(parts[1].to_string()), None, None),
-        _ => (state_str, None, None, None),
-    };
-
-    // Exchange the code for an access token
-    let token = client
-        .exchange_code(AuthorizationCode::new(params.code))
-        .add_extra_param("Accept", "application/json")
-        .request_async(oauth2::reqwest::async_http_client)
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to exchange GitHub code: {}", e)))?;
-
-    // Get Google user info
-    let google_user: GoogleUser = reqwest::Client::new()
-        .get("https://www.googleapis.com/oauth2/v2/userinfo")
-        .header(
-            "Authorization",
-            format!("Bearer {}", token.access_token().secret()),
-        )
-        .send()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to get Google user info: {}", e)))?
-        .json()
-        .await
-        .map_err(|e| AppError::Auth(format!("Failed to parse Google user info: {}", e)))?;
-
-    if !google_user.verified_email {
-        return Err(AppError::Auth("Google email not verified".to_string()));
-    }
+    let provider = find_provider(&state, &provider)?;
+    let display_name = provider.display_name();
+
+    // Checked before the code is used, so a forged callback fails here
+    let PendingAuthorization { code_verifier, redirect_to, user_id, action } =
+        take_pending_authorization(&state.db, provider, &params.state).await?;
+
+    let metadata = serde_json::json!({ "method": display_name });
+    let access_token = exchange_code(
+        provider,
+        &state.circuit_breaker,
+        &params.code,
+        &callback_url(provider)?,
+        &code_verifier,
+    )
+    .await?;
+    let user_info = provider.get_user_info(&state.circuit_breaker, &access_token).await?;
 
-    // Check if user exists with this Google ID
-    let existing_user = sqlx::query_as::<_, User>(
+    // Check if user exists with this provider ID
+    let existing_user = sqlx::query_as::<_, User>(&format!(
         "SELECT u.* FROM users u
          JOIN user_credentials c ON u.id = c.user_id
-         WHERE c.google_id = ?",
-    )
-    .bind(&google_user.id)
+         WHERE c.{} = ?",
+        provider.credential_column()
+    ))
+    .bind(&user_info.id)
     .fetch_optional(state.db.pool())
     .await
-    .map_err(|e| AppError::Database(e.to_string()))?;
+    .map_err(database_error)?;
 
-    // Handle different actions
-    match action.as_deref().or(params.action.as_deref()) {
-        // Connect action - link Google account to existing user
+    // Handle different actions, only ever the one stored by the server
+    match action.as_deref() {
+        // Connect action - link the provider account to existing user
         Some("connect") => {
             let user_id = user_id
                 .ok_or_else(|| AppError::Auth("Invalid state for connect action".to_string()))?;
 
-            // Check if this Google account is already connected to another user
+            // Check if this account is already connected to another user
             if let Some(existing) = &existing_user {
                 if existing.id != user_id {
-                    return Err(AppError::Auth(
-                        "This Google account is already connected to another user".to_string(),
-                    ));
+                    return Err(AppError::Auth(format!(
+                        "This {} account is already connected to another user",
+                        display_name
+                    )));
                 }
-                return Err(AppError::Auth(
-                    "This Google account is already connected to your account".to_string(),
-                ));
+                return Err(AppError::Auth(format!(
+                    "This {} account is already connected to your account",
+                    display_name
+                )));
             }
 
             // Update the user's credentials while preserving other OAuth connections
-            sqlx::query(
-                "UPDATE user_credentials 
-                 SET google_id = ?,
+            sqlx::query(&format!(
+                "UPDATE user_credentials
+                 SET {} = ?,
                      updated_at = ?
                  WHERE user_id = ?",
-            )
-            .bind(&google_user.id)
+                provider.credential_column()
+            ))
+            .bind(&user_info.id)
             .bind(chrono::Utc::now().timestamp())
             .bind(&user_id)
             .execute(state.db.pool())
             .await
-            .map_err(|e| AppError::Database(e.to_string()))?;
+            .map_err(database_error)?;
 
             // Return success response
             let user = sqlx::query_as::<_, User>("SELECT * FROM users WHERE id = ?")
                 .bind(&user_id)
                 .fetch_one(state.db.pool())
                 .await
-                .map_err(|e| AppError::Database(e.to_string()))?;
+                .map_err(database_error)?;
+            request_info.audit(&state.db, &user.id, audit::CONNECT_PROVIDER, None, Some(metadata)).await;
 
-            let token = create_token(&user.id)?;
             let redirect_to = redirect_to.unwrap_or_else(|| "/settings?success=true".to_string());
             Ok(Json(AuthResponse {
-                token,
-                user,
+                auth: None,
                 redirect_to,
             }))
         }
@@ -475,58 +350,55 @@ pub async fn google_callback_handler<D: Database>(
         // Login action - check if account exists
         Some("login") => match existing_user {
             Some(user) => {
-                let token = create_token(&user.id)?;
+                request_info.audit(&state.db, &user.id, audit::LOGIN, None, Some(metadata)).await;
+                security_log::record_login(&state.db, &request_info, &user.id).await;
+                let auth = issue_tokens(&state.db, user).await?;
                 let redirect_to = redirect_to.unwrap_or_else(|| "/mailboxes".to_string());
                 Ok(Json(AuthResponse {
-                    token,
-                    user,
+                    auth: Some(auth),
                     redirect_to,
                 }))
             }
-            None => Err(AppError::Auth(
-                "No account found with this Google account. Please register first.".to_string(),
-            )),
+            None => Err(AppError::Auth(format!(
+                "No account found with this {} account. Please register first.",
+                display_name
+            ))),
         },
 
         // Register action - create new account
         Some("register") => {
             if existing_user.is_some() {
-                Err(AppError::Auth(
-                    "This Google account is already registered. Please login instead.".to_string(),
-                ))
+                Err(AppError::Auth(format!(
+                    "This {} account is already registered. Please login instead.",
+                    display_name
+                )))
             } else {
-                // Generate unique username from email
-                let base_username = google_user
-                    .email
-                    .split('@')
-                    .next()
-                    .unwrap_or(&google_user.email);
                 let username = crate::auth::generate_unique_username(
                     &state.db,
-                    base_username,
-                    AuthType::Google,
+                    &user_info.username,
+                    provider.auth_type(),
                 )
                 .await?;
 
                 // Create new user
-                let user = state.db.create_user(&username, AuthType::Google).await?;
+                let user = state.db.create_user(&username, provider.auth_type()).await?;
 
-                // Store Google credentials
+                // Store provider credentials
                 store_credentials(
                     &state.db,
                     &user.id,
                     None,
-                    Some("google"),
-                    Some(&google_user.id),
+                    Some(provider.credential_column()),
+                    Some(&user_info.id),
                     None,
                 )
                 .await?;
+                request_info.audit(&state.db, &user.id, audit::REGISTER, Some(("user", &user.id)), Some(metadata)).await;
 
-                let token = create_token(&user.id)?;
+                let auth = issue_tokens(&state.db, user).await?;
                 let redirect_to = redirect_to.unwrap_or_else(|| "/mailboxes".to_string());
                 Ok(Json(AuthResponse {
-                    token,
-                    user,
+                    auth: Some(auth),
                     redirect_to,
                 }))
             }
@@ -537,54 +409,303 @@ pub async fn google_callback_handler<D: Database>(
     }
 }
 
+pub async fn oauth_disconnect_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(provider): Path<String>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<()>>, AppError> {
+    let provider = find_provider(&state, &provider)?;
+    let display_name = provider.display_name();
+
+    let connected: Option<String> = sqlx::query_scalar(&format!(
+        "SELECT {} FROM user_credentials WHERE user_id = ?",
+        provider.credential_column()
+    ))
+    .bind(&claims.sub)
+    .fetch_one(state.db.pool())
+    .await
+    .map_err(database_error)?;
+
+    if connected.is_none() {
+        return Err(AppError::Auth(format!("No {} account connected", display_name)));
+    }
+
+    // Ensure user has at least one other authentication method
+    if count_auth_methods(&state, &claims.sub).await? <= 1 {
+        return Err(AppError::Auth(format!(
+            "Cannot disconnect {} account: it is your only authentication method",
+            display_name
+        )));
+    }
+
+    // Remove provider credentials
+    sqlx::query(&format!(
+        "UPDATE user_credentials
+         SET {} = NULL,
+             updated_at = ?
+         WHERE user_id = ?",
+        provider.credential_column()
+    ))
+    .bind(chrono::Utc::now().timestamp())
+    .bind(&claims.sub)
+    .execute(state.db.pool())
+    .await
+    .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
 // Helper functions
 
-fn get_app_url() -> String {
-    get_web_app_url()
+fn find_provider<'a, D: Database>(
+    state: &'a AppState<D>,
+    name: &str,
+) -> Result<&'a dyn OAuthProvider, AppError> {
+    state
+        .oauth_providers
+        .iter()
+        .find(|provider| provider.name() == name)
+        .map(|provider| provider.as_ref())
+        .ok_or_else(|| AppError::NotFound(format!("Unknown authentication provider: {}", name)))
+}
+
+fn client_credentials(provider: &dyn OAuthProvider) -> Result<(String, String), AppError> {
+    let prefix = provider.name().to_ascii_uppercase();
+    let read = |suffix: &str| {
+        let name = format!("{}_{}", prefix, suffix);
+        std::env::var(&name).map_err(|_| AppError::Internal(format!("{} not set", name)))
+    };
+    Ok((read("CLIENT_ID")?, read("CLIENT_SECRET")?))
+}
+
+fn callback_url(provider: &dyn OAuthProvider) -> Result<RedirectUrl, AppError> {
+    RedirectUrl::new(format!("{}/auth/{}/callback", get_web_app_url(), provider.name()))
+        .map_err(|e| AppError::Internal(format!("Invalid redirect URL: {}", e)))
+}
+
+/// Stores what the callback needs under a new CSRF token, returning the
+/// provider's authorization URL. Only the token travels through the provider.
+async fn start_authorization<D: Database>(
+    db: &D,
+    provider: &dyn OAuthProvider,
+    redirect_to: Option<&str>,
+    user_id: Option<&str>,
+    action: &str,
+) -> Result<Url, AppError> {
+    let (auth_url, csrf_token, code_verifier) = authorization_url(provider, callback_url(provider)?)?;
+
+    let now = chrono::Utc::now().timestamp();
+    sqlx::query("DELETE FROM oauth_states WHERE expires_at <= ?")
+        .bind(now)
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+    sqlx::query(
+        "INSERT INTO oauth_states (state_hash, provider, code_verifier, redirect_to, user_id, action, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(hash_token(csrf_token.secret()))
+    .bind(provider.name())
+    .bind(code_verifier.secret())
+    .bind(redirect_to)
+    .bind(user_id)
+    .bind(action)
+    .bind(now + OAUTH_STATE_LIFETIME_SECS)
+    .execute(db.pool())
+    .await
+    .map_err(database_error)?;
+
+    Ok(auth_url)
 }
 
-fn github_oauth_client() -> Result<BasicClient, AppError> {
-    let client_id = ClientId::new(
-        std::env::var("GITHUB_CLIENT_ID")
-            .map_err(|_| AppError::Internal("GITHUB_CLIENT_ID not set".to_string()))?,
-    );
-    let client_secret = ClientSecret::new(
-        std::env::var("GITHUB_CLIENT_SECRET")
-            .map_err(|_| AppError::Internal("GITHUB_CLIENT_SECRET not set".to_string()))?,
-    );
-    let auth_url = AuthUrl::new("https://github.com/login/oauth/authorize".to_string())
-        .map_err(|e| AppError::Internal(format!("Invalid GitHub auth URL: {}", e)))?;
-    let token_url = TokenUrl::new("https://github.com/login/oauth/access_token".to_string())
-        .map_err(|e| AppError::Internal(format!("Invalid GitHub token URL: {}", e)))?;
-    let app_url = get_app_url();
-    let redirect_url = RedirectUrl::new(format!("{}/auth/github/callback", app_url))
-        .map_err(|e| AppError::Internal(format!("Invalid redirect URL: {}", e)))?;
-
-    Ok(
-        BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
-            .set_redirect_uri(redirect_url),
+/// Removes the authorization stored under the CSRF token by the login
+/// request, failing when it is unknown, expired or for another provider
+async fn take_pending_authorization<D: Database>(
+    db: &D,
+    provider: &dyn OAuthProvider,
+    csrf_token: &str,
+) -> Result<PendingAuthorization, AppError> {
+    let row = sqlx::query(
+        "DELETE FROM oauth_states WHERE state_hash = ? AND provider = ? AND expires_at > ?
+         RETURNING code_verifier, redirect_to, user_id, action",
     )
+    .bind(hash_token(csrf_token))
+    .bind(provider.name())
+    .bind(chrono::Utc::now().timestamp())
+    .fetch_optional(db.pool())
+    .await
+    .map_err(database_error)?
+    .ok_or_else(|| AppError::Auth("The login has expired or is invalid. Please sign in again.".to_string()))?;
+
+    Ok(PendingAuthorization {
+        code_verifier: PkceCodeVerifier::new(row.get("code_verifier")),
+        redirect_to: row.get("redirect_to"),
+        user_id: row.get("user_id"),
+        action: row.get("action"),
+    })
+}
+
+/// The provider's authorization URL with a new CSRF token as `state` and an
+/// S256 PKCE challenge, whose verifier the callback sends with the code
+fn authorization_url(
+    provider: &dyn OAuthProvider,
+    redirect_url: RedirectUrl,
+) -> Result<(Url, CsrfToken, PkceCodeVerifier), AppError> {
+    let (code_challenge, code_verifier) = PkceCodeChallenge::new_random_sha256();
+    let client = oauth_client(provider, redirect_url)?;
+    let mut request = client
+        .authorize_url(CsrfToken::new_random)
+        .set_pkce_challenge(code_challenge);
+    for scope in provider.scopes() {
+        request = request.add_scope(Scope::new(scope.to_string()));
+    }
+    let (auth_url, csrf_token) = request.url();
+    Ok((auth_url, csrf_token, code_verifier))
 }
 
-fn google_oauth_client() -> Result<BasicClient, AppError> {
-    let client_id = ClientId::new(
-        std::env::var("GOOGLE_CLIENT_ID")
-            .map_err(|_| AppError::Internal("GOOGLE_CLIENT_ID not set".to_string()))?,
-    );
-    let client_secret = ClientSecret::new(
-        std::env::var("GOOGLE_CLIENT_SECRET")
-            .map_err(|_| AppError::Internal("GOOGLE_CLIENT_SECRET not set".to_string()))?,
-    );
-    let auth_url = AuthUrl::new("https://accounts.google.com/o/oauth2/v2/auth".to_string())
-        .map_err(|e| AppError::Internal(format!("Invalid Google auth URL: {}", e)))?;
-    let token_url = TokenUrl::new("https://oauth2.googleapis.com/token".to_string())
-        .map_err(|e| AppError::Internal(format!("Invalid Google token URL: {}", e)))?;
-    let frontend_url = get_app_url();
-    let redirect_url = RedirectUrl::new(format!("{}/auth/google/callback", frontend_url))
-        .map_err(|e| AppError::Internal(format!("Invalid redirect URL: {}", e)))?;
-
-    Ok(
-        BasicClient::new(client_id, Some(client_secret), auth_url, Some(token_url))
-            .set_redirect_uri(redirect_url),
+fn oauth_client(provider: &dyn OAuthProvider, redirect_url: RedirectUrl) -> Result<BasicClient, AppError> {
+    let (client_id, client_secret) = client_credentials(provider)?;
+    let display_name = provider.display_name();
+    let auth_url = AuthUrl::new(provider.auth_url().to_string())
+        .map_err(|e| AppError::Internal(format!("Invalid {} auth URL: {}", display_name, e)))?;
+    let token_url = TokenUrl::new(provider.token_url().to_string())
+        .map_err(|e| AppError::Internal(format!("Invalid {} token URL: {}", display_name, e)))?;
+
+    Ok(BasicClient::new(
+        ClientId::new(client_id),
+        Some(ClientSecret::new(client_secret)),
+        auth_url,
+        Some(token_url),
     )
+    .set_redirect_uri(redirect_url))
+}
+
+/// Exchanges the authorization code for an access token. Credentials are sent
+/// in the form body and JSON is requested explicitly, which GitHub requires.
+async fn exchange_code(
+    provider: &dyn OAuthProvider,
+    http: &CircuitBreaker,
+    code: &str,
+    redirect_url: &RedirectUrl,
+    code_verifier: &PkceCodeVerifier,
+) -> Result<String, AppError> {
+    let (client_id, client_secret) = client_credentials(provider)?;
+    let display_name = provider.display_name();
+
+    let request = reqwest::Client::new()
+        .post(provider.token_url())
+        .header("Accept", "application/json")
+        .header("User-Agent", "vh-mail-hook")
+        .form(&[
+            ("client_id", client_id),
+            ("client_secret", client_secret),
+            ("code", code.to_string()),
+            ("grant_type", "authorization_code".to_string()),
+            ("redirect_uri", redirect_url.to_string()),
+            ("code_verifier", code_verifier.secret().to_string()),
+        ]);
+    let body = http.send(request)
+        .await
+        .map_err(|e| AppError::Auth(format!("Failed to exchange {} code: {}", display_name, e)))?
+        .text()
+        .await
+        .map_err(|e| AppError::Auth(format!("Failed to get token response text: {}", e)))?;
+
+    #[derive(Deserialize)]
+    struct TokenResponse {
+        access_token: String,
+    }
+
+    let token: TokenResponse = serde_json::from_str(&body).map_err(|e| {
+        AppError::Auth(format!("Failed to parse {} token response: {}", display_name, e))
+    })?;
+
+    Ok(token.access_token)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use wiremock::{
+        matchers::{body_string_contains, method, path},
+        Mock, MockServer, ResponseTemplate,
+    };
+
+    struct MockProvider {
+        auth_url: &'static str,
+        token_url: &'static str,
+    }
+
+    #[async_trait]
+    impl OAuthProvider for MockProvider {
+        fn name(&self) -> &'static str {
+            "mock"
+        }
+
+        fn display_name(&self) -> &'static str {
+            "Mock"
+        }
+
+        fn auth_url(&self) -> &'static str {
+            self.auth_url
+        }
+
+        fn token_url(&self) -> &'static str {
+            self.token_url
+        }
+
+        fn user_info_url(&self) -> &'static str {
+            unreachable!()
+        }
+
+        fn scopes(&self) -> &'static [&'static str] {
+            &["profile"]
+        }
+
+        fn auth_type(&self) -> AuthType {
+            AuthType::GitHub
+        }
+
+        fn credential_column(&self) -> &'static str {
+            "github_id"
+        }
+
+        async fn get_user_info(&self, _http: &CircuitBreaker, _access_token: &str) -> Result<OAuthUserInfo, AppError> {
+            unreachable!()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_pkce() {
+        std::env::set_var("MOCK_CLIENT_ID", "client-id");
+        std::env::set_var("MOCK_CLIENT_SECRET", "client-secret");
+        let server = MockServer::start().await;
+        let provider = MockProvider {
+            auth_url: format!("{}/authorize", server.uri()).leak(),
+            token_url: format!("{}/token", server.uri()).leak(),
+        };
+        let redirect_url = RedirectUrl::new("http://localhost/auth/mock/callback".to_string()).unwrap();
+
+        let (auth_url, csrf_token, code_verifier) = authorization_url(&provider, redirect_url.clone()).unwrap();
+        let query: HashMap<String, String> = auth_url.query_pairs().into_owned().collect();
+        assert_eq!(query["state"], *csrf_token.secret());
+        assert_eq!(query["code_challenge_method"], "S256");
+        let expected_challenge = PkceCodeChallenge::from_code_verifier_sha256(&code_verifier);
+        assert_eq!(query["code_challenge"], expected_challenge.as_str());
+        assert!((43..=128).contains(&code_verifier.secret().len()));
+
+        Mock::given(method("POST"))
+            .and(path("/token"))
+            .and(body_string_contains("code=auth-code"))
+            .and(body_string_contains(format!("code_verifier={}", code_verifier.secret())))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "access_token": "access-token" })))
+            .expect(1)
+            .mount(&server)
+            .await;
+
+        let access_token = exchange_code(&provider, &CircuitBreaker::default(), "auth-code", &redirect_url, &code_verifier)
+            .await
+            .unwrap();
+        assert_eq!(access_token, "access-token");
+    }
 }
@@ -3,6 +3,65 @@ use argon2::{
     Argon2,
 };
 use common::AppError;
+use serde::Serialize;
+use std::sync::OnceLock;
+
+use super::common_passwords::COMMON_PASSWORDS;
+
+const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// A requirement of [`validate_password_strength`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PasswordCheck {
+    TooShort,
+    NoUppercase,
+    NoLowercase,
+    NoDigit,
+    Common,
+}
+
+impl PasswordCheck {
+    fn message(self) -> String {
+        match self {
+            Self::TooShort => format!("Password must be at least {} characters long.", MIN_PASSWORD_LENGTH),
+            Self::NoUppercase => "Password must contain at least one uppercase letter.".to_string(),
+            Self::NoLowercase => "Password must contain at least one lowercase letter.".to_string(),
+            Self::NoDigit => "Password must contain at least one digit.".to_string(),
+            Self::Common => "Password is too common. Please choose a less predictable one.".to_string(),
+        }
+    }
+}
+
+/// The requirements `password` fails, in the order they are reported
+pub fn failed_checks(password: &str) -> Vec<PasswordCheck> {
+    let checks = [
+        (PasswordCheck::TooShort, password.chars().count() >= MIN_PASSWORD_LENGTH),
+        (PasswordCheck::NoUppercase, password.chars().any(char::is_uppercase)),
+        (PasswordCheck::NoLowercase, password.chars().any(char::is_lowercase)),
+        (PasswordCheck::NoDigit, password.chars().any(|c| c.is_ascii_digit())),
+        // The list is lowercase, so "Password1" counts as "password1"
+        (PasswordCheck::Common, !COMMON_PASSWORDS.contains(password.to_lowercase().as_str())),
+    ];
+    checks.into_iter().filter(|(_, passed)| !passed).map(|(check, _)| check).collect()
+}
+
+/// From 0 to 4, one point per passed character and length requirement. Common
+/// passwords score 0 whatever they are made of.
+pub fn strength_score(failed: &[PasswordCheck]) -> u8 {
+    if failed.contains(&PasswordCheck::Common) {
+        return 0;
+    }
+    4 - failed.len() as u8
+}
+
+/// Fails with the first requirement `password` doesn't meet
+pub fn validate_password_strength(password: &str) -> Result<(), AppError> {
+    match failed_checks(password).first() {
+        Some(check) => Err(AppError::Auth(check.message())),
+        None => Ok(()),
+    }
+}
 
 pub fn hash_password(password: &str) -> Result<String, AppError> {
     let salt = SaltString::generate(&mut OsRng);
@@ -21,4 +80,59 @@ pub fn verify_password(password: &str, hash: &str) -> Result<bool, AppError> {
     Ok(Argon2::default()
         .verify_password(password.as_bytes(), &parsed_hash)
         .is_ok())
-} 
\ No newline at end of file
+} 
+/// Verifies against a throwaway hash when there is no real one to check, so
+/// failing for an unknown user takes as long as for a wrong password
+pub fn verify_dummy_password(password: &str) {
+    static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+    let hash = DUMMY_HASH.get_or_init(|| {
+        hash_password(&common::generate_random_id(32, common::IdCharset::UrlSafe))
+            .expect("hashing a random password doesn't fail")
+    });
+    let _ = verify_password(password, hash);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_rules() {
+        assert!(validate_password_strength("Tr0ub4dor-horse").is_ok());
+        for (password, check) in [
+            ("Sh0rt", PasswordCheck::TooShort),
+            ("lowercase-only-1", PasswordCheck::NoUppercase),
+            ("UPPERCASE-ONLY-1", PasswordCheck::NoLowercase),
+            ("No-Digits-Here", PasswordCheck::NoDigit),
+            ("Password1", PasswordCheck::Common),
+            ("1Qaz2wsx", PasswordCheck::Common),
+        ] {
+            assert_eq!(failed_checks(password), vec![check], "{}", password);
+            let Err(AppError::Auth(message)) = validate_password_strength(password) else {
+                panic!("{} was accepted", password);
+            };
+            assert_eq!(message, check.message());
+        }
+    }
+
+    #[test]
+    fn test_common_passwords() {
+        assert_eq!(COMMON_PASSWORDS.len(), 1000);
+        assert!(COMMON_PASSWORDS.iter().all(|password| password.to_lowercase() == *password));
+        for password in ["123456", "password", "qwerty", "letmein", "trustno1"] {
+            assert!(failed_checks(password).contains(&PasswordCheck::Common), "{}", password);
+        }
+        assert!(!failed_checks("Tr0ub4dor-horse").contains(&PasswordCheck::Common));
+    }
+
+    #[test]
+    fn test_strength_score() {
+        let score = |password| strength_score(&failed_checks(password));
+        assert_eq!(score("Tr0ub4dor-horse"), 4);
+        assert_eq!(score("tr0ub4dor-horse"), 3);
+        assert_eq!(score("tr0ub"), 2);
+        assert_eq!(score("!!!"), 0);
+        // Meets every other requirement, but is on the list
+        assert_eq!(score("Password1"), 0);
+    }
+}
@@ -0,0 +1,89 @@
+//! Refresh tokens let clients keep a session beyond the short lifetime of
+//! access tokens. Each one is single use: refreshing revokes it and issues
+//! a new one along with the access token. Only their SHA-256 is stored.
+
+use crate::{ApiResponse, AppState};
+use axum::extract::{Extension, Json, State};
+use common::{db::{database_error, Database}, generate_random_id, AppError, IdCharset};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::sync::Arc;
+
+use super::{issue_tokens, sessions, AuthResponse, Claims};
+
+const REFRESH_TOKEN_LIFETIME_SECS: i64 = 90 * 24 * 3600;
+const REFRESH_TOKEN_LENGTH: usize = 48;
+
+#[derive(Debug, Deserialize)]
+pub struct RefreshTokenRequest {
+    pub refresh_token: String,
+}
+
+pub(super) fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub(super) async fn create_refresh_token<D: Database>(db: &D, user_id: &str) -> Result<String, AppError> {
+    let token = generate_random_id(REFRESH_TOKEN_LENGTH, IdCharset::UrlSafe);
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query(
+        "INSERT INTO refresh_tokens (id, user_id, token_hash, created_at, expires_at) VALUES (?, ?, ?, ?, ?)",
+    )
+    .bind(uuid::Uuid::new_v4().to_string())
+    .bind(user_id)
+    .bind(hash_token(&token))
+    .bind(now)
+    .bind(now + REFRESH_TOKEN_LIFETIME_SECS)
+    .execute(db.pool())
+    .await
+    .map_err(database_error)?;
+
+    Ok(token)
+}
+
+pub(super) async fn refresh_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Json(req): Json<RefreshTokenRequest>,
+) -> Result<Json<ApiResponse<AuthResponse>>, AppError> {
+    let now = chrono::Utc::now().timestamp();
+
+    // Revoking in the lookup keeps two requests from both using the token
+    let user_id: Option<String> = sqlx::query_scalar(
+        "UPDATE refresh_tokens SET revoked_at = ?
+         WHERE token_hash = ? AND revoked_at IS NULL AND expires_at > ?
+         RETURNING user_id",
+    )
+    .bind(now)
+    .bind(hash_token(&req.refresh_token))
+    .bind(now)
+    .fetch_optional(state.db.pool())
+    .await
+    .map_err(database_error)?;
+
+    let session_expired = || AppError::Auth("Your session has expired. Please log in again to continue.".to_string());
+    let user_id = user_id.ok_or_else(session_expired)?;
+    let user = state.db.get_user(&user_id).await?.ok_or_else(session_expired)?;
+
+    Ok(Json(ApiResponse::success(issue_tokens(&state.db, user).await?)))
+}
+
+/// Revokes the refresh token, and the access token the request is
+/// authenticated with if any. Other access tokens stay valid until they expire.
+pub(super) async fn logout_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: Option<Extension<Claims>>,
+    Json(req): Json<RefreshTokenRequest>,
+) -> Result<Json<ApiResponse<()>>, AppError> {
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE token_hash = ? AND revoked_at IS NULL")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(hash_token(&req.refresh_token))
+        .execute(state.db.pool())
+        .await
+        .map_err(database_error)?;
+    if let Some(Extension(claims)) = claims {
+        sessions::revoke_token(&state.db, &claims).await?;
+    }
+
+    Ok(Json(ApiResponse::success(())))
+}
@@ -0,0 +1,98 @@
+//! Revocation of access tokens before they expire. Each token carries a
+//! random `jti` recorded when it is issued, and revoked JTIs are rejected
+//! until the token would have expired anyway.
+
+use crate::{audit::{self, RequestInfo}, ApiResponse, AppState};
+use axum::extract::{Json, State};
+use common::{db::{database_error, Database}, AppError};
+use serde::Serialize;
+use std::sync::Arc;
+
+use super::Claims;
+
+#[derive(Debug, Serialize)]
+pub struct RevokeSessionsResponse {
+    /// Access tokens revoked, not counting refresh tokens
+    pub revoked_count: u64,
+}
+
+pub(super) async fn record_issued_token<D: Database>(
+    db: &D,
+    jti: &str,
+    user_id: &str,
+    issued_at: i64,
+    expires_at: i64,
+) -> Result<(), AppError> {
+    sqlx::query("INSERT INTO issued_tokens (jti, user_id, issued_at, expires_at) VALUES (?, ?, ?, ?)")
+        .bind(jti)
+        .bind(user_id)
+        .bind(issued_at)
+        .bind(expires_at)
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+    Ok(())
+}
+
+pub(super) async fn is_token_revoked<D: Database>(db: &D, jti: &str) -> Result<bool, AppError> {
+    sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM token_revocations WHERE jti = ?)")
+        .bind(jti)
+        .fetch_one(db.pool())
+        .await
+        .map_err(database_error)
+}
+
+/// Revokes one access token. Tokens issued before JWT IDs were added have
+/// none and can't be revoked individually.
+pub(super) async fn revoke_token<D: Database>(db: &D, claims: &Claims) -> Result<(), AppError> {
+    if claims.jti.is_empty() {
+        return Ok(());
+    }
+    sqlx::query(
+        "INSERT INTO token_revocations (jti, user_id, revoked_at, expires_at) VALUES (?, ?, ?, ?)
+         ON CONFLICT (jti) DO NOTHING",
+    )
+    .bind(&claims.jti)
+    .bind(&claims.sub)
+    .bind(chrono::Utc::now().timestamp())
+    .bind(claims.exp as i64)
+    .execute(db.pool())
+    .await
+    .map_err(database_error)?;
+    Ok(())
+}
+
+/// Signs the user out everywhere: every unexpired access token is revoked,
+/// the caller's included, along with every refresh token
+pub(super) async fn revoke_sessions_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: RequestInfo,
+) -> Result<Json<ApiResponse<RevokeSessionsResponse>>, AppError> {
+    let now = chrono::Utc::now().timestamp();
+
+    let mut tx = state.db.pool().begin().await.map_err(database_error)?;
+    let revoked = sqlx::query(
+        "INSERT INTO token_revocations (jti, user_id, revoked_at, expires_at)
+         SELECT jti, user_id, ?1, expires_at FROM issued_tokens WHERE user_id = ?2 AND expires_at > ?1
+         ON CONFLICT (jti) DO NOTHING",
+    )
+    .bind(now)
+    .bind(&claims.sub)
+    .execute(&mut *tx)
+    .await
+    .map_err(database_error)?;
+    sqlx::query("UPDATE refresh_tokens SET revoked_at = ? WHERE user_id = ? AND revoked_at IS NULL")
+        .bind(now)
+        .bind(&claims.sub)
+        .execute(&mut *tx)
+        .await
+        .map_err(database_error)?;
+    tx.commit().await.map_err(database_error)?;
+
+    let revoked_count = revoked.rows_affected();
+    let metadata = serde_json::json!({ "revoked_count": revoked_count });
+    request_info.audit(&state.db, &claims.sub, audit::REVOKE_SESSIONS, None, Some(metadata)).await;
+
+    Ok(Json(ApiResponse::success(RevokeSessionsResponse { revoked_count })))
+}
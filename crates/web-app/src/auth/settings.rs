@@ -0,0 +1,65 @@
+//! The signed-in user's settings. Users change their own preferences here,
+//! while quotas and limits are set through `/api/admin/users/:id/limits`.
+//! Served at `/api/user/settings`, and at `/api/auth/settings` where the
+//! update was first added.
+
+use crate::{auth::Claims, nullable, validate_expires_in, ApiResponse, AppState};
+use axum::extract::{Json, State};
+use common::{db::Database, AppError, CleanupPolicy, UserSettings};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// Fields left out are unchanged, and `null` clears an optional one. Limits
+/// are unknown fields here, so they are refused rather than ignored.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpdateSettingsRequest {
+    email_notifications: Option<bool>,
+    auto_delete_expired: Option<bool>,
+    #[serde(default, deserialize_with = "nullable")]
+    default_mailbox_expiry: Option<Option<i64>>,
+    #[serde(default, deserialize_with = "nullable")]
+    email_cleanup_policy: Option<Option<CleanupPolicy>>,
+}
+
+/// Users without a settings row get the defaults
+pub async fn get_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<UserSettings>>, AppError> {
+    let settings = state.db.get_user_settings(&claims.sub).await?
+        .unwrap_or_else(|| UserSettings::new(&claims.sub));
+    Ok(Json(ApiResponse::success(settings)))
+}
+
+pub async fn update_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<UpdateSettingsRequest>,
+) -> Result<Json<ApiResponse<UserSettings>>, AppError> {
+    if let Some(Some(default_mailbox_expiry)) = req.default_mailbox_expiry {
+        validate_expires_in(default_mailbox_expiry)?;
+    }
+    if let Some(Some(policy)) = &req.email_cleanup_policy {
+        policy.validate()?;
+    }
+
+    let mut settings = state.db.get_user_settings(&claims.sub).await?
+        .unwrap_or_else(|| UserSettings::new(&claims.sub));
+
+    if let Some(email_notifications) = req.email_notifications {
+        settings.email_notifications = email_notifications;
+    }
+    if let Some(auto_delete_expired) = req.auto_delete_expired {
+        settings.auto_delete_expired = auto_delete_expired;
+    }
+    if let Some(default_mailbox_expiry) = req.default_mailbox_expiry {
+        settings.default_mailbox_expiry = default_mailbox_expiry;
+    }
+    if let Some(email_cleanup_policy) = req.email_cleanup_policy {
+        settings.email_cleanup_policy = email_cleanup_policy;
+    }
+
+    state.db.update_user_settings(&settings).await?;
+    Ok(Json(ApiResponse::success(settings)))
+}
@@ -1,12 +1,12 @@
 use axum::{extract::State, Json};
-use common::{AppError, AuthType, User, db::Database};
+use common::{AppError, AuthType, User, db::Database, logging::Redacted};
 use hmac::{Hmac, Mac};
 use serde::Deserialize;
 use sha2::{Sha256, Digest};
 use std::sync::Arc;
-use crate::{AppState, ApiResponse};
+use crate::{audit::{self, RequestInfo}, security_log, AppState, ApiResponse};
 use tracing::{info, error, debug};
-use crate::auth::{create_token, store_credentials, AuthResponse, Claims, get_credentials};
+use crate::auth::{count_auth_methods, issue_tokens, store_credentials, AuthResponse, Claims};
 
 // Telegram login widget data
 #[derive(Debug, Deserialize)]
@@ -21,12 +21,21 @@ pub struct TelegramAuth {
     pub action: String, // "login", "register", or "connect"
 }
 
+impl TelegramAuth {
+    /// The chat new-email notifications are sent to. The login widget doesn't
+    /// report one, but a user's private chat with the bot has their user ID.
+    pub fn chat_id(&self) -> String {
+        self.id.to_string()
+    }
+}
+
 pub async fn telegram_verify_handler<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: Option<axum::extract::Extension<Claims>>,
+    request_info: RequestInfo,
     Json(auth_data): Json<TelegramAuth>,
 ) -> Result<Json<AuthResponse>, AppError> {
-    info!("Received Telegram auth request: {:?}", auth_data);
+    info!("Received Telegram {} request for {}", auth_data.action, Redacted(auth_data.id));
     
     // Verify the authentication data
     if !verify_telegram_auth(&auth_data)? {
@@ -62,9 +71,11 @@ pub async fn telegram_verify_handler<D: Database>(
         // Login attempt
         ("login", Some(user)) => {
             debug!("Found existing user: {}", user.id);
-            let token = create_token(&user.id)?;
             info!("Successfully authenticated Telegram user: {}", user.id);
-            Ok(Json(AuthResponse { token, user }))
+            let metadata = serde_json::json!({ "method": "telegram" });
+            request_info.audit(&state.db, &user.id, audit::LOGIN, None, Some(metadata)).await;
+            security_log::record_login(&state.db, &request_info, &user.id).await;
+            Ok(Json(issue_tokens(&state.db, user).await?))
         }
         ("login", None) => {
             error!("Login attempt with unlinked Telegram account");
@@ -113,9 +124,10 @@ pub async fn telegram_verify_handler<D: Database>(
 
             // Link Telegram ID to the existing account
             sqlx::query(
-                "UPDATE user_credentials SET telegram_id = ?, updated_at = ? WHERE user_id = ?",
+                "UPDATE user_credentials SET telegram_id = ?, telegram_chat_id = ?, updated_at = ? WHERE user_id = ?",
             )
             .bind(auth_data.id.to_string())
+            .bind(auth_data.chat_id())
             .bind(now)
             .bind(&user.id)
             .execute(state.db.pool())
@@ -126,8 +138,7 @@ pub async fn telegram_verify_handler<D: Database>(
             })?;
 
             info!("Successfully linked Telegram account for user: {}", user.id);
-            let token = create_token(&user.id)?;
-            Ok(Json(AuthResponse { token, user }))
+            Ok(Json(issue_tokens(&state.db, user).await?))
         }
 
         // Registration attempt
@@ -165,10 +176,18 @@ pub async fn telegram_verify_handler<D: Database>(
                 error!("Failed to store credentials: {}", e);
                 AppError::Internal("Failed to complete account setup. Please try again.".to_string())
             })?;
+            sqlx::query("UPDATE user_credentials SET telegram_chat_id = ? WHERE user_id = ?")
+                .bind(auth_data.chat_id())
+                .bind(&user.id)
+                .execute(state.db.pool())
+                .await
+                .map_err(|e| {
+                    error!("Failed to store Telegram chat ID: {}", e);
+                    AppError::Internal("Failed to complete account setup. Please try again.".to_string())
+                })?;
 
-            let token = create_token(&user.id)?;
             info!("Successfully created and authenticated new Telegram user: {}", user.id);
-            Ok(Json(AuthResponse { token, user }))
+            Ok(Json(issue_tokens(&state.db, user).await?))
         }
 
         // Invalid action
@@ -212,7 +231,7 @@ fn verify_telegram_auth(auth_data: &TelegramAuth) -> Result<bool, AppError> {
     
     fields.sort();
     let data_check_string = fields.join("\n");
-    debug!("Data check string: {}", data_check_string);
+    debug!("Data check string: {}", Redacted(&data_check_string));
     
     // Generate secret key - use SHA256 of bot token
     let secret = Sha256::digest(bot_token.as_bytes());
@@ -239,27 +258,7 @@ pub async fn telegram_disconnect_handler<D: Database>(
     debug!("Processing Telegram disconnect request for user: {}", claims.sub);
 
     // Check if user has other authentication methods before disconnecting
-    let credentials = get_credentials(&state.db, &claims.sub).await?;
-    let mut auth_methods = 0;
-
-    if let Some(hash) = &credentials.password_hash {
-        if !hash.is_empty() {
-            auth_methods += 1;
-        }
-    }
-    if credentials.google_id.is_some() {
-        auth_methods += 1;
-    }
-
-    if credentials.github_id.is_some() {
-        auth_methods += 1;
-    }
-
-    if credentials.telegram_id.is_some() {
-        auth_methods += 1;
-    }
-
-    if auth_methods <= 1 {
+    if count_auth_methods(&state, &claims.sub).await? <= 1 {
         error!("User attempted to disconnect last auth method: {}", claims.sub);
         return Err(AppError::Auth(
             "Cannot disconnect Telegram: You must have at least one way to log in to your account. Please add another login method first.".to_string()
@@ -268,7 +267,7 @@ pub async fn telegram_disconnect_handler<D: Database>(
 
     // Remove Telegram ID from credentials
     sqlx::query(
-        "UPDATE user_credentials SET telegram_id = NULL, updated_at = ? WHERE user_id = ?",
+        "UPDATE user_credentials SET telegram_id = NULL, telegram_chat_id = NULL, updated_at = ? WHERE user_id = ?",
     )
     .bind(chrono::Utc::now().timestamp())
     .bind(&claims.sub)
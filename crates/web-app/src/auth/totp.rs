@@ -0,0 +1,274 @@
+//! TOTP two-factor authentication for password logins. Once enabled, a
+//! correct password only yields a short-lived challenge token, which is
+//! exchanged for the access and refresh tokens along with a code from the
+//! authenticator app. Each code is accepted once.
+
+use crate::{ApiResponse, AppState};
+use axum::extract::{Json, State};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use common::{db::{database_error, Database}, generate_random_id, AppError, IdCharset};
+use qrcode::{render::svg, QrCode};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+use super::{get_credentials, issue_tokens, refresh::hash_token, AuthResponse, Claims};
+
+const ISSUER: &str = "VHMailHook";
+const STEP_SECS: u64 = 30;
+const DIGITS: usize = 6;
+const CHALLENGE_LIFETIME_SECS: i64 = 5 * 60;
+const CHALLENGE_TOKEN_LENGTH: usize = 48;
+/// Wrong codes after which a challenge is dropped, so codes can't be guessed
+const MAX_CHALLENGE_FAILURES: i64 = 5;
+
+#[derive(Debug, Serialize)]
+pub struct TotpSetupResponse {
+    /// Base32, for authenticator apps that can't scan the QR code
+    pub secret: String,
+    pub provisioning_uri: String,
+    /// SVG data URL of the provisioning URI
+    pub qr_code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCodeRequest {
+    pub code: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TotpCompleteRequest {
+    pub challenge_token: String,
+    pub code: String,
+}
+
+/// Returned by password logins instead of tokens when TOTP is enabled
+#[derive(Debug, Serialize)]
+pub struct TotpChallenge {
+    pub requires_totp: bool,
+    pub challenge_token: String,
+    pub expires_in: i64,
+}
+
+fn build_totp(secret: &str, account: &str) -> Result<TOTP, AppError> {
+    let secret = Secret::Encoded(secret.to_string())
+        .to_bytes()
+        .map_err(|e| AppError::Internal(format!("Invalid TOTP secret: {:?}", e)))?;
+    Ok(TOTP::new_unchecked(Algorithm::SHA1, DIGITS, 0, STEP_SECS, secret, Some(ISSUER.to_string()), account.to_string()))
+}
+
+/// Accepts a code of the current or an adjacent time step, unless a code of
+/// the same or a later step was already accepted
+async fn verify_code<D: Database>(db: &D, user_id: &str, secret: &str, account: &str, code: &str) -> Result<bool, AppError> {
+    let totp = build_totp(secret, account)?;
+    let now = chrono::Utc::now().timestamp() as u64;
+    let step = [now - STEP_SECS, now, now + STEP_SECS]
+        .into_iter()
+        .find(|time| totp.check(code.trim(), *time))
+        .map(|time| (time / STEP_SECS) as i64);
+    let Some(step) = step else {
+        return Ok(false);
+    };
+
+    // Conditional, so concurrent requests with the same code can't both pass
+    let result = sqlx::query(
+        "UPDATE user_credentials SET totp_last_step = ?
+         WHERE user_id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+    .bind(step)
+    .bind(user_id)
+    .bind(step)
+    .execute(db.pool())
+    .await
+    .map_err(database_error)?;
+    Ok(result.rows_affected() == 1)
+}
+
+fn qr_code_data_url(uri: &str) -> Result<String, AppError> {
+    let svg = QrCode::new(uri.as_bytes())
+        .map_err(|e| AppError::Internal(format!("Failed to render QR code: {}", e)))?
+        .render::<svg::Color>()
+        .min_dimensions(200, 200)
+        .build();
+    Ok(format!("data:image/svg+xml;base64,{}", STANDARD.encode(svg)))
+}
+
+async fn get_username<D: Database>(db: &D, user_id: &str) -> Result<String, AppError> {
+    db.get_user(user_id)
+        .await?
+        .map(|user| user.username)
+        .ok_or_else(|| AppError::Auth("Your session has expired. Please log in again to continue.".to_string()))
+}
+
+fn invalid_code() -> AppError {
+    AppError::Auth("The authentication code is incorrect or was already used.".to_string())
+}
+
+/// Starts over with a new secret, which is only used once confirmed
+pub(super) async fn setup_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<TotpSetupResponse>>, AppError> {
+    let credentials = get_credentials(&state.db, &claims.sub).await?;
+    if credentials.totp_enabled {
+        return Err(AppError::Auth("Two-factor authentication is already enabled.".to_string()));
+    }
+    if credentials.password_hash.as_deref().unwrap_or_default().is_empty() {
+        return Err(AppError::Auth("Set a password before enabling two-factor authentication.".to_string()));
+    }
+
+    let secret = match Secret::generate_secret().to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!("to_encoded returns an encoded secret"),
+    };
+    let provisioning_uri = build_totp(&secret, &get_username(&state.db, &claims.sub).await?)?.get_url();
+
+    sqlx::query("UPDATE user_credentials SET totp_secret = ?, totp_last_step = NULL, updated_at = ? WHERE user_id = ?")
+        .bind(&secret)
+        .bind(chrono::Utc::now().timestamp())
+        .bind(&claims.sub)
+        .execute(state.db.pool())
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(TotpSetupResponse {
+        qr_code: qr_code_data_url(&provisioning_uri)?,
+        secret,
+        provisioning_uri,
+    })))
+}
+
+/// Enables TOTP once a code from the set up secret is confirmed
+pub(super) async fn verify_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<TotpCodeRequest>,
+) -> Result<Json<ApiResponse<()>>, AppError> {
+    let credentials = get_credentials(&state.db, &claims.sub).await?;
+    if credentials.totp_enabled {
+        return Err(AppError::Auth("Two-factor authentication is already enabled.".to_string()));
+    }
+    let secret = credentials
+        .totp_secret
+        .ok_or_else(|| AppError::Auth("Set up two-factor authentication first.".to_string()))?;
+
+    let username = get_username(&state.db, &claims.sub).await?;
+    if !verify_code(&state.db, &claims.sub, &secret, &username, &req.code).await? {
+        return Err(invalid_code());
+    }
+
+    sqlx::query("UPDATE user_credentials SET totp_enabled = 1, updated_at = ? WHERE user_id = ?")
+        .bind(chrono::Utc::now().timestamp())
+        .bind(&claims.sub)
+        .execute(state.db.pool())
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+pub(super) async fn disable_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<TotpCodeRequest>,
+) -> Result<Json<ApiResponse<()>>, AppError> {
+    let credentials = get_credentials(&state.db, &claims.sub).await?;
+    let secret = credentials
+        .totp_secret
+        .filter(|_| credentials.totp_enabled)
+        .ok_or_else(|| AppError::Auth("Two-factor authentication is not enabled.".to_string()))?;
+
+    let username = get_username(&state.db, &claims.sub).await?;
+    if !verify_code(&state.db, &claims.sub, &secret, &username, &req.code).await? {
+        return Err(invalid_code());
+    }
+
+    sqlx::query(
+        "UPDATE user_credentials SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL, updated_at = ?
+         WHERE user_id = ?",
+    )
+    .bind(chrono::Utc::now().timestamp())
+    .bind(&claims.sub)
+    .execute(state.db.pool())
+    .await
+    .map_err(database_error)?;
+    sqlx::query("DELETE FROM totp_challenges WHERE user_id = ?")
+        .bind(&claims.sub)
+        .execute(state.db.pool())
+        .await
+        .map_err(database_error)?;
+
+    Ok(Json(ApiResponse::success(())))
+}
+
+pub(super) async fn create_challenge<D: Database>(db: &D, user_id: &str) -> Result<TotpChallenge, AppError> {
+    let token = generate_random_id(CHALLENGE_TOKEN_LENGTH, IdCharset::UrlSafe);
+    let now = chrono::Utc::now().timestamp();
+
+    sqlx::query("DELETE FROM totp_challenges WHERE expires_at <= ?")
+        .bind(now)
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+    sqlx::query("INSERT INTO totp_challenges (token_hash, user_id, expires_at) VALUES (?, ?, ?)")
+        .bind(hash_token(&token))
+        .bind(user_id)
+        .bind(now + CHALLENGE_LIFETIME_SECS)
+        .execute(db.pool())
+        .await
+        .map_err(database_error)?;
+
+    Ok(TotpChallenge {
+        requires_totp: true,
+        challenge_token: token,
+        expires_in: CHALLENGE_LIFETIME_SECS,
+    })
+}
+
+/// Exchanges a login challenge and a code for tokens
+pub(super) async fn complete_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Json(req): Json<TotpCompleteRequest>,
+) -> Result<Json<ApiResponse<AuthResponse>>, AppError> {
+    let expired = || AppError::Auth("The login has expired. Please sign in again.".to_string());
+    let token_hash = hash_token(&req.challenge_token);
+
+    let user_id: String = sqlx::query_scalar("SELECT user_id FROM totp_challenges WHERE token_hash = ? AND expires_at > ?")
+        .bind(&token_hash)
+        .bind(chrono::Utc::now().timestamp())
+        .fetch_optional(state.db.pool())
+        .await
+        .map_err(database_error)?
+        .ok_or_else(expired)?;
+    let credentials = get_credentials(&state.db, &user_id).await?;
+    let secret = credentials.totp_secret.filter(|_| credentials.totp_enabled).ok_or_else(expired)?;
+    let user = state.db.get_user(&user_id).await?.ok_or_else(expired)?;
+
+    if !verify_code(&state.db, &user_id, &secret, &user.username, &req.code).await? {
+        sqlx::query("UPDATE totp_challenges SET failed_attempts = failed_attempts + 1 WHERE token_hash = ?")
+            .bind(&token_hash)
+            .execute(state.db.pool())
+            .await
+            .map_err(database_error)?;
+        sqlx::query("DELETE FROM totp_challenges WHERE token_hash = ? AND failed_attempts >= ?")
+            .bind(&token_hash)
+            .bind(MAX_CHALLENGE_FAILURES)
+            .execute(state.db.pool())
+            .await
+            .map_err(database_error)?;
+        return Err(invalid_code());
+    }
+
+    // Single use: only the request removing the challenge gets tokens
+    let removed = sqlx::query("DELETE FROM totp_challenges WHERE token_hash = ?")
+        .bind(&token_hash)
+        .execute(state.db.pool())
+        .await
+        .map_err(database_error)?
+        .rows_affected();
+    if removed == 0 {
+        return Err(expired());
+    }
+
+    Ok(Json(ApiResponse::success(issue_tokens(&state.db, user).await?)))
+}
@@ -0,0 +1,105 @@
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{header::HeaderName, Extensions, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use common::proxy::TrustedProxies;
+use std::net::{IpAddr, SocketAddr};
+
+/// The address a request came from, set by [`resolve_client_ip`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct ClientIp(pub IpAddr);
+
+/// Where the client address of requests from trusted proxies is read
+#[derive(Debug, Clone, Default)]
+pub struct ClientIpSource {
+    proxies: TrustedProxies,
+    /// `None` for `X-Forwarded-For`, else a header holding just the address
+    header: Option<HeaderName>,
+}
+
+impl ClientIpSource {
+    pub fn parse(trusted_proxies: &[String], header: &str) -> anyhow::Result<Self> {
+        let header = HeaderName::try_from(header.trim())
+            .map_err(|_| anyhow::anyhow!("Invalid header name in CLIENT_IP_HEADER: {}", header))?;
+        Ok(Self {
+            proxies: TrustedProxies::parse(trusted_proxies)?,
+            header: (header != "x-forwarded-for").then_some(header),
+        })
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        if !self.proxies.contains(peer) {
+            return peer;
+        }
+        match &self.header {
+            Some(header) => headers
+                .get(header)
+                .and_then(|value| value.to_str().ok())
+                .and_then(|value| value.trim().parse().ok())
+                .unwrap_or(peer),
+            None => {
+                let hops = headers
+                    .get_all("X-Forwarded-For")
+                    .iter()
+                    .filter_map(|value| value.to_str().ok())
+                    .flat_map(|value| value.split(','))
+                    .collect::<Vec<_>>();
+                self.proxies.forwarded_client(peer, hops.into_iter())
+            }
+        }
+    }
+}
+
+/// Records the request's [`ClientIp`], the connection's peer unless that is
+/// a trusted proxy
+pub async fn resolve_client_ip(
+    State(source): State<ClientIpSource>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request<Body>,
+    next: Next,
+) -> Response {
+    // Requests not served over a connection have no address
+    if let Some(ConnectInfo(peer)) = connect_info {
+        let ip = source.client_ip(req.headers(), peer.ip());
+        req.extensions_mut().insert(ClientIp(ip));
+    }
+    next.run(req).await
+}
+
+pub(crate) fn client_ip(extensions: &Extensions) -> Option<IpAddr> {
+    extensions.get::<ClientIp>().map(|ClientIp(ip)| *ip)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn source(header: &str) -> ClientIpSource {
+        ClientIpSource::parse(&["127.0.0.1".to_string()], header).unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.append(*name, value.parse().unwrap());
+        }
+        headers
+    }
+
+    #[test]
+    fn test_client_ip_header() {
+        let proxy: IpAddr = "127.0.0.1".parse().unwrap();
+        let client: IpAddr = "203.0.113.7".parse().unwrap();
+        let forwarded = headers(&[("X-Forwarded-For", "192.0.2.1, 203.0.113.7"), ("CF-Connecting-IP", "198.51.100.9")]);
+
+        assert_eq!(source("X-Forwarded-For").client_ip(&forwarded, proxy), client);
+        assert_eq!(source("CF-Connecting-IP").client_ip(&forwarded, proxy), "198.51.100.9".parse::<IpAddr>().unwrap());
+        assert_eq!(source("X-Real-IP").client_ip(&forwarded, proxy), proxy);
+        // Only proxies are believed
+        assert_eq!(source("X-Forwarded-For").client_ip(&forwarded, client), client);
+        assert!(ClientIpSource::parse(&[], "bad header").is_err());
+    }
+}
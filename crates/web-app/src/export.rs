@@ -0,0 +1,148 @@
+//! Export of a mailbox as an mboxrd file. Emails stay encrypted: each entry
+//! carries headers rebuilt from the stored metadata around the ciphertext.
+
+use axum::{
+    extract::{Path, Query, State},
+    http::header,
+    response::{IntoResponse, Response},
+    Json,
+};
+use chrono::DateTime;
+use common::{db::Database, AppError, Email};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, can_access_mailbox, ApiResponse, AppState};
+
+const EXPORT_BATCH_SIZE: i64 = 50;
+/// Sender of the envelope line when the email has no From address
+const UNKNOWN_SENDER: &str = "MAILER-DAEMON";
+
+#[derive(Debug, Deserialize)]
+pub struct ExportQuery {
+    format: Option<String>,
+    /// Only emails received at or after this Unix timestamp
+    since: Option<i64>,
+    /// Only emails received at or before this Unix timestamp
+    until: Option<i64>,
+}
+
+/// Header values come from parsed emails, so line breaks must not start new headers
+fn header_value(value: &str) -> String {
+    value.replace(['\r', '\n'], " ")
+}
+
+fn message_id(email: &Email) -> String {
+    email
+        .headers_json
+        .as_deref()
+        .and_then(|headers| serde_json::from_str::<serde_json::Value>(headers).ok())
+        .and_then(|headers| headers.get("message-id")?.as_str().map(header_value))
+        .unwrap_or_else(|| format!("<{}@vh-mail-hook>", email.id))
+}
+
+/// One mbox entry: the `From ` separator line, the headers, the ciphertext
+/// with `From ` lines quoted, and a blank line
+fn mbox_entry(email: &Email) -> String {
+    let received_at = DateTime::from_timestamp(email.received_at, 0).unwrap_or_default();
+    let sender = email
+        .from_addr
+        .split(',')
+        .map(str::trim)
+        .find(|address| !address.is_empty() && !address.contains(char::is_whitespace))
+        .unwrap_or(UNKNOWN_SENDER);
+
+    let mut entry = format!("From {} {}\n", sender, received_at.format("%a %b %e %H:%M:%S %Y"));
+    let from = if email.from_addr.is_empty() { UNKNOWN_SENDER } else { &email.from_addr };
+    entry.push_str(&format!("From: {}\n", header_value(from)));
+    if !email.to_addr.is_empty() {
+        entry.push_str(&format!("To: {}\n", header_value(&email.to_addr)));
+    }
+    if !email.subject.is_empty() {
+        entry.push_str(&format!("Subject: {}\n", header_value(&email.subject)));
+    }
+    entry.push_str(&format!("Date: {}\n", received_at.to_rfc2822()));
+    entry.push_str(&format!("Message-ID: {}\n\n", message_id(email)));
+
+    for line in email.encrypted_content.lines() {
+        if line.trim_start_matches('>').starts_with("From ") {
+            entry.push('>');
+        }
+        entry.push_str(line);
+        entry.push('\n');
+    }
+    entry.push('\n');
+    entry
+}
+
+/// Streams the emails oldest first, querying them in batches so large
+/// mailboxes are never loaded into memory at once
+pub async fn export_mailbox<D: Database + 'static>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> Response {
+    let access: Result<(), AppError> = async {
+        if query.format.as_deref().is_some_and(|format| format != "mbox") {
+            return Err(AppError::Mail("Unsupported export format, only mbox is available".into()));
+        }
+        let mailbox = state.db.get_mailbox(&mailbox_id).await?
+            .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+        if !can_access_mailbox(&state, &mailbox, &claims.sub).await? {
+            return Err(AppError::Auth("You do not have permission to export this mailbox".into()));
+        }
+        Ok(())
+    }.await;
+
+    if let Err(e) = access {
+        error!("Error while exporting mailbox: {}", e);
+        return Json(ApiResponse::<()>::error(e.to_string())).into_response();
+    }
+
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(4);
+    tokio::spawn(async move {
+        let mut after: Option<(i64, String)> = None;
+        loop {
+            let batch = state.db
+                .get_mailbox_emails_batch(
+                    &mailbox_id,
+                    query.since,
+                    query.until,
+                    after.as_ref().map(|(received_at, id)| (*received_at, id.as_str())),
+                    EXPORT_BATCH_SIZE,
+                )
+                .await;
+            let emails = match batch {
+                Ok(emails) => emails,
+                Err(e) => {
+                    error!("Database error while exporting mailbox: {}", e);
+                    let _ = tx.send(Err(std::io::Error::other(e))).await;
+                    break;
+                }
+            };
+
+            let Some(last) = emails.last() else {
+                break;
+            };
+            after = Some((last.received_at, last.id.clone()));
+            let complete = (emails.len() as i64) < EXPORT_BATCH_SIZE;
+            let chunk: String = emails.iter().map(mbox_entry).collect();
+            // Stop reading once the client has gone away
+            if tx.send(Ok(chunk)).await.is_err() || complete {
+                break;
+            }
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|chunk| (chunk, rx))
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/mbox")
+        .header(header::CONTENT_DISPOSITION, "attachment; filename=\"mailbox.mbox\"")
+        .body(axum::body::Body::from_stream(body))
+        .unwrap()
+}
@@ -0,0 +1,125 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+};
+use common::{db::Database, AppError, ForwardingKind, ForwardingRule};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, get_managed_mailbox, webhooks::validate_url, ApiResponse, AppState};
+
+const MAX_RULES_PER_MAILBOX: usize = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateRuleRequest {
+    kind: ForwardingKind,
+    destination: String,
+    filter_from: Option<String>,
+    filter_subject: Option<String>,
+}
+
+/// Empty filters match everything, like missing ones
+fn validate_filter(filter: Option<String>) -> Result<Option<String>, AppError> {
+    match filter.filter(|filter| !filter.trim().is_empty()) {
+        Some(filter) if !ForwardingRule::is_valid_filter(&filter) => {
+            Err(AppError::Mail(format!("Invalid filter pattern: {}", filter)))
+        }
+        filter => Ok(filter),
+    }
+}
+
+pub async fn list_rules<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<ForwardingRule>>>, StatusCode> {
+    let result: Result<Vec<ForwardingRule>, AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "forwarding rules").await?;
+        state.db.get_forwarding_rules(&mailbox_id).await
+    }.await;
+
+    match result {
+        Ok(rules) => Ok(Json(ApiResponse::success(rules))),
+        Err(e) => {
+            error!("Failed to list forwarding rules: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn create_rule<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    Json(req): Json<CreateRuleRequest>,
+) -> Result<Json<ApiResponse<ForwardingRule>>, StatusCode> {
+    let result: Result<ForwardingRule, AppError> = async {
+        let filter_from = validate_filter(req.filter_from)?;
+        let filter_subject = validate_filter(req.filter_subject)?;
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "forwarding rules").await?;
+
+        let destination = match req.kind {
+            ForwardingKind::Webhook => validate_url(&req.destination).await?,
+            ForwardingKind::Mailbox => {
+                // Copies are only saved in mailboxes the user could manage anyway
+                let destination = get_managed_mailbox(&state, req.destination.trim(), &claims.sub, "forwarding rules").await
+                    .map_err(|_| AppError::Mail("Destination mailbox not found".into()))?;
+                if destination.id == mailbox_id {
+                    return Err(AppError::Mail("A mailbox cannot forward to itself".into()));
+                }
+                destination.id
+            }
+        };
+
+        if state.db.get_forwarding_rules(&mailbox_id).await?.len() >= MAX_RULES_PER_MAILBOX {
+            return Err(AppError::Mail(format!(
+                "Mailbox has reached its limit of {} forwarding rules",
+                MAX_RULES_PER_MAILBOX
+            )));
+        }
+
+        let rule = ForwardingRule {
+            id: uuid::Uuid::new_v4().to_string(),
+            mailbox_id: mailbox_id.clone(),
+            kind: req.kind,
+            destination,
+            filter_from,
+            filter_subject,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        state.db.create_forwarding_rule(&rule).await?;
+        Ok(rule)
+    }.await;
+
+    match result {
+        Ok(rule) => Ok(Json(ApiResponse::success(rule))),
+        Err(e) => {
+            error!("Failed to create forwarding rule: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn delete_rule<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, rule_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result: Result<(), AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "forwarding rules").await?;
+
+        if !state.db.delete_forwarding_rule(&mailbox_id, &rule_id).await? {
+            return Err(AppError::NotFound("Forwarding rule not found".into()));
+        }
+        Ok(())
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("Failed to delete forwarding rule: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
@@ -0,0 +1,48 @@
+use maxminddb::{geoip2, Reader};
+use std::{net::IpAddr, sync::OnceLock};
+use tracing::{info, warn};
+
+static READER: OnceLock<Option<Reader<Vec<u8>>>> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct GeoLocation {
+    pub country_code: Option<String>,
+    pub city: Option<String>,
+}
+
+/// Opens the GeoLite2-City database at `GEOIP_DB_PATH` on first use.
+/// Without the variable geolocation is skipped silently.
+fn reader() -> Option<&'static Reader<Vec<u8>>> {
+    READER
+        .get_or_init(|| {
+            let path = std::env::var("GEOIP_DB_PATH").ok().filter(|p| !p.is_empty())?;
+            match Reader::open_readfile(&path) {
+                Ok(reader) => {
+                    info!("Loaded GeoIP database from {}", path);
+                    Some(reader)
+                }
+                Err(e) => {
+                    warn!("Failed to open GeoIP database {}: {}", path, e);
+                    None
+                }
+            }
+        })
+        .as_ref()
+}
+
+pub fn lookup(ip: IpAddr) -> GeoLocation {
+    let Some(reader) = reader() else {
+        return GeoLocation::default();
+    };
+
+    match reader.lookup::<geoip2::City>(ip) {
+        Ok(city) => GeoLocation {
+            country_code: city.country.and_then(|c| c.iso_code).map(str::to_string),
+            city: city
+                .city
+                .and_then(|c| c.names)
+                .and_then(|names| names.get("en").map(|name| name.to_string())),
+        },
+        Err(_) => GeoLocation::default(),
+    }
+}
@@ -0,0 +1,46 @@
+//! Span of each routed request with the OpenTelemetry HTTP attributes, exported
+//! along with the other spans when `common::telemetry` is set up to
+
+use axum::{
+    body::Body,
+    extract::MatchedPath,
+    http::{Request, Response},
+};
+use std::time::Duration;
+use tower_http::{
+    classify::{ServerErrorsAsFailures, SharedClassifier},
+    trace::{DefaultOnBodyChunk, DefaultOnEos, DefaultOnRequest, TraceLayer},
+};
+use tracing::{field, Span};
+
+type MakeSpan = fn(&Request<Body>) -> Span;
+type OnResponse = fn(&Response<Body>, Duration, &Span);
+type HttpTraceLayer =
+    TraceLayer<SharedClassifier<ServerErrorsAsFailures>, MakeSpan, DefaultOnRequest, OnResponse, DefaultOnBodyChunk, DefaultOnEos, ()>;
+
+/// Added with `route_layer`, so the matched route is known
+pub(crate) fn layer() -> HttpTraceLayer {
+    TraceLayer::new_for_http()
+        .make_span_with(make_span as MakeSpan)
+        .on_response(on_response as OnResponse)
+        // Failed requests are logged by their handlers
+        .on_failure(())
+}
+
+fn make_span(request: &Request<Body>) -> Span {
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path(), MatchedPath::as_str);
+    tracing::info_span!(
+        "http.request",
+        otel.name = %format_args!("{} {}", request.method(), route),
+        http.method = %request.method(),
+        http.route = route,
+        http.status_code = field::Empty,
+    )
+}
+
+fn on_response(response: &Response<Body>, _latency: Duration, span: &Span) {
+    span.record("http.status_code", response.status().as_u16());
+}
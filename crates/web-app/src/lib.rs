@@ -1,23 +1,51 @@
 use axum::{
-    extract::{Json, Path, State}, http::{HeaderValue, StatusCode, header}, middleware, routing::{delete, get, patch, post}, Router,
+    extract::{DefaultBodyLimit, Json, Multipart, Path, Query, State}, http::{HeaderMap, HeaderValue, StatusCode, header}, middleware, routing::{delete, get, patch, post}, Router,
     response::{IntoResponse, Response},
 };
-use common::{db::Database, handle_json_response, AppError, Email, Mailbox};
+#[cfg(feature = "admin-api")]
+use common::greylist::Greylist;
+use common::{circuit_breaker::CircuitBreaker, shutdown::CancellationToken, db::Database, events::EmailEvents, handle_json_response, healthcheck::HealthCheck, id::{IdFormat, IdGenerator}, request_id::{RequestIdLayer, REQUEST_ID_HEADER}, ApiKeyScope, AppError, Email, EmailAttachment, EmailFilter, EmailMetadata, IdCharset, Mailbox, MailboxStorageStats, MailboxWithStats, StorageQuota};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, net::SocketAddr, str::FromStr};
+use std::{sync::Arc, net::SocketAddr};
+use tower_http::compression::{
+    predicate::{NotForContentType, Predicate, SizeAbove},
+    CompressionLayer,
+};
 use tower_http::cors::{AllowOrigin, Any, CorsLayer};
-use tracing::{info, error};
+use tracing::{info, error, warn};
 use clap::Parser;
-use tokio::net::TcpListener;
+use tokio::{net::TcpListener, sync::watch};
 use rust_embed::RustEmbed;
 use std::sync::OnceLock;
 use sqlx::Row;
 
+mod admin;
+mod aliases;
 mod auth;
+mod client_ip;
 mod api_spec;
+mod api_usage;
+mod audit;
+mod export;
+mod forwarding;
+mod geoip;
+mod http_trace;
+mod live;
+mod mailbox_keys;
+mod method_filter;
+mod orgs;
+mod rate_limit;
+mod search;
+mod security_log;
+mod webhooks;
+#[cfg(feature = "prometheus")]
+pub mod prometheus;
 use auth::Claims;
 
+pub use api_spec::generate_spec;
+pub use rate_limit::RateLimitConfigs;
+
 mod api_auth {
     use axum::{
         async_trait,
@@ -25,6 +53,7 @@ mod api_auth {
         http::{request::Parts, StatusCode},
         response::{IntoResponse, Response},
     };
+    use common::{ApiKey, ApiKeyScope};
     use serde::Serialize;
     use crate::{AppState, Database};
     use std::sync::Arc;
@@ -32,6 +61,18 @@ mod api_auth {
     #[derive(Debug, Serialize)]
     pub struct ApiClaims {
         pub user_id: String,
+        pub scopes: Vec<ApiKeyScope>,
+    }
+
+    impl ApiClaims {
+        /// Rejects the request with 403 Forbidden unless the key was granted `scope`
+        pub fn require_scope(&self, scope: ApiKeyScope) -> Result<(), StatusCode> {
+            if self.scopes.contains(&scope) {
+                Ok(())
+            } else {
+                Err(StatusCode::FORBIDDEN)
+            }
+        }
     }
 
     #[async_trait]
@@ -55,19 +96,30 @@ mod api_auth {
                     (StatusCode::UNAUTHORIZED, "Missing or invalid Authorization header").into_response()
                 })?;
 
-            // Query the database to find the user associated with this API key
-            let user_id: Option<String> = sqlx::query_scalar(
-                "SELECT user_id FROM api_keys WHERE key = ? AND (expires_at IS NULL OR expires_at > unixepoch())"
-            )
-            .bind(auth_header)
-            .fetch_optional(state.db.pool())
-            .await
-            .map_err(|e| {
+            // Already resolved when the usage tracking layer ran
+            let api_key = match parts.extensions.get::<ApiKey>().filter(|api_key| api_key.key == auth_header) {
+                Some(api_key) => Some(api_key.clone()),
+                None => state.db.get_api_key(auth_header).await.map_err(|e| {
+                    (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response()
+                })?,
+            };
+
+            let Some(api_key) = api_key.filter(|api_key| api_key.is_active(chrono::Utc::now().timestamp())) else {
+                return Err((StatusCode::UNAUTHORIZED, "Invalid API key").into_response());
+            };
+
+            // Banning doesn't revoke the keys, so it is checked on each request
+            let user = state.db.get_user(&api_key.user_id).await.map_err(|e| {
                 (StatusCode::INTERNAL_SERVER_ERROR, format!("Database error: {}", e)).into_response()
             })?;
-
-            match user_id {
-                Some(user_id) => Ok(ApiClaims { user_id }),
+            match user {
+                Some(user) if user.banned_at.is_some() => {
+                    Err((StatusCode::FORBIDDEN, "This account has been banned.").into_response())
+                }
+                Some(_) => Ok(ApiClaims {
+                    user_id: api_key.user_id,
+                    scopes: api_key.scopes,
+                }),
                 None => Err((StatusCode::UNAUTHORIZED, "Invalid API key").into_response()),
             }
         }
@@ -95,6 +147,144 @@ pub struct Config {
     /// Supported email domains (comma-separated)
     #[arg(long, env = "SUPPORTED_DOMAINS", value_delimiter = ',', default_value = "mail-hook.example.com")]
     pub supported_domains: Vec<String>,
+
+    /// Format of generated email IDs: uuid, time_ordered or nanoid
+    #[arg(long, env = "EMAIL_ID_FORMAT", default_value = "uuid")]
+    pub email_id_format: IdFormat,
+
+    /// Seconds to let in-flight requests finish after a shutdown signal
+    #[arg(long, env = "SHUTDOWN_GRACE_PERIOD_SECS", default_value = "30")]
+    pub shutdown_grace_period_secs: u64,
+
+    /// Length of generated mailbox IDs
+    #[arg(long, env = "ENTROPY_MAILBOX_ID_LENGTH", default_value = "12")]
+    pub entropy_mailbox_id_length: usize,
+
+    /// Length of generated mailbox aliases
+    #[arg(long, env = "ENTROPY_ALIAS_LENGTH", default_value = "12")]
+    pub entropy_alias_length: usize,
+
+    /// Number of random characters in generated API keys
+    #[arg(long, env = "ENTROPY_API_KEY_LENGTH", default_value = "32")]
+    pub entropy_api_key_length: usize,
+
+    /// Index keywords of imported emails as HMAC search tokens keyed by the mailbox public key
+    #[arg(long, env = "ENABLE_SEARCH_INDEX")]
+    pub enable_search_index: bool,
+
+    /// Compress responses with br, gzip or deflate when the client accepts it
+    #[arg(long, env = "ENABLE_RESPONSE_COMPRESSION", default_value = "true")]
+    pub enable_response_compression: bool,
+
+    /// Responses smaller than this are sent uncompressed
+    #[arg(long, env = "COMPRESSION_MIN_SIZE_BYTES", default_value = "1024")]
+    pub compression_min_size_bytes: u16,
+
+    /// HTTP methods accepted by the server, others are answered with 405 (comma-separated)
+    #[arg(long, env = "ALLOWED_HTTP_METHODS", value_delimiter = ',', default_value = "GET,HEAD,POST,PUT,PATCH,DELETE,OPTIONS")]
+    pub allowed_http_methods: Vec<String>,
+
+    /// Reject every DELETE request, and the other requests that delete data or
+    /// revoke access, e.g. for read-only audit deployments
+    #[arg(long, env = "DISALLOW_DELETE_OPERATIONS")]
+    pub disallow_delete_operations: bool,
+
+    /// Largest email in bytes, also the limit of each file and of the whole
+    /// upload of an .eml import
+    #[arg(long, env = "MAX_EMAIL_SIZE", default_value_t = DEFAULT_MAX_EMAIL_SIZE)]
+    pub max_email_size: usize,
+
+    /// Maximum number of aliases per mailbox, including the primary one
+    #[arg(long, env = "MAX_ALIASES_PER_MAILBOX", default_value = "5")]
+    pub max_aliases_per_mailbox: usize,
+
+    /// Consecutive failures after which outbound requests to a host are suspended
+    #[arg(long, env = "CIRCUIT_BREAKER_FAILURE_THRESHOLD", default_value = "5")]
+    pub circuit_breaker_failure_threshold: u32,
+
+    /// Seconds before a suspended host is tried again
+    #[arg(long, env = "CIRCUIT_BREAKER_RESET_TIMEOUT_SECS", default_value = "60")]
+    pub circuit_breaker_reset_timeout_secs: u64,
+
+    /// Secret expected in the X-Admin-Secret header of /api/admin requests; the admin API is disabled when unset
+    #[arg(long, env = "ADMIN_SECRET")]
+    pub admin_secret: Option<String>,
+
+    /// Bearer token for POST /api/admin/shutdown, separate from ADMIN_SECRET; the endpoint is disabled when unset
+    #[arg(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Mailboxes a user may create per hour
+    #[arg(long, env = "RATE_LIMIT_MAILBOX_CREATE_PER_HOUR", default_value = "10")]
+    pub rate_limit_mailbox_create_per_hour: u32,
+
+    /// Requests per hour a user may make to each route deleting emails
+    #[arg(long, env = "RATE_LIMIT_EMAIL_DELETE_PER_HOUR", default_value = "200")]
+    pub rate_limit_email_delete_per_hour: u32,
+
+    /// Registrations and logins per minute from a client IP
+    #[arg(long, env = "RATE_LIMIT_AUTH_PER_MINUTE", default_value = "5")]
+    pub rate_limit_auth_per_minute: u32,
+
+    /// OAuth and Telegram login callbacks per minute from a client IP
+    #[arg(long, env = "RATE_LIMIT_OAUTH_CALLBACK_PER_MINUTE", default_value = "10")]
+    pub rate_limit_oauth_callback_per_minute: u32,
+
+    /// Reverse proxies, in CIDR format, whose CLIENT_IP_HEADER gives the client address for rate limits and logs (comma-separated)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',', default_value = "127.0.0.1,::1")]
+    pub trusted_proxies: Vec<String>,
+
+    /// Header trusted proxies put the client address in: X-Forwarded-For, or one holding only the address like X-Real-IP or CF-Connecting-IP
+    #[arg(long, env = "CLIENT_IP_HEADER", default_value = "X-Forwarded-For")]
+    pub client_ip_header: String,
+
+    /// Address serving Prometheus metrics at /metrics, without authentication; metrics are not served when unset
+    #[arg(long, env = "METRICS_BIND_ADDR")]
+    pub metrics_bind_addr: Option<String>,
+
+    /// Apply pending database migrations and exit
+    #[arg(long, env = "MIGRATE_ONLY")]
+    pub migrate_only: bool,
+
+    /// Apply pending migrations to an in-memory copy of the database and exit without changing it; implies --migrate-only
+    #[arg(long = "dry-run", env = "DATABASE_MIGRATION_DRY_RUN")]
+    pub migration_dry_run: bool,
+
+    /// Log email addresses and usernames in full instead of only their first characters
+    #[arg(long, env = "DISABLE_LOG_REDACTION")]
+    pub disable_log_redaction: bool,
+}
+
+const MIN_ID_LENGTH: usize = 10;
+const RECOMMENDED_ID_LENGTH: usize = 12;
+const MIN_API_KEY_LENGTH: usize = 20;
+const RECOMMENDED_API_KEY_LENGTH: usize = 32;
+const DEFAULT_MAX_ALIASES_PER_MAILBOX: usize = 5;
+const DEFAULT_MAX_EMAIL_SIZE: usize = 10 * 1024 * 1024;
+
+impl Config {
+    /// Rejects identifier lengths below the minimum and warns below the recommended values
+    pub fn validate(&self) -> anyhow::Result<()> {
+        let lengths = [
+            ("ENTROPY_MAILBOX_ID_LENGTH", self.entropy_mailbox_id_length, MIN_ID_LENGTH, RECOMMENDED_ID_LENGTH),
+            ("ENTROPY_ALIAS_LENGTH", self.entropy_alias_length, MIN_ID_LENGTH, RECOMMENDED_ID_LENGTH),
+            ("ENTROPY_API_KEY_LENGTH", self.entropy_api_key_length, MIN_API_KEY_LENGTH, RECOMMENDED_API_KEY_LENGTH),
+        ];
+
+        method_filter::AllowedMethods::parse(&self.allowed_http_methods, self.disallow_delete_operations)?;
+        client_ip::ClientIpSource::parse(&self.trusted_proxies, &self.client_ip_header)?;
+
+        for (name, value, minimum, recommended) in lengths {
+            if value < minimum {
+                anyhow::bail!("{} must be at least {}, got {}", name, minimum, value);
+            }
+            if value < recommended {
+                warn!("{} is {}, below the recommended {}", name, value, recommended);
+            }
+        }
+
+        Ok(())
+    }
 }
 
 static CONFIG: OnceLock<Config> = OnceLock::new();
@@ -113,7 +303,8 @@ pub fn init_config(config: Config) {
         if existing.database_path == config.database_path 
             && existing.bind_addr == config.bind_addr 
             && existing.web_app_url == config.web_app_url
-            && existing.supported_domains == config.supported_domains {
+            && existing.supported_domains == config.supported_domains
+            && existing.email_id_format == config.email_id_format {
             return;
         }
     }
@@ -131,6 +322,29 @@ pub fn get_web_app_url() -> String {
 
 pub struct AppState<D: Database> {
     db: Arc<D>,
+    id_generator: Arc<dyn IdGenerator>,
+    mailbox_id_length: usize,
+    alias_length: usize,
+    api_key_length: usize,
+    enable_search_index: bool,
+    max_aliases_per_mailbox: usize,
+    max_email_size: usize,
+    circuit_breaker: CircuitBreaker,
+    /// Shared with the mail service when both run in this process, for the admin API
+    #[cfg(feature = "admin-api")]
+    greylist: Greylist,
+    #[cfg(feature = "admin-api")]
+    admin_secret: Option<String>,
+    admin_token: Option<String>,
+    /// Cancelled to stop the server, and with it the other services of the process
+    shutdown: CancellationToken,
+    token_owners: auth::TokenOwnerCache,
+    oauth_providers: Vec<Box<dyn auth::OAuthProvider>>,
+    email_events: EmailEvents,
+    health: HealthCheck,
+    rate_limits: rate_limit::UserRateLimits,
+    route_rate_limits: RateLimitConfigs,
+    delete_operations: method_filter::DeleteOperations,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -145,6 +359,21 @@ pub struct SupportedDomainsResponse {
     domains: Vec<String>,
 }
 
+#[derive(Debug, Serialize)]
+pub struct UserStatsResponse {
+    email_count: i64,
+    total_bytes: i64,
+    email_count_limit: Option<i64>,
+    total_bytes_limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct StorageStatsResponse {
+    total_emails: u64,
+    total_bytes: i64,
+    by_mailbox: Vec<MailboxStorageStats>,
+}
+
 impl<T> ApiResponse<T> {
     fn success(data: T) -> Self {
         Self {
@@ -168,36 +397,249 @@ pub struct CreateMailboxRequest {
     name: String,
     expires_in_seconds: Option<i64>,
     public_key: String,
+    organization_id: Option<String>,
+    #[serde(default)]
+    strip_attachments: bool,
+    /// A random alias is generated when `None`
+    alias: Option<String>,
 }
 
+/// Body of `POST /api/v1/mailboxes`
 #[derive(Debug, Deserialize)]
-pub struct UpdateMailboxRequest {
+pub struct ApiCreateMailboxRequest {
+    public_key: String,
     name: Option<String>,
     expires_in_seconds: Option<i64>,
+}
+
+impl From<ApiCreateMailboxRequest> for CreateMailboxRequest {
+    fn from(req: ApiCreateMailboxRequest) -> Self {
+        Self {
+            name: req.name.unwrap_or_default(),
+            expires_in_seconds: req.expires_in_seconds,
+            public_key: req.public_key,
+            organization_id: None,
+            strip_attachments: false,
+            alias: None,
+        }
+    }
+}
+
+/// Tells an explicit `null`, `Some(None)`, apart from a missing field, `None`
+pub(crate) fn nullable<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    Option::deserialize(deserializer).map(Some)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateMailboxRequest {
+    name: Option<String>,
+    /// `null` keeps new emails until they are deleted
+    #[serde(default, deserialize_with = "nullable")]
+    expires_in_seconds: Option<Option<i64>>,
     public_key: Option<String>,
+    strip_attachments: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateEmailRequest {
+    /// Counted from now, `null` keeping the email until it is deleted
+    #[serde(default, deserialize_with = "nullable")]
+    expires_in_seconds: Option<Option<i64>>,
+}
+
+pub(crate) fn validate_expires_in(seconds: i64) -> Result<i64, AppError> {
+    if seconds <= 0 {
+        return Err(AppError::Mail("Expiration time must be positive".into()));
+    }
+    if seconds > 30 * 24 * 60 * 60 {
+        return Err(AppError::Mail("Maximum expiration time is 30 days".into()));
+    }
+    Ok(seconds)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EmailListQuery {
+    #[serde(default)]
+    stream: bool,
+    /// Hex HMAC-SHA256 search token derived client-side from a keyword
+    token: Option<String>,
+    /// List only metadata, without the encrypted content, one page at a time
+    #[serde(default)]
+    metadata: bool,
+    limit: Option<i64>,
+    /// ID of the last email of the previous metadata page
+    cursor: Option<String>,
+    /// Substring of the From header
+    from: Option<String>,
+    /// Substring of the Subject header
+    subject: Option<String>,
+    /// Inclusive bounds on the size of the emails as received, in bytes
+    min_size: Option<i64>,
+    max_size: Option<i64>,
+    /// Inclusive bounds on when the emails were received, as Unix timestamps
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+/// Inclusive bounds on when listed emails were received
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+pub struct ReceivedRange {
+    since: Option<i64>,
+    until: Option<i64>,
+}
+
+impl ReceivedRange {
+    fn validate(&self) -> Result<(), AppError> {
+        match (self.since, self.until) {
+            (Some(since), Some(until)) if since > until => {
+                Err(AppError::Mail("`since` must not be after `until`".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn contains(&self, received_at: i64) -> bool {
+        self.since.is_none_or(|since| received_at >= since) && self.until.is_none_or(|until| received_at <= until)
+    }
+}
+
+const MAILBOX_LIST_MAX_AGE_SECS: u32 = 5;
+const HTTP_DATE_FORMAT: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+const DEFAULT_EMAIL_METADATA_PAGE_SIZE: i64 = 50;
+const MAX_EMAIL_METADATA_PAGE_SIZE: i64 = 500;
+
+#[derive(Debug, Serialize)]
+pub struct ImportEmlResponse {
+    imported: u64,
+    errors: Vec<String>,
 }
 
+const MAX_EML_FILES_PER_IMPORT: usize = 100;
+/// Room in an .eml import for the multipart boundaries and file headers
+const EML_IMPORT_OVERHEAD: usize = 1024 * 1024;
+
+/// API keys can't be made to last longer than a year
+const MAX_API_KEY_LIFETIME_SECS: i64 = 365 * 24 * 60 * 60;
+
 #[derive(Debug, Serialize)]
 pub struct ApiKey {
     pub id: String,
     pub key: String,
     pub created_at: i64,
     pub expires_at: Option<i64>,
+    pub revoked_at: Option<i64>,
+    pub scopes: Vec<ApiKeyScope>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct CreateApiKeyRequest {
+    /// Defaults to every scope
+    pub scopes: Option<Vec<ApiKeyScope>>,
+    /// The key never expires when `None`
+    pub expires_in_seconds: Option<i64>,
+}
+
+/// Emails matching both filters are deleted when both are set, and every
+/// email of the mailbox when neither is
+#[derive(Debug, Default, Deserialize)]
+pub struct DeleteEmailsRequest {
+    pub before_timestamp: Option<i64>,
+    pub email_ids: Option<Vec<String>>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteEmailsResponse {
+    deleted_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DeleteExpiredEmailsResponse {
+    mailbox_id: String,
+    deleted_count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RevokeApiKeysResponse {
+    revoked: u64,
 }
 
-pub async fn run(config: Config) -> anyhow::Result<()> {
+/// Runs the web server until `shutdown` is cancelled. `smtp_bound` reports
+/// whether the SMTP server of the same process is listening, which
+/// `/api/ready` then also requires.
+pub async fn run(
+    config: Config,
+    smtp_bound: Option<watch::Receiver<bool>>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    common::logging::set_redaction_enabled(!config.disable_log_redaction);
+    let database_url = format!("sqlite:{}", config.database_path);
+    if config.migrate_only || config.migration_dry_run {
+        common::db::run_migrations_only(&database_url, config.migration_dry_run).await?;
+        return Ok(());
+    }
+
+    config.validate()?;
     init_config(config.clone());
 
-    let db = common::db::SqliteDatabase::new(&format!("sqlite:{}", config.database_path)).await?;
+    let db = common::db::SqliteDatabase::new(&database_url).await?;
     let db = Arc::new(db);
-    
-    let app = create_app(db);
+
+    #[cfg(feature = "prometheus")]
+    if let Some(metrics_addr) = &config.metrics_bind_addr {
+        let metrics_addr: SocketAddr = metrics_addr.parse()?;
+        let metrics_app = prometheus::metrics_router(db.clone())?;
+        let listener = TcpListener::bind(&metrics_addr).await?;
+        info!("Serving metrics on {}", metrics_addr);
+        tokio::spawn(async move {
+            if let Err(e) = axum::serve(listener, metrics_app).await {
+                error!("Metrics server error: {}", e);
+            }
+        });
+    }
+    #[cfg(not(feature = "prometheus"))]
+    if config.metrics_bind_addr.is_some() {
+        warn!("METRICS_BIND_ADDR is ignored as the prometheus feature is disabled");
+    }
+
+    let app = create_app_with_shutdown(db, smtp_bound, shutdown.clone());
 
     let addr: SocketAddr = config.bind_addr.parse()?;
     info!("Starting web server on {}", addr);
     
     let listener = TcpListener::bind(&addr).await?;
-    axum::serve(listener, app.into_make_service()).await?;
+    let grace_period = std::time::Duration::from_secs(config.shutdown_grace_period_secs);
+    serve(listener, app, shutdown, grace_period).await
+}
+
+/// Serves `app` until `shutdown` is cancelled, then stops accepting connections
+/// and lets in-flight requests finish for up to `grace_period`
+pub async fn serve(
+    listener: TcpListener,
+    app: Router,
+    shutdown: CancellationToken,
+    grace_period: std::time::Duration,
+) -> anyhow::Result<()> {
+    let draining = shutdown.clone();
+    let server = axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(async move {
+            draining.cancelled().await;
+            info!("Shutdown requested, draining in-flight HTTP requests");
+        });
+
+    tokio::select! {
+        result = server => result?,
+        _ = async {
+            shutdown.cancelled().await;
+            tokio::time::sleep(grace_period).await;
+        } => {
+            warn!("Shutdown grace period elapsed, dropping remaining HTTP requests");
+        }
+    }
 
     Ok(())
 }
@@ -205,47 +647,259 @@ pub async fn run(config: Config) -> anyhow::Result<()> {
 pub fn create_app<D: Database + 'static>(
     db: Arc<D>,
 ) -> Router {
+    create_app_with_smtp_status(db, None)
+}
+
+pub fn create_app_with_smtp_status<D: Database + 'static>(
+    db: Arc<D>,
+    smtp_bound: Option<watch::Receiver<bool>>,
+) -> Router {
+    create_app_with_shutdown(db, smtp_bound, CancellationToken::new())
+}
+
+/// Like [`create_app_with_smtp_status`], with `POST /api/admin/shutdown`
+/// cancelling `shutdown`
+pub fn create_app_with_shutdown<D: Database + 'static>(
+    db: Arc<D>,
+    smtp_bound: Option<watch::Receiver<bool>>,
+    shutdown: CancellationToken,
+) -> Router {
+    let route_rate_limits = CONFIG.get().map_or_else(RateLimitConfigs::default, RateLimitConfigs::from_config);
+    build_app(db, smtp_bound, shutdown, route_rate_limits)
+}
+
+/// Like [`create_app`], with `route_rate_limits` instead of the configured ones
+pub fn create_app_with_rate_limits<D: Database + 'static>(
+    db: Arc<D>,
+    route_rate_limits: RateLimitConfigs,
+) -> Router {
+    build_app(db, None, CancellationToken::new(), route_rate_limits)
+}
+
+fn build_app<D: Database + 'static>(
+    db: Arc<D>,
+    smtp_bound: Option<watch::Receiver<bool>>,
+    shutdown: CancellationToken,
+    route_rate_limits: RateLimitConfigs,
+) -> Router {
+    let config = CONFIG.get();
+    let id_format = config
+        .map(|config| config.email_id_format)
+        .unwrap_or_default();
+
+    let health = HealthCheck::new(db.pool().clone());
+    let health = match smtp_bound {
+        Some(smtp_bound) => health.with_smtp_bound(smtp_bound),
+        None => health,
+    };
+
     let state = Arc::new(AppState {
         db,
+        id_generator: id_format.generator(),
+        mailbox_id_length: config.map_or(RECOMMENDED_ID_LENGTH, |c| c.entropy_mailbox_id_length),
+        alias_length: config.map_or(RECOMMENDED_ID_LENGTH, |c| c.entropy_alias_length),
+        api_key_length: config.map_or(RECOMMENDED_API_KEY_LENGTH, |c| c.entropy_api_key_length),
+        enable_search_index: config.is_some_and(|c| c.enable_search_index),
+        max_aliases_per_mailbox: config.map_or(DEFAULT_MAX_ALIASES_PER_MAILBOX, |c| c.max_aliases_per_mailbox),
+        max_email_size: config.map_or(DEFAULT_MAX_EMAIL_SIZE, |c| c.max_email_size),
+        circuit_breaker: config.map_or_else(CircuitBreaker::default, |c| CircuitBreaker::new(
+            c.circuit_breaker_failure_threshold,
+            std::time::Duration::from_secs(c.circuit_breaker_reset_timeout_secs),
+        )),
+        #[cfg(feature = "admin-api")]
+        greylist: Greylist::shared(),
+        #[cfg(feature = "admin-api")]
+        admin_secret: config.and_then(|c| c.admin_secret.clone()).filter(|secret| !secret.is_empty()),
+        admin_token: config.and_then(|c| c.admin_token.clone()).filter(|token| !token.is_empty()),
+        shutdown,
+        token_owners: auth::TokenOwnerCache::default(),
+        oauth_providers: auth::default_oauth_providers(),
+        email_events: EmailEvents::shared(),
+        health,
+        rate_limits: rate_limit::UserRateLimits::default(),
+        route_rate_limits,
+        delete_operations: method_filter::DeleteOperations {
+            disallowed: config.is_some_and(|c| c.disallow_delete_operations),
+        },
     });
+    let route_limit = |config: &common::rate_limit::RateLimiterConfig| {
+        middleware::from_fn_with_state(config.clone(), rate_limit::limit_route)
+    };
+    let destructive = middleware::from_fn_with_state(state.delete_operations, method_filter::refuse_destructive);
+    let mailbox_create_limit = route_limit(&state.route_rate_limits.mailbox_create);
+    let email_delete_limit = route_limit(&state.route_rate_limits.email_delete);
 
     let web_app_url: Url = get_web_app_url().parse().unwrap();
 
     let cors = CorsLayer::new()
         .allow_origin(AllowOrigin::exact(HeaderValue::from_str(&web_app_url.origin().ascii_serialization()).unwrap()))
         .allow_methods(Any)
-        .allow_headers(Any);
+        .allow_headers(Any)
+        .expose_headers([REQUEST_ID_HEADER]);
 
     // Create a router for protected mailbox routes
     let frontend_routes = Router::new()
         .route("/api/mailboxes", get(list_mailboxes::<D>))
-        .route("/api/mailboxes", post(create_mailbox::<D>))
+        .route("/api/user/settings", get(auth::settings::get_handler::<D>).patch(auth::settings::update_handler::<D>))
+        .route("/api/mailboxes", post(create_mailbox::<D>).layer(mailbox_create_limit.clone()))
+        .route("/api/mailboxes/expired", delete(delete_all_expired_emails::<D>).layer(email_delete_limit.clone()))
         .route("/api/mailboxes/:id", get(get_mailbox::<D>))
         .route("/api/mailboxes/:id", delete(delete_mailbox::<D>))
         .route("/api/mailboxes/:id", patch(update_mailbox::<D>))
+        .route("/api/mailboxes/:id/alias", patch(aliases::update_mailbox_alias::<D>))
+        .route("/api/mailboxes/:id/rotate-alias", post(aliases::rotate_mailbox_alias::<D>))
+        .route("/api/mailboxes/:id/aliases", get(aliases::list_aliases::<D>))
+        .route("/api/mailboxes/:id/aliases", post(aliases::create_alias::<D>))
+        .route("/api/mailboxes/:id/aliases/:alias_id", delete(aliases::delete_alias::<D>))
+        .route("/api/mailboxes/:id/keys", get(mailbox_keys::list_keys::<D>))
+        .route("/api/mailboxes/:id/keys", post(mailbox_keys::add_key::<D>))
+        .route("/api/mailboxes/:id/keys/:label", delete(mailbox_keys::remove_key::<D>))
+        .route("/api/mailboxes/:id/webhooks", get(webhooks::list_webhooks::<D>))
+        .route("/api/mailboxes/:id/webhooks", post(webhooks::create_webhook::<D>))
+        .route("/api/mailboxes/:id/webhooks/:webhook_id", delete(webhooks::delete_webhook::<D>))
+        .route("/api/mailboxes/:id/webhooks/:webhook_id/rotate-secret", post(webhooks::rotate_webhook_secret::<D>))
+        .route("/api/mailboxes/:id/webhooks/:webhook_id/test", post(webhooks::test_webhook::<D>))
+        .route("/api/mailboxes/:id/webhooks/:webhook_id/preview-template", post(webhooks::preview_payload_template::<D>))
+        .route("/api/mailboxes/:id/rules", get(forwarding::list_rules::<D>))
+        .route("/api/mailboxes/:id/rules", post(forwarding::create_rule::<D>))
+        .route("/api/mailboxes/:id/rules/:rule_id", delete(forwarding::delete_rule::<D>))
+        .route("/api/mailboxes/:id/export", get(export::export_mailbox::<D>))
         .route("/api/mailboxes/:id/emails", get(get_mailbox_emails::<D>))
+        .route("/api/mailboxes/:id/emails", delete(delete_emails::<D>).layer(email_delete_limit.clone()))
+        .route("/api/mailboxes/:id/emails/expired", delete(delete_expired_emails::<D>).layer(email_delete_limit.clone()))
         .route("/api/mailboxes/:id/emails/:email_id", get(get_email::<D>))
-        .route("/api/mailboxes/:id/emails/:email_id", delete(delete_email::<D>))
+        .route("/api/mailboxes/:id/emails/:email_id", delete(delete_email::<D>).layer(email_delete_limit.clone()))
+        .route("/api/mailboxes/:id/emails/:email_id", patch(update_email::<D>))
+        .route("/api/mailboxes/:id/emails/:email_id/headers", get(get_email_headers::<D>))
+        .route("/api/mailboxes/:id/emails/:email_id/raw", get(get_email_raw::<D>))
+        .route(
+            "/api/mailboxes/:id/emails/:email_id/attachments/:attachment_id",
+            get(get_email_attachment::<D>),
+        )
+        .route(
+            "/api/mailboxes/:id/import-eml",
+            post(import_eml::<D>).layer(DefaultBodyLimit::max(
+                MAX_EML_FILES_PER_IMPORT.saturating_mul(state.max_email_size).saturating_add(EML_IMPORT_OVERHEAD),
+            )),
+        )
+        .route("/api/emails/search", get(search::search_emails::<D>))
         .route("/api/supported-domains", get(get_supported_domains::<D>))
+        .route("/api/user/stats", get(get_user_stats::<D>))
+        .route("/api/user/storage-stats", get(get_user_storage_stats::<D>))
+        .route("/api/orgs", get(orgs::list_organizations::<D>))
+        .route("/api/orgs", post(orgs::create_organization::<D>))
+        .route("/api/orgs/:id/members", post(orgs::add_member::<D>))
+        .route("/api/orgs/:id/members/:user_id", delete(orgs::remove_member::<D>))
         .route("/api/api-keys", get(list_api_keys::<D>))
         .route("/api/api-keys", post(create_api_key::<D>))
+        .route("/api/api-keys/revoke-all", post(revoke_all_api_keys::<D>).layer(destructive.clone()))
+        .route("/api/api-keys/:id", get(get_api_key::<D>))
         .route("/api/api-keys/:id", delete(delete_api_key::<D>))
-        .layer(middleware::from_fn(handle_json_response));
+        .route("/api/api-keys/:id/usage", get(api_usage::get_api_key_usage::<D>))
+        .route("/api/api-keys/:id/usage/endpoints", get(api_usage::get_api_key_top_endpoints::<D>))
+        .layer(middleware::from_fn(handle_json_response))
+        .layer(middleware::from_fn_with_state(state.clone(), rate_limit::limit_user_requests::<D>));
 
     let api_routes = Router::new()
+        .route("/v1/mailboxes", post(api_create_mailbox::<D>).layer(mailbox_create_limit))
+        .route("/v1/mailboxes/:id", delete(api_remove_mailbox::<D>).layer(email_delete_limit.clone()))
+        .route("/v1/mailboxes/:id/rotate-alias", post(api_rotate_mailbox_alias::<D>))
         .route("/v1/mailboxes/:id/emails", get(api_get_mailbox_emails::<D>))
+        .route("/v1/mailboxes/:id/emails", delete(api_delete_mailbox_emails::<D>).layer(email_delete_limit.clone()))
         .route("/v1/mailboxes/:id/emails/:email_id", get(api_get_email::<D>))
-        .route("/v1/mailboxes/:id/emails/:email_id", delete(api_delete_email::<D>))
+        .route("/v1/mailboxes/:id/emails/:email_id", delete(api_delete_email::<D>).layer(email_delete_limit))
+        .route("/v1/mailboxes/:id/emails/:email_id/headers", get(api_get_email_headers::<D>))
+        .route("/v1/mailboxes/:id/emails/:email_id/raw", get(api_get_email_raw::<D>))
         .route("/v1/swagger-spec.json", get(serve_swagger_spec))
+        .route_layer(middleware::from_fn_with_state(state.clone(), api_usage::track_api_key_usage::<D>))
         .layer(middleware::from_fn(handle_json_response));
 
-    Router::new()
-        .merge(auth::create_routes::<D>())
-        .nest("/", frontend_routes.layer(middleware::from_fn(auth::auth)))
-        .nest("/api", api_routes)   
-        .fallback(static_handler)
+    #[cfg(feature = "admin-api")]
+    let admin_routes = Router::new()
+        .route("/users", get(admin::users::list_users::<D>))
+        .route("/users/:id", delete(admin::users::delete_user::<D>))
+        .route("/users/:id/mailboxes", get(admin::users::list_user_mailboxes::<D>))
+        .route("/users/:id/limits", patch(admin::users::update_user_limits::<D>))
+        .route("/users/:id/ban", post(admin::users::ban_user::<D>).layer(destructive.clone()))
+        .route("/users/:id/revoke-all-api-keys", post(admin::users::revoke_user_api_keys::<D>).layer(destructive))
+        .route("/stats", get(admin::users::get_stats::<D>))
+        .route("/stats/login-geography", get(admin::users::get_login_geography::<D>))
+        .route("/greylist", get(admin::list_greylist::<D>))
+        .route("/greylist", delete(admin::flush_greylist::<D>))
+        .route("/greylist/:entry_id", delete(admin::delete_greylist_entry::<D>))
+        .route("/bounces", get(admin::list_bounces::<D>))
+        .route("/orgs/:id", patch(admin::update_organization::<D>))
+        .route("/feature-flags", get(admin::list_feature_flags::<D>))
+        .route("/feature-flags/:name", axum::routing::put(admin::update_feature_flag::<D>))
+        .route_layer(middleware::from_fn_with_state(state.clone(), admin::require_admin::<D>));
+
+    // These authenticate on their own, as browsers can't set headers on them
+    let live_routes = Router::new()
+        .route("/api/mailboxes/:id/live", get(live::mailbox_live::<D>))
+        .route("/api/mailboxes/:id/events", get(live::mailbox_events::<D>));
+
+    let app = Router::new()
+        .merge(auth::create_routes(state.clone()))
+        .merge(state.health.clone().routes())
+        .merge(live_routes)
+        .route("/api/admin/shutdown", post(admin::shutdown::<D>))
+        .nest("/", frontend_routes.layer(middleware::from_fn_with_state(state.clone(), auth::auth::<D>)))
+        .nest("/api", api_routes);
+
+    #[cfg(feature = "admin-api")]
+    let app = app.nest("/api/admin", admin_routes);
+
+    // Before the fallback, so static assets are not tracked
+    #[cfg(feature = "prometheus")]
+    let app = app.route_layer(middleware::from_fn(prometheus::track_requests));
+    let app = app.route_layer(http_trace::layer());
+
+    let mut app = app.fallback(static_handler);
+
+    if let Some(config) = config {
+        let allowed_methods = method_filter::AllowedMethods::parse(
+            &config.allowed_http_methods,
+            config.disallow_delete_operations,
+        )
+        .expect("ALLOWED_HTTP_METHODS is checked by Config::validate");
+        app = app.layer(middleware::from_fn_with_state(allowed_methods, method_filter::restrict_methods));
+    }
+
+    // Outside the routes' rate limits, which count by client IP
+    let client_ip_source = config
+        .map(|config| {
+            client_ip::ClientIpSource::parse(&config.trusted_proxies, &config.client_ip_header)
+                .expect("TRUSTED_PROXIES and CLIENT_IP_HEADER are checked by Config::validate")
+        })
+        .unwrap_or_default();
+    let app = app
+        .layer(middleware::from_fn_with_state(client_ip_source, client_ip::resolve_client_ip))
         .layer(cors)
-        .with_state(state)
+        .with_state(state);
+
+    let app = match config.filter(|c| c.enable_response_compression) {
+        Some(config) => app.layer(compression_layer(config.compression_min_size_bytes)),
+        None => app,
+    };
+
+    // Outermost, so every response carries the request ID
+    app.layer(RequestIdLayer)
+}
+
+/// Compresses responses above `min_size_bytes`, skipping content that is
+/// already compressed or streamed to the client as events
+fn compression_layer(min_size_bytes: u16) -> CompressionLayer<impl Predicate> {
+    let predicate = SizeAbove::new(min_size_bytes)
+        .and(NotForContentType::GRPC)
+        .and(NotForContentType::IMAGES)
+        .and(NotForContentType::SSE)
+        .and(NotForContentType::const_new("application/octet-stream"));
+
+    CompressionLayer::new()
+        .br(true)
+        .gzip(true)
+        .deflate(true)
+        .compress_when(predicate)
 }
 
 async fn static_handler(uri: axum::http::Uri, method: axum::http::Method) -> impl IntoResponse {
@@ -323,62 +977,168 @@ async fn static_handler(uri: axum::http::Uri, method: axum::http::Method) -> imp
     }
 }
 
-async fn create_mailbox<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    claims: axum::extract::Extension<Claims>,
-    Json(req): Json<CreateMailboxRequest>,
-) -> Result<Json<ApiResponse<Mailbox>>, StatusCode> {
+/// Users without settings get the default limit
+async fn check_user_mailbox_limit<D: Database>(state: &Arc<AppState<D>>, user_id: &str) -> Result<(), AppError> {
+    let max_mailboxes = match state.db.get_user_settings(user_id).await? {
+        Some(settings) => settings.max_mailboxes,
+        None => Some(common::UserSettings::DEFAULT_MAX_MAILBOXES),
+    };
+    if let Some(max_mailboxes) = max_mailboxes {
+        if state.db.count_user_mailboxes(user_id).await? >= max_mailboxes {
+            return Err(AppError::Mail("Mailbox limit reached".into()));
+        }
+    }
+    Ok(())
+}
+
+/// Validates and stores a new mailbox of `user_id`, for the web and v1 API
+/// routes. Errors are the messages returned to the user.
+async fn create_mailbox_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    request_info: &audit::RequestInfo,
+    req: CreateMailboxRequest,
+) -> Result<Mailbox, String> {
     // Validate expiration time
     if let Some(seconds) = req.expires_in_seconds {
         if seconds <= 0 {
-            return Ok(Json(ApiResponse::error("Expiration time must be positive")));
+            return Err("Expiration time must be positive".into());
         }
         if seconds > 30 * 24 * 60 * 60 {
-            return Ok(Json(ApiResponse::error("Maximum expiration time is 30 days")));
+            return Err("Maximum expiration time is 30 days".into());
         }
     }
 
-    // Validate public key using age crate
-    if let Err(e) = age::x25519::Recipient::from_str(&req.public_key) {
-        return Ok(Json(ApiResponse::error(format!("Invalid public key: {}", e))));
+    // Reject malformed keys now rather than failing every delivery later
+    let Some((key_type, public_key)) = common::security::normalize_public_key(&req.public_key) else {
+        return Err("Invalid age public key format".into());
+    };
+
+    let alias = req
+        .alias
+        .as_deref()
+        .map(aliases::normalize_mailbox_alias)
+        .transpose()
+        .map_err(|e| e.to_string())?;
+
+    check_user_mailbox_limit(state, user_id).await.map_err(|e| e.to_string())?;
+
+    if let Some(org_id) = &req.organization_id {
+        orgs::check_mailbox_quota(state, org_id, user_id).await.map_err(|e| e.to_string())?;
     }
 
     let mailbox = Mailbox {
-        id: common::generate_random_id(12),
-        alias: common::generate_random_id(12),
+        id: common::generate_random_id(state.mailbox_id_length, IdCharset::VisuallyDistinct),
+        alias: alias.unwrap_or_else(|| common::generate_random_id(state.alias_length, IdCharset::VisuallyDistinct)),
         name: req.name,
-        public_key: req.public_key,
-        owner_id: claims.sub.clone(),
+        public_key,
+        key_type,
+        owner_id: user_id.to_string(),
         created_at: chrono::Utc::now().timestamp(),
         mail_expires_in: req.expires_in_seconds,
+        organization_id: req.organization_id,
+        strip_attachments: req.strip_attachments,
+        alias_rotated_at: None,
     };
     
     match state.db.create_mailbox(&mailbox).await {
-        Ok(_) => Ok(Json(ApiResponse::success(mailbox))),
+        Ok(_) => {
+            request_info.audit(&state.db, user_id, audit::CREATE_MAILBOX, Some(("mailbox", &mailbox.id)), None).await;
+            Ok(mailbox)
+        }
         Err(e) => {
             error!("Failed to create mailbox: {}", e);
             // Check if it's a unique constraint violation
             if e.to_string().contains("UNIQUE constraint failed") {
-                Ok(Json(ApiResponse::error("A mailbox with this alias already exists")))
+                Err("A mailbox with this alias already exists".into())
             } else {
-                Ok(Json(ApiResponse::error("Unable to create mailbox. Please try again later")))
+                Err("Unable to create mailbox. Please try again later".into())
             }
         }
     }
 }
 
+async fn create_mailbox<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+    Json(req): Json<CreateMailboxRequest>,
+) -> Result<Json<ApiResponse<Mailbox>>, StatusCode> {
+    let result = create_mailbox_for_user(&state, &claims.sub, &request_info, req).await;
+    Ok(Json(result.map_or_else(ApiResponse::error, ApiResponse::success)))
+}
+
+/// Mailboxes are accessible to their owner and to members of their organization
+async fn can_access_mailbox<D: Database>(
+    state: &Arc<AppState<D>>,
+    mailbox: &Mailbox,
+    user_id: &str,
+) -> Result<bool, AppError> {
+    if mailbox.owner_id == user_id {
+        return Ok(true);
+    }
+    match &mailbox.organization_id {
+        Some(org_id) => Ok(state.db.get_organization_role(org_id, user_id).await?.is_some()),
+        None => Ok(false),
+    }
+}
+
+/// Changing or deleting a mailbox is limited to its owner and the owners/admins of its organization
+async fn can_manage_mailbox<D: Database>(
+    state: &Arc<AppState<D>>,
+    mailbox: &Mailbox,
+    user_id: &str,
+) -> Result<bool, AppError> {
+    if mailbox.owner_id == user_id {
+        return Ok(true);
+    }
+    match &mailbox.organization_id {
+        Some(org_id) => Ok(state.db.get_organization_role(org_id, user_id).await?
+            .is_some_and(|role| role.can_manage())),
+        None => Ok(false),
+    }
+}
+
+/// The mailbox, if the user can manage it. `what` names the mailbox's settings
+/// being changed in the error, like "aliases".
+pub(crate) async fn get_managed_mailbox<D: Database>(
+    state: &Arc<AppState<D>>,
+    mailbox_id: &str,
+    user_id: &str,
+    what: &str,
+) -> Result<Mailbox, AppError> {
+    let mailbox = state.db.get_mailbox(mailbox_id).await?
+        .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+    if !can_manage_mailbox(state, &mailbox, user_id).await? {
+        return Err(AppError::Auth(format!("You do not have permission to manage {} of this mailbox", what)));
+    }
+    Ok(mailbox)
+}
+
 async fn get_mailbox<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
     Path(id): Path<String>,
-) -> Result<Json<ApiResponse<Mailbox>>, StatusCode> {
+) -> Result<Json<ApiResponse<MailboxWithStats>>, StatusCode> {
     match state.db.get_mailbox(&id).await {
         Ok(Some(mailbox)) => {
-            // Ensure the mailbox belongs to the authenticated user
-            if mailbox.owner_id != claims.sub {
-                return Ok(Json(ApiResponse::error("You do not have permission to access this mailbox")));
+            // Ensure the authenticated user can access the mailbox
+            match can_access_mailbox(&state, &mailbox, &claims.sub).await {
+                Ok(true) => match state.db.get_mailbox_with_stats(&id, &claims.sub).await {
+                    Ok(Some(mailbox)) => Ok(Json(ApiResponse::success(mailbox))),
+                    Ok(None) => Ok(Json(ApiResponse::error("Mailbox not found"))),
+                    Err(e) => {
+                        error!("Database error while counting mailbox emails: {}", e);
+                        Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")))
+                    }
+                },
+                Ok(false) => Ok(Json(ApiResponse::error("You do not have permission to access this mailbox"))),
+                Err(e) => {
+                    error!("Database error while checking mailbox access: {}", e);
+                    Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")))
+                }
             }
-            Ok(Json(ApiResponse::success(mailbox)))
         }
         Ok(None) => Ok(Json(ApiResponse::error("Mailbox not found"))),
         Err(e) => {
@@ -388,33 +1148,54 @@ async fn get_mailbox<D: Database>(
     }
 }
 
-async fn delete_mailbox<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    claims: axum::extract::Extension<Claims>,
-    Path(id): Path<String>,
-) -> Result<Json<ApiResponse<()>>, StatusCode> {
+/// Deletes a mailbox `user_id` may manage, for the web and v1 API routes.
+/// Errors are the messages returned to the user.
+async fn delete_mailbox_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    request_info: &audit::RequestInfo,
+    id: &str,
+) -> Result<(), &'static str> {
     // First check if the mailbox belongs to the authenticated user
-    match state.db.get_mailbox(&id).await {
+    match state.db.get_mailbox(id).await {
         Ok(Some(mailbox)) => {
-            if mailbox.owner_id != claims.sub {
-                return Ok(Json(ApiResponse::error("You do not have permission to delete this mailbox")));
+            match can_manage_mailbox(state, &mailbox, user_id).await {
+                Ok(true) => {}
+                Ok(false) => return Err("You do not have permission to delete this mailbox"),
+                Err(e) => {
+                    error!("Database error while checking mailbox access: {}", e);
+                    return Err("Unable to process request. Please try again later");
+                }
             }
-            match state.db.delete_mailbox(&id).await {
-                Ok(_) => Ok(Json(ApiResponse::success(()))),
+            match state.db.delete_mailbox(id).await {
+                Ok(_) => {
+                    request_info.audit(&state.db, user_id, audit::DELETE_MAILBOX, Some(("mailbox", id)), None).await;
+                    Ok(())
+                }
                 Err(e) => {
                     error!("Database error while deleting mailbox: {}", e);
-                    Ok(Json(ApiResponse::error("Unable to delete mailbox. Please try again later")))
+                    Err("Unable to delete mailbox. Please try again later")
                 }
             }
         }
-        Ok(None) => Ok(Json(ApiResponse::error("Mailbox not found"))),
+        Ok(None) => Err("Mailbox not found"),
         Err(e) => {
             error!("Database error while checking mailbox: {}", e);
-            Ok(Json(ApiResponse::error("Unable to process request. Please try again later")))
+            Err("Unable to process request. Please try again later")
         }
     }
 }
 
+async fn delete_mailbox<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result = delete_mailbox_for_user(&state, &claims.sub, &request_info, &id).await;
+    Ok(Json(result.map_or_else(ApiResponse::error, ApiResponse::success)))
+}
+
 async fn update_mailbox<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
@@ -425,8 +1206,8 @@ async fn update_mailbox<D: Database>(
         let mut mailbox = state.db.get_mailbox(&id).await?
             .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
 
-        // Ensure the mailbox belongs to the authenticated user
-        if mailbox.owner_id != claims.sub {
+        // Its key decides who can read future mail, so members can't change it
+        if !can_manage_mailbox(&state, &mailbox, &claims.sub).await? {
             return Err(AppError::Auth("Unauthorized".into()));
         }
 
@@ -434,21 +1215,19 @@ async fn update_mailbox<D: Database>(
             mailbox.name = name;
         }
 
-        if let Some(seconds) = req.expires_in_seconds {
-            if seconds <= 0 {
-                return Err(AppError::Mail("Expiration time must be positive".into()));
-            }
-            if seconds > 30 * 24 * 60 * 60 {
-                return Err(AppError::Mail("Maximum expiration time is 30 days".into()));
-            }
-            mailbox.mail_expires_in = Some(seconds);
+        if let Some(expires_in) = req.expires_in_seconds {
+            mailbox.mail_expires_in = expires_in.map(validate_expires_in).transpose()?;
         }
 
         if let Some(public_key) = req.public_key {
-            // Validate public key using age crate
-            age::x25519::Recipient::from_str(&public_key)
-                .map_err(|e| AppError::Mail(format!("Invalid public key: {}", e)))?;
+            let (key_type, public_key) = common::security::normalize_public_key(&public_key)
+                .ok_or_else(|| AppError::Mail("Invalid age public key format".into()))?;
             mailbox.public_key = public_key;
+            mailbox.key_type = key_type;
+        }
+
+        if let Some(strip_attachments) = req.strip_attachments {
+            mailbox.strip_attachments = strip_attachments;
         }
 
         state.db.update_mailbox(&mailbox).await?;
@@ -468,71 +1247,286 @@ async fn get_mailbox_emails_for_user<D: Database>(
     state: &Arc<AppState<D>>,
     user_id: &str,
     mailbox_id: &str,
+    search_token: Option<&str>,
+    filter: &EmailFilter,
+    range: ReceivedRange,
 ) -> Result<Vec<Email>, AppError> {
+    range.validate()?;
     // First check if the mailbox belongs to the user
     let mailbox = state.db.get_mailbox(mailbox_id).await?
         .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
 
-    if mailbox.owner_id != user_id {
+    if !can_access_mailbox(state, &mailbox, user_id).await? {
         return Err(AppError::Auth("You do not have permission to access emails from this mailbox".into()));
     }
 
-    state.db.get_mailbox_emails(mailbox_id).await
-}
-
-async fn get_mailbox_emails<D: Database>(
-    State(state): State<Arc<AppState<D>>>,
-    claims: axum::extract::Extension<Claims>,
-    Path(id): Path<String>,
-) -> Result<Json<ApiResponse<Vec<Email>>>, StatusCode> {
-    match get_mailbox_emails_for_user(&state, &claims.sub, &id).await {
-        Ok(emails) => Ok(Json(ApiResponse::success(emails))),
-        Err(e) => {
-            error!("Error while retrieving emails: {}", e);
-            Ok(Json(ApiResponse::error(e.to_string())))
+    match search_token {
+        Some(token) => {
+            let mut emails = state.db.search_mailbox_emails(mailbox_id, &token.to_ascii_lowercase()).await?;
+            emails.retain(|email| filter.matches(email) && range.contains(email.received_at));
+            Ok(emails)
+        }
+        None if !filter.is_empty() => {
+            let mut emails = state.db.filter_mailbox_emails(mailbox_id, filter).await?;
+            emails.retain(|email| range.contains(email.received_at));
+            Ok(emails)
         }
+        None => state.db.get_mailbox_emails_in_range(mailbox_id, range.since, range.until).await,
     }
 }
 
-async fn get_email_for_user<D: Database>(
+async fn get_mailbox_email_metadata_for_user<D: Database>(
     state: &Arc<AppState<D>>,
     user_id: &str,
     mailbox_id: &str,
-    email_id: &str,
-) -> Result<Email, AppError> {
-    // First check if the mailbox belongs to the user
+    limit: i64,
+    cursor: Option<&str>,
+    range: ReceivedRange,
+) -> Result<Vec<EmailMetadata>, AppError> {
+    range.validate()?;
     let mailbox = state.db.get_mailbox(mailbox_id).await?
         .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
 
-    if mailbox.owner_id != user_id {
-        return Err(AppError::Auth("You do not have permission to access this email".into()));
-    }
-
-    let email = state.db.get_email(email_id).await?
-        .ok_or_else(|| AppError::NotFound("Email not found".into()))?;
-
-    if email.mailbox_id != mailbox_id {
-        return Err(AppError::NotFound("Email not found in this mailbox".into()));
+    if !can_access_mailbox(state, &mailbox, user_id).await? {
+        return Err(AppError::Auth("You do not have permission to access emails from this mailbox".into()));
     }
 
-    Ok(email)
+    state.db.get_mailbox_email_metadata(mailbox_id, limit, cursor, range.since, range.until).await
 }
 
-async fn get_email<D: Database>(
+async fn get_mailbox_emails<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
-    Path((mailbox_id, email_id)): Path<(String, String)>,
-) -> Result<Json<ApiResponse<Email>>, StatusCode> {
-    match get_email_for_user(&state, &claims.sub, &mailbox_id, &email_id).await {
-        Ok(email) => Ok(Json(ApiResponse::success(email))),
-        Err(e) => {
-            error!("Error while retrieving email: {}", e);
-            Ok(Json(ApiResponse::error(e.to_string())))
-        }
+    Path(id): Path<String>,
+    Query(query): Query<EmailListQuery>,
+) -> Result<Response, StatusCode> {
+    // Taken before the query so emails arriving meanwhile stay unread
+    let viewed_at = chrono::Utc::now().timestamp();
+    let range = ReceivedRange { since: query.since, until: query.until };
+
+    if query.metadata {
+        let limit = query.limit
+            .unwrap_or(DEFAULT_EMAIL_METADATA_PAGE_SIZE)
+            .clamp(1, MAX_EMAIL_METADATA_PAGE_SIZE);
+        return match get_mailbox_email_metadata_for_user(&state, &claims.sub, &id, limit, query.cursor.as_deref(), range).await {
+            Ok(emails) => {
+                mark_mailbox_viewed(&state, &id, &claims.sub, viewed_at).await;
+                Ok(Json(ApiResponse::success(emails)).into_response())
+            }
+            Err(e) => {
+                error!("Error while retrieving email metadata: {}", e);
+                Ok(Json(ApiResponse::<Vec<EmailMetadata>>::error(e.to_string())).into_response())
+            }
+        };
     }
-}
 
-async fn delete_email_for_user<D: Database>(
+    let filter = EmailFilter {
+        from: query.from,
+        subject: query.subject,
+        min_size: query.min_size,
+        max_size: query.max_size,
+    };
+    if query.stream {
+        if query.token.is_some() {
+            let error = ApiResponse::<()>::error("Search tokens can't be combined with `stream`");
+            return Ok((StatusCode::BAD_REQUEST, Json(error)).into_response());
+        }
+        return Ok(stream_mailbox_emails(&state, &claims.sub, id, filter, range, viewed_at).await);
+    }
+
+    match get_mailbox_emails_for_user(&state, &claims.sub, &id, query.token.as_deref(), &filter, range).await {
+        Ok(emails) => {
+            mark_mailbox_viewed(&state, &id, &claims.sub, viewed_at).await;
+            Ok(Json(ApiResponse::success(emails)).into_response())
+        }
+        Err(e) => {
+            error!("Error while retrieving emails: {}", e);
+            Ok(Json(ApiResponse::<Vec<Email>>::error(e.to_string())).into_response())
+        }
+    }
+}
+
+/// Resets the unread count of the mailbox; failing to is not worth failing the listing
+async fn mark_mailbox_viewed<D: Database>(state: &Arc<AppState<D>>, mailbox_id: &str, user_id: &str, viewed_at: i64) {
+    if let Err(e) = state.db.mark_mailbox_viewed(mailbox_id, user_id, viewed_at).await {
+        error!("Database error while marking mailbox viewed: {}", e);
+    }
+}
+
+/// Streams the emails of a mailbox as newline-delimited JSON, one row at a time,
+/// so large mailboxes are never loaded into memory at once
+async fn stream_mailbox_emails<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    mailbox_id: String,
+    filter: EmailFilter,
+    range: ReceivedRange,
+    viewed_at: i64,
+) -> Response {
+    let access: Result<(), AppError> = async {
+        range.validate()?;
+        let mailbox = state.db.get_mailbox(&mailbox_id).await?
+            .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+        if !can_access_mailbox(state, &mailbox, user_id).await? {
+            return Err(AppError::Auth("You do not have permission to access emails from this mailbox".into()));
+        }
+        Ok(())
+    }.await;
+
+    if let Err(e) = access {
+        error!("Error while retrieving emails: {}", e);
+        return Json(ApiResponse::<()>::error(e.to_string())).into_response();
+    }
+    mark_mailbox_viewed(state, &mailbox_id, user_id, viewed_at).await;
+
+    let pool = state.db.pool().clone();
+    let (tx, rx) = tokio::sync::mpsc::channel::<Result<String, std::io::Error>>(32);
+    tokio::spawn(async move {
+        use futures::TryStreamExt;
+
+        let mut emails = common::db::stream_mailbox_emails(&pool, &mailbox_id, &filter, range.since, range.until);
+        loop {
+            let line = match emails.try_next().await {
+                Ok(Some(email)) => serde_json::to_string(&email)
+                    .map(|json| json + "\n")
+                    .map_err(std::io::Error::other),
+                Ok(None) => break,
+                Err(e) => {
+                    error!("Database error while streaming emails: {}", e);
+                    Err(std::io::Error::other(e))
+                }
+            };
+            let failed = line.is_err();
+            // Stop reading once the client has gone away
+            if tx.send(line).await.is_err() || failed {
+                break;
+            }
+        }
+    });
+
+    let body = futures::stream::unfold(rx, |mut rx| async move {
+        rx.recv().await.map(|line| (line, rx))
+    });
+
+    Response::builder()
+        .header(header::CONTENT_TYPE, "application/x-ndjson")
+        .body(axum::body::Body::from_stream(body))
+        .unwrap()
+}
+
+async fn get_email_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    mailbox_id: &str,
+    email_id: &str,
+) -> Result<Email, AppError> {
+    // First check if the mailbox belongs to the user
+    let mailbox = state.db.get_mailbox(mailbox_id).await?
+        .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+    if !can_access_mailbox(state, &mailbox, user_id).await? {
+        return Err(AppError::Auth("You do not have permission to access this email".into()));
+    }
+
+    let email = state.db.get_email(email_id).await?
+        .ok_or_else(|| AppError::NotFound("Email not found".into()))?;
+
+    if email.mailbox_id != mailbox_id {
+        return Err(AppError::NotFound("Email not found in this mailbox".into()));
+    }
+
+    Ok(email)
+}
+
+async fn get_email<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<Email>>, StatusCode> {
+    match get_email_for_user(&state, &claims.sub, &mailbox_id, &email_id).await {
+        Ok(email) => Ok(Json(ApiResponse::success(email))),
+        Err(e) => {
+            error!("Error while retrieving email: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// The ciphertext of an email as a binary age file, to decrypt locally with
+/// the `age` CLI rather than decoding the base64 of the JSON API
+fn raw_email_response(email: &Email) -> Result<Response, AppError> {
+    use base64::Engine as _;
+
+    let ciphertext = base64::engine::general_purpose::STANDARD
+        .decode(&email.encrypted_content)
+        .map_err(|e| AppError::Internal(format!("Stored ciphertext of email {} is not valid base64: {}", email.id, e)))?;
+    Ok(Response::builder()
+        .header(header::CONTENT_TYPE, "application/octet-stream")
+        .header(header::CONTENT_DISPOSITION, format!("attachment; filename=\"{}.age\"", email.id))
+        .body(axum::body::Body::from(ciphertext))
+        .unwrap())
+}
+
+async fn get_email_raw<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+) -> Result<Response, AppError> {
+    let email = get_email_for_user(&state, &claims.sub, &mailbox_id, &email_id).await?;
+    raw_email_response(&email)
+}
+
+async fn get_email_attachment<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, email_id, attachment_id)): Path<(String, String, String)>,
+) -> Result<Json<ApiResponse<EmailAttachment>>, StatusCode> {
+    let result: Result<EmailAttachment, AppError> = async {
+        get_email_for_user(&state, &claims.sub, &mailbox_id, &email_id).await?;
+
+        state.db.get_email_attachment(&attachment_id).await?
+            .filter(|attachment| attachment.email_id == email_id)
+            .ok_or_else(|| AppError::NotFound("Attachment not found".into()))
+    }.await;
+
+    match result {
+        Ok(attachment) => Ok(Json(ApiResponse::success(attachment))),
+        Err(e) => {
+            error!("Error while retrieving email attachment: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+async fn get_email_headers_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    mailbox_id: &str,
+    email_id: &str,
+) -> Result<serde_json::Value, AppError> {
+    get_email_for_user(state, user_id, mailbox_id, email_id).await?;
+
+    state.db.get_email_headers(email_id).await?
+        .ok_or_else(|| AppError::NotFound("No headers were stored for this email".into()))
+}
+
+/// The plaintext headers listed in `common::CAPTURED_HEADERS`, plus the
+/// `X-*` ones, without decrypting the email
+async fn get_email_headers<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode> {
+    match get_email_headers_for_user(&state, &claims.sub, &mailbox_id, &email_id).await {
+        Ok(headers) => Ok(Json(ApiResponse::success(headers))),
+        Err(e) => {
+            error!("Error while retrieving email headers: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+async fn delete_email_for_user<D: Database>(
     state: &Arc<AppState<D>>,
     user_id: &str,
     mailbox_id: &str,
@@ -542,7 +1536,7 @@ async fn delete_email_for_user<D: Database>(
     let mailbox = state.db.get_mailbox(mailbox_id).await?
         .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
 
-    if mailbox.owner_id != user_id {
+    if !can_access_mailbox(state, &mailbox, user_id).await? {
         return Err(AppError::Auth("You do not have permission to delete this email".into()));
     }
 
@@ -570,19 +1564,353 @@ async fn delete_email<D: Database>(
     }
 }
 
+/// Changes when a received email expires, independently of the mailbox's setting
+async fn update_email<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+    Json(req): Json<UpdateEmailRequest>,
+) -> Result<Json<ApiResponse<Email>>, StatusCode> {
+    let result: Result<Email, AppError> = async {
+        let mut email = get_email_for_user(&state, &claims.sub, &mailbox_id, &email_id).await?;
+
+        if let Some(expires_in) = req.expires_in_seconds {
+            let expires_at = expires_in
+                .map(validate_expires_in)
+                .transpose()?
+                .map(|seconds| chrono::Utc::now().timestamp() + seconds);
+            state.db.update_email_expiry(&email_id, expires_at).await?;
+            email.expires_at = expires_at;
+        }
+        Ok(email)
+    }.await;
+
+    match result {
+        Ok(email) => Ok(Json(ApiResponse::success(email))),
+        Err(e) => {
+            error!("Error while updating email: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// The body is optional, and without one the whole mailbox is cleared
+fn parse_delete_emails_request(body: &[u8]) -> Result<DeleteEmailsRequest, String> {
+    if body.is_empty() {
+        return Ok(DeleteEmailsRequest::default());
+    }
+    serde_json::from_slice(body).map_err(|e| format!("Invalid request: {}", e))
+}
+
+async fn delete_emails_for_user<D: Database>(
+    state: &Arc<AppState<D>>,
+    user_id: &str,
+    mailbox_id: &str,
+    req: DeleteEmailsRequest,
+) -> Result<u64, AppError> {
+    let mailbox = state.db.get_mailbox(mailbox_id).await?
+        .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+    if !can_access_mailbox(state, &mailbox, user_id).await? {
+        return Err(AppError::Auth("You do not have permission to delete these emails".into()));
+    }
+
+    // Nothing is deleted when any of the emails is not in this mailbox
+    if let Some(email_ids) = &req.email_ids {
+        for email_id in email_ids {
+            match state.db.get_email(email_id).await? {
+                Some(email) if email.mailbox_id == mailbox_id => {}
+                _ => return Err(AppError::NotFound(format!("Email {} not found in this mailbox", email_id))),
+            }
+        }
+    }
+
+    state.db.delete_emails_bulk(mailbox_id, req.email_ids.as_deref(), req.before_timestamp).await
+}
+
+async fn delete_emails<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<ApiResponse<DeleteEmailsResponse>>, StatusCode> {
+    let req = match parse_delete_emails_request(&body) {
+        Ok(req) => req,
+        Err(e) => return Ok(Json(ApiResponse::error(e))),
+    };
+
+    match delete_emails_for_user(&state, &claims.sub, &mailbox_id, req).await {
+        Ok(deleted_count) => Ok(Json(ApiResponse::success(DeleteEmailsResponse { deleted_count }))),
+        Err(e) => {
+            error!("Error while deleting emails: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Deletes the expired emails of the mailbox now instead of waiting for the
+/// cleanup task
+async fn delete_expired_emails<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<DeleteEmailsResponse>>, StatusCode> {
+    let result: Result<u64, AppError> = async {
+        let mailbox = state.db.get_mailbox(&mailbox_id).await?
+            .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+
+        if !can_access_mailbox(&state, &mailbox, &claims.sub).await? {
+            return Err(AppError::Auth("You do not have permission to delete these emails".into()));
+        }
+
+        state.db.delete_expired_emails_for_mailbox(&mailbox_id).await
+    }.await;
+
+    match result {
+        Ok(deleted_count) => Ok(Json(ApiResponse::success(DeleteEmailsResponse { deleted_count }))),
+        Err(e) => {
+            error!("Error while deleting expired emails: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Deletes the expired emails of every mailbox the user can manage
+async fn delete_all_expired_emails<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<DeleteExpiredEmailsResponse>>>, StatusCode> {
+    let result: Result<Vec<DeleteExpiredEmailsResponse>, AppError> = async {
+        let mut deleted = Vec::new();
+        for mailbox in state.db.get_accessible_mailboxes(&claims.sub).await? {
+            if !can_manage_mailbox(&state, &mailbox, &claims.sub).await? {
+                continue;
+            }
+            let deleted_count = state.db.delete_expired_emails_for_mailbox(&mailbox.id).await?;
+            deleted.push(DeleteExpiredEmailsResponse { mailbox_id: mailbox.id, deleted_count });
+        }
+        Ok(deleted)
+    }.await;
+
+    match result {
+        Ok(deleted) => Ok(Json(ApiResponse::success(deleted))),
+        Err(e) => {
+            error!("Error while deleting expired emails: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+async fn import_eml<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(id): Path<String>,
+    mut multipart: Multipart,
+) -> Result<Json<ApiResponse<ImportEmlResponse>>, StatusCode> {
+    let mailbox = match state.db.get_mailbox(&id).await {
+        Ok(Some(mailbox)) => mailbox,
+        Ok(None) => return Ok(Json(ApiResponse::error("Mailbox not found"))),
+        Err(e) => {
+            error!("Database error while getting mailbox: {}", e);
+            return Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")));
+        }
+    };
+    match can_access_mailbox(&state, &mailbox, &claims.sub).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(Json(ApiResponse::error("You do not have permission to import into this mailbox"))),
+        Err(e) => {
+            error!("Database error while checking mailbox access: {}", e);
+            return Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")));
+        }
+    }
+
+    let keys = match state.db.list_mailbox_keys(&mailbox.id).await {
+        Ok(keys) => keys,
+        Err(e) => {
+            error!("Database error while getting mailbox keys: {}", e);
+            return Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")));
+        }
+    };
+    let backup_key = match state.db.get_backup_key(&mailbox.owner_id).await {
+        Ok(backup_key) => backup_key,
+        Err(e) => {
+            error!("Database error while getting backup key: {}", e);
+            return Ok(Json(ApiResponse::error("Unable to retrieve mailbox. Please try again later")));
+        }
+    };
+
+    let mut imported = 0;
+    let mut errors = Vec::new();
+    let mut files = 0;
+
+    loop {
+        let mut field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return Ok(Json(ApiResponse::error(format!("Invalid upload: {}", e)))),
+        };
+
+        files += 1;
+        if files > MAX_EML_FILES_PER_IMPORT {
+            errors.push(format!("Only the first {} files were imported", MAX_EML_FILES_PER_IMPORT));
+            break;
+        }
+
+        let file_name = field.file_name()
+            .or(field.name())
+            .unwrap_or("unnamed")
+            .to_string();
+
+        // Read chunk by chunk so oversized files are rejected without buffering them
+        let mut raw_email = Vec::new();
+        let mut too_large = false;
+        loop {
+            match field.chunk().await {
+                Ok(Some(chunk)) => {
+                    if raw_email.len() + chunk.len() > state.max_email_size {
+                        too_large = true;
+                        break;
+                    }
+                    raw_email.extend_from_slice(&chunk);
+                }
+                Ok(None) => break,
+                Err(e) => return Ok(Json(ApiResponse::error(format!("Invalid upload: {}", e)))),
+            }
+        }
+
+        if too_large {
+            errors.push(format!("{}: file exceeds the {} byte limit", file_name, state.max_email_size));
+            continue;
+        }
+        let Some(message) = mail_parser::Message::parse(&raw_email) else {
+            errors.push(format!("{}: not a valid RFC 5322 email", file_name));
+            continue;
+        };
+
+        let encrypted_content = match common::security::encrypt_email_to_keys(
+            &raw_email,
+            &keys,
+            backup_key.as_ref().map(|key| key.public_key.as_str()),
+        ) {
+            Ok(content) => content,
+            Err(e) => {
+                errors.push(format!("{}: {}", file_name, e));
+                continue;
+            }
+        };
+
+        // Imports count against the owner's storage quota like received emails
+        match StorageQuota::load(state.db.as_ref(), &mailbox.owner_id).await {
+            Ok(Some(quota)) if quota.check(1, encrypted_content.len() as i64).is_err() => {
+                errors.push(format!("{}: storage quota exceeded", file_name));
+                continue;
+            }
+            Ok(_) => {}
+            Err(e) => {
+                error!("Database error while checking the storage quota: {}", e);
+                errors.push(format!("{}: unable to save email", file_name));
+                continue;
+            }
+        }
+
+        let received_at = chrono::Utc::now().timestamp();
+        let email = Email {
+            id: state.id_generator.generate(),
+            mailbox_id: mailbox.id.clone(),
+            encrypted_content,
+            received_at,
+            expires_at: mailbox.mail_expires_in.map(|duration| received_at + duration),
+            from_addr: String::new(),
+            to_addr: String::new(),
+            subject: String::new(),
+            headers_json: None,
+            raw_size_bytes: raw_email.len() as i64,
+            message_id: None,
+        }
+        .with_headers(&message);
+
+        match state.db.save_email(&email).await {
+            Ok(true) => imported += 1,
+            Ok(false) => {
+                errors.push(format!("{}: already in the mailbox", file_name));
+                continue;
+            }
+            Err(e) => {
+                error!("Failed to save imported email: {}", e);
+                errors.push(format!("{}: unable to save email", file_name));
+                continue;
+            }
+        }
+
+        if state.enable_search_index {
+            let search_tokens = common::search::search_tokens(&message, &mailbox.public_key);
+            if let Err(e) = state.db.save_email_search_tokens(&email, &search_tokens).await {
+                error!("Failed to index imported email {}: {}", email.id, e);
+            }
+        }
+    }
+
+    Ok(Json(ApiResponse::success(ImportEmlResponse { imported, errors })))
+}
+
+/// The mailbox list is polled by the frontend, so it is served with caching
+/// headers and answers conditional requests with 304 while nothing changed
 async fn list_mailboxes<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
-) -> Result<Json<ApiResponse<Vec<Mailbox>>>, StatusCode> {
-    match state.db.get_mailboxes_by_owner(&claims.sub).await {
-        Ok(mailboxes) => Ok(Json(ApiResponse::success(mailboxes))),
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let unavailable = || {
+        Json(ApiResponse::<Vec<MailboxWithStats>>::error("Unable to retrieve mailboxes. Please try again later")).into_response()
+    };
+
+    let (count, last_modified_ms) = match state.db.get_mailbox_list_version(&claims.sub).await {
+        Ok((count, last_modified_ms)) => (count, last_modified_ms.unwrap_or(0)),
+        Err(e) => {
+            error!("Database error while checking mailbox list version: {}", e);
+            return Ok(unavailable());
+        }
+    };
+
+    // The count catches deletions, which never advance the latest modification time
+    let etag = format!("W/\"{}-{}\"", count, last_modified_ms);
+    let last_modified = chrono::DateTime::from_timestamp(last_modified_ms.div_euclid(1000), 0).unwrap_or_default();
+    let cache_headers = [
+        (header::CACHE_CONTROL, format!("private, max-age={}", MAILBOX_LIST_MAX_AGE_SECS)),
+        (header::LAST_MODIFIED, last_modified.format(HTTP_DATE_FORMAT).to_string()),
+        (header::ETAG, etag.clone()),
+    ];
+
+    if is_not_modified(&headers, &etag, last_modified.timestamp()) {
+        return Ok((StatusCode::NOT_MODIFIED, cache_headers).into_response());
+    }
+
+    match state.db.get_mailboxes_by_owner_with_stats(&claims.sub).await {
+        Ok(mailboxes) => Ok((cache_headers, Json(ApiResponse::success(mailboxes))).into_response()),
         Err(e) => {
             error!("Database error while listing mailboxes: {}", e);
-            Ok(Json(ApiResponse::error("Unable to retrieve mailboxes. Please try again later")))
+            Ok(unavailable())
         }
     }
 }
 
+/// If-None-Match takes precedence over If-Modified-Since, as in RFC 9110
+fn is_not_modified(headers: &HeaderMap, etag: &str, last_modified: i64) -> bool {
+    let opaque_tag = |tag: &str| tag.trim().trim_start_matches("W/").to_string();
+
+    if let Some(if_none_match) = headers.get(header::IF_NONE_MATCH).and_then(|v| v.to_str().ok()) {
+        return if_none_match
+            .split(',')
+            .any(|tag| tag.trim() == "*" || opaque_tag(tag) == opaque_tag(etag));
+    }
+
+    headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| chrono::NaiveDateTime::parse_from_str(v, HTTP_DATE_FORMAT).ok())
+        .is_some_and(|since| last_modified <= since.and_utc().timestamp())
+}
+
 async fn get_supported_domains<D: Database>(
     State(_state): State<Arc<AppState<D>>>,
 ) -> Result<Json<ApiResponse<SupportedDomainsResponse>>, StatusCode> {
@@ -594,12 +1922,56 @@ async fn get_supported_domains<D: Database>(
     Ok(Json(ApiResponse::success(SupportedDomainsResponse { domains })))
 }
 
+/// Storage used by the user's mailboxes against their quota
+async fn get_user_stats<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<UserStatsResponse>>, StatusCode> {
+    let result: Result<UserStatsResponse, AppError> = async {
+        let settings = state.db.get_user_settings(&claims.sub).await?;
+        let stats = state.db.get_user_email_stats(&claims.sub).await?;
+        Ok(UserStatsResponse {
+            email_count: stats.email_count,
+            total_bytes: stats.total_bytes,
+            email_count_limit: settings.as_ref().and_then(|settings| settings.email_count_limit),
+            total_bytes_limit: settings.as_ref().and_then(|settings| settings.total_storage_bytes_limit),
+        })
+    }.await;
+
+    match result {
+        Ok(stats) => Ok(Json(ApiResponse::success(stats))),
+        Err(e) => {
+            error!("Error while retrieving user stats: {}", e);
+            Ok(Json(ApiResponse::error("Unable to retrieve storage usage. Please try again later")))
+        }
+    }
+}
+
+/// Raw sizes of the emails as received, unlike the quota usage of
+/// [`get_user_stats`], which counts what is stored
+async fn get_user_storage_stats<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<StorageStatsResponse>>, StatusCode> {
+    match state.db.get_mailbox_storage_stats(&claims.sub).await {
+        Ok(by_mailbox) => Ok(Json(ApiResponse::success(StorageStatsResponse {
+            total_emails: by_mailbox.iter().map(|mailbox| mailbox.email_count).sum(),
+            total_bytes: by_mailbox.iter().map(|mailbox| mailbox.total_bytes).sum(),
+            by_mailbox,
+        }))),
+        Err(e) => {
+            error!("Error while retrieving storage stats: {}", e);
+            Ok(Json(ApiResponse::error("Unable to retrieve storage usage. Please try again later")))
+        }
+    }
+}
+
 async fn list_api_keys<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
 ) -> Result<Json<ApiResponse<Vec<ApiKey>>>, StatusCode> {
     let rows = sqlx::query(
-        "SELECT id, key, created_at, expires_at FROM api_keys WHERE user_id = ?"
+        "SELECT id, key, created_at, expires_at, revoked_at, scopes FROM api_keys WHERE user_id = ?"
     )
     .bind(&claims.sub)
     .fetch_all(state.db.pool())
@@ -614,33 +1986,113 @@ async fn list_api_keys<D: Database>(
         key: row.get("key"),
         created_at: row.get("created_at"),
         expires_at: row.get("expires_at"),
+        revoked_at: row.get("revoked_at"),
+        scopes: ApiKeyScope::parse_list(row.get("scopes")),
     }).collect();
 
     Ok(Json(ApiResponse::success(api_keys)))
 }
 
+/// The request body is optional, keys created without one get every scope
 async fn create_api_key<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+    body: axum::body::Bytes,
 ) -> Result<Json<ApiResponse<ApiKey>>, StatusCode> {
-    let api_key = state.db.create_api_key(&claims.sub)
+    let req = if body.is_empty() {
+        CreateApiKeyRequest::default()
+    } else {
+        match serde_json::from_slice::<CreateApiKeyRequest>(&body) {
+            Ok(req) => req,
+            Err(e) => return Ok(Json(ApiResponse::error(format!("Invalid request: {}", e)))),
+        }
+    };
+    let scopes = req.scopes.unwrap_or_else(|| ApiKeyScope::ALL.to_vec());
+    if scopes.is_empty() {
+        return Ok(Json(ApiResponse::error("An API key needs at least one scope")));
+    }
+    if let Some(expires_in_seconds) = req.expires_in_seconds {
+        if !(1..=MAX_API_KEY_LIFETIME_SECS).contains(&expires_in_seconds) {
+            return Ok(Json(ApiResponse::error(format!(
+                "expires_in_seconds must be between 1 and {}",
+                MAX_API_KEY_LIFETIME_SECS
+            ))));
+        }
+    }
+
+    let api_key = state.db.create_api_key(&claims.sub, state.api_key_length, &scopes, req.expires_in_seconds)
         .await
         .map_err(|e| {
             error!("Database error while creating API key: {}", e);
             StatusCode::INTERNAL_SERVER_ERROR
         })?;
+    let metadata = serde_json::json!({ "scopes": api_key.scopes, "expires_at": api_key.expires_at });
+    request_info.audit(&state.db, &claims.sub, audit::CREATE_API_KEY, Some(("api_key", &api_key.id)), Some(metadata)).await;
 
     Ok(Json(ApiResponse::success(ApiKey {
         id: api_key.id,
         key: api_key.key,
         created_at: api_key.created_at,
         expires_at: api_key.expires_at,
+        revoked_at: api_key.revoked_at,
+        scopes: api_key.scopes,
     })))
 }
 
+async fn get_api_key<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(key_id): Path<String>,
+) -> Result<Json<ApiResponse<ApiKey>>, StatusCode> {
+    let row = sqlx::query(
+        "SELECT id, key, created_at, expires_at, revoked_at, scopes FROM api_keys WHERE id = ? AND user_id = ?"
+    )
+    .bind(&key_id)
+    .bind(&claims.sub)
+    .fetch_optional(state.db.pool())
+    .await
+    .map_err(|e| {
+        error!("Database error while getting API key: {}", e);
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+
+    match row {
+        Some(row) => Ok(Json(ApiResponse::success(ApiKey {
+            id: row.get("id"),
+            key: row.get("key"),
+            created_at: row.get("created_at"),
+            expires_at: row.get("expires_at"),
+            revoked_at: row.get("revoked_at"),
+            scopes: ApiKeyScope::parse_list(row.get("scopes")),
+        }))),
+        None => Ok(Json(ApiResponse::error("API key not found"))),
+    }
+}
+
+async fn revoke_all_api_keys<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+) -> Result<Json<ApiResponse<RevokeApiKeysResponse>>, StatusCode> {
+    match state.db.revoke_user_api_keys(&claims.sub).await {
+        Ok(revoked) => {
+            warn!(target: "security", "User {} revoked all API keys ({} revoked)", claims.sub, revoked);
+            let metadata = serde_json::json!({ "revoked": revoked });
+            request_info.audit(&state.db, &claims.sub, audit::REVOKE_API_KEYS, None, Some(metadata)).await;
+            Ok(Json(ApiResponse::success(RevokeApiKeysResponse { revoked })))
+        }
+        Err(e) => {
+            error!("Database error while revoking API keys: {}", e);
+            Ok(Json(ApiResponse::error("Unable to revoke API keys. Please try again later")))
+        }
+    }
+}
+
 async fn delete_api_key<D: Database>(
     State(state): State<Arc<AppState<D>>>,
     claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
     Path(key_id): Path<String>,
 ) -> Result<Json<ApiResponse<()>>, StatusCode> {
     // First verify the API key belongs to the user
@@ -663,6 +2115,7 @@ async fn delete_api_key<D: Database>(
                     error!("Database error while deleting API key: {}", e);
                     StatusCode::INTERNAL_SERVER_ERROR
                 })?;
+            request_info.audit(&state.db, &claims.sub, audit::DELETE_API_KEY, Some(("api_key", &key_id)), None).await;
             Ok(Json(ApiResponse::success(())))
         }
         Some(_) => Ok(Json(ApiResponse::error("You don't have permission to delete this API key"))),
@@ -673,19 +2126,25 @@ async fn delete_api_key<D: Database>(
 // @APIDOC-START
 /// Get emails from a mailbox
 /// 
-/// Lists all emails in the specified mailbox. Requires API authentication.
+/// Lists the emails in the specified mailbox, newest first. Requires API authentication.
+/// `since` and `until` limit the list to emails received within that time range, inclusively.
 /// 
 /// Authorization:
 /// - Requires a valid API key in the Authorization header
 /// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `read_emails` scope
 /// 
 /// Parameters:
 /// - `id`: The ID of the mailbox to retrieve emails from
 /// 
+/// Query parameters:
+/// - `since` (integer): Unix timestamp of the earliest receipt to include
+/// - `until` (integer): Unix timestamp of the latest receipt to include, not before `since`
+/// 
 /// Returns:
-/// - 200: List of emails in the mailbox
+/// - 200: List of emails in the mailbox, or an error if `since` is after `until`
 /// - 401: Missing or invalid API key
-/// - 403: API key owner doesn't have access to the mailbox
+/// - 403: API key lacks the required scope, or its owner doesn't have access to the mailbox
 /// - 404: Mailbox not found
 /// 
 /// Example response:
@@ -709,11 +2168,14 @@ async fn api_get_mailbox_emails<D>(
     State(state): State<Arc<AppState<D>>>,
     api_claims: api_auth::ApiClaims,
     Path(id): Path<String>,
+    Query(range): Query<ReceivedRange>,
 ) -> Result<Json<ApiResponse<Vec<Email>>>, StatusCode>
 where
     D: Database + Send + Sync + 'static,
 {
-    match get_mailbox_emails_for_user(&state, &api_claims.user_id, &id).await {
+    api_claims.require_scope(ApiKeyScope::ReadEmails)?;
+
+    match get_mailbox_emails_for_user(&state, &api_claims.user_id, &id, None, &EmailFilter::default(), range).await {
         Ok(emails) => Ok(Json(ApiResponse::success(emails))),
         Err(e) => {
             error!("API error while retrieving emails: {}", e);
@@ -730,6 +2192,7 @@ where
 /// Authorization:
 /// - Requires a valid API key in the Authorization header
 /// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `read_emails` scope
 /// 
 /// Parameters:
 /// - `mailbox_id`: The ID of the mailbox containing the email
@@ -738,7 +2201,7 @@ where
 /// Returns:
 /// - 200: The requested email
 /// - 401: Missing or invalid API key
-/// - 403: API key owner doesn't have access to the mailbox
+/// - 403: API key lacks the required scope, or its owner doesn't have access to the mailbox
 /// - 404: Mailbox or email not found
 /// 
 /// Example response:
@@ -752,7 +2215,8 @@ where
 ///     "from": "string",
 ///     "to": "string",
 ///     "content": "string",
-///     "received_at": 1234567890
+///     "received_at": 1234567890,
+///     "size_bytes": 2048
 ///   }
 /// }
 /// ```
@@ -764,6 +2228,8 @@ async fn api_get_email<D>(
 where
     D: Database + Send + Sync + 'static,
 {
+    api_claims.require_scope(ApiKeyScope::ReadEmails)?;
+
     match get_email_for_user(&state, &api_claims.user_id, &mailbox_id, &email_id).await {
         Ok(email) => Ok(Json(ApiResponse::success(email))),
         Err(e) => {
@@ -773,6 +2239,98 @@ where
     }
 }
 
+// @APIDOC-START
+/// Get the headers of an email
+/// 
+/// Retrieves the plaintext headers stored with an email, so they can be read without decrypting it.
+/// They are From, To, Subject, Date, Message-ID and every X- header, keyed by lowercase name with their raw values.
+/// Received, Authentication-Results and the MIME headers describing the body are not stored.
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `read_emails` scope
+/// 
+/// Parameters:
+/// - `mailbox_id`: The ID of the mailbox containing the email
+/// - `email_id`: The ID of the email
+/// 
+/// Returns:
+/// - 200: The headers of the email
+/// - 401: Missing or invalid API key
+/// - 403: API key lacks the required scope, or its owner doesn't have access to the mailbox
+/// - 404: Mailbox or email not found, or the email was stored without headers
+/// 
+/// Example response:
+/// ```json
+/// {
+///   "success": true,
+///   "data": {
+///     "from": "Sender <sender@example.com>",
+///     "to": "inbox@example.com",
+///     "subject": "Hello",
+///     "date": "Mon, 1 Jan 2024 00:00:00 +0000",
+///     "message-id": "<id@example.com>",
+///     "x-mailer": "Mailer"
+///   }
+/// }
+/// ```
+async fn api_get_email_headers<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<serde_json::Value>>, StatusCode>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::ReadEmails)?;
+
+    match get_email_headers_for_user(&state, &api_claims.user_id, &mailbox_id, &email_id).await {
+        Ok(headers) => Ok(Json(ApiResponse::success(headers))),
+        Err(e) => {
+            error!("API error while retrieving email headers: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+// @APIDOC-START
+/// Download the encrypted content of an email
+/// 
+/// Returns the email as the binary age file it is stored as, to decrypt locally with the `age` CLI:
+/// `age --decrypt -i key.txt <email_id>.age`. Unlike the `content` of the email in JSON, it is not base64-encoded.
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `read_emails` scope
+/// 
+/// Parameters:
+/// - `mailbox_id`: The ID of the mailbox containing the email
+/// - `email_id`: The ID of the email
+/// 
+/// Returns:
+/// - 200: The encrypted email, as `application/octet-stream` named `<email_id>.age`
+/// - 401: Missing or invalid API key, or its owner doesn't have access to the mailbox
+/// - 403: API key lacks the required scope
+/// - 404: Mailbox or email not found
+async fn api_get_email_raw<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    Path((mailbox_id, email_id)): Path<(String, String)>,
+) -> Result<Response, Response>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::ReadEmails).map_err(IntoResponse::into_response)?;
+
+    let email = get_email_for_user(&state, &api_claims.user_id, &mailbox_id, &email_id).await;
+    email.and_then(|email| raw_email_response(&email)).map_err(|e| {
+        error!("API error while downloading email: {}", e);
+        e.into_response()
+    })
+}
+
 // @APIDOC-START
 /// Delete an email from a mailbox
 /// 
@@ -782,6 +2340,7 @@ where
 /// Authorization:
 /// - Requires a valid API key in the Authorization header
 /// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `delete_emails` scope
 /// 
 /// Parameters:
 /// - `mailbox_id`: The ID of the mailbox containing the email
@@ -790,7 +2349,7 @@ where
 /// Returns:
 /// - 200: Email successfully deleted
 /// - 401: Missing or invalid API key
-/// - 403: API key owner doesn't have access to the mailbox
+/// - 403: API key lacks the required scope, or its owner doesn't have access to the mailbox
 /// - 404: Mailbox or email not found
 /// 
 /// Example response:
@@ -808,6 +2367,8 @@ async fn api_delete_email<D>(
 where
     D: Database + Send + Sync + 'static,
 {
+    api_claims.require_scope(ApiKeyScope::DeleteEmails)?;
+
     match delete_email_for_user(&state, &api_claims.user_id, &mailbox_id, &email_id).await {
         Ok(_) => Ok(Json(ApiResponse::success(()))),
         Err(e) => {
@@ -817,6 +2378,208 @@ where
     }
 }
 
+// @APIDOC-START
+/// Delete emails from a mailbox in bulk
+/// 
+/// Permanently deletes the emails of the specified mailbox selected by the optional JSON body.
+/// With `email_ids` only those emails are deleted and with `before_timestamp` only the ones received before that time.
+/// Without a body every email of the mailbox is deleted. This operation cannot be undone.
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `delete_emails` scope
+/// 
+/// Parameters:
+/// - `id`: The ID of the mailbox to delete emails from
+/// 
+/// Returns:
+/// - 200: Number of deleted emails
+/// - 401: Missing or invalid API key
+/// - 403: API key lacks the required scope, or its owner doesn't have access to the mailbox
+/// - 404: Mailbox not found, or one of the emails is not in it
+/// 
+/// Example response:
+/// ```json
+/// {
+///   "success": true,
+///   "data": {
+///     "deleted_count": 42
+///   }
+/// }
+/// ```
+async fn api_delete_mailbox_emails<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    Path(mailbox_id): Path<String>,
+    body: axum::body::Bytes,
+) -> Result<Json<ApiResponse<DeleteEmailsResponse>>, StatusCode>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::DeleteEmails)?;
+
+    let req = match parse_delete_emails_request(&body) {
+        Ok(req) => req,
+        Err(e) => return Ok(Json(ApiResponse::error(e))),
+    };
+
+    match delete_emails_for_user(&state, &api_claims.user_id, &mailbox_id, req).await {
+        Ok(deleted_count) => Ok(Json(ApiResponse::success(DeleteEmailsResponse { deleted_count }))),
+        Err(e) => {
+            error!("API error while deleting emails: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+// @APIDOC-START
+/// Rotate the alias of a mailbox
+/// 
+/// Replaces the mailbox's primary alias with a new random one, for when the old address receives spam.
+/// Existing emails stay in the mailbox. Mail to the old alias is rejected with 550 for 24 hours.
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `manage_mailboxes` scope
+/// 
+/// Parameters:
+/// - `id`: The ID of the mailbox whose alias to rotate
+/// 
+/// Returns:
+/// - 200: The mailbox with its new alias
+/// - 401: Missing or invalid API key
+/// - 403: API key lacks the required scope, or its owner can't manage the mailbox
+/// - 404: Mailbox not found
+/// 
+/// Example response:
+/// ```json
+/// {
+///   "success": true,
+///   "data": {
+///     "id": "string",
+///     "alias": "string",
+///     "alias_rotated_at": 1234567890
+///   }
+/// }
+/// ```
+async fn api_rotate_mailbox_alias<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse<Mailbox>>, StatusCode>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::ManageMailboxes)?;
+
+    match aliases::rotate_mailbox_alias_for_user(&state, &api_claims.user_id, &id).await {
+        Ok(mailbox) => Ok(Json(ApiResponse::success(mailbox))),
+        Err(e) => {
+            error!("API error while rotating mailbox alias: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+// @APIDOC-START
+/// Create a mailbox
+/// 
+/// Creates a mailbox owned by the API key's user, with a random alias.
+/// The request body is a JSON object with the recipient `public_key` (age or SSH), and optionally
+/// a display `name` and `expires_in_seconds`, after which emails are deleted (at most 30 days).
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `manage_mailboxes` scope
+/// 
+/// Returns:
+/// - 200: The created mailbox, or an error if the request is invalid or the mailbox limit is reached
+/// - 401: Missing or invalid API key
+/// - 403: API key lacks the required scope
+/// 
+/// Example response:
+/// ```json
+/// {
+///   "success": true,
+///   "data": {
+///     "id": "string",
+///     "alias": "string",
+///     "name": "string",
+///     "public_key": "string",
+///     "key_type": "age_x25519",
+///     "created_at": 1234567890,
+///     "mail_expires_in": 86400
+///   }
+/// }
+/// ```
+async fn api_create_mailbox<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    request_info: audit::RequestInfo,
+    Json(req): Json<ApiCreateMailboxRequest>,
+) -> Result<Json<ApiResponse<Mailbox>>, StatusCode>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::ManageMailboxes)?;
+
+    match create_mailbox_for_user(&state, &api_claims.user_id, &request_info, req.into()).await {
+        Ok(mailbox) => Ok(Json(ApiResponse::success(mailbox))),
+        Err(e) => {
+            error!("API error while creating mailbox: {}", e);
+            Ok(Json(ApiResponse::error(e)))
+        }
+    }
+}
+
+// @APIDOC-START
+/// Delete a mailbox
+/// 
+/// Deletes a mailbox and all of its emails. Mail to its aliases is rejected afterwards.
+/// 
+/// Authorization:
+/// - Requires a valid API key in the Authorization header
+/// - Format: `Authorization: Bearer <api-key>`
+/// - The key needs the `manage_mailboxes` scope
+/// 
+/// Parameters:
+/// - `id`: The ID of the mailbox to delete
+/// 
+/// Returns:
+/// - 200: Mailbox deleted, or an error if it doesn't exist or can't be managed by the key's owner
+/// - 401: Missing or invalid API key
+/// - 403: API key lacks the required scope
+/// 
+/// Example response:
+/// ```json
+/// {
+///   "success": true,
+///   "data": null
+/// }
+/// ```
+async fn api_remove_mailbox<D>(
+    State(state): State<Arc<AppState<D>>>,
+    api_claims: api_auth::ApiClaims,
+    request_info: audit::RequestInfo,
+    Path(id): Path<String>,
+) -> Result<Json<ApiResponse<()>>, StatusCode>
+where
+    D: Database + Send + Sync + 'static,
+{
+    api_claims.require_scope(ApiKeyScope::ManageMailboxes)?;
+
+    match delete_mailbox_for_user(&state, &api_claims.user_id, &request_info, &id).await {
+        Ok(()) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("API error while deleting mailbox: {}", e);
+            Ok(Json(ApiResponse::error(e)))
+        }
+    }
+}
+
 // Re-export auth types for public use
 pub use auth::{AuthResponse, LoginRequest, RegisterRequest};
 
@@ -0,0 +1,182 @@
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Path, Query, State,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+};
+use common::{db::Database, events::EmailEvent, AppError};
+use futures::{future, stream, StreamExt};
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, warn};
+
+use crate::{auth, can_access_mailbox, AppState};
+
+/// How long a client has to send its token as the first message
+const AUTH_TIMEOUT: Duration = Duration::from_secs(10);
+/// Keeps proxies from closing idle event streams
+const PING_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Deserialize)]
+pub struct LiveQuery {
+    token: Option<String>,
+}
+
+/// Streams an `EmailEvent` for every new email of the mailbox. Browsers can't
+/// set headers on WebSockets, so the token is passed as the `token` query
+/// parameter or, to keep it out of access logs, as the first text message.
+pub async fn mailbox_live<D: Database + 'static>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(mailbox_id): Path<String>,
+    Query(query): Query<LiveQuery>,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let Some(token) = query.token else {
+        return ws.on_upgrade(move |mut socket| async move {
+            let token = match tokio::time::timeout(AUTH_TIMEOUT, socket.recv()).await {
+                Ok(Some(Ok(Message::Text(token)))) => token,
+                _ => return,
+            };
+            if authorize(&state, &mailbox_id, &token).await.is_ok() {
+                forward_events(socket, state.email_events.subscribe(), mailbox_id).await;
+            }
+        });
+    };
+
+    if let Err(response) = authorize(&state, &mailbox_id, &token).await {
+        return response;
+    }
+    let events = state.email_events.subscribe();
+    ws.on_upgrade(move |socket| forward_events(socket, events, mailbox_id))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventsQuery {
+    /// Used when the `Authorization` header is absent, as `EventSource`
+    /// can't set headers
+    #[serde(alias = "Authorization")]
+    authorization: Option<String>,
+}
+
+/// Server-sent events alternative to `mailbox_live`, sending a `new_email`
+/// event for every new email of the mailbox. Events carry the email ID as
+/// their ID, so a reconnecting client gets the events it missed from the
+/// recent ones through `Last-Event-ID`.
+pub async fn mailbox_events<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Path(mailbox_id): Path<String>,
+    Query(query): Query<EventsQuery>,
+    headers: HeaderMap,
+) -> Response {
+    let authorization = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .or(query.authorization);
+    let Some(authorization) = authorization else {
+        return (StatusCode::UNAUTHORIZED, "Unauthorized").into_response();
+    };
+    let token = authorization.strip_prefix("Bearer ").unwrap_or(&authorization);
+    if let Err(response) = authorize(&state, &mailbox_id, token).await {
+        return response;
+    }
+
+    let last_event_id = headers
+        .get("Last-Event-ID")
+        .and_then(|value| value.to_str().ok());
+    let (missed, receiver) = match last_event_id {
+        Some(last_event_id) => state.email_events.subscribe_after(last_event_id),
+        None => (Vec::new(), state.email_events.subscribe()),
+    };
+
+    let lagging_mailbox_id = mailbox_id.clone();
+    let received = stream::unfold(receiver, move |mut receiver| {
+        let mailbox_id = lagging_mailbox_id.clone();
+        async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(RecvError::Lagged(missed)) => {
+                        warn!("Event stream of mailbox {} missed {} events", mailbox_id, missed);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    let events = stream::iter(missed)
+        .chain(received)
+        .filter(move |event| future::ready(event.mailbox_id == mailbox_id))
+        .map(|event| Event::default().event("new_email").id(&event.email_id).json_data(&event));
+
+    Sse::new(events)
+        .keep_alive(KeepAlive::new().interval(PING_INTERVAL).event(Event::default().event("ping")))
+        .into_response()
+}
+
+async fn authorize<D: Database>(
+    state: &Arc<AppState<D>>,
+    mailbox_id: &str,
+    token: &str,
+) -> Result<(), Response> {
+    let claims = auth::authenticate_token(state, token).await?;
+
+    let access = match state.db.get_mailbox(mailbox_id).await {
+        Ok(Some(mailbox)) => can_access_mailbox(state, &mailbox, &claims.sub).await,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Mailbox not found").into_response()),
+        Err(e) => Err(e),
+    };
+    match access {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::FORBIDDEN, "You do not have permission to access this mailbox").into_response()),
+        Err(AppError::Auth(e)) => Err((StatusCode::UNAUTHORIZED, e).into_response()),
+        Err(e) => {
+            error!("Database error while checking mailbox access: {}", e);
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response())
+        }
+    }
+}
+
+async fn forward_events(
+    mut socket: WebSocket,
+    mut events: broadcast::Receiver<EmailEvent>,
+    mailbox_id: String,
+) {
+    loop {
+        tokio::select! {
+            event = events.recv() => match event {
+                Ok(event) if event.mailbox_id == mailbox_id => {
+                    let frame = match serde_json::to_string(&event) {
+                        Ok(frame) => frame,
+                        Err(e) => {
+                            error!("Failed to serialize email event: {}", e);
+                            continue;
+                        }
+                    };
+                    if socket.send(Message::Text(frame)).await.is_err() {
+                        break;
+                    }
+                }
+                Ok(_) => {}
+                // The client can still list the mailbox for what it missed
+                Err(RecvError::Lagged(missed)) => {
+                    warn!("Live connection of mailbox {} missed {} events", mailbox_id, missed);
+                }
+                Err(RecvError::Closed) => break,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                // Pings are answered by axum, other messages are ignored
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+    debug!("Live connection of mailbox {} closed", mailbox_id);
+}
@@ -0,0 +1,143 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+};
+use common::{db::Database, AppError, MailboxKey};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{audit, auth::Claims, get_managed_mailbox, ApiResponse, AppState};
+
+/// Including the primary key
+const MAX_KEYS_PER_MAILBOX: usize = 10;
+const MAX_LABEL_LENGTH: usize = 64;
+
+#[derive(Debug, Deserialize)]
+pub struct AddKeyRequest {
+    label: String,
+    public_key: String,
+}
+
+/// Labels appear in URLs, so they are kept to a URL-safe alphabet
+fn validate_label(label: &str) -> Result<String, AppError> {
+    let label = label.trim();
+    if label.is_empty() || label.len() > MAX_LABEL_LENGTH {
+        return Err(AppError::Mail(format!("Key labels must be 1 to {} characters long", MAX_LABEL_LENGTH)));
+    }
+    if !label.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')) {
+        return Err(AppError::Mail("Key labels may only contain letters, digits, '-', '_' and '.'".into()));
+    }
+    Ok(label.to_string())
+}
+
+pub async fn list_keys<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<MailboxKey>>>, StatusCode> {
+    let result: Result<Vec<MailboxKey>, AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "keys").await?;
+        state.db.list_mailbox_keys(&mailbox_id).await
+    }.await;
+
+    match result {
+        Ok(keys) => Ok(Json(ApiResponse::success(keys))),
+        Err(e) => {
+            error!("Failed to list mailbox keys: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn add_key<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+    Path(mailbox_id): Path<String>,
+    Json(req): Json<AddKeyRequest>,
+) -> Result<Json<ApiResponse<MailboxKey>>, StatusCode> {
+    let result: Result<MailboxKey, AppError> = async {
+        let label = validate_label(&req.label)?;
+        let Some((key_type, public_key)) = common::security::normalize_public_key(&req.public_key) else {
+            return Err(AppError::Mail(
+                "Invalid public key: expected an age X25519 (age1...), ssh-ed25519 or ssh-rsa key".into(),
+            ));
+        };
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "keys").await?;
+
+        if state.db.list_mailbox_keys(&mailbox_id).await?.len() >= MAX_KEYS_PER_MAILBOX {
+            return Err(AppError::Mail(format!(
+                "Mailbox has reached its limit of {} keys",
+                MAX_KEYS_PER_MAILBOX
+            )));
+        }
+
+        let key = MailboxKey {
+            mailbox_id: mailbox_id.clone(),
+            label,
+            public_key,
+            key_type,
+            added_at: chrono::Utc::now().timestamp(),
+        };
+        state.db.add_mailbox_key(&key).await.map_err(|e| {
+            if e.to_string().contains("UNIQUE constraint failed") {
+                AppError::Mail(format!("The mailbox already has a key labelled {}", key.label))
+            } else {
+                e
+            }
+        })?;
+        request_info.audit(
+            &state.db,
+            &claims.sub,
+            audit::ADD_MAILBOX_KEY,
+            Some(("mailbox", &mailbox_id)),
+            Some(serde_json::json!({ "label": key.label })),
+        ).await;
+        Ok(key)
+    }.await;
+
+    match result {
+        Ok(key) => Ok(Json(ApiResponse::success(key))),
+        Err(e) => {
+            error!("Failed to add mailbox key: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn remove_key<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    request_info: audit::RequestInfo,
+    Path((mailbox_id, label)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result: Result<(), AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "keys").await?;
+
+        if label == MailboxKey::PRIMARY_LABEL {
+            return Err(AppError::Mail(
+                "The primary key can't be removed; change the mailbox's public key instead".into(),
+            ));
+        }
+        if !state.db.remove_mailbox_key(&mailbox_id, &label).await? {
+            return Err(AppError::NotFound("Key not found".into()));
+        }
+        request_info.audit(
+            &state.db,
+            &claims.sub,
+            audit::REMOVE_MAILBOX_KEY,
+            Some(("mailbox", &mailbox_id)),
+            Some(serde_json::json!({ "label": label })),
+        ).await;
+        Ok(())
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("Failed to remove mailbox key: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
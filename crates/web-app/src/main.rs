@@ -1,21 +1,25 @@
 use tracing::info;
 use web_app::{Config, run};
 use clap::Parser;
+use common::shutdown::CancellationToken;
 
 #[tokio::main]
 async fn main() {
     // Load .env file if it exists
     dotenv::dotenv().ok();
 
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing, exporting spans until the end of main
+    let _tracing = common::telemetry::init_tracing("web-app");
 
     // Parse command line arguments
     let config = Config::parse();
 
     info!("Starting web application...");
     
-    if let Err(e) = run(config).await {
+    // Without an SMTP server in this process, readiness only covers the database
+    let shutdown = CancellationToken::new();
+    common::shutdown::cancel_on_signal(&shutdown);
+    if let Err(e) = run(config, None, shutdown).await {
         tracing::error!("Application error: {}", e);
         std::process::exit(1);
     }
@@ -0,0 +1,121 @@
+use axum::{
+    body::Body,
+    extract::State,
+    http::{Method, Request, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::{collections::HashSet, sync::Arc};
+
+/// HTTP methods the server answers; anything else gets a 405 before routing
+#[derive(Debug, Clone)]
+pub struct AllowedMethods(Arc<HashSet<Method>>);
+
+impl AllowedMethods {
+    /// Parses method names case-insensitively, dropping DELETE when `disallow_delete` is set
+    pub fn parse(methods: &[String], disallow_delete: bool) -> anyhow::Result<Self> {
+        let mut allowed = HashSet::new();
+        for name in methods {
+            let method = Method::from_bytes(name.trim().to_ascii_uppercase().as_bytes())
+                .map_err(|_| anyhow::anyhow!("Invalid HTTP method in ALLOWED_HTTP_METHODS: {}", name))?;
+            allowed.insert(method);
+        }
+        if disallow_delete {
+            allowed.remove(&Method::DELETE);
+        }
+        Ok(Self(Arc::new(allowed)))
+    }
+
+    pub fn contains(&self, method: &Method) -> bool {
+        self.0.contains(method)
+    }
+}
+
+/// Whether `DISALLOW_DELETE_OPERATIONS` is set. DELETE requests are then
+/// dropped from [`AllowedMethods`], and routes that delete or revoke with
+/// another method are refused by [`refuse_destructive`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct DeleteOperations {
+    pub disallowed: bool,
+}
+
+/// Route layer marking a destructive route that isn't a DELETE, such as
+/// deleting an account with POST
+pub async fn refuse_destructive(
+    State(delete_operations): State<DeleteOperations>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if delete_operations.disallowed {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    next.run(req).await
+}
+
+pub async fn restrict_methods(
+    State(allowed): State<AllowedMethods>,
+    req: Request<Body>,
+    next: Next,
+) -> Response {
+    if !allowed.contains(req.method()) {
+        return StatusCode::METHOD_NOT_ALLOWED.into_response();
+    }
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{middleware, routing::{get, post}, Router};
+    use tower::ServiceExt;
+
+    fn methods(names: &[&str]) -> Vec<String> {
+        names.iter().map(|name| name.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_allowed_methods() {
+        let allowed = AllowedMethods::parse(&methods(&["get", " POST", "DELETE"]), false).unwrap();
+        assert!(allowed.contains(&Method::GET));
+        assert!(allowed.contains(&Method::POST));
+        assert!(allowed.contains(&Method::DELETE));
+        assert!(!allowed.contains(&Method::PATCH));
+
+        let read_only = AllowedMethods::parse(&methods(&["GET", "DELETE"]), true).unwrap();
+        assert!(!read_only.contains(&Method::DELETE));
+
+        assert!(AllowedMethods::parse(&methods(&["GET", "NOT A METHOD"]), false).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_destructive_route_is_refused() {
+        let app = |disallowed| {
+            Router::new()
+                .route("/delete-account", post(|| async { "deleted" }))
+                .route_layer(middleware::from_fn_with_state(DeleteOperations { disallowed }, refuse_destructive))
+                .route("/other", post(|| async { "ok" }))
+        };
+
+        let request = |uri: &str| Request::builder().method(Method::POST).uri(uri).body(Body::empty()).unwrap();
+        let response = app(false).oneshot(request("/delete-account")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app(true).oneshot(request("/delete-account")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let response = app(true).oneshot(request("/other")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn test_disallowed_method_is_rejected() {
+        let allowed = AllowedMethods::parse(&methods(&["GET"]), false).unwrap();
+        let app = Router::new()
+            .route("/", get(|| async { "ok" }).delete(|| async { "deleted" }))
+            .layer(middleware::from_fn_with_state(allowed, restrict_methods));
+
+        let request = |method: Method| Request::builder().method(method).uri("/").body(Body::empty()).unwrap();
+        let response = app.clone().oneshot(request(Method::GET)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.oneshot(request(Method::DELETE)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    }
+}
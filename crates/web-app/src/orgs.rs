@@ -0,0 +1,182 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+};
+use common::{db::Database, AppError, OrgRole, Organization, OrganizationMember};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, ApiResponse, AppState};
+
+#[derive(Debug, Deserialize)]
+pub struct CreateOrganizationRequest {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct AddMemberRequest {
+    user_id: String,
+    #[serde(default = "default_member_role")]
+    role: OrgRole,
+}
+
+fn default_member_role() -> OrgRole {
+    OrgRole::Member
+}
+
+/// Checks that the user may create another mailbox in the organization
+pub async fn check_mailbox_quota<D: Database>(
+    state: &Arc<AppState<D>>,
+    org_id: &str,
+    user_id: &str,
+) -> Result<(), AppError> {
+    if state.db.get_organization_role(org_id, user_id).await?.is_none() {
+        return Err(AppError::Auth("You are not a member of this organization".into()));
+    }
+
+    let organization = state.db.get_organization(org_id).await?
+        .ok_or_else(|| AppError::NotFound("Organization not found".into()))?;
+
+    if let Some(max_mailboxes) = organization.max_mailboxes {
+        if state.db.count_organization_mailboxes(org_id).await? >= max_mailboxes {
+            return Err(AppError::Mail(format!(
+                "Organization has reached its limit of {} mailboxes",
+                max_mailboxes
+            )));
+        }
+    }
+
+    Ok(())
+}
+
+/// The user's role, if it lets them manage members
+async fn require_manager<D: Database>(
+    state: &Arc<AppState<D>>,
+    org_id: &str,
+    user_id: &str,
+) -> Result<OrgRole, AppError> {
+    match state.db.get_organization_role(org_id, user_id).await? {
+        Some(role) if role.can_manage() => Ok(role),
+        Some(_) => Err(AppError::Auth("Only organization owners and admins can manage members".into())),
+        None => Err(AppError::NotFound("Organization not found".into())),
+    }
+}
+
+pub async fn create_organization<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Json(req): Json<CreateOrganizationRequest>,
+) -> Result<Json<ApiResponse<Organization>>, StatusCode> {
+    let name = req.name.trim();
+    if name.is_empty() {
+        return Ok(Json(ApiResponse::error("Organization name is required")));
+    }
+
+    let organization = Organization {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        owner_user_id: claims.sub.clone(),
+        // Only the admin API sets a limit
+        max_mailboxes: None,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+
+    match state.db.create_organization(&organization).await {
+        Ok(_) => Ok(Json(ApiResponse::success(organization))),
+        Err(e) => {
+            error!("Failed to create organization: {}", e);
+            Ok(Json(ApiResponse::error("Unable to create organization. Please try again later")))
+        }
+    }
+}
+
+pub async fn list_organizations<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+) -> Result<Json<ApiResponse<Vec<Organization>>>, StatusCode> {
+    match state.db.get_organizations_for_user(&claims.sub).await {
+        Ok(organizations) => Ok(Json(ApiResponse::success(organizations))),
+        Err(e) => {
+            error!("Database error while listing organizations: {}", e);
+            Ok(Json(ApiResponse::error("Unable to retrieve organizations. Please try again later")))
+        }
+    }
+}
+
+pub async fn add_member<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(org_id): Path<String>,
+    Json(req): Json<AddMemberRequest>,
+) -> Result<Json<ApiResponse<OrganizationMember>>, StatusCode> {
+    let result: Result<OrganizationMember, AppError> = async {
+        let role = require_manager(&state, &org_id, &claims.sub).await?;
+
+        if req.role == OrgRole::Owner {
+            return Err(AppError::Auth("An organization can only have one owner".into()));
+        }
+        if state.db.get_user(&req.user_id).await?.is_none() {
+            return Err(AppError::NotFound("User not found".into()));
+        }
+        match state.db.get_organization_role(&org_id, &req.user_id).await? {
+            Some(OrgRole::Owner) => {
+                return Err(AppError::Auth("The organization owner's role cannot be changed".into()));
+            }
+            Some(OrgRole::Admin) if role != OrgRole::Owner => {
+                return Err(AppError::Auth("Only the organization owner can change the role of admins".into()));
+            }
+            _ => {}
+        }
+
+        let member = OrganizationMember {
+            org_id: org_id.clone(),
+            user_id: req.user_id,
+            role: req.role,
+            created_at: chrono::Utc::now().timestamp(),
+        };
+        state.db.add_organization_member(&member).await?;
+        Ok(member)
+    }.await;
+
+    match result {
+        Ok(member) => Ok(Json(ApiResponse::success(member))),
+        Err(e) => {
+            error!("Failed to add organization member: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn remove_member<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((org_id, user_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result: Result<(), AppError> = async {
+        // Members may always leave; removing someone else needs owner/admin
+        // rights, and removing an admin the owner's
+        let role = if user_id != claims.sub {
+            Some(require_manager(&state, &org_id, &claims.sub).await?)
+        } else {
+            None
+        };
+
+        match state.db.get_organization_role(&org_id, &user_id).await? {
+            Some(OrgRole::Owner) => Err(AppError::Auth("The organization owner cannot be removed".into())),
+            Some(OrgRole::Admin) if role.is_some_and(|role| role != OrgRole::Owner) => {
+                Err(AppError::Auth("Only the organization owner can remove admins".into()))
+            }
+            Some(_) => state.db.remove_organization_member(&org_id, &user_id).await,
+            None => Err(AppError::NotFound("Member not found".into())),
+        }
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("Failed to remove organization member: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
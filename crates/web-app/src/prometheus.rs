@@ -0,0 +1,79 @@
+use axum::{
+    body::Body,
+    extract::{MatchedPath, State},
+    http::{header, Request},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use common::db::Database;
+use metrics_exporter_prometheus::{Matcher, PrometheusBuilder, PrometheusHandle};
+use std::{
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+use tracing::error;
+
+/// Buckets of the `*_duration_seconds` histograms, from 1 ms to 10 s
+const DURATION_BUCKETS: &[f64] = &[0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+static HANDLE: OnceLock<PrometheusHandle> = OnceLock::new();
+
+/// Installs the process-wide recorder, so metrics recorded by the mail
+/// service and the database are exported too. Later calls reuse it.
+pub fn install_recorder() -> anyhow::Result<PrometheusHandle> {
+    if let Some(handle) = HANDLE.get() {
+        return Ok(handle.clone());
+    }
+    let handle = PrometheusBuilder::new()
+        .set_buckets_for_metric(Matcher::Suffix("duration_seconds".to_string()), DURATION_BUCKETS)?
+        .install_recorder()?;
+    Ok(HANDLE.get_or_init(|| handle).clone())
+}
+
+struct MetricsState<D> {
+    db: Arc<D>,
+    handle: PrometheusHandle,
+}
+
+/// Serves `GET /metrics` without authentication, so it is meant for its own
+/// listener on METRICS_BIND_ADDR rather than the public one
+pub fn metrics_router<D: Database + 'static>(db: Arc<D>) -> anyhow::Result<Router> {
+    let state = Arc::new(MetricsState { db, handle: install_recorder()? });
+    Ok(Router::new()
+        .route("/metrics", get(render_metrics::<D>))
+        .with_state(state))
+}
+
+async fn render_metrics<D: Database>(State(state): State<Arc<MetricsState<D>>>) -> Response {
+    // Counted on scrape rather than tracked on every create and delete
+    match state.db.count_mailboxes().await {
+        Ok(count) => metrics::gauge!("mailboxes_active").set(count as f64),
+        Err(e) => error!("Failed to count mailboxes: {}", e),
+    }
+    state.handle.run_upkeep();
+
+    ([(header::CONTENT_TYPE, "text/plain; version=0.0.4")], state.handle.render()).into_response()
+}
+
+/// Records `api_requests_total` and `api_request_duration_seconds`. Requests
+/// are labelled with their route rather than their path so IDs don't create
+/// new series.
+pub async fn track_requests(req: Request<Body>, next: Next) -> Response {
+    let path = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| "unmatched".to_string(), |path| path.as_str().to_string());
+    let method = req.method().to_string();
+    let started = Instant::now();
+
+    let response = next.run(req).await;
+
+    let status = response.status().as_u16().to_string();
+    metrics::histogram!("api_request_duration_seconds", "method" => method.clone(), "path" => path.clone())
+        .record(started.elapsed().as_secs_f64());
+    metrics::counter!("api_requests_total", "method" => method, "path" => path, "status" => status).increment(1);
+
+    response
+}
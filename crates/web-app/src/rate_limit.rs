@@ -0,0 +1,198 @@
+//! Per-user limit on web API requests. Every response carries
+//! `X-RateLimit-Limit`, `X-RateLimit-Remaining` and `X-RateLimit-Reset`, the
+//! seconds until the full limit is available again.
+//!
+//! Routes open to abuse, such as logins or mailbox creation, have stricter
+//! limits of their own, see [`RateLimitConfigs`].
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use common::{
+    db::Database,
+    rate_limit::{get_or_create_rate_limiter, RateLimitRule, RateLimiterConfig},
+    AppError,
+};
+use dashmap::DashMap;
+use governor::{
+    clock::{Clock, DefaultClock},
+    middleware::StateInformationMiddleware,
+    state::{InMemoryState, NotKeyed},
+    Quota, RateLimiter,
+};
+use std::{
+    num::NonZeroU32,
+    sync::{Arc, PoisonError},
+    time::{Duration, Instant},
+};
+
+use crate::{auth::Claims, client_ip::client_ip, AppState, Config};
+
+const DEFAULT_REQUESTS_PER_MINUTE: NonZeroU32 = NonZeroU32::new(100).unwrap();
+/// How long a user's limit is used before their settings are read again
+const SETTINGS_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+type Limiter = RateLimiter<NotKeyed, InMemoryState, DefaultClock, StateInformationMiddleware>;
+
+/// Limits of the route groups applied by [`limit_route`], per user and route,
+/// or per client IP on routes used before signing in
+#[derive(Debug, Clone)]
+pub struct RateLimitConfigs {
+    pub mailbox_create: RateLimiterConfig,
+    pub email_delete: RateLimiterConfig,
+    /// Registration and logins
+    pub auth: RateLimiterConfig,
+    /// OAuth and Telegram login callbacks, limited before anything is
+    /// requested from the provider
+    pub oauth_callback: RateLimiterConfig,
+}
+
+impl RateLimitConfigs {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            mailbox_create: vec![RateLimitRule::new(config.rate_limit_mailbox_create_per_hour, 3600)].into(),
+            email_delete: vec![RateLimitRule::new(config.rate_limit_email_delete_per_hour, 3600)].into(),
+            auth: vec![RateLimitRule::new(config.rate_limit_auth_per_minute, 60)].into(),
+            oauth_callback: vec![RateLimitRule::new(config.rate_limit_oauth_callback_per_minute, 60)].into(),
+        }
+    }
+}
+
+impl Default for RateLimitConfigs {
+    fn default() -> Self {
+        Self {
+            mailbox_create: vec![RateLimitRule::new(10, 3600)].into(),
+            email_delete: vec![RateLimitRule::new(200, 3600)].into(),
+            auth: vec![RateLimitRule::new(5, 60)].into(),
+            oauth_callback: vec![RateLimitRule::new(10, 60)].into(),
+        }
+    }
+}
+
+struct UserLimiter {
+    requests_per_minute: NonZeroU32,
+    limiter: Arc<Limiter>,
+    loaded_at: Instant,
+}
+
+#[derive(Default)]
+pub(crate) struct UserRateLimits {
+    limiters: DashMap<String, UserLimiter>,
+}
+
+impl UserRateLimits {
+    /// The user's limiter, replaced when their configured limit changed
+    async fn limiter<D: Database>(&self, db: &D, user_id: &str) -> Result<(NonZeroU32, Arc<Limiter>), AppError> {
+        if let Some(entry) = self.limiters.get(user_id) {
+            if entry.loaded_at.elapsed() < SETTINGS_REFRESH_INTERVAL {
+                return Ok((entry.requests_per_minute, entry.limiter.clone()));
+            }
+        }
+
+        let requests_per_minute = db
+            .get_user_settings(user_id)
+            .await?
+            .and_then(|settings| settings.api_rate_limit_per_minute)
+            .and_then(|limit| u32::try_from(limit).ok())
+            .and_then(NonZeroU32::new)
+            .unwrap_or(DEFAULT_REQUESTS_PER_MINUTE);
+
+        let mut entry = self.limiters.entry(user_id.to_string()).or_insert_with(|| UserLimiter {
+            requests_per_minute,
+            limiter: new_limiter(requests_per_minute),
+            loaded_at: Instant::now(),
+        });
+        if entry.requests_per_minute != requests_per_minute {
+            entry.requests_per_minute = requests_per_minute;
+            entry.limiter = new_limiter(requests_per_minute);
+        }
+        entry.loaded_at = Instant::now();
+        Ok((entry.requests_per_minute, entry.limiter.clone()))
+    }
+}
+
+fn new_limiter(requests_per_minute: NonZeroU32) -> Arc<Limiter> {
+    Arc::new(RateLimiter::direct(Quota::per_minute(requests_per_minute)).with_middleware::<StateInformationMiddleware>())
+}
+
+fn ceil_secs(duration: Duration) -> u64 {
+    duration.as_secs() + u64::from(duration.subsec_nanos() > 0)
+}
+
+fn set_headers(headers: &mut HeaderMap, limit: NonZeroU32, remaining: u32, reset: Duration) {
+    headers.insert("X-RateLimit-Limit", limit.get().into());
+    headers.insert("X-RateLimit-Remaining", remaining.into());
+    headers.insert("X-RateLimit-Reset", ceil_secs(reset).into());
+}
+
+/// Runs after [`crate::auth::auth`], which provides the user
+pub(crate) async fn limit_user_requests<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    Extension(claims): Extension<Claims>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let (limit, limiter) = match state.rate_limits.limiter(&state.db, &claims.sub).await {
+        Ok(limiter) => limiter,
+        Err(e) => return e.into_response(),
+    };
+    let replenish_interval = Quota::per_minute(limit).replenish_interval();
+
+    match limiter.check() {
+        Ok(snapshot) => {
+            let remaining = snapshot.remaining_burst_capacity();
+            let mut response = next.run(request).await;
+            set_headers(response.headers_mut(), limit, remaining, replenish_interval * (limit.get() - remaining));
+            response
+        }
+        Err(not_until) => {
+            let retry_after = not_until.wait_time_from(DefaultClock::default().now());
+            let mut response = too_many_requests(retry_after);
+            set_headers(response.headers_mut(), limit, 0, retry_after + replenish_interval * (limit.get() - 1));
+            response
+        }
+    }
+}
+
+/// Applied to single routes with their group's limit from [`RateLimitConfigs`].
+/// Runs after [`crate::auth::auth`] where there is one, to count by user.
+pub(crate) async fn limit_route(
+    State(config): State<RateLimiterConfig>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let client = match request.extensions().get::<Claims>() {
+        Some(claims) => format!("user {}", claims.sub),
+        None => match client_ip(request.extensions()) {
+            Some(ip) => format!("ip {}", ip),
+            // Nothing to tell clients apart by, as for requests not served
+            // over a connection
+            None => return next.run(request).await,
+        },
+    };
+    let route = request
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or_else(|| request.uri().path().to_string(), |path| path.as_str().to_string());
+
+    let limiter = get_or_create_rate_limiter((client, route), config);
+    let refused = {
+        let mut limiter = limiter.lock().unwrap_or_else(PoisonError::into_inner);
+        (!limiter.trigger()).then(|| limiter.retry_after())
+    };
+    match refused {
+        Some(retry_after) => too_many_requests(retry_after),
+        None => next.run(request).await,
+    }
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let mut response = AppError::Mail("Rate limit exceeded".to_string()).into_response();
+    *response.status_mut() = StatusCode::TOO_MANY_REQUESTS;
+    response.headers_mut().insert(header::RETRY_AFTER, ceil_secs(retry_after).into());
+    response
+}
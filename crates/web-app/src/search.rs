@@ -0,0 +1,62 @@
+//! Full-text search over the plaintext From, To and Subject of the emails a
+//! user can access. Queries use the SQLite FTS5 syntax: `invo*` prefixes,
+//! `"exact phrase"` and `AND`/`OR`/`NOT`.
+
+use axum::{
+    extract::{Json, Query, State},
+    http::StatusCode,
+};
+use common::{db::Database, AppError, Email};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, can_access_mailbox, ApiResponse, AppState};
+
+const DEFAULT_SEARCH_PAGE_SIZE: i64 = 50;
+const MAX_SEARCH_PAGE_SIZE: i64 = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    /// Only search this mailbox
+    mailbox_id: Option<String>,
+    /// Starts at 1
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+pub async fn search_emails<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Query(query): Query<SearchQuery>,
+) -> Result<Json<ApiResponse<Vec<Email>>>, StatusCode> {
+    let result: Result<Vec<Email>, AppError> = async {
+        let q = query.q.trim();
+        if q.is_empty() {
+            return Err(AppError::Mail("Search query must not be empty".into()));
+        }
+
+        // The query only returns accessible emails, but an inaccessible
+        // mailbox is reported like on every other mailbox endpoint
+        if let Some(mailbox_id) = &query.mailbox_id {
+            let mailbox = state.db.get_mailbox(mailbox_id).await?
+                .ok_or_else(|| AppError::NotFound("Mailbox not found".into()))?;
+            if !can_access_mailbox(&state, &mailbox, &claims.sub).await? {
+                return Err(AppError::Auth("You do not have permission to access emails from this mailbox".into()));
+            }
+        }
+
+        let page = query.page.unwrap_or(1).max(1);
+        let per_page = query.per_page.unwrap_or(DEFAULT_SEARCH_PAGE_SIZE).clamp(1, MAX_SEARCH_PAGE_SIZE);
+        state.db.search_emails(&claims.sub, q, query.mailbox_id.as_deref(), page, per_page).await
+    }.await;
+
+    match result {
+        Ok(emails) => Ok(Json(ApiResponse::success(emails))),
+        Err(e) => {
+            error!("Error while searching emails: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
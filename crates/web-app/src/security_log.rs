@@ -0,0 +1,65 @@
+//! Where users log in from. Each login is recorded with its GeoIP location, and
+//! one from a country the user hasn't logged in from lately is audited.
+
+use axum::extract::{Json, Query, State};
+use common::{db::Database, AppError, LoginEvent};
+use serde::Deserialize;
+use std::sync::Arc;
+use tracing::{error, warn};
+
+use crate::{audit::{self, RequestInfo}, auth::Claims, geoip, ApiResponse, AppState};
+
+/// Logins from a country not seen within this window are reported
+const NEW_COUNTRY_WINDOW_SECS: i64 = 30 * 24 * 60 * 60;
+
+const DEFAULT_SECURITY_LOG_PAGE_SIZE: i64 = 50;
+const MAX_SECURITY_LOG_PAGE_SIZE: i64 = 100;
+
+/// Records a login of the user. Like audit entries, failures are logged rather
+/// than failing the login.
+pub(crate) async fn record_login<D: Database>(db: &D, request_info: &RequestInfo, user_id: &str) {
+    let location = request_info.ip().map(geoip::lookup).unwrap_or_default();
+    let now = chrono::Utc::now().timestamp();
+
+    if let Some(country_code) = &location.country_code {
+        match db.get_login_countries(user_id, now - NEW_COUNTRY_WINDOW_SECS).await {
+            // A first located login has nothing to be compared with
+            Ok(countries) if !countries.is_empty() && !countries.contains(country_code) => {
+                warn!(target: "security", "User {} logged in from a new country: {}", user_id, country_code);
+                let metadata = serde_json::json!({ "country_code": country_code, "city": location.city });
+                request_info.audit(db, user_id, audit::NEW_LOGIN_COUNTRY, None, Some(metadata)).await;
+            }
+            Ok(_) => {}
+            Err(e) => error!("Failed to get the login countries of user {}: {}", user_id, e),
+        }
+    }
+
+    let event = LoginEvent {
+        user_id: user_id.to_string(),
+        ip: request_info.ip().map(|ip| ip.to_string()),
+        country_code: location.country_code,
+        city: location.city,
+        logged_in_at: now,
+    };
+    if let Err(e) = db.record_login_event(&event).await {
+        error!("Failed to record login of user {}: {}", user_id, e);
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SecurityLogQuery {
+    /// Starts at 1
+    page: Option<i64>,
+    per_page: Option<i64>,
+}
+
+pub async fn list_handler<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Query(query): Query<SecurityLogQuery>,
+) -> Result<Json<ApiResponse<Vec<LoginEvent>>>, AppError> {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_SECURITY_LOG_PAGE_SIZE).clamp(1, MAX_SECURITY_LOG_PAGE_SIZE);
+    let events = state.db.get_login_events(&claims.sub, page, per_page).await?;
+    Ok(Json(ApiResponse::success(events)))
+}
@@ -0,0 +1,276 @@
+use axum::{
+    extract::{Json, Path, State},
+    http::StatusCode,
+};
+use common::{db::Database, generate_random_id, outbound, AppError, IdCharset, SignatureAlgorithm, Webhook};
+use mail_service::webhooks::{PayloadContext, WebhookSender};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tracing::error;
+
+use crate::{auth::Claims, get_managed_mailbox, ApiResponse, AppState};
+
+const MAX_WEBHOOKS_PER_MAILBOX: usize = 10;
+const WEBHOOK_SECRET_LENGTH: usize = 32;
+const MAX_PAYLOAD_TEMPLATE_LENGTH: usize = 10_000;
+
+#[derive(Debug, Deserialize)]
+pub struct CreateWebhookRequest {
+    url: String,
+    /// Defaults to every event
+    #[serde(default)]
+    events: Option<Vec<String>>,
+    #[serde(default)]
+    signature_algorithm: SignatureAlgorithm,
+    /// Tera template of the payload, rendered with `event`, `email_id`,
+    /// `mailbox_id`, `received_at`, `sender_ip`, `subject` and `from`
+    #[serde(default)]
+    payload_template: Option<String>,
+}
+
+/// The algorithm is kept when left out
+#[derive(Debug, Deserialize)]
+pub struct RotateWebhookSecretRequest {
+    #[serde(default)]
+    signature_algorithm: Option<SignatureAlgorithm>,
+}
+
+/// The only responses including the secret
+#[derive(Debug, Serialize)]
+pub struct CreateWebhookResponse {
+    #[serde(flatten)]
+    webhook: Webhook,
+    secret: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TemplatePreviewResponse {
+    payload: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WebhookTestResponse {
+    signature_algorithm: SignatureAlgorithm,
+    /// HTTP status of the test delivery, `None` if it got no response
+    status: Option<i64>,
+}
+
+/// Webhooks are called from the server, so their host must be a public address
+pub(crate) async fn validate_url(url: &str) -> Result<String, AppError> {
+    let url = Url::parse(url.trim()).map_err(|_| AppError::Mail("Invalid webhook URL".into()))?;
+    outbound::check_url(&url).await?;
+    Ok(url.to_string())
+}
+
+/// Renders the template with sample data, so that templates using unknown
+/// variables are refused along with those that don't compile
+fn validate_payload_template(mailbox_id: &str, template: Option<String>) -> Result<Option<String>, AppError> {
+    let Some(template) = template.filter(|template| !template.trim().is_empty()) else {
+        return Ok(None);
+    };
+    if template.len() > MAX_PAYLOAD_TEMPLATE_LENGTH {
+        return Err(AppError::Mail(format!(
+            "Payload template must be at most {} bytes",
+            MAX_PAYLOAD_TEMPLATE_LENGTH
+        )));
+    }
+    PayloadContext::sample(mailbox_id).render(Some(&template))?;
+    Ok(Some(template))
+}
+
+fn validate_events(events: Option<Vec<String>>) -> Result<Vec<String>, AppError> {
+    let Some(events) = events else {
+        return Ok(Webhook::EVENTS.iter().map(|event| event.to_string()).collect());
+    };
+    if events.is_empty() {
+        return Err(AppError::Mail("A webhook needs at least one event".into()));
+    }
+    if let Some(unknown) = events.iter().find(|event| !Webhook::EVENTS.contains(&event.as_str())) {
+        return Err(AppError::Mail(format!("Unknown webhook event: {}", unknown)));
+    }
+    Ok(events)
+}
+
+pub async fn list_webhooks<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+) -> Result<Json<ApiResponse<Vec<Webhook>>>, StatusCode> {
+    let result: Result<Vec<Webhook>, AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "webhooks").await?;
+        state.db.get_mailbox_webhooks(&mailbox_id).await
+    }.await;
+
+    match result {
+        Ok(webhooks) => Ok(Json(ApiResponse::success(webhooks))),
+        Err(e) => {
+            error!("Failed to list mailbox webhooks: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn create_webhook<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path(mailbox_id): Path<String>,
+    Json(req): Json<CreateWebhookRequest>,
+) -> Result<Json<ApiResponse<CreateWebhookResponse>>, StatusCode> {
+    let result: Result<CreateWebhookResponse, AppError> = async {
+        let url = validate_url(&req.url).await?;
+        let events = validate_events(req.events)?;
+        let payload_template = validate_payload_template(&mailbox_id, req.payload_template)?;
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "webhooks").await?;
+
+        if state.db.get_mailbox_webhooks(&mailbox_id).await?.len() >= MAX_WEBHOOKS_PER_MAILBOX {
+            return Err(AppError::Mail(format!(
+                "Mailbox has reached its limit of {} webhooks",
+                MAX_WEBHOOKS_PER_MAILBOX
+            )));
+        }
+
+        let webhook = Webhook {
+            id: uuid::Uuid::new_v4().to_string(),
+            mailbox_id: mailbox_id.clone(),
+            url,
+            secret: generate_random_id(WEBHOOK_SECRET_LENGTH, IdCharset::UrlSafe),
+            events,
+            signature_algorithm: req.signature_algorithm,
+            payload_template,
+            created_at: chrono::Utc::now().timestamp(),
+            last_triggered_at: None,
+            last_status: None,
+        };
+        state.db.create_webhook(&webhook).await?;
+        Ok(CreateWebhookResponse {
+            secret: webhook.secret.clone(),
+            webhook,
+        })
+    }.await;
+
+    match result {
+        Ok(webhook) => Ok(Json(ApiResponse::success(webhook))),
+        Err(e) => {
+            error!("Failed to create mailbox webhook: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// The webhook of the mailbox, which the user must manage
+async fn get_managed_webhook<D: Database>(
+    state: &Arc<AppState<D>>,
+    mailbox_id: &str,
+    webhook_id: &str,
+    user_id: &str,
+) -> Result<Webhook, AppError> {
+    get_managed_mailbox(state, mailbox_id, user_id, "webhooks").await?;
+    state.db.get_mailbox_webhooks(mailbox_id).await?
+        .into_iter()
+        .find(|webhook| webhook.id == webhook_id)
+        .ok_or_else(|| AppError::NotFound("Webhook not found".into()))
+}
+
+/// Replaces the secret, and the signature algorithm when one is given
+pub async fn rotate_webhook_secret<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, webhook_id)): Path<(String, String)>,
+    Json(req): Json<RotateWebhookSecretRequest>,
+) -> Result<Json<ApiResponse<CreateWebhookResponse>>, StatusCode> {
+    let result: Result<CreateWebhookResponse, AppError> = async {
+        let mut webhook = get_managed_webhook(&state, &mailbox_id, &webhook_id, &claims.sub).await?;
+        webhook.secret = generate_random_id(WEBHOOK_SECRET_LENGTH, IdCharset::UrlSafe);
+        if let Some(signature_algorithm) = req.signature_algorithm {
+            webhook.signature_algorithm = signature_algorithm;
+        }
+
+        if !state.db.rotate_webhook_secret(&mailbox_id, &webhook_id, &webhook.secret, webhook.signature_algorithm).await? {
+            return Err(AppError::NotFound("Webhook not found".into()));
+        }
+        Ok(CreateWebhookResponse {
+            secret: webhook.secret.clone(),
+            webhook,
+        })
+    }.await;
+
+    match result {
+        Ok(webhook) => Ok(Json(ApiResponse::success(webhook))),
+        Err(e) => {
+            error!("Failed to rotate webhook secret: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Renders the webhook's payload with sample data, without delivering it
+pub async fn preview_payload_template<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<TemplatePreviewResponse>>, StatusCode> {
+    let result: Result<TemplatePreviewResponse, AppError> = async {
+        let webhook = get_managed_webhook(&state, &mailbox_id, &webhook_id, &claims.sub).await?;
+        let payload = PayloadContext::sample(&mailbox_id).render(webhook.payload_template.as_deref())?;
+        Ok(TemplatePreviewResponse {
+            payload: String::from_utf8_lossy(&payload).into_owned(),
+        })
+    }.await;
+
+    match result {
+        Ok(preview) => Ok(Json(ApiResponse::success(preview))),
+        Err(e) => {
+            error!("Failed to preview webhook payload: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+/// Sends a signed `webhook.test` payload once, reporting the response status
+/// and the algorithm it was signed with
+pub async fn test_webhook<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<WebhookTestResponse>>, StatusCode> {
+    let result: Result<WebhookTestResponse, AppError> = async {
+        let webhook = get_managed_webhook(&state, &mailbox_id, &webhook_id, &claims.sub).await?;
+        let status = WebhookSender::default().send_test(&webhook).await;
+        Ok(WebhookTestResponse {
+            signature_algorithm: webhook.signature_algorithm,
+            status,
+        })
+    }.await;
+
+    match result {
+        Ok(response) => Ok(Json(ApiResponse::success(response))),
+        Err(e) => {
+            error!("Failed to test webhook: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
+
+pub async fn delete_webhook<D: Database>(
+    State(state): State<Arc<AppState<D>>>,
+    claims: axum::extract::Extension<Claims>,
+    Path((mailbox_id, webhook_id)): Path<(String, String)>,
+) -> Result<Json<ApiResponse<()>>, StatusCode> {
+    let result: Result<(), AppError> = async {
+        get_managed_mailbox(&state, &mailbox_id, &claims.sub, "webhooks").await?;
+
+        if !state.db.delete_webhook(&mailbox_id, &webhook_id).await? {
+            return Err(AppError::NotFound("Webhook not found".into()));
+        }
+        Ok(())
+    }.await;
+
+    match result {
+        Ok(_) => Ok(Json(ApiResponse::success(()))),
+        Err(e) => {
+            error!("Failed to delete mailbox webhook: {}", e);
+            Ok(Json(ApiResponse::error(e.to_string())))
+        }
+    }
+}
@@ -1,38 +1,59 @@
+#[path = "common/mod.rs"]
+mod test_helpers;
+
 use axum::{
+    extract::ConnectInfo,
     routing::Router,
     response::Response,
-    http::{Request, StatusCode},
+    http::{header, Request, StatusCode},
     body::Body,
 };
-use common::{db::Database, db::SqliteDatabase, Mailbox, User, Email};
+use common::{db::Database, db::SqliteDatabase, rate_limit::{RateLimitRule, RateLimiterConfig}, shutdown::CancellationToken, Mailbox, MailboxAlias, MailboxKey, MailboxWithStats, KeyType, User, UserSettings, Email, ForwardingKind, ForwardingRule, AuditEntry, LoginEvent};
+#[cfg(feature = "admin-api")]
+use common::{greylist::{Greylist, GreylistKey}, MailboxSummary};
 use serde_json::json;
-use std::{sync::Arc, env, path::PathBuf};
+use std::{sync::Arc, env, net::SocketAddr, path::PathBuf};
 use tower::Service;
-use web_app::{create_app, ApiResponse, Config, init_config};
+use web_app::{create_app, create_app_with_rate_limits, create_app_with_shutdown, create_app_with_smtp_status, ApiResponse, Config, RateLimitConfigs};
+use test_helpers::{create_test_mailbox, init_test_config, test_config, TEST_PUBLIC_KEY};
 use http_body_util::BodyExt;
 use tracing::{info, error};
-use once_cell::sync::OnceCell;
 
-const TEST_PUBLIC_KEY: &str = "age1creym8a9ncefdvplrqrfy7wf8k3fw2l7w5z7nwp03jgfyhc56gcqgq27cg";
-#[allow(dead_code)]
 const TEST_SECRET_KEY: &str = "AGE-SECRET-KEY-10Q6FGH2JQD9VS0ZM50KV7XVC8SAC50MM5DDH9DKWQR3RCSJKYM6QAX66U8";
+const TEST_SSH_ED25519_KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIEbB7l8lHUa1qtPjqSkYXYTtuIPvQSvPCVu5N4hKeSsc";
+const TEST_ADMIN_SECRET: &str = "test-admin-secret";
+const TEST_ADMIN_TOKEN: &str = "test-admin-token";
 const TEST_USERNAME: &str = "test-user";
-const TEST_PASSWORD: &str = "test-password";
-
-static TEST_CONFIG: OnceCell<()> = OnceCell::new();
-
-fn init_test_config() {
-    TEST_CONFIG.get_or_init(|| {
-        init_config(Config {
-            database_path: ":memory:".to_string(),
-            bind_addr: "127.0.0.1:3000".to_string(),
-            web_app_url: "http://localhost:3000".to_string(),
-            supported_domains: vec!["test.example.com".to_string()],
-        });
-    });
+const TEST_PASSWORD: &str = "Test-password1";
+
+/// The connection of requests made through a reverse proxy the test config trusts
+fn proxy_peer() -> ConnectInfo<SocketAddr> {
+    ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 40000)))
+}
+
+fn api_test_config() -> Config {
+    Config {
+        enable_search_index: true,
+        enable_response_compression: true,
+        max_aliases_per_mailbox: 3,
+        admin_secret: Some(TEST_ADMIN_SECRET.to_string()),
+        admin_token: Some(TEST_ADMIN_TOKEN.to_string()),
+        // Tests make more requests than the production limits allow; test_route_rate_limits
+        // checks the limits with its own
+        rate_limit_mailbox_create_per_hour: 1000,
+        rate_limit_email_delete_per_hour: 1000,
+        rate_limit_auth_per_minute: 1000,
+        rate_limit_oauth_callback_per_minute: 1000,
+        trusted_proxies: vec!["127.0.0.1".to_string(), "10.0.0.0/8".to_string()],
+        ..test_config()
+    }
 }
 
 async fn setup_test_app() -> Router {
+    setup_test_app_with_db().await.0
+}
+
+async fn setup_test_app_with_db() -> (Router, Arc<SqliteDatabase>) {
     info!("Setting up test database");
     
     // Set up the path to migrations
@@ -63,9 +84,9 @@ async fn setup_test_app() -> Router {
     info!("Database setup complete");
     
     // Initialize config for tests
-    init_test_config();
+    init_test_config(api_test_config());
     
-    create_app(db)
+    (create_app(db.clone()), db)
 }
 
 // Helper function to read response body
@@ -103,6 +124,7 @@ fn setup() {
 #[derive(serde::Deserialize)]
 struct AuthResponse {
     token: String,
+    refresh_token: String,
     user: User,
 }
 
@@ -180,6 +202,94 @@ async fn test_create_mailbox() {
     assert!(!mailbox.alias.is_empty(), "Alias should not be empty");
 }
 
+#[tokio::test]
+async fn test_create_mailbox_public_key_validation() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    // Malformed keys are rejected up front
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/mailboxes")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(
+                    json!({
+                        "name": "Bad Key",
+                        "expires_in_seconds": 3600,
+                        "public_key": "age1notavalidkey"
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let response: ApiResponse<Mailbox> = read_body(response).await;
+    assert!(!response.success);
+    assert_eq!(response.error.as_deref(), Some("Invalid age public key format"));
+
+    // Padded, uppercase keys are stored in canonical form
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/mailboxes")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(
+                    json!({
+                        "name": "Uppercase Key",
+                        "expires_in_seconds": 3600,
+                        "public_key": format!("  {}\n", TEST_PUBLIC_KEY.to_uppercase())
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let response: ApiResponse<Mailbox> = read_body(response).await;
+    assert!(response.success, "Expected normalized key to be accepted");
+    let mailbox = response.data.unwrap();
+    assert_eq!(mailbox.public_key, TEST_PUBLIC_KEY);
+    assert_eq!(mailbox.key_type, KeyType::AgeX25519);
+
+    // SSH keys are detected by prefix and stored without their comment
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/mailboxes")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(
+                    json!({
+                        "name": "SSH Key",
+                        "expires_in_seconds": 3600,
+                        "public_key": format!("{} test@example", TEST_SSH_ED25519_KEY)
+                    })
+                    .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let response: ApiResponse<Mailbox> = read_body(response).await;
+    assert!(response.success, "Expected SSH key to be accepted");
+    let mailbox = response.data.unwrap();
+    assert_eq!(mailbox.public_key, TEST_SSH_ED25519_KEY);
+    assert_eq!(mailbox.key_type, KeyType::SshEd25519);
+}
+
 #[tokio::test]
 async fn test_get_mailbox() {
     setup();
@@ -294,6 +404,24 @@ async fn test_update_mailbox() {
     let updated_mailbox = update_response.data.unwrap();
     assert_eq!(updated_mailbox.name, "Updated Test Mailbox");
     assert_eq!(updated_mailbox.mail_expires_in.unwrap(), 3600); // 1 hour expiration
+
+    // The changes are persisted, not only echoed back
+    let get_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let get_response: ApiResponse<MailboxWithStats> = read_body(get_response).await;
+    let stored_mailbox = get_response.data.unwrap().mailbox;
+    assert_eq!(stored_mailbox.name, "Updated Test Mailbox");
+    assert_eq!(stored_mailbox.mail_expires_in, Some(3600));
 }
 
 #[tokio::test]
@@ -463,6 +591,65 @@ async fn test_login() {
     assert_eq!(auth_data.user.username, TEST_USERNAME);
 }
 
+#[tokio::test]
+async fn test_password_strength() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let mut post = |uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        app_service.call(request.body(Body::from(body.to_string())).unwrap())
+    };
+
+    let response = post("/api/auth/check-password-strength", None, json!({ "password": "short" })).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let strength = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(strength, json!({ "score": 1, "failed_checks": ["too_short", "no_uppercase", "no_digit"] }));
+    let response = post("/api/auth/check-password-strength", None, json!({ "password": "Password1" })).await.unwrap();
+    let strength = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(strength, json!({ "score": 0, "failed_checks": ["common"] }));
+    let response = post("/api/auth/check-password-strength", None, json!({ "password": TEST_PASSWORD })).await.unwrap();
+    let strength = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(strength, json!({ "score": 4, "failed_checks": [] }));
+
+    // Weak passwords are refused before the account is created
+    for (password, error) in [
+        ("Sh0rt", "Password must be at least 8 characters long."),
+        ("test-password1", "Password must contain at least one uppercase letter."),
+        ("TEST-PASSWORD1", "Password must contain at least one lowercase letter."),
+        ("Test-password", "Password must contain at least one digit."),
+        ("Password1", "Password is too common. Please choose a less predictable one."),
+    ] {
+        let body = json!({ "username": TEST_USERNAME, "password": password });
+        let response = post("/api/auth/register", None, body).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response: ApiResponse<AuthResponse> = read_body(response).await;
+        assert_eq!(response.error.as_deref(), Some(error));
+    }
+    let body = json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD });
+    let response = post("/api/auth/register", None, body).await.unwrap();
+    let auth = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+
+    // Setting a password on an account without one has the same requirements
+    sqlx::query("UPDATE user_credentials SET password_hash = NULL WHERE user_id = ?")
+        .bind(&auth.user.id)
+        .execute(db.pool())
+        .await
+        .unwrap();
+    let response = post("/api/auth/set-password", Some(&auth.token), json!({ "new_password": "letmein" })).await.unwrap();
+    let response: ApiResponse<()> = read_body(response).await;
+    assert_eq!(response.error.as_deref(), Some("Password must be at least 8 characters long."));
+    let response = post("/api/auth/set-password", Some(&auth.token), json!({ "new_password": "Letmein-again2" })).await.unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+}
+
 #[tokio::test]
 async fn test_auth_check() {
     setup();
@@ -504,4 +691,4256 @@ async fn test_auth_check() {
         .unwrap();
 
     assert_eq!(auth_check_no_token.status(), StatusCode::UNAUTHORIZED);
-} 
\ No newline at end of file
+} 
+
+#[tokio::test]
+async fn test_change_password_revokes_tokens() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, first_token) = create_test_user_with_auth(&mut app_service).await;
+
+    let get_request = |uri: &str, token: &str| {
+        Request::builder()
+            .method("GET")
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let change_password_request = |token: &str, current_password: &str, new_password: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/auth/change-password")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(
+                json!({
+                    "current_password": current_password,
+                    "new_password": new_password
+                })
+                .to_string(),
+            ))
+            .unwrap()
+    };
+    let login_request = |password: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/auth/login")
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({ "username": TEST_USERNAME, "password": password }).to_string()))
+            .unwrap()
+    };
+
+    let response = app_service.call(login_request(TEST_PASSWORD)).await.unwrap();
+    let second_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+
+    let response = app_service
+        .call(change_password_request(&first_token, "wrong-password", "New-password2"))
+        .await
+        .unwrap();
+    let result: ApiResponse<AuthResponse> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Incorrect password. Please try again."));
+
+    // Held to the registration requirements
+    let response = app_service
+        .call(change_password_request(&first_token, TEST_PASSWORD, "weak"))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let result: ApiResponse<AuthResponse> = read_body(response).await;
+    assert!(!result.success);
+
+    let response = app_service
+        .call(change_password_request(&first_token, TEST_PASSWORD, "New-password2"))
+        .await
+        .unwrap();
+    let result: ApiResponse<AuthResponse> = read_body(response).await;
+    assert!(result.success, "Failed to change password: {:?}", result.error);
+    let new_token = result.data.unwrap().token;
+
+    // Every session started before the change is rejected
+    for token in [&first_token, &second_token] {
+        for uri in ["/api/auth/me", "/api/mailboxes"] {
+            let response = app_service.call(get_request(uri, token)).await.unwrap();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} accepted an old token", uri);
+        }
+    }
+    let response = app_service.call(get_request("/api/auth/me", &new_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app_service.call(login_request(TEST_PASSWORD)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(login_request("New-password2")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A second change within the same second still revokes the previous token
+    let response = app_service
+        .call(change_password_request(&new_token, "New-password2", "Newer-password3"))
+        .await
+        .unwrap();
+    let latest_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+    let response = app_service.call(get_request("/api/auth/me", &new_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(get_request("/api/auth/me", &latest_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_password_change() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let post_request = |uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+    let password_change_request = |current_password: &str, new_password: &str| {
+        post_request(
+            "/api/auth/password-change",
+            Some(&token),
+            json!({ "current_password": current_password, "new_password": new_password }),
+        )
+    };
+    let login_request = |password: &str| {
+        post_request("/api/auth/login", None, json!({ "username": TEST_USERNAME, "password": password }))
+    };
+
+    let response = app_service.call(login_request(TEST_PASSWORD)).await.unwrap();
+    let refresh_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().refresh_token;
+
+    let response = app_service.call(password_change_request("Wrong-password1", "Changed-password2")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Incorrect password. Please try again."));
+
+    let response = app_service.call(password_change_request(TEST_PASSWORD, "weak")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(password_change_request(TEST_PASSWORD, TEST_PASSWORD)).await.unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("The new password must be different from the current one."));
+
+    // The same checks apply on the main route, which this one is an alias of
+    let response = app_service
+        .call(post_request(
+            "/api/auth/change-password",
+            Some(&token),
+            json!({ "current_password": TEST_PASSWORD, "new_password": TEST_PASSWORD }),
+        ))
+        .await
+        .unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("The new password must be different from the current one."));
+
+    let response = app_service.call(password_change_request(TEST_PASSWORD, "Changed-password2")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let result: ApiResponse<AuthResponse> = read_body(response).await;
+    assert!(!result.data.unwrap().token.is_empty());
+
+    // Only the new password signs in, and earlier sessions are signed out
+    let response = app_service.call(login_request(TEST_PASSWORD)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(login_request("Changed-password2")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app_service
+        .call(post_request("/api/auth/refresh", None, json!({ "refresh_token": refresh_token })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let entries = db.get_audit_log(&user_id, 1, 50).await.unwrap();
+    assert_eq!(entries.iter().filter(|entry| entry.action == "change_password").count(), 1);
+}
+
+#[tokio::test]
+async fn test_revoke_sessions() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (_, first_token) = create_test_user_with_auth(&mut app_service).await;
+
+    let request = |method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+    let login_request = || {
+        request("POST", "/api/auth/login", None, json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD }))
+    };
+    let me_status = |token: &str| request("GET", "/api/auth/me", Some(token), json!({}));
+
+    // Logging out revokes the token it is sent with, not the others
+    let response = app_service.call(login_request()).await.unwrap();
+    let second = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    let response = app_service
+        .call(request("POST", "/api/auth/logout", Some(&second.token), json!({ "refresh_token": second.refresh_token })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app_service.call(me_status(&second.token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(me_status(&first_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Revoking every session signs out all of them, the caller's included
+    let response = app_service.call(login_request()).await.unwrap();
+    let third = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    let response = app_service.call(request("DELETE", "/api/auth/sessions", Some(&third.token), json!({}))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = read_body(response).await;
+    assert_eq!(body["data"]["revoked_count"], 2);
+    for token in [&first_token, &third.token] {
+        let response = app_service.call(me_status(token)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = app_service
+        .call(request("POST", "/api/auth/refresh", None, json!({ "refresh_token": third.refresh_token })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Signing in again starts a new session
+    let response = app_service.call(login_request()).await.unwrap();
+    let fourth = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    let response = app_service.call(me_status(&fourth.token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Nothing is kept past the tokens' expiry
+    assert_eq!(db.cleanup_expired_tokens(0).await.unwrap(), 0);
+    assert_eq!(db.cleanup_expired_tokens(i64::MAX).await.unwrap(), 4 + 3);
+}
+
+#[tokio::test]
+async fn test_change_username() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+    db.create_user("taken-name", common::AuthType::GitHub).await.unwrap();
+
+    let change_username_request = |new_username: &str| {
+        Request::builder()
+            .method("PATCH")
+            .uri("/api/auth/me/username")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({ "new_username": new_username }).to_string()))
+            .unwrap()
+    };
+
+    for invalid in ["ab", "has space", "dots.not.allowed", &"a".repeat(33), TEST_USERNAME] {
+        let response = app_service.call(change_username_request(invalid)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED, "{} was accepted", invalid);
+    }
+    let response = app_service.call(change_username_request("taken-name")).await.unwrap();
+    let result: ApiResponse<User> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Username is already taken. Please choose a different username."));
+
+    let response = app_service.call(change_username_request("renamed_user-1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let user = read_body::<ApiResponse<User>>(response).await.data.unwrap();
+    assert_eq!(user.id, user_id);
+    assert_eq!(user.username, "renamed_user-1");
+    assert!(user.username_changed_at.is_some());
+
+    // The new username signs in, the old one no longer does
+    let login_request = |username: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/auth/login")
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({ "username": username, "password": TEST_PASSWORD }).to_string()))
+            .unwrap()
+    };
+    let response = app_service.call(login_request("renamed_user-1")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app_service.call(login_request(TEST_USERNAME)).await.unwrap();
+    assert_ne!(response.status(), StatusCode::OK);
+
+    // Once per day
+    let response = app_service.call(change_username_request("renamed-again")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    sqlx::query("UPDATE users SET username_changed_at = username_changed_at - 86400 WHERE id = ?")
+        .bind(&user_id)
+        .execute(db.pool())
+        .await
+        .unwrap();
+    let response = app_service.call(change_username_request("renamed-again")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let entries = db.get_audit_log(&user_id, 1, 50).await.unwrap();
+    let changes: Vec<_> = entries.iter().filter(|entry| entry.action == "change_username").collect();
+    assert_eq!(changes.len(), 2);
+    assert!(changes.iter().any(|entry| entry.metadata == Some(json!({
+        "old_username": TEST_USERNAME,
+        "new_username": "renamed_user-1",
+    }))));
+}
+#[tokio::test]
+async fn test_api_key_usage() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    // Create an API key
+    let create_key_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let create_key_result: ApiResponse<serde_json::Value> = read_body(create_key_response).await;
+    let api_key = create_key_result.data.unwrap();
+    let key_id = api_key["id"].as_str().unwrap().to_string();
+    let key = api_key["key"].as_str().unwrap().to_string();
+
+    // Call the public API with it (the mailbox does not exist, which still counts as usage)
+    for _ in 0..3 {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri("/api/v1/mailboxes/missing/emails")
+                    .header("Authorization", format!("Bearer {}", key))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    // Usage is recorded in the background
+    tokio::time::sleep(std::time::Duration::from_millis(200)).await;
+
+    let usage_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/api-keys/{}/usage?granularity=1d&period=7d", key_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let usage: ApiResponse<Vec<serde_json::Value>> = read_body(usage_response).await;
+    assert!(usage.success);
+    let buckets = usage.data.unwrap();
+    assert_eq!(buckets.len(), 7);
+    let total: i64 = buckets.iter().map(|b| b["requests"].as_i64().unwrap()).sum();
+    assert_eq!(total, 3);
+
+    let endpoints_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/api-keys/{}/usage/endpoints", key_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let endpoints: ApiResponse<Vec<serde_json::Value>> = read_body(endpoints_response).await;
+    let endpoints = endpoints.data.unwrap();
+    assert_eq!(endpoints.len(), 1);
+    assert_eq!(endpoints[0]["method"], "GET");
+    assert_eq!(endpoints[0]["requests"], 3);
+}
+
+#[tokio::test]
+async fn test_import_eml() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Import Mailbox").await;
+
+    let boundary = "eml-import-boundary";
+    let email_content = "From: sender@example.com\r\nTo: test@test.com\r\nSubject: Imported\r\n\r\nHello";
+    let body = format!(
+        "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"good.eml\"\r\nContent-Type: message/rfc822\r\n\r\n{good}\r\n\
+         --{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"empty.eml\"\r\nContent-Type: message/rfc822\r\n\r\n\r\n\
+         --{b}--\r\n",
+        b = boundary,
+        good = email_content,
+    );
+
+    let import_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+                .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let import_result: ApiResponse<serde_json::Value> = read_body(import_response).await;
+    assert!(import_result.success);
+    let data = import_result.data.unwrap();
+    assert_eq!(data["imported"], 1);
+    assert_eq!(data["errors"].as_array().unwrap().len(), 1);
+    assert!(data["errors"][0].as_str().unwrap().starts_with("empty.eml"));
+
+    let get_emails_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let emails: ApiResponse<Vec<Email>> = read_body(get_emails_response).await;
+    let emails = emails.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    let decrypted = common::security::decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY).unwrap();
+    assert_eq!(decrypted, email_content.as_bytes());
+
+    // Imported emails are searchable by keyword token
+    for (keyword, expected) in [("imported", 1), ("missing", 0)] {
+        let search_token = common::search::search_token(TEST_PUBLIC_KEY, keyword);
+        let search_response = app_service
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri(format!("/api/mailboxes/{}/emails?token={}", mailbox.id, search_token))
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let found: ApiResponse<Vec<Email>> = read_body(search_response).await;
+        assert_eq!(found.data.unwrap().len(), expected, "Unexpected results for {}", keyword);
+    }
+
+    // Each file is limited to MAX_EMAIL_SIZE, while an upload may carry several of them
+    let import = |sizes: &[usize]| {
+        let files: String = sizes
+            .iter()
+            .map(|size| {
+                format!(
+                    "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"large.eml\"\r\n\r\nSubject: Large\r\n\r\n{body}\r\n",
+                    b = boundary,
+                    body = "x".repeat(*size),
+                )
+            })
+            .collect();
+        Request::builder()
+            .method("POST")
+            .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+            .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(format!("{}--{}--\r\n", files, boundary)))
+            .unwrap()
+    };
+    let import_result: ApiResponse<serde_json::Value> =
+        read_body(app_service.call(import(&[6 * 1024 * 1024, 6 * 1024 * 1024])).await.unwrap()).await;
+    let data = import_result.data.unwrap();
+    assert_eq!(data["imported"], 2);
+    assert_eq!(data["errors"].as_array().unwrap().len(), 0);
+
+    let import_result: ApiResponse<serde_json::Value> =
+        read_body(app_service.call(import(&[11 * 1024 * 1024])).await.unwrap()).await;
+    let data = import_result.data.unwrap();
+    assert_eq!(data["imported"], 0);
+    assert!(data["errors"][0].as_str().unwrap().contains("exceeds"));
+}
+
+#[tokio::test]
+async fn test_import_eml_storage_quota() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Quota Mailbox").await;
+    db.update_user_settings(&UserSettings {
+        user_id,
+        email_notifications: true,
+        auto_delete_expired: true,
+        default_mailbox_expiry: None,
+        email_cleanup_policy: None,
+        email_count_limit: Some(1),
+        total_storage_bytes_limit: None,
+        api_rate_limit_per_minute: None,
+        max_mailboxes: None,
+    })
+    .await
+    .unwrap();
+
+    let boundary = "eml-quota-boundary";
+    let import = |names: &[&str]| {
+        let files: String = names
+            .iter()
+            .map(|name| {
+                format!(
+                    "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{name}.eml\"\r\n\r\nSubject: {name}\r\n\r\nHello\r\n",
+                    b = boundary,
+                    name = name,
+                )
+            })
+            .collect();
+        Request::builder()
+            .method("POST")
+            .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+            .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(format!("{}--{}--\r\n", files, boundary)))
+            .unwrap()
+    };
+
+    // The first file fills the quota, the second would exceed it
+    let import_result: ApiResponse<serde_json::Value> =
+        read_body(app_service.call(import(&["first", "second"])).await.unwrap()).await;
+    let data = import_result.data.unwrap();
+    assert_eq!(data["imported"], 1);
+    assert_eq!(data["errors"], json!(["second.eml: storage quota exceeded"]));
+
+    // At quota nothing more is imported
+    let import_result: ApiResponse<serde_json::Value> =
+        read_body(app_service.call(import(&["third"])).await.unwrap()).await;
+    let data = import_result.data.unwrap();
+    assert_eq!(data["imported"], 0);
+    assert_eq!(data["errors"], json!(["third.eml: storage quota exceeded"]));
+    assert_eq!(db.get_user_email_stats(&mailbox.owner_id).await.unwrap().email_count, 1);
+}
+
+#[tokio::test]
+async fn test_revoke_all_api_keys() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut keys = Vec::new();
+    for _ in 0..2 {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/api-keys")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        keys.push(result.data.unwrap()["key"].as_str().unwrap().to_string());
+    }
+
+    let revoke_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys/revoke-all")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let revoke_result: ApiResponse<serde_json::Value> = read_body(revoke_response).await;
+    assert!(revoke_result.success);
+    assert_eq!(revoke_result.data.unwrap()["revoked"], 2);
+
+    // Revoked keys are rejected by the public API
+    for key in keys {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri("/api/v1/mailboxes/missing/emails")
+                    .header("Authorization", format!("Bearer {}", key))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_revoke_user_api_keys() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let key = result.data.unwrap()["key"].as_str().unwrap().to_string();
+
+    let list_emails = |key: &str| {
+        Request::builder()
+            .method("GET")
+            .uri("/api/v1/mailboxes/missing/emails")
+            .header("Authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app_service.call(list_emails(&key)).await.unwrap();
+    assert_ne!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let revoke_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/admin/users/{}/revoke-all-api-keys", user_id))
+                .header("X-Admin-Secret", TEST_ADMIN_SECRET)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let revoke_result: ApiResponse<serde_json::Value> = read_body(revoke_response).await;
+    assert!(revoke_result.success);
+    assert_eq!(revoke_result.data.unwrap()["revoked"], 1);
+
+    // Revoked keys are rejected by ApiClaims
+    let response = app_service.call(list_emails(&key)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_update_organization() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/orgs")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "name": "Team" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let org_id = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap()["id"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let mut update = |org_id: &str, secret: &str, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method("PATCH")
+                .uri(format!("/api/admin/orgs/{}", org_id))
+                .header("Content-Type", "application/json")
+                .header("X-Admin-Secret", secret)
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    let response = update(&org_id, "wrong-secret", json!({ "max_mailboxes": 5 })).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = update(&org_id, TEST_ADMIN_SECRET, json!({ "max_mailboxes": 5 })).await.unwrap();
+    let org: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(org.data.unwrap()["max_mailboxes"], 5);
+
+    let response = update(&org_id, TEST_ADMIN_SECRET, json!({ "max_mailboxes": -1 })).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mailbox limit cannot be negative"));
+
+    let response = update("missing", TEST_ADMIN_SECRET, json!({ "max_mailboxes": null })).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Organization not found"));
+}
+
+#[tokio::test]
+async fn test_organization_shared_mailbox() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (_, owner_token) = create_test_user_with_auth(&mut app_service).await;
+
+    let register_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "org-member",
+                    "password": TEST_PASSWORD
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let member: ApiResponse<AuthResponse> = read_body(register_response).await;
+    let member = member.data.unwrap();
+
+    let org_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/orgs")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", owner_token))
+                .body(Body::from(json!({ "name": "Team", "max_mailboxes": 1 }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let org: ApiResponse<serde_json::Value> = read_body(org_response).await;
+    assert!(org.success);
+    let org = org.data.unwrap();
+    let org_id = org["id"].as_str().unwrap().to_string();
+    // Members can't pick their own quota
+    assert!(org["max_mailboxes"].is_null());
+    assert!(db.set_organization_max_mailboxes(&org_id, Some(1)).await.unwrap());
+
+    let add_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/orgs/{}/members", org_id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", owner_token))
+                .body(Body::from(json!({ "user_id": member.user.id }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let added: ApiResponse<serde_json::Value> = read_body(add_response).await;
+    assert!(added.success);
+
+    let create_mailbox = |token: String| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/mailboxes")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({
+                "name": "Shared Mailbox",
+                "public_key": TEST_PUBLIC_KEY,
+                "organization_id": org_id
+            }).to_string()))
+            .unwrap()
+    };
+
+    let response = app_service.call(create_mailbox(owner_token.clone())).await.unwrap();
+    let mailbox: ApiResponse<Mailbox> = read_body(response).await;
+    let mailbox = mailbox.data.unwrap();
+    assert_eq!(mailbox.organization_id.as_deref(), Some(org_id.as_str()));
+
+    // The organization only allows a single mailbox
+    let response = app_service.call(create_mailbox(member.token.clone())).await.unwrap();
+    let rejected: ApiResponse<Mailbox> = read_body(response).await;
+    assert!(!rejected.success);
+
+    // Members can read mailboxes shared with the organization
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Authorization", format!("Bearer {}", member.token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let shared: ApiResponse<Mailbox> = read_body(response).await;
+    assert!(shared.success);
+    assert_eq!(shared.data.unwrap().id, mailbox.id);
+
+    // but only the owners and admins can change them, e.g. their key
+    let update_mailbox = |token: &str| {
+        Request::builder()
+            .method("PATCH")
+            .uri(format!("/api/mailboxes/{}", mailbox.id))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({ "name": "Renamed", "public_key": TEST_PUBLIC_KEY }).to_string()))
+            .unwrap()
+    };
+    let response = app_service.call(update_mailbox(&member.token)).await.unwrap();
+    let rejected: ApiResponse<Mailbox> = read_body(response).await;
+    assert_eq!(rejected.error.as_deref(), Some("Authentication error: Unauthorized"));
+
+    let response = app_service.call(update_mailbox(&owner_token)).await.unwrap();
+    let updated: ApiResponse<Mailbox> = read_body(response).await;
+    assert_eq!(updated.data.unwrap().name, "Renamed");
+
+    // Shared mailboxes are accessible to members without being theirs
+    assert!(db.get_mailboxes_by_owner(&member.user.id).await.unwrap().is_empty());
+    let accessible = db.get_accessible_mailboxes(&member.user.id).await.unwrap();
+    assert_eq!(accessible.iter().map(|mailbox| mailbox.id.as_str()).collect::<Vec<_>>(), [mailbox.id.as_str()]);
+
+    // so clearing expired emails in bulk leaves them to their managers
+    db.save_email(&Email {
+        id: "shared-expired-email".to_string(),
+        mailbox_id: mailbox.id.clone(),
+        encrypted_content: "encrypted".to_string(),
+        received_at: 0,
+        expires_at: Some(1),
+        from_addr: String::new(),
+        to_addr: String::new(),
+        subject: String::new(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    })
+    .await
+    .unwrap();
+    let delete_expired = |token: &str| {
+        Request::builder()
+            .method("DELETE")
+            .uri("/api/mailboxes/expired")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let response = app_service.call(delete_expired(&member.token)).await.unwrap();
+    let deleted: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(deleted.data.unwrap(), json!([]));
+    assert!(db.get_email("shared-expired-email").await.unwrap().is_some());
+
+    let response = app_service.call(delete_expired(&owner_token)).await.unwrap();
+    let deleted: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(deleted.data.unwrap(), json!([{ "mailbox_id": mailbox.id, "deleted_count": 1 }]));
+    assert!(db.get_email("shared-expired-email").await.unwrap().is_none());
+}
+
+#[tokio::test]
+async fn test_organization_admin_roles() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, owner_token) = create_test_user_with_auth(&mut app_service).await;
+    let mut users = Vec::new();
+    for username in ["first-admin", "second-admin", "plain-member"] {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/auth/register")
+                    .header("Content-Type", "application/json")
+                    .body(Body::from(json!({ "username": username, "password": TEST_PASSWORD }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let auth: ApiResponse<AuthResponse> = read_body(response).await;
+        let auth = auth.data.unwrap();
+        users.push((auth.user.id, auth.token));
+    }
+    let [(first_admin, first_admin_token), (second_admin, _), (plain_member, _)] = users.try_into().unwrap();
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/orgs")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", owner_token))
+                .body(Body::from(json!({ "name": "Team" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let org: ApiResponse<serde_json::Value> = read_body(response).await;
+    let org_id = org.data.unwrap()["id"].as_str().unwrap().to_string();
+
+    let set_role = |token: &str, user_id: &str, role: &str| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/api/orgs/{}/members", org_id))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({ "user_id": user_id, "role": role }).to_string()))
+            .unwrap()
+    };
+    let remove = |token: &str, user_id: &str| {
+        Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/orgs/{}/members/{}", org_id, user_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+    for (user_id, role) in [(&first_admin, "admin"), (&second_admin, "admin"), (&plain_member, "member")] {
+        let response = app_service.call(set_role(&owner_token, user_id, role)).await.unwrap();
+        let added: ApiResponse<serde_json::Value> = read_body(response).await;
+        assert!(added.success);
+    }
+
+    // Admins can neither demote nor remove other admins
+    let response = app_service.call(set_role(&first_admin_token, &second_admin, "member")).await.unwrap();
+    let rejected: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(
+        rejected.error.as_deref(),
+        Some("Authentication error: Only the organization owner can change the role of admins")
+    );
+    let response = app_service.call(remove(&first_admin_token, &second_admin)).await.unwrap();
+    let rejected: ApiResponse<()> = read_body(response).await;
+    assert_eq!(rejected.error.as_deref(), Some("Authentication error: Only the organization owner can remove admins"));
+
+    // but manage plain members
+    let response = app_service.call(set_role(&first_admin_token, &plain_member, "admin")).await.unwrap();
+    let promoted: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(promoted.success);
+    let response = app_service.call(set_role(&owner_token, &plain_member, "member")).await.unwrap();
+    let demoted: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(demoted.success);
+    let response = app_service.call(remove(&first_admin_token, &plain_member)).await.unwrap();
+    let removed: ApiResponse<()> = read_body(response).await;
+    assert!(removed.success);
+
+    // The owner can remove admins, who may also leave on their own
+    let response = app_service.call(remove(&owner_token, &second_admin)).await.unwrap();
+    let removed: ApiResponse<()> = read_body(response).await;
+    assert!(removed.success);
+    let response = app_service.call(remove(&first_admin_token, &first_admin)).await.unwrap();
+    let left: ApiResponse<()> = read_body(response).await;
+    assert!(left.success);
+}
+
+#[tokio::test]
+async fn test_response_compression() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    // Enough mailboxes for the listing to exceed the minimum compression size
+    for i in 0..8 {
+        app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/mailboxes")
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::from(
+                        json!({
+                            "name": format!("Compressed Mailbox {}", i),
+                            "public_key": TEST_PUBLIC_KEY
+                        })
+                        .to_string(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+    }
+
+    let plain_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/mailboxes")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(plain_response.headers().get("content-encoding").is_none());
+    let plain = plain_response.into_body().collect().await.unwrap().to_bytes();
+    assert!(plain.len() > 1024, "Listing should exceed the minimum compression size");
+
+    let gzip_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/mailboxes")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Accept-Encoding", "gzip")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(gzip_response.headers().get("content-encoding").unwrap(), "gzip");
+    let compressed = gzip_response.into_body().collect().await.unwrap().to_bytes();
+    assert!(compressed.len() < plain.len());
+
+    let mut decompressed = Vec::new();
+    std::io::Read::read_to_end(&mut flate2::read::GzDecoder::new(&compressed[..]), &mut decompressed).unwrap();
+    let decompressed: serde_json::Value = serde_json::from_slice(&decompressed).unwrap();
+    let plain: serde_json::Value = serde_json::from_slice(&plain).unwrap();
+    assert_eq!(decompressed, plain);
+
+    // Small responses are left alone
+    let small_response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/supported-domains")
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Accept-Encoding", "gzip")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert!(small_response.headers().get("content-encoding").is_none());
+}
+
+#[tokio::test]
+async fn test_get_mailbox_email_metadata() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Metadata Mailbox").await;
+
+    let boundary = "metadata-boundary";
+    let body: String = (0..3)
+        .map(|i| format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"{i}.eml\"\r\nContent-Type: message/rfc822\r\n\r\n\
+             From: sender@example.com\r\nSubject: Email {i}\r\n\r\nBody {i}\r\n",
+            b = boundary,
+            i = i,
+        ))
+        .chain(std::iter::once(format!("--{}--\r\n", boundary)))
+        .collect();
+    let import_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+                .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let import_result: ApiResponse<serde_json::Value> = read_body(import_response).await;
+    assert_eq!(import_result.data.unwrap()["imported"], 3);
+
+    let mut seen = Vec::new();
+    let mut cursor: Option<String> = None;
+    loop {
+        let uri = match &cursor {
+            Some(cursor) => format!("/api/mailboxes/{}/emails?metadata=true&limit=2&cursor={}", mailbox.id, cursor),
+            None => format!("/api/mailboxes/{}/emails?metadata=true&limit=2", mailbox.id),
+        };
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("GET")
+                    .uri(uri)
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+
+        let page: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+        let page = page.data.unwrap();
+        assert!(page.len() <= 2);
+        assert!(page.iter().all(|email| email.get("encrypted_content").is_none()));
+        match page.last() {
+            Some(last) => cursor = Some(last["id"].as_str().unwrap().to_string()),
+            None => break,
+        }
+        seen.extend(page.into_iter().map(|email| email["id"].as_str().unwrap().to_string()));
+    }
+
+    assert_eq!(seen.len(), 3, "Every email should be listed once");
+    seen.sort();
+    seen.dedup();
+    assert_eq!(seen.len(), 3, "Pages should not overlap");
+}
+
+#[tokio::test]
+async fn test_oauth_providers() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    // Unregistered providers are not routed
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/auth/unknown/login")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+
+    // A password-only account has no OAuth connections to list or remove
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/auth/connected-accounts")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let accounts: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    let providers: Vec<_> = accounts
+        .data
+        .unwrap()
+        .iter()
+        .map(|account| account["provider"].as_str().unwrap().to_string())
+        .collect();
+    assert_eq!(providers, ["password"]);
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/github/disconnect")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert!(!result.success);
+    assert_eq!(result.error.as_deref(), Some("No GitHub account connected"));
+
+    // Logins redirect with a PKCE challenge and an opaque state, which only
+    // the provider it was issued for accepts
+    env::set_var("GITHUB_CLIENT_ID", "test-client-id");
+    env::set_var("GITHUB_CLIENT_SECRET", "test-client-secret");
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/auth/github/login?action=login&redirect_to=/mailboxes")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::SEE_OTHER);
+    let location = reqwest::Url::parse(response.headers()[header::LOCATION].to_str().unwrap()).unwrap();
+    let query: std::collections::HashMap<String, String> = location.query_pairs().into_owned().collect();
+    assert_eq!(query["code_challenge_method"], "S256");
+    assert!(!query["code_challenge"].is_empty());
+    let state = &query["state"];
+    assert!(!state.contains("/mailboxes"));
+
+    for callback in [
+        "/api/auth/github/callback?code=test-code&state=forged-state".to_string(),
+        format!("/api/auth/google/callback?code=test-code&state={}", state),
+    ] {
+        let response = app_service
+            .call(Request::builder().method("GET").uri(callback).body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let result: ApiResponse<()> = read_body(response).await;
+        assert_eq!(result.error.as_deref(), Some("The login has expired or is invalid. Please sign in again."));
+    }
+
+    // Connecting an account is only started by the signed-in user, never for
+    // a user named in the request
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/auth/github/login?action=connect&state={}", user_id))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let connect = |token: Option<&str>| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri("/api/auth/github/connect")
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request
+            .body(Body::from(json!({ "redirect_to": "/settings" }).to_string()))
+            .unwrap()
+    };
+    let response = app_service.call(connect(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app_service.call(connect(Some(&token))).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let url = reqwest::Url::parse(result.data.unwrap()["url"].as_str().unwrap()).unwrap();
+    assert!(url.query_pairs().any(|(name, value)| name == "state" && value != user_id));
+    let (stored_user, action): (Option<String>, Option<String>) =
+        sqlx::query_as("SELECT user_id, action FROM oauth_states WHERE action = 'connect'")
+            .fetch_one(db.pool())
+            .await
+            .unwrap();
+    assert_eq!(stored_user.as_deref(), Some(user_id.as_str()));
+    assert_eq!(action.as_deref(), Some("connect"));
+}
+
+#[tokio::test]
+async fn test_mailbox_aliases() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Aliased Mailbox").await;
+
+    let mut add_alias = |alias: &str| {
+        app_service.call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/mailboxes/{}/aliases", mailbox.id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "alias": alias }).to_string()))
+                .unwrap(),
+        )
+    };
+
+    // Aliases are stored lowercase and must be alphanumeric
+    let result: ApiResponse<MailboxAlias> = read_body(add_alias("Support").await.unwrap()).await;
+    assert!(result.success, "Failed to add alias: {:?}", result.error);
+    let support = result.data.unwrap();
+    assert_eq!(support.alias, "support");
+    assert!(!support.is_primary);
+
+    let result: ApiResponse<MailboxAlias> = read_body(add_alias("not.valid").await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: Alias may only contain letters and digits"));
+
+    let result: ApiResponse<MailboxAlias> = read_body(add_alias("support").await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: This alias is already taken"));
+
+    // The limit of 3 includes the primary alias
+    let result: ApiResponse<MailboxAlias> = read_body(add_alias("billing").await.unwrap()).await;
+    assert!(result.success);
+    let result: ApiResponse<MailboxAlias> = read_body(add_alias("sales").await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: Mailbox has reached its limit of 3 aliases"));
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .uri(format!("/api/mailboxes/{}/aliases", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let aliases = read_body::<ApiResponse<Vec<MailboxAlias>>>(response).await.data.unwrap();
+    assert_eq!(aliases.len(), 3);
+    assert!(aliases[0].is_primary);
+    assert_eq!(aliases[0].alias, mailbox.alias);
+
+    let mut delete_alias = |alias_id: &str| {
+        app_service.call(
+            Request::builder()
+                .method("DELETE")
+                .uri(format!("/api/mailboxes/{}/aliases/{}", mailbox.id, alias_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let result: ApiResponse<()> = read_body(delete_alias(&aliases[0].id).await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: The primary alias cannot be deleted"));
+
+    let result: ApiResponse<()> = read_body(delete_alias(&support.id).await.unwrap()).await;
+    assert!(result.success);
+
+    let result: ApiResponse<()> = read_body(delete_alias(&support.id).await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Not found: Alias not found"));
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_greylist() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let greylist = Greylist::shared();
+    let first = GreylistKey::new("192.0.2.10".parse().unwrap(), "alice@spam.example", "inbox@test.example.com");
+    greylist.insert(first.clone(), 1_000, 1_300);
+    greylist.insert(GreylistKey::new("192.0.2.11".parse().unwrap(), "bob@ham.example", "inbox@test.example.com"), 2_000, 2_300);
+
+    let mut admin_request = |method: &str, uri: &str, secret: Option<&str>| {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(secret) = secret {
+            request = request.header("X-Admin-Secret", secret);
+        }
+        app_service.call(request.body(Body::empty()).unwrap())
+    };
+
+    // A wrong or missing secret is rejected
+    let response = admin_request("GET", "/api/admin/greylist", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = admin_request("GET", "/api/admin/greylist", Some("wrong")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = admin_request("GET", "/api/admin/greylist?sender_domain=spam.example", Some(TEST_ADMIN_SECRET))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let page: serde_json::Value = read_body(response).await;
+    assert_eq!(page["data"]["total"], 1);
+    let entry = &page["data"]["entries"][0];
+    assert_eq!(entry["id"], first.id());
+    assert_eq!(entry["ip"], "192.0.2.10");
+    assert_eq!(entry["eligible_at"], 1_300);
+
+    let response = admin_request("GET", "/api/admin/greylist?ip=192.0.2.11&limit=1", Some(TEST_ADMIN_SECRET))
+        .await
+        .unwrap();
+    let page: serde_json::Value = read_body(response).await;
+    assert_eq!(page["data"]["entries"][0]["sender"], "bob@ham.example");
+
+    // Removing an entry allows immediate redelivery
+    let uri = format!("/api/admin/greylist/{}", first.id());
+    let result: ApiResponse<()> = read_body(admin_request("DELETE", &uri, Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert!(result.success);
+    assert_eq!(greylist.eligible_at(&first), None);
+    let result: ApiResponse<()> = read_body(admin_request("DELETE", &uri, Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Greylist entry not found"));
+
+    let response = admin_request("DELETE", "/api/admin/greylist", Some(TEST_ADMIN_SECRET)).await.unwrap();
+    let flushed: serde_json::Value = read_body(response).await;
+    assert_eq!(flushed["data"]["removed"], 1);
+    assert!(greylist.is_empty());
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_feature_flags() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let mut admin_request = |method: &str, uri: &str, body: Option<serde_json::Value>| {
+        let request = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("X-Admin-Secret", TEST_ADMIN_SECRET)
+            .header("Content-Type", "application/json");
+        let body = body.map_or_else(Body::empty, |body| Body::from(body.to_string()));
+        app_service.call(request.body(body).unwrap())
+    };
+
+    // Flags without an override follow the service configuration
+    let flags: serde_json::Value = read_body(admin_request("GET", "/api/admin/feature-flags", None).await.unwrap()).await;
+    let names: Vec<_> = flags["data"].as_array().unwrap().iter().map(|flag| flag["name"].clone()).collect();
+    assert_eq!(names, vec!["greylisting", "spf", "dkim"]);
+    assert!(flags["data"][1]["enabled"].is_null());
+
+    let body = serde_json::json!({ "enabled": false, "updated_by": "ops" });
+    let response = admin_request("PUT", "/api/admin/feature-flags/spf", Some(body)).await.unwrap();
+    let result: serde_json::Value = read_body(response).await;
+    assert_eq!(result["success"], true);
+
+    let flags: serde_json::Value = read_body(admin_request("GET", "/api/admin/feature-flags", None).await.unwrap()).await;
+    let spf = &flags["data"][1];
+    assert_eq!(spf["enabled"], false);
+    assert_eq!(spf["updated_by"], "ops");
+    assert!(spf["updated_at"].as_i64().unwrap() > 0);
+
+    let body = serde_json::json!({ "enabled": true });
+    let response = admin_request("PUT", "/api/admin/feature-flags/unknown", Some(body)).await.unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Unknown feature flag: unknown"));
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_bounces() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    for (id, recipient, permanent) in [
+        ("b1", "gone@example.com", true),
+        ("b2", "gone@example.com", false),
+        ("b3", "full@example.com", true),
+    ] {
+        db.save_bounce(&common::Bounce {
+            id: id.to_string(),
+            message_id: Some(format!("<{}@example.com>", id)),
+            original_recipient: recipient.to_string(),
+            status_code: if permanent { "5.1.1" } else { "4.2.2" }.to_string(),
+            diagnostic: None,
+            permanent,
+            bounced_at: chrono::Utc::now().timestamp(),
+        })
+        .await
+        .unwrap();
+    }
+
+    let mut list_bounces = |query: &str| {
+        app_service.call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/admin/bounces{}", query))
+                .header("X-Admin-Secret", TEST_ADMIN_SECRET)
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let ids = |bounces: ApiResponse<Vec<common::Bounce>>| {
+        let mut ids: Vec<_> = bounces.data.unwrap().into_iter().map(|bounce| bounce.id).collect();
+        ids.sort();
+        ids
+    };
+    assert_eq!(ids(read_body(list_bounces("").await.unwrap()).await), ["b1", "b2", "b3"]);
+    assert_eq!(
+        ids(read_body(list_bounces("?recipient=gone@example.com").await.unwrap()).await),
+        ["b1", "b2"]
+    );
+    assert_eq!(ids(read_body(list_bounces("?permanent=true").await.unwrap()).await), ["b1", "b3"]);
+    assert_eq!(
+        ids(read_body(list_bounces("?recipient=gone@example.com&permanent=false").await.unwrap()).await),
+        ["b2"]
+    );
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_users() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+    let mailbox_id = create_test_mailbox(&mut app_service, &token, "Owned").await.id;
+    let empty_mailbox_id = create_test_mailbox(&mut app_service, &token, "Empty").await.id;
+    db.save_email(&Email {
+        id: "admin-email".to_string(),
+        mailbox_id: mailbox_id.clone(),
+        encrypted_content: "encrypted".to_string(),
+        received_at: 100,
+        expires_at: None,
+        from_addr: "sender@example.com".to_string(),
+        to_addr: "inbox@example.com".to_string(),
+        subject: "Owned".to_string(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    })
+    .await
+    .unwrap();
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let api_key = result.data.unwrap()["key"].as_str().unwrap().to_string();
+
+    let mut admin_request = |method: &str, uri: &str, secret: Option<&str>| {
+        let mut request = Request::builder().method(method).uri(uri);
+        if let Some(secret) = secret {
+            request = request.header("X-Admin-Secret", secret);
+        }
+        app_service.call(request.body(Body::empty()).unwrap())
+    };
+
+    let response = admin_request("GET", "/api/admin/users", None).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let page: serde_json::Value = read_body(admin_request("GET", "/api/admin/users?search=user&page=1", Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert_eq!(page["data"]["total"], 1);
+    assert_eq!(page["data"]["users"][0]["id"], user_id.as_str());
+    let page: serde_json::Value = read_body(admin_request("GET", "/api/admin/users?search=nobody", Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert_eq!(page["data"]["total"], 0);
+    let page: serde_json::Value = read_body(admin_request("GET", "/api/admin/users?page=2", Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert_eq!(page["data"]["users"].as_array().unwrap().len(), 0);
+
+    let uri = format!("/api/admin/users/{}/mailboxes", user_id);
+    let mailboxes: ApiResponse<Vec<MailboxSummary>> = read_body(admin_request("GET", &uri, Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    let mut counts: Vec<_> = mailboxes.data.unwrap().into_iter().map(|summary| (summary.mailbox.id, summary.email_count)).collect();
+    counts.sort_by_key(|(_, count)| *count);
+    assert_eq!(counts, [(empty_mailbox_id.clone(), 0), (mailbox_id.clone(), 1)]);
+    assert_eq!(db.get_mailbox_email_count(&mailbox_id).await.unwrap(), 1);
+    let counts = db.get_mailboxes_email_counts(&[mailbox_id.clone(), empty_mailbox_id.clone()]).await.unwrap();
+    assert_eq!((counts[&mailbox_id], counts[&empty_mailbox_id]), (1, 0));
+
+    let stats: serde_json::Value = read_body(admin_request("GET", "/api/admin/stats", Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert_eq!(stats["data"]["user_count"], 1);
+    assert_eq!(stats["data"]["mailbox_count"], 2);
+    assert_eq!(stats["data"]["email_count"], 1);
+    assert!(stats["data"]["database_size_bytes"].as_i64().unwrap() > 0);
+
+    // Banned users are refused at login
+    let uri = format!("/api/admin/users/{}/ban", user_id);
+    let banned: ApiResponse<User> = read_body(admin_request("POST", &uri, Some(TEST_ADMIN_SECRET)).await.unwrap()).await;
+    assert!(banned.data.unwrap().banned_at.is_some());
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/login")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // and with the tokens and API keys they already hold
+    let response = app_service
+        .call(Request::builder().uri("/api/mailboxes").header("Authorization", format!("Bearer {}", token)).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = app_service
+        .call(
+            Request::builder()
+                .uri(format!("/api/v1/mailboxes/{}/emails", mailbox_id))
+                .header("Authorization", format!("Bearer {}", api_key))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Deleting the user takes their mailboxes and emails with them
+    let uri = format!("/api/admin/users/{}", user_id);
+    let response = app_service
+        .call(Request::builder().method("DELETE").uri(&uri).header("X-Admin-Secret", TEST_ADMIN_SECRET).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert!(result.success);
+    assert!(db.get_user(&user_id).await.unwrap().is_none());
+    assert!(db.get_email("admin-email").await.unwrap().is_none());
+    let stats = db.get_instance_stats().await.unwrap();
+    assert_eq!((stats.user_count, stats.mailbox_count, stats.email_count), (0, 0, 0));
+
+    let response = app_service
+        .call(Request::builder().method("DELETE").uri(&uri).header("X-Admin-Secret", TEST_ADMIN_SECRET).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("User not found"));
+}
+
+#[tokio::test]
+async fn test_mailbox_list_caching() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let list_request = |conditional: Option<(&'static str, String)>| {
+        let mut request = Request::builder()
+            .method("GET")
+            .uri("/api/mailboxes")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token));
+        if let Some((name, value)) = conditional {
+            request = request.header(name, value);
+        }
+        request.body(Body::empty()).unwrap()
+    };
+
+    let response = app_service.call(list_request(None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["cache-control"], "private, max-age=5");
+    let empty_etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Cached Mailbox").await;
+
+    let response = app_service.call(list_request(Some(("If-None-Match", empty_etag.clone())))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let last_modified = response.headers()["last-modified"].to_str().unwrap().to_string();
+    assert_ne!(etag, empty_etag);
+    let result: ApiResponse<Vec<Mailbox>> = read_body(response).await;
+    assert_eq!(result.data.unwrap().len(), 1);
+
+    // Unchanged lists are answered with an empty 304
+    let response = app_service.call(list_request(Some(("If-None-Match", etag.clone())))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    assert_eq!(response.headers()["etag"], etag.as_str());
+    let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(body.is_empty());
+
+    let response = app_service.call(list_request(Some(("If-Modified-Since", last_modified)))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    let epoch = Some(("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT".to_string()));
+    let response = app_service.call(list_request(epoch)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Modification times have millisecond resolution
+    tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    app_service
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "name": "Renamed Mailbox" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let response = app_service.call(list_request(Some(("If-None-Match", etag.clone())))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+
+    // Deleting a mailbox changes the ETag even though no remaining mailbox was modified
+    app_service
+        .call(
+            Request::builder()
+                .method("DELETE")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let response = app_service.call(list_request(Some(("If-None-Match", etag)))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["etag"], empty_etag.as_str());
+}
+
+#[tokio::test]
+async fn test_backup_passphrase_recovery() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Recoverable Mailbox").await;
+
+    let boundary = "backup-boundary";
+    let import_request = |subject: &str| {
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"mail.eml\"\r\n\r\n\
+             From: sender@example.com\r\nSubject: {subject}\r\n\r\nHello\r\n--{b}--\r\n",
+            b = boundary,
+            subject = subject,
+        );
+        Request::builder()
+            .method("POST")
+            .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+            .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(body))
+            .unwrap()
+    };
+    let passphrase_request = |action: &str, passphrase: &str| {
+        Request::builder()
+            .method("POST")
+            .uri(format!("/api/auth/backup-passphrase/{}", action))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({ "passphrase": passphrase }).to_string()))
+            .unwrap()
+    };
+
+    // Emails received before the setup are not encrypted to the backup key
+    app_service.call(import_request("Before backup")).await.unwrap();
+
+    let response = app_service.call(passphrase_request("setup", "too short")).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Backup passphrase must be at least 12 characters long"));
+
+    let response = app_service.call(passphrase_request("setup", "correct horse battery")).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(result.success, "Failed to set up backup: {:?}", result.error);
+    assert!(result.data.unwrap()["public_key"].as_str().unwrap().starts_with("age1"));
+
+    let response = app_service.call(passphrase_request("setup", "another passphrase")).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("A backup passphrase is already set up for this account."));
+
+    app_service.call(import_request("After backup")).await.unwrap();
+
+    // The owner loses the private key and switches the mailbox to a new one
+    let new_identity = age::x25519::Identity::generate();
+    let new_public_key = new_identity.to_public().to_string();
+    app_service
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "public_key": new_public_key }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    let response = app_service.call(passphrase_request("recover", "wrong passphrase")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Incorrect backup passphrase"));
+
+    let response = app_service.call(passphrase_request("recover", "correct horse battery")).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(result.success, "Failed to recover: {:?}", result.error);
+    let data = result.data.unwrap();
+    assert_eq!(data["reencrypted"], 1);
+    assert_eq!(data["skipped"], 1);
+
+    // The recovered email is readable and searchable with the new key
+    let search_token = common::search::search_token(&new_public_key, "after");
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails?token={}", mailbox.id, search_token))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let emails = read_body::<ApiResponse<Vec<Email>>>(response).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    let secret_key = age::secrecy::ExposeSecret::expose_secret(&new_identity.to_string()).clone();
+    let decrypted = common::security::decrypt_email(&emails[0].encrypted_content, &secret_key).unwrap();
+    assert!(String::from_utf8(decrypted).unwrap().contains("Subject: After backup"));
+}
+
+#[tokio::test]
+async fn test_api_key_scopes() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let create_key = |body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/api-keys")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+
+    // Keys created without scopes keep full access
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["scopes"], json!(["read_emails", "delete_emails", "manage_mailboxes"]));
+
+    for body in [json!({ "scopes": [] }), json!({ "scopes": ["send_emails"] })] {
+        let response = app_service.call(create_key(body)).await.unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        assert!(!result.success);
+    }
+
+    let all_scopes = ["read_emails", "delete_emails", "manage_mailboxes"];
+    for mask in 1..(1 << all_scopes.len()) {
+        let scopes: Vec<&str> = all_scopes
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| mask & (1 << i) != 0)
+            .map(|(_, scope)| *scope)
+            .collect();
+
+        let response = app_service.call(create_key(json!({ "scopes": scopes }))).await.unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        let api_key = result.data.unwrap();
+        assert_eq!(api_key["scopes"], json!(scopes));
+        let key = api_key["key"].as_str().unwrap().to_string();
+
+        // The mailbox does not exist, which is reported with 200 once the scope is checked
+        for (method, uri, scope) in [
+            ("GET", "/api/v1/mailboxes/missing/emails", "read_emails"),
+            ("GET", "/api/v1/mailboxes/missing/emails/missing", "read_emails"),
+            ("DELETE", "/api/v1/mailboxes/missing/emails/missing", "delete_emails"),
+            ("POST", "/api/v1/mailboxes/missing/rotate-alias", "manage_mailboxes"),
+            ("DELETE", "/api/v1/mailboxes/missing", "manage_mailboxes"),
+        ] {
+            let response = app_service
+                .call(
+                    Request::builder()
+                        .method(method)
+                        .uri(uri)
+                        .header("Authorization", format!("Bearer {}", key))
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            let expected = if scopes.contains(&scope) { StatusCode::OK } else { StatusCode::FORBIDDEN };
+            assert_eq!(response.status(), expected, "{} {} with scopes {:?}", method, uri, scopes);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_api_key_expiration() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let create_key = |expires_in_seconds: i64| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/api-keys")
+            .header("Authorization", format!("Bearer {}", token))
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({ "expires_in_seconds": expires_in_seconds }).to_string()))
+            .unwrap()
+    };
+    let list_emails = |key: &str| {
+        Request::builder()
+            .method("GET")
+            .uri("/api/v1/mailboxes/missing/emails")
+            .header("Authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    for expires_in_seconds in [0, 366 * 24 * 60 * 60] {
+        let response = app_service.call(create_key(expires_in_seconds)).await.unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        assert!(!result.success, "expires_in_seconds {} was accepted", expires_in_seconds);
+    }
+
+    let response = app_service.call(create_key(3600)).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let long_lived = result.data.unwrap();
+    assert_eq!(
+        long_lived["expires_at"].as_i64().unwrap(),
+        long_lived["created_at"].as_i64().unwrap() + 3600
+    );
+
+    let response = app_service.call(create_key(1)).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let short_lived = result.data.unwrap();
+
+    // The exact expiry can be looked up by the owner
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/api-keys/{}", long_lived["id"].as_str().unwrap()))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["expires_at"], long_lived["expires_at"]);
+
+    let response = app_service.call(list_emails(short_lived["key"].as_str().unwrap())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    tokio::time::sleep(std::time::Duration::from_millis(2100)).await;
+
+    let response = app_service.call(list_emails(short_lived["key"].as_str().unwrap())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(list_emails(long_lived["key"].as_str().unwrap())).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_refresh_tokens() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let post_request = |uri: &str, body: serde_json::Value| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let refresh_request = |refresh_token: &str| post_request("/api/auth/refresh", json!({ "refresh_token": refresh_token }));
+    let login_request = || post_request("/api/auth/login", json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD }));
+
+    create_test_user_with_auth(&mut app_service).await;
+    let response = app_service.call(login_request()).await.unwrap();
+    let first_refresh_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().refresh_token;
+
+    // Refreshing issues a new access token and rotates the refresh token
+    let response = app_service.call(refresh_request(&first_refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let refreshed = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    assert_eq!(refreshed.user.username, TEST_USERNAME);
+    assert_ne!(refreshed.refresh_token, first_refresh_token);
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/auth/me")
+                .header("Authorization", format!("Bearer {}", refreshed.token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    let response = app_service.call(refresh_request(&first_refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(refresh_request("unknown-token")).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Revoked by logging out
+    let response = app_service
+        .call(post_request("/api/auth/logout", json!({ "refresh_token": refreshed.refresh_token })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app_service.call(refresh_request(&refreshed.refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Expired
+    let response = app_service.call(login_request()).await.unwrap();
+    let expiring_refresh_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().refresh_token;
+    sqlx::query("UPDATE refresh_tokens SET expires_at = ? WHERE revoked_at IS NULL")
+        .bind(chrono::Utc::now().timestamp() - 1)
+        .execute(db.pool())
+        .await
+        .unwrap();
+    let response = app_service.call(refresh_request(&expiring_refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Revoked by a password change, which returns a new one
+    let response = app_service.call(login_request()).await.unwrap();
+    let session = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/change-password")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", session.token))
+                .body(Body::from(json!({
+                    "current_password": TEST_PASSWORD,
+                    "new_password": "New-password2"
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let changed = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    let response = app_service.call(refresh_request(&session.refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service.call(refresh_request(&changed.refresh_token)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_bulk_delete_emails() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut mailbox_ids = Vec::new();
+    for name in ["Bulk Mailbox", "Other Mailbox"] {
+        mailbox_ids.push(create_test_mailbox(&mut app_service, &token, name).await.id);
+    }
+    let (mailbox_id, other_mailbox_id) = (&mailbox_ids[0], &mailbox_ids[1]);
+
+    for (id, mailbox_id, received_at) in [
+        ("bulk-1", mailbox_id, 100),
+        ("bulk-2", mailbox_id, 200),
+        ("bulk-3", mailbox_id, 300),
+        ("other-1", other_mailbox_id, 100),
+    ] {
+        db.save_email(&Email {
+            id: id.to_string(),
+            mailbox_id: mailbox_id.to_string(),
+            encrypted_content: "encrypted".to_string(),
+            received_at,
+            expires_at: None,
+            from_addr: "sender@example.com".to_string(),
+            to_addr: "inbox@example.com".to_string(),
+            subject: "Bulk".to_string(),
+            headers_json: None,
+            raw_size_bytes: 0,
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let delete_request = |uri: String, authorization: &str, body: Option<serde_json::Value>| {
+        Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", authorization))
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap()
+    };
+    let emails_uri = format!("/api/mailboxes/{}/emails", mailbox_id);
+
+    // Emails of another mailbox are rejected, and nothing is deleted
+    let response = app_service
+        .call(delete_request(emails_uri.clone(), &token, Some(json!({ "email_ids": ["bulk-1", "other-1"] }))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(!result.success);
+    assert_eq!(db.get_mailbox_emails(mailbox_id).await.unwrap().len(), 3);
+    assert_eq!(db.get_mailbox_emails(other_mailbox_id).await.unwrap().len(), 1);
+
+    let response = app_service
+        .call(delete_request(emails_uri.clone(), &token, Some(json!({ "email_ids": ["bulk-1"] }))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["deleted_count"], 1);
+
+    let response = app_service
+        .call(delete_request(emails_uri.clone(), &token, Some(json!({ "before_timestamp": 250 }))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["deleted_count"], 1);
+    let remaining: Vec<String> = db.get_mailbox_emails(mailbox_id).await.unwrap().into_iter().map(|email| email.id).collect();
+    assert_eq!(remaining, vec!["bulk-3".to_string()]);
+
+    // The external API needs the delete_emails scope
+    let mut api_keys = Vec::new();
+    for scopes in [json!(["read_emails"]), json!(["delete_emails"])] {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/api-keys")
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::from(json!({ "scopes": scopes }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        api_keys.push(result.data.unwrap()["key"].as_str().unwrap().to_string());
+    }
+    let v1_emails_uri = format!("/api/v1/mailboxes/{}/emails", mailbox_id);
+
+    let response = app_service.call(delete_request(v1_emails_uri.clone(), &api_keys[0], None)).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Without a body the whole mailbox is cleared
+    let response = app_service.call(delete_request(v1_emails_uri, &api_keys[1], None)).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["deleted_count"], 1);
+    assert!(db.get_mailbox_emails(mailbox_id).await.unwrap().is_empty());
+    assert_eq!(db.get_mailbox_emails(other_mailbox_id).await.unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn test_delete_expired_emails() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+    let register_response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "expiry-other",
+                    "password": TEST_PASSWORD
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other: ApiResponse<AuthResponse> = read_body(register_response).await;
+    let other_token = other.data.unwrap().token;
+
+    let mut mailbox_ids = Vec::new();
+    for name in ["Expiring Mailbox", "Other Expiring Mailbox"] {
+        mailbox_ids.push(create_test_mailbox(&mut app_service, &token, name).await.id);
+    }
+    let (mailbox_id, other_mailbox_id) = (&mailbox_ids[0], &mailbox_ids[1]);
+
+    let now = chrono::Utc::now().timestamp();
+    for (id, mailbox_id, expires_at) in [
+        ("expired-1", mailbox_id, Some(now - 100)),
+        ("expired-2", mailbox_id, Some(now - 10)),
+        ("fresh-1", mailbox_id, Some(now + 3600)),
+        ("kept-1", mailbox_id, None),
+        ("other-expired-1", other_mailbox_id, Some(now - 100)),
+        ("other-fresh-1", other_mailbox_id, Some(now + 3600)),
+    ] {
+        db.save_email(&Email {
+            id: id.to_string(),
+            mailbox_id: mailbox_id.to_string(),
+            encrypted_content: "encrypted".to_string(),
+            received_at: now - 1000,
+            expires_at,
+            from_addr: "sender@example.com".to_string(),
+            to_addr: "inbox@example.com".to_string(),
+            subject: "Expiring".to_string(),
+            headers_json: None,
+            raw_size_bytes: 0,
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let delete_request = |uri: String, authorization: &str| {
+        Request::builder()
+            .method("DELETE")
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", authorization))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let remaining = |emails: Vec<Email>| {
+        let mut ids: Vec<String> = emails.into_iter().map(|email| email.id).collect();
+        ids.sort();
+        ids
+    };
+    let expired_uri = format!("/api/mailboxes/{}/emails/expired", mailbox_id);
+
+    // Other users cannot clean up the mailbox
+    let response = app_service.call(delete_request(expired_uri.clone(), &other_token)).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert!(!result.success);
+    assert_eq!(db.get_mailbox_emails(mailbox_id).await.unwrap().len(), 4);
+
+    let response = app_service.call(delete_request(expired_uri.clone(), &token)).await.unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.data.unwrap()["deleted_count"], 2);
+    assert_eq!(remaining(db.get_mailbox_emails(mailbox_id).await.unwrap()), vec!["fresh-1", "kept-1"]);
+    assert_eq!(db.get_mailbox_emails(other_mailbox_id).await.unwrap().len(), 2);
+
+    // Across all mailboxes, only the one with expired emails left has any deleted
+    let response = app_service.call(delete_request("/api/mailboxes/expired".to_string(), &token)).await.unwrap();
+    let result: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    let mut counts: Vec<(String, u64)> = result
+        .data
+        .unwrap()
+        .into_iter()
+        .map(|count| (count["mailbox_id"].as_str().unwrap().to_string(), count["deleted_count"].as_u64().unwrap()))
+        .collect();
+    counts.sort();
+    let mut expected = vec![(mailbox_id.clone(), 0), (other_mailbox_id.clone(), 1)];
+    expected.sort();
+    assert_eq!(counts, expected);
+    assert_eq!(remaining(db.get_mailbox_emails(mailbox_id).await.unwrap()), vec!["fresh-1", "kept-1"]);
+    assert_eq!(remaining(db.get_mailbox_emails(other_mailbox_id).await.unwrap()), vec!["other-fresh-1"]);
+}
+
+#[tokio::test]
+async fn test_custom_mailbox_alias() {
+    setup();
+    let app = setup_test_app().await;
+    let (_, token) = create_test_user_with_auth(&mut app.clone().into_service()).await;
+
+    let create_mailbox = |alias: &str| {
+        let app = app.clone();
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/mailboxes")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(
+                json!({
+                    "name": "Custom Alias",
+                    "public_key": TEST_PUBLIC_KEY,
+                    "alias": alias
+                })
+                .to_string(),
+            ))
+            .unwrap();
+        async move { read_body::<ApiResponse<Mailbox>>(app.into_service().call(request).await.unwrap()).await }
+    };
+
+    for invalid in ["abc", "no spaces", "no@sign", &"a".repeat(65)] {
+        let result = create_mailbox(invalid).await;
+        assert!(!result.success, "Alias {:?} should be rejected", invalid);
+    }
+
+    let mailbox = create_mailbox(" John.Doe+Test ").await.data.unwrap();
+    assert_eq!(mailbox.alias, "john.doe+test");
+
+    // Aliases are unique regardless of case
+    let result = create_mailbox("JOHN.DOE+TEST").await;
+    assert_eq!(result.error.as_deref(), Some("A mailbox with this alias already exists"));
+
+    // Concurrent requests for the same alias can't both get it
+    let results = futures::future::join_all((0..5).map(|_| create_mailbox("first-come"))).await;
+    assert_eq!(results.iter().filter(|result| result.success).count(), 1);
+
+    let update_alias = |mailbox_id: &str, alias: &str| {
+        let request = Request::builder()
+            .method("PATCH")
+            .uri(format!("/api/mailboxes/{}/alias", mailbox_id))
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::from(json!({ "alias": alias }).to_string()))
+            .unwrap();
+        let app = app.clone();
+        async move { read_body::<ApiResponse<Mailbox>>(app.into_service().call(request).await.unwrap()).await }
+    };
+
+    let result = update_alias(&mailbox.id, "x").await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: Alias must be 4 to 64 characters long"));
+    let result = update_alias(&mailbox.id, "first-come").await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: This alias is already taken"));
+
+    let updated = update_alias(&mailbox.id, "Jane_Doe").await.data.unwrap();
+    assert_eq!(updated.alias, "jane_doe");
+
+    let response = app
+        .clone()
+        .into_service()
+        .call(
+            Request::builder()
+                .uri(format!("/api/mailboxes/{}/aliases", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let aliases = read_body::<ApiResponse<Vec<MailboxAlias>>>(response).await.data.unwrap();
+    assert_eq!(aliases.len(), 1);
+    assert_eq!(aliases[0].alias, "jane_doe");
+
+    // The previous alias is free again
+    assert!(create_mailbox("john.doe+test").await.success);
+}
+
+#[tokio::test]
+async fn test_health_and_readiness() {
+    setup();
+    let (_, db) = setup_test_app_with_db().await;
+    let (smtp_bound, smtp_bound_rx) = tokio::sync::watch::channel(false);
+    let app = create_app_with_smtp_status(db.clone(), Some(smtp_bound_rx));
+
+    let get = |uri: &'static str| {
+        let app = app.clone();
+        async move {
+            let response = app
+                .into_service()
+                .call(Request::builder().uri(uri).body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            let status = response.status();
+            (status, read_body::<serde_json::Value>(response).await)
+        }
+    };
+
+    // Health needs no token and doesn't depend on anything
+    let (status, health) = get("/api/health").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(health["status"], "ok");
+    assert!(health["uptime_seconds"].is_u64());
+
+    let (status, ready) = get("/api/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready, json!({ "status": "degraded", "reason": "SMTP server is not listening" }));
+
+    smtp_bound.send_replace(true);
+    let (status, ready) = get("/api/ready").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(ready, json!({ "status": "ready" }));
+
+    db.pool().close().await;
+    let (status, ready) = get("/api/ready").await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(ready["status"], "degraded");
+    let (status, _) = get("/api/health").await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_mailbox_name_persists() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Receipts").await;
+    assert_eq!(mailbox.name, "Receipts");
+    assert_eq!(db.get_mailbox(&mailbox.id).await.unwrap().unwrap().name, "Receipts");
+
+    // Only the name is sent, the other settings are kept
+    let update_response = app_service
+        .call(
+            Request::builder()
+                .method("PATCH")
+                .uri(format!("/api/mailboxes/{}", mailbox.id))
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "name": "Invoices" }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<Mailbox> = read_body(update_response).await;
+    assert!(result.success, "Failed to rename mailbox: {:?}", result.error);
+
+    let stored = db.get_mailbox(&mailbox.id).await.unwrap().unwrap();
+    assert_eq!(stored.name, "Invoices");
+    assert_eq!(stored.mail_expires_in, mailbox.mail_expires_in);
+    assert_eq!(stored.public_key, mailbox.public_key);
+    assert_eq!(db.get_mailbox_by_address(&mailbox.alias).await.unwrap().unwrap().name, "Invoices");
+
+    let list_response = app_service
+        .call(
+            Request::builder()
+                .uri("/api/mailboxes")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let mailboxes = read_body::<ApiResponse<Vec<Mailbox>>>(list_response).await.data.unwrap();
+    assert_eq!(mailboxes.len(), 1);
+    assert_eq!(mailboxes[0].name, "Invoices");
+}
+
+#[tokio::test]
+async fn test_route_rate_limits() {
+    setup();
+    let (_, db) = setup_test_app_with_db().await;
+    // Wide enough a window for the slow password hashing of debug builds
+    let limit = |max_requests| RateLimiterConfig::from(vec![RateLimitRule::new(max_requests, 3)]);
+    let app = create_app_with_rate_limits(db, RateLimitConfigs {
+        mailbox_create: limit(2),
+        email_delete: limit(2),
+        auth: limit(3),
+        oauth_callback: limit(3),
+    });
+    let mut app_service = app.into_service();
+
+    // Unauthenticated routes are limited per client IP
+    let mut request = |method: &str, uri: String, token: Option<&str>, body: serde_json::Value| {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Accept", "application/json")
+            .header("X-Forwarded-For", "198.51.100.42")
+            .extension(proxy_peer());
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        app_service.call(builder.body(Body::from(body.to_string())).unwrap())
+    };
+    let credentials = json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD });
+    let response = request("POST", "/api/auth/register".into(), None, credentials.clone()).await.unwrap();
+    let token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+
+    for _ in 0..3 {
+        let response = request("POST", "/api/auth/login".into(), None, credentials.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = request("POST", "/api/auth/login".into(), None, credentials.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!((1..=3).contains(&retry_after));
+
+    // Authenticated routes are limited per user, and keep Retry-After
+    // through the JSON error body
+    let mailbox = json!({ "name": "Limited", "public_key": TEST_PUBLIC_KEY });
+    let mut mailbox_ids = Vec::new();
+    for _ in 0..2 {
+        let response = request("POST", "/api/mailboxes".into(), Some(&token), mailbox.clone()).await.unwrap();
+        mailbox_ids.push(read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap().id);
+    }
+    let response = request("POST", "/api/mailboxes".into(), Some(&token), mailbox.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    let response: ApiResponse<Mailbox> = read_body(response).await;
+    assert_eq!(response.error.as_deref(), Some("429 Too Many Requests"));
+
+    let expired_uri = format!("/api/mailboxes/{}/emails/expired", mailbox_ids[0]);
+    for _ in 0..2 {
+        let response = request("DELETE", expired_uri.clone(), Some(&token), json!({})).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = request("DELETE", expired_uri.clone(), Some(&token), json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    // Other routes are unaffected
+    let response = request("GET", "/api/mailboxes".into(), Some(&token), json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // API keys are held to the same limits
+    let response = request("POST", "/api/api-keys".into(), Some(&token), json!({})).await.unwrap();
+    let api_key = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap()["key"].as_str().unwrap().to_string();
+    for _ in 0..2 {
+        let response = request("POST", "/api/v1/mailboxes".into(), Some(&api_key), mailbox.clone()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+    let response = request("POST", "/api/v1/mailboxes".into(), Some(&api_key), mailbox.clone()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let v1_email_uri = format!("/api/v1/mailboxes/{}/emails/missing", mailbox_ids[0]);
+    for _ in 0..2 {
+        let response = request("DELETE", v1_email_uri.clone(), Some(&api_key), json!({})).await.unwrap();
+        assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+    let response = request("DELETE", v1_email_uri, Some(&api_key), json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+    tokio::time::sleep(std::time::Duration::from_millis(3100)).await;
+    let response = request("POST", "/api/auth/login".into(), None, credentials).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = request("POST", "/api/mailboxes".into(), Some(&token), mailbox).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = request("DELETE", expired_uri, Some(&token), json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_login_lockout() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let (user_id, token) = create_test_user_with_auth(&mut app.clone().into_service()).await;
+
+    let login = |password: &'static str| {
+        let request = Request::builder()
+            .method("POST")
+            .uri("/api/auth/login")
+            .header("Content-Type", "application/json")
+            .header("X-Forwarded-For", "203.0.113.7")
+            .extension(proxy_peer())
+            .body(Body::from(json!({ "username": TEST_USERNAME, "password": password }).to_string()))
+            .unwrap();
+        app.clone().into_service().call(request)
+    };
+    let lockout_status = |authorization: Option<String>| {
+        let mut request = Request::builder().uri(format!("/api/auth/lockout-status?username={}", TEST_USERNAME));
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+        let app = app.clone();
+        async move {
+            let response = app.into_service().call(request.body(Body::empty()).unwrap()).await.unwrap();
+            read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap()
+        }
+    };
+
+    // A successful login clears earlier failures
+    for _ in 0..4 {
+        assert_eq!(login("wrong-password").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(login(TEST_PASSWORD).await.unwrap().status(), StatusCode::OK);
+
+    for _ in 0..5 {
+        assert_eq!(login("wrong-password").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+    let ip: Option<String> = sqlx::query_scalar("SELECT ip FROM login_attempts WHERE user_id = ? LIMIT 1")
+        .bind(&user_id)
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert_eq!(ip.as_deref(), Some("203.0.113.7"));
+
+    // Even the right password is refused while locked
+    let response = login(TEST_PASSWORD).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()[header::RETRY_AFTER].to_str().unwrap().parse().unwrap();
+    assert!(retry_after > 0 && retry_after <= 15 * 60);
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Account temporarily locked"));
+
+    let status = lockout_status(None).await;
+    assert_eq!(status["locked"], true);
+    assert!(status["unlock_in_seconds"].as_u64().unwrap() <= 15 * 60);
+    assert_eq!(lockout_status(Some(format!("Bearer {}", token))).await["locked"], true);
+
+    // Failures older than the window no longer count
+    sqlx::query("UPDATE login_attempts SET attempt_at = attempt_at - 15 * 60")
+        .execute(db.pool())
+        .await
+        .unwrap();
+    let status = lockout_status(None).await;
+    assert_eq!(status, json!({ "locked": false, "unlock_in_seconds": null }));
+    assert_eq!(login(TEST_PASSWORD).await.unwrap().status(), StatusCode::OK);
+
+    assert_eq!(db.cleanup_login_attempts(i64::MAX).await.unwrap(), 0, "Successful login should clear failures");
+}
+
+#[tokio::test]
+async fn test_totp_login() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let post_request = |uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut request = Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Content-Type", "application/json");
+        if let Some(token) = token {
+            request = request.header("Authorization", format!("Bearer {}", token));
+        }
+        request.body(Body::from(body.to_string())).unwrap()
+    };
+    let login_request = || post_request("/api/auth/login", None, json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD }));
+
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+    let response = app_service.call(post_request("/api/auth/totp/setup", Some(&token), json!({}))).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let setup = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    let secret = setup["secret"].as_str().unwrap();
+    assert!(setup["provisioning_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+    assert!(setup["qr_code"].as_str().unwrap().starts_with("data:image/svg+xml;base64,"));
+
+    let totp = totp_rs::TOTP::new_unchecked(
+        totp_rs::Algorithm::SHA1,
+        6,
+        0,
+        30,
+        totp_rs::Secret::Encoded(secret.to_string()).to_bytes().unwrap(),
+        None,
+        String::new(),
+    );
+    // Each accepted code must be of a later time step than the previous one
+    let code_at = |offset: i64| totp.generate((chrono::Utc::now().timestamp() + offset) as u64);
+
+    // Not required until confirmed
+    let response = app_service.call(login_request()).await.unwrap();
+    assert!(read_body::<ApiResponse<AuthResponse>>(response).await.success);
+
+    let response = app_service
+        .call(post_request("/api/auth/totp/verify", Some(&token), json!({ "code": code_at(-30) })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let enabled: bool = sqlx::query_scalar("SELECT totp_enabled FROM user_credentials WHERE user_id = ?")
+        .bind(&user_id)
+        .fetch_one(db.pool())
+        .await
+        .unwrap();
+    assert!(enabled);
+
+    let login_challenge = |response: Response| async move {
+        let challenge = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+        assert_eq!(challenge["requires_totp"], true);
+        assert!(challenge.get("token").is_none());
+        challenge["challenge_token"].as_str().unwrap().to_string()
+    };
+    let challenge_token = login_challenge(app_service.call(login_request()).await.unwrap()).await;
+
+    // Wrong code
+    let response = app_service
+        .call(post_request("/api/auth/totp/complete", None, json!({ "challenge_token": challenge_token, "code": "000000" })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let code = code_at(0);
+    let response = app_service
+        .call(post_request("/api/auth/totp/complete", None, json!({ "challenge_token": challenge_token, "code": code })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let session = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap();
+    assert_eq!(session.user.id, user_id);
+
+    // The challenge and the code are single use
+    let response = app_service
+        .call(post_request("/api/auth/totp/complete", None, json!({ "challenge_token": challenge_token, "code": code })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let challenge_token = login_challenge(app_service.call(login_request()).await.unwrap()).await;
+    let response = app_service
+        .call(post_request("/api/auth/totp/complete", None, json!({ "challenge_token": challenge_token, "code": code })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = app_service
+        .call(post_request("/api/auth/totp/disable", Some(&session.token), json!({ "code": code })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = app_service
+        .call(post_request("/api/auth/totp/disable", Some(&session.token), json!({ "code": code_at(30) })))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app_service.call(login_request()).await.unwrap();
+    assert!(read_body::<ApiResponse<AuthResponse>>(response).await.success);
+}
+
+#[tokio::test]
+async fn test_mailbox_webhooks() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let request = |method: &str, uri: String, body: Option<serde_json::Value>| {
+        Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(body.map_or_else(Body::empty, |body| Body::from(body.to_string())))
+            .unwrap()
+    };
+
+    let response = app_service
+        .call(request("POST", "/api/mailboxes".to_string(), Some(json!({ "name": "Hooked", "public_key": TEST_PUBLIC_KEY }))))
+        .await
+        .unwrap();
+    let mailbox = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    let webhooks_uri = format!("/api/mailboxes/{}/webhooks", mailbox.id);
+
+    let response = app_service
+        .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": "ftp://example.com/hook" }))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: URL must use http or https"));
+    // The server itself and its network are off limits
+    for url in ["http://127.0.0.1:8080/hook", "http://169.254.169.254/latest/meta-data/", "http://10.0.0.1/", "http://[::1]/", "http://localhost/"] {
+        let response = app_service
+            .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": url }))))
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        assert!(!result.success, "{} is refused", url);
+    }
+    let response = app_service
+        .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": "https://203.0.113.10/hook", "events": ["email.deleted"] }))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: Unknown webhook event: email.deleted"));
+
+    // The secret is only returned on creation
+    let response = app_service
+        .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": "https://203.0.113.10/hook" }))))
+        .await
+        .unwrap();
+    let created = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(created["events"], json!(["email.received"]));
+    assert_eq!(created["secret"].as_str().unwrap().len(), 32);
+    assert_eq!(created["last_status"], serde_json::Value::Null);
+
+    let response = app_service.call(request("GET", webhooks_uri.clone(), None)).await.unwrap();
+    let webhooks = read_body::<ApiResponse<Vec<serde_json::Value>>>(response).await.data.unwrap();
+    assert_eq!(webhooks.len(), 1);
+    assert_eq!(webhooks[0]["id"], created["id"]);
+    assert_eq!(webhooks[0]["url"], "https://203.0.113.10/hook");
+    assert!(webhooks[0].get("secret").is_none());
+    assert_eq!(webhooks[0]["signature_algorithm"], "sha256");
+
+    // Rotating replaces the secret and may switch the algorithm
+    let webhook_uri = format!("{}/{}", webhooks_uri, created["id"].as_str().unwrap());
+    let response = app_service
+        .call(request("POST", format!("{}/rotate-secret", webhook_uri), Some(json!({ "signature_algorithm": "sha512" }))))
+        .await
+        .unwrap();
+    let rotated = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(rotated["signature_algorithm"], "sha512");
+    assert_eq!(rotated["secret"].as_str().unwrap().len(), 32);
+    assert_ne!(rotated["secret"], created["secret"]);
+    let response = app_service
+        .call(request("POST", format!("{}/rotate-secret", webhook_uri), Some(json!({}))))
+        .await
+        .unwrap();
+    let rotated = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(rotated["signature_algorithm"], "sha512");
+    let response = app_service
+        .call(request("POST", format!("{}/rotate-secret", webhook_uri), Some(json!({ "signature_algorithm": "md5" }))))
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    let response = app_service
+        .call(request("POST", format!("{}/missing/rotate-secret", webhooks_uri), Some(json!({}))))
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Not found: Webhook not found"));
+
+    // The test delivery reports the configured algorithm, whether or not the endpoint answered
+    let response = app_service.call(request("POST", format!("{}/test", webhook_uri), None)).await.unwrap();
+    let tested = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(tested["signature_algorithm"], "sha512");
+
+    // Templates are checked on creation and previewed with sample data
+    for template in ["{{ subject", "{{ body }}"] {
+        let response = app_service
+            .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": "https://203.0.113.10/hook", "payload_template": template }))))
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        assert!(result.error.unwrap().starts_with("Mail processing error: Invalid payload template"), "{} is refused", template);
+    }
+    let template = r#"{"text": "{{ from }}: {{ subject }}", "mailbox": "{{ mailbox_id }}"}"#;
+    let response = app_service
+        .call(request("POST", webhooks_uri.clone(), Some(json!({ "url": "https://203.0.113.10/hook", "payload_template": template }))))
+        .await
+        .unwrap();
+    let templated = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(templated["payload_template"], template);
+    let response = app_service
+        .call(request("POST", format!("{}/{}/preview-template", webhooks_uri, templated["id"].as_str().unwrap()), None))
+        .await
+        .unwrap();
+    let preview = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    let payload: serde_json::Value = serde_json::from_str(preview["payload"].as_str().unwrap()).unwrap();
+    assert_eq!(payload, json!({ "text": "sender@example.com: Sample subject", "mailbox": mailbox.id }));
+    // Without a template the default payload is previewed
+    let response = app_service.call(request("POST", format!("{}/preview-template", webhook_uri), None)).await.unwrap();
+    let preview = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    let payload: serde_json::Value = serde_json::from_str(preview["payload"].as_str().unwrap()).unwrap();
+    assert_eq!(payload["event"], "email.received");
+    let response = app_service
+        .call(request("DELETE", format!("{}/{}", webhooks_uri, templated["id"].as_str().unwrap()), None))
+        .await
+        .unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+
+    let response = app_service.call(request("DELETE", webhook_uri.clone(), None)).await.unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+    let response = app_service.call(request("DELETE", webhook_uri, None)).await.unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Not found: Webhook not found"));
+    let response = app_service.call(request("GET", webhooks_uri, None)).await.unwrap();
+    assert!(read_body::<ApiResponse<Vec<serde_json::Value>>>(response).await.data.unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn test_rate_limit_headers() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let get_domains = || {
+        Request::builder()
+            .uri("/api/supported-domains")
+            .header("Accept", "application/json")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+    let header = |response: &Response, name: &str| -> u64 { response.headers()[name].to_str().unwrap().parse().unwrap() };
+
+    // 100 per minute by default
+    let response = app_service.call(get_domains()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(header(&response, "X-RateLimit-Limit"), 100);
+    assert_eq!(header(&response, "X-RateLimit-Remaining"), 99);
+    assert_eq!(header(&response, "X-RateLimit-Reset"), 1);
+
+    // A user's own limit applies once their limiter is rebuilt, i.e. in a fresh app
+    db.update_user_settings(&common::UserSettings {
+        user_id,
+        email_notifications: true,
+        auto_delete_expired: true,
+        default_mailbox_expiry: None,
+        email_cleanup_policy: None,
+        email_count_limit: None,
+        total_storage_bytes_limit: None,
+        api_rate_limit_per_minute: Some(3),
+        max_mailboxes: None,
+    })
+    .await
+    .unwrap();
+    let mut app_service = create_app(db.clone()).into_service();
+    for remaining in (0..3).rev() {
+        let response = app_service.call(get_domains()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(header(&response, "X-RateLimit-Limit"), 3);
+        assert_eq!(header(&response, "X-RateLimit-Remaining"), remaining);
+        assert_eq!(header(&response, "X-RateLimit-Reset"), 20 * (3 - remaining));
+    }
+
+    let response = app_service.call(get_domains()).await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(header(&response, "X-RateLimit-Remaining"), 0);
+    let retry_after = header(&response, "Retry-After");
+    assert!(retry_after > 0 && retry_after <= 20);
+    assert!(header(&response, "X-RateLimit-Reset") <= 60);
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Rate limit exceeded"));
+}
+
+#[tokio::test]
+async fn test_export_mailbox_mbox() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Export").await;
+
+    // More than one batch of 50, saved out of order
+    for i in (0..60).rev() {
+        db.save_email(&Email {
+            id: format!("export-{:02}", i),
+            mailbox_id: mailbox.id.clone(),
+            encrypted_content: format!("-----BEGIN AGE ENCRYPTED FILE-----\nFrom the archive {}\n-----END AGE ENCRYPTED FILE-----", i),
+            received_at: 1_700_000_000 + i,
+            expires_at: None,
+            from_addr: "sender@example.com".to_string(),
+            to_addr: "inbox@example.com".to_string(),
+            subject: format!("Export {}", i),
+            headers_json: (i == 0).then(|| json!({ "message-id": "<original@example.com>" }).to_string()),
+            raw_size_bytes: 0,
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let mut export = |query: &str| {
+        app_service.call(
+            Request::builder()
+                .uri(format!("/api/mailboxes/{}/export?{}", mailbox.id, query))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let response = export("format=mbox").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/mbox");
+    assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"mailbox.mbox\"");
+    let bytes = BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+
+    let entries = mail_parser::mailbox::mbox::MessageIterator::new(&bytes[..])
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(entries.len(), 60);
+    for (i, entry) in entries.iter().enumerate() {
+        assert_eq!(entry.from(), "sender@example.com");
+        assert_eq!(entry.internal_date(), 1_700_000_000 + i as u64);
+
+        let message = mail_parser::Message::parse(entry.contents()).unwrap();
+        assert_eq!(message.subject(), Some(format!("Export {}", i).as_str()));
+        assert_eq!(message.date().unwrap().to_timestamp(), 1_700_000_000 + i as i64);
+        let expected_message_id = if i == 0 { "original@example.com".to_string() } else { format!("export-{:02}@vh-mail-hook", i) };
+        assert_eq!(message.message_id(), Some(expected_message_id.as_str()));
+        // The quoted "From " line is restored by the parser
+        assert!(String::from_utf8_lossy(entry.contents())
+            .contains(&format!("\nFrom the archive {}\n", i)));
+    }
+    assert!(String::from_utf8_lossy(&bytes).contains("\n>From the archive 0\n"));
+
+    let response = export("since=1700000010&until=1700000019").await.unwrap();
+    let bytes = BodyExt::collect(response.into_body()).await.unwrap().to_bytes();
+    let dates: Vec<u64> = mail_parser::mailbox::mbox::MessageIterator::new(&bytes[..])
+        .map(|entry| entry.unwrap().internal_date())
+        .collect();
+    assert_eq!(dates, (1_700_000_010..=1_700_000_019).collect::<Vec<_>>());
+
+    let result: ApiResponse<()> = read_body(export("format=json").await.unwrap()).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: Unsupported export format, only mbox is available"));
+}
+
+#[tokio::test]
+async fn test_get_mailbox_emails_in_range() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Range").await;
+
+    for i in 0..10 {
+        db.save_email(&Email {
+            id: format!("range-{}", i),
+            mailbox_id: mailbox.id.clone(),
+            encrypted_content: "encrypted".to_string(),
+            received_at: 1_700_000_000 + i * 100,
+            expires_at: None,
+            from_addr: if i % 2 == 0 { "even@example.com" } else { "odd@example.com" }.to_string(),
+            to_addr: "inbox@example.com".to_string(),
+            subject: format!("Range {}", i),
+            headers_json: None,
+            raw_size_bytes: 0,
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let in_range = db.get_mailbox_emails_in_range(&mailbox.id, Some(1_700_000_200), Some(1_700_000_500)).await.unwrap();
+    assert_eq!(
+        in_range.iter().map(|email| email.id.as_str()).collect::<Vec<_>>(),
+        ["range-5", "range-4", "range-3", "range-2"]
+    );
+    assert_eq!(db.get_mailbox_emails_in_range(&mailbox.id, None, None).await.unwrap().len(), 10);
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "scopes": ["read_emails"] }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result: ApiResponse<serde_json::Value> = read_body(response).await;
+    let api_key = result.data.unwrap()["key"].as_str().unwrap().to_string();
+
+    let mut list = |uri: String, token: &str| {
+        app_service.call(
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let ids = |emails: Vec<serde_json::Value>| {
+        emails.iter().map(|email| email["id"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+    };
+
+    // Bounds are inclusive, and either may be left out
+    for (query, expected) in [
+        ("since=1700000200&until=1700000500", vec!["range-5", "range-4", "range-3", "range-2"]),
+        ("since=1700000750", vec!["range-9", "range-8"]),
+        ("until=1700000100", vec!["range-1", "range-0"]),
+        ("since=1700000300&until=1700000300", vec!["range-3"]),
+        ("since=1700000950", vec![]),
+        // Combined with the other filters
+        ("since=1700000200&until=1700000500&from=odd", vec!["range-5", "range-3"]),
+    ] {
+        let response = list(format!("/api/mailboxes/{}/emails?{}", mailbox.id, query), &token).await.unwrap();
+        let result: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+        assert_eq!(ids(result.data.unwrap()), expected, "{}", query);
+    }
+
+    // Streaming applies the range and filters too
+    for (query, expected) in [
+        ("since=1700000200&until=1700000500", vec!["range-5", "range-4", "range-3", "range-2"]),
+        ("since=1700000200&until=1700000500&from=odd", vec!["range-5", "range-3"]),
+    ] {
+        let response = list(format!("/api/mailboxes/{}/emails?stream=true&{}", mailbox.id, query), &token).await.unwrap();
+        assert_eq!(response.headers()[header::CONTENT_TYPE], "application/x-ndjson");
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let emails = String::from_utf8_lossy(&body)
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(ids(emails), expected, "{}", query);
+    }
+    let response = list(format!("/api/mailboxes/{}/emails?stream=true&token=abc", mailbox.id), &token).await.unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+    // Metadata pages stay within the range
+    let response = list(
+        format!("/api/mailboxes/{}/emails?metadata=true&limit=2&since=1700000200&until=1700000500", mailbox.id),
+        &token,
+    )
+    .await
+    .unwrap();
+    let page: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    assert_eq!(ids(page.data.unwrap()), ["range-5", "range-4"]);
+    let response = list(
+        format!("/api/mailboxes/{}/emails?metadata=true&limit=2&cursor=range-4&since=1700000200&until=1700000500", mailbox.id),
+        &token,
+    )
+    .await
+    .unwrap();
+    let page: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    assert_eq!(ids(page.data.unwrap()), ["range-3", "range-2"]);
+
+    let response = list(format!("/api/mailboxes/{}/emails?since=1700000500&until=1700000200", mailbox.id), &token).await.unwrap();
+    let result: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: `since` must not be after `until`"));
+
+    // The external API takes the same range
+    let response = list(format!("/api/v1/mailboxes/{}/emails?since=1700000750", mailbox.id), &api_key).await.unwrap();
+    let result: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    assert_eq!(ids(result.data.unwrap()), ["range-9", "range-8"]);
+    let response = list(format!("/api/v1/mailboxes/{}/emails?since=2&until=1", mailbox.id), &api_key).await.unwrap();
+    let result: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mail processing error: `since` must not be after `until`"));
+}
+
+#[tokio::test]
+async fn test_mailbox_email_counts() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Counted").await;
+
+    let now = chrono::Utc::now().timestamp();
+    let email = |id: &str, received_at: i64| Email {
+        id: id.to_string(),
+        mailbox_id: mailbox.id.clone(),
+        encrypted_content: "encrypted".to_string(),
+        received_at,
+        expires_at: None,
+        from_addr: "sender@example.com".to_string(),
+        to_addr: "inbox@example.com".to_string(),
+        subject: "Hello".to_string(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    };
+    db.save_email(&email("count-1", now - 100)).await.unwrap();
+    db.save_email(&email("count-2", now - 50)).await.unwrap();
+
+    let mut request = |uri: String| {
+        app_service.call(
+            Request::builder()
+                .uri(uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let counts: ApiResponse<MailboxWithStats> = read_body(request(format!("/api/mailboxes/{}", mailbox.id)).await.unwrap()).await;
+    let counts = counts.data.unwrap();
+    assert_eq!(counts.mailbox.id, mailbox.id);
+    assert_eq!((counts.email_count, counts.unread_count), (2, 2));
+
+    let response = request("/api/mailboxes".to_string()).await.unwrap();
+    let etag = response.headers()["etag"].to_str().unwrap().to_string();
+    let listed = read_body::<ApiResponse<Vec<MailboxWithStats>>>(response).await.data.unwrap();
+    assert_eq!(listed.len(), 1);
+    assert_eq!((listed[0].email_count, listed[0].unread_count), (2, 2));
+
+    // Listing the emails resets the unread count
+    let emails: ApiResponse<Vec<Email>> = read_body(request(format!("/api/mailboxes/{}/emails", mailbox.id)).await.unwrap()).await;
+    assert_eq!(emails.data.unwrap().len(), 2);
+    let counts = read_body::<ApiResponse<MailboxWithStats>>(request(format!("/api/mailboxes/{}", mailbox.id)).await.unwrap()).await.data.unwrap();
+    assert_eq!((counts.email_count, counts.unread_count), (2, 0));
+
+    db.save_email(&email("count-3", now + 100)).await.unwrap();
+    let counts = read_body::<ApiResponse<MailboxWithStats>>(request(format!("/api/mailboxes/{}", mailbox.id)).await.unwrap()).await.data.unwrap();
+    assert_eq!((counts.email_count, counts.unread_count), (3, 1));
+
+    // The counts are part of the list, so its ETag changes with them
+    let response = request("/api/mailboxes".to_string()).await.unwrap();
+    assert_ne!(response.headers()["etag"].to_str().unwrap(), etag);
+    let listed = read_body::<ApiResponse<Vec<MailboxWithStats>>>(response).await.data.unwrap();
+    assert_eq!((listed[0].email_count, listed[0].unread_count), (3, 1));
+}
+
+#[tokio::test]
+async fn test_rotate_mailbox_alias() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Rotated").await;
+    assert_eq!(mailbox.alias_rotated_at, None);
+    db.save_email(&Email {
+        id: "rotated-email".to_string(),
+        mailbox_id: mailbox.id.clone(),
+        encrypted_content: "encrypted".to_string(),
+        received_at: chrono::Utc::now().timestamp(),
+        expires_at: None,
+        from_addr: "sender@example.com".to_string(),
+        to_addr: format!("{}@example.com", mailbox.alias),
+        subject: "Before rotation".to_string(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    })
+    .await
+    .unwrap();
+
+    let rotate = |uri: String, token: &str| {
+        Request::builder()
+            .method("POST")
+            .uri(uri)
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app_service.call(rotate(format!("/api/mailboxes/{}/rotate-alias", mailbox.id), &token)).await.unwrap();
+    let rotated = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    assert_eq!(rotated.id, mailbox.id);
+    assert_ne!(rotated.alias, mailbox.alias);
+    assert_eq!(rotated.alias.len(), mailbox.alias.len());
+    assert!(rotated.alias_rotated_at.is_some());
+
+    // The emails stay and only the new alias reaches the mailbox
+    let stored = db.get_mailbox(&mailbox.id).await.unwrap().unwrap();
+    assert_eq!(stored.alias, rotated.alias);
+    assert_eq!(stored.alias_rotated_at, rotated.alias_rotated_at);
+    assert_eq!(db.get_mailbox_emails(&mailbox.id).await.unwrap().len(), 1);
+    assert!(db.get_mailbox_by_address(&mailbox.alias).await.unwrap().is_none());
+    assert_eq!(db.get_mailbox_by_address(&rotated.alias).await.unwrap().unwrap().id, mailbox.id);
+    let now = chrono::Utc::now().timestamp();
+    assert!(db.is_alias_retired(&mailbox.alias, now).await.unwrap());
+    assert!(!db.is_alias_retired(&mailbox.alias, now + 24 * 60 * 60).await.unwrap());
+    assert!(!db.is_alias_retired(&rotated.alias, now).await.unwrap());
+
+    // The external API needs the manage_mailboxes scope
+    let mut api_keys = Vec::new();
+    for scopes in [json!(["read_emails"]), json!(["manage_mailboxes"])] {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/api-keys")
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::from(json!({ "scopes": scopes }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        api_keys.push(result.data.unwrap()["key"].as_str().unwrap().to_string());
+    }
+    let v1_uri = format!("/api/v1/mailboxes/{}/rotate-alias", mailbox.id);
+
+    let response = app_service.call(rotate(v1_uri.clone(), &api_keys[0])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = app_service.call(rotate(v1_uri, &api_keys[1])).await.unwrap();
+    let rotated_again = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    assert_ne!(rotated_again.alias, rotated.alias);
+    // Both earlier aliases stay retired
+    assert!(db.is_alias_retired(&mailbox.alias, now).await.unwrap());
+    assert!(db.is_alias_retired(&rotated.alias, now).await.unwrap());
+}
+
+#[tokio::test]
+async fn test_api_create_and_delete_mailbox() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut api_keys = Vec::new();
+    for scopes in [json!(["read_emails"]), json!(["manage_mailboxes"])] {
+        let response = app_service
+            .call(
+                Request::builder()
+                    .method("POST")
+                    .uri("/api/api-keys")
+                    .header("Content-Type", "application/json")
+                    .header("Authorization", format!("Bearer {}", token))
+                    .body(Body::from(json!({ "scopes": scopes }).to_string()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        let result: ApiResponse<serde_json::Value> = read_body(response).await;
+        api_keys.push(result.data.unwrap()["key"].as_str().unwrap().to_string());
+    }
+
+    let create = |body: serde_json::Value, key: &str| {
+        Request::builder()
+            .method("POST")
+            .uri("/api/v1/mailboxes")
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", key))
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    };
+    let remove = |id: &str, key: &str| {
+        Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/v1/mailboxes/{}", id))
+            .header("Authorization", format!("Bearer {}", key))
+            .body(Body::empty())
+            .unwrap()
+    };
+
+    let response = app_service.call(create(json!({ "public_key": TEST_PUBLIC_KEY }), &api_keys[0])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    // Validated like mailboxes created in the web app
+    for (body, error) in [
+        (json!({ "public_key": "not-a-key" }), "Invalid age public key format"),
+        (json!({ "public_key": TEST_PUBLIC_KEY, "expires_in_seconds": 0 }), "Expiration time must be positive"),
+        (json!({ "public_key": TEST_PUBLIC_KEY, "expires_in_seconds": 31 * 24 * 60 * 60 }), "Maximum expiration time is 30 days"),
+    ] {
+        let response = app_service.call(create(body, &api_keys[1])).await.unwrap();
+        let result: ApiResponse<Mailbox> = read_body(response).await;
+        assert!(!result.success);
+        assert_eq!(result.error.as_deref(), Some(error));
+    }
+
+    let response = app_service
+        .call(create(json!({ "public_key": TEST_PUBLIC_KEY, "name": "CI", "expires_in_seconds": 3600 }), &api_keys[1]))
+        .await
+        .unwrap();
+    let mailbox = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    assert_eq!(mailbox.owner_id, user_id);
+    assert_eq!(mailbox.name, "CI");
+    assert_eq!(mailbox.mail_expires_in, Some(3600));
+
+    let response = app_service.call(create(json!({ "public_key": TEST_PUBLIC_KEY }), &api_keys[1])).await.unwrap();
+    let unnamed = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    assert_eq!(unnamed.name, "");
+    assert_eq!(unnamed.mail_expires_in, None);
+
+    // Both show up in the web app
+    let response = app_service
+        .call(
+            Request::builder()
+                .uri("/api/mailboxes")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let listed: ApiResponse<Vec<serde_json::Value>> = read_body(response).await;
+    let ids: Vec<_> = listed.data.unwrap().iter().map(|m| m["id"].as_str().unwrap().to_string()).collect();
+    assert!(ids.contains(&mailbox.id));
+    assert!(ids.contains(&unnamed.id));
+
+    let response = app_service.call(remove(&mailbox.id, &api_keys[0])).await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(db.get_mailbox(&mailbox.id).await.unwrap().is_some());
+
+    let response = app_service.call(remove(&mailbox.id, &api_keys[1])).await.unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+    assert!(db.get_mailbox(&mailbox.id).await.unwrap().is_none());
+    assert!(db.get_mailbox(&unnamed.id).await.unwrap().is_some());
+
+    let response = app_service.call(remove(&mailbox.id, &api_keys[1])).await.unwrap();
+    let result: ApiResponse<()> = read_body(response).await;
+    assert_eq!(result.error.as_deref(), Some("Mailbox not found"));
+}
+
+#[tokio::test]
+async fn test_search_emails() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": "search-other", "password": TEST_PASSWORD }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+
+    let mut mailbox_ids = Vec::new();
+    for (name, token) in [("Bills", &token), ("Personal", &token), ("Other", &other_token)] {
+        mailbox_ids.push(create_test_mailbox(&mut app_service, token, name).await.id);
+    }
+
+    for (i, (mailbox, from_addr, subject)) in [
+        (0, "billing@acme.example", "Invoice for March"),
+        (1, "friend@example.org", "Holiday photos from March"),
+        (0, "billing@acme.example", "Invoice reminder"),
+        (1, "photos@example.org", "Photos of the holiday"),
+        (2, "billing@acme.example", "Invoice for another user"),
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        db.save_email(&Email {
+            id: format!("search-{}", i),
+            mailbox_id: mailbox_ids[mailbox].clone(),
+            encrypted_content: "encrypted".to_string(),
+            received_at: 1_700_000_000 + i as i64,
+            expires_at: None,
+            from_addr: from_addr.to_string(),
+            to_addr: "inbox@example.com".to_string(),
+            subject: subject.to_string(),
+            headers_json: None,
+            raw_size_bytes: 0,
+            message_id: None,
+        })
+        .await
+        .unwrap();
+    }
+
+    let mut search = |query: String, token: &str| {
+        app_service.call(
+            Request::builder()
+                .uri(format!("/api/emails/search?{}", query))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let q = |query: &str| format!("q={}", urlencoding::encode(query));
+    let ids = |result: ApiResponse<Vec<Email>>| -> Vec<String> {
+        result.data.unwrap().into_iter().map(|email| email.id).collect()
+    };
+
+    // Prefix search, newest first and only over the user's own mailboxes
+    let result = read_body(search(q("invo*"), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-2", "search-0"]);
+
+    // Phrase search matches the words in order only
+    let result = read_body(search(q("\"holiday photos\""), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-1"]);
+
+    let result = read_body(search(q("invoice AND march"), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-0"]);
+
+    // Every word of the query must match somewhere in From, To or Subject
+    let result = read_body(search(q("acme reminder"), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-2"]);
+
+    let result = read_body(search(format!("{}&mailbox_id={}", q("march"), mailbox_ids[1]), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-1"]);
+
+    let result = read_body(search(format!("{}&page=2&per_page=1", q("invo*")), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-0"]);
+
+    // Other users neither see these emails nor can target the mailbox
+    let result = read_body(search(q("invo*"), &other_token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-4"]);
+    let result: ApiResponse<Vec<Email>> =
+        read_body(search(format!("{}&mailbox_id={}", q("invo*"), mailbox_ids[0]), &other_token).await.unwrap()).await;
+    assert!(!result.success);
+
+    let result: ApiResponse<Vec<Email>> = read_body(search(q("\"unterminated"), &token).await.unwrap()).await;
+    assert!(result.error.unwrap().contains("Invalid search query"));
+
+    // Deleted emails leave the index
+    db.delete_email("search-2").await.unwrap();
+    let result = read_body(search(q("invo*"), &token).await.unwrap()).await;
+    assert_eq!(ids(result), ["search-0"]);
+}
+
+#[tokio::test]
+async fn test_update_expiry() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut request = |method: &str, uri: String, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    let response = request(
+        "POST",
+        "/api/mailboxes".to_string(),
+        json!({ "name": "Expiring", "expires_in_seconds": 3600, "public_key": TEST_PUBLIC_KEY }),
+    ).await.unwrap();
+    let mailbox = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+    assert_eq!(mailbox.mail_expires_in, Some(3600));
+
+    // Leaving the field out keeps the mailbox's expiry, null clears it
+    let response = request("PATCH", format!("/api/mailboxes/{}", mailbox.id), json!({ "name": "Renamed" })).await.unwrap();
+    assert_eq!(read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap().mail_expires_in, Some(3600));
+    let response = request("PATCH", format!("/api/mailboxes/{}", mailbox.id), json!({ "expires_in_seconds": null })).await.unwrap();
+    assert_eq!(read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap().mail_expires_in, None);
+    assert_eq!(db.get_mailbox(&mailbox.id).await.unwrap().unwrap().mail_expires_in, None);
+
+    let now = chrono::Utc::now().timestamp();
+    db.save_email(&Email {
+        id: "expiring-email".to_string(),
+        mailbox_id: mailbox.id.clone(),
+        encrypted_content: "encrypted".to_string(),
+        received_at: now,
+        expires_at: Some(now + 3600),
+        from_addr: "sender@example.com".to_string(),
+        to_addr: "inbox@example.com".to_string(),
+        subject: "Hello".to_string(),
+        headers_json: None,
+        raw_size_bytes: 0,
+        message_id: None,
+    }).await.unwrap();
+    let email_uri = format!("/api/mailboxes/{}/emails/expiring-email", mailbox.id);
+
+    // Extending and shortening count from now
+    for seconds in [7 * 24 * 60 * 60, 60] {
+        let response = request("PATCH", email_uri.clone(), json!({ "expires_in_seconds": seconds })).await.unwrap();
+        let email = read_body::<ApiResponse<Email>>(response).await.data.unwrap();
+        let stored = db.get_email("expiring-email").await.unwrap().unwrap().expires_at.unwrap();
+        assert_eq!(email.expires_at, Some(stored));
+        assert!((now + seconds..=now + seconds + 5).contains(&stored));
+    }
+
+    let response = request("PATCH", email_uri.clone(), json!({ "expires_in_seconds": 0 })).await.unwrap();
+    assert_eq!(read_body::<ApiResponse<Email>>(response).await.error.unwrap(), "Mail processing error: Expiration time must be positive");
+    let response = request("PATCH", email_uri.clone(), json!({ "expires_in_seconds": 31 * 24 * 60 * 60 })).await.unwrap();
+    assert_eq!(read_body::<ApiResponse<Email>>(response).await.error.unwrap(), "Mail processing error: Maximum expiration time is 30 days");
+
+    let response = request("PATCH", email_uri.clone(), json!({ "expires_in_seconds": null })).await.unwrap();
+    assert_eq!(read_body::<ApiResponse<Email>>(response).await.data.unwrap().expires_at, None);
+    assert_eq!(db.get_email("expiring-email").await.unwrap().unwrap().expires_at, None);
+
+    // Emails without an expiry survive the cleanup
+    db.cleanup_expired_emails().await.unwrap();
+    assert!(db.get_email("expiring-email").await.unwrap().is_some());
+
+    let response = request("PATCH", format!("/api/mailboxes/{}/emails/missing", mailbox.id), json!({ "expires_in_seconds": 60 })).await.unwrap();
+    assert!(!read_body::<ApiResponse<Email>>(response).await.success);
+}
+
+#[tokio::test]
+async fn test_user_mailbox_limit() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut request = |method: &str, uri: &str, admin_secret: Option<&str>, body: serde_json::Value| {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("Authorization", format!("Bearer {}", token));
+        if let Some(secret) = admin_secret {
+            builder = builder.header("X-Admin-Secret", secret);
+        }
+        app_service.call(builder.body(Body::from(body.to_string())).unwrap())
+    };
+    let mailbox = |i: usize| json!({ "name": format!("Mailbox {}", i), "public_key": TEST_PUBLIC_KEY });
+
+    for i in 0..20 {
+        let response = request("POST", "/api/mailboxes", None, mailbox(i)).await.unwrap();
+        assert!(read_body::<ApiResponse<Mailbox>>(response).await.success);
+    }
+    assert_eq!(db.count_user_mailboxes(&user_id).await.unwrap(), 20);
+
+    let response = request("POST", "/api/mailboxes", None, mailbox(20)).await.unwrap();
+    let response: ApiResponse<Mailbox> = read_body(response).await;
+    assert_eq!(response.error.unwrap(), "Mail processing error: Mailbox limit reached");
+    assert_eq!(db.count_user_mailboxes(&user_id).await.unwrap(), 20);
+
+    // Users change their preferences but not their limits
+    let response = request("PATCH", "/api/auth/settings", None, json!({ "email_notifications": false })).await.unwrap();
+    let settings = read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap();
+    assert!(!settings.email_notifications);
+    assert_eq!(settings.max_mailboxes, Some(UserSettings::DEFAULT_MAX_MAILBOXES));
+    // Even with the admin secret, limits are unknown fields there
+    for admin_secret in [None, Some(TEST_ADMIN_SECRET)] {
+        let response = request("PATCH", "/api/auth/settings", admin_secret, json!({ "max_mailboxes": 21 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+    assert_eq!(
+        db.get_user_settings(&user_id).await.unwrap().unwrap().max_mailboxes,
+        Some(UserSettings::DEFAULT_MAX_MAILBOXES)
+    );
+    // A cleanup policy cannot delete every email
+    for (policy, error) in [
+        (json!({ "max_age_seconds": 0 }), "max_age_seconds must be positive"),
+        (json!({ "max_count": 0 }), "max_count must be at least 1"),
+    ] {
+        let response = request("PATCH", "/api/auth/settings", None, json!({ "email_cleanup_policy": policy })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(read_body::<ApiResponse<UserSettings>>(response).await.error.as_deref(), Some(error));
+    }
+
+    // Admins set the limits through the admin API, keyed by user id
+    #[cfg(feature = "admin-api")]
+    {
+        let limits_uri = format!("/api/admin/users/{}/limits", user_id);
+        let response = request("PATCH", &limits_uri, None, json!({ "max_mailboxes": 21 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = request("PATCH", &limits_uri, Some("wrong-secret"), json!({ "max_mailboxes": 21 })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = request("PATCH", &limits_uri, Some(TEST_ADMIN_SECRET), json!({ "email_count_limit": -1 })).await.unwrap();
+        assert_eq!(read_body::<ApiResponse<UserSettings>>(response).await.error.as_deref(), Some("Limits can't be negative"));
+        let response = request("PATCH", "/api/admin/users/missing/limits", Some(TEST_ADMIN_SECRET), json!({ "max_mailboxes": 21 })).await.unwrap();
+        assert_eq!(read_body::<ApiResponse<UserSettings>>(response).await.error.as_deref(), Some("User not found"));
+
+        let response = request("PATCH", &limits_uri, Some(TEST_ADMIN_SECRET), json!({ "max_mailboxes": 21 })).await.unwrap();
+        let settings = read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap();
+        assert_eq!(settings.max_mailboxes, Some(21));
+        assert!(!settings.email_notifications);
+
+        let response = request("POST", "/api/mailboxes", None, mailbox(20)).await.unwrap();
+        assert!(read_body::<ApiResponse<Mailbox>>(response).await.success);
+        let response = request("POST", "/api/mailboxes", None, mailbox(21)).await.unwrap();
+        assert!(!read_body::<ApiResponse<Mailbox>>(response).await.success);
+
+        // Without a limit there is no check
+        let response = request("PATCH", &limits_uri, Some(TEST_ADMIN_SECRET), json!({ "max_mailboxes": null })).await.unwrap();
+        assert_eq!(read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap().max_mailboxes, None);
+        let response = request("POST", "/api/mailboxes", None, mailbox(21)).await.unwrap();
+        assert!(read_body::<ApiResponse<Mailbox>>(response).await.success);
+        assert_eq!(db.get_user_settings(&user_id).await.unwrap().unwrap().max_mailboxes, None);
+    }
+}
+
+#[tokio::test]
+async fn test_forwarding_rules() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": "other-user", "password": TEST_PASSWORD }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+
+    let mut request = |method: &str, uri: String, token: &str, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+    let new_mailbox = json!({ "name": "Forwarding", "public_key": TEST_PUBLIC_KEY });
+    let source = read_body::<ApiResponse<Mailbox>>(request("POST", "/api/mailboxes".into(), &token, new_mailbox.clone()).await.unwrap()).await.data.unwrap();
+    let destination = read_body::<ApiResponse<Mailbox>>(request("POST", "/api/mailboxes".into(), &token, new_mailbox.clone()).await.unwrap()).await.data.unwrap();
+    let foreign = read_body::<ApiResponse<Mailbox>>(request("POST", "/api/mailboxes".into(), &other_token, new_mailbox).await.unwrap()).await.data.unwrap();
+    let rules_uri = format!("/api/mailboxes/{}/rules", source.id);
+
+    let response = request("POST", rules_uri.clone(), &token, json!({
+        "kind": "webhook",
+        "destination": "https://203.0.113.10/forward",
+        "filter_subject": "*invoice*",
+    })).await.unwrap();
+    let webhook_rule = read_body::<ApiResponse<ForwardingRule>>(response).await.data.unwrap();
+    assert_eq!(webhook_rule.kind, ForwardingKind::Webhook);
+    assert_eq!(webhook_rule.filter_subject.as_deref(), Some("*invoice*"));
+    assert_eq!(webhook_rule.filter_from, None);
+
+    let response = request("POST", rules_uri.clone(), &token, json!({ "kind": "mailbox", "destination": destination.id, "filter_from": "" })).await.unwrap();
+    let mailbox_rule = read_body::<ApiResponse<ForwardingRule>>(response).await.data.unwrap();
+    assert_eq!(mailbox_rule.destination, destination.id);
+    assert_eq!(mailbox_rule.filter_from, None);
+
+    for (body, error) in [
+        (json!({ "kind": "webhook", "destination": "ftp://example.com" }), "URL must use http or https"),
+        (json!({ "kind": "webhook", "destination": "https://203.0.113.10", "filter_from": "[" }), "Invalid filter pattern: ["),
+        (json!({ "kind": "webhook", "destination": "http://127.0.0.1:8080/" }), "URL must not point to a private or local address"),
+        (json!({ "kind": "mailbox", "destination": source.id }), "A mailbox cannot forward to itself"),
+        (json!({ "kind": "mailbox", "destination": foreign.id }), "Destination mailbox not found"),
+        (json!({ "kind": "mailbox", "destination": "missing" }), "Destination mailbox not found"),
+    ] {
+        let response = request("POST", rules_uri.clone(), &token, body).await.unwrap();
+        let response: ApiResponse<ForwardingRule> = read_body(response).await;
+        assert_eq!(response.error.unwrap(), format!("Mail processing error: {}", error));
+    }
+
+    // Other users neither see nor change the rules
+    let response = request("GET", rules_uri.clone(), &other_token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<Vec<ForwardingRule>>>(response).await.success);
+    let response = request("DELETE", format!("{}/{}", rules_uri, webhook_rule.id), &other_token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<()>>(response).await.success);
+
+    let response = request("GET", rules_uri.clone(), &token, json!({})).await.unwrap();
+    let rules = read_body::<ApiResponse<Vec<ForwardingRule>>>(response).await.data.unwrap();
+    assert_eq!(rules.len(), 2);
+
+    let response = request("DELETE", format!("{}/{}", rules_uri, webhook_rule.id), &token, json!({})).await.unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+    let response = request("DELETE", format!("{}/{}", rules_uri, webhook_rule.id), &token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<()>>(response).await.success);
+    let response = request("GET", rules_uri, &token, json!({})).await.unwrap();
+    let rules = read_body::<ApiResponse<Vec<ForwardingRule>>>(response).await.data.unwrap();
+    assert_eq!(rules.len(), 1);
+    assert_eq!(rules[0].id, mailbox_rule.id);
+}
+
+#[tokio::test]
+async fn test_mailbox_keys() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": "key-outsider", "password": TEST_PASSWORD }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+
+    let mut request = |method: &str, uri: String, token: &str, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+    let mailbox = read_body::<ApiResponse<Mailbox>>(
+        request("POST", "/api/mailboxes".into(), &token, json!({ "name": "Team", "public_key": TEST_PUBLIC_KEY })).await.unwrap(),
+    ).await.data.unwrap();
+    let keys_uri = format!("/api/mailboxes/{}/keys", mailbox.id);
+
+    // New mailboxes list their primary key
+    let response = request("GET", keys_uri.clone(), &token, json!({})).await.unwrap();
+    let keys = read_body::<ApiResponse<Vec<MailboxKey>>>(response).await.data.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].label, MailboxKey::PRIMARY_LABEL);
+    assert_eq!(keys[0].public_key, TEST_PUBLIC_KEY);
+
+    let teammate_identity = age::x25519::Identity::generate();
+    let teammate_public_key = teammate_identity.to_public().to_string();
+    let response = request("POST", keys_uri.clone(), &token, json!({ "label": "teammate", "public_key": teammate_public_key })).await.unwrap();
+    let key = read_body::<ApiResponse<MailboxKey>>(response).await.data.unwrap();
+    assert_eq!(key.label, "teammate");
+    assert_eq!(key.key_type, KeyType::AgeX25519);
+
+    for (body, error) in [
+        (json!({ "label": "teammate", "public_key": TEST_PUBLIC_KEY }), "The mailbox already has a key labelled teammate"),
+        (json!({ "label": "primary", "public_key": TEST_PUBLIC_KEY }), "The mailbox already has a key labelled primary"),
+        (json!({ "label": "a/b", "public_key": TEST_PUBLIC_KEY }), "Key labels may only contain letters, digits, '-', '_' and '.'"),
+        (json!({ "label": "", "public_key": TEST_PUBLIC_KEY }), "Key labels must be 1 to 64 characters long"),
+        (
+            json!({ "label": "broken", "public_key": "not-a-key" }),
+            "Invalid public key: expected an age X25519 (age1...), ssh-ed25519 or ssh-rsa key",
+        ),
+    ] {
+        let response = request("POST", keys_uri.clone(), &token, body).await.unwrap();
+        let response: ApiResponse<MailboxKey> = read_body(response).await;
+        assert_eq!(response.error.unwrap(), format!("Mail processing error: {}", error));
+    }
+
+    // Other users neither see nor change the keys
+    let response = request("GET", keys_uri.clone(), &other_token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<Vec<MailboxKey>>>(response).await.success);
+    let response = request("POST", keys_uri.clone(), &other_token, json!({ "label": "intruder", "public_key": TEST_PUBLIC_KEY })).await.unwrap();
+    assert!(!read_body::<ApiResponse<MailboxKey>>(response).await.success);
+    let response = request("DELETE", format!("{}/teammate", keys_uri), &other_token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<()>>(response).await.success);
+
+    let response = request("GET", keys_uri.clone(), &token, json!({})).await.unwrap();
+    let keys = read_body::<ApiResponse<Vec<MailboxKey>>>(response).await.data.unwrap();
+    let labels: Vec<&str> = keys.iter().map(|key| key.label.as_str()).collect();
+    assert_eq!(labels, vec![MailboxKey::PRIMARY_LABEL, "teammate"]);
+
+    // Imported emails are encrypted to every key
+    let boundary = "mailbox-keys-boundary";
+    let email_content = "From: sender@example.com\r\nTo: team@test.com\r\nSubject: Shared\r\n\r\nHello team";
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri(format!("/api/mailboxes/{}/import-eml", mailbox.id))
+                .header("Content-Type", format!("multipart/form-data; boundary={}", boundary))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(format!(
+                    "--{b}\r\nContent-Disposition: form-data; name=\"files\"; filename=\"shared.eml\"\r\nContent-Type: message/rfc822\r\n\r\n{content}\r\n--{b}--\r\n",
+                    b = boundary,
+                    content = email_content,
+                )))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap()["imported"], 1);
+
+    let mut request = |method: &str, uri: String, token: &str, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+    let response = request("GET", format!("/api/mailboxes/{}/emails", mailbox.id), &token, json!({})).await.unwrap();
+    let emails = read_body::<ApiResponse<Vec<Email>>>(response).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    let teammate_secret_key = age::secrecy::ExposeSecret::expose_secret(&teammate_identity.to_string()).clone();
+    for secret_key in [TEST_SECRET_KEY, teammate_secret_key.as_str()] {
+        let decrypted = common::security::decrypt_email(&emails[0].encrypted_content, secret_key).unwrap();
+        assert_eq!(decrypted, email_content.as_bytes());
+    }
+
+    // The primary key follows the mailbox's public key and can't be removed
+    let response = request("PATCH", format!("/api/mailboxes/{}", mailbox.id), &token, json!({ "public_key": teammate_public_key })).await.unwrap();
+    assert!(read_body::<ApiResponse<Mailbox>>(response).await.success);
+    let response = request("DELETE", format!("{}/primary", keys_uri), &token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<()>>(response).await.success);
+
+    let response = request("DELETE", format!("{}/teammate", keys_uri), &token, json!({})).await.unwrap();
+    assert!(read_body::<ApiResponse<()>>(response).await.success);
+    let response = request("DELETE", format!("{}/teammate", keys_uri), &token, json!({})).await.unwrap();
+    assert!(!read_body::<ApiResponse<()>>(response).await.success);
+
+    let response = request("GET", keys_uri, &token, json!({})).await.unwrap();
+    let keys = read_body::<ApiResponse<Vec<MailboxKey>>>(response).await.data.unwrap();
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].label, MailboxKey::PRIMARY_LABEL);
+    assert_eq!(keys[0].public_key, teammate_public_key);
+}
+
+#[tokio::test]
+async fn test_audit_log() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut request = |method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("User-Agent", "audit-test/1.0")
+            .header("X-Forwarded-For", "203.0.113.7, 10.0.0.1")
+            .extension(proxy_peer());
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        app_service.call(builder.body(Body::from(body.to_string())).unwrap())
+    };
+
+    let login = json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD });
+    let response = request("POST", "/api/auth/login", None, login).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let wrong_login = json!({ "username": TEST_USERNAME, "password": "wrong-password" });
+    let response = request("POST", "/api/auth/login", None, wrong_login).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let mailbox = json!({ "name": "Audited", "public_key": TEST_PUBLIC_KEY });
+    let response = request("POST", "/api/mailboxes", Some(&token), mailbox).await.unwrap();
+    let mailbox = read_body::<ApiResponse<Mailbox>>(response).await.data.unwrap();
+
+    let response = request("GET", "/api/auth/audit-log", Some(&token), json!({})).await.unwrap();
+    let entries = read_body::<ApiResponse<Vec<AuditEntry>>>(response).await.data.unwrap();
+    let actions: Vec<&str> = entries.iter().map(|entry| entry.action.as_str()).collect();
+    assert_eq!(actions, ["create_mailbox", "login_failed", "login", "register"]);
+    assert!(entries.iter().all(|entry| entry.user_id == user_id));
+    assert!(entries.windows(2).all(|pair| pair[0].occurred_at >= pair[1].occurred_at));
+    assert_eq!(entries[0].resource_type.as_deref(), Some("mailbox"));
+    assert_eq!(entries[0].resource_id.as_deref(), Some(mailbox.id.as_str()));
+
+    let login = &entries[2];
+    assert_eq!(login.ip.as_deref(), Some("203.0.113.7"));
+    assert_eq!(login.user_agent.as_deref(), Some("audit-test/1.0"));
+    assert_eq!(login.metadata.as_ref().unwrap()["method"], "password");
+
+    let response = request("GET", "/api/auth/audit-log?page=2&per_page=1", Some(&token), json!({})).await.unwrap();
+    let entries = read_body::<ApiResponse<Vec<AuditEntry>>>(response).await.data.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, "login_failed");
+
+    // Users only see their own entries
+    let other = json!({ "username": "audit-other", "password": TEST_PASSWORD });
+    let response = request("POST", "/api/auth/register", None, other).await.unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+    let response = request("GET", "/api/auth/audit-log", Some(&other_token), json!({})).await.unwrap();
+    let entries = read_body::<ApiResponse<Vec<AuditEntry>>>(response).await.data.unwrap();
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].action, "register");
+
+    let response = request("GET", "/api/auth/audit-log", None, json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_security_log() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut request = |method: &str, uri: &str, token: Option<&str>, body: serde_json::Value| {
+        let mut builder = Request::builder()
+            .method(method)
+            .uri(uri)
+            .header("Content-Type", "application/json")
+            .header("X-Forwarded-For", "203.0.113.7")
+            .extension(proxy_peer());
+        if let Some(token) = token {
+            builder = builder.header("Authorization", format!("Bearer {}", token));
+        }
+        app_service.call(builder.body(Body::from(body.to_string())).unwrap())
+    };
+
+    let login = json!({ "username": TEST_USERNAME, "password": TEST_PASSWORD });
+    request("POST", "/api/auth/login", None, login).await.unwrap();
+    let wrong_login = json!({ "username": TEST_USERNAME, "password": "wrong-password" });
+    request("POST", "/api/auth/login", None, wrong_login).await.unwrap();
+
+    // Only successful logins are listed, unlocated without a GeoIP database
+    let response = request("GET", "/api/auth/security-log", Some(&token), json!({})).await.unwrap();
+    let events = read_body::<ApiResponse<Vec<LoginEvent>>>(response).await.data.unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].user_id, user_id);
+    assert_eq!(events[0].ip.as_deref(), Some("203.0.113.7"));
+    assert_eq!(events[0].country_code, None);
+
+    let response = request("GET", "/api/auth/security-log", None, json!({})).await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[cfg(feature = "admin-api")]
+#[tokio::test]
+async fn test_admin_login_geography() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (user_id, _) = create_test_user_with_auth(&mut app_service).await;
+
+    for country_code in ["DE", "DE", "JP"] {
+        let event = LoginEvent {
+            user_id: user_id.clone(),
+            ip: None,
+            country_code: Some(country_code.to_string()),
+            city: None,
+            logged_in_at: chrono::Utc::now().timestamp(),
+        };
+        db.record_login_event(&event).await.unwrap();
+    }
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("GET")
+                .uri("/api/admin/stats/login-geography")
+                .header("X-Admin-Secret", TEST_ADMIN_SECRET)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let geography = read_body::<ApiResponse<std::collections::HashMap<String, u64>>>(response).await.data.unwrap();
+    assert_eq!(geography.len(), 2);
+    assert_eq!(geography["DE"], 2);
+    assert_eq!(geography["JP"], 1);
+}
+
+#[tokio::test]
+async fn test_user_settings() {
+    setup();
+    let app = setup_test_app().await;
+    let mut app_service = app.into_service();
+    let (user_id, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mut request = |method: &str, body: serde_json::Value| {
+        app_service.call(
+            Request::builder()
+                .method(method)
+                .uri("/api/user/settings")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(body.to_string()))
+                .unwrap(),
+        )
+    };
+
+    // No row is needed to read the defaults
+    let response = request("GET", json!({})).await.unwrap();
+    let settings = read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap();
+    assert_eq!(settings.user_id, user_id);
+    assert!(settings.email_notifications);
+    assert!(settings.auto_delete_expired);
+    assert_eq!(settings.default_mailbox_expiry, None);
+
+    let update = json!({ "auto_delete_expired": false, "default_mailbox_expiry": 3600 });
+    let response = request("PATCH", update).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = request("GET", json!({})).await.unwrap();
+    let settings = read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap();
+    assert!(settings.email_notifications);
+    assert!(!settings.auto_delete_expired);
+    assert_eq!(settings.default_mailbox_expiry, Some(3600));
+
+    for expiry in [0, 31 * 24 * 60 * 60] {
+        let response = request("PATCH", json!({ "default_mailbox_expiry": expiry })).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    }
+
+    let response = request("PATCH", json!({ "default_mailbox_expiry": null })).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = request("GET", json!({})).await.unwrap();
+    let settings = read_body::<ApiResponse<UserSettings>>(response).await.data.unwrap();
+    assert_eq!(settings.default_mailbox_expiry, None);
+    assert!(!settings.auto_delete_expired);
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    setup();
+    let (_, db) = setup_test_app_with_db().await;
+    let shutdown = CancellationToken::new();
+    let app = create_app_with_shutdown(db, None, shutdown.clone()).route(
+        "/slow",
+        axum::routing::get(|| async {
+            tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+            "finished"
+        }),
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(web_app::serve(listener, app, shutdown.clone(), std::time::Duration::from_secs(5)));
+
+    let client = reqwest::Client::new();
+    let in_flight = tokio::spawn(client.get(format!("http://{}/slow", addr)).send());
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+    let shutdown_url = format!("http://{}/api/admin/shutdown", addr);
+    for token in ["wrong-token", TEST_ADMIN_SECRET] {
+        let response = client.post(&shutdown_url).bearer_auth(token).send().await.unwrap();
+        assert_eq!(response.status().as_u16(), StatusCode::UNAUTHORIZED.as_u16());
+    }
+    assert!(!shutdown.is_cancelled());
+
+    let response = client.post(&shutdown_url).bearer_auth(TEST_ADMIN_TOKEN).send().await.unwrap();
+    assert_eq!(response.status().as_u16(), StatusCode::OK.as_u16());
+    assert!(shutdown.is_cancelled());
+
+    // The request started before the shutdown still completes
+    let response = in_flight.await.unwrap().unwrap();
+    assert_eq!(response.status().as_u16(), StatusCode::OK.as_u16());
+    assert_eq!(response.text().await.unwrap(), "finished");
+
+    tokio::time::timeout(std::time::Duration::from_secs(5), server)
+        .await
+        .expect("Server should stop once drained")
+        .unwrap()
+        .unwrap();
+    assert!(client.get(format!("http://{}/slow", addr)).send().await.is_err());
+}
+
+#[tokio::test]
+async fn test_get_email_raw() {
+    setup();
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+    let (_, token) = create_test_user_with_auth(&mut app_service).await;
+
+    let mailbox = create_test_mailbox(&mut app_service, &token, "Raw").await;
+
+    let raw_email = b"From: sender@example.com\r\nTo: inbox@example.com\r\nSubject: Raw\r\n\r\nRaw body\r\n";
+    let encrypted_content = common::security::encrypt_email(raw_email, TEST_PUBLIC_KEY, KeyType::AgeX25519).unwrap();
+    db.save_email(&Email {
+        id: "raw-email".to_string(),
+        mailbox_id: mailbox.id.clone(),
+        encrypted_content,
+        received_at: chrono::Utc::now().timestamp(),
+        expires_at: None,
+        from_addr: "sender@example.com".to_string(),
+        to_addr: "inbox@example.com".to_string(),
+        subject: "Raw".to_string(),
+        headers_json: None,
+        raw_size_bytes: raw_email.len() as i64,
+        message_id: None,
+    })
+    .await
+    .unwrap();
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .uri(format!("/api/mailboxes/{}/emails/raw-email/raw", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/octet-stream");
+    assert_eq!(response.headers()[header::CONTENT_DISPOSITION], "attachment; filename=\"raw-email.age\"");
+    let ciphertext = response.into_body().collect().await.unwrap().to_bytes();
+    let encoded = base64::Engine::encode(&base64::engine::general_purpose::STANDARD, &ciphertext);
+    let decrypted = common::security::decrypt_email(&encoded, TEST_SECRET_KEY).unwrap();
+    assert_eq!(decrypted, raw_email);
+
+    // Another user cannot download the email
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": "raw-other", "password": TEST_PASSWORD }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(response).await.data.unwrap().token;
+    let response = app_service
+        .call(
+            Request::builder()
+                .uri(format!("/api/mailboxes/{}/emails/raw-email/raw", mailbox.id))
+                .header("Authorization", format!("Bearer {}", other_token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_ne!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_oauth_callback_rate_limit() {
+    setup();
+    let (_, db) = setup_test_app_with_db().await;
+    let app = create_app_with_rate_limits(db, RateLimitConfigs {
+        oauth_callback: vec![RateLimitRule::new(10, 60)].into(),
+        ..RateLimitConfigs::default()
+    });
+    let mut app_service = app.into_service();
+
+    let mut callback = |ip: &str| {
+        app_service.call(
+            Request::builder()
+                .uri("/api/auth/github/callback?code=invalid&state=forged")
+                .header("X-Forwarded-For", ip)
+                .extension(proxy_peer())
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    // A burst of forged callbacks from one address is cut off, others are unaffected
+    for _ in 0..10 {
+        let response = callback("198.51.100.7").await.unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    }
+    let response = callback("198.51.100.7").await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    assert!(response.headers().contains_key(header::RETRY_AFTER));
+    let response: ApiResponse<()> = read_body(response).await;
+    assert_eq!(response.error.as_deref(), Some("Rate limit exceeded"));
+    let response = callback("198.51.100.8").await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let mut telegram_verify = |ip: &str| {
+        let auth_data = json!({ "id": 1, "auth_date": 0, "hash": "forged", "action": "login" });
+        app_service.call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/telegram/verify")
+                .header("Content-Type", "application/json")
+                .header("X-Forwarded-For", ip)
+                .extension(proxy_peer())
+                .body(Body::from(auth_data.to_string()))
+                .unwrap(),
+        )
+    };
+    for _ in 0..10 {
+        let response = telegram_verify("198.51.100.9").await.unwrap();
+        assert_ne!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+    let response = telegram_verify("198.51.100.9").await.unwrap();
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_client_ip_behind_proxy() {
+    setup();
+    let (_, db) = setup_test_app_with_db().await;
+    let app = create_app_with_rate_limits(db, RateLimitConfigs {
+        oauth_callback: vec![RateLimitRule::new(2, 60)].into(),
+        ..RateLimitConfigs::default()
+    });
+    let mut app_service = app.into_service();
+
+    let mut callback = |peer: [u8; 4], forwarded_for: &str| {
+        app_service.call(
+            Request::builder()
+                .uri("/api/auth/github/callback?code=invalid&state=forged")
+                .header("X-Forwarded-For", forwarded_for)
+                .extension(ConnectInfo(SocketAddr::from((peer, 40000))))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    // Through the trusted proxy, each client has its own limit
+    for _ in 0..2 {
+        assert_eq!(callback([127, 0, 0, 1], "198.51.100.1").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(callback([127, 0, 0, 1], "198.51.100.1").await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+    // Even with a forged first hop, behind the proxies in 10.0.0.0/8
+    assert_eq!(
+        callback([127, 0, 0, 1], "192.0.2.1, 198.51.100.1, 10.0.0.2").await.unwrap().status(),
+        StatusCode::TOO_MANY_REQUESTS
+    );
+    assert_eq!(callback([127, 0, 0, 1], "198.51.100.2").await.unwrap().status(), StatusCode::UNAUTHORIZED);
+
+    // Other peers are counted by their own address, whatever they claim
+    for ip in ["198.51.100.3", "198.51.100.4"] {
+        assert_eq!(callback([203, 0, 113, 5], ip).await.unwrap().status(), StatusCode::UNAUTHORIZED);
+    }
+    assert_eq!(callback([203, 0, 113, 5], "198.51.100.5").await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn test_telegram_register_stores_chat_id() {
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    setup();
+    env::set_var("TELEGRAM_BOT_TOKEN", "test-bot-token");
+    let (app, db) = setup_test_app_with_db().await;
+    let mut app_service = app.into_service();
+
+    let auth_date = chrono::Utc::now().timestamp();
+    let data_check_string = format!("auth_date={}\nid=4242\nusername=telegram_user", auth_date);
+    let mut mac = Hmac::<Sha256>::new_from_slice(&Sha256::digest(b"test-bot-token")).unwrap();
+    mac.update(data_check_string.as_bytes());
+    let hash = hex::encode(mac.finalize().into_bytes());
+
+    let response = app_service
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/telegram/verify")
+                .header("Content-Type", "application/json")
+                .body(Body::from(
+                    json!({ "id": 4242, "username": "telegram_user", "auth_date": auth_date, "hash": hash, "action": "register" })
+                        .to_string(),
+                ))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let user_id = read_body::<AuthResponse>(response).await.user.id;
+
+    // Notifications of new emails go to the private chat with the bot
+    assert_eq!(db.get_telegram_chat_id(&user_id).await.unwrap().as_deref(), Some("4242"));
+}
@@ -0,0 +1,89 @@
+//! Helpers shared by the integration tests
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+};
+use common::{id::IdFormat, Mailbox};
+use http_body_util::BodyExt;
+use once_cell::sync::OnceCell;
+use serde_json::json;
+use tower::{Service, ServiceExt};
+use web_app::{init_config, ApiResponse, Config};
+
+pub const TEST_PUBLIC_KEY: &str = "age1creym8a9ncefdvplrqrfy7wf8k3fw2l7w5z7nwp03jgfyhc56gcqgq27cg";
+
+static TEST_CONFIG: OnceCell<()> = OnceCell::new();
+
+/// The config tests start from, overriding fields with struct update syntax
+pub fn test_config() -> Config {
+    Config {
+        database_path: ":memory:".to_string(),
+        bind_addr: "127.0.0.1:3000".to_string(),
+        web_app_url: "http://localhost:3000".to_string(),
+        supported_domains: vec!["test.example.com".to_string()],
+        email_id_format: IdFormat::Uuid,
+        shutdown_grace_period_secs: 30,
+        entropy_mailbox_id_length: 12,
+        entropy_alias_length: 12,
+        entropy_api_key_length: 32,
+        enable_search_index: false,
+        enable_response_compression: false,
+        compression_min_size_bytes: 1024,
+        allowed_http_methods: vec!["GET".into(), "POST".into(), "PUT".into(), "PATCH".into(), "DELETE".into(), "OPTIONS".into()],
+        disallow_delete_operations: false,
+        max_email_size: 10 * 1024 * 1024,
+        max_aliases_per_mailbox: 5,
+        circuit_breaker_failure_threshold: 5,
+        circuit_breaker_reset_timeout_secs: 60,
+        admin_secret: None,
+        admin_token: None,
+        rate_limit_mailbox_create_per_hour: 10,
+        rate_limit_email_delete_per_hour: 200,
+        rate_limit_auth_per_minute: 5,
+        rate_limit_oauth_callback_per_minute: 10,
+        trusted_proxies: vec![],
+        client_ip_header: "X-Forwarded-For".to_string(),
+        metrics_bind_addr: None,
+        migrate_only: false,
+        migration_dry_run: false,
+        disable_log_redaction: false,
+    }
+}
+
+/// Sets the global config; only the first call of a test binary takes effect
+pub fn init_test_config(config: Config) {
+    TEST_CONFIG.get_or_init(|| init_config(config));
+}
+
+/// Creates a mailbox encrypted to [`TEST_PUBLIC_KEY`] for the user behind `token`
+// Not every test binary creates mailboxes
+#[allow(dead_code)]
+pub async fn create_test_mailbox<S>(app: &mut S, token: &str, name: &str) -> Mailbox
+where
+    S: Service<Request<Body>, Response = Response> + Send,
+    S::Future: Send,
+    S::Error: std::fmt::Debug,
+{
+    let response = app
+        .ready()
+        .await
+        .unwrap()
+        .call(
+            Request::builder()
+                .method("POST")
+                .uri("/api/mailboxes")
+                .header("Content-Type", "application/json")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::from(json!({ "name": name, "public_key": TEST_PUBLIC_KEY }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    let bytes = response.into_body().collect().await.unwrap().to_bytes();
+    let response: ApiResponse<Mailbox> = serde_json::from_slice(&bytes).unwrap();
+    response.data.expect("Failed to create the test mailbox")
+}
@@ -1,3 +1,6 @@
+#[path = "common/mod.rs"]
+mod test_helpers;
+
 use axum::{
     response::Response,
     http::{Request, StatusCode},
@@ -8,8 +11,10 @@ use common::{
     db::SqliteDatabase, 
     Mailbox, 
     User, 
+    UserSettings,
     Email,
     security::decrypt_email,
+    id::IdFormat,
     AuthType,
 };
 use mail_service::{
@@ -19,28 +24,14 @@ use mail_service::{
 use serde_json::json;
 use std::{sync::Arc, net::IpAddr, time::Duration, path::PathBuf, env};
 use tower::ServiceExt;
-use web_app::{create_app, ApiResponse, Config, init_config};
+use web_app::{create_app, ApiResponse, Config};
+use test_helpers::{create_test_mailbox, init_test_config, test_config, TEST_PUBLIC_KEY};
 use http_body_util::BodyExt;
 use tracing::{info, error};
-use once_cell::sync::OnceCell;
 
-const TEST_PUBLIC_KEY: &str = "age1creym8a9ncefdvplrqrfy7wf8k3fw2l7w5z7nwp03jgfyhc56gcqgq27cg";
 const TEST_SECRET_KEY: &str = "AGE-SECRET-KEY-10Q6FGH2JQD9VS0ZM50KV7XVC8SAC50MM5DDH9DKWQR3RCSJKYM6QAX66U8";
 const TEST_USERNAME: &str = "test-user";
-const TEST_PASSWORD: &str = "test-password";
-
-static TEST_CONFIG: OnceCell<()> = OnceCell::new();
-
-fn init_test_config() {
-    TEST_CONFIG.get_or_init(|| {
-        init_config(Config {
-            database_path: ":memory:".to_string(),
-            bind_addr: "127.0.0.1:3000".to_string(),
-            web_app_url: "http://localhost:3000".to_string(),
-            supported_domains: vec!["test.example.com".to_string()],
-        });
-    });
-}
+const TEST_PASSWORD: &str = "Test-password1";
 
 async fn read_body<T>(response: Response) -> T 
 where
@@ -110,7 +101,7 @@ async fn test_complete_flow() -> anyhow::Result<()> {
     info!("Creating web app...");
     
     // Initialize config for tests
-    init_test_config();
+    init_test_config(Config { enable_search_index: true, ..test_config() });
     
     // Set up web app
     let app = create_app(db.clone());
@@ -177,6 +168,13 @@ async fn test_complete_flow() -> anyhow::Result<()> {
         greylist_delay: Duration::from_secs(1),
         enable_spf: false,
         enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
     };
 
     let service = MailService::with_mock_resolver(
@@ -221,6 +219,29 @@ async fn test_complete_flow() -> anyhow::Result<()> {
     let decrypted = decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY)?;
     assert_eq!(decrypted, email_content.as_bytes());
     println!("Decrypted email: {:?}", decrypted);
+
+    // The same emails as newline-delimited JSON
+    let stream_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails?stream=true", mailbox.id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+
+    assert_eq!(stream_response.headers()["content-type"], "application/x-ndjson");
+    let body = BodyExt::collect(stream_response.into_body()).await.unwrap().to_bytes();
+    let streamed: Vec<Email> = String::from_utf8_lossy(&body)
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(streamed.len(), 1);
+    assert_eq!(streamed[0].id, emails[0].id);
     
     // Delete the email
     let delete_email_response = app
@@ -257,4 +278,603 @@ async fn test_complete_flow() -> anyhow::Result<()> {
     assert!(emails.is_empty());
     
     Ok(())
-} 
\ No newline at end of file
+} 
+#[tokio::test]
+async fn test_filter_emails_by_headers() -> anyhow::Result<()> {
+    setup();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(Config { enable_search_index: true, ..test_config() });
+    let app = create_app(db.clone());
+
+    let register_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "filter-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let token = read_body::<ApiResponse<AuthResponse>>(register_response).await.data.unwrap().token;
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Filtered").await;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+
+    let address = mailbox.get_address("test.example.com");
+    let mut sizes = Vec::new();
+    for (from, subject) in [
+        ("Alice <alice@example.com>", "Invoice 100%"),
+        ("bob@example.net", "Weekly report"),
+        ("carol@example.com", "Invoice reminder"),
+    ] {
+        let email = format!("From: {}\r\nTo: {}\r\nSubject: {}\r\n\r\nBody.", from, address, subject);
+        service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>()?).await?;
+        sizes.push(email.len() as i64);
+    }
+
+    let list = |query: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails?{}", mailbox.id, query))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list("subject=invoice").await?).await.data.unwrap();
+    assert_eq!(emails.len(), 2);
+    assert!(emails.iter().all(|email| email.subject.starts_with("Invoice")));
+
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list("from=example.com&subject=invoice").await?).await.data.unwrap();
+    assert_eq!(emails.len(), 2);
+
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list("from=ALICE%40").await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].from_addr, "alice@example.com");
+    assert_eq!(emails[0].to_addr, address);
+    assert_eq!(emails[0].subject, "Invoice 100%");
+    let decrypted = decrypt_email(&emails[0].encrypted_content, TEST_SECRET_KEY)?;
+    assert!(String::from_utf8(decrypted)?.contains("Subject: Invoice 100%"));
+
+    // LIKE wildcards are matched literally
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list("subject=100%25").await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list("subject=%25report").await?).await.data.unwrap();
+    assert!(emails.is_empty());
+
+    // Size bounds are inclusive and combine with the header filters
+    let (alice, bob, carol) = (sizes[0], sizes[1], sizes[2]);
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list(&format!("min_size={}", alice)).await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].from_addr, "alice@example.com");
+    assert_eq!(emails[0].raw_size_bytes, alice);
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list(&format!("max_size={}", bob)).await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].from_addr, "bob@example.net");
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list(&format!("min_size={}&max_size={}", bob + 1, alice - 1)).await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].from_addr, "carol@example.com");
+    let emails = read_body::<ApiResponse<Vec<Email>>>(list(&format!("subject=invoice&max_size={}", carol)).await?).await.data.unwrap();
+    assert_eq!(emails.len(), 1);
+    assert_eq!(emails[0].from_addr, "carol@example.com");
+
+    let response = app.clone().oneshot(
+        Request::builder()
+            .uri(format!("/api/mailboxes/{}/emails/{}", mailbox.id, emails[0].id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap(),
+    ).await?;
+    let email: serde_json::Value = read_body(response).await;
+    assert_eq!(email["data"]["size_bytes"], carol);
+
+    assert_eq!(db.get_mailbox_total_size(&mailbox.id).await?, alice + bob + carol);
+    let response = app.clone().oneshot(
+        Request::builder()
+            .uri("/api/user/storage-stats")
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap(),
+    ).await?;
+    let stats: serde_json::Value = read_body(response).await;
+    assert_eq!(stats["data"]["total_emails"], 3);
+    assert_eq!(stats["data"]["total_bytes"], alice + bob + carol);
+    assert_eq!(stats["data"]["by_mailbox"], json!([
+        { "mailbox_id": mailbox.id, "email_count": 3, "total_bytes": alice + bob + carol },
+    ]));
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_live_email_notifications() -> anyhow::Result<()> {
+    use futures::StreamExt;
+    use tokio_tungstenite::tungstenite::Message;
+
+    setup();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(Config { enable_search_index: true, ..test_config() });
+    let app = create_app(db.clone());
+
+    let register_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "live-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let token = read_body::<ApiResponse<AuthResponse>>(register_response).await.data.unwrap().token;
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Live").await;
+
+    // WebSockets need a real connection rather than `oneshot`
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let live_url = format!("ws://{}/api/mailboxes/{}/live", addr, mailbox.id);
+    assert!(tokio_tungstenite::connect_async(format!("{}?token=invalid", live_url)).await.is_err());
+    let (mut socket, _) = tokio_tungstenite::connect_async(format!("{}?token={}", live_url, token)).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+
+    let address = mailbox.get_address("test.example.com");
+    let email = format!("From: sender@example.com\r\nTo: {}\r\nSubject: Live\r\n\r\nBody.", address);
+    service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>()?).await?;
+
+    let frame = tokio::time::timeout(Duration::from_millis(500), socket.next())
+        .await?
+        .expect("connection closed")?;
+    let Message::Text(frame) = frame else {
+        panic!("expected a text frame, got {:?}", frame);
+    };
+    let event: common::events::EmailEvent = serde_json::from_str(&frame)?;
+    assert_eq!(event.mailbox_id, mailbox.id);
+
+    let emails = db.get_mailbox_emails(&mailbox.id).await?;
+    assert_eq!(emails.len(), 1);
+    assert_eq!(event.email_id, emails[0].id);
+    assert_eq!(event.received_at, emails[0].received_at);
+
+    socket.close(None).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_email_event_stream() -> anyhow::Result<()> {
+    setup();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(Config { enable_search_index: true, ..test_config() });
+    let app = create_app(db.clone());
+
+    let register_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "events-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let token = read_body::<ApiResponse<AuthResponse>>(register_response).await.data.unwrap().token;
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Events").await;
+    let events_uri = format!("/api/mailboxes/{}/events", mailbox.id);
+
+    let unauthorized = app
+        .clone()
+        .oneshot(Request::builder().uri(&events_uri).body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(unauthorized.status(), StatusCode::UNAUTHORIZED);
+
+    // `EventSource` can't set headers, so the token may be a query parameter
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(format!("{}?authorization=Bearer%20{}", events_uri, token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()["content-type"], "text/event-stream");
+    let mut stream = response.into_body();
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+
+    let address = mailbox.get_address("test.example.com");
+    let email = format!("From: sender@example.com\r\nTo: {}\r\nSubject: First\r\n\r\nBody.", address);
+    service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>()?).await?;
+
+    let frame = tokio::time::timeout(Duration::from_millis(500), stream.frame())
+        .await?
+        .expect("stream ended")
+        .map_err(|e| anyhow::anyhow!(e))?;
+    let frame = String::from_utf8(frame.into_data().unwrap().to_vec())?;
+    let first_id = db.get_mailbox_emails(&mailbox.id).await?[0].id.clone();
+    assert!(frame.starts_with("event: new_email\n"), "unexpected frame {:?}", frame);
+    assert!(frame.contains(&format!("id: {}\n", first_id)));
+    assert!(frame.contains(&format!("\"email_id\":\"{}\"", first_id)));
+
+    // A client reconnecting after a gap receives the events it missed
+    drop(stream);
+    let email = format!("From: sender@example.com\r\nTo: {}\r\nSubject: Second\r\n\r\nBody.", address);
+    service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>()?).await?;
+    let second_id = db.get_mailbox_emails(&mailbox.id).await?
+        .into_iter()
+        .find(|email| email.id != first_id)
+        .unwrap()
+        .id;
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .uri(&events_uri)
+                .header("Authorization", format!("Bearer {}", token))
+                .header("Last-Event-ID", &first_id)
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let mut stream = response.into_body();
+    let frame = tokio::time::timeout(Duration::from_millis(500), stream.frame())
+        .await?
+        .expect("stream ended")
+        .map_err(|e| anyhow::anyhow!(e))?;
+    let frame = String::from_utf8(frame.into_data().unwrap().to_vec())?;
+    assert!(frame.contains(&format!("id: {}\n", second_id)), "unexpected frame {:?}", frame);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_storage_quota() -> anyhow::Result<()> {
+    setup();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(Config { enable_search_index: true, ..test_config() });
+    let app = create_app(db.clone());
+
+    let register_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "quota-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let auth = read_body::<ApiResponse<AuthResponse>>(register_response).await.data.unwrap();
+    let token = auth.token;
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Quota").await;
+
+    let mut settings = UserSettings {
+        user_id: auth.user.id.clone(),
+        email_notifications: true,
+        auto_delete_expired: true,
+        default_mailbox_expiry: None,
+        email_cleanup_policy: None,
+        email_count_limit: Some(1),
+        total_storage_bytes_limit: None,
+        api_rate_limit_per_minute: None,
+        max_mailboxes: None,
+    };
+    db.update_user_settings(&settings).await?;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+
+    let address = mailbox.get_address("test.example.com");
+    let email = format!("From: sender@example.com\r\nTo: {}\r\nSubject: Quota\r\n\r\nBody.", address);
+    let deliver = || service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>().unwrap());
+
+    deliver().await?;
+    let error = deliver().await.unwrap_err();
+    assert_eq!(error.to_string(), "Mail processing error: Storage quota exceeded");
+
+    let stats = |token: &str| {
+        app.clone().oneshot(
+            Request::builder()
+                .method("GET")
+                .uri("/api/user/stats")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+    };
+    let response = stats(&token).await.unwrap();
+    let usage = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(usage["email_count"], 1);
+    assert_eq!(usage["email_count_limit"], 1);
+    assert!(usage["total_bytes_limit"].is_null());
+    let total_bytes = usage["total_bytes"].as_i64().unwrap();
+    assert_eq!(total_bytes, db.get_mailbox_emails(&mailbox.id).await?[0].encrypted_content.len() as i64);
+
+    // The byte limit counts the encrypted size of the new email too
+    settings.email_count_limit = None;
+    settings.total_storage_bytes_limit = Some(total_bytes + 10);
+    db.update_user_settings(&settings).await?;
+    let error = deliver().await.unwrap_err();
+    assert_eq!(error.to_string(), "Mail processing error: Storage quota exceeded");
+
+    settings.total_storage_bytes_limit = Some(total_bytes * 3);
+    db.update_user_settings(&settings).await?;
+    deliver().await?;
+    assert_eq!(db.get_mailbox_emails(&mailbox.id).await?.len(), 2);
+
+    Ok(())
+}
+
+#[tokio::test]
+async fn test_email_headers_round_trip() -> anyhow::Result<()> {
+    setup();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(Config { enable_search_index: true, ..test_config() });
+    let app = create_app(db.clone());
+
+    let register_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "headers-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let token = read_body::<ApiResponse<AuthResponse>>(register_response).await.data.unwrap().token;
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Headers").await;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+
+    let address = mailbox.get_address("test.example.com");
+    let email = format!(
+        "Received: from mx.example.com by test.example.com\r\n\
+         Authentication-Results: test.example.com; spf=pass\r\n\
+         From: Sender <sender@example.com>\r\n\
+         To: {}\r\n\
+         Subject: Headers\r\n\
+         Date: Mon, 1 Jan 2024 00:00:00 +0000\r\n\
+         Message-ID: <headers@example.com>\r\n\
+         X-Campaign: spring\r\n\
+         Content-Type: text/plain\r\n\
+         \r\n\
+         Body.",
+        address
+    );
+    service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", "192.168.1.1".parse::<IpAddr>().unwrap()).await?;
+    let email_id = db.get_mailbox_emails(&mailbox.id).await?[0].id.clone();
+
+    let expected = json!({
+        "from": "Sender <sender@example.com>",
+        "to": address,
+        "subject": "Headers",
+        "date": "Mon, 1 Jan 2024 00:00:00 +0000",
+        "message-id": "<headers@example.com>",
+        "x-campaign": "spring",
+    });
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails/{}/headers", mailbox.id, email_id))
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let headers = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(headers, expected);
+
+    let create_key_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/api-keys")
+                .header("Authorization", format!("Bearer {}", token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let api_key = read_body::<ApiResponse<serde_json::Value>>(create_key_response).await.data.unwrap();
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/v1/mailboxes/{}/emails/{}/headers", mailbox.id, email_id))
+                .header("Authorization", format!("Bearer {}", api_key["key"].as_str().unwrap()))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let headers = read_body::<ApiResponse<serde_json::Value>>(response).await.data.unwrap();
+    assert_eq!(headers, expected);
+
+    // Other users can't read the headers
+    let other_response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({
+                    "username": "headers-other-user",
+                    "password": TEST_PASSWORD,
+                    "auth_type": AuthType::Password
+                }).to_string()))
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let other_token = read_body::<ApiResponse<AuthResponse>>(other_response).await.data.unwrap().token;
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("GET")
+                .uri(format!("/api/mailboxes/{}/emails/{}/headers", mailbox.id, email_id))
+                .header("Authorization", format!("Bearer {}", other_token))
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await
+        .unwrap();
+    let result = read_body::<ApiResponse<serde_json::Value>>(response).await;
+    assert!(!result.success);
+    assert!(result.data.is_none());
+
+    Ok(())
+}
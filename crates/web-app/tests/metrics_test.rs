@@ -0,0 +1,140 @@
+#![cfg(feature = "prometheus")]
+
+#[path = "common/mod.rs"]
+mod test_helpers;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use common::{db::Database, db::SqliteDatabase, id::IdFormat, AuthType};
+use http_body_util::BodyExt;
+use mail_service::{MailService, ServiceConfig};
+use serde_json::json;
+use std::{net::IpAddr, sync::Arc, time::Duration};
+use tower::ServiceExt;
+use web_app::{create_app, prometheus::metrics_router};
+use test_helpers::{create_test_mailbox, init_test_config, test_config};
+
+async fn body_bytes(response: Response) -> Vec<u8> {
+    response.into_body().collect().await.unwrap().to_bytes().to_vec()
+}
+
+async fn call(app: &Router, request: Request<Body>) -> serde_json::Value {
+    let response = app.clone().oneshot(request).await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    serde_json::from_slice(&body_bytes(response).await).unwrap()
+}
+
+/// The value of the sample of `name` having exactly `labels`
+fn sample(metrics: &str, name: &str, labels: &[(&str, &str)]) -> Option<f64> {
+    metrics.lines().find_map(|line| {
+        let (series, value) = line.rsplit_once(' ')?;
+        let (series_name, series_labels) = match series.split_once('{') {
+            Some((series_name, series_labels)) => (series_name, series_labels.strip_suffix('}')?),
+            None => (series, ""),
+        };
+        let mut series_labels: Vec<&str> = series_labels.split(',').filter(|label| !label.is_empty()).collect();
+        let mut expected: Vec<String> = labels.iter().map(|(key, value)| format!("{}=\"{}\"", key, value)).collect();
+        series_labels.sort_unstable();
+        expected.sort_unstable();
+        (series_name == name && series_labels == expected).then(|| value.parse().unwrap())
+    })
+}
+
+#[tokio::test]
+async fn test_metrics_after_email_flow() -> anyhow::Result<()> {
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    let db = Arc::new(db);
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(test_config());
+    let metrics_app = metrics_router(db.clone())?;
+    let app = create_app(db.clone());
+
+    let auth = call(
+        &app,
+        Request::builder()
+            .method("POST")
+            .uri("/api/auth/register")
+            .header("Content-Type", "application/json")
+            .body(Body::from(json!({
+                "username": "metrics-user",
+                "password": "Test-password1",
+                "auth_type": AuthType::Password
+            }).to_string()))
+            .unwrap(),
+    )
+    .await;
+    let token = auth["data"]["token"].as_str().unwrap().to_string();
+
+    let mailbox = create_test_mailbox(&mut app.clone(), &token, "Metrics").await;
+
+    let config = ServiceConfig {
+        blocked_networks: vec![],
+        max_email_size: 1024 * 1024,
+        rate_limit_per_hour: 100,
+        enable_greylisting: false,
+        greylist_delay: Duration::from_secs(1),
+        enable_spf: false,
+        enable_dkim: false,
+        email_id_format: IdFormat::Uuid,
+        api_key_usage_retention_days: 30,
+        enable_search_index: false,
+        blocked_dnsbls: vec![],
+        supported_domains: vec!["test.example.com".to_string()],
+        max_smtp_connections: 100,
+        trusted_proxies: vec![],
+    };
+    let service = MailService::with_mock_resolver(db.clone(), config, vec![]).await?;
+    let ip: IpAddr = "192.168.1.1".parse()?;
+
+    let address = mailbox.get_address("test.example.com");
+    let email = format!("From: sender@example.com\r\nTo: {}\r\nSubject: Metrics\r\n\r\nBody.", address);
+    for _ in 0..2 {
+        service.process_incoming_email(email.as_bytes(), &address, "sender@example.com", ip).await?;
+    }
+    assert!(service
+        .process_incoming_email(email.as_bytes(), "unknown@test.example.com", "sender@example.com", ip)
+        .await
+        .is_err());
+
+    let email_id = db.get_mailbox_emails(&mailbox.id).await?[0].id.clone();
+    call(
+        &app,
+        Request::builder()
+            .method("DELETE")
+            .uri(format!("/api/mailboxes/{}/emails/{}", mailbox.id, email_id))
+            .header("Authorization", format!("Bearer {}", token))
+            .body(Body::empty())
+            .unwrap(),
+    )
+    .await;
+
+    let response = metrics_app
+        .oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap())
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let metrics = String::from_utf8(body_bytes(response).await)?;
+
+    assert_eq!(sample(&metrics, "emails_received_total", &[("mailbox_id", &mailbox.id), ("status", "ok")]), Some(2.0));
+    assert_eq!(sample(&metrics, "emails_received_total", &[("mailbox_id", ""), ("status", "rejected")]), Some(1.0));
+    assert_eq!(sample(&metrics, "emails_deleted_total", &[]), Some(1.0));
+    assert_eq!(sample(&metrics, "mailboxes_active", &[]), Some(1.0));
+
+    // Requests are labelled with their route, not the IDs in their path
+    let mailbox_requests = [("method", "POST"), ("path", "/api/mailboxes"), ("status", "200")];
+    assert_eq!(sample(&metrics, "api_requests_total", &mailbox_requests), Some(1.0));
+    let delete_requests = [("method", "DELETE"), ("path", "/api/mailboxes/:id/emails/:email_id"), ("status", "200")];
+    assert_eq!(sample(&metrics, "api_requests_total", &delete_requests), Some(1.0));
+    assert_eq!(
+        sample(&metrics, "api_request_duration_seconds_count", &[("method", "DELETE"), ("path", "/api/mailboxes/:id/emails/:email_id")]),
+        Some(1.0)
+    );
+    assert_eq!(sample(&metrics, "db_query_duration_seconds_count", &[("method", "save_email")]), Some(2.0));
+
+    Ok(())
+}
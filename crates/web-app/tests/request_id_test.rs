@@ -0,0 +1,112 @@
+#[path = "common/mod.rs"]
+mod test_helpers;
+
+use axum::{
+    body::Body,
+    http::{Request, StatusCode},
+    response::Response,
+    Router,
+};
+use common::{db::Database, db::SqliteDatabase};
+use http_body_util::BodyExt;
+use serde_json::json;
+use std::{
+    io,
+    sync::{Arc, Mutex},
+};
+use tower::ServiceExt;
+use web_app::create_app;
+use test_helpers::{init_test_config, test_config};
+
+/// Collects everything logged, the global subscriber writing to it
+#[derive(Clone, Default)]
+struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl io::Write for LogBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+async fn call(app: &Router, request_id: Option<&str>, token: Option<&str>) -> Response {
+    let mut request = Request::builder().uri("/api/mailboxes");
+    if let Some(request_id) = request_id {
+        request = request.header("X-Request-ID", request_id);
+    }
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    app.clone().oneshot(request.body(Body::empty()).unwrap()).await.unwrap()
+}
+
+fn request_id(response: &Response) -> String {
+    response.headers()["X-Request-ID"].to_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn test_request_id() -> anyhow::Result<()> {
+    let logs = LogBuffer::default();
+    let writer = logs.clone();
+    tracing_subscriber::fmt()
+        .with_writer(move || writer.clone())
+        .with_env_filter("debug")
+        .with_ansi(false)
+        .init();
+
+    let db = SqliteDatabase::new_in_memory().await?;
+    db.init().await?;
+    std::env::set_var("JWT_SECRET", "test-secret-key");
+    init_test_config(test_config());
+    let app = create_app(Arc::new(db));
+
+    let response = app
+        .clone()
+        .oneshot(
+            Request::builder()
+                .method("POST")
+                .uri("/api/auth/register")
+                .header("Content-Type", "application/json")
+                .body(Body::from(json!({ "username": "traced-user", "password": "Test-password1" }).to_string()))
+                .unwrap(),
+        )
+        .await?;
+    let auth: serde_json::Value = serde_json::from_slice(&response.into_body().collect().await?.to_bytes())?;
+    let token = auth["data"]["token"].as_str().unwrap();
+    let user_id = auth["data"]["user"]["id"].as_str().unwrap();
+
+    // Generated when missing or invalid, echoed otherwise
+    let response = call(&app, None, Some(token)).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let generated = request_id(&response);
+    assert!(uuid::Uuid::parse_str(&generated).is_ok());
+    let replaced = request_id(&call(&app, Some("not valid"), Some(token)).await);
+    assert!(uuid::Uuid::parse_str(&replaced).is_ok());
+    let echoed = request_id(&call(&app, Some("edge-42.abc"), Some(token)).await);
+    assert_eq!(echoed, "edge-42.abc");
+
+    // Rejected requests carry one too
+    let response = call(&app, Some("unauthenticated-1"), None).await;
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    assert_eq!(request_id(&response), "unauthenticated-1");
+
+    // The user is only known once the request is authenticated
+    let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+    let completed = |request_id: &str| {
+        logs.lines()
+            .find(|line| line.contains(&format!("request_id={}", request_id)) && line.contains("Request completed"))
+            .unwrap_or_else(|| panic!("no log line with request ID {}", request_id))
+    };
+    for request_id in [&generated, &replaced, &echoed] {
+        let line = completed(request_id);
+        assert!(line.contains(&format!("user_id={}", user_id)), "{}", line);
+    }
+    let line = completed("unauthenticated-1");
+    assert!(!line.contains("user_id="), "{}", line);
+
+    Ok(())
+}
@@ -1,6 +1,6 @@
 import fetch from 'cross-fetch';
 import * as age from 'age-encryption';
-import { VHMailHookConfig, Email, EmailListResponse, EmailResponse, DecryptedEmail } from './types.js';
+import { VHMailHookConfig, Email, EmailListResponse, EmailResponse, DecryptedEmail, DeleteEmailsOptions, DeleteEmailsResponse } from './types.js';
 
 export class VHMailHookClient {
   private domain: string;
@@ -11,13 +11,15 @@ export class VHMailHookClient {
     this.apiKey = config.apiKey;
   }
 
-  private async request<T>(path: string, method: 'GET' | 'DELETE' = 'GET'): Promise<T> {
+  private async request<T>(path: string, method: 'GET' | 'DELETE' = 'GET', body?: unknown): Promise<T> {
     const response = await fetch(`${this.domain}${path}`, {
       method,
       headers: {
         'Authorization': `Bearer ${this.apiKey}`,
-        'Accept': 'application/json'
-      }
+        'Accept': 'application/json',
+        ...(body !== undefined && { 'Content-Type': 'application/json' })
+      },
+      body: body !== undefined ? JSON.stringify(body) : undefined
     });
 
     if (!response.ok) {
@@ -77,4 +79,15 @@ export class VHMailHookClient {
   async deleteEmail(mailboxId: string, emailId: string): Promise<void> {
     await this.request(`/api/v1/mailboxes/${mailboxId}/emails/${emailId}`, 'DELETE');
   }
+
+  /**
+   * Delete emails from a mailbox in bulk, or all of them without options
+   * @param mailboxId The ID of the mailbox
+   * @param options Optional filters on the emails to delete
+   * @returns The number of deleted emails
+   */
+  async deleteEmails(mailboxId: string, options?: DeleteEmailsOptions): Promise<number> {
+    const response = await this.request<DeleteEmailsResponse>(`/api/v1/mailboxes/${mailboxId}/emails`, 'DELETE', options);
+    return response.data.deleted_count;
+  }
 } 
\ No newline at end of file
@@ -8,6 +8,9 @@ export interface Email {
   received_at: number;
   expires_at: number;
   encrypted_content: string;
+  from_addr: string;
+  to_addr: string;
+  subject: string;
 }
 
 export interface APIResponse<T> {
@@ -24,6 +27,17 @@ export interface EmailResponse extends APIResponse<Email> {
   data: Email;
 }
 
+export interface DeleteEmailsOptions {
+  /** Only delete emails received before this Unix timestamp */
+  before_timestamp?: number;
+  /** Only delete these emails */
+  email_ids?: string[];
+}
+
+export interface DeleteEmailsResponse extends APIResponse<{ deleted_count: number }> {
+  data: { deleted_count: number };
+}
+
 export interface DecryptedEmail extends Omit<Email, 'encrypted_content'> {
   content: string;
 } 
\ No newline at end of file
@@ -0,0 +1,100 @@
+//! Regenerates the TypeScript client SDK from the API specification.
+//!
+//! Run from the workspace root with `make sdk` or `cargo run --bin sdk-gen`.
+//! The spec is validated against the Swagger 2.0 JSON Schema before
+//! `openapi-generator-cli` is invoked through npx (requires Node.js and Java).
+
+use anyhow::{bail, Context};
+use clap::Parser;
+use serde_json::Value;
+use std::{fs, path::PathBuf, process::Command};
+use tracing::info;
+
+const SWAGGER_SCHEMA: &str = include_str!("../../schemas/swagger-2.0.json");
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// Where the generated spec is written
+    #[arg(long, default_value = "clients/typescript/swagger-spec.json")]
+    spec_path: PathBuf,
+
+    /// Directory receiving the generated TypeScript sources
+    #[arg(long, default_value = "clients/typescript/src")]
+    output_dir: PathBuf,
+
+    /// openapi-generator generator name
+    #[arg(long, default_value = "typescript-fetch")]
+    generator: String,
+
+    /// Only write and validate the spec
+    #[arg(long)]
+    skip_generate: bool,
+}
+
+fn validate_spec(spec: &Value) -> anyhow::Result<()> {
+    let schema: Value = serde_json::from_str(SWAGGER_SCHEMA)?;
+    let validator = jsonschema::draft4::new(&schema)
+        .map_err(|e| anyhow::anyhow!("Invalid Swagger schema: {}", e))?;
+
+    let errors: Vec<String> = validator
+        .iter_errors(spec)
+        .map(|e| format!("{} at {}", e, e.instance_path))
+        .collect();
+    if !errors.is_empty() {
+        bail!("API spec does not conform to Swagger 2.0:\n{}", errors.join("\n"));
+    }
+    Ok(())
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::fmt::init();
+    let args = Args::parse();
+
+    let spec_json = web_app::generate_spec();
+    let spec: Value = serde_json::from_str(&spec_json)?;
+    validate_spec(&spec)?;
+    info!("API spec is valid Swagger 2.0");
+
+    if let Some(parent) = args.spec_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&args.spec_path, format!("{}\n", spec_json))
+        .with_context(|| format!("Failed to write {}", args.spec_path.display()))?;
+    info!("Wrote API spec to {}", args.spec_path.display());
+
+    if args.skip_generate {
+        return Ok(());
+    }
+
+    let status = Command::new("npx")
+        .args(["--yes", "@openapitools/openapi-generator-cli", "generate", "-g", &args.generator])
+        .arg("-i")
+        .arg(&args.spec_path)
+        .arg("-o")
+        .arg(&args.output_dir)
+        .status()
+        .context("Failed to run npx, is Node.js installed?")?;
+    if !status.success() {
+        bail!("openapi-generator-cli exited with {}", status);
+    }
+    info!("Generated {} client in {}", args.generator, args.output_dir.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_spec_is_valid_swagger() {
+        let spec: Value = serde_json::from_str(&web_app::generate_spec()).unwrap();
+        validate_spec(&spec).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_spec() {
+        let spec = serde_json::json!({ "swagger": "2.0", "info": {}, "paths": {} });
+        assert!(validate_spec(&spec).is_err());
+    }
+}
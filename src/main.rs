@@ -1,7 +1,13 @@
 use clap::Parser;
+use common::shutdown::CancellationToken;
+use std::time::Duration;
 use tokio::try_join;
 use tracing::{error, info};
 
+/// How long after the shutdown grace period the process exits anyway, should
+/// a service hang while stopping
+const FORCED_EXIT_TIMEOUT: Duration = Duration::from_secs(30);
+
 #[derive(Parser)]
 pub struct Config {
     /// Web app URL (e.g. 'https://example.com')
@@ -24,6 +30,10 @@ pub struct Config {
     #[arg(long, env = "SMTP_TLS_BIND_ADDR", default_value = "127.0.0.1:465")]
     pub smtp_tls_bind_addr: String,
 
+    /// SMTP STARTTLS server bind address (only used when TLS is configured)
+    #[arg(long, env = "SMTP_STARTTLS_BIND_ADDR", default_value = "127.0.0.1:587")]
+    pub smtp_starttls_bind_addr: String,
+
     /// TLS certificate path (optional)
     #[arg(long, env = "TLS_CERT_PATH")]
     pub tls_cert_path: Option<std::path::PathBuf>,
@@ -72,9 +82,130 @@ pub struct Config {
     #[arg(long, env = "BLOCKED_NETWORKS", value_delimiter = ',')]
     pub blocked_networks: Option<Vec<String>>,
 
+    /// DNSBL zones to reject listed client IPs with (e.g. "zen.spamhaus.org,bl.spamcop.net")
+    #[arg(long, env = "BLOCKED_DNSBLS", value_delimiter = ',')]
+    pub blocked_dnsbls: Option<Vec<String>>,
+
     /// Supported email domains (comma-separated)
     #[arg(long, env = "SUPPORTED_DOMAINS", value_delimiter = ',', default_value = "mail-hook.example.com")]
     pub supported_domains: Vec<String>,
+
+    /// Token of the Telegram login bot, which also notifies users of new emails; no notifications are sent when unset
+    #[arg(long, env = "TELEGRAM_BOT_TOKEN")]
+    pub telegram_bot_token: Option<String>,
+
+    /// Maximum number of recipients of a single message processed concurrently
+    #[arg(long, env = "MAX_PARALLEL_RECIPIENTS", default_value = "8")]
+    pub max_parallel_recipients: usize,
+
+    /// Maximum number of SMTP connections served at once
+    #[arg(long, env = "MAX_SMTP_CONNECTIONS", default_value = "100")]
+    pub max_smtp_connections: usize,
+
+    /// Format of generated email IDs: uuid, time_ordered or nanoid
+    #[arg(long, env = "EMAIL_ID_FORMAT", default_value = "uuid")]
+    pub email_id_format: common::id::IdFormat,
+
+    /// Days to keep API key usage records
+    #[arg(long, env = "API_KEY_USAGE_RETENTION_DAYS", default_value = "30")]
+    pub api_key_usage_retention_days: u32,
+
+    /// Length of generated mailbox IDs
+    #[arg(long, env = "ENTROPY_MAILBOX_ID_LENGTH", default_value = "12")]
+    pub entropy_mailbox_id_length: usize,
+
+    /// Length of generated mailbox aliases
+    #[arg(long, env = "ENTROPY_ALIAS_LENGTH", default_value = "12")]
+    pub entropy_alias_length: usize,
+
+    /// Number of random characters in generated API keys
+    #[arg(long, env = "ENTROPY_API_KEY_LENGTH", default_value = "32")]
+    pub entropy_api_key_length: usize,
+
+    /// Seconds to let in-flight requests and SMTP transactions finish after a shutdown signal
+    #[arg(long, env = "SHUTDOWN_GRACE_PERIOD_SECS", default_value = "30")]
+    pub shutdown_grace_period_secs: u64,
+
+    /// Index email keywords as HMAC search tokens keyed by the mailbox public key
+    #[arg(long, env = "ENABLE_SEARCH_INDEX")]
+    pub enable_search_index: bool,
+
+    /// Compress HTTP responses with br, gzip or deflate when the client accepts it
+    #[arg(long, env = "ENABLE_RESPONSE_COMPRESSION", default_value = "true")]
+    pub enable_response_compression: bool,
+
+    /// HTTP responses smaller than this are sent uncompressed
+    #[arg(long, env = "COMPRESSION_MIN_SIZE_BYTES", default_value = "1024")]
+    pub compression_min_size_bytes: u16,
+
+    /// HTTP methods accepted by the web server, others are answered with 405 (comma-separated)
+    #[arg(long, env = "ALLOWED_HTTP_METHODS", value_delimiter = ',', default_value = "GET,HEAD,POST,PUT,PATCH,DELETE,OPTIONS")]
+    pub allowed_http_methods: Vec<String>,
+
+    /// Reject every DELETE request to the web server, and the other requests that
+    /// delete data or revoke access, e.g. for read-only audit deployments
+    #[arg(long, env = "DISALLOW_DELETE_OPERATIONS")]
+    pub disallow_delete_operations: bool,
+
+    /// Maximum number of aliases per mailbox, including the primary one
+    #[arg(long, env = "MAX_ALIASES_PER_MAILBOX", default_value = "5")]
+    pub max_aliases_per_mailbox: usize,
+
+    /// Consecutive failures after which outbound requests to a host are suspended
+    #[arg(long, env = "CIRCUIT_BREAKER_FAILURE_THRESHOLD", default_value = "5")]
+    pub circuit_breaker_failure_threshold: u32,
+
+    /// Seconds before a suspended host is tried again
+    #[arg(long, env = "CIRCUIT_BREAKER_RESET_TIMEOUT_SECS", default_value = "60")]
+    pub circuit_breaker_reset_timeout_secs: u64,
+
+    /// Secret expected in the X-Admin-Secret header of /api/admin requests; the admin API is disabled when unset
+    #[arg(long, env = "ADMIN_SECRET")]
+    pub admin_secret: Option<String>,
+
+    /// Bearer token for POST /api/admin/shutdown, separate from ADMIN_SECRET; the endpoint is disabled when unset
+    #[arg(long, env = "ADMIN_TOKEN")]
+    pub admin_token: Option<String>,
+
+    /// Mailboxes a user may create per hour
+    #[arg(long, env = "RATE_LIMIT_MAILBOX_CREATE_PER_HOUR", default_value = "10")]
+    pub rate_limit_mailbox_create_per_hour: u32,
+
+    /// Requests per hour a user may make to each route deleting emails
+    #[arg(long, env = "RATE_LIMIT_EMAIL_DELETE_PER_HOUR", default_value = "200")]
+    pub rate_limit_email_delete_per_hour: u32,
+
+    /// Registrations and logins per minute from a client IP
+    #[arg(long, env = "RATE_LIMIT_AUTH_PER_MINUTE", default_value = "5")]
+    pub rate_limit_auth_per_minute: u32,
+
+    /// OAuth and Telegram login callbacks per minute from a client IP
+    #[arg(long, env = "RATE_LIMIT_OAUTH_CALLBACK_PER_MINUTE", default_value = "10")]
+    pub rate_limit_oauth_callback_per_minute: u32,
+
+    /// Reverse proxies in CIDR format whose forwarding headers give the client address, for HTTP and SMTP (comma-separated)
+    #[arg(long, env = "TRUSTED_PROXIES", value_delimiter = ',', default_value = "127.0.0.1,::1")]
+    pub trusted_proxies: Vec<String>,
+
+    /// Header trusted HTTP proxies put the client address in: X-Forwarded-For, or one holding only the address like X-Real-IP or CF-Connecting-IP
+    #[arg(long, env = "CLIENT_IP_HEADER", default_value = "X-Forwarded-For")]
+    pub client_ip_header: String,
+
+    /// Address serving Prometheus metrics at /metrics, without authentication; metrics are not served when unset
+    #[arg(long, env = "METRICS_BIND_ADDR")]
+    pub metrics_bind_addr: Option<String>,
+
+    /// Apply pending database migrations and exit
+    #[arg(long, env = "MIGRATE_ONLY")]
+    pub migrate_only: bool,
+
+    /// Apply pending migrations to an in-memory copy of the database and exit without changing it; implies --migrate-only
+    #[arg(long = "dry-run", env = "DATABASE_MIGRATION_DRY_RUN")]
+    pub migration_dry_run: bool,
+
+    /// Log email addresses and usernames in full instead of only their first characters
+    #[arg(long, env = "DISABLE_LOG_REDACTION")]
+    pub disable_log_redaction: bool,
 }
 
 #[tokio::main]
@@ -82,12 +213,22 @@ async fn main() {
     // Load .env file if it exists
     dotenv::dotenv().ok();
 
-    // Initialize tracing
-    tracing_subscriber::fmt::init();
+    // Initialize tracing, exporting spans until the end of main
+    let _tracing = common::telemetry::init_tracing("vh-mail-hook");
 
     // Parse command line arguments
     let config = Config::parse();
 
+    // Both services share the database, so migrations are handled once here
+    if config.migrate_only || config.migration_dry_run {
+        let database_url = format!("sqlite:{}", config.database_path);
+        if let Err(e) = common::db::run_migrations_only(&database_url, config.migration_dry_run).await {
+            error!("Migration error: {}", e);
+            std::process::exit(1);
+        }
+        return;
+    }
+
     info!("Starting mail hook application...");
 
     // Create web app config
@@ -96,6 +237,32 @@ async fn main() {
         bind_addr: config.web_bind_addr.clone(),
         web_app_url: config.web_app_url.clone(),
         supported_domains: config.supported_domains.clone(),
+        email_id_format: config.email_id_format,
+        shutdown_grace_period_secs: config.shutdown_grace_period_secs,
+        entropy_mailbox_id_length: config.entropy_mailbox_id_length,
+        entropy_alias_length: config.entropy_alias_length,
+        entropy_api_key_length: config.entropy_api_key_length,
+        enable_search_index: config.enable_search_index,
+        enable_response_compression: config.enable_response_compression,
+        compression_min_size_bytes: config.compression_min_size_bytes,
+        allowed_http_methods: config.allowed_http_methods,
+        disallow_delete_operations: config.disallow_delete_operations,
+        max_email_size: config.max_email_size,
+        max_aliases_per_mailbox: config.max_aliases_per_mailbox,
+        circuit_breaker_failure_threshold: config.circuit_breaker_failure_threshold,
+        circuit_breaker_reset_timeout_secs: config.circuit_breaker_reset_timeout_secs,
+        admin_secret: config.admin_secret,
+        admin_token: config.admin_token,
+        rate_limit_mailbox_create_per_hour: config.rate_limit_mailbox_create_per_hour,
+        rate_limit_email_delete_per_hour: config.rate_limit_email_delete_per_hour,
+        rate_limit_auth_per_minute: config.rate_limit_auth_per_minute,
+        rate_limit_oauth_callback_per_minute: config.rate_limit_oauth_callback_per_minute,
+        trusted_proxies: config.trusted_proxies.clone(),
+        client_ip_header: config.client_ip_header,
+        metrics_bind_addr: config.metrics_bind_addr,
+        migrate_only: false,
+        migration_dry_run: false,
+        disable_log_redaction: config.disable_log_redaction,
     };
 
     // Create mail service config
@@ -103,11 +270,16 @@ async fn main() {
         database_path: config.database_path.clone(),
         smtp_bind_addr: config.smtp_bind_addr.clone(),
         smtp_tls_bind_addr: config.smtp_tls_bind_addr.clone(),
+        smtp_starttls_bind_addr: config.smtp_starttls_bind_addr.clone(),
         tls_cert_path: config.tls_cert_path,
         tls_key_path: config.tls_key_path,
         tls_chain_path: config.tls_chain_path,
         tls_poll_interval: config.tls_poll_interval,
         blocked_networks: config.blocked_networks,
+        blocked_dnsbls: config.blocked_dnsbls,
+        supported_domains: config.supported_domains,
+        trusted_proxies: config.trusted_proxies,
+        telegram_bot_token: config.telegram_bot_token,
         max_email_size: config.max_email_size,
         rate_limit_per_hour: config.rate_limit_per_hour,
         enable_greylisting: config.enable_greylisting,
@@ -115,6 +287,15 @@ async fn main() {
         enable_spf: config.enable_spf,
         enable_dkim: config.enable_dkim,
         cleanup_interval: config.cleanup_interval,
+        api_key_usage_retention_days: config.api_key_usage_retention_days,
+        shutdown_grace_period_secs: config.shutdown_grace_period_secs,
+        max_parallel_recipients: config.max_parallel_recipients,
+        max_smtp_connections: config.max_smtp_connections,
+        email_id_format: config.email_id_format,
+        enable_search_index: config.enable_search_index,
+        migrate_only: false,
+        migration_dry_run: false,
+        disable_log_redaction: config.disable_log_redaction,
     };
 
     // Run both services concurrently
@@ -123,8 +304,31 @@ async fn main() {
         config.web_bind_addr, config.smtp_bind_addr
     );
 
-    if let Err(e) = try_join!(web_app::run(web_config), mail_service::run(mail_config)) {
-        error!("Application error: {}", e);
-        std::process::exit(1);
+    // Lets the web app's readiness probe check the SMTP server is listening
+    let (smtp_bound, smtp_bound_rx) = tokio::sync::watch::channel(false);
+    // Signals and the admin API stop both services through the same token
+    let shutdown = CancellationToken::new();
+    common::shutdown::cancel_on_signal(&shutdown);
+    let forced_exit_after = Duration::from_secs(config.shutdown_grace_period_secs) + FORCED_EXIT_TIMEOUT;
+
+    tokio::select! {
+        result = async {
+            try_join!(
+                web_app::run(web_config, Some(smtp_bound_rx), shutdown.clone()),
+                mail_service::run(mail_config, smtp_bound, shutdown.clone())
+            )
+        } => {
+            if let Err(e) = result {
+                error!("Application error: {}", e);
+                std::process::exit(1);
+            }
+        }
+        _ = async {
+            shutdown.cancelled().await;
+            tokio::time::sleep(forced_exit_after).await;
+        } => {
+            error!("Services did not stop within {:?} of the shutdown, exiting", forced_exit_after);
+            std::process::exit(1);
+        }
     }
 }